@@ -0,0 +1,604 @@
+//! Tar archive import and export for lolelffs
+//!
+//! Streams tar entries straight into an image through the regular library
+//! calls, so root filesystems can be built from the same archives used for
+//! containers and chroots, and serializes images back into archives that
+//! standard tooling can inspect. Gzip-compressed archives are detected by
+//! their magic bytes and decompressed on the fly. Xattrs travel as PAX
+//! `SCHILY.xattr.*` records, as written by GNU tar and bsdtar.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Gzip stream magic
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// PAX record prefix for extended attributes
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Summary of a tar import or export
+#[derive(Debug, Clone, Default)]
+pub struct TarStats {
+    /// Regular files written
+    pub files: u32,
+    /// Directories created or updated
+    pub dirs: u32,
+    /// Symlinks created
+    pub symlinks: u32,
+    /// Hard links created
+    pub hardlinks: u32,
+    /// Entries of types lolelffs cannot store (devices, FIFOs, ...)
+    pub skipped: u32,
+    /// Files left out of an export because this build cannot decode them
+    pub unsupported: u32,
+}
+
+/// How `export_tar_with` writes an archive
+#[derive(Debug, Clone, Default)]
+pub struct TarExportOptions {
+    /// Leave out files stored with an algorithm this build cannot decode,
+    /// counting them in `TarStats::unsupported`, instead of failing
+    pub skip_unsupported: bool,
+    /// Write files in the order their contents are stored on the image (see
+    /// `walk_tree_physical`) rather than by path
+    pub physical_order: bool,
+}
+
+/// How `import_tar_with` applies an archive
+#[derive(Debug, Clone, Default)]
+pub struct TarImportOptions {
+    /// Create entries in sorted path order rather than archive order, so
+    /// inode numbers and block placement do not depend on how the archive
+    /// was written (buffers the whole archive in memory)
+    pub sorted: bool,
+    /// Record each file's SHA-256 for `verify`
+    pub checksums: bool,
+}
+
+/// An archive entry read out of the stream
+struct TarItem {
+    path: PathBuf,
+    header: tar::Header,
+    xattrs: Vec<(String, Vec<u8>)>,
+    /// Symlink or hard link target
+    link: Option<PathBuf>,
+    /// Contents of a regular file
+    data: Vec<u8>,
+}
+
+impl TarItem {
+    fn read<R: Read>(mut entry: tar::Entry<R>) -> Result<Self> {
+        let path = entry.path()?.into_owned();
+        let header = entry.header().clone();
+        let link = entry.link_name()?.map(|t| t.into_owned());
+        let xattrs = match entry.pax_extensions()? {
+            Some(extensions) => extensions
+                .filter_map(|ext| ext.ok())
+                .filter_map(|ext| {
+                    let name = ext.key().ok()?.strip_prefix(PAX_XATTR_PREFIX)?;
+                    Some((name.to_string(), ext.value_bytes().to_vec()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut data = Vec::new();
+        if header.entry_type().is_file() {
+            data.reserve(header.size()? as usize);
+            entry.read_to_end(&mut data)?;
+        }
+        Ok(TarItem {
+            path,
+            header,
+            xattrs,
+            link,
+            data,
+        })
+    }
+}
+
+impl LolelfFs {
+    /// Import a tar archive (optionally gzip-compressed) below `dest_dir`
+    ///
+    /// Existing directories are merged into and existing entries of another
+    /// type are replaced. Mode, ownership, and mtime are taken from the
+    /// archive; directory mtimes are applied last since adding children
+    /// updates them.
+    pub fn import_tar<R: Read>(&mut self, reader: R, dest_dir: u32) -> Result<TarStats> {
+        self.import_tar_with(reader, dest_dir, &TarImportOptions::default())
+    }
+
+    /// `import_tar` with explicit options
+    pub fn import_tar_with<R: Read>(
+        &mut self,
+        reader: R,
+        dest_dir: u32,
+        opts: &TarImportOptions,
+    ) -> Result<TarStats> {
+        let mut reader = BufReader::new(reader);
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            let gz = flate2::read::GzDecoder::new(reader);
+            self.import_tar_stream(tar::Archive::new(gz), dest_dir, opts)
+        } else {
+            self.import_tar_stream(tar::Archive::new(reader), dest_dir, opts)
+        }
+    }
+
+    fn import_tar_stream<R: Read>(
+        &mut self,
+        mut archive: tar::Archive<R>,
+        dest_dir: u32,
+        opts: &TarImportOptions,
+    ) -> Result<TarStats> {
+        let mut stats = TarStats::default();
+        let mut dir_mtimes = Vec::new();
+        let mut pending = Vec::new();
+
+        let mut count = 0;
+        for entry in archive.entries()? {
+            let item = TarItem::read(entry?)?;
+            count += 1;
+            if opts.sorted {
+                let key = (
+                    item.header.entry_type().is_hard_link(),
+                    archive_path(&item.path)?,
+                );
+                pending.push((key, item));
+            } else {
+                self.report_progress("import", count, 0, &item.path.to_string_lossy());
+                self.import_tar_item(item, dest_dir, opts, &mut stats, &mut dir_mtimes)?;
+            }
+        }
+
+        // Parents sort before their children, and hard links go last so their
+        // targets exist; the stable sort keeps the last of duplicate entries
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        let total = pending.len() as u64;
+        for (i, (_, item)) in pending.into_iter().enumerate() {
+            self.report_progress("import", i as u64 + 1, total, &item.path.to_string_lossy());
+            self.import_tar_item(item, dest_dir, opts, &mut stats, &mut dir_mtimes)?;
+        }
+        if !opts.sorted {
+            self.report_progress("import", count, count, "");
+        }
+
+        for (inode_num, mtime) in dir_mtimes {
+            let mut inode = self.read_inode(inode_num)?;
+            inode.i_mtime = mtime as u32;
+            self.write_inode(inode_num, &inode)?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Create one archive entry below `dest_dir`
+    fn import_tar_item(
+        &mut self,
+        item: TarItem,
+        dest_dir: u32,
+        opts: &TarImportOptions,
+        stats: &mut TarStats,
+        dir_mtimes: &mut Vec<(u32, u64)>,
+    ) -> Result<()> {
+        let TarItem {
+            path,
+            header,
+            xattrs,
+            link,
+            data,
+        } = item;
+        let entry_type = header.entry_type();
+
+        let mut names = archive_path(&path)?;
+        let name = match names.pop() {
+            Some(name) => name,
+            None if entry_type.is_dir() => {
+                // "./" itself carries the destination's own metadata
+                self.apply_tar_metadata(dest_dir, &header, &xattrs)?;
+                dir_mtimes.push((dest_dir, header.mtime()?));
+                return Ok(());
+            }
+            None => bail!("Archive entry '{}' has no name", path.display()),
+        };
+        let parent = self.tar_mkdirs(dest_dir, &names)?;
+
+        let inode_num = if entry_type.is_dir() {
+            let ino = match self.tar_existing(parent, &name, |i| i.is_dir())? {
+                Some(ino) => ino,
+                None => self.mkdir(parent, &name)?,
+            };
+            dir_mtimes.push((ino, header.mtime()?));
+            stats.dirs += 1;
+            ino
+        } else if entry_type.is_file() {
+            let ino = match self.tar_existing(parent, &name, |i| i.is_file())? {
+                Some(ino) => ino,
+                None => self.create_file(parent, &name)?,
+            };
+            self.write_file(ino, &data)?;
+            if opts.checksums {
+                self.record_sha256(ino, &data)?;
+            }
+            stats.files += 1;
+            ino
+        } else if entry_type.is_symlink() {
+            let target = link
+                .map(|t| t.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if self.lookup(parent, &name)?.is_some() {
+                self.remove_tree(parent, &name)?;
+            }
+            stats.symlinks += 1;
+            self.symlink(parent, &name, &target)
+                .with_context(|| format!("Failed to import symlink '{}'", path.display()))?
+        } else if entry_type.is_hard_link() {
+            let Some(target) = link else {
+                bail!("Hard link '{}' has no target", path.display());
+            };
+            let target_ino = self.tar_resolve(dest_dir, &target)?;
+            if self.lookup(parent, &name)?.is_some() {
+                self.remove_tree(parent, &name)?;
+            }
+            self.link(target_ino, parent, &name)?;
+            stats.hardlinks += 1;
+            return Ok(());
+        } else {
+            // PAX and GNU metadata headers are consumed by the tar crate,
+            // anything left here has no lolelffs representation
+            stats.skipped += 1;
+            return Ok(());
+        };
+
+        self.apply_tar_metadata(inode_num, &header, &xattrs)
+    }
+
+    /// Existing entry `name` if it satisfies `same_type`; other types are removed
+    fn tar_existing(
+        &mut self,
+        parent: u32,
+        name: &str,
+        same_type: impl Fn(&Inode) -> bool,
+    ) -> Result<Option<u32>> {
+        match self.lookup(parent, name)? {
+            Some(ino) if same_type(&self.read_inode(ino)?) => Ok(Some(ino)),
+            Some(_) => {
+                self.remove_tree(parent, name)?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Walk (creating as needed) the parent directories of an entry
+    fn tar_mkdirs(&mut self, dest_dir: u32, names: &[String]) -> Result<u32> {
+        let mut dir = dest_dir;
+        for name in names {
+            dir = match self.tar_existing(dir, name, |i| i.is_dir())? {
+                Some(ino) => ino,
+                None => self.mkdir(dir, name)?,
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Resolve an archive-relative path, e.g. a hard link target
+    fn tar_resolve(&mut self, dest_dir: u32, path: &Path) -> Result<u32> {
+        let mut ino = dest_dir;
+        for name in archive_path(path)? {
+            ino = self.lookup(ino, &name)?.ok_or_else(|| {
+                anyhow::anyhow!("Link target '{}' not found in archive", path.display())
+            })?;
+        }
+        Ok(ino)
+    }
+
+    /// Copy xattrs, mode, ownership, and mtime from a tar entry onto an inode
+    fn apply_tar_metadata(
+        &mut self,
+        inode_num: u32,
+        header: &tar::Header,
+        xattrs: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        for (name, value) in xattrs {
+            self.set_xattr(inode_num, name, value)?;
+        }
+
+        let mut inode = self.read_inode(inode_num)?;
+        inode.i_mode = (inode.i_mode & mode::S_IFMT) | (header.mode()? & 0o7777);
+        inode.i_uid = header.uid()? as u32;
+        inode.i_gid = header.gid()? as u32;
+        inode.i_mtime = header.mtime()? as u32;
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Write a directory and everything below it as a tar archive
+    ///
+    /// Paths are relative to `dir_inode_num`, which is itself stored as
+    /// `./`. Files with several links are stored once and
+    /// referenced by hard link entries afterwards.
+    pub fn export_tar<W: Write>(&mut self, writer: W, dir_inode_num: u32) -> Result<TarStats> {
+        self.export_tar_with(writer, dir_inode_num, &TarExportOptions::default())
+    }
+
+    /// `export_tar` with explicit options
+    pub fn export_tar_with<W: Write>(
+        &mut self,
+        writer: W,
+        dir_inode_num: u32,
+        opts: &TarExportOptions,
+    ) -> Result<TarStats> {
+        let dir_inode = self.read_inode(dir_inode_num)?;
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+
+        let mut builder = tar::Builder::new(writer);
+        let mut stats = TarStats::default();
+        let mut linked: HashMap<u32, String> = HashMap::new();
+
+        self.export_xattrs(&mut builder, dir_inode_num)?;
+        let mut header = tar_header(&dir_inode, tar::EntryType::Directory);
+        builder.append_data(&mut header, "./", std::io::empty())?;
+
+        let walked = if opts.physical_order {
+            self.walk_tree_physical(dir_inode_num)?
+        } else {
+            self.walk_tree(dir_inode_num)?
+        };
+        let total = walked.len() as u64;
+        for (i, walk) in walked.into_iter().enumerate() {
+            let inode_num = walk.entry.inode_num;
+            let inode = &walk.entry.inode;
+            let path = walk.path;
+            self.report_progress("export", i as u64 + 1, total, &path);
+
+            if opts.skip_unsupported
+                && inode.is_file()
+                && self.unsupported_algorithm(inode_num)?.is_some()
+            {
+                stats.unsupported += 1;
+                continue;
+            }
+            if let Some(target) = linked.get(&inode_num) {
+                let mut header = tar_header(inode, tar::EntryType::Link);
+                builder.append_link(&mut header, &path, target)?;
+                stats.hardlinks += 1;
+                continue;
+            }
+
+            self.export_xattrs(&mut builder, inode_num)?;
+            if inode.is_dir() {
+                let mut header = tar_header(inode, tar::EntryType::Directory);
+                builder.append_data(&mut header, format!("{}/", path), std::io::empty())?;
+                stats.dirs += 1;
+            } else if inode.is_symlink() {
+                let target = String::from_utf8_lossy(&self.read_file(inode_num)?).into_owned();
+                let mut header = tar_header(inode, tar::EntryType::Symlink);
+                builder.append_link(&mut header, &path, &target)?;
+                stats.symlinks += 1;
+            } else {
+                let reader = self.open_reader(inode_num)?;
+                let mut header = tar_header(inode, tar::EntryType::Regular);
+                header.set_size(reader.size());
+                builder.append_data(&mut header, &path, reader)?;
+                stats.files += 1;
+                if inode.i_nlink > 1 {
+                    linked.insert(inode_num, path);
+                }
+            }
+        }
+
+        builder.finish()?;
+        Ok(stats)
+    }
+
+    /// Emit an inode's xattrs as a PAX header for the entry that follows
+    fn export_xattrs<W: Write>(
+        &mut self,
+        builder: &mut tar::Builder<W>,
+        inode_num: u32,
+    ) -> Result<()> {
+        let mut records = Vec::new();
+        for name in self.list_xattrs(inode_num)? {
+            let value = self.get_xattr(inode_num, &name)?;
+            records.push((format!("{}{}", PAX_XATTR_PREFIX, name), value));
+        }
+        builder.append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), &v[..])))?;
+        Ok(())
+    }
+}
+
+/// Tar header carrying an inode's mode, ownership, and mtime
+fn tar_header(inode: &Inode, entry_type: tar::EntryType) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(inode.i_mode & 0o7777);
+    header.set_uid(inode.i_uid as u64);
+    header.set_gid(inode.i_gid as u64);
+    header.set_mtime(inode.i_mtime as u64);
+    header.set_size(0);
+    header
+}
+
+/// Split an archive path into names, refusing anything that escapes the root
+fn archive_path(path: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+            Component::CurDir | Component::RootDir => {}
+            _ => bail!("Refusing unsafe archive path '{}'", path.display()),
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_image, TempPath};
+
+    fn header(entry_type: tar::EntryType, mode: u32, mtime: u64, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(mtime);
+        header.set_size(size);
+        header
+    }
+
+    #[test]
+    fn test_import_tar() {
+        let (_path, mut fs) = temp_image("tar.img");
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut dir = header(tar::EntryType::Directory, 0o750, 1_600_000_000, 0);
+        dir.set_uid(1000);
+        dir.set_gid(100);
+        builder
+            .append_data(&mut dir, "./etc/", std::io::empty())
+            .unwrap();
+
+        let data = b"root:x:0:0::/root:/bin/sh\n";
+        let mut file = header(
+            tar::EntryType::Regular,
+            0o644,
+            1_600_000_001,
+            data.len() as u64,
+        );
+        builder
+            .append_data(&mut file, "./etc/passwd", &data[..])
+            .unwrap();
+
+        let mut link = header(tar::EntryType::Symlink, 0o777, 0, 0);
+        builder
+            .append_link(&mut link, "./etc/pw", "passwd")
+            .unwrap();
+
+        let mut link = header(tar::EntryType::Link, 0o644, 0, 0);
+        builder
+            .append_link(&mut link, "./bin/passwd2", "./etc/passwd")
+            .unwrap();
+
+        let raw = builder.into_inner().unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gz, &raw).unwrap();
+        let stats = fs
+            .import_tar(&gz.finish().unwrap()[..], LOLELFFS_ROOT_INO)
+            .unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.dirs, 1);
+        assert_eq!(stats.symlinks, 1);
+        assert_eq!(stats.hardlinks, 1);
+
+        let etc = fs.resolve_path("/etc").unwrap();
+        let etc_inode = fs.read_inode(etc).unwrap();
+        assert_eq!(etc_inode.i_mode & 0o7777, 0o750);
+        assert_eq!((etc_inode.i_uid, etc_inode.i_gid), (1000, 100));
+        assert_eq!(etc_inode.i_mtime, 1_600_000_000);
+
+        let passwd = fs.resolve_path("/etc/passwd").unwrap();
+        assert_eq!(fs.read_file(passwd).unwrap(), data);
+        assert_eq!(fs.read_inode(passwd).unwrap().i_nlink, 2);
+        assert_eq!(fs.resolve_path("/bin/passwd2").unwrap(), passwd);
+        let pw = fs.lookup(etc, "pw").unwrap().unwrap();
+        assert_eq!(fs.read_file(pw).unwrap(), b"passwd");
+
+        // Uncompressed archives work too
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut file = header(tar::EntryType::Regular, 0o600, 0, 1);
+        builder.append_data(&mut file, "ok", &b"x"[..]).unwrap();
+        let raw = builder.into_inner().unwrap();
+        assert_eq!(fs.import_tar(&raw[..], etc).unwrap().files, 1);
+        assert!(fs.lookup(etc, "ok").unwrap().is_some());
+
+        assert!(archive_path(Path::new("./a/../../b")).is_err());
+        assert_eq!(archive_path(Path::new("/a/./b")).unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_import_tar_sorted_is_reproducible() {
+        // The same tree archived in two different orders, hard link included
+        let archive = |order: &[usize]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for &i in order {
+                match i {
+                    0 => {
+                        let mut h = header(tar::EntryType::Directory, 0o755, 5, 0);
+                        builder.append_data(&mut h, "b/", std::io::empty()).unwrap();
+                    }
+                    1 | 2 => {
+                        let (name, data) = [("b/two", "second"), ("a", "first")][i - 1];
+                        let mut h = header(tar::EntryType::Regular, 0o644, 5, data.len() as u64);
+                        builder.append_data(&mut h, name, data.as_bytes()).unwrap();
+                    }
+                    _ => {
+                        let mut h = header(tar::EntryType::Link, 0o644, 5, 0);
+                        builder.append_link(&mut h, "c", "a").unwrap();
+                    }
+                }
+            }
+            builder.into_inner().unwrap()
+        };
+
+        let mut images = Vec::new();
+        for (i, order) in [[0, 1, 2, 3], [2, 3, 1, 0]].iter().enumerate() {
+            let path = TempPath::new(&format!("tar-repro-{}.img", i));
+            let mut fs = LolelfFs::create(&path, 2 * 1024 * 1024).unwrap();
+            fs.make_reproducible(1_700_000_000).unwrap();
+            let opts = TarImportOptions {
+                sorted: true,
+                ..Default::default()
+            };
+            let stats = fs
+                .import_tar_with(&archive(order)[..], LOLELFFS_ROOT_INO, &opts)
+                .unwrap();
+            assert_eq!((stats.files, stats.dirs, stats.hardlinks), (2, 1, 1));
+            drop(fs);
+            images.push(std::fs::read(&path).unwrap());
+        }
+        assert!(images[0] == images[1]);
+    }
+
+    #[test]
+    fn test_export_tar_roundtrip() {
+        let (_src_path, mut src) = temp_image("export.img");
+
+        let etc = src.mkdir(LOLELFFS_ROOT_INO, "etc").unwrap();
+        let hosts = src.create_file(etc, "hosts").unwrap();
+        let data: Vec<u8> = (0..9000u32).map(|i| (i % 13) as u8).collect();
+        src.write_file(hosts, &data).unwrap();
+        src.set_mode(hosts, 0o600).unwrap();
+        src.set_owner(hosts, Some(42), Some(7)).unwrap();
+        src.set_xattr(hosts, "user.origin", b"test").unwrap();
+        src.link(hosts, LOLELFFS_ROOT_INO, "hosts.link").unwrap();
+        src.symlink(etc, "h", "hosts").unwrap();
+
+        let mut archive = Vec::new();
+        let stats = src.export_tar(&mut archive, LOLELFFS_ROOT_INO).unwrap();
+        assert_eq!(
+            (stats.files, stats.dirs, stats.symlinks, stats.hardlinks),
+            (1, 1, 1, 1)
+        );
+
+        let (_dst_path, mut dst) = temp_image("export-dst.img");
+        dst.import_tar(&archive[..], LOLELFFS_ROOT_INO).unwrap();
+
+        // The walk is sorted, so "etc/hosts" comes first and the link follows
+        let hosts = dst.resolve_path("/etc/hosts").unwrap();
+        assert_eq!(dst.read_file(hosts).unwrap(), data);
+        let inode = dst.read_inode(hosts).unwrap();
+        assert_eq!(inode.i_mode & 0o7777, 0o600);
+        assert_eq!((inode.i_uid, inode.i_gid, inode.i_nlink), (42, 7, 2));
+        assert_eq!(dst.get_xattr(hosts, "user.origin").unwrap(), b"test");
+        assert_eq!(dst.resolve_path("/hosts.link").unwrap(), hosts);
+        let h = dst.resolve_path("/etc").unwrap();
+        let h = dst.lookup(h, "h").unwrap().unwrap();
+        assert_eq!(dst.read_file(h).unwrap(), b"hosts");
+    }
+}
@@ -0,0 +1,172 @@
+//! Moving data between regions of an image
+//!
+//! `relocate_extent` moves one extent of a file or directory to a chosen free
+//! run: the blocks are copied first, the extent index is rewritten to point
+//! at the copy, and only then are the old blocks freed, with barriers in
+//! between under ordered writes. `balance` uses it to empty a block range,
+//! e.g. to evacuate a suspected bad area of a device before it fails.
+
+use crate::fs::LolelfFs;
+use anyhow::{bail, Result};
+use std::ops::Range;
+
+/// Work done by `LolelfFs::balance`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceStats {
+    /// Data and xattr extents moved
+    pub extents_moved: u32,
+    /// Blocks copied, including extent and xattr index blocks
+    pub blocks_moved: u32,
+}
+
+impl LolelfFs {
+    /// Move extent `extent_idx` of `inode_num` to the free run starting at
+    /// `new_start`
+    pub fn relocate_extent(
+        &mut self,
+        inode_num: u32,
+        extent_idx: usize,
+        new_start: u32,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+
+        let inode = self.read_inode(inode_num)?;
+        if inode.ei_block == 0 || inode.is_symlink() {
+            bail!("Inode {} has no extents", inode_num);
+        }
+        let ei = self.read_extent_index(&inode)?;
+        let used = ei.extents.iter().take_while(|e| !e.is_empty()).count();
+        if extent_idx >= used {
+            bail!(
+                "Inode {} has {} extents, no extent {}",
+                inode_num,
+                used,
+                extent_idx
+            );
+        }
+        let len = ei.extents[extent_idx].ee_len;
+
+        let end = new_start as u64 + len as u64;
+        if new_start < self.superblock.data_block_start() || end > self.superblock.nr_blocks as u64
+        {
+            bail!("Blocks {}..{} are outside the data area", new_start, end);
+        }
+        for block_num in new_start..new_start + len {
+            if !self.is_block_free(block_num)? {
+                bail!("Block {} is in use", block_num);
+            }
+        }
+
+        self.mark_blocks(new_start, len, false)?;
+        self.superblock.nr_free_blocks -= len;
+        self.write_superblock()?;
+
+        self.move_extent(inode_num, extent_idx, new_start, &(0..0))
+    }
+
+    /// Copy extent `extent_idx` of `inode_num` to the already allocated run
+    /// at `new_start` and point the index at it. The old blocks are freed
+    /// unless they lie in `keep`.
+    pub(crate) fn move_extent(
+        &mut self,
+        inode_num: u32,
+        extent_idx: usize,
+        new_start: u32,
+        keep: &Range<u32>,
+    ) -> Result<()> {
+        let inode = self.read_inode(inode_num)?;
+        let mut ei = self.read_extent_index(&inode)?;
+        let old = ei.extents[extent_idx];
+
+        self.copy_blocks(old.ee_start, new_start, old.ee_len, inode.is_dir())?;
+        // The copy must be durable before the index names it, and the index
+        // before the old blocks can be reused
+        self.barrier()?;
+        ei.extents[extent_idx].ee_start = new_start;
+        self.write_extent_index(inode.ei_block, &ei)?;
+        // Files sharing the extent follow it
+        if old.is_shared() {
+            for (other, idx) in self.extent_sharers(inode_num, old.ee_start)? {
+                let other_inode = self.read_inode(other)?;
+                let mut other_ei = self.read_extent_index(&other_inode)?;
+                other_ei.extents[idx].ee_start = new_start;
+                self.write_extent_index(other_inode.ei_block, &other_ei)?;
+            }
+        }
+        self.barrier()?;
+
+        self.free_blocks_outside(old.ee_start..old.ee_start + old.ee_len, keep)
+    }
+
+    /// Move everything stored in blocks `range` elsewhere, leaving the range
+    /// free
+    ///
+    /// New allocations are kept out of the range while it is emptied. Fails up
+    /// front when the data would not fit elsewhere; when an extent finds no
+    /// free run long enough, the extents already moved stay moved.
+    pub fn balance(&mut self, range: Range<u32>) -> Result<BalanceStats> {
+        self.ensure_writable()?;
+        if range.is_empty() {
+            return Ok(BalanceStats::default());
+        }
+
+        let stats = self.evacuate(range.clone())?;
+        // Evacuation leaves the range allocated but unreferenced
+        self.free_blocks(range.start, range.len() as u32)?;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use crate::types::*;
+
+    #[test]
+    fn test_relocate_and_balance() {
+        let path = TempPath::new("balance.img");
+        let mut fs = LolelfFs::create(&path, 8 * 1024 * 1024).unwrap();
+        fs.superblock.comp_enabled = 0;
+
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let content: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        fs.write_file(ino, &content).unwrap();
+        let sub = fs.mkdir(LOLELFFS_ROOT_INO, "sub").unwrap();
+        fs.create_file(sub, "inner").unwrap();
+
+        let inode = fs.read_inode(ino).unwrap();
+        let extent = fs.read_extent_index(&inode).unwrap().extents[0];
+        let free_before = fs.superblock.nr_free_blocks;
+
+        // Moving onto used blocks or past the end is refused
+        assert!(fs.relocate_extent(ino, 0, extent.ee_start).is_err());
+        assert!(fs
+            .relocate_extent(ino, 0, fs.superblock.nr_blocks - 1)
+            .is_err());
+        assert!(fs.relocate_extent(ino, 5, 1500).is_err());
+
+        fs.relocate_extent(ino, 0, 1500).unwrap();
+        let inode = fs.read_inode(ino).unwrap();
+        assert_eq!(
+            fs.read_extent_index(&inode).unwrap().extents[0].ee_start,
+            1500
+        );
+        assert_eq!(fs.read_file(ino).unwrap(), content);
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+        assert!(fs.is_block_free(extent.ee_start).unwrap());
+
+        // Empty the start of the data area, directories included
+        let start = fs.superblock.data_block_start();
+        let stats = fs.balance(start..start + 64).unwrap();
+        assert!(stats.blocks_moved > 0);
+        for block_num in start..start + 64 {
+            assert!(fs.is_block_free(block_num).unwrap(), "block {}", block_num);
+        }
+        assert_eq!(fs.read_file(ino).unwrap(), content);
+        assert!(fs.lookup(sub, "inner").unwrap().is_some());
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+        let report = fs.fsck_full().unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+}
@@ -1,14 +1,17 @@
 //! Bitmap operations for inode and block allocation
 
+use crate::error::{FsError, NoSpaceKind};
 use crate::fs::LolelfFs;
 use crate::types::*;
 use anyhow::{bail, Result};
+use std::collections::HashSet;
 
 impl LolelfFs {
     /// Allocate a free inode
     pub fn alloc_inode(&mut self) -> Result<u32> {
+        span!("alloc_inode");
         if self.superblock.nr_free_inodes == 0 {
-            bail!("No free inodes available");
+            return Err(FsError::no_space(NoSpaceKind::Inodes, 1, 0).into());
         }
 
         let ifree_start = self.superblock.ifree_bitmap_start();
@@ -30,7 +33,7 @@ impl LolelfFs {
 
                             // Clear the bit
                             block[byte_idx] &= !(1 << bit_idx);
-                            self.write_block(ifree_start + block_idx, &block)?;
+                            self.write_meta_block(ifree_start + block_idx, &block)?;
 
                             // Update superblock
                             self.superblock.nr_free_inodes -= 1;
@@ -43,7 +46,8 @@ impl LolelfFs {
             }
         }
 
-        bail!("No free inodes found in bitmap");
+        // Superblock counter disagrees with the bitmap; trust the bitmap
+        Err(FsError::no_space(NoSpaceKind::Inodes, 1, 0).into())
     }
 
     /// Free an inode
@@ -51,6 +55,7 @@ impl LolelfFs {
         if inode_num >= self.superblock.nr_inodes {
             bail!("Invalid inode number {}", inode_num);
         }
+        self.block_cache.invalidate(inode_num);
 
         let ifree_start = self.superblock.ifree_bitmap_start();
         let block_idx = inode_num / LOLELFFS_BITS_PER_BLOCK;
@@ -62,7 +67,7 @@ impl LolelfFs {
 
         // Set the bit
         block[byte_idx] |= 1 << bit_offset;
-        self.write_block(ifree_start + block_idx, &block)?;
+        self.write_meta_block(ifree_start + block_idx, &block)?;
 
         // Update superblock
         self.superblock.nr_free_inodes += 1;
@@ -73,16 +78,24 @@ impl LolelfFs {
 
     /// Allocate consecutive free blocks
     pub fn alloc_blocks(&mut self, count: u32) -> Result<u32> {
+        self.alloc_blocks_placed(count, false)
+    }
+
+    /// Allocate consecutive free blocks, taking the first free run from the
+    /// start of the data area or, with `from_end`, the last one before the end
+    pub(crate) fn alloc_blocks_placed(&mut self, count: u32, from_end: bool) -> Result<u32> {
+        span!("alloc_blocks", count);
         if count == 0 {
             bail!("Cannot allocate 0 blocks");
         }
 
         if count > self.superblock.nr_free_blocks {
-            bail!(
-                "Not enough free blocks: need {}, have {}",
-                count,
-                self.superblock.nr_free_blocks
-            );
+            return Err(FsError::no_space(
+                NoSpaceKind::DataBlocks,
+                count as u64,
+                self.superblock.nr_free_blocks as u64,
+            )
+            .into());
         }
 
         let bfree_start = self.superblock.bfree_bitmap_start();
@@ -91,8 +104,14 @@ impl LolelfFs {
         // Search for consecutive free blocks
         let mut start_block = None;
         let mut consecutive = 0u32;
+        let mut longest = 0u32;
 
-        'outer: for block_num in data_start..self.superblock.nr_blocks {
+        let candidates: Box<dyn Iterator<Item = u32>> = if from_end {
+            Box::new((data_start..self.superblock.nr_blocks).rev())
+        } else {
+            Box::new(data_start..self.superblock.nr_blocks)
+        };
+        'outer: for block_num in candidates {
             let block_idx = block_num / LOLELFFS_BITS_PER_BLOCK;
             let bit_idx = block_num % LOLELFFS_BITS_PER_BLOCK;
             let byte_idx = (bit_idx / 8) as usize;
@@ -101,11 +120,12 @@ impl LolelfFs {
             let block = self.read_block(bfree_start + block_idx)?;
 
             if block[byte_idx] & (1 << bit_offset) != 0 {
-                // Block is free
-                if consecutive == 0 {
+                // Block is free; walking backwards, each one starts the run
+                if consecutive == 0 || from_end {
                     start_block = Some(block_num);
                 }
                 consecutive += 1;
+                longest = longest.max(consecutive);
 
                 if consecutive >= count {
                     break 'outer;
@@ -118,7 +138,12 @@ impl LolelfFs {
         }
 
         if consecutive < count {
-            bail!("Could not find {} consecutive free blocks", count);
+            return Err(FsError::no_space(
+                NoSpaceKind::ContiguousBlocks,
+                count as u64,
+                longest as u64,
+            )
+            .into());
         }
 
         let start = start_block.unwrap();
@@ -133,7 +158,7 @@ impl LolelfFs {
 
             let mut block = self.read_block(bfree_start + block_idx)?;
             block[byte_idx] &= !(1 << bit_offset);
-            self.write_block(bfree_start + block_idx, &block)?;
+            self.write_meta_block(bfree_start + block_idx, &block)?;
         }
 
         // Update superblock
@@ -145,18 +170,21 @@ impl LolelfFs {
 
     /// Free blocks
     pub fn free_blocks(&mut self, start: u32, count: u32) -> Result<()> {
+        span!("free_blocks", start, count);
         if count == 0 {
             return Ok(());
         }
 
+        // Validate the whole range first: a corrupt extent must neither mark
+        // metadata free nor leave the bitmap half updated
+        let data_start = self.superblock.data_block_start();
+        self.check_block_range(start, data_start)?;
+        self.check_block_range(start.saturating_add(count - 1), data_start)?;
+
         let bfree_start = self.superblock.bfree_bitmap_start();
 
         for i in 0..count {
             let block_num = start + i;
-            if block_num >= self.superblock.nr_blocks {
-                bail!("Invalid block number {}", block_num);
-            }
-
             let block_idx = block_num / LOLELFFS_BITS_PER_BLOCK;
             let bit_idx = block_num % LOLELFFS_BITS_PER_BLOCK;
             let byte_idx = (bit_idx / 8) as usize;
@@ -164,7 +192,7 @@ impl LolelfFs {
 
             let mut block = self.read_block(bfree_start + block_idx)?;
             block[byte_idx] |= 1 << bit_offset;
-            self.write_block(bfree_start + block_idx, &block)?;
+            self.write_meta_block(bfree_start + block_idx, &block)?;
         }
 
         // Update superblock
@@ -174,6 +202,34 @@ impl LolelfFs {
         Ok(())
     }
 
+    /// Set the bitmap bits of a block range without touching the free count,
+    /// rewriting each bitmap block once
+    pub(crate) fn mark_blocks(&mut self, start: u32, count: u32, free: bool) -> Result<()> {
+        let bfree_start = self.superblock.bfree_bitmap_start();
+        let end = start + count;
+        let mut block_num = start;
+
+        while block_num < end {
+            let block_idx = block_num / LOLELFFS_BITS_PER_BLOCK;
+            let chunk_end = end.min((block_idx + 1) * LOLELFFS_BITS_PER_BLOCK);
+            let mut block = self.read_block(bfree_start + block_idx)?;
+            for bit in
+                block_num % LOLELFFS_BITS_PER_BLOCK..chunk_end - block_idx * LOLELFFS_BITS_PER_BLOCK
+            {
+                let (byte_idx, bit_offset) = ((bit / 8) as usize, bit % 8);
+                if free {
+                    block[byte_idx] |= 1 << bit_offset;
+                } else {
+                    block[byte_idx] &= !(1 << bit_offset);
+                }
+            }
+            self.write_meta_block(bfree_start + block_idx, &block)?;
+            block_num = chunk_end;
+        }
+
+        Ok(())
+    }
+
     /// Check if a block is free
     pub fn is_block_free(&mut self, block_num: u32) -> Result<bool> {
         if block_num >= self.superblock.nr_blocks {
@@ -206,6 +262,54 @@ impl LolelfFs {
         Ok(block[byte_idx] & (1 << bit_offset) != 0)
     }
 
+    /// Collect every block referenced by a live inode
+    ///
+    /// Covers extent index blocks, file and directory data, and xattr index
+    /// and data blocks. Unreadable structures are skipped rather than failing
+    /// the scan, since callers use this to look for damage.
+    pub fn referenced_blocks(&mut self) -> Result<HashSet<u32>> {
+        let mut referenced = HashSet::new();
+
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let inode = self.read_inode(inode_num)?;
+            referenced.extend(self.inode_blocks(&inode));
+        }
+
+        Ok(referenced)
+    }
+
+    /// Blocks one inode references, as `referenced_blocks` counts them
+    ///
+    /// Index blocks past the end of the image are left out; extents are
+    /// reported as stored, so callers can range-check them.
+    pub(crate) fn inode_blocks(&mut self, inode: &Inode) -> Vec<u32> {
+        let nr_blocks = self.superblock.nr_blocks;
+        let mut blocks = Vec::new();
+
+        if inode.ei_block != 0 && inode.ei_block < nr_blocks && !inode.is_symlink() {
+            blocks.push(inode.ei_block);
+            if let Ok(ei) = self.read_extent_index(inode) {
+                for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+                    blocks.extend(extent.ee_start..extent.ee_start.saturating_add(extent.ee_len));
+                }
+            }
+        }
+
+        if inode.xattr_block != 0 && inode.xattr_block < nr_blocks {
+            blocks.push(inode.xattr_block);
+            if let Ok(index) = crate::xattr::read_xattr_index(self, inode.xattr_block) {
+                for extent in index.extents.iter().take_while(|e| !e.is_empty()) {
+                    blocks.extend(extent.ee_start..extent.ee_start.saturating_add(extent.ee_len));
+                }
+            }
+        }
+
+        blocks
+    }
+
     /// Calculate optimal extent size based on file size
     pub fn calc_optimal_extent_size(&self, current_blocks: u32, needs_metadata: bool) -> u32 {
         // Determine maximum based on metadata requirement
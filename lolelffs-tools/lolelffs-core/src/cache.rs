@@ -0,0 +1,202 @@
+//! A bounded cache of decompressed file blocks
+//!
+//! Decompressing the same blocks over and over (shared libraries read by
+//! many processes through FUSE, say) costs CPU for nothing. `BlockCache`
+//! keeps the decoded contents of compressed blocks keyed by inode and
+//! logical block, evicting the least recently used once `capacity` blocks
+//! are held. Uncompressed blocks are cheap to read again and never cached.
+//!
+//! Entries are dropped when their file is rewritten or its inode freed, so
+//! the cache never outlives the data it was decoded from.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Default number of decompressed blocks a handle keeps (1 MiB)
+pub const LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS: usize = 256;
+
+/// Counters describing how well a block cache is doing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads of compressed blocks served from the cache
+    pub hits: u64,
+    /// Reads of compressed blocks that had to be decompressed
+    pub misses: u64,
+    /// Blocks dropped to make room for another
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Fraction of lookups served from the cache (0 before any lookup)
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// LRU cache of decompressed blocks keyed by (inode, logical block)
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<(u32, u32), Vec<u8>>,
+    /// Least recently used at the front
+    order: VecDeque<(u32, u32)>,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` blocks (0 disables it)
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Most blocks kept at once
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of blocks currently cached
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether no block is cached
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Cache counters since the cache was created
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Change the capacity, evicting blocks beyond the new one
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink_to(capacity);
+    }
+
+    /// Decoded contents of a block, counting a hit or a miss
+    pub fn get(&mut self, inode_num: u32, logical_block: u32) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let key = (inode_num, logical_block);
+        match self.blocks.get(&key) {
+            Some(block) => {
+                self.stats.hits += 1;
+                let block = block.clone();
+                if let Some(pos) = self.order.iter().position(|k| *k == key) {
+                    self.order.remove(pos);
+                }
+                self.order.push_back(key);
+                Some(block)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Remember the decoded contents of a block
+    pub fn insert(&mut self, inode_num: u32, logical_block: u32, block: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (inode_num, logical_block);
+        if self.blocks.contains_key(&key) {
+            self.order.retain(|k| *k != key);
+        } else {
+            self.shrink_to(self.capacity - 1);
+        }
+        self.blocks.insert(key, block);
+        self.order.push_back(key);
+    }
+
+    /// Drop every block of `inode_num`
+    pub fn invalidate(&mut self, inode_num: u32) {
+        let before = self.blocks.len();
+        self.blocks.retain(|&(ino, _), _| ino != inode_num);
+        if self.blocks.len() != before {
+            self.order.retain(|&(ino, _)| ino != inode_num);
+        }
+    }
+
+    /// Drop every block
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.order.clear();
+    }
+
+    fn shrink_to(&mut self, len: usize) {
+        while self.blocks.len() > len {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.blocks.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+    use crate::types::*;
+
+    #[test]
+    fn test_block_cache_lru() {
+        let mut cache = BlockCache::new(2);
+        cache.insert(1, 0, vec![1]);
+        cache.insert(1, 1, vec![2]);
+        assert_eq!(cache.get(1, 0), Some(vec![1]));
+        cache.insert(2, 0, vec![3]);
+        // (1, 1) was the least recently used
+        assert_eq!(cache.get(1, 1), None);
+        assert_eq!(cache.get(1, 0), Some(vec![1]));
+        cache.invalidate(1);
+        assert_eq!(cache.get(1, 0), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            *cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                evictions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_fs_block_cache() {
+        let (_path, mut fs) = temp_image("cache.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, &vec![7u8; 3 * LOLELFFS_BLOCK_SIZE as usize])
+            .unwrap();
+
+        fs.read_file(ino).unwrap();
+        fs.read_file(ino).unwrap();
+        let stats = fs.stats().block_cache;
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert_eq!(stats.hit_rate(), 0.5);
+
+        // Rewriting the file drops its cached blocks
+        let data = vec![9u8; 2 * LOLELFFS_BLOCK_SIZE as usize];
+        fs.write_file(ino, &data).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), data);
+
+        fs.set_block_cache_size(0);
+        fs.read_file(ino).unwrap();
+        assert_eq!(fs.stats().block_cache.hits, 3);
+    }
+}
@@ -0,0 +1,146 @@
+//! Compacting copies of an image
+//!
+//! Long-lived images accumulate fragmented files, scattered free space and
+//! directory blocks with holes. Cloning rebuilds the tree into a fresh image
+//! with the same tunables, where every file is written in one go and so gets
+//! the fewest extents the allocator can give it, leaving free space in one
+//! run at the end. The clone can then be shrunk to the smallest size that
+//! holds it. It gets a new UUID; an encrypted source is re-encrypted under a
+//! new master key wrapped with the same password.
+
+use crate::fs::LolelfFs;
+use crate::sync::{SyncOptions, SyncStats};
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Options for `LolelfFs::clone_to`
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// Shrink the clone to the minimum size that fits its contents
+    pub minimize: bool,
+    /// Password of an encrypted source, reused for the clone
+    pub password: Option<String>,
+}
+
+impl LolelfFs {
+    /// Rebuild this image's tree into a new image at `path`
+    ///
+    /// The clone starts at the source's size and inode count and keeps its
+    /// label, compression settings and exclusions, extent limits, directory
+    /// checksums, ordered writes and reflinks.
+    pub fn clone_to<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        opts: &CloneOptions,
+    ) -> Result<(LolelfFs, SyncStats)> {
+        let sb = self.superblock.clone();
+        let enc_config = if sb.enc_enabled != 0 {
+            let Some(password) = opts.password.clone() else {
+                bail!("The source is encrypted; its password is needed to clone it");
+            };
+            Some((password, sb.enc_default_algo as u8, sb.enc_kdf_iterations))
+        } else {
+            None
+        };
+
+        let size = sb.nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64;
+        let mut dst = LolelfFs::create_with_encryption(path, size, enc_config)?;
+        if !sb.label().is_empty() {
+            dst.set_label(&sb.label())?;
+        }
+        dst.superblock.comp_enabled = sb.comp_enabled;
+        dst.superblock.comp_default_algo = sb.comp_default_algo;
+        dst.superblock.comp_min_block_size = sb.comp_min_block_size;
+        dst.superblock.max_extent_blocks = sb.max_extent_blocks;
+        dst.superblock.max_extent_blocks_large = sb.max_extent_blocks_large;
+        dst.write_superblock()?;
+        if !self.comp_exclude().is_empty() {
+            dst.set_comp_exclude(self.comp_exclude().to_vec())?;
+        }
+        let checksums = sb.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM);
+        if checksums != LOLELFFS_HASH_NONE {
+            dst.set_dir_checksums(checksums)?;
+        }
+        if sb.comp_features & LOLELFFS_FEATURE_ORDERED_WRITES != 0 {
+            dst.set_ordered_writes(true)?;
+        }
+        if sb.comp_features & LOLELFFS_FEATURE_REFLINK != 0 {
+            dst.set_reflink(true)?;
+        }
+
+        // The copy is this image's operation, so it reports through this
+        // image's callback
+        dst.progress = self.progress.take();
+        let synced = dst.sync_from(self, &SyncOptions { delete: false });
+        self.progress = dst.progress.take();
+        let stats = synced?;
+
+        if opts.minimize {
+            let min = dst.min_size();
+            if min < size {
+                dst.shrink(min)?;
+            }
+        }
+
+        Ok((dst, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_clone_compacts() {
+        let src_path = TempPath::new("clone-src.img");
+        let dst_path = TempPath::new("clone-dst.img");
+        let mut src = LolelfFs::create(&src_path, 8 * 1024 * 1024).unwrap();
+        src.superblock.comp_enabled = 0;
+        src.set_label("data").unwrap();
+
+        // Punch holes, then write a file that has to fill them
+        for i in 0..6 {
+            let ino = src
+                .create_file(LOLELFFS_ROOT_INO, &format!("f{}", i))
+                .unwrap();
+            src.write_file(ino, &[i as u8; 8192]).unwrap();
+        }
+        for i in (1..6).step_by(2) {
+            src.unlink(LOLELFFS_ROOT_INO, &format!("f{}", i)).unwrap();
+        }
+        let big = src.create_file(LOLELFFS_ROOT_INO, "big").unwrap();
+        src.write_file(big, &[7; 20000]).unwrap();
+        src.set_xattr(big, "user.k", b"v").unwrap();
+        assert!(src.frag_stats().unwrap().fragmented_files > 0);
+
+        let opts = CloneOptions {
+            minimize: true,
+            password: None,
+        };
+        let (mut dst, stats) = src.clone_to(&dst_path, &opts).unwrap();
+        assert_eq!(stats.created, 4);
+        let frag = dst.frag_stats().unwrap();
+        assert_eq!(frag.fragmented_files, 0);
+        assert!(frag.free_runs <= 1);
+        assert_eq!(dst.superblock.label(), "data");
+        assert!(dst.superblock.nr_blocks < src.superblock.nr_blocks);
+        assert!(src
+            .diff(
+                LOLELFFS_ROOT_INO,
+                &mut dst,
+                LOLELFFS_ROOT_INO,
+                &Default::default()
+            )
+            .unwrap()
+            .is_empty());
+        drop(dst);
+
+        let fs = LolelfFs::open(&dst_path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&dst_path).unwrap().len(),
+            fs.superblock.nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64
+        );
+    }
+}
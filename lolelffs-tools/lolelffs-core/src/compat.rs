@@ -0,0 +1,347 @@
+//! Compatibility with images written by other tool versions
+//!
+//! The version field only changes with the on-disk layout. Everything else a
+//! tool may rely on is recorded in feature bits and algorithm IDs, and the
+//! tables below are the matrix of what this build implements. An image that
+//! uses anything beyond them still opens read-only: the parts that do not
+//! depend on the unknown feature read normally, a file stored with an
+//! unknown algorithm fails on its own with `FsError::Unsupported`, and
+//! directory checksums of an unknown algorithm are not verified. Writing is
+//! refused, since it could break invariants the newer tool maintains.
+
+use crate::error::FsError;
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::Result;
+
+/// `comp_features` bits this build implements
+pub const SUPPORTED_FEATURES: &[(u32, &str)] = &[
+    (LOLELFFS_FEATURE_LARGE_EXTENTS, "large_extents"),
+    (LOLELFFS_FEATURE_COMP_EXCLUDE, "comp_exclude"),
+    (LOLELFFS_FEATURE_ORDERED_WRITES, "ordered_writes"),
+    (LOLELFFS_FEATURE_REFLINK, "reflink"),
+    (LOLELFFS_FEATURE_DIR_CSUM, "dir_csum"),
+];
+
+/// `enc_features` bits this build implements
+pub const SUPPORTED_ENC_FEATURES: &[(u32, &str)] = &[
+    (LOLELFFS_ENC_FEAT_PER_FILE_KEYS, "per_file_keys"),
+    (LOLELFFS_ENC_FEAT_OPAQUE, "opaque"),
+];
+
+/// `comp_features` bits the kernel module implements; it refuses images
+/// with any other (`LOLELFFS_KERNEL_FEATURES` in lolelffs.h)
+pub const KERNEL_FEATURES: u32 = LOLELFFS_FEATURE_LARGE_EXTENTS
+    | LOLELFFS_FEATURE_COMP_EXCLUDE
+    | LOLELFFS_FEATURE_ORDERED_WRITES;
+
+/// `enc_features` bits the kernel module implements
+/// (`LOLELFFS_KERNEL_ENC_FEATURES`)
+pub const KERNEL_ENC_FEATURES: u32 = 0;
+
+/// Integrity features with a hash algorithm slot in `hash_algos`
+const HASH_FEATURES: &[(u32, &str)] = &[
+    (LOLELFFS_HASH_FEAT_CHECKSUM, "checksum"),
+    (LOLELFFS_HASH_FEAT_MANIFEST, "manifest"),
+    (LOLELFFS_HASH_FEAT_DEDUP, "dedup"),
+    (LOLELFFS_HASH_FEAT_MERKLE, "merkle"),
+];
+
+/// Whether this build can decode blocks compressed with `algo`, counting
+/// codecs registered with `compress::register_codec`
+pub fn comp_algo_supported(algo: u8) -> bool {
+    algo == LOLELFFS_COMP_NONE || crate::compress::codec(algo).is_some()
+}
+
+/// Whether this build can decrypt blocks encrypted with `algo`, counting
+/// ciphers registered with `encrypt::register_cipher`
+pub fn enc_algo_supported(algo: u8) -> bool {
+    algo == LOLELFFS_ENC_NONE || crate::encrypt::cipher(algo).is_some()
+}
+
+/// Whether this build can derive keys with `algo`
+///
+/// Only PBKDF2: there is no Argon2id implementation, although the C mkfs
+/// defaults to it.
+pub fn kdf_supported(algo: u8) -> bool {
+    algo == LOLELFFS_KDF_PBKDF2
+}
+
+/// Whether this build implements hash `algo`
+pub fn hash_algo_supported(algo: u8) -> bool {
+    matches!(
+        algo,
+        LOLELFFS_HASH_NONE
+            | LOLELFFS_HASH_CRC32C
+            | LOLELFFS_HASH_XXH64
+            | LOLELFFS_HASH_SHA256
+            | LOLELFFS_HASH_BLAKE3
+    )
+}
+
+/// Bits of `flags` missing from `known`
+fn unknown_bits(flags: u32, known: &[(u32, &str)]) -> u32 {
+    known.iter().fold(flags, |rest, (bit, _)| rest & !bit)
+}
+
+impl Superblock {
+    /// Describe everything the image relies on that this build does not
+    /// implement; such images can only be opened read-only
+    pub fn compat_issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        let unknown = unknown_bits(self.comp_features, SUPPORTED_FEATURES);
+        if unknown != 0 {
+            issues.push(format!("unknown feature flags 0x{:08X}", unknown));
+        }
+        // Bits claimed by registered ciphers are theirs to understand
+        let unknown = unknown_bits(
+            self.enc_features & !crate::encrypt::cipher_features(),
+            SUPPORTED_ENC_FEATURES,
+        );
+        if unknown != 0 {
+            issues.push(format!(
+                "unknown encryption feature flags 0x{:08X}",
+                unknown
+            ));
+        }
+        if !comp_algo_supported(self.comp_default_algo as u8) {
+            issues.push(format!(
+                "default compression algorithm {} is not implemented",
+                self.comp_default_algo
+            ));
+        }
+        if self.enc_enabled != 0 {
+            if !enc_algo_supported(self.enc_default_algo as u8) {
+                issues.push(format!(
+                    "default encryption algorithm {} is not implemented",
+                    self.enc_default_algo
+                ));
+            }
+            if !kdf_supported(self.enc_kdf_algo as u8) {
+                issues.push(format!(
+                    "key derivation function {} is not implemented; encrypted files cannot be read",
+                    self.enc_kdf_algo
+                ));
+            }
+        }
+        for &(feature, name) in HASH_FEATURES {
+            let algo = self.hash_algo(feature);
+            if !hash_algo_supported(algo) {
+                issues.push(format!(
+                    "{} hash algorithm {} is not implemented",
+                    name, algo
+                ));
+            }
+        }
+
+        issues
+    }
+}
+
+/// Names of the bits of `flags` outside `allowed`, from `known` where listed
+fn bit_names(flags: u32, allowed: u32, known: &[(u32, &str)]) -> Vec<String> {
+    (0..32)
+        .map(|shift| 1u32 << shift)
+        .filter(|bit| flags & bit != 0 && allowed & bit == 0)
+        .map(
+            |bit| match known.iter().find(|(known_bit, _)| *known_bit == bit) {
+                Some((_, name)) => name.to_string(),
+                None => format!("0x{:08X}", bit),
+            },
+        )
+        .collect()
+}
+
+impl Superblock {
+    /// Describe what the image uses that the kernel module does not
+    /// implement; such images must be mounted with the FUSE driver
+    ///
+    /// The module refuses unknown feature bits itself. Inodes left on the
+    /// orphan list are not flagged anywhere it looks, and it would treat
+    /// them as in use forever. Nor are directory checksums enabled before
+    /// `LOLELFFS_FEATURE_DIR_CSUM` existed, which it would leave stale.
+    pub fn kernel_issues(&self) -> Vec<String> {
+        let mut issues: Vec<String> =
+            bit_names(self.comp_features, KERNEL_FEATURES, SUPPORTED_FEATURES)
+                .into_iter()
+                .map(|name| format!("feature {}", name))
+                .collect();
+        issues.extend(
+            bit_names(
+                self.enc_features,
+                KERNEL_ENC_FEATURES,
+                SUPPORTED_ENC_FEATURES,
+            )
+            .into_iter()
+            .map(|name| format!("encryption feature {}", name)),
+        );
+        if self.comp_features & LOLELFFS_FEATURE_DIR_CSUM == 0
+            && self.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM) != LOLELFFS_HASH_NONE
+        {
+            issues.push("directory checksums".to_string());
+        }
+        if self.last_orphan != 0 {
+            issues.push("unlinked inodes on the orphan list".to_string());
+        }
+        issues
+    }
+}
+
+/// Algorithms this build can read and write, by ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupportedAlgorithms {
+    pub compression: Vec<u8>,
+    pub encryption: Vec<u8>,
+    pub hashing: Vec<u8>,
+}
+
+/// The first algorithm in `ei` this build cannot decode, described for
+/// error messages
+fn unsupported_in(ei: &ExtentIndex) -> Option<String> {
+    ei.extents
+        .iter()
+        .take_while(|e| !e.is_empty())
+        .find_map(|extent| {
+            let (comp, enc) = (extent.ee_comp_algo as u8, extent.ee_enc_algo);
+            if !comp_algo_supported(comp) {
+                Some(format!(
+                    "compression algorithm {}",
+                    algo_label(comp, crate::compress::get_algo_name(comp))
+                ))
+            } else if !enc_algo_supported(enc) {
+                Some(format!(
+                    "encryption algorithm {}",
+                    algo_label(enc, crate::encrypt::get_algo_name(enc))
+                ))
+            } else {
+                None
+            }
+        })
+}
+
+/// An algorithm's name, or its ID when this build has never heard of it
+fn algo_label(algo: u8, name: &str) -> String {
+    if name == "unknown" {
+        algo.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+impl LolelfFs {
+    /// Algorithms compiled into this build
+    pub fn supported_algorithms(&self) -> SupportedAlgorithms {
+        SupportedAlgorithms {
+            compression: (0..=u8::MAX).filter(|&a| comp_algo_supported(a)).collect(),
+            encryption: (0..=u8::MAX).filter(|&a| enc_algo_supported(a)).collect(),
+            hashing: (0..=u8::MAX).filter(|&a| hash_algo_supported(a)).collect(),
+        }
+    }
+
+    /// Describe the algorithm a file is stored with that this build cannot
+    /// decode, e.g. "compression algorithm zstd", or `None` if it can be read
+    pub fn unsupported_algorithm(&mut self, inode_num: u32) -> Result<Option<String>> {
+        let inode = self.read_inode(inode_num)?;
+        if inode.ei_block == 0 || inode.is_symlink() {
+            return Ok(None);
+        }
+        let ei = self.read_extent_index(&inode)?;
+        Ok(unsupported_in(&ei))
+    }
+
+    /// Fail with `FsError::Unsupported` if a file has extents stored with an
+    /// algorithm this build cannot decode
+    pub(crate) fn check_extents_supported(&self, inode_num: u32, ei: &ExtentIndex) -> Result<()> {
+        match unsupported_in(ei) {
+            Some(what) => Err(FsError::Unsupported {
+                inode: inode_num,
+                what,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::OpenMode;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_unknown_features_open_read_only() {
+        let (path, mut fs) = temp_image("compat.img");
+        let good = fs.create_file(LOLELFFS_ROOT_INO, "good").unwrap();
+        fs.write_file(good, b"readable").unwrap();
+        let bad = fs.create_file(LOLELFFS_ROOT_INO, "bad").unwrap();
+        fs.write_file(bad, &[b'z'; 10000]).unwrap();
+        fs.set_dir_checksums(LOLELFFS_HASH_CRC32C).unwrap();
+        assert!(fs.superblock.compat_issues().is_empty());
+        assert_eq!(fs.superblock.kernel_issues(), ["feature dir_csum"]);
+        let mut sb = fs.superblock.clone();
+        sb.comp_features &= !LOLELFFS_FEATURE_DIR_CSUM;
+        assert_eq!(sb.kernel_issues(), ["directory checksums"]);
+
+        // What a newer tool might leave behind: a feature bit, a codec and a
+        // checksum algorithm this build has never heard of
+        let inode = fs.read_inode(bad).unwrap();
+        let mut ei = fs.read_extent_index(&inode).unwrap();
+        ei.extents[0].ee_comp_algo = 9;
+        fs.write_extent_index(inode.ei_block, &ei).unwrap();
+        fs.superblock.comp_features |= 0x8000;
+        fs.superblock
+            .set_hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM, 0x42);
+        fs.write_superblock().unwrap();
+        drop(fs);
+
+        let err = LolelfFs::open(&path).err().unwrap().to_string();
+        assert!(err.contains("0x00008000"), "{}", err);
+        assert!(err.contains("read-only"), "{}", err);
+
+        let mut fs = LolelfFs::open_with_mode(&path, OpenMode::ReadOnly).unwrap();
+        assert_eq!(fs.superblock.compat_issues().len(), 2);
+        let mut sb = fs.superblock.clone();
+        sb.comp_features |= LOLELFFS_FEATURE_REFLINK;
+        sb.enc_features |= LOLELFFS_ENC_FEAT_PER_FILE_KEYS;
+        assert_eq!(
+            sb.kernel_issues(),
+            [
+                "feature reflink",
+                "feature dir_csum",
+                "feature 0x00008000",
+                "encryption feature per_file_keys"
+            ]
+        );
+        assert_eq!(fs.unsupported_algorithm(good).unwrap(), None);
+        assert_eq!(
+            fs.unsupported_algorithm(bad).unwrap().as_deref(),
+            Some("compression algorithm 9")
+        );
+        let algos = fs.supported_algorithms();
+        assert!(algos.compression.contains(&LOLELFFS_COMP_LZ4));
+        assert_eq!(
+            algos.compression.contains(&LOLELFFS_COMP_ZSTD),
+            cfg!(feature = "compress-zstd")
+        );
+        assert_eq!(fs.list_dir(LOLELFFS_ROOT_INO).unwrap().len(), 2);
+        assert_eq!(fs.read_file(good).unwrap(), b"readable");
+        let err = fs.read_file(bad).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FsError>(),
+            Some(FsError::Unsupported { inode, .. }) if *inode == bad
+        ));
+
+        // Exports fail on the file, or leave it out when asked to
+        assert!(fs.export_tar(std::io::sink(), LOLELFFS_ROOT_INO).is_err());
+        let opts = crate::archive::TarExportOptions {
+            skip_unsupported: true,
+            ..Default::default()
+        };
+        let stats = fs
+            .export_tar_with(std::io::sink(), LOLELFFS_ROOT_INO, &opts)
+            .unwrap();
+        assert_eq!((stats.files, stats.unsupported), (1, 1));
+    }
+}
@@ -0,0 +1,772 @@
+//! Compression support for lolelffs
+//!
+//! Provides compression and decompression using LZ4, zlib, and zstd algorithms.
+//! Matches the kernel module compression behavior. zstd is optional (the
+//! `compress-zstd` feature, on by default); see `compat` for what a build
+//! supports.
+//!
+//! Algorithms are `Codec`s looked up by ID in a process-wide registry, so
+//! other crates can add their own with `register_codec` and have them used
+//! for writes, reads, `tune` and everything else that takes an algorithm.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Error for zstd blocks in builds without the codec
+#[cfg(not(feature = "compress-zstd"))]
+const ZSTD_MISSING: &str = "zstd support is not built in (enable the compress-zstd feature)";
+
+/// First algorithm ID available to codecs registered by other crates; IDs
+/// below it are reserved for lolelffs itself
+pub const FIRST_CUSTOM_CODEC_ID: u8 = 128;
+
+/// A block compression algorithm
+///
+/// Implementations work on single blocks: `compress` gets exactly
+/// `LOLELFFS_BLOCK_SIZE` bytes, and `decompress` gets the stored payload,
+/// which is zero-padded to the block size, so the format must know where its
+/// own stream ends.
+pub trait Codec: Send + Sync {
+    /// ID recorded in extents and in the superblock's default algorithm
+    fn id(&self) -> u8;
+
+    /// Name used on the command line, lowercase
+    fn name(&self) -> &'static str;
+
+    /// Compress a block, or return `None` if that would not save space
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Decode a payload into exactly `expected_size` bytes
+    fn decompress(&self, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>>;
+}
+
+struct Lz4Codec;
+
+impl Codec for Lz4Codec {
+    fn id(&self) -> u8 {
+        LOLELFFS_COMP_LZ4
+    }
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        compress_lz4(data)
+    }
+    fn decompress(&self, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+        decompress_lz4(compressed, expected_size)
+    }
+}
+
+struct ZlibCodec;
+
+impl Codec for ZlibCodec {
+    fn id(&self) -> u8 {
+        LOLELFFS_COMP_ZLIB
+    }
+    fn name(&self) -> &'static str {
+        "zlib"
+    }
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        compress_zlib(data)
+    }
+    fn decompress(&self, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+        decompress_zlib(compressed, expected_size)
+    }
+}
+
+#[cfg(feature = "compress-zstd")]
+struct ZstdCodec;
+
+#[cfg(feature = "compress-zstd")]
+impl Codec for ZstdCodec {
+    fn id(&self) -> u8 {
+        LOLELFFS_COMP_ZSTD
+    }
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+    fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        compress_zstd(data)
+    }
+    fn decompress(&self, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+        decompress_zstd(compressed, expected_size)
+    }
+}
+
+/// Every codec this process knows, built-in ones first
+fn registry() -> &'static RwLock<Vec<Arc<dyn Codec>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Codec>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        RwLock::new(vec![
+            Arc::new(Lz4Codec),
+            Arc::new(ZlibCodec),
+            #[cfg(feature = "compress-zstd")]
+            Arc::new(ZstdCodec),
+        ])
+    })
+}
+
+/// Make a codec available to every image opened by this process
+///
+/// Its ID must be at least `FIRST_CUSTOM_CODEC_ID`, and neither the ID nor
+/// the name may already be taken. Images written with it can only be read
+/// where the same codec is registered; elsewhere (including the kernel
+/// module) its files fail with `FsError::Unsupported` and the image opens
+/// read-only if it is the default algorithm.
+pub fn register_codec(codec: Arc<dyn Codec>) -> Result<()> {
+    let (id, name) = (codec.id(), codec.name());
+    if id < FIRST_CUSTOM_CODEC_ID {
+        bail!(
+            "Codec ID {} is reserved; custom codecs use {} to 255",
+            id,
+            FIRST_CUSTOM_CODEC_ID
+        );
+    }
+    if name.is_empty() || name != name.to_lowercase() || name == "none" {
+        bail!("Invalid codec name '{}' (expected a lowercase name)", name);
+    }
+
+    let mut codecs = registry().write().unwrap_or_else(|e| e.into_inner());
+    if let Some(taken) = codecs.iter().find(|c| c.id() == id || c.name() == name) {
+        bail!(
+            "Codec {} ({}) is already registered",
+            taken.id(),
+            taken.name()
+        );
+    }
+    codecs.push(codec);
+    Ok(())
+}
+
+/// The codec for algorithm `id`, if this process has one
+pub fn codec(id: u8) -> Option<Arc<dyn Codec>> {
+    let codecs = registry().read().unwrap_or_else(|e| e.into_inner());
+    codecs.iter().find(|c| c.id() == id).cloned()
+}
+
+/// All available codecs, by ID
+pub fn codecs() -> Vec<Arc<dyn Codec>> {
+    let mut codecs = registry().read().unwrap_or_else(|e| e.into_inner()).clone();
+    codecs.sort_by_key(|c| c.id());
+    codecs
+}
+
+/// Error for an algorithm without a codec
+fn missing_codec(algo: u8) -> anyhow::Error {
+    #[cfg(not(feature = "compress-zstd"))]
+    if algo == LOLELFFS_COMP_ZSTD {
+        return anyhow::anyhow!(ZSTD_MISSING);
+    }
+    anyhow::anyhow!("Unsupported compression algorithm: {}", algo)
+}
+
+/// Compress a block using the specified algorithm
+pub fn compress_block(algo: u8, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    span!("compress_block", algo);
+    if data.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!("Data must be exactly {} bytes", LOLELFFS_BLOCK_SIZE);
+    }
+    if algo == LOLELFFS_COMP_NONE {
+        return Ok(None);
+    }
+    codec(algo)
+        .ok_or_else(|| missing_codec(algo))?
+        .compress(data)
+}
+
+/// Decompress a block using the specified algorithm
+pub fn decompress_block(algo: u8, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+    span!("decompress_block", algo, len = compressed.len());
+    if algo == LOLELFFS_COMP_NONE {
+        if compressed.len() != expected_size {
+            bail!(
+                "Uncompressed data size mismatch: {} != {}",
+                compressed.len(),
+                expected_size
+            );
+        }
+        return Ok(compressed.to_vec());
+    }
+    codec(algo)
+        .ok_or_else(|| missing_codec(algo))?
+        .decompress(compressed, expected_size)
+}
+
+/// Compress using LZ4
+fn compress_lz4(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    // Don't prepend size - we track it separately in metadata
+    let compressed = lz4::block::compress(data, None, false)?;
+
+    // Only use compression if it saves space
+    if compressed.len() < data.len() {
+        Ok(Some(compressed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Decompress using LZ4
+///
+/// On-disk payloads are zero-padded to the block size, so decoding stops as
+/// soon as `expected_size` bytes have been produced instead of requiring the
+/// input to end exactly at the last sequence.
+fn decompress_lz4(compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected_size);
+    let mut pos = 0usize;
+
+    // Read an LZ4 length extension (runs of 255 terminated by a smaller byte)
+    let read_len = |pos: &mut usize, mut len: usize| -> Result<usize> {
+        loop {
+            let byte = *compressed
+                .get(*pos)
+                .ok_or_else(|| anyhow::anyhow!("LZ4 stream truncated"))?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                return Ok(len);
+            }
+        }
+    };
+
+    while out.len() < expected_size {
+        let token = *compressed
+            .get(pos)
+            .ok_or_else(|| anyhow::anyhow!("LZ4 stream truncated"))?;
+        pos += 1;
+
+        // Literals
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len = read_len(&mut pos, lit_len)?;
+        }
+        let literals = compressed
+            .get(pos..pos + lit_len)
+            .ok_or_else(|| anyhow::anyhow!("LZ4 literals out of bounds"))?;
+        out.extend_from_slice(literals);
+        pos += lit_len;
+
+        if out.len() >= expected_size {
+            break;
+        }
+
+        // Match copy
+        let offset_bytes = compressed
+            .get(pos..pos + 2)
+            .ok_or_else(|| anyhow::anyhow!("LZ4 stream truncated"))?;
+        let offset = u16::from_le_bytes([offset_bytes[0], offset_bytes[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out.len() {
+            bail!("Invalid LZ4 match offset {}", offset);
+        }
+
+        let mut match_len = (token & 0x0F) as usize;
+        if match_len == 15 {
+            match_len = read_len(&mut pos, match_len)?;
+        }
+        match_len += 4;
+
+        let start = out.len() - offset;
+        for i in 0..match_len {
+            let byte = out[start + i];
+            out.push(byte);
+        }
+    }
+
+    if out.len() != expected_size {
+        bail!(
+            "Decompressed size mismatch: {} != {}",
+            out.len(),
+            expected_size
+        );
+    }
+
+    Ok(out)
+}
+
+/// Compress using zlib
+fn compress_zlib(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    // Only use compression if it saves space
+    if compressed.len() < data.len() {
+        Ok(Some(compressed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Decompress using zlib
+fn decompress_zlib(compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+    // The read-side decoder stops at the end of the zlib stream, ignoring padding
+    let mut decoder = flate2::read::ZlibDecoder::new(compressed);
+    let mut decompressed = Vec::with_capacity(expected_size);
+    decoder.read_to_end(&mut decompressed)?;
+
+    if decompressed.len() != expected_size {
+        bail!(
+            "Decompressed size mismatch: {} != {}",
+            decompressed.len(),
+            expected_size
+        );
+    }
+
+    Ok(decompressed)
+}
+
+/// Compress using zstd
+#[cfg(feature = "compress-zstd")]
+fn compress_zstd(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let compressed = zstd::encode_all(data, 3)?;
+
+    // Only use compression if it saves space
+    if compressed.len() < data.len() {
+        Ok(Some(compressed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Decompress using zstd
+#[cfg(feature = "compress-zstd")]
+fn decompress_zstd(compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+    // Decode a single frame so the zero padding after it is ignored
+    let mut decoder = zstd::stream::read::Decoder::new(compressed)?.single_frame();
+    let mut decompressed = Vec::with_capacity(expected_size);
+    decoder.read_to_end(&mut decompressed)?;
+
+    if decompressed.len() != expected_size {
+        bail!(
+            "Decompressed size mismatch: {} != {}",
+            decompressed.len(),
+            expected_size
+        );
+    }
+
+    Ok(decompressed)
+}
+
+/// Get the name of a compression algorithm
+///
+/// zstd is named even in builds without it, so messages can say what is
+/// missing.
+pub fn get_algo_name(algo: u8) -> &'static str {
+    match algo {
+        LOLELFFS_COMP_NONE => "none",
+        LOLELFFS_COMP_ZSTD => "zstd",
+        _ => codec(algo).map_or("unknown", |c| c.name()),
+    }
+}
+
+/// Parse a compression algorithm name
+pub fn parse_algo_name(name: &str) -> Result<u8> {
+    let name = name.to_lowercase();
+    if name == "none" {
+        return Ok(LOLELFFS_COMP_NONE);
+    }
+    let codecs = codecs();
+    if let Some(codec) = codecs.iter().find(|c| c.name() == name) {
+        return Ok(codec.id());
+    }
+    let known: Vec<&str> = codecs.iter().map(|c| c.name()).collect();
+    bail!(
+        "Unknown compression algorithm '{}' (expected none, {})",
+        name,
+        known.join(", ")
+    )
+}
+
+/// Parse a compression exclusion pattern
+///
+/// Patterns are globs matched case-insensitively against file names; a bare
+/// extension such as `jpg` or `.jpg` is shorthand for `*.jpg`.
+pub fn parse_exclude_pattern(spec: &str) -> Result<glob::Pattern> {
+    if spec.is_empty() || spec.contains('/') || spec.contains('\0') {
+        bail!(
+            "Invalid exclusion pattern '{}' (expected a file name glob)",
+            spec
+        );
+    }
+    let glob = if spec.contains(['*', '?', '[']) {
+        spec.to_string()
+    } else {
+        format!("*.{}", spec.trim_start_matches('.'))
+    };
+    glob::Pattern::new(&glob).map_err(|e| anyhow::anyhow!("Invalid pattern '{}': {}", spec, e))
+}
+
+/// Block counts and sizes for one compression algorithm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlgoStats {
+    /// Data blocks encoded with the algorithm
+    pub blocks: u64,
+    /// File bytes those blocks hold
+    pub logical: u64,
+    /// Encoded bytes inside those blocks (each still occupies a whole block)
+    pub stored: u64,
+}
+
+/// How a file's data was compressed
+#[derive(Debug, Clone, Default)]
+pub struct CompStats {
+    /// Logical file size in bytes
+    pub size: u64,
+    /// Data blocks allocated on disk
+    pub blocks: u64,
+    /// Per-algorithm figures, keyed by `LOLELFFS_COMP_*`
+    pub by_algo: BTreeMap<u8, AlgoStats>,
+    /// False when encrypted blocks could not be measured (image locked)
+    pub measured: bool,
+}
+
+impl CompStats {
+    /// Encoded bytes across all algorithms
+    pub fn stored(&self) -> u64 {
+        self.by_algo.values().map(|a| a.stored).sum()
+    }
+
+    /// File bytes held in allocated blocks (holes excluded)
+    pub fn logical(&self) -> u64 {
+        self.by_algo.values().map(|a| a.logical).sum()
+    }
+
+    /// Fold another file's figures into these
+    pub fn add(&mut self, other: &CompStats) {
+        self.size += other.size;
+        self.blocks += other.blocks;
+        self.measured &= other.measured;
+        for (algo, stats) in &other.by_algo {
+            let entry = self.by_algo.entry(*algo).or_default();
+            entry.blocks += stats.blocks;
+            entry.logical += stats.logical;
+            entry.stored += stats.stored;
+        }
+    }
+}
+
+impl LolelfFs {
+    /// Measure how a file's blocks were compressed
+    ///
+    /// Compressed payloads are padded to a whole block, so `blocks` is the
+    /// real on-disk cost; `stored` is the size the encoder produced, found by
+    /// re-encoding each decoded block with its extent's algorithm.
+    /// Directories and symlinks report only their size.
+    pub fn comp_stats(&mut self, inode_num: u32) -> Result<CompStats> {
+        let inode = self.read_inode(inode_num)?;
+        let mut stats = CompStats {
+            size: inode.i_size as u64,
+            measured: true,
+            ..Default::default()
+        };
+        if !inode.is_file() || inode.ei_block == 0 {
+            return Ok(stats);
+        }
+
+        let ei = self.read_extent_index(&inode)?;
+        let key = self.file_key(inode_num, &inode);
+        let block_size = LOLELFFS_BLOCK_SIZE as u64;
+        for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+            let algo = extent.ee_comp_algo as u8;
+            for logical_block in extent.ee_block..extent.ee_block + extent.ee_len {
+                let logical = (inode.i_size as u64)
+                    .saturating_sub(logical_block as u64 * block_size)
+                    .min(block_size);
+                stats.blocks += 1;
+                let entry = stats.by_algo.entry(algo).or_default();
+                entry.blocks += 1;
+                entry.logical += logical;
+
+                if algo == LOLELFFS_COMP_NONE {
+                    entry.stored += logical;
+                } else if extent.ee_enc_algo != LOLELFFS_ENC_NONE && !self.enc_unlocked {
+                    stats.measured = false;
+                } else {
+                    let data = self.read_logical_block(&ei, &key, logical_block)?;
+                    entry.stored +=
+                        compress_block(algo, &data)?.map_or(block_size, |c| c.len() as u64);
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Name globs whose files are written uncompressed
+    pub fn comp_exclude(&self) -> &[glob::Pattern] {
+        &self.comp_exclude
+    }
+
+    /// Check a file name against the compression exclusion list
+    pub fn is_comp_excluded(&self, name: &str) -> bool {
+        let opts = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        self.comp_exclude.iter().any(|p| p.matches_with(name, opts))
+    }
+
+    /// Replace the compression exclusion list stored in the superblock block
+    ///
+    /// Files are flagged when created or renamed, so the new list applies to
+    /// files created from now on; existing files keep their flag.
+    pub fn set_comp_exclude(&mut self, patterns: Vec<glob::Pattern>) -> Result<()> {
+        let mut list = Vec::new();
+        for pattern in &patterns {
+            list.extend_from_slice(pattern.as_str().as_bytes());
+            list.push(0);
+        }
+        list.push(0);
+        if list.len() > LOLELFFS_BLOCK_SIZE as usize - LOLELFFS_COMP_EXCLUDE_OFFSET {
+            bail!(
+                "Compression exclusion list is too long ({} bytes)",
+                list.len()
+            );
+        }
+
+        let mut block = self.read_block(0)?;
+        block[LOLELFFS_COMP_EXCLUDE_OFFSET..].fill(0);
+        block[LOLELFFS_COMP_EXCLUDE_OFFSET..LOLELFFS_COMP_EXCLUDE_OFFSET + list.len()]
+            .copy_from_slice(&list);
+        self.write_meta_block(0, &block)?;
+
+        if patterns.is_empty() {
+            self.superblock.comp_features &= !LOLELFFS_FEATURE_COMP_EXCLUDE;
+        } else {
+            self.superblock.comp_features |= LOLELFFS_FEATURE_COMP_EXCLUDE;
+        }
+        self.write_superblock()?;
+
+        self.comp_exclude = patterns;
+        Ok(())
+    }
+
+    /// Load the exclusion list recorded in block 0, if the feature is set
+    pub(crate) fn load_comp_exclude(&mut self) -> Result<()> {
+        self.comp_exclude.clear();
+        if self.superblock.comp_features & LOLELFFS_FEATURE_COMP_EXCLUDE == 0 {
+            return Ok(());
+        }
+
+        let block = self.read_block(0)?;
+        for entry in block[LOLELFFS_COMP_EXCLUDE_OFFSET..].split(|&b| b == 0) {
+            if entry.is_empty() {
+                break;
+            }
+            let glob = String::from_utf8_lossy(entry);
+            self.comp_exclude.push(
+                glob::Pattern::new(&glob)
+                    .map_err(|e| anyhow::anyhow!("Corrupt exclusion pattern '{}': {}", glob, e))?,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    /// Stores a block without its trailing zeros, behind a length prefix
+    struct TrimCodec;
+
+    impl Codec for TrimCodec {
+        fn id(&self) -> u8 {
+            200
+        }
+        fn name(&self) -> &'static str {
+            "trim"
+        }
+        fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+            let len = data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            if len + 2 >= data.len() {
+                return Ok(None);
+            }
+            let mut out = (len as u16).to_le_bytes().to_vec();
+            out.extend_from_slice(&data[..len]);
+            Ok(Some(out))
+        }
+        fn decompress(&self, compressed: &[u8], expected_size: usize) -> Result<Vec<u8>> {
+            let len = u16::from_le_bytes([compressed[0], compressed[1]]) as usize;
+            let mut out = compressed[2..2 + len].to_vec();
+            out.resize(expected_size, 0);
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        register_codec(Arc::new(TrimCodec)).unwrap();
+        assert!(register_codec(Arc::new(TrimCodec)).is_err());
+        assert!(register_codec(Arc::new(Lz4Codec)).is_err());
+        assert_eq!(parse_algo_name("TRIM").unwrap(), 200);
+        assert_eq!(get_algo_name(200), "trim");
+        assert!(codecs().iter().any(|c| c.id() == LOLELFFS_COMP_LZ4));
+
+        let (_path, mut fs) = temp_image("codec.img");
+        let params = crate::tune::TuneParams {
+            comp_algo: Some(200),
+            comp_enabled: Some(true),
+            ..Default::default()
+        };
+        fs.tune(&params, None).unwrap();
+        let mut data = vec![0u8; 3 * LOLELFFS_BLOCK_SIZE as usize];
+        data[0] = 1;
+        data[LOLELFFS_BLOCK_SIZE as usize + 10] = 2;
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "sparse").unwrap();
+        fs.write_file(ino, &data).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), data);
+        assert_eq!(fs.comp_stats(ino).unwrap().by_algo[&200].blocks, 3);
+        assert!(fs.superblock.compat_issues().is_empty());
+    }
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        let data = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        let compressed = compress_block(LOLELFFS_COMP_LZ4, &data).unwrap();
+
+        if let Some(comp_data) = compressed {
+            let decompressed = decompress_block(LOLELFFS_COMP_LZ4, &comp_data, data.len()).unwrap();
+            assert_eq!(data, decompressed);
+        }
+    }
+
+    #[test]
+    fn test_zlib_roundtrip() {
+        let data = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        let compressed = compress_block(LOLELFFS_COMP_ZLIB, &data).unwrap();
+
+        if let Some(comp_data) = compressed {
+            let decompressed =
+                decompress_block(LOLELFFS_COMP_ZLIB, &comp_data, data.len()).unwrap();
+            assert_eq!(data, decompressed);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "compress-zstd")]
+    fn test_zstd_roundtrip() {
+        let data = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        let compressed = compress_block(LOLELFFS_COMP_ZSTD, &data).unwrap();
+
+        if let Some(comp_data) = compressed {
+            let decompressed =
+                decompress_block(LOLELFFS_COMP_ZSTD, &comp_data, data.len()).unwrap();
+            assert_eq!(data, decompressed);
+        }
+    }
+
+    #[test]
+    fn test_padded_roundtrip() {
+        // Payloads are stored zero-padded to a full block on disk
+        let data: Vec<u8> = (0..LOLELFFS_BLOCK_SIZE)
+            .map(|i| (i % 251) as u8 / 8)
+            .collect();
+
+        for algo in [LOLELFFS_COMP_LZ4, LOLELFFS_COMP_ZLIB, LOLELFFS_COMP_ZSTD] {
+            if !crate::compat::comp_algo_supported(algo) {
+                continue;
+            }
+            let compressed = compress_block(algo, &data).unwrap().unwrap();
+            let mut padded = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+            padded[..compressed.len()].copy_from_slice(&compressed);
+
+            let decompressed = decompress_block(algo, &padded, data.len()).unwrap();
+            assert_eq!(data, decompressed, "{}", get_algo_name(algo));
+        }
+    }
+
+    #[test]
+    fn test_comp_stats() {
+        let (_path, mut fs) = temp_image("compstat.img");
+
+        // Two compressible blocks, one incompressible, and a partial tail
+        // (which is never compressed)
+        let mut data = vec![b'a'; 2 * LOLELFFS_BLOCK_SIZE as usize];
+        let mut noise = 0x1234_5678u32;
+        for _ in 0..LOLELFFS_BLOCK_SIZE {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            data.push(noise as u8);
+        }
+        data.extend_from_slice(&[b'z'; 100]);
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "mixed").unwrap();
+        fs.write_file(ino, &data).unwrap();
+
+        let stats = fs.comp_stats(ino).unwrap();
+        assert!(stats.measured);
+        assert_eq!(stats.size, data.len() as u64);
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.logical(), data.len() as u64);
+        let lz4 = stats.by_algo[&LOLELFFS_COMP_LZ4];
+        let none = stats.by_algo[&LOLELFFS_COMP_NONE];
+        assert_eq!((lz4.blocks, none.blocks), (2, 2));
+        assert_eq!(none.stored, LOLELFFS_BLOCK_SIZE as u64 + 100);
+        assert!(lz4.stored < lz4.logical / 10);
+
+        let mut total = CompStats {
+            measured: true,
+            ..Default::default()
+        };
+        total.add(&stats);
+        total.add(&fs.comp_stats(LOLELFFS_ROOT_INO).unwrap());
+        assert_eq!(total.blocks, 4);
+        assert_eq!(total.stored(), stats.stored());
+    }
+
+    #[test]
+    fn test_comp_exclude_list() {
+        let (path, mut fs) = temp_image("exclude.img");
+        let patterns = vec![
+            parse_exclude_pattern("jpg").unwrap(),
+            parse_exclude_pattern("*.zst").unwrap(),
+        ];
+        assert_eq!(patterns[0].as_str(), "*.jpg");
+        assert!(parse_exclude_pattern("dir/*.jpg").is_err());
+        fs.set_comp_exclude(patterns).unwrap();
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(fs.comp_exclude().len(), 2);
+        assert!(fs.is_comp_excluded("Photo.JPG"));
+        assert!(!fs.is_comp_excluded("notes.txt"));
+
+        let data = vec![0u8; 4 * LOLELFFS_BLOCK_SIZE as usize];
+        let photo = fs.create_file(LOLELFFS_ROOT_INO, "photo.jpg").unwrap();
+        let notes = fs.create_file(LOLELFFS_ROOT_INO, "notes.txt").unwrap();
+        fs.write_file(photo, &data).unwrap();
+        fs.write_file(notes, &data).unwrap();
+        let comp_algo = |fs: &mut LolelfFs, ino| {
+            let inode = fs.read_inode(ino).unwrap();
+            fs.read_extent_index(&inode).unwrap().extents[0].ee_comp_algo as u8
+        };
+        assert_eq!(comp_algo(&mut fs, photo), LOLELFFS_COMP_NONE);
+        assert_eq!(comp_algo(&mut fs, notes), LOLELFFS_COMP_LZ4);
+
+        // The flag follows renames
+        fs.rename(
+            LOLELFFS_ROOT_INO,
+            "notes.txt",
+            LOLELFFS_ROOT_INO,
+            "notes.zst",
+        )
+        .unwrap();
+        fs.write_file(notes, &data).unwrap();
+        assert_eq!(comp_algo(&mut fs, notes), LOLELFFS_COMP_NONE);
+        assert_eq!(fs.read_file(notes).unwrap(), data);
+
+        fs.set_comp_exclude(Vec::new()).unwrap();
+        assert_eq!(
+            fs.superblock.comp_features & LOLELFFS_FEATURE_COMP_EXCLUDE,
+            0
+        );
+    }
+}
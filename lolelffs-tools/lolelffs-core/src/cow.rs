@@ -0,0 +1,366 @@
+//! Copy-on-write scratch overlays
+//!
+//! `open_cow` opens an image so that every write lands in a separate scratch
+//! file and the image itself is never modified. Reads take a block from the
+//! scratch file once it has been written there and fall back to the image
+//! otherwise, so repairs, recompression or imports can be tried against a
+//! production image and thrown away by deleting the scratch file. Reopening
+//! the same scratch file continues where the last session stopped.
+//!
+//! `commit` folds the scratch blocks into the image and `discard` drops
+//! them, leaving the overlay attached and empty either way.
+//!
+//! The scratch file holds a header block, a bitmap of the blocks it holds,
+//! and then block N of the image at block `1 + bitmap_blocks + N`. Blocks
+//! never written stay holes, so the file is only as large on disk as the
+//! changes.
+
+use crate::fs::{LolelfFs, OpenMode};
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic at the start of a scratch file
+pub const LOLELFFS_COW_MAGIC: &[u8; 8] = b"LOLFSCOW";
+
+/// Scratch file format version
+pub const LOLELFFS_COW_VERSION: u32 = 1;
+
+/// Byte offset of the commit state in the scratch header
+const COW_STATE_OFFSET: u64 = 32;
+/// Header state while blocks are being copied into the image
+const COW_STATE_COMMITTING: u32 = 1;
+
+/// Blocks of an image redirected to a scratch file
+pub(crate) struct CowOverlay {
+    file: File,
+    /// Image file the blocks belong to, and where the filesystem starts in it
+    base: PathBuf,
+    base_offset: u64,
+    /// Blocks in the image the overlay was created for
+    nr_blocks: u32,
+    /// One bit per image block, set once the block lives in the scratch file
+    present: Vec<u8>,
+}
+
+impl CowOverlay {
+    /// Open `path` as the overlay for the image at `base_offset` in `base`
+    /// described by `sb`, creating it if it does not exist or is empty
+    ///
+    /// A commit that was interrupted is finished before returning.
+    fn open(path: &Path, base: &Path, base_offset: u64, sb: &Superblock) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open scratch file {}", path.display()))?;
+        let mut present = vec![0u8; (sb.nr_blocks as usize).div_ceil(8)];
+
+        if file.metadata()?.len() == 0 {
+            let mut header = Vec::with_capacity(LOLELFFS_BLOCK_SIZE as usize);
+            header.extend_from_slice(LOLELFFS_COW_MAGIC);
+            header.write_u32::<LittleEndian>(LOLELFFS_COW_VERSION)?;
+            header.write_u32::<LittleEndian>(sb.nr_blocks)?;
+            header.extend_from_slice(&sb.uuid);
+            header.write_u32::<LittleEndian>(0)?;
+            header.resize(LOLELFFS_BLOCK_SIZE as usize, 0);
+            file.write_all(&header)?;
+            file.write_all(&present)?;
+            file.flush()?;
+        } else {
+            let mut magic = [0u8; 8];
+            file.read_exact(&mut magic)
+                .with_context(|| format!("{} is not a scratch file", path.display()))?;
+            if &magic != LOLELFFS_COW_MAGIC {
+                bail!("{} is not a scratch file", path.display());
+            }
+            let version = file.read_u32::<LittleEndian>()?;
+            if version != LOLELFFS_COW_VERSION {
+                bail!("Unsupported scratch file version {}", version);
+            }
+            let nr_blocks = file.read_u32::<LittleEndian>()?;
+            let mut uuid = [0u8; 16];
+            file.read_exact(&mut uuid)?;
+            if nr_blocks != sb.nr_blocks || uuid != sb.uuid {
+                bail!(
+                    "Scratch file {} belongs to a different image",
+                    path.display()
+                );
+            }
+            let state = file.read_u32::<LittleEndian>()?;
+            file.seek(SeekFrom::Start(LOLELFFS_BLOCK_SIZE as u64))?;
+            file.read_exact(&mut present)
+                .context("Scratch file bitmap is truncated")?;
+
+            if state == COW_STATE_COMMITTING {
+                let mut overlay = CowOverlay {
+                    file,
+                    base: base.to_path_buf(),
+                    base_offset,
+                    nr_blocks,
+                    present,
+                };
+                overlay.commit()?;
+                return Ok(overlay);
+            }
+        }
+
+        Ok(CowOverlay {
+            file,
+            base: base.to_path_buf(),
+            base_offset,
+            nr_blocks: sb.nr_blocks,
+            present,
+        })
+    }
+
+    /// Byte offset of image block `block_num` in the scratch file
+    fn offset(&self, block_num: u32) -> u64 {
+        let bitmap_blocks = (self.present.len() as u64).div_ceil(LOLELFFS_BLOCK_SIZE as u64);
+        (1 + bitmap_blocks + block_num as u64) * LOLELFFS_BLOCK_SIZE as u64
+    }
+
+    /// Whether `block_num` has been written to the scratch file
+    pub(crate) fn contains(&self, block_num: u32) -> bool {
+        block_num < self.nr_blocks
+            && self.present[block_num as usize / 8] & (1 << (block_num % 8)) != 0
+    }
+
+    /// Number of blocks held in the scratch file
+    pub(crate) fn len(&self) -> usize {
+        self.present.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Scratch copy of `block_num`
+    pub(crate) fn read(&mut self, block_num: u32) -> Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(self.offset(block_num)))?;
+        let mut data = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Store `data` as the new contents of `block_num`
+    ///
+    /// The block is written before its bitmap bit, so a crash in between
+    /// leaves the previous contents visible rather than a torn block.
+    pub(crate) fn write(&mut self, block_num: u32, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.offset(block_num)))?;
+        self.file.write_all(data)?;
+
+        let byte = block_num as usize / 8;
+        let bit = 1u8 << (block_num % 8);
+        if self.present[byte] & bit == 0 {
+            self.present[byte] |= bit;
+            self.file
+                .seek(SeekFrom::Start(LOLELFFS_BLOCK_SIZE as u64 + byte as u64))?;
+            self.file.write_all(&[self.present[byte]])?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Make everything written to the scratch file durable
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: u32) -> Result<()> {
+        self.file.seek(SeekFrom::Start(COW_STATE_OFFSET))?;
+        self.file.write_u32::<LittleEndian>(state)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Copy every held block into the image, then empty the overlay
+    ///
+    /// Copying only starts once the scratch file is durably marked as
+    /// committing, and the mark is cleared only after the image is synced,
+    /// so replaying an interrupted commit yields the same image.
+    fn commit(&mut self) -> Result<usize> {
+        self.sync()?;
+        self.set_state(COW_STATE_COMMITTING)?;
+
+        let mut image = OpenOptions::new()
+            .write(true)
+            .open(&self.base)
+            .with_context(|| format!("Failed to open {} for writing", self.base.display()))?;
+        let blocks: Vec<u32> = (0..self.nr_blocks).filter(|&b| self.contains(b)).collect();
+        for &block_num in &blocks {
+            let data = self.read(block_num)?;
+            let offset = self.base_offset + block_num as u64 * LOLELFFS_BLOCK_SIZE as u64;
+            image.seek(SeekFrom::Start(offset))?;
+            image.write_all(&data)?;
+        }
+        image.sync_all()?;
+
+        self.clear()?;
+        Ok(blocks.len())
+    }
+
+    /// Forget every held block, shrinking the scratch file back to its header
+    fn clear(&mut self) -> Result<usize> {
+        let dropped = self.len();
+        self.present.fill(0);
+        self.file.set_len(LOLELFFS_BLOCK_SIZE as u64)?;
+        self.file
+            .seek(SeekFrom::Start(LOLELFFS_BLOCK_SIZE as u64))?;
+        self.file.write_all(&self.present)?;
+        self.set_state(0)?;
+        Ok(dropped)
+    }
+}
+
+impl LolelfFs {
+    /// Open `base` with every write redirected to `scratch`
+    ///
+    /// The image file is opened read-only and never modified; the handle
+    /// otherwise behaves like `open`. `scratch` is created if missing, and an
+    /// existing scratch file must have been made for the same image.
+    /// Resizing is refused while the overlay is in use.
+    pub fn open_cow<P: AsRef<Path>, Q: AsRef<Path>>(base: P, scratch: Q) -> Result<Self> {
+        Self::open_cow_at(base, 0, scratch)
+    }
+
+    /// `open_cow` for a filesystem that starts `offset` bytes into the file
+    pub(crate) fn open_cow_at<P: AsRef<Path>, Q: AsRef<Path>>(
+        base: P,
+        offset: u64,
+        scratch: Q,
+    ) -> Result<Self> {
+        let mut fs = Self::open_at(base.as_ref(), offset, OpenMode::ReadOnly)?;
+        fs.cow = Some(CowOverlay::open(
+            scratch.as_ref(),
+            base.as_ref(),
+            offset,
+            &fs.superblock,
+        )?);
+        fs.mode = OpenMode::ReadWrite;
+
+        // Earlier sessions may have changed the superblock or block 0
+        fs.reload_block0()?;
+        Ok(fs)
+    }
+
+    fn reload_block0(&mut self) -> Result<()> {
+        let block = self.read_block(0)?;
+        self.superblock = Self::read_superblock_at(&mut Cursor::new(block), 0)?;
+        self.load_comp_exclude()
+    }
+
+    /// Write every block held by the scratch overlay into the image
+    ///
+    /// Returns the number of blocks written. A commit interrupted by a crash
+    /// is finished the next time the scratch file is opened. The overlay
+    /// stays attached, now empty, so later writes start a new scratch set.
+    pub fn commit(&mut self) -> Result<usize> {
+        let Some(cow) = self.cow.as_mut() else {
+            bail!("Image was not opened with a scratch overlay");
+        };
+        cow.commit()
+    }
+
+    /// Drop every block held by the scratch overlay
+    ///
+    /// The handle goes back to the image's own contents. Returns the number
+    /// of blocks dropped.
+    pub fn discard(&mut self) -> Result<usize> {
+        let Some(cow) = self.cow.as_mut() else {
+            bail!("Image was not opened with a scratch overlay");
+        };
+        let dropped = cow.clear()?;
+        self.reload_block0()?;
+        Ok(dropped)
+    }
+
+    /// Whether writes go to a scratch overlay instead of the image
+    pub fn is_cow(&self) -> bool {
+        self.cow.is_some()
+    }
+
+    /// Number of blocks the scratch overlay holds (0 without one)
+    pub fn scratch_blocks(&self) -> usize {
+        self.cow.as_ref().map_or(0, |cow| cow.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_cow_overlay() {
+        let path = TempPath::new("cow.img");
+        let scratch = TempPath::new("cow.scratch");
+        let other = TempPath::new("cow-other.img");
+
+        let mut fs = LolelfFs::create(&path, 2 * 1024 * 1024).unwrap();
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, b"original").unwrap();
+        drop(fs);
+        let pristine = std::fs::read(&path).unwrap();
+
+        let mut fs = LolelfFs::open_cow(&path, &scratch).unwrap();
+        assert!(fs.is_cow());
+        fs.write_file(ino, b"experiment").unwrap();
+        let dir_ino = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        fs.set_label("scratch").unwrap();
+        assert!(fs.grow(4 * 1024 * 1024).is_err());
+        assert!(fs.scratch_blocks() > 0);
+        drop(fs);
+
+        // The image is untouched and still shows the old contents
+        assert_eq!(std::fs::read(&path).unwrap(), pristine);
+        let mut fs = LolelfFs::open_readonly(&path).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"original");
+        assert!(fs.lookup(LOLELFFS_ROOT_INO, "d").unwrap().is_none());
+        drop(fs);
+
+        // Reopening the scratch file picks up every change
+        let mut fs = LolelfFs::open_cow(&path, &scratch).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"experiment");
+        assert_eq!(fs.lookup(LOLELFFS_ROOT_INO, "d").unwrap(), Some(dir_ino));
+        assert_eq!(fs.superblock.label(), "scratch");
+        drop(fs);
+
+        // A scratch file only fits the image it was made for
+        LolelfFs::create(&other, 2 * 1024 * 1024).unwrap();
+        assert!(LolelfFs::open_cow(&other, &scratch).is_err());
+
+        // Discarding goes back to the image; committing writes into it
+        let mut fs = LolelfFs::open_cow(&path, &scratch).unwrap();
+        assert!(fs.discard().unwrap() > 0);
+        assert_eq!(fs.read_file(ino).unwrap(), b"original");
+        assert_eq!(fs.superblock.label(), "");
+        fs.write_file(ino, b"kept").unwrap();
+        assert!(fs.commit().unwrap() > 0);
+        assert_eq!(fs.scratch_blocks(), 0);
+        assert_eq!(fs.read_file(ino).unwrap(), b"kept");
+        drop(fs);
+        let mut fs = LolelfFs::open_readonly(&path).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"kept");
+        assert!(fs.commit().is_err());
+        drop(fs);
+
+        // An interrupted commit is finished when the scratch file is reopened
+        let mut fs = LolelfFs::open_cow(&path, &scratch).unwrap();
+        fs.write_file(ino, b"replayed").unwrap();
+        fs.cow
+            .as_mut()
+            .unwrap()
+            .set_state(COW_STATE_COMMITTING)
+            .unwrap();
+        drop(fs);
+        let fs = LolelfFs::open_cow(&path, &scratch).unwrap();
+        assert_eq!(fs.scratch_blocks(), 0);
+        drop(fs);
+        let mut fs = LolelfFs::open_readonly(&path).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"replayed");
+    }
+}
@@ -0,0 +1,199 @@
+//! Removing encryption from an image
+//!
+//! `decrypt_image` turns an encrypted image into a plain one that can be
+//! handed off without the password. Every encrypted extent is opened with
+//! its file's key and written back in place as plaintext, with its
+//! compression untouched. An opaque image then has its block layer removed
+//! as well. Last, the superblock forgets the wrapped master key, salt and
+//! KDF parameters, and the image opens like any other.
+//!
+//! Blocks are rewritten in place and the superblock is only cleared at the
+//! end, so an interrupted run leaves an image that is partly plaintext and
+//! still claims to be encrypted. Run it on a copy when that matters.
+
+use crate::fs::LolelfFs;
+use crate::passwd::PasswordCheck;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+/// Work done by `LolelfFs::decrypt_image`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptStats {
+    /// Files that had encrypted extents
+    pub files: u32,
+    /// Data blocks decrypted, not counting the opaque block layer
+    pub blocks: u64,
+    /// How well the password could be confirmed before anything was written
+    pub check: PasswordCheck,
+}
+
+impl LolelfFs {
+    /// Decrypt every block of the image in place and turn encryption off
+    ///
+    /// Fails without writing anything if `password` is provably wrong.
+    pub fn decrypt_image(&mut self, password: &str) -> Result<DecryptStats> {
+        self.ensure_writable()?;
+        let sb = self.superblock.clone();
+        if sb.enc_enabled == 0 {
+            bail!("Filesystem is not encrypted");
+        }
+
+        let user_key = crate::encrypt::derive_key_pbkdf2(
+            password.as_bytes(),
+            &sb.enc_salt,
+            sb.enc_kdf_iterations,
+        );
+        let master_key = crate::encrypt::decrypt_master_key(&sb.enc_master_key, &user_key)?;
+        if self.enc_unlocked && master_key != self.enc_master_key {
+            bail!("Wrong password");
+        }
+        let check = if self.is_opaque() {
+            // Unlocking an opaque image proves the password
+            self.unlock(password)?;
+            PasswordCheck::Verified
+        } else {
+            let check = self.check_master_key(master_key)?;
+            self.enc_master_key = master_key;
+            self.enc_unlocked = true;
+            check
+        };
+
+        let mut stats = DecryptStats {
+            files: 0,
+            blocks: 0,
+            check,
+        };
+        // Shared extents are decrypted once, through whichever file comes
+        // first
+        let mut decrypted = std::collections::HashSet::new();
+        let nr_inodes = sb.nr_inodes;
+        for inode_num in 0..nr_inodes {
+            self.report_progress("decrypt", inode_num as u64 + 1, nr_inodes as u64, "");
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let inode = self.read_inode(inode_num)?;
+            if inode.is_symlink() || inode.ei_block == 0 {
+                continue;
+            }
+            let mut ei = self.read_extent_index(&inode)?;
+            if !ei
+                .extents
+                .iter()
+                .any(|e| !e.is_empty() && e.ee_enc_algo != LOLELFFS_ENC_NONE)
+            {
+                continue;
+            }
+
+            let key = self.file_key(inode_num, &inode);
+            for extent in ei.extents.iter_mut() {
+                if extent.is_empty() || extent.ee_enc_algo == LOLELFFS_ENC_NONE {
+                    continue;
+                }
+                if extent.is_shared() && !decrypted.insert(extent.ee_start) {
+                    extent.ee_enc_algo = LOLELFFS_ENC_NONE;
+                    extent.ee_flags &= !LOLELFFS_EXT_ENCRYPTED;
+                    continue;
+                }
+                for i in 0..extent.ee_len {
+                    let phys_block = extent.ee_start + i;
+                    let logical_block = (extent.ee_block + i) as u64;
+                    let sealed = self.read_block(phys_block)?;
+                    let plain = crate::encrypt::open_block(
+                        extent.ee_enc_algo,
+                        &key,
+                        logical_block,
+                        &sealed,
+                    )?;
+                    self.write_block(phys_block, &plain)?;
+                }
+                stats.blocks += extent.ee_len as u64;
+                extent.ee_enc_algo = LOLELFFS_ENC_NONE;
+                extent.ee_flags &= !LOLELFFS_EXT_ENCRYPTED;
+            }
+            // The plaintext must be durable before the index stops
+            // calling it ciphertext
+            self.barrier()?;
+            self.write_extent_index(inode.ei_block, &ei)?;
+            stats.files += 1;
+        }
+
+        if self.is_opaque() {
+            self.remove_opaque_layer()?;
+        }
+
+        let sb = &mut self.superblock;
+        sb.enc_enabled = 0;
+        sb.enc_default_algo = LOLELFFS_ENC_NONE as u32;
+        sb.enc_kdf_algo = LOLELFFS_KDF_NONE as u32;
+        sb.enc_kdf_iterations = 0;
+        sb.enc_kdf_memory = 0;
+        sb.enc_kdf_parallelism = 0;
+        sb.enc_salt = [0; 32];
+        sb.enc_master_key = [0; 32];
+        sb.enc_features = 0;
+        self.write_superblock()?;
+        self.enc_master_key = [0; 32];
+        self.enc_unlocked = false;
+
+        Ok(stats)
+    }
+
+    /// Decrypt every block after the superblock, undoing `make_opaque`
+    fn remove_opaque_layer(&mut self) -> Result<()> {
+        let key = crate::encrypt::derive_image_key(&self.enc_master_key);
+        self.barrier()?;
+
+        // With the flag off in memory, blocks are read and written as stored
+        self.superblock.enc_features &= !LOLELFFS_ENC_FEAT_OPAQUE;
+        let total = self.superblock.nr_blocks as u64 - 1;
+        for block_num in 1..self.superblock.nr_blocks {
+            let sealed = self.read_block(block_num)?;
+            let block = crate::encrypt::decrypt_aes_xts(&key, block_num as u64, &sealed)?;
+            self.write_meta_block(block_num, &block)?;
+            self.report_progress("opaque", block_num as u64, total, "");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_decrypt_image() {
+        for opaque in [false, true] {
+            let path = TempPath::new(&format!("decrypt-{}.img", opaque));
+            let enc = Some(("secret".to_string(), LOLELFFS_ENC_AES256_XTS, 1000));
+            let mut fs = LolelfFs::create_with_encryption(&path, 4 * 1024 * 1024, enc).unwrap();
+            if opaque {
+                fs.make_opaque().unwrap();
+            }
+            fs.unlock("secret").unwrap();
+            let compressible = b"plaintext-marker ".repeat(1000);
+            let a = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+            fs.write_file(a, &compressible).unwrap();
+            let b = fs.create_file(LOLELFFS_ROOT_INO, "b").unwrap();
+            fs.write_file(b, b"short plaintext-marker").unwrap();
+            drop(fs);
+
+            let mut fs = LolelfFs::open(&path).unwrap();
+            assert!(fs.decrypt_image("wrong").is_err());
+            let stats = fs.decrypt_image("secret").unwrap();
+            assert_eq!(stats.files, 2);
+            assert_eq!(stats.check, PasswordCheck::Verified);
+            drop(fs);
+
+            let raw = std::fs::read(&path).unwrap();
+            assert!(raw.windows(16).any(|w| w == b"short plaintext-"));
+            let mut fs = LolelfFs::open(&path).unwrap();
+            assert_eq!(fs.superblock.enc_enabled, 0);
+            assert_eq!(fs.superblock.enc_master_key, [0; 32]);
+            assert!(!fs.is_opaque());
+            assert_eq!(fs.read_file(a).unwrap(), compressible);
+            assert_eq!(fs.read_file(b).unwrap(), b"short plaintext-marker");
+        }
+    }
+}
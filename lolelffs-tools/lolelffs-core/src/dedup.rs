@@ -0,0 +1,348 @@
+//! Whole-file deduplication at write time
+//!
+//! With the reflink feature on, writing a whole file hashes its contents and
+//! looks the hash up in an index of recently written files, kept as an
+//! xattr on the root directory. When a file there has the same size and,
+//! compared byte for byte, the same contents, the new file's extent index
+//! points at that file's extents instead of at blocks of its own, and both
+//! files' extents are flagged as shared. Writes that force an algorithm,
+//! files with a compression setting of their own and files smaller than a
+//! block are never shared.
+//!
+//! Blocks of a shared extent are only freed once no other file names them,
+//! and relocating one moves every file that shares it. Rewriting either file
+//! gives it new blocks and leaves the other's contents alone. The index
+//! only points the way: a stale or missing entry costs a missed share, never
+//! a wrong one, so it can be dropped at any time.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+/// Root directory xattr holding the content hash index
+pub const DEDUP_INDEX_XATTR: &str = "trusted.lolelffs.dedup";
+
+/// Most files the index remembers; the oldest entries make way for new ones
+pub const LOLELFFS_DEDUP_INDEX_MAX: usize = 1024;
+
+/// Bytes per index entry: an 8-byte SHA-256 prefix and an inode number
+const ENTRY_SIZE: usize = 12;
+
+/// Space saved by files sharing extents
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Extents named by more than one file
+    pub shared_extents: u32,
+    /// Blocks that would be needed if every file had its own copy
+    pub saved_blocks: u64,
+}
+
+impl DedupStats {
+    /// Bytes saved
+    pub fn saved_bytes(&self) -> u64 {
+        self.saved_blocks * LOLELFFS_BLOCK_SIZE as u64
+    }
+}
+
+impl LolelfFs {
+    /// Whether identical files written from now on share their extents
+    pub fn reflink_enabled(&self) -> bool {
+        self.superblock.comp_features & LOLELFFS_FEATURE_REFLINK != 0
+    }
+
+    /// Turn write-time deduplication on or off for every later open
+    ///
+    /// Turning it off is refused while files still share extents, since
+    /// tools that do not know the feature would free the blocks twice.
+    pub fn set_reflink(&mut self, on: bool) -> Result<()> {
+        let params = crate::tune::TuneParams {
+            reflink: Some(on),
+            ..Default::default()
+        };
+        self.tune(&params, None)
+    }
+
+    /// Measure how much sharing extents saves across the image
+    pub fn dedup_stats(&mut self) -> Result<DedupStats> {
+        // Length and number of files for every shared extent, by first block
+        let mut refs: HashMap<u32, (u32, u32)> = HashMap::new();
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let Some(extents) = self.file_extents(inode_num)? else {
+                continue;
+            };
+            for extent in extents.iter().filter(|e| e.is_shared()) {
+                refs.entry(extent.ee_start).or_insert((extent.ee_len, 0)).1 += 1;
+            }
+        }
+
+        let mut stats = DedupStats::default();
+        for &(len, files) in refs.values().filter(|(_, files)| *files > 1) {
+            stats.shared_extents += 1;
+            stats.saved_blocks += len as u64 * (files as u64 - 1);
+        }
+        Ok(stats)
+    }
+
+    /// Point `inode` at the extents of an indexed file holding exactly
+    /// `data`, returning whether one was found
+    ///
+    /// `digest` is the SHA-256 of `data`, and `enc_algo` the encryption the
+    /// write would use. The inode's previous extents are released.
+    pub(crate) fn share_identical(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        data: &[u8],
+        digest: &[u8],
+        enc_algo: u8,
+    ) -> Result<bool> {
+        // Per-file keys make the same contents different ciphertext
+        if enc_algo != LOLELFFS_ENC_NONE
+            && self.superblock.enc_features & LOLELFFS_ENC_FEAT_PER_FILE_KEYS != 0
+        {
+            return Ok(false);
+        }
+
+        let candidates: Vec<u32> = self
+            .dedup_index()?
+            .into_iter()
+            .filter(|(key, ino)| key[..] == digest[..8] && *ino != inode_num)
+            .map(|(_, ino)| ino)
+            .collect();
+        for candidate in candidates {
+            if candidate >= self.superblock.nr_inodes || self.is_inode_free(candidate)? {
+                continue;
+            }
+            let other = self.read_inode(candidate)?;
+            if other.i_size as usize != data.len() {
+                continue;
+            }
+            let Some(mut extents) = self.file_extents(candidate)? else {
+                continue;
+            };
+            if extents.is_empty()
+                || extents
+                    .iter()
+                    .any(|e| e.ee_enc_algo != enc_algo || e.has_metadata())
+                || self.read_file(candidate)? != data
+            {
+                continue;
+            }
+
+            for extent in &mut extents {
+                extent.ee_flags |= LOLELFFS_EXT_SHARED;
+            }
+            extents.resize(LOLELFFS_MAX_EXTENTS, Extent::default());
+            let ei = ExtentIndex {
+                nr_files: 0,
+                extents,
+            };
+            self.write_extent_index(other.ei_block, &ei)?;
+
+            let old_extents: Vec<Extent> = if inode.ei_block != 0 {
+                let old = self.read_extent_index(inode)?;
+                old.extents
+                    .into_iter()
+                    .take_while(|e| !e.is_empty())
+                    .collect()
+            } else {
+                inode.ei_block = self.alloc_blocks(1)?;
+                Vec::new()
+            };
+            self.write_extent_index(inode.ei_block, &ei)?;
+            inode.i_size = other.i_size;
+            inode.i_blocks = other.i_blocks;
+            let now = self.now();
+            inode.i_mtime = now;
+            inode.i_ctime = now;
+            self.write_inode(inode_num, inode)?;
+
+            self.barrier()?;
+            for extent in &old_extents {
+                self.release_extent(inode_num, extent)?;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Remember that `inode_num` now holds contents with SHA-256 `digest`
+    pub(crate) fn record_content(&mut self, inode_num: u32, digest: &[u8]) -> Result<()> {
+        let mut key = [0u8; 8];
+        key.copy_from_slice(&digest[..8]);
+        let mut index = self.dedup_index()?;
+        if index.last() == Some(&(key, inode_num)) {
+            return Ok(());
+        }
+        index.retain(|&(k, ino)| k != key && ino != inode_num);
+        index.push((key, inode_num));
+        if index.len() > LOLELFFS_DEDUP_INDEX_MAX {
+            index.drain(..index.len() - LOLELFFS_DEDUP_INDEX_MAX);
+        }
+
+        let mut raw = Vec::with_capacity(index.len() * ENTRY_SIZE);
+        for (key, ino) in &index {
+            raw.extend_from_slice(key);
+            raw.extend_from_slice(&ino.to_le_bytes());
+        }
+        self.set_xattr(LOLELFFS_ROOT_INO, DEDUP_INDEX_XATTR, &raw)
+    }
+
+    /// Free the blocks of one of `inode_num`'s extents unless another file
+    /// shares them
+    pub(crate) fn release_extent(&mut self, inode_num: u32, extent: &Extent) -> Result<()> {
+        if extent.is_shared() && !self.extent_sharers(inode_num, extent.ee_start)?.is_empty() {
+            return Ok(());
+        }
+        self.free_blocks(extent.ee_start, extent.ee_len)
+    }
+
+    /// Every other file with a shared extent starting at block `start`, with
+    /// the extent's position in its index
+    pub(crate) fn extent_sharers(
+        &mut self,
+        inode_num: u32,
+        start: u32,
+    ) -> Result<Vec<(u32, usize)>> {
+        let mut sharers = Vec::new();
+        for other in 0..self.superblock.nr_inodes {
+            if other == inode_num || self.is_inode_free(other)? {
+                continue;
+            }
+            let Some(extents) = self.file_extents(other)? else {
+                continue;
+            };
+            for (idx, extent) in extents.iter().enumerate() {
+                if extent.is_shared() && extent.ee_start == start {
+                    sharers.push((other, idx));
+                }
+            }
+        }
+        Ok(sharers)
+    }
+
+    /// Blocks of `inode`'s shared extents
+    pub(crate) fn shared_blocks(&mut self, inode: &Inode) -> HashSet<u32> {
+        let mut blocks = HashSet::new();
+        if inode.is_file() && inode.ei_block != 0 {
+            if let Ok(ei) = self.read_extent_index(inode) {
+                for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+                    if extent.is_shared() {
+                        blocks
+                            .extend(extent.ee_start..extent.ee_start.saturating_add(extent.ee_len));
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Forget every indexed file
+    pub(crate) fn drop_dedup_index(&mut self) -> Result<()> {
+        if self.has_dedup_index()? {
+            self.remove_xattr(LOLELFFS_ROOT_INO, DEDUP_INDEX_XATTR)?;
+        }
+        Ok(())
+    }
+
+    fn has_dedup_index(&mut self) -> Result<bool> {
+        Ok(self
+            .list_xattrs(LOLELFFS_ROOT_INO)?
+            .iter()
+            .any(|name| name == DEDUP_INDEX_XATTR))
+    }
+
+    /// Index entries, oldest first
+    fn dedup_index(&mut self) -> Result<Vec<([u8; 8], u32)>> {
+        if !self.has_dedup_index()? {
+            return Ok(Vec::new());
+        }
+        let raw = self.get_xattr(LOLELFFS_ROOT_INO, DEDUP_INDEX_XATTR)?;
+        Ok(raw
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let mut key = [0u8; 8];
+                key.copy_from_slice(&entry[..8]);
+                let ino = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+                (key, ino)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    fn content(seed: u32) -> Vec<u8> {
+        (0..20000u32)
+            .map(|i| ((i * 7 + seed) % 251) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_write_dedup() {
+        let (_path, mut fs) = temp_image("dedup.img");
+        fs.set_reflink(true).unwrap();
+        let data = content(0);
+        let a = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+        fs.write_file(a, &data).unwrap();
+        let b = fs.create_file(LOLELFFS_ROOT_INO, "b").unwrap();
+        let free = fs.superblock.nr_free_blocks;
+        fs.write_file(b, &data).unwrap();
+        assert_eq!(fs.superblock.nr_free_blocks, free);
+        let stats = fs.dedup_stats().unwrap();
+        assert_eq!(
+            stats.saved_blocks,
+            fs.read_inode(a).unwrap().i_blocks as u64
+        );
+        assert!(fs.set_reflink(false).is_err());
+
+        // Forced algorithms opt out
+        let c = fs.create_file(LOLELFFS_ROOT_INO, "c").unwrap();
+        let opts = crate::file::WriteOptions {
+            comp_algo: Some(LOLELFFS_COMP_NONE),
+            enc_algo: None,
+        };
+        fs.write_file_with_options(c, &data, &opts).unwrap();
+        assert!(fs.superblock.nr_free_blocks < free);
+
+        // Rewriting one copy leaves the other intact
+        fs.write_file(a, &content(1)).unwrap();
+        assert_eq!(fs.read_file(b).unwrap(), data);
+        assert_eq!(fs.dedup_stats().unwrap(), DedupStats::default());
+        fs.unlink(LOLELFFS_ROOT_INO, "b").unwrap();
+        fs.unlink(LOLELFFS_ROOT_INO, "c").unwrap();
+        assert_eq!(fs.superblock.nr_free_blocks, free + 1);
+        assert!(fs.fsck_full().unwrap().problems.is_empty());
+        fs.set_reflink(false).unwrap();
+    }
+
+    #[test]
+    fn test_shared_extents_move_together() {
+        let (_path, mut fs) = temp_image("dedup-move.img");
+        fs.set_reflink(true).unwrap();
+        let data = content(2);
+        let a = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+        fs.write_file(a, &data).unwrap();
+        let b = fs.create_file(LOLELFFS_ROOT_INO, "b").unwrap();
+        fs.write_file(b, &data).unwrap();
+        assert!(fs.fsck_full().unwrap().problems.is_empty());
+
+        let extent = fs.file_extents(a).unwrap().unwrap()[0];
+        fs.balance(extent.ee_start..extent.ee_start + extent.ee_len)
+            .unwrap();
+        assert_eq!(
+            fs.file_extents(a).unwrap().unwrap()[0].ee_start,
+            fs.file_extents(b).unwrap().unwrap()[0].ee_start
+        );
+        assert_eq!(fs.read_file(a).unwrap(), data);
+        assert_eq!(fs.read_file(b).unwrap(), data);
+        assert!(fs.fsck_full().unwrap().problems.is_empty());
+    }
+}
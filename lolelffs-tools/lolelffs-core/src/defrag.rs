@@ -0,0 +1,274 @@
+//! Defragmentation of regular files
+//!
+//! Block allocation is first-fit, so files written into a well-used image end
+//! up scattered across whatever holes were free. Defragmenting copies such a
+//! file into a single free run, then merges extents that are adjacent both
+//! logically and physically. Encryption is keyed on logical block numbers and
+//! compressed blocks are self-contained, so blocks can be moved verbatim.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::Result;
+
+/// Fragmentation of the regular files and free space in an image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragStats {
+    /// Regular files with data
+    pub files: u32,
+    /// Files whose data is split across more than one physical run
+    pub fragmented_files: u32,
+    /// Extent slots in use across all files
+    pub extents: u32,
+    /// Physically contiguous runs across all files
+    pub runs: u32,
+    /// Runs of free data blocks
+    pub free_runs: u32,
+    /// Longest run of free data blocks
+    pub largest_free_run: u32,
+}
+
+/// Work done by `LolelfFs::defrag`
+#[derive(Debug, Clone, Default)]
+pub struct DefragStats {
+    /// Files moved into a single contiguous run
+    pub files_moved: u32,
+    /// Fragmented files that could not be moved for lack of a large enough
+    /// free run
+    pub files_skipped: u32,
+    /// Extents removed by coalescing
+    pub extents_merged: u32,
+}
+
+/// Number of physically contiguous runs in a list of extents
+fn count_runs(extents: &[Extent]) -> u32 {
+    let mut runs = 0;
+    let mut next = None;
+    for extent in extents {
+        if next != Some(extent.ee_start) {
+            runs += 1;
+        }
+        next = Some(extent.ee_start + extent.ee_len);
+    }
+    runs
+}
+
+impl LolelfFs {
+    /// Longest extent `defrag` may build from extents like `extent`
+    pub(crate) fn merged_extent_limit(&self, extent: &Extent) -> u32 {
+        if extent.has_metadata() {
+            return LOLELFFS_MAX_BLOCKS_PER_EXTENT;
+        }
+        let large = self.superblock.max_extent_blocks_large;
+        if large == 0 || large > LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE {
+            LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE
+        } else {
+            large
+        }
+    }
+
+    /// Used extents of a regular file, or `None` for anything else
+    pub(crate) fn file_extents(&mut self, inode_num: u32) -> Result<Option<Vec<Extent>>> {
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() || inode.ei_block == 0 {
+            return Ok(None);
+        }
+        let ei = self.read_extent_index(&inode)?;
+        Ok(Some(
+            ei.extents
+                .into_iter()
+                .take_while(|e| !e.is_empty())
+                .collect(),
+        ))
+    }
+
+    /// Measure file and free-space fragmentation
+    pub fn frag_stats(&mut self) -> Result<FragStats> {
+        let mut stats = FragStats::default();
+
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let Some(extents) = self.file_extents(inode_num)? else {
+                continue;
+            };
+            if extents.is_empty() {
+                continue;
+            }
+            let runs = count_runs(&extents);
+            stats.files += 1;
+            stats.extents += extents.len() as u32;
+            stats.runs += runs;
+            if runs > 1 {
+                stats.fragmented_files += 1;
+            }
+        }
+
+        let mut run = 0;
+        for block_num in self.superblock.data_block_start()..self.superblock.nr_blocks {
+            if self.is_block_free(block_num)? {
+                if run == 0 {
+                    stats.free_runs += 1;
+                }
+                run += 1;
+                stats.largest_free_run = stats.largest_free_run.max(run);
+            } else {
+                run = 0;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Defragment one regular file, returning its extent count before and
+    /// after
+    ///
+    /// A file split across several runs is copied into the first free run
+    /// large enough for all of it; the new extent index is written before the
+    /// old blocks are freed. If no such run exists the data stays where it is,
+    /// but adjacent extents are still merged. Files with per-block metadata
+    /// or shared extents are left alone.
+    pub fn defrag_file(&mut self, inode_num: u32) -> Result<(u32, u32)> {
+        self.ensure_writable()?;
+
+        let Some(old_extents) = self.file_extents(inode_num)? else {
+            return Ok((0, 0));
+        };
+        let before = old_extents.len() as u32;
+        if old_extents.is_empty()
+            || old_extents
+                .iter()
+                .any(|e| e.has_metadata() || e.is_shared())
+        {
+            return Ok((before, before));
+        }
+
+        let mut extents = old_extents.clone();
+        let mut moved = false;
+        if count_runs(&extents) > 1 {
+            let total: u32 = extents.iter().map(|e| e.ee_len).sum();
+            if let Ok(new_start) = self.alloc_blocks(total) {
+                let mut next = new_start;
+                for extent in &mut extents {
+                    for i in 0..extent.ee_len {
+                        let block = self.read_block(extent.ee_start + i)?;
+                        self.write_block(next + i, &block)?;
+                    }
+                    extent.ee_start = next;
+                    next += extent.ee_len;
+                }
+                moved = true;
+            }
+        }
+
+        // Merge extents that continue each other with the same encoding
+        let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+        for extent in extents {
+            if let Some(last) = merged.last_mut() {
+                if last.ee_block + last.ee_len == extent.ee_block
+                    && last.ee_start + last.ee_len == extent.ee_start
+                    && last.ee_comp_algo == extent.ee_comp_algo
+                    && last.ee_enc_algo == extent.ee_enc_algo
+                    && last.ee_flags == extent.ee_flags
+                    && last.ee_len + extent.ee_len <= self.merged_extent_limit(last)
+                {
+                    last.ee_len += extent.ee_len;
+                    continue;
+                }
+            }
+            merged.push(extent);
+        }
+
+        let after = merged.len() as u32;
+        if moved || after < before {
+            let inode = self.read_inode(inode_num)?;
+            let mut ei = self.read_extent_index(&inode)?;
+            merged.resize(LOLELFFS_MAX_EXTENTS, Extent::default());
+            ei.extents = merged;
+            self.write_extent_index(inode.ei_block, &ei)?;
+        }
+        if moved {
+            for extent in &old_extents {
+                self.free_blocks(extent.ee_start, extent.ee_len)?;
+            }
+        }
+
+        Ok((before, after))
+    }
+
+    /// Defragment every regular file in the image
+    pub fn defrag(&mut self) -> Result<DefragStats> {
+        let mut stats = DefragStats::default();
+
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let Some(extents) = self.file_extents(inode_num)? else {
+                continue;
+            };
+            let fragmented = count_runs(&extents) > 1;
+
+            let (before, after) = self.defrag_file(inode_num)?;
+            stats.extents_merged += before - after;
+            if fragmented {
+                let extents = self.file_extents(inode_num)?.unwrap_or_default();
+                if count_runs(&extents) > 1 {
+                    stats.files_skipped += 1;
+                } else {
+                    stats.files_moved += 1;
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_defrag_interleaved_files() {
+        let (_path, mut fs) = temp_image("defrag.img");
+
+        // Punch two-block holes, then write a file that has to fill them
+        for i in 0..10 {
+            let ino = fs
+                .create_file(LOLELFFS_ROOT_INO, &format!("f{}", i))
+                .unwrap();
+            fs.write_file(ino, &[i as u8; 8192]).unwrap();
+        }
+        for i in (1..10).step_by(2) {
+            fs.unlink(LOLELFFS_ROOT_INO, &format!("f{}", i)).unwrap();
+        }
+        let big = fs.create_file(LOLELFFS_ROOT_INO, "big").unwrap();
+        let content: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        fs.write_file(big, &content).unwrap();
+        assert!(count_runs(&fs.file_extents(big).unwrap().unwrap()) > 1);
+
+        let before = fs.frag_stats().unwrap();
+        let free_before = fs.superblock.nr_free_blocks;
+
+        let stats = fs.defrag().unwrap();
+        let after = fs.frag_stats().unwrap();
+        assert_eq!(after.files, before.files);
+        assert_eq!(after.fragmented_files, 0);
+        assert_eq!(after.runs, after.files);
+        assert!(after.extents < before.extents);
+        assert!(stats.extents_merged > 0);
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+
+        assert_eq!(fs.read_file(big).unwrap(), content);
+        for i in (0..10).step_by(2) {
+            let ino = fs.resolve_path(&format!("/f{}", i)).unwrap();
+            assert_eq!(fs.read_file(ino).unwrap(), vec![i as u8; 8192]);
+        }
+
+        // A second pass has nothing left to do
+        let again = fs.defrag().unwrap();
+        assert_eq!((again.files_moved, again.extents_merged), (0, 0));
+    }
+}
@@ -0,0 +1,290 @@
+//! Comparing two images
+//!
+//! Both trees are walked and matched up by path. A path present on one side
+//! only is added or removed; one present on both is modified if its type,
+//! permissions, owner, xattrs, contents or (except for directories, whose
+//! mtime follows their entries) modification time differ. Contents
+//! of files of equal size are compared by SHA-256, so unchanged files are
+//! read once per side and never held in memory. Inode numbers, block
+//! placement and link counts are not compared: two images with the same
+//! tree are equal however they were built.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// What happened to a path between the two images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only in the second image
+    Added,
+    /// Only in the first image
+    Removed,
+    /// In both, with differences
+    Modified,
+}
+
+impl ChangeKind {
+    /// One-letter code, as in `git diff --name-status`
+    pub fn code(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Removed => 'D',
+            ChangeKind::Modified => 'M',
+        }
+    }
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A path that differs between two images
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathChange {
+    /// Path relative to the root of the walk
+    pub path: String,
+    pub kind: ChangeKind,
+    /// For modified paths, what differs: "type", "mode", "owner", "mtime",
+    /// "xattrs", "content"
+    pub fields: Vec<&'static str>,
+    /// Size in the first image (0 if added)
+    pub old_size: u64,
+    /// Size in the second image (0 if removed)
+    pub new_size: u64,
+}
+
+/// What `LolelfFs::diff` compares
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions {
+    /// Do not report modification time differences
+    pub ignore_times: bool,
+}
+
+/// Totals over a list of changes, for `diff --stat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStat {
+    pub added: u32,
+    pub removed: u32,
+    pub modified: u32,
+    /// Bytes gained by added and grown paths
+    pub bytes_added: u64,
+    /// Bytes lost by removed and shrunk paths
+    pub bytes_removed: u64,
+}
+
+impl DiffStat {
+    /// Sum up `changes`
+    pub fn of(changes: &[PathChange]) -> Self {
+        let mut stat = DiffStat::default();
+        for change in changes {
+            match change.kind {
+                ChangeKind::Added => stat.added += 1,
+                ChangeKind::Removed => stat.removed += 1,
+                ChangeKind::Modified => stat.modified += 1,
+            }
+            stat.bytes_added += change.new_size.saturating_sub(change.old_size);
+            stat.bytes_removed += change.old_size.saturating_sub(change.new_size);
+        }
+        stat
+    }
+}
+
+/// Xattr names and values of an inode, sorted by name
+fn xattrs(fs: &mut LolelfFs, inode_num: u32) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut names = fs.list_xattrs(inode_num)?;
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let value = fs.get_xattr(inode_num, &name)?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Size a change is reported with: file and symlink lengths, 0 for
+/// directories
+fn reported_size(inode: &Inode) -> u64 {
+    if inode.is_dir() {
+        0
+    } else {
+        inode.i_size as u64
+    }
+}
+
+impl LolelfFs {
+    /// Compare the tree below `dir_inode_num` with the tree below
+    /// `other_dir_inode_num` in `other`, returning changes sorted by path
+    pub fn diff(
+        &mut self,
+        dir_inode_num: u32,
+        other: &mut LolelfFs,
+        other_dir_inode_num: u32,
+        opts: &DiffOptions,
+    ) -> Result<Vec<PathChange>> {
+        let old: BTreeMap<String, (u32, Inode)> = self
+            .walk_tree(dir_inode_num)?
+            .into_iter()
+            .map(|w| (w.path, (w.entry.inode_num, w.entry.inode)))
+            .collect();
+        let mut new: BTreeMap<String, (u32, Inode)> = other
+            .walk_tree(other_dir_inode_num)?
+            .into_iter()
+            .map(|w| (w.path, (w.entry.inode_num, w.entry.inode)))
+            .collect();
+
+        let mut changes = Vec::new();
+        for (path, (old_ino, old_inode)) in old {
+            let Some((new_ino, new_inode)) = new.remove(&path) else {
+                changes.push(PathChange {
+                    path,
+                    kind: ChangeKind::Removed,
+                    fields: Vec::new(),
+                    old_size: reported_size(&old_inode),
+                    new_size: 0,
+                });
+                continue;
+            };
+
+            let fields =
+                self.changed_fields(old_ino, &old_inode, other, new_ino, &new_inode, opts)?;
+            if !fields.is_empty() {
+                changes.push(PathChange {
+                    path,
+                    kind: ChangeKind::Modified,
+                    fields,
+                    old_size: reported_size(&old_inode),
+                    new_size: reported_size(&new_inode),
+                });
+            }
+        }
+        for (path, (_, new_inode)) in new {
+            changes.push(PathChange {
+                path,
+                kind: ChangeKind::Added,
+                fields: Vec::new(),
+                old_size: 0,
+                new_size: reported_size(&new_inode),
+            });
+        }
+
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    /// What differs between an inode here and one in `other`
+    fn changed_fields(
+        &mut self,
+        old_ino: u32,
+        old: &Inode,
+        other: &mut LolelfFs,
+        new_ino: u32,
+        new: &Inode,
+        opts: &DiffOptions,
+    ) -> Result<Vec<&'static str>> {
+        let mut fields = Vec::new();
+        if old.i_mode & mode::S_IFMT != new.i_mode & mode::S_IFMT {
+            // Nothing else is comparable across types
+            fields.push("type");
+            return Ok(fields);
+        }
+        if old.i_mode & 0o7777 != new.i_mode & 0o7777 {
+            fields.push("mode");
+        }
+        if (old.i_uid, old.i_gid) != (new.i_uid, new.i_gid) {
+            fields.push("owner");
+        }
+        if !opts.ignore_times && old.i_mtime != new.i_mtime && !old.is_dir() {
+            fields.push("mtime");
+        }
+        if xattrs(self, old_ino)? != xattrs(other, new_ino)? {
+            fields.push("xattrs");
+        }
+
+        let content_differs = if old.is_symlink() {
+            crate::file::symlink_target(old) != crate::file::symlink_target(new)
+        } else if old.is_file() {
+            old.i_size != new.i_size || self.file_sha256(old_ino)? != other.file_sha256(new_ino)?
+        } else {
+            false
+        };
+        if content_differs {
+            fields.push("content");
+        }
+
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_diff_images() {
+        let path_a = TempPath::new("diff-a.img");
+        let path_b = TempPath::new("diff-b.img");
+        let mut a = LolelfFs::create(&path_a, 4 * 1024 * 1024).unwrap();
+        let mut b = LolelfFs::create(&path_b, 4 * 1024 * 1024).unwrap();
+
+        for fs in [&mut a, &mut b] {
+            let sub = fs.mkdir(LOLELFFS_ROOT_INO, "sub").unwrap();
+            let same = fs.create_file(sub, "same").unwrap();
+            fs.write_file(same, b"unchanged").unwrap();
+            let mut inode = fs.read_inode(same).unwrap();
+            inode.i_mtime = 1_000_000;
+            fs.write_inode(same, &inode).unwrap();
+            fs.symlink(LOLELFFS_ROOT_INO, "link", "sub/same").unwrap();
+        }
+        let opts = DiffOptions { ignore_times: true };
+        assert!(a
+            .diff(LOLELFFS_ROOT_INO, &mut b, LOLELFFS_ROOT_INO, &opts)
+            .unwrap()
+            .is_empty());
+
+        let gone = a.create_file(LOLELFFS_ROOT_INO, "gone").unwrap();
+        a.write_file(gone, &[1; 300]).unwrap();
+        let new = b.create_file(LOLELFFS_ROOT_INO, "new").unwrap();
+        b.write_file(new, &[2; 100]).unwrap();
+        let edited_a = a.create_file(LOLELFFS_ROOT_INO, "edited").unwrap();
+        a.write_file(edited_a, b"version one").unwrap();
+        let edited_b = b.create_file(LOLELFFS_ROOT_INO, "edited").unwrap();
+        b.write_file(edited_b, b"version two").unwrap();
+        b.set_xattr(edited_b, "user.tag", b"x").unwrap();
+        let sub_b = b.lookup(LOLELFFS_ROOT_INO, "sub").unwrap().unwrap();
+        let mut inode = b.read_inode(sub_b).unwrap();
+        inode.i_mode = mode::S_IFDIR | 0o700;
+        b.write_inode(sub_b, &inode).unwrap();
+
+        let changes = a
+            .diff(LOLELFFS_ROOT_INO, &mut b, LOLELFFS_ROOT_INO, &opts)
+            .unwrap();
+        let summary: Vec<(&str, char, Vec<&str>)> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind.code(), c.fields.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("edited", 'M', vec!["xattrs", "content"]),
+                ("gone", 'D', vec![]),
+                ("new", 'A', vec![]),
+                ("sub", 'M', vec!["mode"]),
+            ]
+        );
+        let stat = DiffStat::of(&changes);
+        assert_eq!((stat.added, stat.removed, stat.modified), (1, 1, 2));
+        assert_eq!((stat.bytes_added, stat.bytes_removed), (100, 300));
+    }
+}
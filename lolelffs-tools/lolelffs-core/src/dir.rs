@@ -0,0 +1,1431 @@
+//! Directory operations for lolelffs
+
+use crate::error::FsError;
+use crate::fs::{BlockKind, LolelfFs};
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Directory entry with full information
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub inode_num: u32,
+    pub filename: String,
+    pub inode: Inode,
+}
+
+/// How file names are normalized for interoperability (e.g. NFD from macOS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalizationPolicy {
+    /// Names are stored and matched byte for byte
+    #[default]
+    Off,
+    /// New names are stored in NFC, and lookups are normalized to NFC
+    NfcOnCreate,
+    /// Names are stored as given, but match if their NFC forms are equal
+    NormalizeOnLookup,
+}
+
+/// Entry visited by a recursive directory walk
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// Path relative to the walk's starting directory
+    pub path: String,
+    /// Nesting level (1 for direct children of the starting directory)
+    pub depth: usize,
+    /// Whether this is the last entry of its directory
+    pub is_last: bool,
+    pub entry: DirEntry,
+}
+
+impl LolelfFs {
+    /// Form of a new name as it will be stored under the normalization policy
+    fn stored_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.normalization {
+            NormalizationPolicy::NfcOnCreate if !is_nfc(name) => Cow::Owned(name.nfc().collect()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// Whether a stored entry name matches a name being looked up
+    fn names_match(&self, stored: &str, name: &str) -> bool {
+        if stored == name {
+            return true;
+        }
+        match self.normalization {
+            NormalizationPolicy::Off => false,
+            NormalizationPolicy::NfcOnCreate => !is_nfc(name) && name.nfc().eq(stored.chars()),
+            NormalizationPolicy::NormalizeOnLookup => name.nfc().eq(stored.nfc()),
+        }
+    }
+
+    /// List a directory's entries keyed by name, for comparing against another tree
+    pub fn dir_entry_map(
+        &mut self,
+        dir_inode_num: u32,
+    ) -> Result<std::collections::HashMap<String, DirEntry>> {
+        Ok(self
+            .list_dir(dir_inode_num)?
+            .into_iter()
+            .map(|e| (e.filename.clone(), e))
+            .collect())
+    }
+
+    /// List all entries in a directory
+    pub fn list_dir(&mut self, dir_inode_num: u32) -> Result<Vec<DirEntry>> {
+        let dir_inode = self.read_inode(dir_inode_num)?;
+
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+
+        if dir_inode.ei_block == 0 {
+            return Ok(Vec::new());
+        }
+
+        let ei = self.read_extent_index(&dir_inode)?;
+        let mut entries = Vec::new();
+
+        // Iterate through all extents
+        for extent in &ei.extents {
+            if extent.is_empty() {
+                break;
+            }
+
+            // Iterate through all blocks in extent
+            for block_offset in 0..extent.ee_len {
+                let block_num = extent.ee_start + block_offset;
+                let block = self.read_dir_block(block_num)?;
+
+                // Iterate through all file entries in block
+                for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                    let offset = file_idx * FileEntry::SIZE;
+                    let entry_data = &block[offset..offset + FileEntry::SIZE];
+
+                    if let Some(entry) = FileEntry::from_bytes(entry_data) {
+                        let inode = self.read_inode(entry.inode)?;
+                        entries.push(DirEntry {
+                            inode_num: entry.inode,
+                            filename: entry.filename,
+                            inode,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Look up a file in a directory by name
+    pub fn lookup(&mut self, dir_inode_num: u32, name: &str) -> Result<Option<u32>> {
+        let dir_inode = self.read_inode(dir_inode_num)?;
+
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+
+        if dir_inode.ei_block == 0 {
+            return Ok(None);
+        }
+
+        let ei = self.read_extent_index(&dir_inode)?;
+
+        // Search through all extents
+        for extent in &ei.extents {
+            if extent.is_empty() {
+                break;
+            }
+
+            for block_offset in 0..extent.ee_len {
+                let block_num = extent.ee_start + block_offset;
+                let block = self.read_dir_block(block_num)?;
+
+                for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                    let offset = file_idx * FileEntry::SIZE;
+                    let entry_data = &block[offset..offset + FileEntry::SIZE];
+
+                    if let Some(entry) = FileEntry::from_bytes(entry_data) {
+                        if self.names_match(&entry.filename, name) {
+                            return Ok(Some(entry.inode));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Entries of a directory whose name starts with `prefix`, sorted by name
+    ///
+    /// Only the directory's own blocks are read, not the inodes of its
+    /// entries, so this stays fast on large directories.
+    pub fn lookup_prefix(
+        &mut self,
+        dir_inode_num: u32,
+        prefix: &str,
+    ) -> Result<Vec<(String, u32)>> {
+        let mut matches = Vec::new();
+        for block_num in self.dir_blocks(dir_inode_num)? {
+            let block = self.read_dir_block(block_num)?;
+
+            for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                let offset = file_idx * FileEntry::SIZE;
+                if let Some(entry) = FileEntry::from_bytes(&block[offset..offset + FileEntry::SIZE])
+                {
+                    if entry.filename.starts_with(prefix) {
+                        matches.push((entry.filename, entry.inode));
+                    }
+                }
+            }
+        }
+
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Complete a partial path (relative to the root) for interactive shells
+    ///
+    /// Returns the partial path's directory joined with each name its last
+    /// component could expand to, with directories ending in "/". Hidden
+    /// entries are only offered once the partial name starts with ".". An
+    /// unresolvable parent yields nothing.
+    pub fn complete_path(&mut self, partial: &str) -> Result<Vec<String>> {
+        let (parent, prefix) = match partial.rfind('/') {
+            Some(idx) => partial.split_at(idx + 1),
+            None => ("", partial),
+        };
+        let Ok(dir_inode_num) = self.resolve_path(parent) else {
+            return Ok(Vec::new());
+        };
+        if !self.read_inode(dir_inode_num)?.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut completions = Vec::new();
+        for (name, inode_num) in self.lookup_prefix(dir_inode_num, prefix)? {
+            if name.starts_with('.') && !prefix.starts_with('.') {
+                continue;
+            }
+            let suffix = if self.read_inode(inode_num)?.is_dir() {
+                "/"
+            } else {
+                ""
+            };
+            completions.push(format!("{}{}{}", parent, name, suffix));
+        }
+
+        Ok(completions)
+    }
+
+    /// Resolve a path to an inode number
+    ///
+    /// Symlinks are not followed; see `resolve_path_follow`.
+    pub fn resolve_path(&mut self, path: &str) -> Result<u32> {
+        check_path_limits(path)?;
+        let path = path.trim_matches('/');
+
+        if path.is_empty() {
+            return Ok(LOLELFFS_ROOT_INO);
+        }
+
+        let mut current_inode = LOLELFFS_ROOT_INO;
+
+        for component in path.split('/') {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+
+            if component == ".." {
+                // For now, don't support parent directory traversal
+                // This would require tracking parent inodes
+                bail!("Parent directory traversal not supported");
+            }
+
+            match self.lookup(current_inode, component)? {
+                Some(inode) => current_inode = inode,
+                None => bail!("Path not found: {}", path),
+            }
+        }
+
+        Ok(current_inode)
+    }
+
+    /// Resolve a path to an inode number, following symlinks
+    ///
+    /// Links in the middle of the path are always followed; the last
+    /// component is followed only if `follow_last` is set, as with
+    /// `stat` versus `lstat`. Relative targets resolve against the directory
+    /// holding the link, and `..` is supported while expanding them. More than
+    /// `LOLELFFS_MAX_SYMLINK_HOPS` links fails with `FsError::SymlinkLoop`,
+    /// and paths that grow past the length or depth limits through expansion
+    /// fail as well.
+    pub fn resolve_path_follow(&mut self, path: &str, follow_last: bool) -> Result<u32> {
+        check_path_limits(path)?;
+
+        // Directories walked so far, and components still to resolve in
+        // reverse order so expansions can be pushed onto the end
+        let mut stack = vec![LOLELFFS_ROOT_INO];
+        let mut pending: Vec<String> = path.split('/').rev().map(str::to_string).collect();
+        let mut hops = 0;
+
+        while let Some(component) = pending.pop() {
+            match component.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let dir = *stack.last().unwrap();
+            let Some(inode_num) = self.lookup(dir, &component)? else {
+                bail!("Path not found: {}", path);
+            };
+            let inode = self.read_inode(inode_num)?;
+            let is_last = pending.iter().all(|c| c.is_empty() || c == ".");
+
+            if inode.is_symlink() && (follow_last || !is_last) {
+                hops += 1;
+                if hops > LOLELFFS_MAX_SYMLINK_HOPS {
+                    return Err(FsError::SymlinkLoop {
+                        path: path.to_string(),
+                    }
+                    .into());
+                }
+                let target =
+                    String::from_utf8_lossy(&crate::file::symlink_target(&inode)).into_owned();
+                check_path_limits(&target)?;
+                if target.starts_with('/') {
+                    stack.truncate(1);
+                }
+                pending.extend(target.split('/').rev().map(str::to_string));
+                if pending.len() + stack.len() > LOLELFFS_MAX_PATH_DEPTH {
+                    return Err(FsError::PathTooDeep {
+                        max: LOLELFFS_MAX_PATH_DEPTH,
+                    }
+                    .into());
+                }
+                continue;
+            }
+
+            if !is_last && !inode.is_dir() {
+                bail!(
+                    "Path not found: {} ({} is not a directory)",
+                    path,
+                    component
+                );
+            }
+            stack.push(inode_num);
+            if stack.len() > LOLELFFS_MAX_PATH_DEPTH {
+                return Err(FsError::PathTooDeep {
+                    max: LOLELFFS_MAX_PATH_DEPTH,
+                }
+                .into());
+            }
+        }
+
+        Ok(*stack.last().unwrap())
+    }
+
+    /// Recursively list a directory in pre-order, sorted by name
+    ///
+    /// Directories reachable more than once (only possible on a corrupt
+    /// image) are not descended into again, and trees nested deeper than
+    /// `LOLELFFS_MAX_PATH_DEPTH` fail with `FsError::PathTooDeep`.
+    pub fn walk_tree(&mut self, dir_inode_num: u32) -> Result<Vec<WalkEntry>> {
+        let mut entries = Vec::new();
+        let mut visited = std::collections::HashSet::from([dir_inode_num]);
+        self.walk_dir(dir_inode_num, "", 1, &mut visited, &mut entries)?;
+        Ok(entries)
+    }
+
+    /// `walk_tree` reordered for reading file contents sequentially
+    ///
+    /// Directories and symlinks come first in walk order, so parents always
+    /// precede their children; regular files follow, sorted by the physical
+    /// block their contents start at. Reading the files in this order sweeps
+    /// the image front to back instead of seeking for every file.
+    pub fn walk_tree_physical(&mut self, dir_inode_num: u32) -> Result<Vec<WalkEntry>> {
+        let mut keyed = Vec::new();
+        for walk in self.walk_tree(dir_inode_num)? {
+            let start = match self.file_extents(walk.entry.inode_num)? {
+                Some(extents) => extents
+                    .iter()
+                    .min_by_key(|e| e.ee_block)
+                    .map_or(0, |e| e.ee_start as u64 + 1),
+                None => 0,
+            };
+            keyed.push((walk.entry.inode.is_file(), start, walk));
+        }
+        // Stable, so entries with equal keys keep their walk order
+        keyed.sort_by_key(|(is_file, start, _)| (*is_file, *start));
+        Ok(keyed.into_iter().map(|(_, _, walk)| walk).collect())
+    }
+
+    fn walk_dir(
+        &mut self,
+        dir_inode_num: u32,
+        prefix: &str,
+        depth: usize,
+        visited: &mut std::collections::HashSet<u32>,
+        out: &mut Vec<WalkEntry>,
+    ) -> Result<()> {
+        if depth > LOLELFFS_MAX_PATH_DEPTH {
+            return Err(FsError::PathTooDeep {
+                max: LOLELFFS_MAX_PATH_DEPTH,
+            }
+            .into());
+        }
+        let mut children = self.list_dir(dir_inode_num)?;
+        children.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+        let count = children.len();
+        for (idx, entry) in children.into_iter().enumerate() {
+            let path = if prefix.is_empty() {
+                entry.filename.clone()
+            } else {
+                format!("{}/{}", prefix, entry.filename)
+            };
+            let descend = entry.inode.is_dir() && visited.insert(entry.inode_num);
+            let inode_num = entry.inode_num;
+
+            out.push(WalkEntry {
+                path: path.clone(),
+                depth,
+                is_last: idx + 1 == count,
+                entry,
+            });
+
+            if descend {
+                self.walk_dir(inode_num, &path, depth + 1, visited, out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a file entry to a directory
+    pub fn add_dir_entry(
+        &mut self,
+        dir_inode_num: u32,
+        filename: &str,
+        file_inode_num: u32,
+    ) -> Result<()> {
+        let filename = self.stored_name(filename);
+        let filename = filename.as_ref();
+
+        if filename.len() > LOLELFFS_MAX_FILENAME - 1 {
+            bail!(
+                "Filename too long (max {} bytes)",
+                LOLELFFS_MAX_FILENAME - 1
+            );
+        }
+
+        let mut dir_inode = self.read_inode(dir_inode_num)?;
+
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+
+        // Check if file already exists
+        if self.lookup(dir_inode_num, filename)?.is_some() {
+            bail!("File '{}' already exists", filename);
+        }
+
+        // The inode the entry names must be on disk before the entry is
+        self.barrier()?;
+
+        let mut ei = if dir_inode.ei_block == 0 {
+            // Allocate extent index block for new directory
+            let ei_block = self.alloc_blocks(1)?;
+            dir_inode.ei_block = ei_block;
+            dir_inode.i_blocks += 1;
+            ExtentIndex {
+                nr_files: 0,
+                extents: vec![Extent::default(); LOLELFFS_MAX_EXTENTS],
+            }
+        } else {
+            self.read_extent_index(&dir_inode)?
+        };
+
+        // Find a slot for the new entry
+        let mut slot_found = false;
+        let mut target_block = 0u32;
+        let mut target_offset = 0usize;
+
+        // Search for empty slot in existing blocks
+        for (ext_idx, extent) in ei.extents.iter().enumerate() {
+            if extent.is_empty() && (ext_idx == 0 || ei.extents[ext_idx - 1].is_empty()) {
+                // Need to allocate first block
+                break;
+            }
+
+            for block_offset in 0..extent.ee_len {
+                let block_num = extent.ee_start + block_offset;
+                let block = self.read_dir_block(block_num)?;
+
+                for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                    let offset = file_idx * FileEntry::SIZE;
+                    let entry_data = &block[offset..offset + FileEntry::SIZE];
+
+                    // Check if slot is empty (inode 0 and no filename)
+                    if entry_data[0..4] == [0, 0, 0, 0] && entry_data[4] == 0 {
+                        target_block = block_num;
+                        target_offset = offset;
+                        slot_found = true;
+                        break;
+                    }
+                }
+
+                if slot_found {
+                    break;
+                }
+            }
+
+            if slot_found {
+                break;
+            }
+        }
+
+        // If no slot found, need to allocate new block
+        if !slot_found {
+            // Find extent with space or create new extent
+            let mut extent_idx = None;
+            let mut next_logical = 0u32;
+
+            for (idx, extent) in ei.extents.iter().enumerate() {
+                if extent.is_empty() {
+                    extent_idx = Some(idx);
+                    break;
+                }
+                next_logical = extent.ee_block + extent.ee_len;
+            }
+
+            let extent_idx = extent_idx.ok_or_else(|| anyhow::anyhow!("Directory full"))?;
+
+            // Allocate a new block
+            let new_block = self.alloc_blocks(1)?;
+
+            // Update extent
+            ei.extents[extent_idx] = Extent {
+                ee_block: next_logical,
+                ee_len: 1,
+                ee_start: new_block,
+                ee_comp_algo: LOLELFFS_COMP_NONE as u16,
+                ee_enc_algo: LOLELFFS_ENC_NONE,
+                ee_reserved: 0,
+                ee_flags: 0,
+                ee_reserved2: 0,
+                ee_meta: 0,
+            };
+
+            // Initialize the new block
+            let mut empty_block = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+            self.write_dir_block(new_block, &mut empty_block)?;
+
+            target_block = new_block;
+            target_offset = 0;
+
+            dir_inode.i_blocks += 1;
+        }
+
+        // Write the directory entry
+        let entry = FileEntry {
+            inode: file_inode_num,
+            filename: filename.to_string(),
+        };
+        let entry_data = entry.to_bytes();
+
+        let mut block = self.read_dir_block(target_block)?;
+        block[target_offset..target_offset + FileEntry::SIZE].copy_from_slice(&entry_data);
+        self.write_dir_block(target_block, &mut block)?;
+
+        // Update extent index
+        ei.nr_files += 1;
+        self.write_extent_index(dir_inode.ei_block, &ei)?;
+
+        // Update directory inode
+        dir_inode.i_size = dir_inode.i_blocks * LOLELFFS_BLOCK_SIZE;
+        let now = self.now();
+        dir_inode.i_mtime = now;
+        dir_inode.i_ctime = now;
+        self.write_inode(dir_inode_num, &dir_inode)?;
+
+        Ok(())
+    }
+
+    /// Remove a file entry from a directory
+    pub fn remove_dir_entry(&mut self, dir_inode_num: u32, filename: &str) -> Result<u32> {
+        let mut dir_inode = self.read_inode(dir_inode_num)?;
+
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+
+        if dir_inode.ei_block == 0 {
+            bail!("File '{}' not found", filename);
+        }
+
+        let mut ei = self.read_extent_index(&dir_inode)?;
+        let mut removed_inode = None;
+        let mut emptied_block = None;
+
+        // Search for the entry
+        'outer: for (ext_idx, extent) in ei.extents.iter().enumerate() {
+            if extent.is_empty() {
+                break;
+            }
+
+            for block_offset in 0..extent.ee_len {
+                let block_num = extent.ee_start + block_offset;
+                let mut block = self.read_dir_block(block_num)?;
+
+                for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                    let offset = file_idx * FileEntry::SIZE;
+                    let entry_data = &block[offset..offset + FileEntry::SIZE];
+
+                    if let Some(entry) = FileEntry::from_bytes(entry_data) {
+                        if self.names_match(&entry.filename, filename) {
+                            removed_inode = Some(entry.inode);
+
+                            // Clear the entry
+                            for byte in &mut block[offset..offset + FileEntry::SIZE] {
+                                *byte = 0;
+                            }
+                            self.write_dir_block(block_num, &mut block)?;
+
+                            if block[..LOLELFFS_DIR_CSUM_OFFSET].iter().all(|&b| b == 0) {
+                                emptied_block = Some((ext_idx, block_offset));
+                            }
+
+                            break 'outer;
+                        }
+                    }
+                }
+            }
+        }
+
+        let removed_inode =
+            removed_inode.ok_or_else(|| anyhow::anyhow!("File '{}' not found", filename))?;
+
+        // Compact: release a block left empty if it sits at the end of its extent
+        let mut freed = None;
+        if let Some((ext_idx, block_offset)) = emptied_block {
+            let extent = &mut ei.extents[ext_idx];
+            if block_offset == extent.ee_len - 1 {
+                freed = Some(extent.ee_start + block_offset);
+                extent.ee_len -= 1;
+                if extent.ee_len == 0 {
+                    ei.extents.remove(ext_idx);
+                    ei.extents.push(Extent::default());
+                }
+                dir_inode.i_blocks = dir_inode.i_blocks.saturating_sub(1);
+
+                // Keep logical block numbers dense after removing blocks
+                let mut next_logical = 0u32;
+                for extent in ei.extents.iter_mut().take_while(|e| !e.is_empty()) {
+                    extent.ee_block = next_logical;
+                    next_logical += extent.ee_len;
+                }
+            }
+        }
+
+        // Update extent index
+        ei.nr_files = ei.nr_files.saturating_sub(1);
+        self.write_extent_index(dir_inode.ei_block, &ei)?;
+
+        // Update directory inode
+        dir_inode.i_size = dir_inode.i_blocks * LOLELFFS_BLOCK_SIZE;
+        let now = self.now();
+        dir_inode.i_mtime = now;
+        dir_inode.i_ctime = now;
+        self.write_inode(dir_inode_num, &dir_inode)?;
+
+        // Nothing may be freed, here or by the caller, while the old entry
+        // could still be read back
+        self.barrier()?;
+        if let Some(block_num) = freed {
+            self.free_blocks(block_num, 1)?;
+        }
+
+        Ok(removed_inode)
+    }
+
+    /// Create a new directory
+    pub fn mkdir(&mut self, parent_inode_num: u32, name: &str) -> Result<u32> {
+        // Allocate new inode
+        let new_inode_num = self.alloc_inode()?;
+
+        // Allocate extent index block
+        let ei_block = self.alloc_blocks(1)?;
+
+        // Create the inode
+        let now = self.now();
+
+        let new_inode = Inode {
+            i_mode: mode::S_IFDIR | 0o755,
+            i_uid: 0,
+            i_gid: 0,
+            i_size: LOLELFFS_BLOCK_SIZE, // Extent index block
+            i_ctime: now,
+            i_atime: now,
+            i_mtime: now,
+            i_blocks: 1,
+            i_nlink: 2, // . and parent's link
+            ei_block,
+            xattr_block: 0, // No xattrs initially
+            i_data: [0u8; 28],
+        };
+        self.write_inode(new_inode_num, &new_inode)?;
+
+        // Initialize extent index block
+        let ei = ExtentIndex {
+            nr_files: 0,
+            extents: vec![Extent::default(); LOLELFFS_MAX_EXTENTS],
+        };
+        self.write_extent_index(ei_block, &ei)?;
+
+        // Add entry to parent directory
+        if let Err(e) = crate::xattr::inherit_default_xattrs(self, parent_inode_num, new_inode_num)
+            .and_then(|()| self.add_dir_entry(parent_inode_num, name, new_inode_num))
+        {
+            // Rollback on failure
+            self.free_inode_xattrs(new_inode_num)?;
+            self.free_inode(new_inode_num)?;
+            self.free_blocks(ei_block, 1)?;
+            return Err(e);
+        }
+
+        // Increment parent's link count
+        let mut parent_inode = self.read_inode(parent_inode_num)?;
+        parent_inode.i_nlink += 1;
+        self.write_inode(parent_inode_num, &parent_inode)?;
+
+        Ok(new_inode_num)
+    }
+
+    /// Remove a directory (must be empty)
+    pub fn rmdir(&mut self, parent_inode_num: u32, name: &str) -> Result<()> {
+        // Look up the directory
+        let dir_inode_num = self
+            .lookup(parent_inode_num, name)?
+            .ok_or_else(|| anyhow::anyhow!("Directory '{}' not found", name))?;
+
+        let dir_inode = self.read_inode(dir_inode_num)?;
+
+        if !dir_inode.is_dir() {
+            bail!("'{}' is not a directory", name);
+        }
+
+        // Check if directory is empty
+        let entries = self.list_dir(dir_inode_num)?;
+        if !entries.is_empty() {
+            bail!("Directory '{}' is not empty", name);
+        }
+
+        // Remove from parent
+        self.remove_dir_entry(parent_inode_num, name)?;
+
+        // Free extent index block
+        if dir_inode.ei_block != 0 {
+            self.free_blocks(dir_inode.ei_block, 1)?;
+        }
+
+        // Free any data blocks
+        if dir_inode.ei_block != 0 {
+            let ei = self.read_extent_index(&dir_inode)?;
+            for extent in &ei.extents {
+                if extent.is_empty() {
+                    break;
+                }
+                self.free_blocks(extent.ee_start, extent.ee_len)?;
+            }
+        }
+
+        // Free xattr blocks
+        self.free_inode_xattrs(dir_inode_num)?;
+
+        // Free the inode
+        self.free_inode(dir_inode_num)?;
+
+        // Decrement parent's link count
+        let mut parent_inode = self.read_inode(parent_inode_num)?;
+        parent_inode.i_nlink = parent_inode.i_nlink.saturating_sub(1);
+        self.write_inode(parent_inode_num, &parent_inode)?;
+
+        Ok(())
+    }
+
+    /// Rename or move a directory entry
+    ///
+    /// An existing destination is replaced, following POSIX rules: a file may
+    /// replace a file, and a directory may replace an empty directory. When the
+    /// destination exists its entry is rewritten in place, so the name never
+    /// disappears; otherwise the new entry is added before the old one is
+    /// removed, and the addition is rolled back if the removal fails.
+    pub fn rename(
+        &mut self,
+        old_parent: u32,
+        old_name: &str,
+        new_parent: u32,
+        new_name: &str,
+    ) -> Result<()> {
+        let inode_num = self
+            .lookup(old_parent, old_name)?
+            .ok_or_else(|| anyhow::anyhow!("'{}' not found", old_name))?;
+
+        if !self.read_inode(new_parent)?.is_dir() {
+            bail!("Inode {} is not a directory", new_parent);
+        }
+
+        if old_parent == new_parent && old_name == new_name {
+            return Ok(());
+        }
+
+        let inode = self.read_inode(inode_num)?;
+
+        // A directory cannot be moved beneath itself
+        if inode.is_dir() && (new_parent == inode_num || self.is_ancestor(inode_num, new_parent)?) {
+            bail!("Cannot move '{}' into its own subdirectory", old_name);
+        }
+
+        match self.lookup(new_parent, new_name)? {
+            // Respelling one entry (e.g. NFD to NFC) rewrites it under the new name
+            Some(existing)
+                if existing == inode_num
+                    && old_parent == new_parent
+                    && self.normalization != NormalizationPolicy::Off
+                    && old_name.nfc().eq(new_name.nfc()) =>
+            {
+                self.remove_dir_entry(old_parent, old_name)?;
+                if let Err(e) = self.add_dir_entry(new_parent, new_name, inode_num) {
+                    self.add_dir_entry(old_parent, old_name, inode_num)?;
+                    return Err(e);
+                }
+            }
+            // Renaming onto another name for the same inode leaves both in place
+            Some(existing) if existing == inode_num => return Ok(()),
+            Some(existing) => {
+                let existing_inode = self.read_inode(existing)?;
+                if inode.is_dir() && !existing_inode.is_dir() {
+                    bail!(
+                        "Cannot overwrite non-directory '{}' with a directory",
+                        new_name
+                    );
+                }
+                if !inode.is_dir() && existing_inode.is_dir() {
+                    bail!(
+                        "Cannot overwrite directory '{}' with a non-directory",
+                        new_name
+                    );
+                }
+                if existing_inode.is_dir() && !self.list_dir(existing)?.is_empty() {
+                    bail!("Directory '{}' is not empty", new_name);
+                }
+
+                self.set_dir_entry_inode(new_parent, new_name, inode_num)?;
+                self.remove_dir_entry(old_parent, old_name)?;
+                self.release_replaced(new_parent, existing, existing_inode)?;
+            }
+            None => {
+                self.add_dir_entry(new_parent, new_name, inode_num)?;
+                if let Err(e) = self.remove_dir_entry(old_parent, old_name) {
+                    self.remove_dir_entry(new_parent, new_name)?;
+                    return Err(e);
+                }
+            }
+        }
+
+        // A moved directory's link to its parent follows it
+        if inode.is_dir() && old_parent != new_parent {
+            let mut parent_inode = self.read_inode(old_parent)?;
+            parent_inode.i_nlink = parent_inode.i_nlink.saturating_sub(1);
+            self.write_inode(old_parent, &parent_inode)?;
+
+            let mut parent_inode = self.read_inode(new_parent)?;
+            parent_inode.i_nlink += 1;
+            self.write_inode(new_parent, &parent_inode)?;
+        }
+
+        let mut inode = self.read_inode(inode_num)?;
+        inode.i_ctime = self.now();
+        // The compression exclusion follows the file's new name
+        if inode.is_file() {
+            let flags = inode.flags() & !LOLELFFS_INODE_NOCOMP;
+            if self.is_comp_excluded(new_name) {
+                inode.set_flags(flags | LOLELFFS_INODE_NOCOMP);
+            } else {
+                inode.set_flags(flags);
+            }
+        }
+        self.write_inode(inode_num, &inode)?;
+
+        Ok(())
+    }
+
+    /// Check whether `dir_inode_num` lies somewhere beneath `ancestor`
+    fn is_ancestor(&mut self, ancestor: u32, dir_inode_num: u32) -> Result<bool> {
+        for entry in self.list_dir(ancestor)? {
+            if !entry.inode.is_dir() {
+                continue;
+            }
+            if entry.inode_num == dir_inode_num
+                || self.is_ancestor(entry.inode_num, dir_inode_num)?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Point an existing directory entry at a different inode
+    fn set_dir_entry_inode(
+        &mut self,
+        dir_inode_num: u32,
+        name: &str,
+        inode_num: u32,
+    ) -> Result<()> {
+        let dir_inode = self.read_inode(dir_inode_num)?;
+        let ei = self.read_extent_index(&dir_inode)?;
+
+        for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+            for block_offset in 0..extent.ee_len {
+                let block_num = extent.ee_start + block_offset;
+                let mut block = self.read_dir_block(block_num)?;
+
+                for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+                    let offset = file_idx * FileEntry::SIZE;
+                    let entry_data = &block[offset..offset + FileEntry::SIZE];
+
+                    if let Some(entry) = FileEntry::from_bytes(entry_data) {
+                        if self.names_match(&entry.filename, name) {
+                            let entry = FileEntry {
+                                inode: inode_num,
+                                filename: entry.filename,
+                            };
+                            block[offset..offset + FileEntry::SIZE]
+                                .copy_from_slice(&entry.to_bytes());
+                            return self.write_dir_block(block_num, &mut block);
+                        }
+                    }
+                }
+            }
+        }
+
+        bail!("File '{}' not found", name)
+    }
+
+    /// Drop the inode whose directory entry was overwritten by a rename
+    fn release_replaced(
+        &mut self,
+        parent_inode_num: u32,
+        inode_num: u32,
+        inode: Inode,
+    ) -> Result<()> {
+        let mut inode = inode;
+
+        if inode.is_dir() {
+            inode.i_nlink = 0;
+            let mut parent_inode = self.read_inode(parent_inode_num)?;
+            parent_inode.i_nlink = parent_inode.i_nlink.saturating_sub(1);
+            self.write_inode(parent_inode_num, &parent_inode)?;
+        } else {
+            inode.i_nlink = inode.i_nlink.saturating_sub(1);
+        }
+
+        if inode.i_nlink > 0 {
+            return self.write_inode(inode_num, &inode);
+        }
+
+        self.free_unlinked(inode_num, &inode)
+    }
+
+    /// Read a directory block, verifying its checksum unless the verify
+    /// policy is `Never`
+    pub(crate) fn read_dir_block(&mut self, block_num: u32) -> Result<Vec<u8>> {
+        self.read_block_checked(block_num, BlockKind::Directory)
+    }
+
+    /// Stamp a directory block's checksum (if enabled) and write it
+    pub(crate) fn write_dir_block(&mut self, block_num: u32, block: &mut [u8]) -> Result<()> {
+        let algo = self.superblock.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM);
+        block[LOLELFFS_DIR_CSUM_OFFSET..].fill(0);
+        if algo != LOLELFFS_HASH_NONE {
+            let digest = dir_block_csum(algo, block_num, block)?;
+            block[LOLELFFS_DIR_CSUM_OFFSET..LOLELFFS_DIR_CSUM_OFFSET + digest.len()]
+                .copy_from_slice(&digest);
+        }
+        self.write_block(block_num, block)
+    }
+
+    /// Whether a directory block's stored checksum matches its contents
+    pub(crate) fn dir_block_csum_ok(&self, block_num: u32, block: &[u8]) -> Result<bool> {
+        let algo = self.superblock.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM);
+        // An algorithm from a newer tool cannot be checked; the image is
+        // read-only then, and the gap is reported by `compat_issues`
+        if algo == LOLELFFS_HASH_NONE || !crate::compat::hash_algo_supported(algo) {
+            return Ok(true);
+        }
+        let digest = dir_block_csum(algo, block_num, block)?;
+        Ok(block[LOLELFFS_DIR_CSUM_OFFSET..LOLELFFS_DIR_CSUM_OFFSET + digest.len()] == digest[..])
+    }
+
+    /// Physical data blocks of a directory, in logical order
+    pub fn dir_blocks(&mut self, dir_inode_num: u32) -> Result<Vec<u32>> {
+        let dir_inode = self.read_inode(dir_inode_num)?;
+        if !dir_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+        if dir_inode.ei_block == 0 {
+            return Ok(Vec::new());
+        }
+
+        let ei = self.read_extent_index(&dir_inode)?;
+        Ok(ei
+            .extents
+            .iter()
+            .take_while(|e| !e.is_empty())
+            .flat_map(|e| e.ee_start..e.ee_start + e.ee_len)
+            .collect())
+    }
+
+    /// Check a directory block's checksum (always true when checksums are off)
+    pub fn verify_dir_block(&mut self, block_num: u32) -> Result<bool> {
+        let block = self.read_block(block_num)?;
+        self.dir_block_csum_ok(block_num, &block)
+    }
+
+    /// Drop unparseable entries from a directory block and re-stamp its checksum
+    ///
+    /// An entry survives if it names an allocated inode and its name is
+    /// valid UTF-8 with clean zero padding, which a half-written entry from a
+    /// torn write is unlikely to have. Returns the number of entries dropped.
+    pub fn repair_dir_block(&mut self, block_num: u32) -> Result<u32> {
+        let mut block = self.read_block(block_num)?;
+        let mut dropped = 0;
+
+        for file_idx in 0..LOLELFFS_FILES_PER_BLOCK {
+            let offset = file_idx * FileEntry::SIZE;
+            let entry_data = &block[offset..offset + FileEntry::SIZE];
+            if entry_data.iter().all(|&b| b == 0) {
+                continue;
+            }
+            if !self.plausible_dir_entry(entry_data)? {
+                block[offset..offset + FileEntry::SIZE].fill(0);
+                dropped += 1;
+            }
+        }
+
+        self.write_dir_block(block_num, &mut block)?;
+        Ok(dropped)
+    }
+
+    fn plausible_dir_entry(&mut self, entry_data: &[u8]) -> Result<bool> {
+        let inode_num =
+            u32::from_le_bytes([entry_data[0], entry_data[1], entry_data[2], entry_data[3]]);
+        let name = &entry_data[4..];
+        let name_len = match name.iter().position(|&b| b == 0) {
+            Some(len) if len > 0 => len,
+            _ => return Ok(false),
+        };
+
+        Ok(inode_num < self.superblock.nr_inodes
+            && !self.is_inode_free(inode_num)?
+            && name[name_len..].iter().all(|&b| b == 0)
+            && std::str::from_utf8(&name[..name_len]).is_ok_and(|n| !n.contains('/')))
+    }
+
+    /// Select the checksum algorithm for directory blocks, re-stamping them all
+    ///
+    /// `LOLELFFS_HASH_NONE` turns checksums off. The kernel module does not
+    /// maintain these checksums, so they also set `LOLELFFS_FEATURE_DIR_CSUM`,
+    /// which it refuses.
+    pub fn set_dir_checksums(&mut self, algo: u8) -> Result<()> {
+        if algo != LOLELFFS_HASH_NONE && crate::hash::digest_size(algo) == 0 {
+            bail!("Unsupported hash algorithm: {}", algo);
+        }
+
+        // Verify everything under the old algorithm before switching
+        let mut blocks = Vec::new();
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? || !self.read_inode(inode_num)?.is_dir() {
+                continue;
+            }
+            for block_num in self.dir_blocks(inode_num)? {
+                if !self.verify_dir_block(block_num)? {
+                    bail!(
+                        "Directory block {} is corrupt; repair it with fsck first",
+                        block_num
+                    );
+                }
+                blocks.push(block_num);
+            }
+        }
+
+        self.superblock
+            .set_hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM, algo);
+        if algo == LOLELFFS_HASH_NONE {
+            self.superblock.comp_features &= !LOLELFFS_FEATURE_DIR_CSUM;
+        } else {
+            self.superblock.comp_features |= LOLELFFS_FEATURE_DIR_CSUM;
+        }
+        self.write_superblock()?;
+
+        for block_num in blocks {
+            let mut block = self.read_block(block_num)?;
+            self.write_dir_block(block_num, &mut block)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks a directory should account for: its extent index plus data blocks
+    pub fn dir_allocated_blocks(&mut self, dir_inode: &Inode) -> Result<u32> {
+        if dir_inode.ei_block == 0 {
+            return Ok(0);
+        }
+
+        let ei = self.read_extent_index(dir_inode)?;
+        Ok(1 + ei.total_blocks())
+    }
+
+    /// Remove a directory entry and, for directories, everything beneath it
+    pub fn remove_tree(&mut self, parent_inode_num: u32, name: &str) -> Result<()> {
+        let inode_num = self
+            .lookup(parent_inode_num, name)?
+            .ok_or_else(|| anyhow::anyhow!("'{}' not found", name))?;
+
+        let inode = self.read_inode(inode_num)?;
+
+        if !inode.is_dir() {
+            return self.unlink(parent_inode_num, name);
+        }
+
+        for entry in self.list_dir(inode_num)? {
+            self.remove_tree(inode_num, &entry.filename)?;
+        }
+
+        self.rmdir(parent_inode_num, name)
+    }
+}
+
+/// Reject paths over the length or depth limits, or with overlong components
+pub fn check_path_limits(path: &str) -> Result<()> {
+    if path.len() > LOLELFFS_PATH_MAX {
+        return Err(FsError::NameTooLong {
+            len: path.len(),
+            max: LOLELFFS_PATH_MAX,
+        }
+        .into());
+    }
+    let mut depth = 0;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if component.len() > LOLELFFS_MAX_FILENAME {
+            return Err(FsError::NameTooLong {
+                len: component.len(),
+                max: LOLELFFS_MAX_FILENAME,
+            }
+            .into());
+        }
+        depth += 1;
+    }
+    if depth > LOLELFFS_MAX_PATH_DEPTH {
+        return Err(FsError::PathTooDeep {
+            max: LOLELFFS_MAX_PATH_DEPTH,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Checksum of a directory block's entries, bound to its block number
+fn dir_block_csum(algo: u8, block_num: u32, block: &[u8]) -> Result<Vec<u8>> {
+    let mut hasher = crate::hash::Hasher::new(algo)?;
+    hasher.update(&block[..LOLELFFS_DIR_CSUM_OFFSET]);
+    hasher.update(&block_num.to_le_bytes());
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::VerifyPolicy;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_dir_size_tracks_allocated_blocks() {
+        let (_path, mut fs) = temp_image("dir.img");
+
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let inode = fs.read_inode(dir).unwrap();
+        assert_eq!(inode.i_size, LOLELFFS_BLOCK_SIZE);
+
+        // One more entry than fits in a single block spills into a second block
+        for i in 0..=LOLELFFS_FILES_PER_BLOCK {
+            fs.create_file(dir, &format!("f{}", i)).unwrap();
+        }
+        let inode = fs.read_inode(dir).unwrap();
+        assert_eq!(inode.i_blocks, 3);
+        assert_eq!(inode.i_size, 3 * LOLELFFS_BLOCK_SIZE);
+        assert_eq!(fs.dir_allocated_blocks(&inode).unwrap(), 3);
+
+        // Emptying the trailing block releases it
+        let free_before = fs.superblock.nr_free_blocks;
+        fs.unlink(dir, &format!("f{}", LOLELFFS_FILES_PER_BLOCK))
+            .unwrap();
+        let inode = fs.read_inode(dir).unwrap();
+        assert_eq!(inode.i_size, 2 * LOLELFFS_BLOCK_SIZE);
+        assert_eq!(fs.dir_allocated_blocks(&inode).unwrap(), 2);
+        // The file's extent index block plus the emptied directory block
+        assert_eq!(fs.superblock.nr_free_blocks, free_before + 2);
+        assert_eq!(fs.list_dir(dir).unwrap().len(), LOLELFFS_FILES_PER_BLOCK);
+    }
+
+    #[test]
+    fn test_rename() {
+        let (_path, mut fs) = temp_image("rename.img");
+
+        let a = fs.mkdir(LOLELFFS_ROOT_INO, "a").unwrap();
+        let b = fs.mkdir(LOLELFFS_ROOT_INO, "b").unwrap();
+        let file = fs.create_file(a, "f").unwrap();
+        fs.write_file(file, b"hello").unwrap();
+
+        // Rename in place, then move across directories
+        fs.rename(a, "f", a, "g").unwrap();
+        assert_eq!(fs.lookup(a, "f").unwrap(), None);
+        fs.rename(a, "g", b, "h").unwrap();
+        assert!(fs.list_dir(a).unwrap().is_empty());
+        assert_eq!(fs.resolve_path("/b/h").unwrap(), file);
+        assert_eq!(fs.read_file(file).unwrap(), b"hello");
+
+        // Replacing a file frees the old one
+        let victim = fs.create_file(b, "victim").unwrap();
+        fs.write_file(victim, b"old").unwrap();
+        let free_before = fs.superblock.nr_free_blocks;
+        fs.rename(b, "h", b, "victim").unwrap();
+        assert_eq!(fs.resolve_path("/b/victim").unwrap(), file);
+        assert_eq!(fs.list_dir(b).unwrap().len(), 1);
+        assert_eq!(fs.superblock.nr_free_blocks, free_before + 2);
+
+        // Moving a directory carries its parent link along
+        let root_links = fs.read_inode(LOLELFFS_ROOT_INO).unwrap().i_nlink;
+        fs.rename(LOLELFFS_ROOT_INO, "b", a, "b").unwrap();
+        assert_eq!(
+            fs.read_inode(LOLELFFS_ROOT_INO).unwrap().i_nlink,
+            root_links - 1
+        );
+        assert_eq!(fs.read_inode(a).unwrap().i_nlink, 3);
+        assert_eq!(fs.resolve_path("/a/b/victim").unwrap(), file);
+
+        // ...but never beneath itself, and never over a non-empty directory
+        assert!(fs.rename(LOLELFFS_ROOT_INO, "a", b, "a").is_err());
+        assert!(fs.rename(LOLELFFS_ROOT_INO, "a", a, "x").is_err());
+        let c = fs.mkdir(LOLELFFS_ROOT_INO, "c").unwrap();
+        fs.create_file(c, "keep").unwrap();
+        assert!(fs.rename(a, "b", LOLELFFS_ROOT_INO, "c").is_err());
+        assert_eq!(fs.resolve_path("/a/b").unwrap(), b);
+    }
+
+    #[test]
+    fn test_walk_tree() {
+        let (_path, mut fs) = temp_image("walk.img");
+
+        let b = fs.mkdir(LOLELFFS_ROOT_INO, "b").unwrap();
+        let z = fs.create_file(b, "z").unwrap();
+        let y = fs.create_file(b, "y").unwrap();
+        let a = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+
+        let walked: Vec<_> = fs
+            .walk_tree(LOLELFFS_ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.path, e.depth, e.is_last))
+            .collect();
+        assert_eq!(
+            walked,
+            vec![
+                ("a".to_string(), 1, false),
+                ("b".to_string(), 1, true),
+                ("b/y".to_string(), 2, false),
+                ("b/z".to_string(), 2, true),
+            ]
+        );
+
+        // Physical order puts directories first, then files as written
+        for ino in [z, y, a] {
+            fs.write_file(ino, b"data").unwrap();
+        }
+        let walked: Vec<_> = fs
+            .walk_tree_physical(LOLELFFS_ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(walked, vec!["b", "b/z", "b/y", "a"]);
+    }
+
+    #[test]
+    fn test_complete_path() {
+        let (_path, mut fs) = temp_image("complete.img");
+
+        let etc = fs.mkdir(LOLELFFS_ROOT_INO, "etc").unwrap();
+        fs.create_file(etc, "hosts").unwrap();
+        fs.create_file(etc, "hostname").unwrap();
+        fs.create_file(etc, ".hidden").unwrap();
+        fs.create_file(LOLELFFS_ROOT_INO, "empty").unwrap();
+
+        assert_eq!(fs.complete_path("/e").unwrap(), vec!["/empty", "/etc/"]);
+        assert_eq!(fs.complete_path("et").unwrap(), vec!["etc/"]);
+        assert_eq!(
+            fs.complete_path("/etc/").unwrap(),
+            vec!["/etc/hostname", "/etc/hosts"]
+        );
+        assert_eq!(fs.complete_path("/etc/.").unwrap(), vec!["/etc/.hidden"]);
+        assert!(fs.complete_path("/nope/x").unwrap().is_empty());
+        assert!(fs.complete_path("/empty/x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_normalization_policy() {
+        let (_path, mut fs) = temp_image("nfc.img");
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+
+        // Off: the two spellings are different names
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, nfd).unwrap();
+        assert_eq!(fs.lookup(LOLELFFS_ROOT_INO, nfc).unwrap(), None);
+
+        // NormalizeOnLookup: either spelling finds the stored NFD name
+        fs.normalization = NormalizationPolicy::NormalizeOnLookup;
+        assert_eq!(fs.lookup(LOLELFFS_ROOT_INO, nfc).unwrap(), Some(ino));
+        assert!(fs.create_file(LOLELFFS_ROOT_INO, nfc).is_err());
+        assert_eq!(fs.list_dir(LOLELFFS_ROOT_INO).unwrap()[0].filename, nfd);
+
+        // Renaming to the other spelling re-stores the entry
+        fs.normalization = NormalizationPolicy::NfcOnCreate;
+        fs.rename(LOLELFFS_ROOT_INO, nfd, LOLELFFS_ROOT_INO, nfc)
+            .unwrap();
+        let entries = fs.list_dir(LOLELFFS_ROOT_INO).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, nfc);
+
+        // NfcOnCreate: new names are stored in NFC and found by either spelling
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let other = fs.create_file(dir, nfd).unwrap();
+        assert_eq!(fs.list_dir(dir).unwrap()[0].filename, nfc);
+        assert_eq!(fs.lookup(dir, nfd).unwrap(), Some(other));
+        fs.unlink(dir, nfd).unwrap();
+        assert!(fs.list_dir(dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dir_block_checksums() {
+        let (_path, mut fs) = temp_image("dircsum.img");
+        fs.create_file(LOLELFFS_ROOT_INO, "before").unwrap();
+        fs.set_dir_checksums(LOLELFFS_HASH_CRC32C).unwrap();
+        fs.create_file(LOLELFFS_ROOT_INO, "after").unwrap();
+        assert_ne!(fs.superblock.comp_features & LOLELFFS_FEATURE_DIR_CSUM, 0);
+
+        let block_num = fs.dir_blocks(LOLELFFS_ROOT_INO).unwrap()[0];
+        assert!(fs.verify_dir_block(block_num).unwrap());
+        assert_eq!(fs.list_dir(LOLELFFS_ROOT_INO).unwrap().len(), 2);
+
+        // Simulate a torn write: half of a new entry reaches the disk
+        let mut block = fs.read_block(block_num).unwrap();
+        let offset = 2 * FileEntry::SIZE;
+        block[offset..offset + 4].copy_from_slice(&1u32.to_le_bytes());
+        block[offset + 4..offset + 100].fill(b'x');
+        block[offset + 100..offset + 200].fill(0xAB);
+        fs.write_block(block_num, &block).unwrap();
+
+        assert!(!fs.verify_dir_block(block_num).unwrap());
+        let err = fs.list_dir(LOLELFFS_ROOT_INO).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FsError>(),
+            Some(FsError::ChecksumMismatch { .. })
+        ));
+
+        // The fast path skips verification and reads the block as it is
+        fs.verify = VerifyPolicy::Never;
+        assert!(fs.list_dir(LOLELFFS_ROOT_INO).is_ok());
+        fs.verify = VerifyPolicy::Metadata;
+
+        assert_eq!(fs.repair_dir_block(block_num).unwrap(), 1);
+        assert!(fs.verify_dir_block(block_num).unwrap());
+        let mut names: Vec<_> = fs
+            .list_dir(LOLELFFS_ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.filename)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["after", "before"]);
+
+        fs.set_dir_checksums(LOLELFFS_HASH_NONE).unwrap();
+        assert_eq!(fs.superblock.comp_features & LOLELFFS_FEATURE_DIR_CSUM, 0);
+        let block = fs.read_block(block_num).unwrap();
+        assert!(block[LOLELFFS_DIR_CSUM_OFFSET..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_symlink_loops_and_limits() {
+        let (_path, mut fs) = temp_image("symloop.img");
+
+        let etc = fs.mkdir(LOLELFFS_ROOT_INO, "etc").unwrap();
+        let hosts = fs.create_file(etc, "hosts").unwrap();
+        fs.symlink(LOLELFFS_ROOT_INO, "cfg", "etc").unwrap();
+        fs.symlink(etc, "up", "../cfg/hosts").unwrap();
+        let abs = fs.symlink(etc, "abs", "/etc/hosts").unwrap();
+
+        assert_eq!(fs.resolve_path_follow("/cfg/hosts", true).unwrap(), hosts);
+        assert_eq!(fs.resolve_path_follow("/etc/up", true).unwrap(), hosts);
+        assert_eq!(fs.resolve_path_follow("/cfg/abs", true).unwrap(), hosts);
+        assert_eq!(fs.resolve_path_follow("/etc/abs", false).unwrap(), abs);
+        assert!(fs.resolve_path_follow("/etc/hosts/x", true).is_err());
+
+        // A link to itself, and a pair pointing at each other
+        fs.symlink(LOLELFFS_ROOT_INO, "self", "self").unwrap();
+        fs.symlink(LOLELFFS_ROOT_INO, "ping", "/pong").unwrap();
+        fs.symlink(LOLELFFS_ROOT_INO, "pong", "ping").unwrap();
+        for looping in ["/self", "/ping", "/pong/x", "/cfg/../self"] {
+            let err = fs.resolve_path_follow(looping, true).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<FsError>(),
+                    Some(FsError::SymlinkLoop { .. })
+                ),
+                "{}: {}",
+                looping,
+                err
+            );
+        }
+        // Not following the last component never loops
+        assert!(fs.resolve_path_follow("/self", false).is_ok());
+
+        // A link that expands into itself keeps growing the path; it is
+        // stopped rather than resolved
+        fs.symlink(LOLELFFS_ROOT_INO, "deep", "etc/../deep/x")
+            .unwrap();
+        assert!(fs.resolve_path_follow("/deep", true).is_err());
+
+        // Length and depth limits apply to the plain resolver too
+        let long = "a".repeat(LOLELFFS_MAX_FILENAME + 1);
+        let err = fs.resolve_path(&long).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FsError>(),
+            Some(FsError::NameTooLong { .. })
+        ));
+        let deep = "a/".repeat(LOLELFFS_MAX_PATH_DEPTH + 1);
+        let err = fs.resolve_path(&deep).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FsError>(),
+            Some(FsError::PathTooDeep { .. })
+        ));
+        assert!(fs.resolve_path(&"/".repeat(LOLELFFS_PATH_MAX + 1)).is_err());
+    }
+}
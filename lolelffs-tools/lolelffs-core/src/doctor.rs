@@ -0,0 +1,355 @@
+//! One-shot health summary
+//!
+//! `doctor` runs the checks that are cheap enough to do on every support
+//! request: superblock sanity, free counts against the bitmaps, file and
+//! free-space fragmentation, space and inode usage, and the strength of the
+//! encryption parameters. Each finding carries the command that addresses
+//! it. Nothing here walks the directory tree, so it is no substitute for
+//! `fsck`; the bitmap reconciliation in particular only compares counts and
+//! cannot see leaked or doubly used blocks.
+
+use crate::defrag::FragStats;
+use crate::fs::{FsStats, LolelfFs};
+use crate::types::*;
+use anyhow::Result;
+use std::fmt;
+
+/// PBKDF2 iterations below which the password is considered weakly
+/// protected; what `mkfs --encrypt` uses by default
+pub const RECOMMENDED_KDF_ITERATIONS: u32 = 100_000;
+
+/// Fragmentation score from which `defrag` is suggested
+const FRAG_SCORE_WARN: u32 = 25;
+
+/// Usage percentage from which space or inodes are reported as running out
+const USE_PERCENT_WARN: u32 = 90;
+
+/// How much a finding matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Optional improvement
+    Note,
+    /// Worth acting on soon
+    Warning,
+    /// The image is damaged or unusable as it is
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Note => "note",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something `doctor` noticed, with what to do about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub level: Level,
+    /// Which check produced it, e.g. "superblock" or "encryption"
+    pub area: &'static str,
+    pub message: String,
+    /// Suggested fix, if there is one
+    pub advice: Option<String>,
+}
+
+/// Result of `LolelfFs::doctor`
+#[derive(Debug, Clone)]
+pub struct DoctorReport {
+    pub stats: FsStats,
+    pub frag: FragStats,
+    /// Free inodes according to the inode bitmap
+    pub bitmap_free_inodes: u32,
+    /// Free blocks according to the block bitmap
+    pub bitmap_free_blocks: u32,
+    pub findings: Vec<Finding>,
+}
+
+impl DoctorReport {
+    /// Physical runs beyond the first of each file, as a percentage of all
+    /// runs: 0 when every file is contiguous
+    pub fn frag_score(&self) -> u32 {
+        if self.frag.runs == 0 {
+            return 0;
+        }
+        (self.frag.runs - self.frag.files) * 100 / self.frag.runs
+    }
+
+    /// Worst level among the findings, `None` if there are none
+    pub fn worst(&self) -> Option<Level> {
+        self.findings.iter().map(|f| f.level).max()
+    }
+
+    /// Distinct advice of all findings, most severe first
+    pub fn recommendations(&self) -> Vec<&str> {
+        let mut findings: Vec<&Finding> = self.findings.iter().collect();
+        findings.sort_by_key(|f| std::cmp::Reverse(f.level));
+        let mut advice: Vec<&str> = Vec::new();
+        for text in findings.iter().filter_map(|f| f.advice.as_deref()) {
+            if !advice.contains(&text) {
+                advice.push(text);
+            }
+        }
+        advice
+    }
+
+    fn add(&mut self, level: Level, area: &'static str, message: String, advice: Option<&str>) {
+        self.findings.push(Finding {
+            level,
+            area,
+            message,
+            advice: advice.map(str::to_string),
+        });
+    }
+}
+
+impl LolelfFs {
+    /// Set bits among the first `nbits` bits of the bitmap starting at block
+    /// `start`, reading each bitmap block once
+    pub(crate) fn count_set_bits(&mut self, start: u32, nbits: u32) -> Result<u32> {
+        let mut count = 0;
+        let mut bit = 0;
+        while bit < nbits {
+            let block = self.read_block(start + bit / LOLELFFS_BITS_PER_BLOCK)?;
+            let in_block = (nbits - bit).min(LOLELFFS_BITS_PER_BLOCK);
+            let whole = (in_block / 8) as usize;
+            count += block[..whole].iter().map(|b| b.count_ones()).sum::<u32>();
+            for i in (whole as u32 * 8)..in_block {
+                if block[(i / 8) as usize] & (1 << (i % 8)) != 0 {
+                    count += 1;
+                }
+            }
+            bit += in_block;
+        }
+        Ok(count)
+    }
+
+    /// Run the quick health checks and collect what they find
+    pub fn doctor(&mut self) -> Result<DoctorReport> {
+        let sb = self.superblock.clone();
+        let mut report = DoctorReport {
+            stats: self.statfs(),
+            frag: FragStats::default(),
+            bitmap_free_inodes: 0,
+            bitmap_free_blocks: 0,
+            findings: Vec::new(),
+        };
+
+        // Superblock sanity; with a broken layout the bitmaps cannot be
+        // trusted either, so stop there
+        let layout = sb.layout_issues();
+        for issue in &layout {
+            report.add(
+                Level::Error,
+                "superblock",
+                issue.clone(),
+                Some("run `lolelffs fsck` and restore from a backup if it cannot repair the image"),
+            );
+        }
+        if sb.nr_free_inodes > sb.nr_inodes || sb.nr_free_blocks > sb.nr_blocks {
+            report.add(
+                Level::Error,
+                "superblock",
+                "free counts exceed the totals".to_string(),
+                Some("run `lolelffs fsck` for a full check"),
+            );
+        }
+        for issue in sb.compat_issues() {
+            report.add(
+                Level::Warning,
+                "compatibility",
+                format!("not supported by this build: {}", issue),
+                Some("use a newer lolelffs to write to this image"),
+            );
+        }
+        if !layout.is_empty() {
+            return Ok(report);
+        }
+
+        // Free counts against the bitmaps
+        report.bitmap_free_inodes = self.count_set_bits(sb.ifree_bitmap_start(), sb.nr_inodes)?;
+        report.bitmap_free_blocks = self.count_set_bits(sb.bfree_bitmap_start(), sb.nr_blocks)?;
+        let free_meta = self.count_set_bits(sb.bfree_bitmap_start(), sb.data_block_start())?;
+        if free_meta > 0 {
+            report.add(
+                Level::Error,
+                "bitmaps",
+                format!("{} metadata blocks are marked free", free_meta),
+                Some("run `lolelffs fsck` for a full check"),
+            );
+        }
+        if report.bitmap_free_inodes != sb.nr_free_inodes {
+            report.add(
+                Level::Warning,
+                "bitmaps",
+                format!(
+                    "superblock counts {} free inodes, the bitmap has {}",
+                    sb.nr_free_inodes, report.bitmap_free_inodes
+                ),
+                Some("run `lolelffs fsck` for a full check"),
+            );
+        }
+        if report.bitmap_free_blocks != sb.nr_free_blocks {
+            report.add(
+                Level::Warning,
+                "bitmaps",
+                format!(
+                    "superblock counts {} free blocks, the bitmap has {}",
+                    sb.nr_free_blocks, report.bitmap_free_blocks
+                ),
+                Some("run `lolelffs fsck` for a full check"),
+            );
+        }
+
+        // Fragmentation
+        report.frag = self.frag_stats()?;
+        let score = report.frag_score();
+        if score >= FRAG_SCORE_WARN {
+            report.add(
+                Level::Warning,
+                "fragmentation",
+                format!(
+                    "fragmentation score {} ({} of {} files fragmented)",
+                    score, report.frag.fragmented_files, report.frag.files
+                ),
+                Some("run `lolelffs defrag` to rewrite fragmented files contiguously"),
+            );
+        }
+        let free = report.stats.free_blocks;
+        if free > 0 && report.frag.largest_free_run < free / 4 {
+            report.add(
+                Level::Note,
+                "fragmentation",
+                format!(
+                    "free space is split into {} runs, the largest {} blocks",
+                    report.frag.free_runs, report.frag.largest_free_run
+                ),
+                Some("run `lolelffs defrag` to rewrite fragmented files contiguously"),
+            );
+        }
+
+        // Space
+        let block_use = report.stats.block_use_percent();
+        if block_use >= USE_PERCENT_WARN {
+            report.add(
+                Level::Warning,
+                "space",
+                format!("{}% of blocks are in use", block_use),
+                Some("grow the image with `lolelffs resize`"),
+            );
+        }
+        let inode_use = report.stats.inode_use_percent();
+        if inode_use >= USE_PERCENT_WARN {
+            report.add(
+                Level::Warning,
+                "space",
+                format!("{}% of inodes are in use", inode_use),
+                Some("grow the image with `lolelffs resize`, which adds inodes too"),
+            );
+        }
+
+        // Encryption parameters
+        if sb.enc_enabled != 0 {
+            let kdf = sb.enc_kdf_algo as u8;
+            if kdf == LOLELFFS_KDF_NONE {
+                report.add(
+                    Level::Error,
+                    "encryption",
+                    "the master key is wrapped without a key derivation function".to_string(),
+                    None,
+                );
+            } else if kdf == LOLELFFS_KDF_PBKDF2
+                && sb.enc_kdf_iterations < RECOMMENDED_KDF_ITERATIONS
+            {
+                report.add(
+                    Level::Warning,
+                    "encryption",
+                    format!(
+                        "PBKDF2 uses {} iterations, below the recommended {}",
+                        sb.enc_kdf_iterations, RECOMMENDED_KDF_ITERATIONS
+                    ),
+                    Some(
+                        format!(
+                            "raise it with `lolelffs tune --kdf-iterations {}` or more",
+                            RECOMMENDED_KDF_ITERATIONS
+                        )
+                        .as_str(),
+                    ),
+                );
+            }
+            if sb.enc_features & LOLELFFS_ENC_FEAT_PER_FILE_KEYS == 0 {
+                report.add(
+                    Level::Note,
+                    "encryption",
+                    "all files are encrypted directly with the master key".to_string(),
+                    None,
+                );
+            }
+        }
+
+        // Optional protections
+        if sb.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM) == LOLELFFS_HASH_NONE {
+            report.add(
+                Level::Note,
+                "integrity",
+                "directory blocks are not checksummed".to_string(),
+                Some("enable directory checksums with `lolelffs tune --dir-checksums crc32c`"),
+            );
+        }
+        if sb.comp_features & LOLELFFS_FEATURE_ORDERED_WRITES == 0 {
+            report.add(
+                Level::Note,
+                "integrity",
+                "ordered writes are off, so a crash can leave metadata pointing at unwritten data"
+                    .to_string(),
+                Some("enable ordered writes with `lolelffs tune --ordered-writes on`"),
+            );
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_doctor_findings() {
+        let (_path, mut fs) = temp_image("doctor.img");
+        fs.set_dir_checksums(LOLELFFS_HASH_CRC32C).unwrap();
+        fs.superblock.comp_features |= LOLELFFS_FEATURE_ORDERED_WRITES;
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+        fs.write_file(ino, b"hello").unwrap();
+
+        let report = fs.doctor().unwrap();
+        assert_eq!(report.worst(), None, "{:?}", report.findings);
+        assert_eq!(report.bitmap_free_blocks, fs.superblock.nr_free_blocks);
+        assert_eq!(report.bitmap_free_inodes, fs.superblock.nr_free_inodes);
+        assert_eq!(report.frag_score(), 0);
+
+        // A stale free count and weak key derivation
+        fs.superblock.nr_free_blocks -= 3;
+        fs.superblock.enc_enabled = 1;
+        fs.superblock.enc_kdf_algo = LOLELFFS_KDF_PBKDF2 as u32;
+        fs.superblock.enc_kdf_iterations = 5000;
+        let report = fs.doctor().unwrap();
+        assert_eq!(report.worst(), Some(Level::Warning));
+        let areas: Vec<&str> = report
+            .findings
+            .iter()
+            .filter(|f| f.level == Level::Warning)
+            .map(|f| f.area)
+            .collect();
+        assert_eq!(areas, ["bitmaps", "encryption"]);
+        assert_eq!(
+            report.recommendations()[0],
+            "run `lolelffs fsck` for a full check"
+        );
+    }
+}
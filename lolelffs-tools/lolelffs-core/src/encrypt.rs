@@ -0,0 +1,711 @@
+//! Encryption support for lolelffs
+//!
+//! Provides per-block encryption and decryption using AES-256-XTS and ChaCha20-Poly1305.
+//! Matches the kernel module encryption behavior.
+//!
+//! Like compression codecs, algorithms are `Cipher`s looked up by ID in a
+//! process-wide registry, so other crates can add their own (AES-GCM,
+//! Adiantum for devices without AES instructions) with `register_cipher`. A
+//! cipher that needs readers to understand something beyond its ID claims
+//! `enc_features` bits, which an image records before its first block is
+//! written with that cipher; builds without it then see unknown feature
+//! bits and open the image read-only.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use aes::Aes256;
+use anyhow::{bail, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use hkdf::Hkdf;
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, OnceLock, RwLock};
+use xts_mode::Xts128;
+
+/// HKDF info prefix for per-file keys (followed by inode number and generation)
+const LOLELFFS_FILE_KEY_INFO: &[u8] = b"lolelffs file key v1";
+
+/// HKDF info for the key of an opaque image's block layer
+const LOLELFFS_IMAGE_KEY_INFO: &[u8] = b"lolelffs image key v1";
+
+/// First algorithm ID available to ciphers registered by other crates; IDs
+/// below it are reserved for lolelffs itself
+pub const FIRST_CUSTOM_CIPHER_ID: u8 = 128;
+
+/// `enc_features` bits registered ciphers may claim; the rest are reserved
+/// for lolelffs itself
+pub const CUSTOM_ENC_FEATURES: u32 = 0xFFFF_0000;
+
+/// A block encryption algorithm
+///
+/// Implementations seal one block at a time: `seal` gets exactly
+/// `LOLELFFS_BLOCK_SIZE - tag_size()` bytes and must return exactly
+/// `LOLELFFS_BLOCK_SIZE`, and `open` reverses it. The logical block number
+/// is the tweak or nonce, so the same key never sees two blocks at the same
+/// position of a file.
+pub trait Cipher: Send + Sync {
+    /// ID recorded in extents and in the superblock's default algorithm
+    fn id(&self) -> u8;
+
+    /// Name used on the command line, lowercase
+    fn name(&self) -> &'static str;
+
+    /// Bytes at the end of each block taken by an authentication tag
+    fn tag_size(&self) -> usize {
+        0
+    }
+
+    /// `enc_features` bits an image must record before using this cipher
+    fn features(&self) -> u32 {
+        0
+    }
+
+    /// Encrypt a block's payload into one on-disk block
+    fn seal(&self, key: &[u8; 32], block_num: u64, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypt one on-disk block back into its payload
+    fn open(&self, key: &[u8; 32], block_num: u64, block: &[u8]) -> Result<Vec<u8>>;
+}
+
+struct AesXtsCipher;
+
+impl Cipher for AesXtsCipher {
+    fn id(&self) -> u8 {
+        LOLELFFS_ENC_AES256_XTS
+    }
+    fn name(&self) -> &'static str {
+        "aes-256-xts"
+    }
+    fn seal(&self, key: &[u8; 32], block_num: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        encrypt_aes_xts(key, block_num, plaintext)
+    }
+    fn open(&self, key: &[u8; 32], block_num: u64, block: &[u8]) -> Result<Vec<u8>> {
+        decrypt_aes_xts(key, block_num, block)
+    }
+}
+
+struct ChaChaCipher;
+
+impl Cipher for ChaChaCipher {
+    fn id(&self) -> u8 {
+        LOLELFFS_ENC_CHACHA20_POLY
+    }
+    fn name(&self) -> &'static str {
+        "chacha20-poly1305"
+    }
+    fn tag_size(&self) -> usize {
+        16
+    }
+    fn seal(&self, key: &[u8; 32], block_num: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        ChaCha20Poly1305::new(key.into())
+            .encrypt(&block_nonce(block_num), plaintext)
+            .map_err(|_| anyhow::anyhow!("ChaCha20-Poly1305 encryption failed"))
+    }
+    fn open(&self, key: &[u8; 32], block_num: u64, block: &[u8]) -> Result<Vec<u8>> {
+        ChaCha20Poly1305::new(key.into())
+            .decrypt(&block_nonce(block_num), block)
+            .map_err(|_| {
+                anyhow::anyhow!("ChaCha20-Poly1305 decryption failed (authentication failed)")
+            })
+    }
+}
+
+/// 96-bit nonce holding a block number
+fn block_nonce(block_num: u64) -> Nonce {
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[..8].copy_from_slice(&block_num.to_le_bytes());
+    *Nonce::from_slice(&nonce_bytes)
+}
+
+/// Every cipher this process knows, built-in ones first
+fn registry() -> &'static RwLock<Vec<Arc<dyn Cipher>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Cipher>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(vec![Arc::new(AesXtsCipher), Arc::new(ChaChaCipher)]))
+}
+
+/// Make a cipher available to every image opened by this process
+///
+/// Its ID must be at least `FIRST_CUSTOM_CIPHER_ID`, its feature bits must
+/// lie in `CUSTOM_ENC_FEATURES`, and neither the ID nor the name may already
+/// be taken. Images written with it can only be read where the same cipher
+/// is registered; elsewhere its files fail with `FsError::Unsupported`.
+pub fn register_cipher(cipher: Arc<dyn Cipher>) -> Result<()> {
+    let (id, name) = (cipher.id(), cipher.name());
+    if id < FIRST_CUSTOM_CIPHER_ID {
+        bail!(
+            "Cipher ID {} is reserved; custom ciphers use {} to 255",
+            id,
+            FIRST_CUSTOM_CIPHER_ID
+        );
+    }
+    if name.is_empty() || name != name.to_lowercase() || name == "none" {
+        bail!("Invalid cipher name '{}' (expected a lowercase name)", name);
+    }
+    if cipher.features() & !CUSTOM_ENC_FEATURES != 0 {
+        bail!(
+            "Cipher {} claims reserved feature bits 0x{:08X}",
+            name,
+            cipher.features() & !CUSTOM_ENC_FEATURES
+        );
+    }
+    if cipher.tag_size() >= LOLELFFS_BLOCK_SIZE as usize / 2 {
+        bail!(
+            "Cipher {} uses {} bytes per block for its tag",
+            name,
+            cipher.tag_size()
+        );
+    }
+
+    let mut ciphers = registry().write().unwrap_or_else(|e| e.into_inner());
+    if let Some(taken) = ciphers.iter().find(|c| c.id() == id || c.name() == name) {
+        bail!(
+            "Cipher {} ({}) is already registered",
+            taken.id(),
+            taken.name()
+        );
+    }
+    ciphers.push(cipher);
+    Ok(())
+}
+
+/// The cipher for algorithm `id`, if this process has one
+pub fn cipher(id: u8) -> Option<Arc<dyn Cipher>> {
+    let ciphers = registry().read().unwrap_or_else(|e| e.into_inner());
+    ciphers.iter().find(|c| c.id() == id).cloned()
+}
+
+/// All available ciphers, by ID
+pub fn ciphers() -> Vec<Arc<dyn Cipher>> {
+    let mut ciphers = registry().read().unwrap_or_else(|e| e.into_inner()).clone();
+    ciphers.sort_by_key(|c| c.id());
+    ciphers
+}
+
+/// `enc_features` bits claimed by registered ciphers
+pub fn cipher_features() -> u32 {
+    let ciphers = registry().read().unwrap_or_else(|e| e.into_inner());
+    ciphers.iter().fold(0, |bits, c| bits | c.features())
+}
+
+/// The cipher for `algo`, or an error naming it
+fn require_cipher(algo: u8) -> Result<Arc<dyn Cipher>> {
+    if algo == LOLELFFS_ENC_NONE {
+        bail!("Cannot encrypt with NONE algorithm");
+    }
+    cipher(algo).ok_or_else(|| anyhow::anyhow!("Unsupported encryption algorithm: {}", algo))
+}
+
+/// Encrypt a block using AES-256-XTS
+pub fn encrypt_aes_xts(key: &[u8; 32], block_num: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+    if plaintext.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!("Plaintext must be exactly {} bytes", LOLELFFS_BLOCK_SIZE);
+    }
+
+    // XTS mode requires two separate keys
+    // Derive second key from first using SHA256
+    let second_key = Sha256::digest(key);
+
+    // Create two AES-256 cipher instances
+    let cipher1 = Aes256::new_from_slice(key)
+        .map_err(|_| anyhow::anyhow!("Failed to create first AES cipher"))?;
+    let cipher2 = Aes256::new_from_slice(&second_key)
+        .map_err(|_| anyhow::anyhow!("Failed to create second AES cipher"))?;
+
+    // Create XTS cipher with both instances
+    let cipher = Xts128::<Aes256>::new(cipher1, cipher2);
+
+    // Use block number as tweak (sector number)
+    let tweak = block_num as u128;
+
+    // Encrypt in place
+    let mut ciphertext = plaintext.to_vec();
+    cipher.encrypt_area(
+        &mut ciphertext,
+        LOLELFFS_BLOCK_SIZE as usize,
+        tweak,
+        |t: u128| (t + 1).to_le_bytes(),
+    );
+
+    Ok(ciphertext)
+}
+
+/// Decrypt a block using AES-256-XTS
+pub fn decrypt_aes_xts(key: &[u8; 32], block_num: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    if ciphertext.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!("Ciphertext must be exactly {} bytes", LOLELFFS_BLOCK_SIZE);
+    }
+
+    // XTS mode requires two separate keys
+    // Derive second key from first using SHA256
+    let second_key = Sha256::digest(key);
+
+    // Create two AES-256 cipher instances
+    let cipher1 = Aes256::new_from_slice(key)
+        .map_err(|_| anyhow::anyhow!("Failed to create first AES cipher"))?;
+    let cipher2 = Aes256::new_from_slice(&second_key)
+        .map_err(|_| anyhow::anyhow!("Failed to create second AES cipher"))?;
+
+    // Create XTS cipher with both instances
+    let cipher = Xts128::<Aes256>::new(cipher1, cipher2);
+
+    // Use block number as tweak (sector number)
+    let tweak = block_num as u128;
+
+    // Decrypt in place
+    let mut plaintext = ciphertext.to_vec();
+    cipher.decrypt_area(
+        &mut plaintext,
+        LOLELFFS_BLOCK_SIZE as usize,
+        tweak,
+        |t: u128| (t + 1).to_le_bytes(),
+    );
+
+    Ok(plaintext)
+}
+
+/// Encrypt a block using ChaCha20-Poly1305
+pub fn encrypt_chacha20_poly1305(
+    key: &[u8; 32],
+    block_num: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    if plaintext.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!("Plaintext must be exactly {} bytes", LOLELFFS_BLOCK_SIZE);
+    }
+
+    // Create cipher
+    let cipher = ChaCha20Poly1305::new(key.into());
+
+    // Derive nonce from block number (12 bytes)
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[..8].copy_from_slice(&block_num.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    // Encrypt with authentication
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("ChaCha20-Poly1305 encryption failed"))?;
+
+    Ok(ciphertext)
+}
+
+/// Decrypt a block using ChaCha20-Poly1305
+pub fn decrypt_chacha20_poly1305(
+    key: &[u8; 32],
+    block_num: u64,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    // Ciphertext includes 16-byte authentication tag
+    if ciphertext.len() != LOLELFFS_BLOCK_SIZE as usize + 16 {
+        bail!(
+            "Ciphertext must be exactly {} bytes (data + tag)",
+            LOLELFFS_BLOCK_SIZE + 16
+        );
+    }
+
+    // Create cipher
+    let cipher = ChaCha20Poly1305::new(key.into());
+
+    // Derive nonce from block number (12 bytes)
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[..8].copy_from_slice(&block_num.to_le_bytes());
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    // Decrypt with authentication verification
+    let plaintext = cipher.decrypt(nonce, ciphertext).map_err(|_| {
+        anyhow::anyhow!("ChaCha20-Poly1305 decryption failed (authentication failed)")
+    })?;
+
+    Ok(plaintext)
+}
+
+/// Encrypt a block using the specified algorithm
+pub fn encrypt_block(
+    algo: u8,
+    key: &[u8; 32],
+    block_num: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    span!("encrypt_block", algo, block = block_num);
+    match algo {
+        LOLELFFS_ENC_NONE => bail!("Cannot encrypt with NONE algorithm"),
+        LOLELFFS_ENC_AES256_XTS => encrypt_aes_xts(key, block_num, plaintext),
+        LOLELFFS_ENC_CHACHA20_POLY => encrypt_chacha20_poly1305(key, block_num, plaintext),
+        _ => bail!("Unsupported encryption algorithm: {}", algo),
+    }
+}
+
+/// Decrypt a block using the specified algorithm
+pub fn decrypt_block(
+    algo: u8,
+    key: &[u8; 32],
+    block_num: u64,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    span!("decrypt_block", algo, block = block_num);
+    match algo {
+        LOLELFFS_ENC_NONE => bail!("Cannot decrypt with NONE algorithm"),
+        LOLELFFS_ENC_AES256_XTS => decrypt_aes_xts(key, block_num, ciphertext),
+        LOLELFFS_ENC_CHACHA20_POLY => decrypt_chacha20_poly1305(key, block_num, ciphertext),
+        _ => bail!("Unsupported encryption algorithm: {}", algo),
+    }
+}
+
+/// Bytes at the end of each on-disk block used for an authentication tag
+pub fn tag_size(algo: u8) -> usize {
+    cipher(algo).map_or(0, |c| c.tag_size())
+}
+
+/// Largest payload that can be sealed into one on-disk block
+pub fn block_capacity(algo: u8) -> usize {
+    LOLELFFS_BLOCK_SIZE as usize - tag_size(algo)
+}
+
+/// Encrypt a payload into exactly one on-disk block
+///
+/// For AEAD algorithms the tag occupies the final bytes of the block, so the
+/// payload must fit in `block_capacity(algo)`; it is zero-padded to that size.
+pub fn seal_block(algo: u8, key: &[u8; 32], block_num: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    span!("seal_block", algo, block = block_num);
+    let capacity = block_capacity(algo);
+    if payload.len() > capacity {
+        bail!(
+            "Payload of {} bytes does not fit in a {} block (max {})",
+            payload.len(),
+            get_algo_name(algo),
+            capacity
+        );
+    }
+
+    let mut padded = vec![0u8; capacity];
+    padded[..payload.len()].copy_from_slice(payload);
+
+    let sealed = require_cipher(algo)?.seal(key, block_num, &padded)?;
+    if sealed.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!(
+            "{} sealed a block into {} bytes",
+            get_algo_name(algo),
+            sealed.len()
+        );
+    }
+    Ok(sealed)
+}
+
+/// Decrypt one on-disk block sealed by `seal_block`
+///
+/// Always returns a full block; bytes beyond the payload capacity are zero.
+pub fn open_block(algo: u8, key: &[u8; 32], block_num: u64, block: &[u8]) -> Result<Vec<u8>> {
+    span!("open_block", algo, block = block_num);
+    if block.len() != LOLELFFS_BLOCK_SIZE as usize {
+        bail!("Ciphertext must be exactly {} bytes", LOLELFFS_BLOCK_SIZE);
+    }
+
+    let mut plaintext = require_cipher(algo)?.open(key, block_num, block)?;
+    plaintext.resize(LOLELFFS_BLOCK_SIZE as usize, 0);
+    Ok(plaintext)
+}
+
+/// Derive a key from a password using PBKDF2-HMAC-SHA256
+pub fn derive_key_pbkdf2(password: &[u8], salt: &[u8; 32], iterations: u32) -> [u8; 32] {
+    span!("derive_key_pbkdf2", iterations);
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key);
+    key
+}
+
+/// Derive a per-file key from the master key with HKDF-SHA256
+///
+/// The inode number and generation are bound into the info string, so every
+/// file (and every reuse of an inode number) gets an independent key.
+pub fn derive_file_key(master_key: &[u8; 32], inode_num: u32, generation: u32) -> [u8; 32] {
+    let mut info = Vec::with_capacity(LOLELFFS_FILE_KEY_INFO.len() + 8);
+    info.extend_from_slice(LOLELFFS_FILE_KEY_INFO);
+    info.extend_from_slice(&inode_num.to_le_bytes());
+    info.extend_from_slice(&generation.to_le_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master_key)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Derive the key an opaque image encrypts its blocks with, independent of
+/// every per-file key
+pub fn derive_image_key(master_key: &[u8; 32]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master_key)
+        .expand(LOLELFFS_IMAGE_KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Generate a random salt
+pub fn generate_salt() -> [u8; 32] {
+    let mut salt = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// Get encryption algorithm name
+pub fn get_algo_name(algo: u8) -> &'static str {
+    match algo {
+        LOLELFFS_ENC_NONE => "none",
+        _ => cipher(algo).map_or("unknown", |c| c.name()),
+    }
+}
+
+/// Parse an encryption algorithm name
+pub fn parse_algo_name(name: &str) -> Result<u8> {
+    let name = name.to_lowercase();
+    if name == "none" {
+        return Ok(LOLELFFS_ENC_NONE);
+    }
+    let ciphers = ciphers();
+    if let Some(cipher) = ciphers.iter().find(|c| c.name() == name) {
+        return Ok(cipher.id());
+    }
+    let known: Vec<&str> = ciphers.iter().map(|c| c.name()).collect();
+    bail!(
+        "Unknown encryption algorithm '{}' (expected {})",
+        name,
+        known.join(", ")
+    )
+}
+
+/// Get authentication tag size for algorithm
+pub fn get_tag_size(algo: u8) -> usize {
+    tag_size(algo)
+}
+
+/// Generate a random master key
+pub fn generate_master_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Encrypt master key with user-derived key (AES-256 ECB for single block)
+pub fn encrypt_master_key(master_key: &[u8; 32], user_key: &[u8; 32]) -> Result<[u8; 32]> {
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    // Use AES-256 in ECB mode for single 32-byte block (two AES blocks)
+    let cipher = Aes256::new(user_key.into());
+
+    let mut encrypted = [0u8; 32];
+
+    // Encrypt first 16 bytes
+    let mut block1 = aes::Block::clone_from_slice(&master_key[..16]);
+    cipher.encrypt_block(&mut block1);
+    encrypted[..16].copy_from_slice(&block1);
+
+    // Encrypt second 16 bytes
+    let mut block2 = aes::Block::clone_from_slice(&master_key[16..]);
+    cipher.encrypt_block(&mut block2);
+    encrypted[16..].copy_from_slice(&block2);
+
+    Ok(encrypted)
+}
+
+/// Decrypt master key with user-derived key
+pub fn decrypt_master_key(encrypted_key: &[u8; 32], user_key: &[u8; 32]) -> Result<[u8; 32]> {
+    use aes::cipher::{BlockDecrypt, KeyInit};
+
+    // Use AES-256 in ECB mode for single 32-byte block (two AES blocks)
+    let cipher = Aes256::new(user_key.into());
+
+    let mut decrypted = [0u8; 32];
+
+    // Decrypt first 16 bytes
+    let mut block1 = aes::Block::clone_from_slice(&encrypted_key[..16]);
+    cipher.decrypt_block(&mut block1);
+    decrypted[..16].copy_from_slice(&block1);
+
+    // Decrypt second 16 bytes
+    let mut block2 = aes::Block::clone_from_slice(&encrypted_key[16..]);
+    cipher.decrypt_block(&mut block2);
+    decrypted[16..].copy_from_slice(&block2);
+
+    Ok(decrypted)
+}
+
+impl LolelfFs {
+    /// Record the `enc_features` bits cipher `algo` needs, before any block
+    /// is written with it (a locked image cannot write encrypted blocks and
+    /// is left alone)
+    pub(crate) fn negotiate_cipher(&mut self, algo: u8) -> Result<()> {
+        if algo == LOLELFFS_ENC_NONE || !self.enc_unlocked {
+            return Ok(());
+        }
+        let needed = require_cipher(algo)?.features();
+        if self.superblock.enc_features & needed != needed {
+            self.superblock.enc_features |= needed;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    /// XORs with the key and appends a byte sum, standing in for an AEAD
+    struct XorCipher;
+
+    impl XorCipher {
+        fn keystream(key: &[u8; 32], block_num: u64, data: &[u8]) -> Vec<u8> {
+            data.iter()
+                .enumerate()
+                .map(|(i, b)| b ^ key[i % 32] ^ block_num as u8)
+                .collect()
+        }
+    }
+
+    impl Cipher for XorCipher {
+        fn id(&self) -> u8 {
+            200
+        }
+        fn name(&self) -> &'static str {
+            "xor"
+        }
+        fn tag_size(&self) -> usize {
+            8
+        }
+        fn features(&self) -> u32 {
+            0x0001_0000
+        }
+        fn seal(&self, key: &[u8; 32], block_num: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+            let sum: u64 = plaintext.iter().map(|&b| b as u64).sum();
+            let mut out = Self::keystream(key, block_num, plaintext);
+            out.extend_from_slice(&sum.to_le_bytes());
+            Ok(out)
+        }
+        fn open(&self, key: &[u8; 32], block_num: u64, block: &[u8]) -> Result<Vec<u8>> {
+            let (body, tag) = block.split_at(block.len() - 8);
+            let plaintext = Self::keystream(key, block_num, body);
+            let sum: u64 = plaintext.iter().map(|&b| b as u64).sum();
+            if sum.to_le_bytes() != tag {
+                bail!("xor tag mismatch");
+            }
+            Ok(plaintext)
+        }
+    }
+
+    #[test]
+    fn test_custom_cipher() {
+        register_cipher(Arc::new(XorCipher)).unwrap();
+        assert!(register_cipher(Arc::new(XorCipher)).is_err());
+        assert!(register_cipher(Arc::new(ChaChaCipher)).is_err());
+        assert_eq!(parse_algo_name("XOR").unwrap(), 200);
+        assert_eq!(get_algo_name(200), "xor");
+        assert_eq!(block_capacity(200), LOLELFFS_BLOCK_SIZE as usize - 8);
+        assert!(ciphers().iter().any(|c| c.id() == LOLELFFS_ENC_AES256_XTS));
+
+        // The image picks up the cipher's feature bit on its first write
+        let path = TempPath::new("cipher.img");
+        let enc = Some(("secret".to_string(), LOLELFFS_ENC_CHACHA20_POLY, 1000));
+        let mut fs = LolelfFs::create_with_encryption(&path, 4 * 1024 * 1024, enc).unwrap();
+        assert_eq!(fs.superblock.enc_features & 0x0001_0000, 0);
+        let data = vec![9u8; 3 * LOLELFFS_BLOCK_SIZE as usize];
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "xored").unwrap();
+        let opts = crate::file::WriteOptions {
+            enc_algo: Some(200),
+            ..Default::default()
+        };
+        fs.write_file_with_options(ino, &data, &opts).unwrap();
+        assert_ne!(fs.superblock.enc_features & 0x0001_0000, 0);
+        assert!(fs.superblock.compat_issues().is_empty());
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        fs.unlock("secret").unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), data);
+    }
+
+    #[test]
+    fn test_aes_xts_roundtrip() {
+        let key = [42u8; 32];
+        let block_num = 123;
+        let plaintext = vec![0xAAu8; LOLELFFS_BLOCK_SIZE as usize];
+
+        let ciphertext = encrypt_aes_xts(&key, block_num, &plaintext).unwrap();
+        assert_eq!(ciphertext.len(), LOLELFFS_BLOCK_SIZE as usize);
+        assert_ne!(ciphertext, plaintext); // Should be different
+
+        let decrypted = decrypt_aes_xts(&key, block_num, &ciphertext).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_roundtrip() {
+        let key = [42u8; 32];
+        let block_num = 456;
+        let plaintext = vec![0xBBu8; LOLELFFS_BLOCK_SIZE as usize];
+
+        let ciphertext = encrypt_chacha20_poly1305(&key, block_num, &plaintext).unwrap();
+        assert_eq!(ciphertext.len(), LOLELFFS_BLOCK_SIZE as usize + 16); // +16 for tag
+        assert_ne!(&ciphertext[..LOLELFFS_BLOCK_SIZE as usize], &plaintext[..]); // Should be different
+
+        let decrypted = decrypt_chacha20_poly1305(&key, block_num, &ciphertext).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_authentication() {
+        let key = [42u8; 32];
+        let block_num = 789;
+        let plaintext = vec![0xCCu8; LOLELFFS_BLOCK_SIZE as usize];
+
+        let mut ciphertext = encrypt_chacha20_poly1305(&key, block_num, &plaintext).unwrap();
+
+        // Corrupt the ciphertext
+        ciphertext[100] ^= 1;
+
+        // Decryption should fail due to authentication
+        let result = decrypt_chacha20_poly1305(&key, block_num, &ciphertext);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_pbkdf2_derivation() {
+        let password = b"test_password";
+        let salt = [0x42u8; 32];
+        let iterations = 10000;
+
+        let key1 = derive_key_pbkdf2(password, &salt, iterations);
+        let key2 = derive_key_pbkdf2(password, &salt, iterations);
+
+        // Same input should produce same key
+        assert_eq!(key1, key2);
+
+        // Different password should produce different key
+        let key3 = derive_key_pbkdf2(b"different_password", &salt, iterations);
+        assert_ne!(key1, key3);
+    }
+
+    #[test]
+    fn test_seal_open_block() {
+        let key = [7u8; 32];
+        let payload = vec![0x42u8; 100];
+
+        for algo in [LOLELFFS_ENC_AES256_XTS, LOLELFFS_ENC_CHACHA20_POLY] {
+            let sealed = seal_block(algo, &key, 3, &payload).unwrap();
+            assert_eq!(sealed.len(), LOLELFFS_BLOCK_SIZE as usize);
+
+            let opened = open_block(algo, &key, 3, &sealed).unwrap();
+            assert_eq!(opened.len(), LOLELFFS_BLOCK_SIZE as usize);
+            assert_eq!(&opened[..payload.len()], payload.as_slice());
+            assert!(opened[payload.len()..].iter().all(|&b| b == 0));
+        }
+
+        // A full block leaves no room for the AEAD tag
+        let full = vec![1u8; LOLELFFS_BLOCK_SIZE as usize];
+        assert!(seal_block(LOLELFFS_ENC_CHACHA20_POLY, &key, 0, &full).is_err());
+    }
+}
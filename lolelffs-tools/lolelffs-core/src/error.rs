@@ -0,0 +1,80 @@
+//! Structured errors for lolelffs
+//!
+//! Most operations report failures through `anyhow`, but errors that callers
+//! need to act on (e.g. mapping to an errno) are defined here so they can be
+//! recovered with `anyhow::Error::downcast_ref`.
+
+use std::fmt;
+use thiserror::Error;
+
+/// Which resource ran out when an allocation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoSpaceKind {
+    /// Not enough free data blocks in total
+    DataBlocks,
+    /// Enough free blocks, but no contiguous run large enough
+    ContiguousBlocks,
+    /// No free inodes
+    Inodes,
+    /// File would need more extents than fit in its extent index
+    ExtentSlots,
+}
+
+impl fmt::Display for NoSpaceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            NoSpaceKind::DataBlocks => "out of data blocks",
+            NoSpaceKind::ContiguousBlocks => "no contiguous run of free blocks",
+            NoSpaceKind::Inodes => "out of inodes",
+            NoSpaceKind::ExtentSlots => "out of extent slots",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Errors that callers may want to match on
+#[derive(Debug, Error)]
+pub enum FsError {
+    /// An allocation failed for lack of space
+    #[error("No space left on filesystem: {kind} (needed {needed}, available {available})")]
+    NoSpace {
+        kind: NoSpaceKind,
+        needed: u64,
+        available: u64,
+    },
+    /// A metadata block failed checksum verification, e.g. after a torn write
+    #[error("Checksum mismatch in {what} block {block}")]
+    ChecksumMismatch { what: &'static str, block: u32 },
+    /// Resolving a path followed more symlinks than allowed
+    #[error("Too many levels of symbolic links resolving {path}")]
+    SymlinkLoop { path: String },
+    /// A path or one of its components is longer than allowed
+    #[error("Name too long: {len} bytes, the limit is {max}")]
+    NameTooLong { len: usize, max: usize },
+    /// A path, or a directory tree, nests deeper than allowed
+    #[error("Path too deep: more than {max} components")]
+    PathTooDeep { max: usize },
+    /// A block number falls outside the area an access is allowed to touch,
+    /// e.g. a corrupt extent pointing into the inode table
+    #[error("Block {block} is outside the allowed range {start}..{end}")]
+    BlockOutOfRange { block: u32, start: u32, end: u32 },
+    /// A file is stored with something this build does not implement, e.g.
+    /// a compression algorithm added by a newer tool; image-wide parameters
+    /// such as the key derivation function are reported against the root
+    #[error("Inode {inode} is stored with {what}, which this build does not implement")]
+    Unsupported { inode: u32, what: String },
+    /// An `FsFile` was read from or written to without being opened for it
+    #[error("File handle for inode {inode} is not open for {access}")]
+    NotOpenFor { inode: u32, access: &'static str },
+}
+
+impl FsError {
+    /// Shorthand for building a `NoSpace` error
+    pub fn no_space(kind: NoSpaceKind, needed: u64, available: u64) -> Self {
+        FsError::NoSpace {
+            kind,
+            needed,
+            available,
+        }
+    }
+}
@@ -0,0 +1,1308 @@
+//! ext2 image conversion
+//!
+//! A self-contained ext2 reader and writer, enough to migrate root
+//! filesystem images between ext2 and lolelffs without mounting either. The
+//! reader handles revision 0 and 1 images of any block size, block maps up to
+//! triple indirection, sparse files, hashed directories (read linearly) and
+//! xattrs stored in blocks or in large inodes. Features that change where data
+//! is found (extents, meta_bg, 64-bit descriptors, inline data, a journal
+//! needing recovery) are refused. The writer produces revision 1 images with
+//! 4 KiB blocks, 128-byte inodes, a superblock backup in every group and the
+//! filetype and ext_attr features, which e2fsck accepts as they are.
+//!
+//! Devices, FIFOs and sockets have no lolelffs representation and are
+//! skipped, as are symlinks longer than lolelffs stores and POSIX ACLs, whose
+//! ext2 encoding differs from the xattr one.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const EXT2_ROOT_INO: u32 = 2;
+/// First inode not reserved for the filesystem's own use
+const EXT2_FIRST_INO: u32 = 11;
+const EXT2_NDIR_BLOCKS: usize = 12;
+/// Longest symlink target kept in the block map instead of a data block
+const EXT2_FAST_SYMLINK_MAX: u64 = 59;
+
+const COMPAT_EXT_ATTR: u32 = 0x0008;
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+/// Incompatible features the reader can ignore, as neither changes where
+/// data is found once the group descriptors are read
+const READER_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+/// ext2 xattr name indexes and the prefixes they stand for
+const XATTR_PREFIXES: &[(u8, &str)] = &[
+    (1, "user."),
+    (4, "trusted."),
+    (6, "security."),
+    (7, "system."),
+];
+/// POSIX ACLs, stored by ext2 in an encoding of its own
+const ACL_XATTRS: &[&str] = &["system.posix_acl_access", "system.posix_acl_default"];
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xA000;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+// Geometry of the images `export_ext2` writes
+const BLOCK_SIZE: u32 = 4096;
+const BLOCKS_PER_GROUP: u32 = 8 * BLOCK_SIZE;
+const INODE_SIZE: u32 = 128;
+const INODES_PER_BLOCK: u32 = BLOCK_SIZE / INODE_SIZE;
+const PTRS_PER_BLOCK: u64 = BLOCK_SIZE as u64 / 4;
+const DESC_SIZE: u32 = 32;
+/// Image bytes per inode when sizing inode tables, as mke2fs does
+const BYTES_PER_INODE: u64 = 16384;
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+fn put16(buf: &mut [u8], off: usize, value: u16) {
+    buf[off..off + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], off: usize, value: u32) {
+    buf[off..off + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Summary of a conversion
+#[derive(Debug, Clone, Default)]
+pub struct Ext2Stats {
+    pub files: u32,
+    pub dirs: u32,
+    pub symlinks: u32,
+    /// Additional names of files already converted
+    pub hardlinks: u32,
+    /// Entries, or parts of them, left out, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+/// An ext2 image opened for reading
+pub struct Ext2Image {
+    file: File,
+    block_size: u32,
+    inode_size: u32,
+    inodes_per_group: u32,
+    inode_count: u32,
+    /// First block of each group's inode table
+    inode_tables: Vec<u32>,
+    /// Volume label
+    pub label: String,
+    /// Size of the filesystem in bytes
+    pub size: u64,
+}
+
+/// The parts of an ext2 inode conversion needs
+#[derive(Debug, Clone)]
+struct Ext2Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: u32,
+    mtime: u32,
+    links: u16,
+    /// 512-byte sectors in use, including indirect and xattr blocks
+    sectors: u32,
+    block: [u32; 15],
+    file_acl: u32,
+    /// Bytes past the first 128, where large inodes keep xattrs
+    extra: Vec<u8>,
+}
+
+impl Ext2Image {
+    /// Open an ext2 image, refusing features the reader does not implement
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file =
+            File::open(path).with_context(|| format!("Cannot open '{}'", path.display()))?;
+        let mut sb = [0u8; 1024];
+        file.seek(SeekFrom::Start(1024))?;
+        file.read_exact(&mut sb)
+            .with_context(|| format!("'{}' is too small for ext2", path.display()))?;
+        if le16(&sb, 56) != EXT2_MAGIC {
+            bail!("'{}' is not an ext2 image", path.display());
+        }
+
+        let inode_count = le32(&sb, 0);
+        let blocks_count = le32(&sb, 4);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 {
+            bail!("'{}' has a corrupt ext2 superblock", path.display());
+        }
+        let block_size = 1024 << log_block_size;
+
+        let (inode_size, incompat) = if le32(&sb, 76) == 0 {
+            (128, 0)
+        } else {
+            (le16(&sb, 88) as u32, le32(&sb, 96))
+        };
+        if inode_size < 128 || inode_size > block_size || !inode_size.is_power_of_two() {
+            bail!(
+                "'{}' has an invalid inode size {}",
+                path.display(),
+                inode_size
+            );
+        }
+        let unknown = incompat & !READER_INCOMPAT;
+        if unknown != 0 {
+            bail!(
+                "'{}' uses incompatible features 0x{:X} (extents, 64-bit, a journal needing recovery, ...) that the ext2 reader does not implement",
+                path.display(),
+                unknown
+            );
+        }
+
+        let groups = blocks_count
+            .saturating_sub(first_data_block)
+            .div_ceil(blocks_per_group);
+        let mut gdt = vec![0u8; groups as usize * DESC_SIZE as usize];
+        file.seek(SeekFrom::Start(
+            (first_data_block as u64 + 1) * block_size as u64,
+        ))?;
+        file.read_exact(&mut gdt)
+            .context("Cannot read the ext2 group descriptors")?;
+        let inode_tables = (0..groups as usize)
+            .map(|g| le32(&gdt, g * DESC_SIZE as usize + 8))
+            .collect();
+
+        let label_bytes = &sb[120..136];
+        let label_len = label_bytes.iter().position(|&b| b == 0).unwrap_or(16);
+
+        Ok(Ext2Image {
+            file,
+            block_size,
+            inode_size,
+            inodes_per_group,
+            inode_count,
+            inode_tables,
+            label: String::from_utf8_lossy(&label_bytes[..label_len]).into_owned(),
+            size: blocks_count as u64 * block_size as u64,
+        })
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.block_size as usize];
+        self.file
+            .seek(SeekFrom::Start(block as u64 * self.block_size as u64))?;
+        self.file
+            .read_exact(&mut buf)
+            .with_context(|| format!("Cannot read ext2 block {}", block))?;
+        Ok(buf)
+    }
+
+    fn read_inode(&mut self, ino: u32) -> Result<Ext2Inode> {
+        if ino == 0 || ino > self.inode_count {
+            bail!("ext2 inode {} out of range", ino);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = (ino - 1) % self.inodes_per_group;
+        let Some(&table) = self.inode_tables.get(group) else {
+            bail!("ext2 inode {} lies past the last group", ino);
+        };
+
+        let mut raw = vec![0u8; self.inode_size as usize];
+        self.file.seek(SeekFrom::Start(
+            table as u64 * self.block_size as u64 + index as u64 * self.inode_size as u64,
+        ))?;
+        self.file
+            .read_exact(&mut raw)
+            .with_context(|| format!("Cannot read ext2 inode {}", ino))?;
+
+        let mode = le16(&raw, 0);
+        let mut size = le32(&raw, 4) as u64;
+        if mode & S_IFMT == S_IFREG {
+            size |= (le32(&raw, 108) as u64) << 32;
+        }
+        let mut block = [0u32; 15];
+        for (i, slot) in block.iter_mut().enumerate() {
+            *slot = le32(&raw, 40 + i * 4);
+        }
+        Ok(Ext2Inode {
+            mode,
+            uid: le16(&raw, 2) as u32 | (le16(&raw, 120) as u32) << 16,
+            gid: le16(&raw, 24) as u32 | (le16(&raw, 122) as u32) << 16,
+            size,
+            atime: le32(&raw, 8),
+            mtime: le32(&raw, 16),
+            links: le16(&raw, 26),
+            sectors: le32(&raw, 28),
+            block,
+            file_acl: le32(&raw, 104),
+            extra: raw[128..].to_vec(),
+        })
+    }
+
+    /// Physical block of each logical block of an inode, 0 for holes
+    fn block_map(&mut self, inode: &Ext2Inode) -> Result<Vec<u32>> {
+        let count = inode.size.div_ceil(self.block_size as u64) as usize;
+        let mut map: Vec<u32> = inode.block[..EXT2_NDIR_BLOCKS]
+            .iter()
+            .copied()
+            .take(count)
+            .collect();
+        for (level, &ptr) in (1..=3).zip(&inode.block[EXT2_NDIR_BLOCKS..]) {
+            if map.len() >= count {
+                break;
+            }
+            self.map_indirect(ptr, level, count, &mut map)?;
+        }
+        Ok(map)
+    }
+
+    fn map_indirect(
+        &mut self,
+        ptr: u32,
+        level: u32,
+        count: usize,
+        map: &mut Vec<u32>,
+    ) -> Result<()> {
+        let per_block = self.block_size as usize / 4;
+        if ptr == 0 {
+            let span = per_block.pow(level);
+            let holes = span.min(count - map.len());
+            map.resize(map.len() + holes, 0);
+            return Ok(());
+        }
+        let block = self.read_block(ptr)?;
+        for i in 0..per_block {
+            if map.len() >= count {
+                break;
+            }
+            let child = le32(&block, i * 4);
+            if level == 1 {
+                map.push(child);
+            } else {
+                self.map_indirect(child, level - 1, count, map)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Contents of a regular file or slow symlink
+    fn read_data(&mut self, inode: &Ext2Inode) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(inode.size as usize);
+        for block in self.block_map(inode)? {
+            if block == 0 {
+                data.resize(data.len() + self.block_size as usize, 0);
+            } else {
+                data.extend_from_slice(&self.read_block(block)?);
+            }
+        }
+        data.truncate(inode.size as usize);
+        Ok(data)
+    }
+
+    fn symlink_target(&mut self, inode: &Ext2Inode) -> Result<Vec<u8>> {
+        let xattr_sectors = if inode.file_acl != 0 {
+            self.block_size / 512
+        } else {
+            0
+        };
+        if inode.size <= EXT2_FAST_SYMLINK_MAX && inode.sectors == xattr_sectors {
+            let inline: Vec<u8> = inode.block.iter().flat_map(|b| b.to_le_bytes()).collect();
+            return Ok(inline[..inode.size as usize].to_vec());
+        }
+        self.read_data(inode)
+    }
+
+    /// Names and inode numbers in a directory, without "." and ".."
+    fn read_dir(&mut self, inode: &Ext2Inode) -> Result<Vec<(Vec<u8>, u32)>> {
+        let mut entries = Vec::new();
+        for block_num in self.block_map(inode)? {
+            if block_num == 0 {
+                continue;
+            }
+            let block = self.read_block(block_num)?;
+            let mut pos = 0;
+            while pos + 8 <= block.len() {
+                let ino = le32(&block, pos);
+                let rec_len = le16(&block, pos + 4) as usize;
+                // With the filetype feature the high byte of the name length
+                // holds the type, and names never exceed 255 bytes
+                let name_len = block[pos + 6] as usize;
+                if rec_len < 8 || pos + rec_len > block.len() || 8 + name_len > rec_len {
+                    bail!("Corrupt ext2 directory block {}", block_num);
+                }
+                let name = &block[pos + 8..pos + 8 + name_len];
+                if ino != 0 && name != b"." && name != b".." {
+                    entries.push((name.to_vec(), ino));
+                }
+                pos += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Xattrs of an inode, from the inode itself and its xattr block
+    fn xattrs(&mut self, inode: &Ext2Inode) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut xattrs = Vec::new();
+        if inode.extra.len() >= 4 {
+            let header = le16(&inode.extra, 0) as usize;
+            if header + 4 <= inode.extra.len() && le32(&inode.extra, header) == XATTR_MAGIC {
+                parse_xattrs(&inode.extra, header + 4, header + 4, &mut xattrs)?;
+            }
+        }
+        if inode.file_acl != 0 {
+            let block = self.read_block(inode.file_acl)?;
+            if le32(&block, 0) == XATTR_MAGIC {
+                parse_xattrs(&block, 32, 0, &mut xattrs)?;
+            }
+        }
+        Ok(xattrs)
+    }
+}
+
+/// Decode xattr entries starting at `start`, whose value offsets count from
+/// `value_base`; entries in namespaces lolelffs lacks are dropped
+fn parse_xattrs(
+    buf: &[u8],
+    start: usize,
+    value_base: usize,
+    out: &mut Vec<(String, Vec<u8>)>,
+) -> Result<()> {
+    let mut pos = start;
+    while pos + 16 <= buf.len() && le32(buf, pos) != 0 {
+        let name_len = buf[pos] as usize;
+        let index = buf[pos + 1];
+        let value_offs = le16(buf, pos + 2) as usize;
+        let value_inum = le32(buf, pos + 4);
+        let value_size = le32(buf, pos + 8) as usize;
+        let name_end = pos + 16 + name_len;
+        let value_start = value_base + value_offs;
+        if name_end > buf.len() || value_start + value_size > buf.len() {
+            bail!("Corrupt ext2 xattr entry");
+        }
+        let name = &buf[pos + 16..name_end];
+        pos = (name_end + 3) & !3;
+
+        if value_inum != 0 {
+            continue;
+        }
+        if let Some((_, prefix)) = XATTR_PREFIXES.iter().find(|(i, _)| *i == index) {
+            out.push((
+                format!("{}{}", prefix, String::from_utf8_lossy(name)),
+                buf[value_start..value_start + value_size].to_vec(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Name hash e2fsck checks each xattr entry against
+fn xattr_entry_hash(name: &[u8], value: &[u8]) -> u32 {
+    let mut hash: u32 = 0;
+    for &c in name {
+        // The kernel hashes the name as signed chars
+        hash = (hash << 5) ^ (hash >> 27) ^ (c as i8 as i32 as u32);
+    }
+    let mut padded = value.to_vec();
+    padded.resize(value.len().div_ceil(4) * 4, 0);
+    for word in padded.chunks_exact(4) {
+        hash = (hash << 16) ^ (hash >> 16) ^ le32(word, 0);
+    }
+    hash
+}
+
+/// Encode xattrs, none of them ACLs, as an ext2 xattr block, or `None` if
+/// they do not fit in one
+fn encode_xattr_block(xattrs: &[(String, Vec<u8>)]) -> Option<Vec<u8>> {
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    put32(&mut block, 0, XATTR_MAGIC);
+    put32(&mut block, 4, 1); // refcount
+    put32(&mut block, 8, 1); // blocks
+
+    let mut entries: Vec<(u8, &[u8], &[u8])> = xattrs
+        .iter()
+        .filter_map(|(name, value)| {
+            XATTR_PREFIXES.iter().find_map(|&(index, prefix)| {
+                name.strip_prefix(prefix)
+                    .map(|rest| (index, rest.as_bytes(), &value[..]))
+            })
+        })
+        .collect();
+    // e2fsck expects entries sorted by index, then name
+    entries.sort();
+
+    let mut pos = 32;
+    let mut value_end = BLOCK_SIZE as usize;
+    let mut block_hash: u32 = 0;
+    for (index, name, value) in entries {
+        let entry_len = (16 + name.len() + 3) & !3;
+        let value_len = value.len().div_ceil(4) * 4;
+        // Keep four zero bytes after the last entry
+        if pos + entry_len + 4 + value_len > value_end {
+            return None;
+        }
+        value_end -= value_len;
+        block[value_end..value_end + value.len()].copy_from_slice(value);
+
+        let hash = xattr_entry_hash(name, value);
+        block[pos] = name.len() as u8;
+        block[pos + 1] = index;
+        put16(&mut block, pos + 2, value_end as u16);
+        put32(&mut block, pos + 8, value.len() as u32);
+        put32(&mut block, pos + 12, hash);
+        block[pos + 16..pos + 16 + name.len()].copy_from_slice(name);
+        pos += entry_len;
+        block_hash = (block_hash << 16) ^ (block_hash >> 16) ^ hash;
+    }
+    put32(&mut block, 16, block_hash);
+    Some(block)
+}
+
+/// Indirect blocks needed to map `blocks` data blocks
+fn indirect_blocks(blocks: u64) -> u64 {
+    let mut rest = blocks.saturating_sub(EXT2_NDIR_BLOCKS as u64);
+    let mut count = 0;
+    for level in 1..=3u32 {
+        let take = rest.min(PTRS_PER_BLOCK.pow(level));
+        // One block at the top, then one per PTRS_PER_BLOCK^j data blocks
+        // at each level below
+        count += (1..=level)
+            .map(|j| take.div_ceil(PTRS_PER_BLOCK.pow(j)))
+            .sum::<u64>();
+        rest -= take;
+    }
+    count
+}
+
+/// Pack directory entries into blocks; the last entry of each block takes
+/// up its remaining space
+fn pack_dir(entries: &[(Vec<u8>, u32, u8)]) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    let mut pos = 0;
+    let mut last = 0;
+    for (name, ino, file_type) in entries {
+        let rec_len = (8 + name.len() + 3) & !3;
+        if pos + rec_len > BLOCK_SIZE as usize {
+            put16(&mut block, last + 4, (BLOCK_SIZE as usize - last) as u16);
+            blocks.push(std::mem::replace(
+                &mut block,
+                vec![0u8; BLOCK_SIZE as usize],
+            ));
+            pos = 0;
+        }
+        put32(&mut block, pos, *ino);
+        put16(&mut block, pos + 4, rec_len as u16);
+        block[pos + 6] = name.len() as u8;
+        block[pos + 7] = *file_type;
+        block[pos + 8..pos + 8 + name.len()].copy_from_slice(name);
+        last = pos;
+        pos += rec_len;
+    }
+    put16(&mut block, last + 4, (BLOCK_SIZE as usize - last) as u16);
+    blocks.push(block);
+    blocks
+}
+
+/// Group geometry of an image being written
+#[derive(Debug, Clone, Copy)]
+struct Ext2Layout {
+    blocks: u32,
+    groups: u32,
+    inodes_per_group: u32,
+    gdt_blocks: u32,
+    /// Superblock, descriptors, bitmaps and inode table at the start of
+    /// every group
+    overhead: u32,
+}
+
+impl Ext2Layout {
+    /// Geometry for an image of `blocks` blocks with at least `min_inodes`
+    /// inodes; a last group too small for its metadata is dropped
+    fn plan(mut blocks: u32, min_inodes: u32) -> Result<Self> {
+        loop {
+            let groups = blocks.div_ceil(BLOCKS_PER_GROUP);
+            let wanted =
+                (blocks as u64 * BLOCK_SIZE as u64 / BYTES_PER_INODE).max(min_inodes as u64);
+            let inodes_per_group = (wanted.div_ceil(groups as u64) as u32)
+                .div_ceil(INODES_PER_BLOCK)
+                * INODES_PER_BLOCK;
+            if inodes_per_group > 8 * BLOCK_SIZE {
+                bail!(
+                    "{} inodes do not fit in {} blocks of ext2",
+                    min_inodes,
+                    blocks
+                );
+            }
+            let gdt_blocks = (groups * DESC_SIZE).div_ceil(BLOCK_SIZE);
+            let overhead = 1 + gdt_blocks + 2 + inodes_per_group / INODES_PER_BLOCK;
+
+            let last_group = blocks - (groups - 1) * BLOCKS_PER_GROUP;
+            if last_group > overhead {
+                return Ok(Ext2Layout {
+                    blocks,
+                    groups,
+                    inodes_per_group,
+                    gdt_blocks,
+                    overhead,
+                });
+            }
+            if groups == 1 {
+                bail!("{} blocks are too few for an ext2 image", blocks);
+            }
+            blocks = (groups - 1) * BLOCKS_PER_GROUP;
+        }
+    }
+
+    fn data_capacity(&self) -> u64 {
+        self.blocks as u64 - self.groups as u64 * self.overhead as u64
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        (self.blocks - self.group_start(group)).min(BLOCKS_PER_GROUP)
+    }
+
+    fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_start(group) + 1 + self.gdt_blocks
+    }
+
+    fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 2
+    }
+}
+
+/// Hands out data blocks in order, tracking them in the group bitmaps
+struct Ext2Allocator {
+    layout: Ext2Layout,
+    next: u32,
+    bitmaps: Vec<Vec<u8>>,
+    free: Vec<u32>,
+}
+
+impl Ext2Allocator {
+    fn new(layout: Ext2Layout) -> Self {
+        let mut bitmaps = Vec::new();
+        let mut free = Vec::new();
+        for group in 0..layout.groups {
+            let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+            let in_group = layout.blocks_in_group(group);
+            // Group metadata, and the padding past the last block
+            for bit in (0..layout.overhead).chain(in_group..BLOCKS_PER_GROUP) {
+                bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+            bitmaps.push(bitmap);
+            free.push(in_group - layout.overhead);
+        }
+        Ext2Allocator {
+            layout,
+            next: layout.overhead,
+            bitmaps,
+            free,
+        }
+    }
+
+    fn alloc(&mut self) -> Result<u32> {
+        loop {
+            if self.next >= self.layout.blocks {
+                bail!("The ext2 image is too small for the tree");
+            }
+            let group = self.next / BLOCKS_PER_GROUP;
+            let bit = self.next % BLOCKS_PER_GROUP;
+            if bit < self.layout.overhead {
+                self.next = self.layout.group_start(group) + self.layout.overhead;
+                continue;
+            }
+            self.bitmaps[group as usize][(bit / 8) as usize] |= 1 << (bit % 8);
+            self.free[group as usize] -= 1;
+            self.next += 1;
+            return Ok(self.next - 1);
+        }
+    }
+}
+
+/// An inode of the image being written
+struct Ext2Node {
+    /// lolelffs inode it is copied from, 0 for a new lost+found
+    src_ino: u32,
+    inode: Inode,
+    /// Path for messages
+    path: String,
+    links: u16,
+    /// Directory entries, for directories
+    entries: Vec<(Vec<u8>, u32, u8)>,
+    xattr_block: Option<Vec<u8>>,
+}
+
+/// Writes blocks of an ext2 image
+struct Ext2Writer {
+    file: File,
+    alloc: Ext2Allocator,
+}
+
+impl Ext2Writer {
+    fn write_at(&mut self, block: u32, offset: usize, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(
+            block as u64 * BLOCK_SIZE as u64 + offset as u64,
+        ))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Allocate and write blocks for `data`, returning the inode's block
+    /// map and the blocks used, including indirect ones
+    fn write_data(&mut self, data: &[u8]) -> Result<([u32; 15], u32)> {
+        let mut blocks = Vec::new();
+        for chunk in data.chunks(BLOCK_SIZE as usize) {
+            let block = self.alloc.alloc()?;
+            self.write_at(block, 0, chunk)?;
+            blocks.push(block);
+        }
+        self.map_blocks(&blocks)
+    }
+
+    fn map_blocks(&mut self, blocks: &[u32]) -> Result<([u32; 15], u32)> {
+        let mut map = [0u32; 15];
+        let direct = blocks.len().min(EXT2_NDIR_BLOCKS);
+        map[..direct].copy_from_slice(&blocks[..direct]);
+        let mut used = blocks.len() as u32;
+        let mut rest = &blocks[direct..];
+        for level in 1..=3u32 {
+            if rest.is_empty() {
+                break;
+            }
+            let take = rest.len().min(PTRS_PER_BLOCK.pow(level) as usize);
+            map[EXT2_NDIR_BLOCKS + level as usize - 1] =
+                self.write_indirect(level, &rest[..take], &mut used)?;
+            rest = &rest[take..];
+        }
+        Ok((map, used))
+    }
+
+    fn write_indirect(&mut self, level: u32, blocks: &[u32], used: &mut u32) -> Result<u32> {
+        let block = self.alloc.alloc()?;
+        *used += 1;
+        let ptrs = if level == 1 {
+            blocks.to_vec()
+        } else {
+            let span = PTRS_PER_BLOCK.pow(level - 1) as usize;
+            blocks
+                .chunks(span)
+                .map(|chunk| self.write_indirect(level - 1, chunk, used))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let bytes: Vec<u8> = ptrs.iter().flat_map(|p| p.to_le_bytes()).collect();
+        self.write_at(block, 0, &bytes)?;
+        Ok(block)
+    }
+}
+
+/// Encode a 128-byte ext2 inode
+fn encode_inode(
+    inode: &Inode,
+    links: u16,
+    size: u64,
+    map: &[u32; 15],
+    blocks: u32,
+    file_acl: u32,
+) -> [u8; 128] {
+    let mut raw = [0u8; 128];
+    put16(&mut raw, 0, inode.i_mode as u16);
+    put16(&mut raw, 2, inode.i_uid as u16);
+    put32(&mut raw, 4, size as u32);
+    put32(&mut raw, 8, inode.i_atime);
+    put32(&mut raw, 12, inode.i_ctime);
+    put32(&mut raw, 16, inode.i_mtime);
+    put16(&mut raw, 24, inode.i_gid as u16);
+    put16(&mut raw, 26, links);
+    put32(&mut raw, 28, blocks * (BLOCK_SIZE / 512));
+    for (i, &block) in map.iter().enumerate() {
+        put32(&mut raw, 40 + i * 4, block);
+    }
+    put32(&mut raw, 104, file_acl);
+    put32(&mut raw, 108, (size >> 32) as u32);
+    put16(&mut raw, 120, (inode.i_uid >> 16) as u16);
+    put16(&mut raw, 122, (inode.i_gid >> 16) as u16);
+    raw
+}
+
+/// File type code of a directory entry
+fn ext2_file_type(inode: &Inode) -> u8 {
+    if inode.is_dir() {
+        FT_DIR
+    } else if inode.is_symlink() {
+        FT_SYMLINK
+    } else {
+        FT_REG_FILE
+    }
+}
+
+impl LolelfFs {
+    /// Re-create an ext2 image's tree below `dest_dir`, with modes,
+    /// ownership, timestamps, hard links and xattrs
+    pub fn import_ext2(&mut self, src: &mut Ext2Image, dest_dir: u32) -> Result<Ext2Stats> {
+        let mut stats = Ext2Stats::default();
+        let mut linked = HashMap::new();
+        let mut visited = HashSet::from([EXT2_ROOT_INO]);
+        let mut count = 0;
+
+        let root = src.read_inode(EXT2_ROOT_INO)?;
+        if root.mode & S_IFMT != S_IFDIR {
+            bail!("The ext2 root inode is not a directory");
+        }
+        let mut queue = vec![(root, dest_dir, String::new())];
+        // Directory metadata is applied last, since adding entries changes it
+        let mut dirs = Vec::new();
+
+        while let Some((dir_inode, dir, prefix)) = queue.pop() {
+            let mut entries = src.read_dir(&dir_inode)?;
+            entries.sort();
+            for (raw_name, ino) in entries {
+                let path = format!("{}/{}", prefix, String::from_utf8_lossy(&raw_name));
+                count += 1;
+                self.report_progress("convert", count, 0, &path);
+                let Ok(name) = String::from_utf8(raw_name) else {
+                    stats
+                        .skipped
+                        .push((path, "name is not valid UTF-8".to_string()));
+                    continue;
+                };
+                if let Some(&target) = linked.get(&ino) {
+                    self.link(target, dir, &name)?;
+                    stats.hardlinks += 1;
+                    continue;
+                }
+
+                let inode = src.read_inode(ino)?;
+                let new_ino = match inode.mode & S_IFMT {
+                    S_IFDIR => {
+                        if !visited.insert(ino) {
+                            stats
+                                .skipped
+                                .push((path, "directory is linked more than once".to_string()));
+                            continue;
+                        }
+                        let new_dir = self.mkdir(dir, &name)?;
+                        stats.dirs += 1;
+                        queue.push((inode, new_dir, path));
+                        continue;
+                    }
+                    S_IFREG => {
+                        if inode.size > u32::MAX as u64 {
+                            stats
+                                .skipped
+                                .push((path, "file is 4 GiB or larger".to_string()));
+                            continue;
+                        }
+                        let data = src.read_data(&inode)?;
+                        let file = self.create_file(dir, &name)?;
+                        self.write_file(file, &data)
+                            .with_context(|| format!("Failed to convert '{}'", path))?;
+                        if inode.links > 1 {
+                            linked.insert(ino, file);
+                        }
+                        stats.files += 1;
+                        file
+                    }
+                    S_IFLNK => {
+                        let target = src.symlink_target(&inode)?;
+                        let target = match String::from_utf8(target) {
+                            Ok(target) if target.len() <= 27 => target,
+                            Ok(target) => {
+                                stats.skipped.push((
+                                    path,
+                                    format!(
+                                        "symlink target is {} bytes, lolelffs stores at most 27",
+                                        target.len()
+                                    ),
+                                ));
+                                continue;
+                            }
+                            Err(_) => {
+                                stats
+                                    .skipped
+                                    .push((path, "symlink target is not valid UTF-8".to_string()));
+                                continue;
+                            }
+                        };
+                        stats.symlinks += 1;
+                        self.symlink(dir, &name, &target)?
+                    }
+                    _ => {
+                        stats.skipped.push((
+                            path,
+                            "devices, FIFOs and sockets are not supported".to_string(),
+                        ));
+                        continue;
+                    }
+                };
+                self.apply_ext2_metadata(src, &inode, new_ino, &path, &mut stats)?;
+            }
+            dirs.push((dir_inode, dir, prefix));
+        }
+
+        for (inode, dir, path) in dirs.into_iter().rev() {
+            let path = if path.is_empty() {
+                "/".to_string()
+            } else {
+                path
+            };
+            self.apply_ext2_metadata(src, &inode, dir, &path, &mut stats)?;
+        }
+        self.report_progress("convert", count, count, "");
+        Ok(stats)
+    }
+
+    /// Copy mode, ownership, timestamps and xattrs from an ext2 inode
+    fn apply_ext2_metadata(
+        &mut self,
+        src: &mut Ext2Image,
+        ext2: &Ext2Inode,
+        inode_num: u32,
+        path: &str,
+        stats: &mut Ext2Stats,
+    ) -> Result<()> {
+        for (name, value) in src.xattrs(ext2)? {
+            if let Err(e) = self.set_xattr(inode_num, &name, &value) {
+                stats
+                    .skipped
+                    .push((format!("{} ({})", path, name), e.to_string()));
+            }
+        }
+        let mut inode = self.read_inode(inode_num)?;
+        inode.i_mode = (inode.i_mode & mode::S_IFMT) | (ext2.mode as u32 & 0o7777);
+        inode.i_uid = ext2.uid;
+        inode.i_gid = ext2.gid;
+        inode.i_atime = ext2.atime;
+        inode.i_mtime = ext2.mtime;
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Write the tree below `dir_inode_num` to a new ext2 image at `path`
+    ///
+    /// Without `size` the image is as large as this one, or as large as the
+    /// uncompressed tree needs if that is more. A /lost+found is added for
+    /// e2fsck if the tree has none.
+    pub fn export_ext2<P: AsRef<Path>>(
+        &mut self,
+        dir_inode_num: u32,
+        path: P,
+        size: Option<u64>,
+    ) -> Result<Ext2Stats> {
+        let root_inode = self.read_inode(dir_inode_num)?;
+        if !root_inode.is_dir() {
+            bail!("Inode {} is not a directory", dir_inode_num);
+        }
+        let mut stats = Ext2Stats::default();
+        let walked = self.walk_tree(dir_inode_num)?;
+
+        // Number the inodes: the root is 2, lost+found 11 if it has to be
+        // made, and everything else follows in walk order
+        let mut nodes: Vec<Ext2Node> = vec![Ext2Node {
+            src_ino: dir_inode_num,
+            inode: root_inode,
+            path: "/".to_string(),
+            links: 2,
+            entries: Vec::new(),
+            xattr_block: None,
+        }];
+        let ino_of = |index: usize| {
+            if index == 0 {
+                EXT2_ROOT_INO
+            } else {
+                EXT2_FIRST_INO + index as u32 - 1
+            }
+        };
+        let has_lost_found = walked
+            .iter()
+            .any(|w| w.depth == 1 && w.path == "lost+found" && w.entry.inode.is_dir());
+        if !has_lost_found {
+            let now = self.now();
+            nodes.push(Ext2Node {
+                src_ino: 0,
+                inode: Inode {
+                    i_mode: mode::S_IFDIR | 0o700,
+                    i_uid: 0,
+                    i_gid: 0,
+                    i_size: 0,
+                    i_ctime: now,
+                    i_atime: now,
+                    i_mtime: now,
+                    i_blocks: 0,
+                    i_nlink: 2,
+                    ei_block: 0,
+                    xattr_block: 0,
+                    i_data: [0; 28],
+                },
+                path: "/lost+found".to_string(),
+                links: 2,
+                entries: Vec::new(),
+                xattr_block: None,
+            });
+            nodes[0].links += 1;
+            nodes[0]
+                .entries
+                .push((b"lost+found".to_vec(), ino_of(1), FT_DIR));
+        }
+
+        let mut node_of_src: HashMap<u32, usize> = HashMap::new();
+        let mut dir_of_path: HashMap<String, usize> = HashMap::from([(String::new(), 0)]);
+        for walk in walked {
+            let parent_path = walk.path.rsplit_once('/').map_or("", |(p, _)| p);
+            let parent = dir_of_path[parent_path];
+            let inode = walk.entry.inode;
+            let file_type = ext2_file_type(&inode);
+
+            let index = match node_of_src.get(&walk.entry.inode_num) {
+                Some(&index) if !inode.is_dir() => {
+                    nodes[index].links += 1;
+                    stats.hardlinks += 1;
+                    index
+                }
+                _ => {
+                    nodes.push(Ext2Node {
+                        src_ino: walk.entry.inode_num,
+                        links: if inode.is_dir() { 2 } else { 1 },
+                        inode,
+                        path: format!("/{}", walk.path),
+                        entries: Vec::new(),
+                        xattr_block: None,
+                    });
+                    node_of_src.insert(walk.entry.inode_num, nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+            if file_type == FT_DIR {
+                nodes[parent].links += 1;
+                dir_of_path.insert(walk.path, index);
+            }
+            nodes[parent].entries.push((
+                walk.entry.filename.into_bytes(),
+                ino_of(index),
+                file_type,
+            ));
+        }
+
+        // Size everything up before laying out the image
+        let mut data_blocks = 0u64;
+        let mut large_file = false;
+        for (index, node) in nodes.iter_mut().enumerate() {
+            if node.inode.is_dir() {
+                let parent_ino = match node.path.rsplit_once('/') {
+                    Some(("", _)) | None => EXT2_ROOT_INO,
+                    Some((parent, _)) => ino_of(dir_of_path[parent.trim_start_matches('/')]),
+                };
+                let mut entries = vec![
+                    (b".".to_vec(), ino_of(index), FT_DIR),
+                    (b"..".to_vec(), parent_ino, FT_DIR),
+                ];
+                entries.append(&mut node.entries);
+                data_blocks += pack_dir(&entries).len() as u64;
+                node.entries = entries;
+            } else if node.inode.is_file() {
+                let size = node.inode.i_size as u64;
+                let blocks = size.div_ceil(BLOCK_SIZE as u64);
+                data_blocks += blocks + indirect_blocks(blocks);
+                large_file |= size >= 1 << 31;
+            }
+
+            if node.src_ino == 0 {
+                continue;
+            }
+            let mut xattrs = Vec::new();
+            for name in self.list_xattrs(node.src_ino)? {
+                if ACL_XATTRS.contains(&name.as_str()) {
+                    stats.skipped.push((
+                        format!("{} ({})", node.path, name),
+                        "POSIX ACLs are not converted".to_string(),
+                    ));
+                    continue;
+                }
+                let value = self.get_xattr(node.src_ino, &name)?;
+                xattrs.push((name, value));
+            }
+            if xattrs.is_empty() {
+                continue;
+            }
+            match encode_xattr_block(&xattrs) {
+                Some(block) => {
+                    node.xattr_block = Some(block);
+                    data_blocks += 1;
+                }
+                None => stats.skipped.push((
+                    node.path.clone(),
+                    "xattrs do not fit in one ext2 block".to_string(),
+                )),
+            }
+        }
+
+        let min_inodes = EXT2_FIRST_INO + nodes.len() as u32;
+        let layout = match size {
+            Some(size) => {
+                let layout = Ext2Layout::plan((size / BLOCK_SIZE as u64) as u32, min_inodes)?;
+                if layout.data_capacity() < data_blocks {
+                    bail!(
+                        "The tree needs {} blocks of ext2 data, {} bytes hold only {}",
+                        data_blocks,
+                        size,
+                        layout.data_capacity()
+                    );
+                }
+                layout
+            }
+            None => {
+                let mut blocks = self.superblock.nr_blocks;
+                loop {
+                    let layout = Ext2Layout::plan(blocks, min_inodes)?;
+                    if layout.data_capacity() >= data_blocks {
+                        break layout;
+                    }
+                    blocks += (data_blocks - layout.data_capacity()) as u32 + layout.overhead;
+                }
+            }
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())
+            .with_context(|| format!("Cannot create '{}'", path.as_ref().display()))?;
+        file.set_len(layout.blocks as u64 * BLOCK_SIZE as u64)?;
+        let mut out = Ext2Writer {
+            file,
+            alloc: Ext2Allocator::new(layout),
+        };
+
+        let mut used_dirs = vec![0u32; layout.groups as usize];
+        let mut used_inodes = vec![0u32; layout.groups as usize];
+        let total = nodes.len() as u64;
+        for (index, node) in nodes.iter().enumerate() {
+            let ino = ino_of(index);
+            self.report_progress("convert", index as u64 + 1, total, &node.path);
+
+            let (size, map, mut blocks) = if node.inode.is_dir() {
+                let dir_blocks = pack_dir(&node.entries);
+                let size = dir_blocks.len() as u64 * BLOCK_SIZE as u64;
+                let (map, blocks) = out.write_data(&dir_blocks.concat())?;
+                // As on import, the root and an added lost+found do not count
+                if index != 0 && node.src_ino != 0 {
+                    stats.dirs += 1;
+                }
+                (size, map, blocks)
+            } else if node.inode.is_symlink() {
+                // lolelffs targets always fit a fast symlink
+                let target = crate::file::symlink_target(&node.inode);
+                let mut map = [0u32; 15];
+                for (i, chunk) in target.chunks(4).enumerate() {
+                    let mut word = [0u8; 4];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    map[i] = u32::from_le_bytes(word);
+                }
+                stats.symlinks += 1;
+                (target.len() as u64, map, 0)
+            } else {
+                let mut data = Vec::with_capacity(node.inode.i_size as usize);
+                self.read_file_to(node.src_ino, &mut data)
+                    .with_context(|| format!("Failed to read '{}'", node.path))?;
+                let (map, blocks) = out.write_data(&data)?;
+                stats.files += 1;
+                (data.len() as u64, map, blocks)
+            };
+
+            let file_acl = match &node.xattr_block {
+                Some(block) => {
+                    let block_num = out.alloc.alloc()?;
+                    out.write_at(block_num, 0, block)?;
+                    blocks += 1;
+                    block_num
+                }
+                None => 0,
+            };
+
+            let raw = encode_inode(&node.inode, node.links, size, &map, blocks, file_acl);
+            let group = (ino - 1) / layout.inodes_per_group;
+            let slot = (ino - 1) % layout.inodes_per_group;
+            out.write_at(
+                layout.inode_table(group),
+                (slot * INODE_SIZE) as usize,
+                &raw,
+            )?;
+            if node.inode.is_dir() {
+                used_dirs[group as usize] += 1;
+            }
+        }
+
+        // Reserved inodes and those just written are in use, in order
+        let last_ino = ino_of(nodes.len() - 1);
+        let mut inode_bitmaps = Vec::new();
+        for group in 0..layout.groups {
+            let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+            let first = group * layout.inodes_per_group + 1;
+            for bit in 0..8 * BLOCK_SIZE {
+                let ino = first + bit;
+                if bit >= layout.inodes_per_group || ino <= last_ino {
+                    bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+                    if bit < layout.inodes_per_group {
+                        used_inodes[group as usize] += 1;
+                    }
+                }
+            }
+            inode_bitmaps.push(bitmap);
+        }
+
+        let mut descriptors = vec![0u8; (layout.gdt_blocks * BLOCK_SIZE) as usize];
+        for group in 0..layout.groups {
+            let g = group as usize;
+            let desc = &mut descriptors[g * DESC_SIZE as usize..(g + 1) * DESC_SIZE as usize];
+            put32(desc, 0, layout.block_bitmap(group));
+            put32(desc, 4, layout.inode_bitmap(group));
+            put32(desc, 8, layout.inode_table(group));
+            put16(desc, 12, out.alloc.free[g] as u16);
+            put16(desc, 14, (layout.inodes_per_group - used_inodes[g]) as u16);
+            put16(desc, 16, used_dirs[g] as u16);
+        }
+
+        let now = self.now();
+        let inode_count = layout.groups * layout.inodes_per_group;
+        let mut sb = [0u8; 1024];
+        put32(&mut sb, 0, inode_count);
+        put32(&mut sb, 4, layout.blocks);
+        put32(&mut sb, 12, out.alloc.free.iter().sum());
+        put32(&mut sb, 16, inode_count - used_inodes.iter().sum::<u32>());
+        put32(&mut sb, 24, BLOCK_SIZE.trailing_zeros() - 10);
+        put32(&mut sb, 28, BLOCK_SIZE.trailing_zeros() - 10);
+        put32(&mut sb, 32, BLOCKS_PER_GROUP);
+        put32(&mut sb, 36, BLOCKS_PER_GROUP);
+        put32(&mut sb, 40, layout.inodes_per_group);
+        put32(&mut sb, 48, now);
+        put16(&mut sb, 54, u16::MAX); // no mount-count checks
+        put16(&mut sb, 56, EXT2_MAGIC);
+        put16(&mut sb, 58, 1); // clean
+        put16(&mut sb, 60, 1); // continue on errors
+        put32(&mut sb, 64, now);
+        put32(&mut sb, 76, 1); // dynamic revision
+        put32(&mut sb, 84, EXT2_FIRST_INO);
+        put16(&mut sb, 88, INODE_SIZE as u16);
+        put32(&mut sb, 92, COMPAT_EXT_ATTR);
+        put32(&mut sb, 96, INCOMPAT_FILETYPE);
+        put32(
+            &mut sb,
+            100,
+            if large_file { RO_COMPAT_LARGE_FILE } else { 0 },
+        );
+        let uuid = if self.superblock.uuid == [0; 16] {
+            rand::random()
+        } else {
+            self.superblock.uuid
+        };
+        sb[104..120].copy_from_slice(&uuid);
+        let label = self.superblock.label();
+        let label = &label.as_bytes()[..label.len().min(16)];
+        sb[120..120 + label.len()].copy_from_slice(label);
+
+        for group in 0..layout.groups {
+            let start = layout.group_start(group);
+            put16(&mut sb, 90, group as u16);
+            // The primary superblock sits 1024 bytes in, its backups at the
+            // start of their group
+            out.write_at(start, if group == 0 { 1024 } else { 0 }, &sb)?;
+            out.write_at(start + 1, 0, &descriptors)?;
+            let bitmap = out.alloc.bitmaps[group as usize].clone();
+            out.write_at(layout.block_bitmap(group), 0, &bitmap)?;
+            out.write_at(
+                layout.inode_bitmap(group),
+                0,
+                &inode_bitmaps[group as usize],
+            )?;
+        }
+        out.file.sync_all()?;
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_ext2_roundtrip() {
+        let src_path = TempPath::new("ext2-src.img");
+        let ext2_path = TempPath::new("ext2.ext2");
+        let back_path = TempPath::new("ext2-back.img");
+
+        let mut src = LolelfFs::create(&src_path, 16 * 1024 * 1024).unwrap();
+        src.set_label("rootfs").unwrap();
+        let etc = src.mkdir(LOLELFFS_ROOT_INO, "etc").unwrap();
+        let conf = src.create_file(etc, "app.conf").unwrap();
+        src.write_file(conf, b"key = value\n").unwrap();
+        src.set_xattr(conf, "user.origin", b"test").unwrap();
+        src.set_xattr(conf, "security.capability", &[1, 0, 0, 2])
+            .unwrap();
+        src.link(conf, LOLELFFS_ROOT_INO, "conf-link").unwrap();
+        src.symlink(LOLELFFS_ROOT_INO, "cfg", "etc/app.conf")
+            .unwrap();
+        // Past the single indirect block's reach
+        let big: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i % 249) as u8).collect();
+        let data = src.create_file(LOLELFFS_ROOT_INO, "big.bin").unwrap();
+        src.write_file(data, &big).unwrap();
+        let mut inode = src.read_inode(data).unwrap();
+        inode.i_mode = mode::S_IFREG | 0o640;
+        inode.i_uid = 70000;
+        inode.i_gid = 100;
+        src.write_inode(data, &inode).unwrap();
+
+        let stats = src
+            .export_ext2(LOLELFFS_ROOT_INO, &ext2_path, None)
+            .unwrap();
+        assert_eq!((stats.files, stats.symlinks, stats.hardlinks), (2, 1, 1));
+        assert_eq!(stats.dirs, 1);
+        assert!(stats.skipped.is_empty());
+
+        let mut ext2 = Ext2Image::open(&ext2_path).unwrap();
+        assert_eq!(ext2.label, "rootfs");
+        let mut back = LolelfFs::create(&back_path, ext2.size).unwrap();
+        let stats = back.import_ext2(&mut ext2, LOLELFFS_ROOT_INO).unwrap();
+        assert_eq!((stats.files, stats.symlinks, stats.hardlinks), (2, 1, 1));
+        assert_eq!(stats.dirs, 2);
+
+        // The same tree, plus the lost+found ext2 needs
+        let changes = src
+            .diff(
+                LOLELFFS_ROOT_INO,
+                &mut back,
+                LOLELFFS_ROOT_INO,
+                &Default::default(),
+            )
+            .unwrap();
+        let changed: Vec<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(changed, ["lost+found"]);
+
+        assert!(Ext2Image::open(&src_path).is_err());
+    }
+}
@@ -0,0 +1,1460 @@
+//! File operations for lolelffs
+
+use crate::compress;
+use crate::error::{FsError, NoSpaceKind};
+use crate::fs::{BlockKind, LolelfFs};
+use crate::hint::AccessHint;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::io::Write;
+
+/// Per-write overrides for the algorithms recorded in each extent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// Compression algorithm (None = superblock default)
+    pub comp_algo: Option<u8>,
+    /// Encryption algorithm (None = superblock default)
+    pub enc_algo: Option<u8>,
+}
+
+/// A block ready to be written, with the algorithms actually applied
+struct EncodedBlock {
+    data: Vec<u8>,
+    comp_algo: u8,
+    enc_algo: u8,
+}
+
+/// Fill in what `opts` leaves open for a write of `inode`, and the access
+/// hint that decides where its blocks go
+fn resolve_write_options(inode: &Inode, opts: &WriteOptions) -> (WriteOptions, AccessHint) {
+    // A per-file algorithm wins over the exclusion list and hints
+    let mut opts = *opts;
+    if opts.comp_algo.is_none() {
+        opts.comp_algo = inode.comp_override();
+    }
+    // Files matching the exclusion list skip compression unless overridden
+    if opts.comp_algo.is_none() && inode.flags() & LOLELFFS_INODE_NOCOMP != 0 {
+        opts.comp_algo = Some(LOLELFFS_COMP_NONE);
+    }
+    // Access hints pick the compression the caller left open and where the
+    // data goes
+    let hint = AccessHint::from_flags(inode.flags());
+    if opts.comp_algo.is_none() {
+        opts.comp_algo = hint.comp_algo();
+    }
+    (opts, hint)
+}
+
+/// Symlink target stored inline in i_data
+pub(crate) fn symlink_target(inode: &Inode) -> Vec<u8> {
+    inode
+        .i_data
+        .iter()
+        .take_while(|&&b| b != 0)
+        .copied()
+        .collect()
+}
+
+impl LolelfFs {
+    /// Quick change check: whether a file's size or mtime differs from the
+    /// given ones, as rsync does before comparing any data
+    pub fn file_changed(&mut self, inode_num: u32, size: u64, mtime: u32) -> Result<bool> {
+        let inode = self.read_inode(inode_num)?;
+        Ok(inode.i_size as u64 != size || inode.i_mtime != mtime)
+    }
+
+    /// Read file contents
+    ///
+    /// Files larger than `max_read_size` are rejected; use `read_file_to` to
+    /// stream them instead.
+    pub fn read_file(&mut self, inode_num: u32) -> Result<Vec<u8>> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.is_symlink() {
+            return Ok(symlink_target(&inode));
+        }
+
+        if inode.i_size as usize > self.max_read_size {
+            bail!(
+                "File is {} bytes, exceeding the in-memory read limit of {} bytes",
+                inode.i_size,
+                self.max_read_size
+            );
+        }
+
+        let mut data = Vec::new();
+        self.read_file_to(inode_num, &mut data)?;
+        Ok(data)
+    }
+
+    /// Stream file contents to a writer one block at a time
+    ///
+    /// Returns the number of bytes written.
+    pub fn read_file_to<W: Write>(&mut self, inode_num: u32, writer: &mut W) -> Result<u64> {
+        self.read_file_range_to(inode_num, 0, u64::MAX, writer)
+    }
+
+    /// Read up to `len` bytes starting at byte `offset` of a file
+    ///
+    /// Only the blocks covering the range are decoded. The result is shorter
+    /// than `len` when the range runs past the end of the file, and empty when
+    /// it starts there.
+    pub fn read_file_range(&mut self, inode_num: u32, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let inode = self.read_inode(inode_num)?;
+        let size = if inode.is_symlink() {
+            symlink_target(&inode).len() as u64
+        } else {
+            inode.i_size as u64
+        };
+        let span = size.saturating_sub(offset).min(len);
+        if span > self.max_read_size as u64 {
+            bail!(
+                "Range is {} bytes, exceeding the in-memory read limit of {} bytes",
+                span,
+                self.max_read_size
+            );
+        }
+
+        let mut data = Vec::with_capacity(span as usize);
+        self.read_file_range_to(inode_num, offset, len, &mut data)?;
+        Ok(data)
+    }
+
+    /// Read the first `n` bytes of a file, or all of it if it is shorter
+    ///
+    /// Only the blocks holding the prefix are decoded, which for a small
+    /// `n` means the start of the first extent, so this stays cheap however
+    /// large or fragmented the file is.
+    pub fn read_prefix(&mut self, inode_num: u32, n: u64) -> Result<Vec<u8>> {
+        self.read_file_range(inode_num, 0, n)
+    }
+
+    /// Stream up to `len` bytes starting at byte `offset` of a file to a
+    /// writer, decoding only the blocks covering the range
+    ///
+    /// Returns the number of bytes written.
+    pub fn read_file_range_to<W: Write>(
+        &mut self,
+        inode_num: u32,
+        offset: u64,
+        len: u64,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.is_dir() {
+            bail!("Cannot read directory as file");
+        }
+
+        if inode.is_symlink() {
+            let target = symlink_target(&inode);
+            let start = offset.min(target.len() as u64) as usize;
+            let end = offset.saturating_add(len).min(target.len() as u64) as usize;
+            writer.write_all(&target[start..end])?;
+            return Ok((end - start) as u64);
+        }
+
+        if inode.ei_block == 0 || inode.i_size == 0 {
+            return Ok(0);
+        }
+
+        let ei = self.readable_extent_index(inode_num, &inode)?;
+        let size = inode.i_size as u64;
+        let start = offset.min(size);
+        let end = offset.saturating_add(len).min(size);
+        if start >= end {
+            return Ok(0);
+        }
+
+        let block_size = LOLELFFS_BLOCK_SIZE as u64;
+        let first_block = (start / block_size) as u32;
+        let last_block = ((end - 1) / block_size) as u32;
+        let key = self.file_key(inode_num, &inode);
+
+        for logical_block in first_block..=last_block {
+            let block = self.read_file_block(inode_num, &ei, &key, logical_block)?;
+
+            // Clip the block to the requested range
+            let block_start = logical_block as u64 * block_size;
+            let from = (start.max(block_start) - block_start) as usize;
+            let to = (end.min(block_start + block_size) - block_start) as usize;
+
+            writer.write_all(&block[from..to])?;
+        }
+
+        Ok(end - start)
+    }
+
+    /// Extent index of a file about to be read, checked for algorithms this
+    /// build cannot decode and for a size the extents cannot back
+    pub(crate) fn readable_extent_index(
+        &mut self,
+        inode_num: u32,
+        inode: &Inode,
+    ) -> Result<ExtentIndex> {
+        let ei = self.read_extent_index(inode)?;
+        self.check_extents_supported(inode_num, &ei)?;
+
+        // Reject sizes the extents cannot back before trusting i_size
+        let covered_blocks = ei
+            .extents
+            .iter()
+            .take_while(|e| !e.is_empty())
+            .map(|e| e.ee_block as u64 + e.ee_len as u64)
+            .max()
+            .unwrap_or(0);
+        if inode.i_size as u64 > covered_blocks * LOLELFFS_BLOCK_SIZE as u64 {
+            bail!(
+                "Corrupt inode {}: size {} exceeds the {} blocks covered by its extents",
+                inode_num,
+                inode.i_size,
+                covered_blocks
+            );
+        }
+        Ok(ei)
+    }
+
+    /// Read one logical block of a file through the block cache
+    ///
+    /// Blocks of compressed extents are decoded once and then served from
+    /// the cache until the file is rewritten.
+    pub(crate) fn read_file_block(
+        &mut self,
+        inode_num: u32,
+        ei: &ExtentIndex,
+        key: &[u8; 32],
+        logical_block: u32,
+    ) -> Result<Vec<u8>> {
+        let compressed = ei
+            .find_extent(logical_block)
+            .is_some_and(|e| e.ee_comp_algo != LOLELFFS_COMP_NONE as u16);
+        if !compressed {
+            return self.read_logical_block(ei, key, logical_block);
+        }
+        if let Some(block) = self.block_cache.get(inode_num, logical_block) {
+            return Ok(block);
+        }
+        let block = self.read_logical_block(ei, key, logical_block)?;
+        self.block_cache
+            .insert(inode_num, logical_block, block.clone());
+        Ok(block)
+    }
+
+    /// Read and decode one logical block of a file (zeros for holes)
+    pub(crate) fn read_logical_block(
+        &mut self,
+        ei: &ExtentIndex,
+        key: &[u8; 32],
+        logical_block: u32,
+    ) -> Result<Vec<u8>> {
+        let extent = match ei.find_extent(logical_block) {
+            Some(extent) => extent,
+            None => return Ok(vec![0u8; LOLELFFS_BLOCK_SIZE as usize]),
+        };
+        let phys_block = match extent.get_physical(logical_block) {
+            Some(phys_block) => phys_block,
+            None => return Ok(vec![0u8; LOLELFFS_BLOCK_SIZE as usize]),
+        };
+
+        let raw_block = self.read_block_checked(phys_block, BlockKind::Data)?;
+
+        // Step 1: Decrypt if needed (decrypt-then-decompress pipeline)
+        let decrypted_block = if extent.ee_enc_algo != LOLELFFS_ENC_NONE {
+            // Check if filesystem is unlocked
+            if !self.enc_unlocked {
+                bail!("Cannot read encrypted block: filesystem is locked");
+            }
+
+            crate::encrypt::open_block(extent.ee_enc_algo, key, logical_block as u64, &raw_block)?
+        } else {
+            raw_block
+        };
+
+        // Step 2: Decompress if needed
+        if extent.ee_comp_algo != LOLELFFS_COMP_NONE as u16 {
+            compress::decompress_block(
+                extent.ee_comp_algo as u8,
+                &decrypted_block,
+                LOLELFFS_BLOCK_SIZE as usize,
+            )
+        } else {
+            Ok(decrypted_block)
+        }
+    }
+
+    /// Write data to a file
+    pub fn write_file(&mut self, inode_num: u32, data: &[u8]) -> Result<()> {
+        self.write_file_with_options(inode_num, data, &WriteOptions::default())
+    }
+
+    /// Write data to a file, overriding the superblock's default algorithms
+    ///
+    /// Each extent records the algorithms its blocks were written with, so
+    /// files written with different options can coexist in one image.
+    pub fn write_file_with_options(
+        &mut self,
+        inode_num: u32,
+        data: &[u8],
+        opts: &WriteOptions,
+    ) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        self.block_cache.invalidate(inode_num);
+
+        if inode.is_dir() {
+            bail!("Cannot write to directory");
+        }
+
+        if inode.is_symlink() {
+            bail!("Cannot write to symlink");
+        }
+
+        let (opts, hint) = resolve_write_options(&inode, opts);
+        let enc_algo = self.write_enc_algo(&opts);
+        self.negotiate_cipher(enc_algo)?;
+
+        // With reflinks on, contents another file already holds share its
+        // extents instead of being written again
+        let digest = if self.reflink_enabled()
+            && opts.comp_algo.is_none()
+            && opts.enc_algo.is_none()
+            && data.len() >= LOLELFFS_BLOCK_SIZE as usize
+        {
+            let digest = crate::hash::hash(LOLELFFS_HASH_SHA256, data)?;
+            if self.share_identical(inode_num, &mut inode, data, &digest, enc_algo)? {
+                return Ok(());
+            }
+            Some(digest)
+        } else {
+            None
+        };
+
+        // Encode every block up front so a failure leaves the old contents intact
+        let key = self.file_key(inode_num, &inode);
+        let blocks = self.encode_blocks(data, &key, &opts)?;
+
+        // Free existing blocks. With ordered writes they stay allocated until
+        // the index no longer names them, so new data never lands in them.
+        let ordered = self.ordered_writes();
+        let old_extents: Vec<Extent> = if inode.ei_block != 0 {
+            let ei = self.read_extent_index(&inode)?;
+            ei.extents
+                .into_iter()
+                .take_while(|e| !e.is_empty())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !ordered {
+            for extent in &old_extents {
+                self.release_extent(inode_num, extent)?;
+            }
+        }
+
+        // Handle empty file
+        if data.is_empty() {
+            if inode.ei_block != 0 {
+                let ei = ExtentIndex {
+                    nr_files: 0,
+                    extents: vec![Extent::default(); LOLELFFS_MAX_EXTENTS],
+                };
+                self.write_extent_index(inode.ei_block, &ei)?;
+            }
+
+            inode.i_size = 0;
+            inode.i_blocks = 0;
+            let now = self.now();
+            inode.i_mtime = now;
+            inode.i_ctime = now;
+            self.write_inode(inode_num, &inode)?;
+            if ordered {
+                self.free_replaced_extents(inode_num, &old_extents)?;
+            }
+            return Ok(());
+        }
+
+        // Allocate extent index block if needed
+        if inode.ei_block == 0 {
+            let ei_block = self.alloc_blocks(1)?;
+            inode.ei_block = ei_block;
+        }
+
+        let num_blocks = blocks.len() as u32;
+        let mut extents: Vec<Extent> = Vec::new();
+        self.append_extents(&mut extents, &blocks, 0, hint == AccessHint::Cold)?;
+
+        // New data and its bitmap bits must be durable before the index
+        self.barrier()?;
+
+        // Pad extents to LOLELFFS_MAX_EXTENTS
+        while extents.len() < LOLELFFS_MAX_EXTENTS {
+            extents.push(Extent::default());
+        }
+
+        // Write extent index
+        let ei = ExtentIndex {
+            nr_files: 0,
+            extents,
+        };
+        self.write_extent_index(inode.ei_block, &ei)?;
+
+        // Update inode
+        inode.i_size = data.len() as u32;
+        inode.i_blocks = num_blocks;
+        let now = self.now();
+        inode.i_mtime = now;
+        inode.i_ctime = now;
+        self.write_inode(inode_num, &inode)?;
+        if ordered {
+            self.free_replaced_extents(inode_num, &old_extents)?;
+        }
+        if let Some(digest) = digest {
+            self.record_content(inode_num, &digest)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` at byte `offset` of a regular file, growing the file if
+    /// the write ends past its end
+    ///
+    /// Only the blocks the write touches are decoded and encoded again, in
+    /// place and with the algorithms their extent records. Blocks past the
+    /// old end are added after the last extent, which grows instead when
+    /// the blocks behind it are free. A write that cannot be done this way,
+    /// into a shared extent or a hole, or making a block that no longer
+    /// compresses with its extent's algorithm, rewrites the whole file with
+    /// `write_file`. Unlike `write_file`, blocks are overwritten in place,
+    /// so an interrupted write can leave a mix of old and new blocks.
+    pub fn write_file_at(&mut self, inode_num: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        if inode.is_dir() {
+            bail!("Cannot write to directory");
+        }
+        if inode.is_symlink() {
+            bail!("Cannot write to symlink");
+        }
+        let end = offset.saturating_add(data.len() as u64);
+        if end > u32::MAX as u64 {
+            bail!(
+                "Write ending at byte {} exceeds the largest file size of {} bytes",
+                end,
+                u32::MAX
+            );
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        if inode.ei_block == 0 {
+            return self.write_file_at_by_rewrite(inode_num, offset, data);
+        }
+        self.block_cache.invalidate(inode_num);
+
+        let (opts, hint) = resolve_write_options(&inode, &WriteOptions::default());
+        let comp_algo = self.write_comp_algo(&opts);
+        let enc_algo = self.write_enc_algo(&opts);
+        self.negotiate_cipher(enc_algo)?;
+
+        let mut ei = self.read_extent_index(&inode)?;
+        self.check_extents_supported(inode_num, &ei)?;
+        let mut extents: Vec<Extent> = ei
+            .extents
+            .iter()
+            .take_while(|e| !e.is_empty())
+            .copied()
+            .collect();
+        let mapped_end = extents
+            .iter()
+            .map(|e| e.ee_block + e.ee_len)
+            .max()
+            .unwrap_or(0);
+        let key = self.file_key(inode_num, &inode);
+
+        // A growing file also changes the length of its old last block and
+        // zero-fills up to the write
+        let old_size = inode.i_size as u64;
+        let new_size = end.max(old_size);
+        let bs = LOLELFFS_BLOCK_SIZE as u64;
+        let first_block = if end > old_size {
+            (offset.min(old_size) / bs) as u32
+        } else {
+            (offset / bs) as u32
+        };
+        let last_block = ((end - 1) / bs) as u32;
+
+        // Encode everything before writing anything, so falling back leaves
+        // no partial changes behind
+        let mut rewrites = Vec::new();
+        let mut appended = Vec::new();
+        for logical_block in first_block..=last_block {
+            let block_start = logical_block as u64 * bs;
+            let mut block = if block_start < old_size {
+                self.read_logical_block(&ei, &key, logical_block)?
+            } else {
+                Vec::new()
+            };
+            block.resize(bs as usize, 0);
+            let from = offset.max(block_start);
+            let to = end.min(block_start + bs);
+            if from < to {
+                block[(from - block_start) as usize..(to - block_start) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            }
+            block.truncate((new_size - block_start).min(bs) as usize);
+
+            if logical_block >= mapped_end {
+                let encoded =
+                    self.encode_block(&block, logical_block, comp_algo, enc_algo, &key)?;
+                match encoded {
+                    Some(encoded) => appended.push(encoded),
+                    None => return self.write_file_at_by_rewrite(inode_num, offset, data),
+                }
+                continue;
+            }
+            let extent = match ei.find_extent(logical_block) {
+                Some(extent) if !extent.is_shared() && !extent.has_metadata() => *extent,
+                _ => return self.write_file_at_by_rewrite(inode_num, offset, data),
+            };
+            let encoded = self.encode_block(
+                &block,
+                logical_block,
+                extent.ee_comp_algo as u8,
+                extent.ee_enc_algo,
+                &key,
+            )?;
+            match (encoded, extent.get_physical(logical_block)) {
+                (Some(encoded), Some(phys_block))
+                    if encoded.comp_algo as u16 == extent.ee_comp_algo =>
+                {
+                    rewrites.push((phys_block, encoded.data))
+                }
+                _ => return self.write_file_at_by_rewrite(inode_num, offset, data),
+            }
+        }
+
+        for (phys_block, block) in &rewrites {
+            self.write_block(*phys_block, block)?;
+        }
+        if !appended.is_empty() {
+            let first_appended = last_block + 1 - appended.len() as u32;
+            self.append_extents(
+                &mut extents,
+                &appended,
+                first_appended,
+                hint == AccessHint::Cold,
+            )?;
+            // New data and its bitmap bits must be durable before the index
+            self.barrier()?;
+            extents.resize(LOLELFFS_MAX_EXTENTS, Extent::default());
+            ei.extents = extents;
+            self.write_extent_index(inode.ei_block, &ei)?;
+            inode.i_blocks += appended.len() as u32;
+        }
+
+        inode.i_size = new_size as u32;
+        let now = self.now();
+        inode.i_mtime = now;
+        inode.i_ctime = now;
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// `write_file_at` for writes that cannot be done in place: read the
+    /// file, patch it and write it back whole
+    fn write_file_at_by_rewrite(&mut self, inode_num: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let mut contents = self.read_file(inode_num)?;
+        let end = offset as usize + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        self.write_file(inode_num, &contents)
+    }
+
+    /// Allocate and write `blocks` as logical blocks `first_block..` after
+    /// `extents`, adding them to it
+    ///
+    /// An extent carries a single pair of algorithms, so a new extent starts
+    /// wherever the encoding changes. Blocks that continue the last extent
+    /// with its encoding grow it when the blocks behind it are free. On
+    /// failure everything allocated here is freed and `extents` is left as
+    /// it was.
+    fn append_extents(
+        &mut self,
+        extents: &mut Vec<Extent>,
+        blocks: &[EncodedBlock],
+        first_block: u32,
+        cold: bool,
+    ) -> Result<()> {
+        let kept = extents.len();
+        let kept_len = extents.last().map_or(0, |e| e.ee_len);
+        let result = self.place_blocks(extents, blocks, first_block, cold);
+        if result.is_err() {
+            if kept > 0 {
+                let last = &mut extents[kept - 1];
+                let grown = last.ee_len - kept_len;
+                last.ee_len = kept_len;
+                let start = last.ee_start + kept_len;
+                self.free_blocks(start, grown)?;
+            }
+            for extent in extents.drain(kept..).collect::<Vec<_>>() {
+                self.free_blocks(extent.ee_start, extent.ee_len)?;
+            }
+            return result;
+        }
+
+        for extent in extents.iter() {
+            let end = extent.ee_block + extent.ee_len;
+            if end <= first_block {
+                continue;
+            }
+            for logical_block in extent.ee_block.max(first_block)..end {
+                let block = &blocks[(logical_block - first_block) as usize];
+                self.write_block(extent.get_physical(logical_block).unwrap(), &block.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate room for `blocks` for `append_extents`, recording it in
+    /// `extents` without writing anything
+    fn place_blocks(
+        &mut self,
+        extents: &mut Vec<Extent>,
+        blocks: &[EncodedBlock],
+        first_block: u32,
+        cold: bool,
+    ) -> Result<()> {
+        let num_blocks = blocks.len() as u32;
+        let mut allocated = 0u32;
+
+        while allocated < num_blocks {
+            let (comp_algo, enc_algo) = {
+                let first = &blocks[allocated as usize];
+                (first.comp_algo, first.enc_algo)
+            };
+            let run = blocks[allocated as usize..]
+                .iter()
+                .take_while(|b| b.comp_algo == comp_algo && b.enc_algo == enc_algo)
+                .count() as u32;
+
+            // Continue the last extent where the blocks behind it are free
+            if let Some(last) = extents.last_mut() {
+                let limit = self.merged_extent_limit(last);
+                if last.ee_block + last.ee_len == first_block + allocated
+                    && last.ee_comp_algo == comp_algo as u16
+                    && last.ee_enc_algo == enc_algo
+                    && !last.is_shared()
+                    && !last.has_metadata()
+                    && last.ee_len < limit
+                {
+                    let next = last.ee_start + last.ee_len;
+                    let mut grow = 0;
+                    while grow < run.min(limit - last.ee_len)
+                        && next + grow < self.superblock.nr_blocks
+                        && self.is_block_free(next + grow)?
+                    {
+                        grow += 1;
+                    }
+                    if grow > 0 {
+                        self.mark_blocks(next, grow, false)?;
+                        self.superblock.nr_free_blocks -= grow;
+                        self.write_superblock()?;
+                        last.ee_len += grow;
+                        allocated += grow;
+                        continue;
+                    }
+                }
+            }
+
+            // Determine if we need metadata for this extent
+            let needs_metadata = false; // Currently always false - no per-block metadata
+
+            let max_extent_size = if needs_metadata {
+                LOLELFFS_MAX_BLOCKS_PER_EXTENT
+            } else {
+                let large = self.superblock.max_extent_blocks_large;
+                if large == 0 || large > LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE {
+                    LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE
+                } else {
+                    large
+                }
+            };
+
+            let extent_size = self
+                .calc_optimal_extent_size(first_block + allocated, needs_metadata)
+                .min(run)
+                .min(max_extent_size)
+                .max(1);
+
+            if extents.len() >= LOLELFFS_MAX_EXTENTS {
+                return Err(FsError::no_space(
+                    NoSpaceKind::ExtentSlots,
+                    extents.len() as u64 + 1,
+                    LOLELFFS_MAX_EXTENTS as u64,
+                )
+                .into());
+            }
+
+            let start_block = self.alloc_blocks_placed(extent_size, cold)?;
+
+            let mut flags = 0u16;
+            if comp_algo != LOLELFFS_COMP_NONE {
+                flags |= LOLELFFS_EXT_COMPRESSED;
+            }
+            if enc_algo != LOLELFFS_ENC_NONE {
+                flags |= LOLELFFS_EXT_ENCRYPTED;
+            }
+
+            extents.push(Extent {
+                ee_block: first_block + allocated,
+                ee_len: extent_size,
+                ee_start: start_block,
+                ee_comp_algo: comp_algo as u16,
+                ee_enc_algo: enc_algo,
+                ee_reserved: 0,
+                ee_flags: flags,
+                ee_reserved2: 0,
+                ee_meta: 0,
+            });
+
+            allocated += extent_size;
+        }
+        Ok(())
+    }
+
+    /// Free a file's previous extents once its new index is durable
+    fn free_replaced_extents(&mut self, inode_num: u32, extents: &[Extent]) -> Result<()> {
+        self.barrier()?;
+        for extent in extents {
+            self.release_extent(inode_num, extent)?;
+        }
+        Ok(())
+    }
+
+    /// Encryption key for a file's data blocks
+    ///
+    /// Images created with per-file keys derive an independent key from the
+    /// master key, inode number, and the inode's generation; older images use
+    /// the master key directly.
+    pub fn file_key(&self, inode_num: u32, inode: &Inode) -> [u8; 32] {
+        if self.superblock.enc_features & LOLELFFS_ENC_FEAT_PER_FILE_KEYS != 0 {
+            crate::encrypt::derive_file_key(&self.enc_master_key, inode_num, inode.generation())
+        } else {
+            self.enc_master_key
+        }
+    }
+
+    /// Encryption algorithm a write with `opts` uses
+    fn write_enc_algo(&self, opts: &WriteOptions) -> u8 {
+        match opts.enc_algo {
+            Some(algo) => algo,
+            None if self.superblock.enc_enabled != 0 => self.superblock.enc_default_algo as u8,
+            None => LOLELFFS_ENC_NONE,
+        }
+    }
+
+    /// Compression algorithm a write with `opts` tries
+    fn write_comp_algo(&self, opts: &WriteOptions) -> u8 {
+        match opts.comp_algo {
+            Some(algo) => algo,
+            None if self.superblock.comp_enabled != 0 => self.superblock.comp_default_algo as u8,
+            None => LOLELFFS_COMP_NONE,
+        }
+    }
+
+    /// Compress and encrypt file data into on-disk blocks
+    fn encode_blocks(
+        &self,
+        data: &[u8],
+        key: &[u8; 32],
+        opts: &WriteOptions,
+    ) -> Result<Vec<EncodedBlock>> {
+        let comp_algo = self.write_comp_algo(opts);
+        let enc_algo = self.write_enc_algo(opts);
+
+        let mut blocks = Vec::with_capacity(data.len().div_ceil(LOLELFFS_BLOCK_SIZE as usize));
+
+        for (idx, chunk) in data.chunks(LOLELFFS_BLOCK_SIZE as usize).enumerate() {
+            match self.encode_block(chunk, idx as u32, comp_algo, enc_algo, key)? {
+                Some(block) => blocks.push(block),
+                None => bail!(
+                    "Block {} does not leave room for the {} authentication tag; \
+                     enable compression or use a non-AEAD algorithm",
+                    idx,
+                    crate::encrypt::get_algo_name(enc_algo)
+                ),
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// Compress and encrypt the `chunk` of file data stored as
+    /// `logical_block`, or `None` if an authentication tag does not fit
+    ///
+    /// Only full blocks are compressed, and only when that makes them
+    /// smaller; the returned block says which algorithm was applied.
+    fn encode_block(
+        &self,
+        chunk: &[u8],
+        logical_block: u32,
+        comp_algo: u8,
+        enc_algo: u8,
+        key: &[u8; 32],
+    ) -> Result<Option<EncodedBlock>> {
+        if enc_algo != LOLELFFS_ENC_NONE {
+            if self.superblock.enc_enabled == 0 {
+                bail!("Cannot write encrypted data: filesystem has no encryption key");
+            }
+            // Check if filesystem is unlocked
+            if !self.enc_unlocked {
+                bail!("Cannot write encrypted data: filesystem is locked");
+            }
+        }
+
+        // Prepare block data (pad to full block size)
+        let mut block = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        block[..chunk.len()].copy_from_slice(chunk);
+
+        // Step 1: Compress if enabled (only full blocks are compressed)
+        let compressed =
+            if comp_algo != LOLELFFS_COMP_NONE && chunk.len() == LOLELFFS_BLOCK_SIZE as usize {
+                compress::compress_block(comp_algo, &block).ok().flatten()
+            } else {
+                None
+            };
+        let (payload, used_comp_algo) = match compressed {
+            Some(compressed) => (compressed, comp_algo),
+            None => (chunk.to_vec(), LOLELFFS_COMP_NONE),
+        };
+
+        // Step 2: Encrypt if enabled (compress-then-encrypt)
+        let data = if enc_algo != LOLELFFS_ENC_NONE {
+            if payload.len() > crate::encrypt::block_capacity(enc_algo) {
+                return Ok(None);
+            }
+            crate::encrypt::seal_block(enc_algo, key, logical_block as u64, &payload)?
+        } else {
+            let mut padded = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+            padded[..payload.len()].copy_from_slice(&payload);
+            padded
+        };
+
+        Ok(Some(EncodedBlock {
+            data,
+            comp_algo: used_comp_algo,
+            enc_algo,
+        }))
+    }
+
+    /// Create a new regular file
+    pub fn create_file(&mut self, parent_inode_num: u32, name: &str) -> Result<u32> {
+        let nocomp = self.is_comp_excluded(name);
+        let new_inode_num = self.alloc_file_inode(1, nocomp)?;
+
+        // Add entry to parent directory
+        if let Err(e) = crate::xattr::inherit_default_xattrs(self, parent_inode_num, new_inode_num)
+            .and_then(|()| self.add_dir_entry(parent_inode_num, name, new_inode_num))
+        {
+            // Rollback on failure
+            let ei_block = self.read_inode(new_inode_num)?.ei_block;
+            self.free_inode_xattrs(new_inode_num)?;
+            self.free_inode(new_inode_num)?;
+            self.free_blocks(ei_block, 1)?;
+            return Err(e);
+        }
+
+        Ok(new_inode_num)
+    }
+
+    /// Create a regular file with no directory entry (like `O_TMPFILE`)
+    ///
+    /// The inode starts with a link count of zero and is put on the orphan
+    /// list, so it is reclaimed by `free_orphans` if it is never linked.
+    /// `parent_hint` must be a directory; it is where the file is expected to
+    /// be linked later.
+    pub fn create_unnamed(&mut self, parent_hint: u32) -> Result<u32> {
+        if !self.read_inode(parent_hint)?.is_dir() {
+            bail!("Inode {} is not a directory", parent_hint);
+        }
+
+        let inode_num = self.alloc_file_inode(0, false)?;
+        let mut inode = self.read_inode(inode_num)?;
+        inode.set_next_orphan(self.superblock.last_orphan);
+        self.write_inode(inode_num, &inode)?;
+
+        self.superblock.last_orphan = inode_num;
+        self.write_superblock()?;
+
+        Ok(inode_num)
+    }
+
+    /// Give an unnamed inode from `create_unnamed` its first name
+    pub fn link_unnamed(
+        &mut self,
+        inode_num: u32,
+        parent_inode_num: u32,
+        name: &str,
+    ) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        if inode.i_nlink != 0 || !self.orphans()?.contains(&inode_num) {
+            bail!("Inode {} is not an unnamed inode", inode_num);
+        }
+
+        // The link count goes up first: an orphan that still has links is
+        // only dropped from the list, never freed, if we stop part way
+        inode.i_nlink = 1;
+        if self.is_comp_excluded(name) {
+            inode.set_flags(inode.flags() | LOLELFFS_INODE_NOCOMP);
+        }
+        inode.i_ctime = self.now();
+        self.write_inode(inode_num, &inode)?;
+
+        if let Err(e) = self.add_dir_entry(parent_inode_num, name, inode_num) {
+            inode.i_nlink = 0;
+            self.write_inode(inode_num, &inode)?;
+            return Err(e);
+        }
+
+        self.remove_orphan(inode_num)
+    }
+
+    /// Free an unnamed inode that will not be linked
+    pub fn discard_unnamed(&mut self, inode_num: u32) -> Result<()> {
+        let inode = self.read_inode(inode_num)?;
+        if inode.i_nlink != 0 || !self.orphans()?.contains(&inode_num) {
+            bail!("Inode {} is not an unnamed inode", inode_num);
+        }
+
+        self.remove_orphan(inode_num)?;
+        self.free_unlinked(inode_num, &inode)
+    }
+
+    /// Inodes on the orphan list, most recently created first
+    pub fn orphans(&mut self) -> Result<Vec<u32>> {
+        let mut orphans = Vec::new();
+        let mut next = self.superblock.last_orphan;
+
+        while next != 0 {
+            if next >= self.superblock.nr_inodes || orphans.contains(&next) {
+                bail!("Corrupt orphan list at inode {}", next);
+            }
+            orphans.push(next);
+            next = self.read_inode(next)?.next_orphan();
+        }
+
+        Ok(orphans)
+    }
+
+    /// Reclaim unnamed inodes left behind, e.g. by a crash before linking
+    ///
+    /// Only call this when no other process can be holding unnamed inodes
+    /// of this image. Returns the number of inodes freed.
+    pub fn free_orphans(&mut self) -> Result<u32> {
+        let mut freed = 0;
+
+        for inode_num in self.orphans()? {
+            let mut inode = self.read_inode(inode_num)?;
+            inode.set_next_orphan(0);
+            if inode.i_nlink == 0 {
+                self.free_unlinked(inode_num, &inode)?;
+                freed += 1;
+            } else {
+                self.write_inode(inode_num, &inode)?;
+            }
+        }
+
+        self.superblock.last_orphan = 0;
+        self.write_superblock()?;
+
+        Ok(freed)
+    }
+
+    /// Unhook one inode from the orphan list
+    fn remove_orphan(&mut self, inode_num: u32) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        let next = inode.next_orphan();
+
+        if self.superblock.last_orphan == inode_num {
+            self.superblock.last_orphan = next;
+            self.write_superblock()?;
+        } else {
+            let orphans = self.orphans()?;
+            let prev = orphans
+                .iter()
+                .position(|&ino| ino == inode_num)
+                .and_then(|idx| idx.checked_sub(1))
+                .map(|idx| orphans[idx])
+                .ok_or_else(|| anyhow::anyhow!("Inode {} is not on the orphan list", inode_num))?;
+            let mut prev_inode = self.read_inode(prev)?;
+            prev_inode.set_next_orphan(next);
+            self.write_inode(prev, &prev_inode)?;
+        }
+
+        inode.set_next_orphan(0);
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Free the blocks, xattrs, and inode of a file with no links left
+    pub(crate) fn free_unlinked(&mut self, inode_num: u32, inode: &Inode) -> Result<()> {
+        if inode.ei_block != 0 {
+            let ei = self.read_extent_index(inode)?;
+            for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+                self.release_extent(inode_num, extent)?;
+            }
+            self.free_blocks(inode.ei_block, 1)?;
+        }
+        self.free_inode_xattrs(inode_num)?;
+        self.free_inode(inode_num)
+    }
+
+    /// Allocate and initialize a regular file inode and its extent index
+    fn alloc_file_inode(&mut self, nlink: u32, nocomp: bool) -> Result<u32> {
+        // Allocate new inode
+        let new_inode_num = self.alloc_inode()?;
+
+        // Allocate extent index block
+        let ei_block = self.alloc_blocks(1)?;
+
+        // Create the inode
+        let now = self.now();
+
+        let mut new_inode = Inode {
+            i_mode: mode::S_IFREG | 0o644,
+            i_uid: 0,
+            i_gid: 0,
+            i_size: 0,
+            i_ctime: now,
+            i_atime: now,
+            i_mtime: now,
+            i_blocks: 0,
+            i_nlink: nlink,
+            ei_block,
+            xattr_block: 0, // No xattrs initially
+            i_data: [0u8; 28],
+        };
+        // A fresh generation keeps a reused inode number from reusing its key;
+        // reproducible images step past whatever the slot held before
+        let generation = match self.fixed_time {
+            Some(_) => self.read_inode(new_inode_num)?.generation().wrapping_add(1),
+            None => rand::random(),
+        };
+        new_inode.set_generation(generation);
+        if nocomp {
+            new_inode.set_flags(LOLELFFS_INODE_NOCOMP);
+        }
+        self.write_inode(new_inode_num, &new_inode)?;
+
+        // Initialize extent index block
+        let ei = ExtentIndex {
+            nr_files: 0,
+            extents: vec![Extent::default(); LOLELFFS_MAX_EXTENTS],
+        };
+        self.write_extent_index(ei_block, &ei)?;
+
+        Ok(new_inode_num)
+    }
+
+    /// Remove a file (unlink)
+    pub fn unlink(&mut self, parent_inode_num: u32, name: &str) -> Result<()> {
+        // Look up the file
+        let file_inode_num = self
+            .lookup(parent_inode_num, name)?
+            .ok_or_else(|| anyhow::anyhow!("File '{}' not found", name))?;
+
+        let file_inode = self.read_inode(file_inode_num)?;
+
+        if file_inode.is_dir() {
+            bail!("Cannot unlink directory '{}', use rmdir instead", name);
+        }
+
+        // Remove from parent
+        self.remove_dir_entry(parent_inode_num, name)?;
+
+        // Decrement link count
+        let mut file_inode = file_inode;
+        file_inode.i_nlink = file_inode.i_nlink.saturating_sub(1);
+
+        // If link count is 0, free the file's resources
+        if file_inode.i_nlink == 0 {
+            self.free_unlinked(file_inode_num, &file_inode)?;
+        } else {
+            // Just update the link count
+            self.write_inode(file_inode_num, &file_inode)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a symbolic link
+    pub fn symlink(&mut self, parent_inode_num: u32, name: &str, target: &str) -> Result<u32> {
+        if target.len() > 27 {
+            bail!("Symlink target too long (max 27 bytes)");
+        }
+
+        // Allocate new inode
+        let new_inode_num = self.alloc_inode()?;
+
+        // Create the inode
+        let now = self.now();
+
+        let mut i_data = [0u8; 28];
+        i_data[..target.len()].copy_from_slice(target.as_bytes());
+
+        let new_inode = Inode {
+            i_mode: mode::S_IFLNK | 0o777,
+            i_uid: 0,
+            i_gid: 0,
+            i_size: target.len() as u32,
+            i_ctime: now,
+            i_atime: now,
+            i_mtime: now,
+            i_blocks: 0,
+            i_nlink: 1,
+            ei_block: 0,    // Symlinks don't need extent index
+            xattr_block: 0, // No xattrs initially
+            i_data,
+        };
+        self.write_inode(new_inode_num, &new_inode)?;
+
+        // Add entry to parent directory
+        if let Err(e) = self.add_dir_entry(parent_inode_num, name, new_inode_num) {
+            // Rollback on failure
+            self.free_inode(new_inode_num)?;
+            return Err(e);
+        }
+
+        Ok(new_inode_num)
+    }
+
+    /// Create a hard link
+    pub fn link(&mut self, target_inode_num: u32, parent_inode_num: u32, name: &str) -> Result<()> {
+        let mut target_inode = self.read_inode(target_inode_num)?;
+
+        if target_inode.is_dir() {
+            bail!("Cannot create hard link to directory");
+        }
+
+        // Increment link count
+        target_inode.i_nlink += 1;
+        let now = self.now();
+        target_inode.i_ctime = now;
+        self.write_inode(target_inode_num, &target_inode)?;
+
+        // Add entry to parent directory
+        if let Err(e) = self.add_dir_entry(parent_inode_num, name, target_inode_num) {
+            // Rollback link count on failure
+            target_inode.i_nlink -= 1;
+            self.write_inode(target_inode_num, &target_inode)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Truncate a file to specified size
+    pub fn truncate(&mut self, inode_num: u32, size: u32) -> Result<()> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.is_dir() {
+            bail!("Cannot truncate directory");
+        }
+
+        if size == 0 {
+            return self.write_file(inode_num, &[]);
+        }
+
+        if size >= inode.i_size {
+            // Extending file - read current data and pad with zeros
+            let mut data = self.read_file(inode_num)?;
+            data.resize(size as usize, 0);
+            self.write_file(inode_num, &data)
+        } else {
+            // Shrinking file - read and truncate
+            let mut data = self.read_file(inode_num)?;
+            data.truncate(size as usize);
+            self.write_file(inode_num, &data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_image, TempPath};
+
+    #[test]
+    fn test_read_file_limits() {
+        let (_path, mut fs) = temp_image("file.img");
+        fs.superblock.comp_enabled = 0;
+
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let data = vec![0xABu8; 10000];
+        fs.write_file(ino, &data).unwrap();
+
+        // Over the in-memory limit, only the streaming path succeeds
+        fs.max_read_size = 4096;
+        assert!(fs.read_file(ino).is_err());
+        let mut streamed = Vec::new();
+        assert_eq!(fs.read_file_to(ino, &mut streamed).unwrap(), 10000);
+        assert_eq!(streamed, data);
+
+        // A size the extents cannot back is rejected before allocating
+        fs.max_read_size = LOLELFFS_DEFAULT_MAX_READ_SIZE;
+        let mut inode = fs.read_inode(ino).unwrap();
+        inode.i_size = 0xFFFF_FFFF;
+        fs.write_inode(ino, &inode).unwrap();
+        assert!(fs.read_file(ino).is_err());
+        assert!(fs.read_file_to(ino, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_file_range() {
+        let (_path, mut fs) = temp_image("read-range.img");
+
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let data: Vec<u8> = (0..3 * LOLELFFS_BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs.write_file(ino, &data).unwrap();
+
+        // Ranges within a block, across block boundaries and past the end
+        let bs = LOLELFFS_BLOCK_SIZE as u64;
+        for (offset, len) in [(0, 10), (bs - 5, 10), (100, 2 * bs), (3 * bs + 50, 1000)] {
+            let end = (offset + len).min(data.len() as u64) as usize;
+            assert_eq!(
+                fs.read_file_range(ino, offset, len).unwrap(),
+                &data[offset as usize..end],
+                "offset {} len {}",
+                offset,
+                len
+            );
+        }
+        assert!(fs.read_file_range(ino, 1 << 40, 10).unwrap().is_empty());
+        assert_eq!(
+            fs.read_file_range(ino, 0, u64::MAX).unwrap().len(),
+            data.len()
+        );
+
+        // The in-memory limit applies to the range, not the file
+        fs.max_read_size = 4096;
+        assert!(fs.read_file(ino).is_err());
+        assert_eq!(fs.read_file_range(ino, bs, 4096).unwrap().len(), 4096);
+        assert!(fs.read_file_range(ino, 0, 4097).is_err());
+    }
+
+    #[test]
+    fn test_write_file_at() {
+        let (_path, mut fs) = temp_image("write-at.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let mut expected = b"partial writes ".repeat(1000);
+        fs.write_file(ino, &expected).unwrap();
+        let starts = |fs: &mut LolelfFs| -> Vec<u32> {
+            let extents = fs.file_extents(ino).unwrap().unwrap();
+            extents.iter().map(|e| e.ee_start).collect()
+        };
+        let before = starts(&mut fs);
+        let patch = |fs: &mut LolelfFs, expected: &mut Vec<u8>, offset: usize, data: &[u8]| {
+            fs.write_file_at(ino, offset as u64, data).unwrap();
+            if expected.len() < offset + data.len() {
+                expected.resize(offset + data.len(), 0);
+            }
+            expected[offset..offset + data.len()].copy_from_slice(data);
+            assert!(fs.read_file(ino).unwrap() == *expected, "offset {}", offset);
+        };
+
+        // Overwrites across a block boundary stay in the same blocks
+        patch(&mut fs, &mut expected, 4090, b"straddles two blocks");
+        assert_eq!(starts(&mut fs), before);
+
+        // Appending one block at a time grows the last extent
+        for _ in 0..40 {
+            let end = expected.len();
+            patch(&mut fs, &mut expected, end, &[0x5a; 4096]);
+        }
+        assert!(starts(&mut fs).len() <= before.len() + 2);
+
+        // Past the end is zero-filled; incompressible data in a compressed
+        // block falls back to a full rewrite
+        let end = expected.len();
+        patch(&mut fs, &mut expected, end + 10000, b"after a gap");
+        let noise: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        patch(&mut fs, &mut expected, 0, &noise);
+
+        let inode = fs.read_inode(ino).unwrap();
+        let mapped: u32 = fs
+            .file_extents(ino)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|e| e.ee_len)
+            .sum();
+        assert_eq!(inode.i_blocks, mapped);
+        assert_eq!(
+            fs.doctor().unwrap().bitmap_free_blocks,
+            fs.superblock.nr_free_blocks
+        );
+    }
+
+    #[test]
+    fn test_write_with_options_mixes_algorithms() {
+        let path = TempPath::new("opts.img");
+        let mut fs = LolelfFs::create_with_encryption(
+            &path,
+            4 * 1024 * 1024,
+            Some(("secret".to_string(), LOLELFFS_ENC_AES256_XTS, 1000)),
+        )
+        .unwrap();
+
+        // Compressible blocks followed by an incompressible tail block
+        let mut bulk = vec![0x11u8; 3 * LOLELFFS_BLOCK_SIZE as usize];
+        let mut noise = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut noise);
+        bulk.extend_from_slice(&noise);
+        let bulk_ino = fs.create_file(LOLELFFS_ROOT_INO, "bulk").unwrap();
+        fs.write_file(bulk_ino, &bulk).unwrap();
+
+        let config = b"key = value\n".to_vec();
+        let config_ino = fs.create_file(LOLELFFS_ROOT_INO, "config").unwrap();
+        let opts = WriteOptions {
+            enc_algo: Some(LOLELFFS_ENC_CHACHA20_POLY),
+            ..Default::default()
+        };
+        fs.write_file_with_options(config_ino, &config, &opts)
+            .unwrap();
+
+        assert_eq!(fs.read_file(bulk_ino).unwrap(), bulk);
+        assert_eq!(fs.read_file(config_ino).unwrap(), config);
+
+        // Each run of identically-encoded blocks gets its own extent
+        let inode = fs.read_inode(bulk_ino).unwrap();
+        let ei = fs.read_extent_index(&inode).unwrap();
+        let first = &ei.extents[0];
+        assert_eq!(first.ee_comp_algo, LOLELFFS_COMP_LZ4 as u16);
+        assert_eq!(first.ee_enc_algo, LOLELFFS_ENC_AES256_XTS);
+        let last = ei.find_extent(3).unwrap();
+        assert_eq!(last.ee_comp_algo, LOLELFFS_COMP_NONE as u16);
+
+        let inode = fs.read_inode(config_ino).unwrap();
+        let ei = fs.read_extent_index(&inode).unwrap();
+        assert_eq!(ei.extents[0].ee_enc_algo, LOLELFFS_ENC_CHACHA20_POLY);
+        assert_ne!(ei.extents[0].ee_flags & LOLELFFS_EXT_ENCRYPTED, 0);
+    }
+
+    #[test]
+    fn test_per_file_keys() {
+        let path = TempPath::new("filekey.img");
+        let mut fs = LolelfFs::create_with_encryption(
+            &path,
+            4 * 1024 * 1024,
+            Some(("secret".to_string(), LOLELFFS_ENC_AES256_XTS, 1000)),
+        )
+        .unwrap();
+        assert_ne!(
+            fs.superblock.enc_features & LOLELFFS_ENC_FEAT_PER_FILE_KEYS,
+            0
+        );
+
+        let data = vec![0x42u8; 100];
+        let a = fs.create_file(LOLELFFS_ROOT_INO, "a").unwrap();
+        let b = fs.create_file(LOLELFFS_ROOT_INO, "b").unwrap();
+        fs.write_file(a, &data).unwrap();
+        fs.write_file(b, &data).unwrap();
+
+        // Each file gets its own key, distinct from the master key
+        let inode_a = fs.read_inode(a).unwrap();
+        let inode_b = fs.read_inode(b).unwrap();
+        let key_a = fs.file_key(a, &inode_a);
+        let key_b = fs.file_key(b, &inode_b);
+        assert_ne!(key_a, fs.enc_master_key);
+        assert_ne!(key_a, key_b);
+
+        // A new generation for the same inode number yields a new key
+        let mut reused = inode_a.clone();
+        reused.set_generation(inode_a.generation().wrapping_add(1));
+        assert_ne!(fs.file_key(a, &reused), key_a);
+
+        drop(fs);
+        let mut fs = LolelfFs::open(&path).unwrap();
+        fs.unlock("secret").unwrap();
+        assert_eq!(fs.read_file(a).unwrap(), data);
+        assert_eq!(fs.read_file(b).unwrap(), data);
+    }
+
+    #[test]
+    fn test_unnamed_inodes() {
+        let (path, mut fs) = temp_image("unnamed.img");
+        let free_before = (fs.superblock.nr_free_inodes, fs.superblock.nr_free_blocks);
+
+        // Write then link: the name only appears once the contents are complete
+        let tmp = fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+        fs.write_file(tmp, b"complete").unwrap();
+        assert!(fs.list_dir(LOLELFFS_ROOT_INO).unwrap().is_empty());
+        assert_eq!(fs.orphans().unwrap(), [tmp]);
+
+        fs.link_unnamed(tmp, LOLELFFS_ROOT_INO, "config").unwrap();
+        assert_eq!(fs.resolve_path("/config").unwrap(), tmp);
+        assert_eq!(fs.read_inode(tmp).unwrap().i_nlink, 1);
+        assert!(fs.orphans().unwrap().is_empty());
+        assert!(fs.link_unnamed(tmp, LOLELFFS_ROOT_INO, "again").is_err());
+
+        // Discarded and abandoned inodes are both reclaimed
+        let a = fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+        let b = fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+        let c = fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+        fs.write_file(b, &vec![7u8; 10000]).unwrap();
+        assert_eq!(fs.orphans().unwrap(), [c, b, a]);
+        fs.discard_unnamed(b).unwrap();
+        assert_eq!(fs.orphans().unwrap(), [c, a]);
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(fs.orphans().unwrap(), [c, a]);
+        assert_eq!(fs.free_orphans().unwrap(), 2);
+        assert!(fs.orphans().unwrap().is_empty());
+
+        fs.unlink(LOLELFFS_ROOT_INO, "config").unwrap();
+        assert_eq!(
+            (fs.superblock.nr_free_inodes, fs.superblock.nr_free_blocks),
+            free_before
+        );
+    }
+
+    #[test]
+    fn test_ordered_writes_do_not_reuse_blocks() {
+        let (path, mut fs) = temp_image("ordered.img");
+        fs.set_ordered_writes(true).unwrap();
+        drop(fs);
+
+        // The mode is persisted in the superblock
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert!(fs.ordered_writes());
+
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, &[1u8; 20000]).unwrap();
+        let old: Vec<u32> = fs
+            .file_extents(ino)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|e| e.ee_start)
+            .collect();
+        let free = fs.superblock.nr_free_blocks;
+
+        // A rewrite lands in fresh blocks and releases the old ones afterwards
+        fs.write_file(ino, &[2u8; 20000]).unwrap();
+        let extents = fs.file_extents(ino).unwrap().unwrap();
+        assert!(extents.iter().all(|e| !old.contains(&e.ee_start)));
+        assert_eq!(fs.superblock.nr_free_blocks, free);
+        assert!(old.iter().all(|&b| fs.is_block_free(b).unwrap()));
+        assert_eq!(fs.read_file(ino).unwrap(), vec![2u8; 20000]);
+
+        fs.write_file(ino, &[]).unwrap();
+        assert!(fs.read_file(ino).unwrap().is_empty());
+        fs.unlink(LOLELFFS_ROOT_INO, "f").unwrap();
+
+        fs.set_ordered_writes(false).unwrap();
+        assert!(!fs.ordered_writes());
+    }
+}
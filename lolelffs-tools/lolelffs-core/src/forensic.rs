@@ -0,0 +1,303 @@
+//! Forensic inspection of lolelffs images
+//!
+//! Looks at what normal operations ignore: unallocated data blocks, extent
+//! indexes that no live inode references, and known file signatures sitting
+//! in free space. Everything here requires an image opened with
+//! `OpenMode::Forensic`, which guarantees the evidence is never written.
+
+use crate::fs::{LolelfFs, OpenMode};
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::io::Write;
+
+/// File signatures recognized by `carve`: (kind, extension, offset, magic)
+const SIGNATURES: &[(&str, &str, usize, &[u8])] = &[
+    ("PNG image", "png", 0, b"\x89PNG\r\n\x1a\n"),
+    ("JPEG image", "jpg", 0, b"\xff\xd8\xff"),
+    ("GIF image", "gif", 0, b"GIF8"),
+    ("PDF document", "pdf", 0, b"%PDF-"),
+    ("ZIP archive", "zip", 0, b"PK\x03\x04"),
+    ("gzip data", "gz", 0, b"\x1f\x8b\x08"),
+    ("bzip2 data", "bz2", 0, b"BZh"),
+    ("xz data", "xz", 0, b"\xfd7zXZ\x00"),
+    ("zstd data", "zst", 0, b"\x28\xb5\x2f\xfd"),
+    ("7-Zip archive", "7z", 0, b"7z\xbc\xaf\x27\x1c"),
+    ("ELF binary", "elf", 0, b"\x7fELF"),
+    ("SQLite database", "sqlite", 0, b"SQLite format 3\x00"),
+    ("tar archive", "tar", 257, b"ustar"),
+];
+
+/// A file whose extent index survives although no live inode owns it
+#[derive(Debug, Clone)]
+pub struct OrphanFile {
+    /// Freed inode still pointing at the index, if one was found
+    pub inode_num: Option<u32>,
+    /// Extent index block
+    pub ei_block: u32,
+    /// Size from the stale inode, or the extents' full length if unknown
+    pub size: u64,
+    /// Data blocks covered by the extents
+    pub blocks: u32,
+}
+
+/// A known file signature found at the start of an unallocated block
+#[derive(Debug, Clone)]
+pub struct CarveHit {
+    /// Block the signature starts in
+    pub block: u32,
+    /// Human-readable file type
+    pub kind: &'static str,
+    /// Suggested file extension
+    pub extension: &'static str,
+    /// Free blocks from `block` up to the next allocated, zeroed, or signature block
+    pub run_blocks: u32,
+}
+
+/// Refuse to run forensic scans on images not opened for it
+fn ensure_forensic(fs: &LolelfFs) -> Result<()> {
+    if fs.mode() != OpenMode::Forensic {
+        bail!("Forensic operations require an image opened with OpenMode::Forensic");
+    }
+    Ok(())
+}
+
+/// Runs of unallocated data blocks as (start, length)
+pub fn unallocated_runs(fs: &mut LolelfFs) -> Result<Vec<(u32, u32)>> {
+    ensure_forensic(fs)?;
+
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for block in fs.superblock.data_block_start()..fs.superblock.nr_blocks {
+        if !fs.is_block_free(block)? {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == block => *len += 1,
+            _ => runs.push((block, 1)),
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Copy every unallocated data block to a writer, returning the block count
+pub fn dump_unallocated<W: Write>(fs: &mut LolelfFs, writer: &mut W) -> Result<u64> {
+    let mut count = 0u64;
+    for (start, len) in unallocated_runs(fs)? {
+        for block in start..start + len {
+            writer.write_all(&fs.read_block(block)?)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Find extent indexes of deleted files
+///
+/// Freed inodes keep their last contents, so they are checked first and
+/// give exact sizes. Unallocated and unreferenced blocks are then scanned for
+/// anything shaped like an extent index, to catch files whose inode has
+/// since been reused.
+pub fn find_orphan_files(fs: &mut LolelfFs) -> Result<Vec<OrphanFile>> {
+    ensure_forensic(fs)?;
+
+    let referenced = fs.referenced_blocks()?;
+    let mut seen = HashSet::new();
+    let mut orphans = Vec::new();
+
+    for inode_num in 0..fs.superblock.nr_inodes {
+        if !fs.is_inode_free(inode_num)? {
+            continue;
+        }
+        let inode = fs.read_inode(inode_num)?;
+        if !inode.is_file() || inode.ei_block == 0 || referenced.contains(&inode.ei_block) {
+            continue;
+        }
+        if inode.ei_block < fs.superblock.data_block_start()
+            || inode.ei_block >= fs.superblock.nr_blocks
+            || !seen.insert(inode.ei_block)
+        {
+            continue;
+        }
+
+        if let Some(ei) = plausible_extent_index(fs, inode.ei_block)? {
+            orphans.push(OrphanFile {
+                inode_num: Some(inode_num),
+                ei_block: inode.ei_block,
+                size: inode.i_size as u64,
+                blocks: ei.total_blocks(),
+            });
+        }
+    }
+
+    for block in fs.superblock.data_block_start()..fs.superblock.nr_blocks {
+        if referenced.contains(&block) || seen.contains(&block) {
+            continue;
+        }
+        if let Some(ei) = plausible_extent_index(fs, block)? {
+            let blocks = ei.total_blocks();
+            orphans.push(OrphanFile {
+                inode_num: None,
+                ei_block: block,
+                size: blocks as u64 * LOLELFFS_BLOCK_SIZE as u64,
+                blocks,
+            });
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// Write the recoverable contents of an orphaned file
+///
+/// Blocks are decoded like a live file; encrypted blocks need an unlocked
+/// image and a key that did not depend on the lost inode.
+pub fn recover_orphan<W: Write>(
+    fs: &mut LolelfFs,
+    orphan: &OrphanFile,
+    writer: &mut W,
+) -> Result<u64> {
+    ensure_forensic(fs)?;
+
+    let ei = ExtentIndex::from_bytes(&fs.read_block(orphan.ei_block)?);
+    let key = match orphan.inode_num {
+        Some(inode_num) => {
+            let inode = fs.read_inode(inode_num)?;
+            fs.file_key(inode_num, &inode)
+        }
+        None => fs.enc_master_key,
+    };
+
+    let num_blocks = orphan.size.div_ceil(LOLELFFS_BLOCK_SIZE as u64) as u32;
+    for logical_block in 0..num_blocks {
+        let block = fs.read_logical_block(&ei, &key, logical_block)?;
+        let block_start = logical_block as u64 * LOLELFFS_BLOCK_SIZE as u64;
+        let block_end = (block_start + LOLELFFS_BLOCK_SIZE as u64).min(orphan.size);
+        writer.write_all(&block[..(block_end - block_start) as usize])?;
+    }
+
+    Ok(orphan.size)
+}
+
+/// Look for known file signatures at the start of unallocated blocks
+pub fn carve(fs: &mut LolelfFs) -> Result<Vec<CarveHit>> {
+    let runs = unallocated_runs(fs)?;
+    let mut hits: Vec<CarveHit> = Vec::new();
+
+    for (start, len) in runs {
+        // Index of the candidate still growing, if any
+        let mut open: Option<usize> = None;
+
+        for block in start..start + len {
+            let data = fs.read_block(block)?;
+            let signature = SIGNATURES.iter().find(|(_, _, offset, magic)| {
+                data.get(*offset..*offset + magic.len()) == Some(*magic)
+            });
+
+            // A candidate ends at the next signature or never-written block
+            if signature.is_some() || data.iter().all(|&b| b == 0) {
+                if let Some(idx) = open.take() {
+                    hits[idx].run_blocks = block - hits[idx].block;
+                }
+            }
+
+            if let Some((kind, extension, _, _)) = signature {
+                open = Some(hits.len());
+                hits.push(CarveHit {
+                    block,
+                    kind,
+                    extension,
+                    run_blocks: 0,
+                });
+            }
+        }
+
+        if let Some(idx) = open {
+            hits[idx].run_blocks = start + len - hits[idx].block;
+        }
+    }
+
+    Ok(hits)
+}
+
+/// Parse a block as an extent index if its extents are self-consistent
+fn plausible_extent_index(fs: &mut LolelfFs, block: u32) -> Result<Option<ExtentIndex>> {
+    let ei = ExtentIndex::from_bytes(&fs.read_block(block)?);
+    let data_start = fs.superblock.data_block_start();
+    let nr_blocks = fs.superblock.nr_blocks;
+    let max_len = fs
+        .superblock
+        .max_extent_blocks
+        .max(fs.superblock.max_extent_blocks_large);
+
+    let used = ei.count_extents();
+    let trailing_junk = ei.extents[used..]
+        .iter()
+        .any(|e| e.ee_block != 0 || e.ee_start != 0 || e.ee_len != 0);
+    if used == 0 || trailing_junk {
+        return Ok(None);
+    }
+
+    let mut next_logical = 0u32;
+    for extent in &ei.extents[..used] {
+        let valid = extent.ee_block == next_logical
+            && extent.ee_len <= max_len
+            && extent.ee_start >= data_start
+            && extent.ee_start as u64 + extent.ee_len as u64 <= nr_blocks as u64
+            && (extent.ee_comp_algo <= LOLELFFS_COMP_ZSTD as u16
+                || (extent.ee_comp_algo <= u8::MAX as u16
+                    && crate::compat::comp_algo_supported(extent.ee_comp_algo as u8)))
+            && (extent.ee_enc_algo <= LOLELFFS_ENC_CHACHA20_POLY
+                || crate::compat::enc_algo_supported(extent.ee_enc_algo));
+        if !valid {
+            return Ok(None);
+        }
+        next_logical += extent.ee_len;
+    }
+
+    Ok(Some(ei))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_recover_deleted_file() {
+        let (path, mut fs) = temp_image("forensic.img");
+
+        let mut secret = b"%PDF-1.7\n".to_vec();
+        secret.extend((0..6000u32).map(|i| (i * 7 % 251) as u8));
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "secret.pdf").unwrap();
+        let opts = crate::file::WriteOptions {
+            comp_algo: Some(LOLELFFS_COMP_NONE),
+            ..Default::default()
+        };
+        fs.write_file_with_options(ino, &secret, &opts).unwrap();
+        fs.unlink(LOLELFFS_ROOT_INO, "secret.pdf").unwrap();
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert!(find_orphan_files(&mut fs).is_err());
+
+        let mut fs = LolelfFs::open_with_mode(&path, OpenMode::Forensic).unwrap();
+        let orphans = find_orphan_files(&mut fs).unwrap();
+        let orphan = orphans
+            .iter()
+            .find(|o| o.inode_num == Some(ino))
+            .expect("deleted file not found");
+        assert_eq!(orphan.size, secret.len() as u64);
+
+        let mut recovered = Vec::new();
+        recover_orphan(&mut fs, orphan, &mut recovered).unwrap();
+        assert_eq!(recovered, secret);
+
+        let hits = carve(&mut fs).unwrap();
+        assert!(hits.iter().any(|h| h.extension == "pdf"));
+
+        let free = unallocated_runs(&mut fs).unwrap();
+        let free_blocks: u32 = free.iter().map(|(_, len)| len).sum();
+        assert_eq!(free_blocks, fs.superblock.nr_free_blocks);
+    }
+}
@@ -0,0 +1,1639 @@
+//! Filesystem operations for lolelffs
+
+use crate::balance::BalanceStats;
+use crate::cache::{BlockCache, CacheStats, LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS};
+use crate::dir::NormalizationPolicy;
+use crate::error::FsError;
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+/// Main filesystem handle
+pub struct LolelfFs {
+    file: File,
+    /// Byte offset of the filesystem in `file` (non-zero inside ELF binaries
+    /// or larger containers)
+    base: u64,
+    pub(crate) mode: OpenMode,
+    pub superblock: Superblock,
+    pub enc_unlocked: bool,
+    pub enc_master_key: [u8; 32],
+    /// Largest file `read_file` will buffer in memory; use `read_file_to` beyond this
+    pub max_read_size: usize,
+    /// How names are normalized in `lookup`, `add_dir_entry`, and `rename`
+    pub normalization: NormalizationPolicy,
+    /// Which blocks `read_block_checked` verifies; defaults from the open mode
+    pub verify: VerifyPolicy,
+    /// Name globs whose files are never compressed (persisted in block 0)
+    pub(crate) comp_exclude: Vec<glob::Pattern>,
+    /// Scratch file taking every write when opened with `open_cow`
+    pub(crate) cow: Option<crate::cow::CowOverlay>,
+    /// Timestamp given to every change instead of the wall clock, for
+    /// reproducible images (see `make_reproducible`)
+    pub fixed_time: Option<u32>,
+    /// Receives progress events of long operations (see `set_progress`)
+    pub(crate) progress: Option<crate::progress::ProgressCallback>,
+    /// Decompressed blocks, sized with `set_block_cache_size`
+    pub(crate) block_cache: BlockCache,
+    /// This handle set `LOLELFFS_STATE_DIRTY` and clears it when dropped
+    pub(crate) marked_dirty: bool,
+    /// The image was already flagged dirty when this handle opened it
+    pub(crate) opened_unclean: bool,
+    /// What the quick check run by `open_with_options` found
+    pub(crate) auto_fsck: Option<crate::recovery::QuickCheckReport>,
+    /// Bumped by every `write_inode`, so an `FsFile` can tell whether the
+    /// inode it holds is still current
+    pub(crate) inode_generation: u64,
+    /// Bumped by every `write_extent_index`, likewise for extent indexes
+    pub(crate) extent_generation: u64,
+}
+
+/// Byte offset of `state` in the superblock
+const SB_STATE_OFFSET: u64 = 160;
+
+/// How an image is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Read and write
+    ReadWrite,
+    /// Read-only file handle
+    ReadOnly,
+    /// Read-only, and every write path is refused before it reaches the image
+    ///
+    /// Guarantees the image is never modified, not even by housekeeping such
+    /// as superblock updates, which makes it suitable for evidence images.
+    /// This is checked at run time; `NoTouchFs` also rules writes out at
+    /// compile time.
+    ReadNoTouch,
+    /// `ReadNoTouch` that additionally allows the `forensic` module to
+    /// inspect unallocated space and deleted structures
+    Forensic,
+}
+
+/// Settings for `LolelfFs::open_with_options`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub mode: OpenMode,
+    /// Run `quick_check` for at most this long when the image was not
+    /// closed cleanly; `None` only reports it through `opened_unclean`
+    pub auto_fsck: Option<Duration>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            mode: OpenMode::ReadWrite,
+            auto_fsck: None,
+        }
+    }
+}
+
+/// Which blocks are verified against their checksums as they are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// Skip verification entirely, for trusted images on hot paths
+    Never,
+    /// Verify metadata (directory) blocks only
+    Metadata,
+    /// Verify every block that carries a checksum
+    Always,
+}
+
+impl VerifyPolicy {
+    /// Default policy for an open mode: forensic opens verify everything
+    pub fn for_mode(mode: OpenMode) -> Self {
+        match mode {
+            OpenMode::Forensic => VerifyPolicy::Always,
+            _ => VerifyPolicy::Metadata,
+        }
+    }
+}
+
+impl std::str::FromStr for VerifyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(VerifyPolicy::Never),
+            "metadata" => Ok(VerifyPolicy::Metadata),
+            "always" => Ok(VerifyPolicy::Always),
+            _ => Err(format!(
+                "unknown verify policy '{}' (expected never, metadata, or always)",
+                s
+            )),
+        }
+    }
+}
+
+/// What a block read through `read_block_checked` holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// File contents
+    Data,
+    /// Directory entries
+    Directory,
+}
+
+impl BlockKind {
+    /// Name used in error messages
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockKind::Data => "data",
+            BlockKind::Directory => "directory",
+        }
+    }
+}
+
+impl LolelfFs {
+    /// Open an existing lolelffs filesystem image
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_mode(path, OpenMode::ReadWrite)
+    }
+
+    /// Open filesystem in read-only mode
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_mode(path, OpenMode::ReadOnly)
+    }
+
+    /// Open filesystem with an explicit mode
+    pub fn open_with_mode<P: AsRef<Path>>(path: P, mode: OpenMode) -> Result<Self> {
+        Self::open_at(path, 0, mode)
+    }
+
+    /// Open a filesystem that starts `base` bytes into the file
+    pub(crate) fn open_at<P: AsRef<Path>>(path: P, base: u64, mode: OpenMode) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(mode == OpenMode::ReadWrite)
+            .open(path.as_ref())
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+
+        let superblock = Self::read_superblock_at(&mut file, base)?;
+
+        if superblock.magic != LOLELFFS_MAGIC {
+            bail!(
+                "Invalid magic number: expected 0x{:08X}, got 0x{:08X}",
+                LOLELFFS_MAGIC,
+                superblock.magic
+            );
+        }
+
+        if superblock.version != LOLELFFS_VERSION {
+            bail!(
+                "Unsupported filesystem version: expected {}, got {}",
+                LOLELFFS_VERSION,
+                superblock.version
+            );
+        }
+
+        let issues = superblock.compat_issues();
+        if mode == OpenMode::ReadWrite && !issues.is_empty() {
+            bail!(
+                "Image uses features this build does not implement ({}); it can only be opened read-only",
+                issues.join("; ")
+            );
+        }
+
+        let mut fs = LolelfFs {
+            file,
+            base,
+            mode,
+            superblock,
+            enc_unlocked: false,
+            enc_master_key: [0; 32],
+            max_read_size: LOLELFFS_DEFAULT_MAX_READ_SIZE,
+            normalization: NormalizationPolicy::Off,
+            verify: VerifyPolicy::for_mode(mode),
+            comp_exclude: Vec::new(),
+            cow: None,
+            fixed_time: None,
+            progress: None,
+            block_cache: BlockCache::new(LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS),
+            marked_dirty: false,
+            opened_unclean: false,
+            auto_fsck: None,
+            inode_generation: 0,
+            extent_generation: 0,
+        };
+        fs.load_comp_exclude()?;
+        if mode == OpenMode::ReadWrite {
+            fs.mark_dirty()?;
+        } else {
+            fs.opened_unclean = fs.superblock.state & LOLELFFS_STATE_DIRTY != 0;
+        }
+
+        Ok(fs)
+    }
+
+    /// Open an image as `options` asks, checking it first if it was not
+    /// closed cleanly and `auto_fsck` is set
+    ///
+    /// The check's findings are available from `auto_fsck_report`; problems
+    /// it cannot repair do not make the open fail.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &OpenOptions) -> Result<Self> {
+        let mut fs = Self::open_with_mode(path, options.mode)?;
+        if let (true, Some(budget)) = (fs.opened_unclean, options.auto_fsck) {
+            fs.auto_fsck = Some(fs.quick_check(budget)?);
+        }
+        Ok(fs)
+    }
+
+    /// Whether the image was flagged dirty when it was opened: the last
+    /// writer crashed or is still running
+    pub fn opened_unclean(&self) -> bool {
+        self.opened_unclean
+    }
+
+    /// Findings of the check `open_with_options` ran, if it ran one
+    pub fn auto_fsck_report(&self) -> Option<&crate::recovery::QuickCheckReport> {
+        self.auto_fsck.as_ref()
+    }
+
+    /// Set `LOLELFFS_STATE_DIRTY` for as long as this handle is open
+    fn mark_dirty(&mut self) -> Result<()> {
+        self.opened_unclean = self.superblock.state & LOLELFFS_STATE_DIRTY != 0;
+        self.superblock.state |= LOLELFFS_STATE_DIRTY;
+        self.write_state()?;
+        self.marked_dirty = true;
+        Ok(())
+    }
+
+    /// Store the in-memory `state` without rewriting any other superblock
+    /// field, which other code may have changed in memory only
+    fn write_state(&mut self) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(self.base + SB_STATE_OFFSET))?;
+        self.file.write_u32::<LittleEndian>(self.superblock.state)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Mode the image was opened with
+    pub fn mode(&self) -> OpenMode {
+        self.mode
+    }
+
+    /// Refuse writes on images opened with `OpenMode::ReadNoTouch` or `Forensic`
+    pub(crate) fn ensure_writable(&self) -> Result<()> {
+        if matches!(self.mode, OpenMode::ReadNoTouch | OpenMode::Forensic) {
+            bail!("Image is opened read-only (no-touch); refusing to write");
+        }
+        Ok(())
+    }
+
+    /// Read a superblock starting at a byte offset (e.g. inside an ELF section)
+    pub(crate) fn read_superblock_at<R: Read + Seek>(
+        file: &mut R,
+        offset: u64,
+    ) -> Result<Superblock> {
+        file.seek(SeekFrom::Start(offset))?;
+
+        let magic = file.read_u32::<LittleEndian>()?;
+        let nr_blocks = file.read_u32::<LittleEndian>()?;
+        let nr_inodes = file.read_u32::<LittleEndian>()?;
+        let nr_istore_blocks = file.read_u32::<LittleEndian>()?;
+        let nr_ifree_blocks = file.read_u32::<LittleEndian>()?;
+        let nr_bfree_blocks = file.read_u32::<LittleEndian>()?;
+        let nr_free_inodes = file.read_u32::<LittleEndian>()?;
+        let nr_free_blocks = file.read_u32::<LittleEndian>()?;
+        let version = file.read_u32::<LittleEndian>()?;
+        let comp_default_algo = file.read_u32::<LittleEndian>()?;
+        let comp_enabled = file.read_u32::<LittleEndian>()?;
+        let comp_min_block_size = file.read_u32::<LittleEndian>()?;
+        let comp_features = file.read_u32::<LittleEndian>()?;
+        let max_extent_blocks = file.read_u32::<LittleEndian>()?;
+        let max_extent_blocks_large = file.read_u32::<LittleEndian>()?;
+        let enc_enabled = file.read_u32::<LittleEndian>()?;
+        let enc_default_algo = file.read_u32::<LittleEndian>()?;
+        let enc_kdf_algo = file.read_u32::<LittleEndian>()?;
+        let enc_kdf_iterations = file.read_u32::<LittleEndian>()?;
+        let enc_kdf_memory = file.read_u32::<LittleEndian>()?;
+        let enc_kdf_parallelism = file.read_u32::<LittleEndian>()?;
+        let mut enc_salt = [0u8; 32];
+        file.read_exact(&mut enc_salt)?;
+        let mut enc_master_key = [0u8; 32];
+        file.read_exact(&mut enc_master_key)?;
+        let enc_features = file.read_u32::<LittleEndian>()?;
+        let hash_algos = file.read_u32::<LittleEndian>()?;
+        let last_orphan = file.read_u32::<LittleEndian>()?;
+        let state = file.read_u32::<LittleEndian>()?;
+        let mut uuid = [0u8; 16];
+        file.read_exact(&mut uuid)?;
+        let mut label = [0u8; LOLELFFS_LABEL_MAX];
+        file.read_exact(&mut label)?;
+
+        Ok(Superblock {
+            magic,
+            nr_blocks,
+            nr_inodes,
+            nr_istore_blocks,
+            nr_ifree_blocks,
+            nr_bfree_blocks,
+            nr_free_inodes,
+            nr_free_blocks,
+            version,
+            comp_default_algo,
+            comp_enabled,
+            comp_min_block_size,
+            comp_features,
+            max_extent_blocks,
+            max_extent_blocks_large,
+            enc_enabled,
+            enc_default_algo,
+            enc_kdf_algo,
+            enc_kdf_iterations,
+            enc_kdf_memory,
+            enc_kdf_parallelism,
+            enc_salt,
+            enc_master_key,
+            enc_features,
+            hash_algos,
+            last_orphan,
+            state,
+            uuid,
+            label,
+        })
+    }
+
+    /// Write superblock to disk
+    pub fn write_superblock(&mut self) -> Result<()> {
+        self.ensure_writable()?;
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(self.superblock.magic)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_inodes)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_istore_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_ifree_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_bfree_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_free_inodes)?;
+        buf.write_u32::<LittleEndian>(self.superblock.nr_free_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.version)?;
+        buf.write_u32::<LittleEndian>(self.superblock.comp_default_algo)?;
+        buf.write_u32::<LittleEndian>(self.superblock.comp_enabled)?;
+        buf.write_u32::<LittleEndian>(self.superblock.comp_min_block_size)?;
+        buf.write_u32::<LittleEndian>(self.superblock.comp_features)?;
+        buf.write_u32::<LittleEndian>(self.superblock.max_extent_blocks)?;
+        buf.write_u32::<LittleEndian>(self.superblock.max_extent_blocks_large)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_enabled)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_default_algo)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_kdf_algo)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_kdf_iterations)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_kdf_memory)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_kdf_parallelism)?;
+        buf.write_all(&self.superblock.enc_salt)?;
+        buf.write_all(&self.superblock.enc_master_key)?;
+        buf.write_u32::<LittleEndian>(self.superblock.enc_features)?;
+        buf.write_u32::<LittleEndian>(self.superblock.hash_algos)?;
+        buf.write_u32::<LittleEndian>(self.superblock.last_orphan)?;
+        buf.write_u32::<LittleEndian>(self.superblock.state)?;
+        buf.write_all(&self.superblock.uuid)?;
+        buf.write_all(&self.superblock.label)?;
+
+        if self.cow.is_some() {
+            let mut block = self.read_block(0)?;
+            block[..buf.len()].copy_from_slice(&buf);
+            return self.write_raw_block(0, &block);
+        }
+        self.file.seek(SeekFrom::Start(self.base))?;
+        self.file.write_all(&buf)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Whether dependent updates are separated by write barriers
+    pub fn ordered_writes(&self) -> bool {
+        self.superblock.comp_features & LOLELFFS_FEATURE_ORDERED_WRITES != 0
+    }
+
+    /// Turn ordered writes on or off for every later open of the image
+    ///
+    /// In ordered mode, data reaches the disk before the metadata that points
+    /// at it, and pointers are dropped before the blocks they named are
+    /// freed, with an fsync at each of those points. A crash can then lose
+    /// the most recent updates or leak blocks for fsck to reclaim, but it
+    /// cannot leave a pointer to unwritten or reused blocks.
+    pub fn set_ordered_writes(&mut self, on: bool) -> Result<()> {
+        if on {
+            self.superblock.comp_features |= LOLELFFS_FEATURE_ORDERED_WRITES;
+        } else {
+            self.superblock.comp_features &= !LOLELFFS_FEATURE_ORDERED_WRITES;
+        }
+        self.write_superblock()
+    }
+
+    /// Make everything written so far durable before what depends on it
+    ///
+    /// A no-op unless ordered writes are enabled.
+    pub(crate) fn barrier(&mut self) -> Result<()> {
+        span!("barrier");
+        if self.ordered_writes() {
+            match self.cow.as_mut() {
+                Some(cow) => cow.sync()?,
+                None => self.file.sync_data()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Make everything written so far durable, e.g. before the image is
+    /// handed to another process
+    pub fn flush(&mut self) -> Result<()> {
+        match self.cow.as_mut() {
+            Some(cow) => cow.sync(),
+            None => Ok(self.file.sync_all()?),
+        }
+    }
+
+    /// Current time for timestamps: `fixed_time` if set, else the wall clock
+    pub fn now(&self) -> u32 {
+        self.fixed_time.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32
+        })
+    }
+
+    /// Pin the clock and identity of a freshly created image
+    ///
+    /// Sets `fixed_time`, clears the UUID and restamps the root directory,
+    /// so images created with the same size and then given the same
+    /// operations in the same order are byte-identical. Inode generations
+    /// are derived from the previous occupant of the inode instead of drawn
+    /// at random. Encrypted images stay unique, their salt and master key
+    /// being random.
+    pub fn make_reproducible(&mut self, time: u32) -> Result<()> {
+        self.fixed_time = Some(time);
+        self.superblock.uuid = [0; 16];
+        self.write_superblock()?;
+
+        let mut root = self.read_inode(LOLELFFS_ROOT_INO)?;
+        root.i_ctime = time;
+        root.i_atime = time;
+        root.i_mtime = time;
+        self.write_inode(LOLELFFS_ROOT_INO, &root)
+    }
+
+    /// Change the volume label
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        self.superblock.label = encode_label(label)?;
+        self.write_superblock()
+    }
+
+    /// Fail unless `block_num` lies in `start..nr_blocks`
+    pub(crate) fn check_block_range(&self, block_num: u32, start: u32) -> Result<()> {
+        let end = self.superblock.nr_blocks;
+        if block_num < start || block_num >= end {
+            return Err(FsError::BlockOutOfRange {
+                block: block_num,
+                start,
+                end,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Read any block of the filesystem, metadata included
+    pub fn read_block(&mut self, block_num: u32) -> Result<Vec<u8>> {
+        span!("read_block", block = block_num);
+        self.check_block_range(block_num, 0)?;
+        if let Some(cow) = self.cow.as_mut() {
+            if cow.contains(block_num) {
+                let data = cow.read(block_num)?;
+                return self.open_image_block(block_num, data);
+            }
+        }
+        let offset = self.base + block_num as u64 * LOLELFFS_BLOCK_SIZE as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        self.file.read_exact(&mut data)?;
+        self.open_image_block(block_num, data)
+    }
+
+    /// Read a block, verifying its checksum if the policy covers its kind
+    ///
+    /// Both kinds live in the data area, so a block number below it is
+    /// rejected rather than returning metadata as file contents. Directory
+    /// blocks are verified under `Metadata` and `Always`. Data blocks carry
+    /// no checksum of their own yet (AEAD-encrypted blocks are authenticated
+    /// when they are opened), so they pass through unchecked.
+    pub fn read_block_checked(&mut self, block_num: u32, kind: BlockKind) -> Result<Vec<u8>> {
+        self.check_block_range(block_num, self.superblock.data_block_start())?;
+        let block = self.read_block(block_num)?;
+
+        let checked = match kind {
+            BlockKind::Data => self.verify == VerifyPolicy::Always,
+            BlockKind::Directory => self.verify != VerifyPolicy::Never,
+        };
+        if checked && !self.block_csum_ok(block_num, kind, &block)? {
+            return Err(FsError::ChecksumMismatch {
+                what: kind.name(),
+                block: block_num,
+            }
+            .into());
+        }
+
+        Ok(block)
+    }
+
+    /// Whether a block matches its stored checksum (true if it has none)
+    fn block_csum_ok(&self, block_num: u32, kind: BlockKind, block: &[u8]) -> Result<bool> {
+        match kind {
+            BlockKind::Data => Ok(true),
+            BlockKind::Directory => self.dir_block_csum_ok(block_num, block),
+        }
+    }
+
+    /// Resize the backing file
+    pub(crate) fn set_image_len(&mut self, len: u64) -> Result<()> {
+        self.ensure_writable()?;
+        if self.base != 0 {
+            bail!("Cannot resize a filesystem embedded at an offset in a larger file");
+        }
+        if self.cow.is_some() {
+            bail!("Cannot resize an image opened with a scratch overlay");
+        }
+        self.file.set_len(len)?;
+        Ok(())
+    }
+
+    /// Write a block in the data area
+    ///
+    /// Everything reached through an extent or block pointer is written here,
+    /// so a corrupt pointer into the superblock, inode table or bitmaps fails
+    /// instead of destroying them. Those are written with `write_meta_block`.
+    pub fn write_block(&mut self, block_num: u32, data: &[u8]) -> Result<()> {
+        self.check_block_range(block_num, self.superblock.data_block_start())?;
+        self.write_raw_block(block_num, data)
+    }
+
+    /// Write any block of the filesystem, metadata included
+    pub(crate) fn write_meta_block(&mut self, block_num: u32, data: &[u8]) -> Result<()> {
+        self.check_block_range(block_num, 0)?;
+        self.write_raw_block(block_num, data)
+    }
+
+    fn write_raw_block(&mut self, block_num: u32, data: &[u8]) -> Result<()> {
+        span!("write_block", block = block_num);
+        if data.len() != LOLELFFS_BLOCK_SIZE as usize {
+            bail!(
+                "Block data must be {} bytes, got {}",
+                LOLELFFS_BLOCK_SIZE,
+                data.len()
+            );
+        }
+        self.ensure_writable()?;
+        let data = self.seal_image_block(block_num, data)?;
+        if let Some(cow) = self.cow.as_mut() {
+            return cow.write(block_num, &data);
+        }
+
+        let offset = self.base + block_num as u64 * LOLELFFS_BLOCK_SIZE as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&data)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Read an inode from the filesystem
+    pub fn read_inode(&mut self, inode_num: u32) -> Result<Inode> {
+        if inode_num >= self.superblock.nr_inodes {
+            bail!(
+                "Invalid inode number {} (max {})",
+                inode_num,
+                self.superblock.nr_inodes - 1
+            );
+        }
+
+        let block_num =
+            self.superblock.inode_store_start() + (inode_num / LOLELFFS_INODES_PER_BLOCK);
+        let offset_in_block = (inode_num % LOLELFFS_INODES_PER_BLOCK) * Inode::SIZE as u32;
+
+        let block = self.read_block(block_num)?;
+        let inode_data = &block[offset_in_block as usize..offset_in_block as usize + Inode::SIZE];
+
+        Self::parse_inode(inode_data)
+    }
+
+    /// Parse inode from raw bytes
+    fn parse_inode(data: &[u8]) -> Result<Inode> {
+        use std::io::Cursor;
+        let mut cursor = Cursor::new(data);
+
+        let i_mode = cursor.read_u32::<LittleEndian>()?;
+        let i_uid = cursor.read_u32::<LittleEndian>()?;
+        let i_gid = cursor.read_u32::<LittleEndian>()?;
+        let i_size = cursor.read_u32::<LittleEndian>()?;
+        let i_ctime = cursor.read_u32::<LittleEndian>()?;
+        let i_atime = cursor.read_u32::<LittleEndian>()?;
+        let i_mtime = cursor.read_u32::<LittleEndian>()?;
+        let i_blocks = cursor.read_u32::<LittleEndian>()?;
+        let i_nlink = cursor.read_u32::<LittleEndian>()?;
+        let ei_block = cursor.read_u32::<LittleEndian>()?;
+        let xattr_block = cursor.read_u32::<LittleEndian>()?;
+
+        let mut i_data = [0u8; 28];
+        cursor.read_exact(&mut i_data)?;
+
+        Ok(Inode {
+            i_mode,
+            i_uid,
+            i_gid,
+            i_size,
+            i_ctime,
+            i_atime,
+            i_mtime,
+            i_blocks,
+            i_nlink,
+            ei_block,
+            xattr_block,
+            i_data,
+        })
+    }
+
+    /// Write an inode to the filesystem
+    pub fn write_inode(&mut self, inode_num: u32, inode: &Inode) -> Result<()> {
+        if inode_num >= self.superblock.nr_inodes {
+            bail!(
+                "Invalid inode number {} (max {})",
+                inode_num,
+                self.superblock.nr_inodes - 1
+            );
+        }
+
+        let block_num =
+            self.superblock.inode_store_start() + (inode_num / LOLELFFS_INODES_PER_BLOCK);
+        let offset_in_block = (inode_num % LOLELFFS_INODES_PER_BLOCK) * Inode::SIZE as u32;
+
+        // Read the block, modify the inode, write back
+        let mut block = self.read_block(block_num)?;
+        let inode_data = Self::serialize_inode(inode);
+        block[offset_in_block as usize..offset_in_block as usize + Inode::SIZE]
+            .copy_from_slice(&inode_data);
+        self.write_meta_block(block_num, &block)?;
+        self.inode_generation += 1;
+
+        Ok(())
+    }
+
+    /// Serialize inode to bytes
+    fn serialize_inode(inode: &Inode) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Inode::SIZE);
+        data.write_u32::<LittleEndian>(inode.i_mode).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_uid).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_gid).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_size).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_ctime).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_atime).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_mtime).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_blocks).unwrap();
+        data.write_u32::<LittleEndian>(inode.i_nlink).unwrap();
+        data.write_u32::<LittleEndian>(inode.ei_block).unwrap();
+        data.write_u32::<LittleEndian>(inode.xattr_block).unwrap();
+        data.extend_from_slice(&inode.i_data);
+        data
+    }
+
+    /// Read extent index block for an inode
+    pub fn read_extent_index(&mut self, inode: &Inode) -> Result<ExtentIndex> {
+        if inode.ei_block == 0 {
+            bail!("Inode has no extent index block");
+        }
+        self.check_block_range(inode.ei_block, self.superblock.data_block_start())?;
+        let block = self.read_block(inode.ei_block)?;
+        Ok(ExtentIndex::from_bytes(&block))
+    }
+
+    /// Write extent index block
+    pub fn write_extent_index(&mut self, block_num: u32, ei: &ExtentIndex) -> Result<()> {
+        let data = ei.to_bytes();
+        self.write_block(block_num, &data)?;
+        self.extent_generation += 1;
+        Ok(())
+    }
+
+    /// Get the physical block number for a logical block in a file
+    pub fn get_physical_block(&mut self, inode: &Inode, logical_block: u32) -> Result<Option<u32>> {
+        let ei = self.read_extent_index(inode)?;
+
+        if let Some(extent) = ei.find_extent(logical_block) {
+            Ok(extent.get_physical(logical_block))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Create a new filesystem on an image file
+    /// Create a new filesystem (without encryption)
+    pub fn create<P: AsRef<Path>>(path: P, size: u64) -> Result<Self> {
+        Self::create_with_encryption(path, size, None)
+    }
+
+    /// Create a new filesystem with optional encryption
+    /// enc_config: Option<(password: String, algo: u8, iterations: u32)>
+    pub fn create_with_encryption<P: AsRef<Path>>(
+        path: P,
+        size: u64,
+        enc_config: Option<(String, u8, u32)>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+
+        // Refuse a cipher this process lacks before touching the file
+        let cipher_features = match &enc_config {
+            Some((_, algo, _)) => crate::encrypt::cipher(*algo)
+                .ok_or_else(|| anyhow::anyhow!("Unsupported encryption algorithm: {}", algo))?
+                .features(),
+            None => 0,
+        };
+
+        // Create the file with the specified size
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size)?;
+
+        let nr_blocks = (size / LOLELFFS_BLOCK_SIZE as u64) as u32;
+        if nr_blocks < LOLELFFS_MIN_BLOCKS {
+            bail!(
+                "Filesystem too small: need at least {} blocks, got {}",
+                LOLELFFS_MIN_BLOCKS,
+                nr_blocks
+            );
+        }
+
+        // Calculate filesystem layout
+        let Layout {
+            nr_inodes,
+            nr_istore_blocks,
+            nr_ifree_blocks,
+            nr_bfree_blocks,
+            ..
+        } = Layout::for_blocks(nr_blocks);
+
+        // Handle encryption configuration
+        let (
+            enc_enabled,
+            enc_algo,
+            enc_kdf_algo,
+            enc_kdf_iterations,
+            enc_salt,
+            enc_master_key,
+            master_key_plain,
+        ) = if let Some((password, algo, iterations)) = enc_config {
+            // Generate random salt and master key
+            let salt = crate::encrypt::generate_salt();
+            let master_key = crate::encrypt::generate_master_key();
+
+            // Derive user key from password
+            let user_key =
+                crate::encrypt::derive_key_pbkdf2(password.as_bytes(), &salt, iterations);
+
+            // Encrypt master key
+            let encrypted_master_key = crate::encrypt::encrypt_master_key(&master_key, &user_key)?;
+
+            (
+                1,
+                algo as u32,
+                LOLELFFS_KDF_PBKDF2 as u32,
+                iterations,
+                salt,
+                encrypted_master_key,
+                master_key,
+            )
+        } else {
+            (
+                0,
+                LOLELFFS_ENC_NONE as u32,
+                LOLELFFS_KDF_ARGON2ID as u32,
+                3,
+                [0; 32],
+                [0; 32],
+                [0; 32],
+            )
+        };
+
+        // Create superblock
+        let superblock = Superblock {
+            magic: LOLELFFS_MAGIC,
+            nr_blocks,
+            nr_inodes,
+            nr_istore_blocks,
+            nr_ifree_blocks,
+            nr_bfree_blocks,
+            nr_free_inodes: nr_inodes - 1, // Root inode is used
+            nr_free_blocks: 0,             // Will be calculated
+            version: LOLELFFS_VERSION,
+            comp_default_algo: LOLELFFS_COMP_LZ4 as u32,
+            comp_enabled: 1, // Compression enabled by default
+            comp_min_block_size: 128,
+            comp_features: LOLELFFS_FEATURE_LARGE_EXTENTS,
+            max_extent_blocks: LOLELFFS_MAX_BLOCKS_PER_EXTENT,
+            max_extent_blocks_large: LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE,
+            enc_enabled,
+            enc_default_algo: enc_algo,
+            enc_kdf_algo,
+            enc_kdf_iterations,
+            enc_kdf_memory: 65536,  // Not used for PBKDF2
+            enc_kdf_parallelism: 4, // Not used for PBKDF2
+            enc_salt,
+            enc_master_key,
+            // The kernel module refuses images with per-file keys, so
+            // encrypted images made here are for the tools and FUSE only
+            enc_features: if enc_enabled != 0 {
+                LOLELFFS_ENC_FEAT_PER_FILE_KEYS | cipher_features
+            } else {
+                0
+            },
+            hash_algos: 0,
+            last_orphan: 0,
+            state: 0,
+            uuid: generate_uuid(),
+            label: [0; LOLELFFS_LABEL_MAX],
+        };
+
+        let mut fs = LolelfFs {
+            file,
+            base: 0,
+            mode: OpenMode::ReadWrite,
+            superblock,
+            enc_unlocked: enc_enabled != 0, // If encrypted, start unlocked
+            enc_master_key: master_key_plain,
+            max_read_size: LOLELFFS_DEFAULT_MAX_READ_SIZE,
+            normalization: NormalizationPolicy::Off,
+            verify: VerifyPolicy::for_mode(OpenMode::ReadWrite),
+            comp_exclude: Vec::new(),
+            cow: None,
+            fixed_time: None,
+            progress: None,
+            block_cache: BlockCache::new(LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS),
+            marked_dirty: false,
+            opened_unclean: false,
+            auto_fsck: None,
+            inode_generation: 0,
+            extent_generation: 0,
+        };
+
+        // Initialize the filesystem
+        fs.init_filesystem()?;
+        fs.mark_dirty()?;
+
+        Ok(fs)
+    }
+
+    /// Initialize filesystem structures
+    fn init_filesystem(&mut self) -> Result<()> {
+        // Write superblock
+        self.write_superblock()?;
+
+        // Initialize bitmaps
+        let ifree_start = self.superblock.ifree_bitmap_start();
+        let bfree_start = self.superblock.bfree_bitmap_start();
+        let data_start = self.superblock.data_block_start();
+
+        // Initialize inode free bitmap (mark inode 0 as used)
+        let mut ifree_block = vec![0xFFu8; LOLELFFS_BLOCK_SIZE as usize];
+        ifree_block[0] = 0xFE; // First inode (root) is used
+        for i in 0..self.superblock.nr_ifree_blocks {
+            if i == 0 {
+                self.write_meta_block(ifree_start + i, &ifree_block)?;
+            } else {
+                self.write_meta_block(
+                    ifree_start + i,
+                    &vec![0xFFu8; LOLELFFS_BLOCK_SIZE as usize],
+                )?;
+            }
+        }
+
+        // Initialize block free bitmap (mark metadata blocks as used)
+        let mut free_blocks = 0u32;
+        for i in 0..self.superblock.nr_bfree_blocks {
+            let mut block = vec![0xFFu8; LOLELFFS_BLOCK_SIZE as usize];
+            let block_start = i * LOLELFFS_BITS_PER_BLOCK;
+
+            for bit in 0..LOLELFFS_BITS_PER_BLOCK {
+                let block_num = block_start + bit;
+                if block_num >= self.superblock.nr_blocks {
+                    // Mark non-existent blocks as used
+                    let byte_idx = (bit / 8) as usize;
+                    let bit_idx = bit % 8;
+                    block[byte_idx] &= !(1 << bit_idx);
+                } else if block_num < data_start + 1 {
+                    // Mark metadata blocks and root dir extent block as used
+                    let byte_idx = (bit / 8) as usize;
+                    let bit_idx = bit % 8;
+                    block[byte_idx] &= !(1 << bit_idx);
+                } else {
+                    free_blocks += 1;
+                }
+            }
+
+            self.write_meta_block(bfree_start + i, &block)?;
+        }
+
+        // Update free blocks count
+        self.superblock.nr_free_blocks = free_blocks;
+        self.write_superblock()?;
+
+        // Create root inode
+        let now = self.now();
+
+        let root_inode = Inode {
+            i_mode: mode::S_IFDIR | 0o755,
+            i_uid: 0,
+            i_gid: 0,
+            i_size: LOLELFFS_BLOCK_SIZE, // Extent index block
+            i_ctime: now,
+            i_atime: now,
+            i_mtime: now,
+            i_blocks: 1,
+            i_nlink: 2, // . and itself
+            ei_block: data_start,
+            xattr_block: 0, // No xattrs on root initially
+            i_data: [0u8; 28],
+        };
+        self.write_inode(LOLELFFS_ROOT_INO, &root_inode)?;
+
+        // Initialize root directory extent index block
+        let root_ei = ExtentIndex {
+            nr_files: 0,
+            extents: vec![Extent::default(); LOLELFFS_MAX_EXTENTS],
+        };
+        self.write_extent_index(data_start, &root_ei)?;
+
+        Ok(())
+    }
+
+    /// Get an extended attribute value
+    pub fn get_xattr(&mut self, inode_num: u32, name: &str) -> Result<Vec<u8>> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.xattr_block == 0 {
+            bail!("No extended attributes set on inode {}", inode_num);
+        }
+
+        let (namespace, base_name) = crate::xattr::parse_xattr_name(name)?;
+        let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+        let data = crate::xattr::read_xattr_data(self, &index)?;
+        let entries = crate::xattr::parse_xattr_entries(&data)?;
+
+        for entry in entries {
+            if entry.name_index == namespace && entry.name == base_name {
+                return Ok(entry.value);
+            }
+        }
+
+        bail!("Extended attribute '{}' not found", name);
+    }
+
+    /// Set an extended attribute
+    ///
+    /// Fails without changing the inode if the name, the value or the whole
+    /// set would exceed the on-disk limits.
+    pub fn set_xattr(&mut self, inode_num: u32, name: &str, value: &[u8]) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        let (namespace, base_name) = crate::xattr::parse_xattr_name(name)?;
+        crate::xattr::check_xattr_limits(&base_name, value)?;
+
+        // Read existing entries if any
+        let mut entries = if inode.xattr_block != 0 {
+            let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+            let data = crate::xattr::read_xattr_data(self, &index)?;
+            crate::xattr::parse_xattr_entries(&data)?
+        } else {
+            Vec::new()
+        };
+
+        // Update or add the entry
+        let mut found = false;
+        for entry in &mut entries {
+            if entry.name_index == namespace && entry.name == base_name {
+                entry.value = value.to_vec();
+                entry.value_len = value.len() as u16;
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            entries.push(XattrEntry {
+                name_len: base_name.len() as u8,
+                name_index: namespace,
+                value_len: value.len() as u16,
+                value_offset: 0,
+                name: base_name,
+                value: value.to_vec(),
+            });
+        }
+
+        let now = self.now();
+        inode.i_ctime = now;
+        crate::xattr::store_xattr_entries(self, inode_num, &mut inode, &entries)
+    }
+
+    /// List all extended attribute names
+    pub fn list_xattrs(&mut self, inode_num: u32) -> Result<Vec<String>> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.xattr_block == 0 {
+            return Ok(Vec::new());
+        }
+
+        let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+        let data = crate::xattr::read_xattr_data(self, &index)?;
+        let entries = crate::xattr::parse_xattr_entries(&data)?;
+
+        let names = entries
+            .iter()
+            .map(|e| {
+                let prefix = match e.name_index {
+                    XattrNamespace::User => "user.",
+                    XattrNamespace::Trusted => "trusted.",
+                    XattrNamespace::System => "system.",
+                    XattrNamespace::Security => "security.",
+                };
+                format!("{}{}", prefix, e.name)
+            })
+            .collect();
+
+        Ok(names)
+    }
+
+    /// Remove an extended attribute
+    pub fn remove_xattr(&mut self, inode_num: u32, name: &str) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+
+        if inode.xattr_block == 0 {
+            bail!("No extended attributes set on inode {}", inode_num);
+        }
+
+        let (namespace, base_name) = crate::xattr::parse_xattr_name(name)?;
+        let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+        let data = crate::xattr::read_xattr_data(self, &index)?;
+        let mut entries = crate::xattr::parse_xattr_entries(&data)?;
+
+        // Find and remove the entry
+        let initial_len = entries.len();
+        entries.retain(|e| !(e.name_index == namespace && e.name == base_name));
+
+        if entries.len() == initial_len {
+            bail!("Extended attribute '{}' not found", name);
+        }
+
+        let now = self.now();
+        inode.i_ctime = now;
+
+        // An emptied set also releases the index block
+        crate::xattr::store_xattr_entries(self, inode_num, &mut inode, &entries)
+    }
+
+    /// Free xattr blocks for an inode (called during inode deletion)
+    pub fn free_inode_xattrs(&mut self, inode_num: u32) -> Result<()> {
+        let inode = self.read_inode(inode_num)?;
+
+        if inode.xattr_block == 0 {
+            return Ok(());
+        }
+
+        // Read xattr index
+        let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+
+        // Free all xattr data blocks
+        for extent in &index.extents {
+            if extent.is_empty() {
+                break;
+            }
+            self.free_blocks(extent.ee_start, extent.ee_len)?;
+        }
+
+        // Free xattr index block
+        self.free_blocks(inode.xattr_block, 1)?;
+
+        // Clear the pointer so the freed inode cannot be mistaken for a leak
+        let mut inode = inode;
+        inode.xattr_block = 0;
+        self.write_inode(inode_num, &inode)?;
+
+        Ok(())
+    }
+
+    /// Change an inode's permission bits, keeping its file type
+    pub fn set_mode(&mut self, inode_num: u32, perm: u32) -> Result<()> {
+        if perm & !0o7777 != 0 {
+            bail!("Invalid permission bits {:o}", perm);
+        }
+
+        let mut inode = self.read_inode(inode_num)?;
+        inode.i_mode = (inode.i_mode & mode::S_IFMT) | perm;
+        inode.i_ctime = self.now();
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Change an inode's owner and/or group, leaving `None` fields untouched
+    pub fn set_owner(&mut self, inode_num: u32, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let mut inode = self.read_inode(inode_num)?;
+        if let Some(uid) = uid {
+            inode.i_uid = uid;
+        }
+        if let Some(gid) = gid {
+            inode.i_gid = gid;
+        }
+        inode.i_ctime = self.now();
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Get filesystem statistics
+    pub fn statfs(&self) -> FsStats {
+        FsStats {
+            total_blocks: self.superblock.nr_blocks,
+            free_blocks: self.superblock.nr_free_blocks,
+            total_inodes: self.superblock.nr_inodes,
+            free_inodes: self.superblock.nr_free_inodes,
+            block_size: LOLELFFS_BLOCK_SIZE,
+        }
+    }
+
+    /// Counters of this handle's caches since it was opened
+    pub fn stats(&self) -> IoStats {
+        IoStats {
+            block_cache: self.block_cache.stats().clone(),
+        }
+    }
+
+    /// Keep at most `blocks` decompressed blocks in memory (0 disables the
+    /// cache)
+    pub fn set_block_cache_size(&mut self, blocks: usize) {
+        self.block_cache.set_capacity(blocks);
+    }
+
+    /// Check usage against alarm thresholds, returning every one exceeded
+    pub fn check_thresholds(&mut self, limits: &Thresholds) -> Result<Vec<ThresholdAlarm>> {
+        let stats = self.statfs();
+        let mut alarms = Vec::new();
+
+        let block_use = stats.block_use_percent();
+        if block_use >= limits.max_block_use {
+            alarms.push(ThresholdAlarm::BlockUse(block_use));
+        }
+        let inode_use = stats.inode_use_percent();
+        if inode_use >= limits.max_inode_use {
+            alarms.push(ThresholdAlarm::InodeUse(inode_use));
+        }
+
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let inode = self.read_inode(inode_num)?;
+            if inode.is_symlink() || inode.ei_block == 0 {
+                continue;
+            }
+            let extents = self.read_extent_index(&inode)?.count_extents();
+            if extents > limits.max_extents {
+                alarms.push(ThresholdAlarm::Extents { inode_num, extents });
+            }
+        }
+
+        Ok(alarms)
+    }
+
+    /// Unlock encrypted filesystem with password
+    pub fn unlock(&mut self, password: &str) -> Result<()> {
+        // Check if encryption is enabled
+        if self.superblock.enc_enabled == 0 {
+            bail!("Filesystem is not encrypted");
+        }
+
+        // Check if already unlocked
+        if self.enc_unlocked {
+            return Ok(());
+        }
+
+        // Any other KDF would "unlock" with a wrong key
+        let kdf = self.superblock.enc_kdf_algo;
+        if kdf != LOLELFFS_KDF_PBKDF2 as u32 {
+            let name = if kdf == LOLELFFS_KDF_ARGON2ID as u32 {
+                " (Argon2id)"
+            } else {
+                ""
+            };
+            return Err(FsError::Unsupported {
+                inode: LOLELFFS_ROOT_INO,
+                what: format!(
+                    "a master key wrapped with key derivation function {}{}",
+                    kdf, name
+                ),
+            }
+            .into());
+        }
+
+        // Derive user key from password using the same parameters as creation
+        let user_key = crate::encrypt::derive_key_pbkdf2(
+            password.as_bytes(),
+            &self.superblock.enc_salt,
+            self.superblock.enc_kdf_iterations,
+        );
+
+        // Decrypt master key
+        let master_key =
+            crate::encrypt::decrypt_master_key(&self.superblock.enc_master_key, &user_key)?;
+
+        // Store the decrypted master key
+        self.enc_master_key = master_key;
+        self.enc_unlocked = true;
+
+        // The wrapped key has no check value, but an opaque image can only
+        // be read with the right one
+        if self.is_opaque() && !self.root_inode_plausible()? {
+            self.enc_master_key = [0; 32];
+            self.enc_unlocked = false;
+            bail!("Wrong password");
+        }
+
+        Ok(())
+    }
+    /// Move everything stored in `range` elsewhere
+    ///
+    /// Afterwards every block in the range is allocated but unreferenced, ready
+    /// for the caller to repurpose. Extents are moved whole, so this can also
+    /// fail for lack of a long enough free run; blocks already moved stay
+    /// moved, and the rest of the range is released again.
+    pub(crate) fn evacuate(&mut self, range: Range<u32>) -> Result<BalanceStats> {
+        if range.start < self.superblock.data_block_start() || range.end > self.superblock.nr_blocks
+        {
+            bail!("Cannot evacuate blocks {}..{}", range.start, range.end);
+        }
+
+        // Reserve the range first so relocated data cannot land back in it
+        let mut reserved = 0;
+        for block_num in range.clone() {
+            if self.is_block_free(block_num)? {
+                reserved += 1;
+            }
+        }
+        let in_use = range.len() as u32 - reserved;
+        if in_use > self.superblock.nr_free_blocks - reserved {
+            bail!(
+                "Not enough free space to move {} blocks out of {}..{}",
+                in_use,
+                range.start,
+                range.end
+            );
+        }
+        self.mark_blocks(range.start, range.len() as u32, false)?;
+        self.superblock.nr_free_blocks -= reserved;
+        self.write_superblock()?;
+
+        let mut stats = BalanceStats::default();
+        if let Err(err) = self.relocate_from(&range, &mut stats) {
+            // Give back whatever in the range nothing references any more
+            let referenced = self.referenced_blocks()?;
+            for block_num in range {
+                if !referenced.contains(&block_num) {
+                    self.free_blocks(block_num, 1)?;
+                }
+            }
+            return Err(err);
+        }
+
+        Ok(stats)
+    }
+
+    /// Point every extent, extent index, and xattr index overlapping `range`
+    /// at a copy outside it
+    fn relocate_from(&mut self, range: &Range<u32>, stats: &mut BalanceStats) -> Result<()> {
+        let overlaps = |start: u32, len: u32| start < range.end && start + len > range.start;
+
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let mut inode = self.read_inode(inode_num)?;
+            let mut inode_changed = false;
+
+            if inode.ei_block != 0 && !inode.is_symlink() {
+                let ei = self.read_extent_index(&inode)?;
+                for (idx, extent) in ei.extents.iter().take_while(|e| !e.is_empty()).enumerate() {
+                    if overlaps(extent.ee_start, extent.ee_len) {
+                        let new_start = self.alloc_blocks(extent.ee_len)?;
+                        self.move_extent(inode_num, idx, new_start, range)?;
+                        stats.extents_moved += 1;
+                        stats.blocks_moved += extent.ee_len;
+                    }
+                }
+                if overlaps(inode.ei_block, 1) {
+                    let ei = self.read_extent_index(&inode)?;
+                    inode.ei_block = self.move_blocks(inode.ei_block, 1, range, false)?;
+                    self.write_extent_index(inode.ei_block, &ei)?;
+                    inode_changed = true;
+                    stats.blocks_moved += 1;
+                }
+            }
+
+            if inode.xattr_block != 0 {
+                let mut index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+                let mut index_changed = false;
+                for extent in index.extents.iter_mut().take_while(|e| !e.is_empty()) {
+                    if overlaps(extent.ee_start, extent.ee_len) {
+                        extent.ee_start =
+                            self.move_blocks(extent.ee_start, extent.ee_len, range, false)?;
+                        index_changed = true;
+                        stats.extents_moved += 1;
+                        stats.blocks_moved += extent.ee_len;
+                    }
+                }
+                if overlaps(inode.xattr_block, 1) {
+                    inode.xattr_block = self.move_blocks(inode.xattr_block, 1, range, false)?;
+                    inode_changed = true;
+                    index_changed = true;
+                    stats.blocks_moved += 1;
+                }
+                if index_changed {
+                    crate::xattr::write_xattr_index(self, inode.xattr_block, &index)?;
+                }
+            }
+
+            if inode_changed {
+                self.write_inode(inode_num, &inode)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy a block run to a fresh allocation and return its new start. Old
+    /// blocks inside `keep` stay allocated; the rest are freed.
+    fn move_blocks(&mut self, start: u32, len: u32, keep: &Range<u32>, dir: bool) -> Result<u32> {
+        let new_start = self.alloc_blocks(len)?;
+        self.copy_blocks(start, new_start, len, dir)?;
+        self.free_blocks_outside(start..start + len, keep)?;
+        Ok(new_start)
+    }
+
+    /// Copy `len` blocks from `src` to `dst` verbatim, or for directory blocks
+    /// re-stamped for their new location
+    pub(crate) fn copy_blocks(&mut self, src: u32, dst: u32, len: u32, dir: bool) -> Result<()> {
+        for i in 0..len {
+            if dir {
+                // Directory checksums cover the block number, so re-stamp them
+                let mut block = self.read_dir_block(src + i)?;
+                self.write_dir_block(dst + i, &mut block)?;
+            } else {
+                let block = self.read_block(src + i)?;
+                self.write_block(dst + i, &block)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Free the blocks of `run` that lie outside `keep`
+    pub(crate) fn free_blocks_outside(&mut self, run: Range<u32>, keep: &Range<u32>) -> Result<()> {
+        for block_num in run {
+            if !keep.contains(&block_num) {
+                self.free_blocks(block_num, 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LolelfFs {
+    /// Clear the dirty flag: the image is consistent once the handle goes
+    fn drop(&mut self) {
+        if self.marked_dirty {
+            self.superblock.state &= !LOLELFFS_STATE_DIRTY;
+            let _ = self.write_state();
+        }
+    }
+}
+
+/// Random (version 4) UUID for a new volume
+fn generate_uuid() -> [u8; 16] {
+    let mut uuid: [u8; 16] = rand::random();
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    uuid
+}
+
+/// Counters of one open handle, returned by `LolelfFs::stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Decompressed-block cache
+    pub block_cache: CacheStats,
+}
+
+/// Filesystem statistics
+#[derive(Debug, Clone)]
+pub struct FsStats {
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    pub total_inodes: u32,
+    pub free_inodes: u32,
+    pub block_size: u32,
+}
+
+impl FsStats {
+    /// Get total size in bytes
+    pub fn total_size(&self) -> u64 {
+        self.total_blocks as u64 * self.block_size as u64
+    }
+
+    /// Get free size in bytes
+    pub fn free_size(&self) -> u64 {
+        self.free_blocks as u64 * self.block_size as u64
+    }
+
+    /// Get used size in bytes
+    pub fn used_size(&self) -> u64 {
+        self.total_size() - self.free_size()
+    }
+
+    /// Percentage of blocks in use, rounded down
+    pub fn block_use_percent(&self) -> u32 {
+        use_percent(self.total_blocks - self.free_blocks, self.total_blocks)
+    }
+
+    /// Percentage of inodes in use, rounded down
+    pub fn inode_use_percent(&self) -> u32 {
+        use_percent(self.total_inodes - self.free_inodes, self.total_inodes)
+    }
+}
+
+fn use_percent(used: u32, total: u32) -> u32 {
+    if total == 0 {
+        0
+    } else {
+        (used as u64 * 100 / total as u64) as u32
+    }
+}
+
+/// Usage limits checked by `LolelfFs::check_thresholds`
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    /// Alarm when this percentage of blocks is in use
+    pub max_block_use: u32,
+    /// Alarm when this percentage of inodes is in use
+    pub max_inode_use: u32,
+    /// Alarm for any file or directory with more extents than this
+    pub max_extents: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_block_use: 90,
+            max_inode_use: 90,
+            max_extents: 150,
+        }
+    }
+}
+
+/// A threshold exceeded by the filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThresholdAlarm {
+    /// Block usage percentage
+    BlockUse(u32),
+    /// Inode usage percentage
+    InodeUse(u32),
+    /// An inode fragmented into too many extents
+    Extents { inode_num: u32, extents: usize },
+}
+
+impl std::fmt::Display for ThresholdAlarm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThresholdAlarm::BlockUse(pct) => write!(f, "{}% of blocks used", pct),
+            ThresholdAlarm::InodeUse(pct) => write!(f, "{}% of inodes used", pct),
+            ThresholdAlarm::Extents { inode_num, extents } => {
+                write!(f, "inode {} has {} extents", inode_num, extents)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_read_no_touch_never_writes() {
+        let (path, mut fs) = temp_image("notouch.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "evidence").unwrap();
+        fs.write_file(ino, b"exhibit A").unwrap();
+        drop(fs);
+
+        let before = std::fs::read(&path).unwrap();
+
+        let mut fs = LolelfFs::open_with_mode(&path, OpenMode::ReadNoTouch).unwrap();
+        assert_eq!(fs.mode(), OpenMode::ReadNoTouch);
+        assert_eq!(fs.read_file(ino).unwrap(), b"exhibit A");
+        assert_eq!(fs.list_dir(LOLELFFS_ROOT_INO).unwrap().len(), 1);
+
+        // Every write path is refused up front
+        assert!(fs.write_superblock().is_err());
+        assert!(fs.write_file(ino, b"tampered").is_err());
+        assert!(fs.create_file(LOLELFFS_ROOT_INO, "new").is_err());
+        drop(fs);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_check_thresholds() {
+        let (_path, mut fs) = temp_image("thresholds.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "data").unwrap();
+        fs.write_file(ino, &vec![0x42u8; 64 * 1024]).unwrap();
+
+        assert!(fs
+            .check_thresholds(&Thresholds::default())
+            .unwrap()
+            .is_empty());
+
+        let strict = Thresholds {
+            max_block_use: 0,
+            max_inode_use: 100,
+            max_extents: 0,
+        };
+        let alarms = fs.check_thresholds(&strict).unwrap();
+        let block_use = fs.statfs().block_use_percent();
+        assert!(alarms.contains(&ThresholdAlarm::BlockUse(block_use)));
+        assert!(!alarms
+            .iter()
+            .any(|a| matches!(a, ThresholdAlarm::InodeUse(_))));
+        assert!(alarms
+            .iter()
+            .any(|a| matches!(a, ThresholdAlarm::Extents { inode_num, .. } if *inode_num == ino)));
+    }
+
+    #[test]
+    fn test_block_range_guard() {
+        let (_path, mut fs) = temp_image("range.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, &[0x11; 8192]).unwrap();
+
+        let zero = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        let nr_blocks = fs.superblock.nr_blocks;
+        let istore = fs.superblock.inode_store_start();
+        let out_of_range = |e: anyhow::Error| {
+            matches!(
+                e.downcast_ref::<FsError>(),
+                Some(FsError::BlockOutOfRange { .. })
+            )
+        };
+
+        // Nothing past the end, and no data access below the data area
+        assert!(out_of_range(fs.read_block(nr_blocks).unwrap_err()));
+        assert!(out_of_range(fs.write_block(nr_blocks, &zero).unwrap_err()));
+        assert!(out_of_range(fs.write_block(istore, &zero).unwrap_err()));
+        assert!(out_of_range(
+            fs.read_block_checked(istore, BlockKind::Data).unwrap_err()
+        ));
+        assert!(out_of_range(fs.free_blocks(istore, 1).unwrap_err()));
+
+        // A corrupt extent pointing into the inode table is neither read as
+        // file data nor overwritten or freed by a rewrite
+        let inode = fs.read_inode(ino).unwrap();
+        let mut ei = fs.read_extent_index(&inode).unwrap();
+        ei.extents[0].ee_start = istore;
+        fs.write_extent_index(inode.ei_block, &ei).unwrap();
+        let inode_table = fs.read_block(istore).unwrap();
+        let free_before = fs.superblock.nr_free_blocks;
+
+        assert!(fs.read_file(ino).is_err());
+        assert!(fs.write_file(ino, &[0x22; 8192]).is_err());
+        assert_eq!(fs.read_block(istore).unwrap(), inode_table);
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+        assert!(!fs.is_block_free(istore).unwrap());
+    }
+
+    #[test]
+    fn test_label_and_uuid() {
+        let (path, mut fs) = temp_image("label.img");
+        let uuid = fs.superblock.uuid;
+        assert_ne!(uuid, [0; 16]);
+        assert_eq!(uuid[6] >> 4, 4);
+        assert_eq!(fs.superblock.uuid_string().len(), 36);
+        assert_eq!(fs.superblock.label(), "");
+
+        fs.set_label("backups").unwrap();
+        assert!(fs.set_label(&"x".repeat(LOLELFFS_LABEL_MAX + 1)).is_err());
+        fs.set_label(&"y".repeat(LOLELFFS_LABEL_MAX)).unwrap();
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(fs.superblock.uuid, uuid);
+        assert_eq!(fs.superblock.label(), "y".repeat(LOLELFFS_LABEL_MAX));
+        fs.set_label("").unwrap();
+        assert_eq!(fs.superblock.label(), "");
+    }
+}
@@ -0,0 +1,543 @@
+//! Full-filesystem consistency check
+//!
+//! Walks every directory reachable from the root, then cross-checks what the
+//! walk found against the inode and block bitmaps and the superblock's free
+//! counts. Nothing is modified; repairs are separate, opt-in steps, such as
+//! re-linking lost inodes into `/lost+found`.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How serious a problem found by `LolelfFs::fsck_full` is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Data can be lost or corrupted if the image is used as is
+    Error,
+    /// Wasted space or stale accounting; safe to use
+    Warning,
+}
+
+/// One problem found by `LolelfFs::fsck_full`
+#[derive(Debug, Clone)]
+pub struct FsckProblem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Outcome of `LolelfFs::fsck_full`
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Directories walked
+    pub dirs: u32,
+    /// Inodes in use according to the inode bitmap
+    pub inodes: u32,
+    /// Data blocks referenced by inodes in use
+    pub blocks: u32,
+    /// Problems found, in the order they were found
+    pub problems: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    fn error(&mut self, message: String) {
+        self.problems.push(FsckProblem {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.problems.push(FsckProblem {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    /// Number of problems with the given severity
+    pub fn count(&self, severity: Severity) -> usize {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == severity)
+            .count()
+    }
+}
+
+/// Name of the directory lost inodes are re-linked into
+pub const LOST_FOUND: &str = "lost+found";
+
+/// Join a directory path and an entry name for messages
+fn child_path(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl LolelfFs {
+    /// Check the whole filesystem
+    ///
+    /// Every directory is walked and every inode an entry names must be in
+    /// range, allocated and of a known type. Every allocated inode must be
+    /// named somewhere or sit on the orphan list, with a link count matching
+    /// its names. Every block an inode references must lie in the data area,
+    /// be marked in use and belong to no other inode, and the free counts in
+    /// the superblock must match the bitmaps.
+    pub fn fsck_full(&mut self) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let nr_inodes = self.superblock.nr_inodes;
+        let nr_blocks = self.superblock.nr_blocks;
+        let data_start = self.superblock.data_block_start();
+
+        // Names per inode and subdirectories per directory
+        let mut names: HashMap<u32, u32> = HashMap::new();
+        let mut subdirs: HashMap<u32, u32> = HashMap::new();
+        let mut visited = HashSet::from([LOLELFFS_ROOT_INO]);
+        let mut queue = VecDeque::from([(LOLELFFS_ROOT_INO, "/".to_string())]);
+
+        while let Some((dir, path)) = queue.pop_front() {
+            report.dirs += 1;
+            self.report_progress("directories", report.dirs as u64, 0, &path);
+            let blocks = match self.dir_blocks(dir) {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    report.error(format!("Cannot read directory '{}': {:#}", path, e));
+                    continue;
+                }
+            };
+
+            for block_num in blocks {
+                let block = match self.read_dir_block(block_num) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        report.error(format!(
+                            "Cannot read block {} of directory '{}': {:#}",
+                            block_num, path, e
+                        ));
+                        continue;
+                    }
+                };
+
+                for slot in block
+                    .chunks_exact(FileEntry::SIZE)
+                    .take(LOLELFFS_FILES_PER_BLOCK)
+                {
+                    let Some(entry) = FileEntry::from_bytes(slot) else {
+                        continue;
+                    };
+                    let entry_path = child_path(&path, &entry.filename);
+
+                    if entry.inode >= nr_inodes {
+                        report.error(format!(
+                            "'{}' names inode {} beyond the last inode {}",
+                            entry_path,
+                            entry.inode,
+                            nr_inodes - 1
+                        ));
+                        continue;
+                    }
+                    if self.is_inode_free(entry.inode)? {
+                        report.error(format!(
+                            "'{}' names inode {}, which is marked free",
+                            entry_path, entry.inode
+                        ));
+                    }
+                    let inode = match self.read_inode(entry.inode) {
+                        Ok(inode) => inode,
+                        Err(e) => {
+                            report.error(format!(
+                                "Cannot read inode {} of '{}': {:#}",
+                                entry.inode, entry_path, e
+                            ));
+                            continue;
+                        }
+                    };
+                    if !inode.is_dir() && !inode.is_file() && !inode.is_symlink() {
+                        report.error(format!(
+                            "Inode {} of '{}' has unknown mode {:o}",
+                            entry.inode, entry_path, inode.i_mode
+                        ));
+                        continue;
+                    }
+
+                    *names.entry(entry.inode).or_default() += 1;
+                    if inode.is_dir() {
+                        *subdirs.entry(dir).or_default() += 1;
+                        if visited.insert(entry.inode) {
+                            queue.push_back((entry.inode, entry_path));
+                        } else {
+                            report.error(format!(
+                                "Directory inode {} is linked more than once (again at '{}')",
+                                entry.inode, entry_path
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.report_progress("directories", report.dirs as u64, report.dirs as u64, "");
+
+        let orphans: HashSet<u32> = match self.orphans() {
+            Ok(orphans) => orphans.into_iter().collect(),
+            Err(e) => {
+                report.error(format!("{:#}", e));
+                HashSet::new()
+            }
+        };
+
+        // Every allocated inode: reachability, link counts, block ownership
+        // Owner of every referenced block, and whether through a shared extent
+        let mut owners: HashMap<u32, (u32, bool)> = HashMap::new();
+        let mut free_inodes = 0;
+        for inode_num in 0..nr_inodes {
+            self.report_progress("inodes", inode_num as u64 + 1, nr_inodes as u64, "");
+            if self.is_inode_free(inode_num)? {
+                free_inodes += 1;
+                continue;
+            }
+            report.inodes += 1;
+
+            let inode = match self.read_inode(inode_num) {
+                Ok(inode) => inode,
+                Err(e) => {
+                    report.error(format!("Cannot read inode {}: {:#}", inode_num, e));
+                    continue;
+                }
+            };
+
+            let named = names.get(&inode_num).copied().unwrap_or(0);
+            if inode_num == LOLELFFS_ROOT_INO || orphans.contains(&inode_num) {
+                // The root has no name, and unnamed inodes are expected
+            } else if named == 0 {
+                report.warning(format!(
+                    "Inode {} is in use but not linked from any directory (run with --lost-found)",
+                    inode_num
+                ));
+            } else if inode.is_dir() {
+                let expected = 2 + subdirs.get(&inode_num).copied().unwrap_or(0);
+                if inode.i_nlink != expected {
+                    report.warning(format!(
+                        "Directory inode {} has link count {}, expected {}",
+                        inode_num, inode.i_nlink, expected
+                    ));
+                }
+            } else if inode.i_nlink != named {
+                report.warning(format!(
+                    "Inode {} has link count {} but {} names",
+                    inode_num, inode.i_nlink, named
+                ));
+            }
+
+            let shared = self.shared_blocks(&inode);
+            for block_num in self.inode_blocks(&inode) {
+                if block_num < data_start || block_num >= nr_blocks {
+                    report.error(format!(
+                        "Inode {} references block {} outside the data area {}..{}",
+                        inode_num, block_num, data_start, nr_blocks
+                    ));
+                    continue;
+                }
+                let is_shared = shared.contains(&block_num);
+                if let Some((owner, was_shared)) = owners.insert(block_num, (inode_num, is_shared))
+                {
+                    if owner != inode_num && !(was_shared && is_shared) {
+                        report.error(format!(
+                            "Block {} is used by both inode {} and inode {}",
+                            block_num, owner, inode_num
+                        ));
+                    }
+                }
+            }
+        }
+        report.blocks = owners.len() as u32;
+
+        // The block bitmap against what the inodes reference
+        let mut free_blocks = 0;
+        let mut leaked = 0;
+        for block_num in data_start..nr_blocks {
+            self.report_progress(
+                "blocks",
+                (block_num - data_start + 1) as u64,
+                (nr_blocks - data_start) as u64,
+                "",
+            );
+            let free = self.is_block_free(block_num)?;
+            match (free, owners.get(&block_num)) {
+                (true, Some((owner, _))) => report.error(format!(
+                    "Block {} is marked free but used by inode {}",
+                    block_num, owner
+                )),
+                (false, None) => leaked += 1,
+                _ => {}
+            }
+            if free {
+                free_blocks += 1;
+            }
+        }
+        for block_num in 0..data_start {
+            if self.is_block_free(block_num)? {
+                report.error(format!(
+                    "Metadata block {} is marked free in the block bitmap",
+                    block_num
+                ));
+            }
+        }
+        if leaked > 0 {
+            report.warning(format!(
+                "{} blocks are marked in use but not referenced by any inode",
+                leaked
+            ));
+        }
+
+        if self.superblock.nr_free_inodes != free_inodes {
+            report.warning(format!(
+                "Superblock counts {} free inodes, the bitmap has {}",
+                self.superblock.nr_free_inodes, free_inodes
+            ));
+        }
+        if self.superblock.nr_free_blocks != free_blocks {
+            report.warning(format!(
+                "Superblock counts {} free blocks, the bitmap has {}",
+                self.superblock.nr_free_blocks, free_blocks
+            ));
+        }
+
+        Ok(report)
+    }
+}
+
+impl LolelfFs {
+    /// Entries of a directory as (name, inode) pairs, skipping unparseable
+    /// slots and unreadable blocks
+    fn raw_dir_entries(&mut self, dir_inode_num: u32) -> Vec<(String, u32)> {
+        let mut entries = Vec::new();
+        let Ok(blocks) = self.dir_blocks(dir_inode_num) else {
+            return entries;
+        };
+        for block_num in blocks {
+            let Ok(block) = self.read_dir_block(block_num) else {
+                continue;
+            };
+            for slot in block
+                .chunks_exact(FileEntry::SIZE)
+                .take(LOLELFFS_FILES_PER_BLOCK)
+            {
+                if let Some(entry) = FileEntry::from_bytes(slot) {
+                    entries.push((entry.filename, entry.inode));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Allocated inodes that no directory reachable from the root names
+    ///
+    /// The root and unnamed inodes on the orphan list are not lost.
+    pub fn lost_inodes(&mut self) -> Result<Vec<u32>> {
+        let nr_inodes = self.superblock.nr_inodes;
+        let mut linked = HashSet::from([LOLELFFS_ROOT_INO]);
+        let mut queue = VecDeque::from([LOLELFFS_ROOT_INO]);
+        while let Some(dir) = queue.pop_front() {
+            for (_, inode_num) in self.raw_dir_entries(dir) {
+                if inode_num >= nr_inodes || !linked.insert(inode_num) {
+                    continue;
+                }
+                if self.read_inode(inode_num).is_ok_and(|inode| inode.is_dir()) {
+                    queue.push_back(inode_num);
+                }
+            }
+        }
+        let orphans: HashSet<u32> = self.orphans().unwrap_or_default().into_iter().collect();
+
+        let mut lost = Vec::new();
+        for inode_num in 0..nr_inodes {
+            if !linked.contains(&inode_num)
+                && !orphans.contains(&inode_num)
+                && !self.is_inode_free(inode_num)?
+            {
+                lost.push(inode_num);
+            }
+        }
+        Ok(lost)
+    }
+
+    /// Re-link lost inodes into `/lost+found`, creating it if needed
+    ///
+    /// Each inode is named `#<inode number>`, as e2fsck does. A lost
+    /// directory brings its contents back with it, so only inodes that no
+    /// other lost directory names are linked. Returns the inodes linked.
+    pub fn recover_lost_inodes(&mut self) -> Result<Vec<u32>> {
+        self.ensure_writable()?;
+        let mut recovered = Vec::new();
+        let mut lost_found = None;
+
+        loop {
+            let lost = self.lost_inodes()?;
+            if lost.is_empty() {
+                break;
+            }
+
+            // Inodes inside a lost directory come back with it
+            let lost_set: HashSet<u32> = lost.iter().copied().collect();
+            let mut contained = HashSet::new();
+            for &inode_num in &lost {
+                if self.read_inode(inode_num)?.is_dir() {
+                    for (_, child) in self.raw_dir_entries(inode_num) {
+                        if child != inode_num && lost_set.contains(&child) {
+                            contained.insert(child);
+                        }
+                    }
+                }
+            }
+            let mut tops: Vec<u32> = lost
+                .iter()
+                .copied()
+                .filter(|ino| !contained.contains(ino))
+                .collect();
+            if tops.is_empty() {
+                // Lost directories that only name each other; break the cycle
+                tops.push(lost[0]);
+            }
+
+            let dir = match lost_found {
+                Some(dir) => dir,
+                None => {
+                    let dir = match self.lookup(LOLELFFS_ROOT_INO, LOST_FOUND)? {
+                        Some(dir) if self.read_inode(dir)?.is_dir() => dir,
+                        Some(_) => bail!("/{} exists but is not a directory", LOST_FOUND),
+                        None => self.mkdir(LOLELFFS_ROOT_INO, LOST_FOUND)?,
+                    };
+                    lost_found = Some(dir);
+                    dir
+                }
+            };
+
+            for inode_num in tops {
+                self.add_dir_entry(dir, &format!("#{}", inode_num), inode_num)?;
+                let mut inode = self.read_inode(inode_num)?;
+                if inode.is_dir() {
+                    let mut parent = self.read_inode(dir)?;
+                    parent.i_nlink += 1;
+                    self.write_inode(dir, &parent)?;
+                } else {
+                    inode.i_nlink = 1;
+                    self.write_inode(inode_num, &inode)?;
+                }
+                recovered.push(inode_num);
+            }
+        }
+
+        Ok(recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    fn messages(report: &FsckReport, severity: Severity) -> Vec<String> {
+        report
+            .problems
+            .iter()
+            .filter(|p| p.severity == severity)
+            .map(|p| p.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_fsck_full() {
+        let (_path, mut fs) = temp_image("fsck.img");
+
+        // A busy but consistent tree
+        let a = fs.mkdir(LOLELFFS_ROOT_INO, "a").unwrap();
+        let b = fs.mkdir(a, "b").unwrap();
+        let f = fs.create_file(b, "f").unwrap();
+        fs.write_file(f, &[7u8; 30000]).unwrap();
+        fs.link(f, LOLELFFS_ROOT_INO, "hard").unwrap();
+        fs.symlink(a, "s", "b/f").unwrap();
+        fs.set_xattr(f, "user.k", b"v").unwrap();
+        fs.rename(a, "b", LOLELFFS_ROOT_INO, "b").unwrap();
+        let g = fs.create_file(LOLELFFS_ROOT_INO, "g").unwrap();
+        fs.write_file(g, &[1u8; 9000]).unwrap();
+        fs.unlink(LOLELFFS_ROOT_INO, "g").unwrap();
+        fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+
+        let report = fs.fsck_full().unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.dirs, 3);
+
+        // A block marked used that nothing references
+        let leaked = fs.alloc_blocks(1).unwrap();
+        // A block marked free while a file still uses it (after the
+        // allocations below, which would otherwise pick it up again)
+        let inode = fs.read_inode(f).unwrap();
+        let ei = fs.read_extent_index(&inode).unwrap();
+        let used = ei.extents[0].ee_start;
+        // An entry naming a free inode, and a wrong link count
+        let h = fs.create_file(LOLELFFS_ROOT_INO, "h").unwrap();
+        fs.free_inode(h).unwrap();
+        fs.mark_blocks(used, 1, true).unwrap();
+        let mut inode = fs.read_inode(f).unwrap();
+        inode.i_nlink = 5;
+        fs.write_inode(f, &inode).unwrap();
+
+        let report = fs.fsck_full().unwrap();
+        let errors = messages(&report, Severity::Error);
+        let warnings = messages(&report, Severity::Warning);
+        assert!(errors
+            .iter()
+            .any(|m| m.contains(&format!("Block {} is marked free", used))));
+        assert!(errors.iter().any(|m| m.contains("'/h' names inode")));
+        assert!(warnings
+            .iter()
+            .any(|m| m.contains("link count 5 but 2 names")));
+        // The stray block, and the extent index of the inode freed under "h"
+        assert!(warnings
+            .iter()
+            .any(|m| m.contains("2 blocks are marked in use")));
+        assert!(warnings.iter().any(|m| m.contains("free blocks")));
+        assert!(!fs.is_block_free(leaked).unwrap());
+    }
+
+    #[test]
+    fn test_recover_lost_inodes() {
+        let (_path, mut fs) = temp_image("lostfound.img");
+
+        let f = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(f, b"lost file").unwrap();
+        let d = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let child = fs.create_file(d, "child").unwrap();
+        fs.create_unnamed(LOLELFFS_ROOT_INO).unwrap();
+        assert!(fs.lost_inodes().unwrap().is_empty());
+
+        // Drop the names but keep the inodes, as a crash between the two
+        // halves of an unlink would
+        fs.remove_dir_entry(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.remove_dir_entry(LOLELFFS_ROOT_INO, "d").unwrap();
+        let mut root = fs.read_inode(LOLELFFS_ROOT_INO).unwrap();
+        root.i_nlink -= 1;
+        fs.write_inode(LOLELFFS_ROOT_INO, &root).unwrap();
+        assert_eq!(fs.lost_inodes().unwrap(), vec![f, d, child]);
+
+        // The directory comes back with its contents, the orphan stays put
+        assert_eq!(fs.recover_lost_inodes().unwrap(), vec![f, d]);
+        assert!(fs.lost_inodes().unwrap().is_empty());
+        assert_eq!(
+            fs.resolve_path(&format!("/{}/#{}", LOST_FOUND, f)).unwrap(),
+            f
+        );
+        assert_eq!(
+            fs.resolve_path(&format!("/{}/#{}/child", LOST_FOUND, d))
+                .unwrap(),
+            child
+        );
+        assert_eq!(fs.read_file(f).unwrap(), b"lost file");
+
+        let report = fs.fsck_full().unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(fs.recover_lost_inodes().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,339 @@
+//! Open file handles
+//!
+//! The `read_file_*`/`write_file_*` calls take an inode number and look up
+//! the inode and its extent index every time. An `FsFile` is what a caller
+//! keeps between calls instead, the way a kernel keeps a struct file: it
+//! knows its access mode, has a position for `read`, `write` and `seek`,
+//! and holds on to the inode and extent index for as long as nothing has
+//! written either. It does not borrow the filesystem, so a FUSE driver can
+//! keep one per open file descriptor behind its lock and pass the
+//! filesystem into each call.
+
+use crate::error::FsError;
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::io::SeekFrom;
+
+/// How `LolelfFs::open_file` opens a file, following `std::fs::OpenOptions`
+///
+/// The default is read-only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Write at the end of the file whatever the position; implies `write`
+    pub append: bool,
+    /// Create the file if it does not exist; needs `write` or `append`
+    pub create: bool,
+    /// Cut the file to zero length when it is opened; needs `write`
+    pub truncate: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            read: true,
+            write: false,
+            append: false,
+            create: false,
+            truncate: false,
+        }
+    }
+}
+
+impl FileOptions {
+    fn writable(&self) -> bool {
+        self.write || self.append
+    }
+}
+
+/// An open regular file, from `LolelfFs::open_file` or `open_inode`
+///
+/// Every method takes the filesystem the file was opened on. Changes made
+/// through other handles or `LolelfFs` calls are seen by the next call.
+pub struct FsFile {
+    inode_num: u32,
+    options: FileOptions,
+    pos: u64,
+    /// The inode, as of `LolelfFs::inode_generation` `.0`
+    inode: (u64, Inode),
+    /// Its extent index, as of `LolelfFs::extent_generation` `.0`
+    extents: Option<(u64, ExtentIndex)>,
+}
+
+impl LolelfFs {
+    /// Open the regular file at `path`, following symlinks
+    pub fn open_file(&mut self, path: &str, options: &FileOptions) -> Result<FsFile> {
+        if (options.create && !options.writable()) || (options.truncate && !options.write) {
+            bail!("Creating or truncating '{}' needs write access", path);
+        }
+        if !options.create {
+            let inode_num = self.resolve_path_follow(path, true)?;
+            return self.open_inode(inode_num, options);
+        }
+
+        let (parent, name) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+        let parent_inode = self.resolve_path_follow(parent, true)?;
+        let inode_num = match self.lookup(parent_inode, name)? {
+            Some(_) => self.resolve_path_follow(path, true)?,
+            None => self.create_file(parent_inode, name)?,
+        };
+        self.open_inode(inode_num, options)
+    }
+
+    /// Open regular file `inode_num`; `options.create` is ignored
+    pub fn open_inode(&mut self, inode_num: u32, options: &FileOptions) -> Result<FsFile> {
+        if !options.read && !options.writable() {
+            bail!("Inode {} opened for neither reading nor writing", inode_num);
+        }
+        if options.writable() {
+            self.ensure_writable()?;
+        }
+        let inode = self.read_inode(inode_num)?;
+        if inode.is_dir() {
+            bail!("Inode {} is a directory", inode_num);
+        }
+        if !inode.is_file() {
+            bail!("Inode {} is not a regular file", inode_num);
+        }
+        if options.truncate && inode.i_size != 0 {
+            self.truncate(inode_num, 0)?;
+        }
+
+        Ok(FsFile {
+            inode_num,
+            options: *options,
+            pos: 0,
+            inode: (self.inode_generation, self.read_inode(inode_num)?),
+            extents: None,
+        })
+    }
+}
+
+impl FsFile {
+    /// Inode this handle is open on
+    pub fn inode_num(&self) -> u32 {
+        self.inode_num
+    }
+
+    /// Options the file was opened with
+    pub fn options(&self) -> &FileOptions {
+        &self.options
+    }
+
+    /// Where the next `read` or `write` starts
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Current size in bytes
+    pub fn size(&mut self, fs: &mut LolelfFs) -> Result<u64> {
+        Ok(self.inode(fs)?.i_size as u64)
+    }
+
+    /// The inode, read again only if some inode was written since
+    fn inode(&mut self, fs: &mut LolelfFs) -> Result<&Inode> {
+        if self.inode.0 != fs.inode_generation {
+            self.inode = (fs.inode_generation, fs.read_inode(self.inode_num)?);
+        }
+        Ok(&self.inode.1)
+    }
+
+    fn check_access(&self, write: bool) -> Result<()> {
+        let (allowed, access) = if write {
+            (self.options.writable(), "writing")
+        } else {
+            (self.options.read, "reading")
+        };
+        if !allowed {
+            return Err(FsError::NotOpenFor {
+                inode: self.inode_num,
+                access,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Read from the position, advancing it; returns 0 at the end
+    pub fn read(&mut self, fs: &mut LolelfFs, buf: &mut [u8]) -> Result<usize> {
+        let n = self.read_at(fs, self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Read from byte `offset` without moving the position
+    pub fn read_at(&mut self, fs: &mut LolelfFs, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.check_access(false)?;
+        let inode = self.inode(fs)?.clone();
+        let size = inode.i_size as u64;
+        if offset >= size || buf.is_empty() || inode.ei_block == 0 {
+            return Ok(0);
+        }
+
+        let stale = match &self.extents {
+            Some((generation, _)) => *generation != fs.extent_generation,
+            None => true,
+        };
+        if stale {
+            let ei = fs.readable_extent_index(self.inode_num, &inode)?;
+            self.extents = Some((fs.extent_generation, ei));
+        }
+        let ei = &self.extents.as_ref().unwrap().1;
+        let key = fs.file_key(self.inode_num, &inode);
+
+        let block_size = LOLELFFS_BLOCK_SIZE as u64;
+        let end = size.min(offset + buf.len() as u64);
+        let mut done = 0;
+        let mut at = offset;
+        while at < end {
+            let logical_block = (at / block_size) as u32;
+            let block = fs.read_file_block(self.inode_num, ei, &key, logical_block)?;
+            let block_start = logical_block as u64 * block_size;
+            let from = (at - block_start) as usize;
+            let to = (end - block_start).min(block_size) as usize;
+            // Short blocks are the zero-padded tail of the file
+            for (i, byte) in buf[done..done + to - from].iter_mut().enumerate() {
+                *byte = block.get(from + i).copied().unwrap_or(0);
+            }
+            done += to - from;
+            at += (to - from) as u64;
+        }
+        Ok(done)
+    }
+
+    /// Write at the position, or at the end when opened for append, and
+    /// move the position past what was written
+    pub fn write(&mut self, fs: &mut LolelfFs, data: &[u8]) -> Result<usize> {
+        let offset = if self.options.append {
+            self.size(fs)?
+        } else {
+            self.pos
+        };
+        self.write_at(fs, offset, data)?;
+        self.pos = offset + data.len() as u64;
+        Ok(data.len())
+    }
+
+    /// Write at byte `offset`, or at the end when opened for append,
+    /// without moving the position
+    pub fn write_at(&mut self, fs: &mut LolelfFs, offset: u64, data: &[u8]) -> Result<usize> {
+        self.check_access(true)?;
+        let offset = if self.options.append {
+            self.size(fs)?
+        } else {
+            offset
+        };
+        fs.write_file_at(self.inode_num, offset, data)?;
+        Ok(data.len())
+    }
+
+    /// Move the position; seeking past the end is allowed and a write there
+    /// leaves zeros in between
+    pub fn seek(&mut self, fs: &mut LolelfFs, pos: SeekFrom) -> Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.size(fs)?, delta),
+            SeekFrom::Current(delta) => (self.pos, delta),
+        };
+        match base.checked_add_signed(delta) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => bail!("Seek to a negative or overflowing position"),
+        }
+    }
+
+    /// Cut or extend the file to `size` bytes; the position is unchanged
+    pub fn truncate(&mut self, fs: &mut LolelfFs, size: u64) -> Result<()> {
+        self.check_access(true)?;
+        let Ok(size) = u32::try_from(size) else {
+            bail!("Size {} is larger than a file can be", size);
+        };
+        fs.truncate(self.inode_num, size)
+    }
+
+    /// Make everything written so far durable
+    ///
+    /// Writes reach the image as they are made, so this flushes the image
+    /// as a whole, other files' writes included.
+    pub fn sync(&mut self, fs: &mut LolelfFs) -> Result<()> {
+        if self.options.writable() {
+            fs.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_open_file() {
+        let (_path, mut fs) = temp_image("handle.img");
+        fs.mkdir(LOLELFFS_ROOT_INO, "dir").unwrap();
+        assert!(fs.open_file("/dir/f", &FileOptions::default()).is_err());
+
+        let rw = FileOptions {
+            write: true,
+            create: true,
+            ..Default::default()
+        };
+        let mut file = fs.open_file("/dir/f", &rw).unwrap();
+        let data: Vec<u8> = (0..2 * LOLELFFS_BLOCK_SIZE + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        file.write(&mut fs, &data).unwrap();
+        assert_eq!(file.position(), data.len() as u64);
+
+        file.seek(&mut fs, SeekFrom::Start(0)).unwrap();
+        let mut back = vec![0u8; data.len() + 100];
+        assert_eq!(file.read(&mut fs, &mut back).unwrap(), data.len());
+        assert_eq!(back[..data.len()], data[..]);
+        assert_eq!(file.read(&mut fs, &mut back).unwrap(), 0);
+
+        // A second handle sees the first one's writes, cached index or not
+        let mut other = fs.open_file("/dir/f", &FileOptions::default()).unwrap();
+        let mut head = [0u8; 4];
+        other.read_at(&mut fs, 0, &mut head).unwrap();
+        file.write_at(&mut fs, 1, b"xy").unwrap();
+        other.read_at(&mut fs, 0, &mut head).unwrap();
+        assert_eq!(head, [data[0], b'x', b'y', data[3]]);
+        assert!(other.write(&mut fs, b"no").is_err());
+
+        file.truncate(&mut fs, 3).unwrap();
+        assert_eq!(other.read_at(&mut fs, 0, &mut head).unwrap(), 3);
+        file.sync(&mut fs).unwrap();
+
+        let append = FileOptions {
+            read: false,
+            append: true,
+            ..Default::default()
+        };
+        let mut log = fs.open_file("/dir/f", &append).unwrap();
+        log.write(&mut fs, b"!").unwrap();
+        assert_eq!(
+            fs.read_file(file.inode_num()).unwrap(),
+            [data[0], b'x', b'y', b'!']
+        );
+
+        let truncate = FileOptions {
+            write: true,
+            truncate: true,
+            ..Default::default()
+        };
+        let mut file = fs.open_file("/dir/f", &truncate).unwrap();
+        assert_eq!(file.size(&mut fs).unwrap(), 0);
+        assert!(fs.open_file("/dir", &FileOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Hashing support for lolelffs
+//!
+//! Provides a single `Hasher` abstraction over CRC32C, xxHash64, SHA-256, and
+//! BLAKE3 that is shared by all integrity features (checksums, manifests,
+//! dedup, merkle trees). The algorithm is identified by a `LOLELFFS_HASH_*` ID
+//! which is recorded alongside the data it protects.
+
+use crate::types::*;
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+/// Incremental hasher for any supported algorithm
+pub enum Hasher {
+    Crc32c(u32),
+    Xxh64(Box<xxhash_rust::xxh64::Xxh64>),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// Create a hasher for the specified algorithm
+    pub fn new(algo: u8) -> Result<Self> {
+        match algo {
+            LOLELFFS_HASH_CRC32C => Ok(Hasher::Crc32c(0)),
+            LOLELFFS_HASH_XXH64 => Ok(Hasher::Xxh64(Box::new(xxhash_rust::xxh64::Xxh64::new(0)))),
+            LOLELFFS_HASH_SHA256 => Ok(Hasher::Sha256(Sha256::new())),
+            LOLELFFS_HASH_BLAKE3 => Ok(Hasher::Blake3(Box::new(blake3::Hasher::new()))),
+            _ => bail!("Unsupported hash algorithm: {}", algo),
+        }
+    }
+
+    /// Algorithm ID of this hasher
+    pub fn algo(&self) -> u8 {
+        match self {
+            Hasher::Crc32c(_) => LOLELFFS_HASH_CRC32C,
+            Hasher::Xxh64(_) => LOLELFFS_HASH_XXH64,
+            Hasher::Sha256(_) => LOLELFFS_HASH_SHA256,
+            Hasher::Blake3(_) => LOLELFFS_HASH_BLAKE3,
+        }
+    }
+
+    /// Feed more data into the hasher
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            Hasher::Xxh64(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Finish hashing and return the digest (big-endian for integer hashes)
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Crc32c(crc) => crc.to_be_bytes().to_vec(),
+            Hasher::Xxh64(h) => h.digest().to_be_bytes().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Hash a buffer in one shot
+pub fn hash(algo: u8, data: &[u8]) -> Result<Vec<u8>> {
+    let mut hasher = Hasher::new(algo)?;
+    hasher.update(data);
+    Ok(hasher.finalize())
+}
+
+/// Size in bytes of the digest produced by an algorithm
+pub fn digest_size(algo: u8) -> usize {
+    match algo {
+        LOLELFFS_HASH_CRC32C => 4,
+        LOLELFFS_HASH_XXH64 => 8,
+        LOLELFFS_HASH_SHA256 => 32,
+        LOLELFFS_HASH_BLAKE3 => 32,
+        _ => 0,
+    }
+}
+
+/// Get the name of a hash algorithm
+pub fn get_algo_name(algo: u8) -> &'static str {
+    match algo {
+        LOLELFFS_HASH_NONE => "none",
+        LOLELFFS_HASH_CRC32C => "crc32c",
+        LOLELFFS_HASH_XXH64 => "xxh64",
+        LOLELFFS_HASH_SHA256 => "sha256",
+        LOLELFFS_HASH_BLAKE3 => "blake3",
+        _ => "unknown",
+    }
+}
+
+/// Parse a hash algorithm name
+pub fn parse_algo_name(name: &str) -> Result<u8> {
+    match name.to_lowercase().as_str() {
+        "none" => Ok(LOLELFFS_HASH_NONE),
+        "crc32c" => Ok(LOLELFFS_HASH_CRC32C),
+        "xxh64" | "xxhash64" => Ok(LOLELFFS_HASH_XXH64),
+        "sha256" | "sha-256" => Ok(LOLELFFS_HASH_SHA256),
+        "blake3" => Ok(LOLELFFS_HASH_BLAKE3),
+        _ => bail!(
+            "Unknown hash algorithm '{}' (expected crc32c, xxh64, sha256, blake3)",
+            name
+        ),
+    }
+}
+
+/// Format a digest as a self-describing "algo:hex" string (for manifests)
+pub fn format_digest(algo: u8, digest: &[u8]) -> String {
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}:{}", get_algo_name(algo), hex)
+}
+
+/// Parse an "algo:hex" digest string produced by `format_digest`
+pub fn parse_digest(s: &str) -> Result<(u8, Vec<u8>)> {
+    let (name, hex) = match s.split_once(':') {
+        Some(parts) => parts,
+        None => bail!("Digest '{}' is missing an algorithm prefix", s),
+    };
+    let algo = parse_algo_name(name)?;
+
+    if hex.len() != digest_size(algo) * 2 {
+        bail!("Invalid {} digest length: {}", name, hex.len());
+    }
+    let digest = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow::anyhow!("Invalid hex in digest '{}'", s))?;
+
+    Ok((algo, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_vectors() {
+        let crc = hash(LOLELFFS_HASH_CRC32C, b"123456789").unwrap();
+        assert_eq!(crc, 0xE3069283u32.to_be_bytes());
+
+        let sha = hash(LOLELFFS_HASH_SHA256, b"abc").unwrap();
+        assert_eq!(
+            format_digest(LOLELFFS_HASH_SHA256, &sha),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_incremental_matches_oneshot() {
+        let data = vec![0x5Au8; 10000];
+        for algo in [
+            LOLELFFS_HASH_CRC32C,
+            LOLELFFS_HASH_XXH64,
+            LOLELFFS_HASH_SHA256,
+            LOLELFFS_HASH_BLAKE3,
+        ] {
+            let mut hasher = Hasher::new(algo).unwrap();
+            for chunk in data.chunks(777) {
+                hasher.update(chunk);
+            }
+            let digest = hasher.finalize();
+            assert_eq!(digest, hash(algo, &data).unwrap());
+            assert_eq!(digest.len(), digest_size(algo));
+
+            let formatted = format_digest(algo, &digest);
+            assert_eq!(parse_digest(&formatted).unwrap(), (algo, digest));
+        }
+    }
+}
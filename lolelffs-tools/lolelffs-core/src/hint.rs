@@ -0,0 +1,153 @@
+//! Per-file access pattern hints
+//!
+//! A file marked hot is read often: its blocks are stored uncompressed and
+//! allocated first-fit from the start of the data area, next to the inode
+//! store and directories. A cold file is rarely read: it is compressed with
+//! zstd (zlib in builds without it) and allocated from the end of the device, keeping the front free for
+//! hot data. Hints are inode flags and take effect on the next write; the
+//! exclusion list still wins, so already-compressed content stays raw.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// How a file is expected to be accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessHint {
+    #[default]
+    Normal,
+    Hot,
+    Cold,
+}
+
+impl AccessHint {
+    /// The hint recorded in inode flags
+    pub fn from_flags(flags: u32) -> Self {
+        if flags & LOLELFFS_INODE_HOT != 0 {
+            AccessHint::Hot
+        } else if flags & LOLELFFS_INODE_COLD != 0 {
+            AccessHint::Cold
+        } else {
+            AccessHint::Normal
+        }
+    }
+
+    /// `flags` with this hint in place of any other
+    pub fn apply(&self, flags: u32) -> u32 {
+        let flags = flags & !(LOLELFFS_INODE_HOT | LOLELFFS_INODE_COLD);
+        match self {
+            AccessHint::Normal => flags,
+            AccessHint::Hot => flags | LOLELFFS_INODE_HOT,
+            AccessHint::Cold => flags | LOLELFFS_INODE_COLD,
+        }
+    }
+
+    /// Compression algorithm the hint asks for, if it overrides the default
+    pub fn comp_algo(&self) -> Option<u8> {
+        match self {
+            AccessHint::Normal => None,
+            AccessHint::Hot => Some(LOLELFFS_COMP_NONE),
+            AccessHint::Cold if cfg!(feature = "compress-zstd") => Some(LOLELFFS_COMP_ZSTD),
+            AccessHint::Cold => Some(LOLELFFS_COMP_ZLIB),
+        }
+    }
+}
+
+impl FromStr for AccessHint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(AccessHint::Normal),
+            "hot" => Ok(AccessHint::Hot),
+            "cold" => Ok(AccessHint::Cold),
+            _ => Err(format!(
+                "unknown access hint '{}' (expected normal, hot, or cold)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AccessHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AccessHint::Normal => "normal",
+            AccessHint::Hot => "hot",
+            AccessHint::Cold => "cold",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl LolelfFs {
+    /// Access hint of a file
+    pub fn access_hint(&mut self, inode_num: u32) -> Result<AccessHint> {
+        Ok(AccessHint::from_flags(self.read_inode(inode_num)?.flags()))
+    }
+
+    /// Set the access hint of a regular file, applied from its next write
+    pub fn set_access_hint(&mut self, inode_num: u32, hint: AccessHint) -> Result<()> {
+        self.ensure_writable()?;
+        let mut inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            bail!("Access hints apply to regular files only");
+        }
+        inode.set_flags(hint.apply(inode.flags()));
+        inode.i_ctime = self.now();
+        self.write_inode(inode_num, &inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_hot_and_cold_placement() {
+        let path = TempPath::new("hint.img");
+        let mut fs = LolelfFs::create(&path, 8 * 1024 * 1024).unwrap();
+        let data = vec![b'x'; 8 * LOLELFFS_BLOCK_SIZE as usize];
+
+        let hot = fs.create_file(LOLELFFS_ROOT_INO, "hot").unwrap();
+        let cold = fs.create_file(LOLELFFS_ROOT_INO, "cold").unwrap();
+        fs.set_access_hint(hot, AccessHint::Hot).unwrap();
+        fs.set_access_hint(cold, AccessHint::Cold).unwrap();
+        assert!(fs
+            .set_access_hint(LOLELFFS_ROOT_INO, AccessHint::Hot)
+            .is_err());
+        fs.write_file(hot, &data).unwrap();
+        fs.write_file(cold, &data).unwrap();
+
+        let first_extent = |fs: &mut LolelfFs, ino| {
+            let inode = fs.read_inode(ino).unwrap();
+            fs.read_extent_index(&inode).unwrap().extents[0]
+        };
+        let middle = fs.superblock.nr_blocks / 2;
+        let hot_extent = first_extent(&mut fs, hot);
+        assert_eq!(hot_extent.ee_comp_algo, LOLELFFS_COMP_NONE as u16);
+        assert!(hot_extent.ee_start < middle);
+        let cold_extent = first_extent(&mut fs, cold);
+        assert_eq!(
+            cold_extent.ee_comp_algo,
+            AccessHint::Cold.comp_algo().unwrap() as u16
+        );
+        assert_eq!(
+            cold_extent.ee_start + cold_extent.ee_len,
+            fs.superblock.nr_blocks
+        );
+        assert_eq!(fs.read_file(cold).unwrap(), data);
+
+        // Hints are exclusive, survive reopening, and clear back to normal
+        fs.set_access_hint(cold, AccessHint::Hot).unwrap();
+        drop(fs);
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(fs.access_hint(cold).unwrap(), AccessHint::Hot);
+        fs.set_access_hint(cold, AccessHint::Normal).unwrap();
+        assert_eq!(fs.access_hint(cold).unwrap(), AccessHint::Normal);
+        assert_eq!(fs.read_inode(cold).unwrap().flags(), 0);
+    }
+}
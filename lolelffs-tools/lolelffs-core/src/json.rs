@@ -0,0 +1,566 @@
+//! Structured output for scripts
+//!
+//! Library types that commands report on implement `ToJson`, so the CLI's
+//! `--json` mode and embedders render the same fields under the same names.
+//! Objects keep their fields in insertion order, which keeps the output
+//! stable for diffing.
+
+use crate::compress::{self, CompStats};
+use crate::dedup::DedupStats;
+use crate::dir::DirEntry;
+use crate::doctor::{DoctorReport, Finding};
+use crate::encrypt;
+use crate::extcheck::{ExtentCheck, ExtentReport};
+use crate::forensic::{CarveHit, OrphanFile};
+use crate::fs::{FsStats, ThresholdAlarm};
+use crate::fsck::{FsckProblem, FsckReport, Severity};
+use crate::progress::Progress;
+use crate::stress::{StressOp, StressReport};
+use crate::types::*;
+use crate::verify::{DigestCheck, VerifyReport};
+use crate::xattr::XattrUsage;
+use std::fmt::{self, Write};
+
+/// A JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Num(u64),
+    Str(String),
+    List(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// An object from `(key, value)` pairs, in order
+    pub fn object<K: Into<String>>(fields: impl IntoIterator<Item = (K, JsonValue)>) -> Self {
+        JsonValue::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Append a field to an object (no-op for other values)
+    pub fn push(&mut self, key: impl Into<String>, value: impl Into<JsonValue>) {
+        if let JsonValue::Object(fields) = self {
+            fields.push((key.into(), value.into()));
+        }
+    }
+
+    /// Render with two-space indentation
+    pub fn to_pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(Some(0), &mut out);
+        out
+    }
+
+    /// `indent` is `None` for compact output
+    fn write(&self, indent: Option<usize>, out: &mut String) {
+        let (open_pad, close_pad, sep) = match indent {
+            Some(n) => (" ".repeat(n + 2), " ".repeat(n), "\n"),
+            None => (String::new(), String::new(), ""),
+        };
+        let inner = indent.map(|n| n + 2);
+        match self {
+            JsonValue::Null => out.push_str("null"),
+            JsonValue::Bool(b) => {
+                let _ = write!(out, "{}", b);
+            }
+            JsonValue::Num(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            JsonValue::Str(s) => json_string(s, out),
+            JsonValue::List(items) if items.is_empty() => out.push_str("[]"),
+            JsonValue::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            JsonValue::List(items) => {
+                out.push('[');
+                out.push_str(sep);
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&open_pad);
+                    item.write(inner, out);
+                    out.push_str(if i + 1 < items.len() { "," } else { "" });
+                    out.push_str(sep);
+                }
+                out.push_str(&close_pad);
+                out.push(']');
+            }
+            JsonValue::Object(fields) => {
+                out.push('{');
+                out.push_str(sep);
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&open_pad);
+                    json_string(key, out);
+                    out.push_str(if indent.is_some() { ": " } else { ":" });
+                    value.write(inner, out);
+                    out.push_str(if i + 1 < fields.len() { "," } else { "" });
+                    out.push_str(sep);
+                }
+                out.push_str(&close_pad);
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Compact rendering on one line
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        self.write(None, &mut out);
+        f.write_str(&out)
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(b: bool) -> Self {
+        JsonValue::Bool(b)
+    }
+}
+
+impl From<u32> for JsonValue {
+    fn from(n: u32) -> Self {
+        JsonValue::Num(n as u64)
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(n: u64) -> Self {
+        JsonValue::Num(n)
+    }
+}
+
+impl From<usize> for JsonValue {
+    fn from(n: usize) -> Self {
+        JsonValue::Num(n as u64)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(s: &str) -> Self {
+        JsonValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(s: String) -> Self {
+        JsonValue::Str(s)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(JsonValue::Null, Into::into)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(items: Vec<T>) -> Self {
+        JsonValue::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Append `s` as a JSON string literal
+pub(crate) fn json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Types with a structured form for `--json` output
+pub trait ToJson {
+    fn to_json(&self) -> JsonValue;
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::List(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl ToJson for Inode {
+    fn to_json(&self) -> JsonValue {
+        let kind = if self.is_dir() {
+            "directory"
+        } else if self.is_symlink() {
+            "symlink"
+        } else {
+            "file"
+        };
+        JsonValue::object([
+            ("type", kind.into()),
+            ("mode", format!("{:04o}", self.i_mode & 0o7777).into()),
+            ("uid", self.i_uid.into()),
+            ("gid", self.i_gid.into()),
+            ("size", self.i_size.into()),
+            ("blocks", self.i_blocks.into()),
+            ("nlink", self.i_nlink.into()),
+            ("atime", self.i_atime.into()),
+            ("mtime", self.i_mtime.into()),
+            ("ctime", self.i_ctime.into()),
+            ("ei_block", self.ei_block.into()),
+            ("xattr_block", self.xattr_block.into()),
+        ])
+    }
+}
+
+impl ToJson for DirEntry {
+    /// The entry's name and inode number followed by the inode's fields
+    fn to_json(&self) -> JsonValue {
+        let mut fields = vec![
+            ("name".to_string(), self.filename.as_str().into()),
+            ("inode".to_string(), self.inode_num.into()),
+        ];
+        if let JsonValue::Object(inode) = self.inode.to_json() {
+            fields.extend(inode);
+        }
+        JsonValue::Object(fields)
+    }
+}
+
+impl ToJson for FsStats {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("block_size", self.block_size.into()),
+            ("total_blocks", self.total_blocks.into()),
+            ("free_blocks", self.free_blocks.into()),
+            ("total_inodes", self.total_inodes.into()),
+            ("free_inodes", self.free_inodes.into()),
+            ("total_bytes", self.total_size().into()),
+            ("used_bytes", self.used_size().into()),
+            ("free_bytes", self.free_size().into()),
+            ("block_use_percent", self.block_use_percent().into()),
+            ("inode_use_percent", self.inode_use_percent().into()),
+        ])
+    }
+}
+
+impl ToJson for DedupStats {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("shared_extents", self.shared_extents.into()),
+            ("saved_blocks", self.saved_blocks.into()),
+            ("saved_bytes", self.saved_bytes().into()),
+        ])
+    }
+}
+
+impl ToJson for ThresholdAlarm {
+    /// The alarm's kind and measured value, plus its text as `message`
+    fn to_json(&self) -> JsonValue {
+        let mut out = match self {
+            ThresholdAlarm::BlockUse(pct) => {
+                JsonValue::object([("kind", "block_use".into()), ("percent", (*pct).into())])
+            }
+            ThresholdAlarm::InodeUse(pct) => {
+                JsonValue::object([("kind", "inode_use".into()), ("percent", (*pct).into())])
+            }
+            ThresholdAlarm::Extents { inode_num, extents } => JsonValue::object([
+                ("kind", "extents".into()),
+                ("inode", (*inode_num).into()),
+                ("extents", (*extents).into()),
+            ]),
+        };
+        out.push("message", self.to_string());
+        out
+    }
+}
+
+impl ToJson for Superblock {
+    fn to_json(&self) -> JsonValue {
+        let uuid = (self.uuid != [0; 16]).then(|| self.uuid_string());
+        JsonValue::object([
+            ("magic", format!("0x{:08X}", self.magic).into()),
+            ("version", self.version.into()),
+            ("uuid", uuid.into()),
+            ("label", self.label().into()),
+            ("dirty", (self.state & LOLELFFS_STATE_DIRTY != 0).into()),
+            ("nr_blocks", self.nr_blocks.into()),
+            ("nr_inodes", self.nr_inodes.into()),
+            ("nr_istore_blocks", self.nr_istore_blocks.into()),
+            ("nr_ifree_blocks", self.nr_ifree_blocks.into()),
+            ("nr_bfree_blocks", self.nr_bfree_blocks.into()),
+            ("nr_free_inodes", self.nr_free_inodes.into()),
+            ("nr_free_blocks", self.nr_free_blocks.into()),
+            ("data_block_start", self.data_block_start().into()),
+            ("max_extent_blocks", self.max_extent_blocks.into()),
+            (
+                "max_extent_blocks_large",
+                self.max_extent_blocks_large.into(),
+            ),
+            ("comp_enabled", (self.comp_enabled != 0).into()),
+            ("comp_default_algo", self.comp_default_algo.into()),
+            ("comp_min_block_size", self.comp_min_block_size.into()),
+            ("comp_features", self.comp_features.into()),
+            ("enc_enabled", (self.enc_enabled != 0).into()),
+            ("enc_default_algo", self.enc_default_algo.into()),
+            ("enc_kdf_iterations", self.enc_kdf_iterations.into()),
+            ("hash_algos", self.hash_algos.into()),
+        ])
+    }
+}
+
+impl ToJson for FsckProblem {
+    fn to_json(&self) -> JsonValue {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        JsonValue::object([
+            ("severity", severity.into()),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+}
+
+impl ToJson for FsckReport {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("dirs", self.dirs.into()),
+            ("inodes", self.inodes.into()),
+            ("blocks", self.blocks.into()),
+            ("problems", self.problems.to_json()),
+        ])
+    }
+}
+
+impl ToJson for Progress<'_> {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("phase", self.phase.into()),
+            ("current", self.current.into()),
+            ("total", self.total.into()),
+            ("message", self.message.into()),
+        ])
+    }
+}
+
+impl ToJson for Finding {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("level", self.level.to_string().into()),
+            ("area", self.area.into()),
+            ("message", self.message.as_str().into()),
+            ("advice", self.advice.clone().into()),
+        ])
+    }
+}
+
+impl ToJson for DoctorReport {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("stats", self.stats.to_json()),
+            ("frag_score", self.frag_score().into()),
+            ("files", self.frag.files.into()),
+            ("fragmented_files", self.frag.fragmented_files.into()),
+            ("free_runs", self.frag.free_runs.into()),
+            ("largest_free_run", self.frag.largest_free_run.into()),
+            ("bitmap_free_inodes", self.bitmap_free_inodes.into()),
+            ("bitmap_free_blocks", self.bitmap_free_blocks.into()),
+            ("findings", self.findings.to_json()),
+            ("recommendations", self.recommendations().into()),
+        ])
+    }
+}
+
+impl ToJson for CompStats {
+    /// Figures per algorithm by name; `stored` is null when encrypted
+    /// blocks could not be measured
+    fn to_json(&self) -> JsonValue {
+        let algos = self.by_algo.iter().map(|(algo, stats)| {
+            (
+                compress::get_algo_name(*algo),
+                JsonValue::object([
+                    ("blocks", stats.blocks.into()),
+                    ("logical", stats.logical.into()),
+                    ("stored", stats.stored.into()),
+                ]),
+            )
+        });
+        JsonValue::object([
+            ("size", self.size.into()),
+            ("blocks", self.blocks.into()),
+            ("logical", self.logical().into()),
+            ("stored", self.measured.then(|| self.stored()).into()),
+            ("algorithms", JsonValue::object(algos)),
+        ])
+    }
+}
+
+impl ToJson for DigestCheck {
+    fn to_json(&self) -> JsonValue {
+        match self {
+            DigestCheck::Match => JsonValue::object([("status", "match".into())]),
+            DigestCheck::Mismatch { stored, actual } => JsonValue::object([
+                ("status", "mismatch".into()),
+                ("stored", stored.as_str().into()),
+                ("actual", actual.as_str().into()),
+            ]),
+            DigestCheck::Missing => JsonValue::object([("status", "missing".into())]),
+        }
+    }
+}
+
+impl ToJson for VerifyReport {
+    fn to_json(&self) -> JsonValue {
+        let errors = self.errors.iter().map(|(path, err)| {
+            JsonValue::object([
+                ("path", path.as_str().into()),
+                ("error", err.as_str().into()),
+            ])
+        });
+        JsonValue::object([
+            ("verified", self.verified.into()),
+            ("recorded", self.recorded.into()),
+            ("missing", self.missing.clone().into()),
+            ("mismatched", self.mismatched.clone().into()),
+            ("errors", JsonValue::List(errors.collect())),
+        ])
+    }
+}
+
+impl ToJson for ExtentCheck {
+    fn to_json(&self) -> JsonValue {
+        let extent = &self.extent;
+        JsonValue::object([
+            ("logical", extent.ee_block.into()),
+            ("len", extent.ee_len.into()),
+            ("physical", extent.ee_start.into()),
+            (
+                "compression",
+                compress::get_algo_name(extent.ee_comp_algo as u8).into(),
+            ),
+            (
+                "encryption",
+                encrypt::get_algo_name(extent.ee_enc_algo).into(),
+            ),
+            ("blocks_ok", self.blocks_ok.into()),
+            ("problems", self.problems.clone().into()),
+        ])
+    }
+}
+
+impl ToJson for ExtentReport {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("extents", self.extents.to_json()),
+            ("problems", self.problems.clone().into()),
+            (
+                "digest",
+                self.digest
+                    .as_ref()
+                    .map_or(JsonValue::Null, ToJson::to_json),
+            ),
+        ])
+    }
+}
+
+impl ToJson for XattrUsage {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("inode", self.inode_num.into()),
+            ("count", self.count.into()),
+            ("bytes", self.bytes.into()),
+            ("blocks", self.blocks.into()),
+            ("extents", self.extents.into()),
+            ("over_capacity", self.over_capacity().into()),
+        ])
+    }
+}
+
+impl ToJson for OrphanFile {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("inode", self.inode_num.into()),
+            ("ei_block", self.ei_block.into()),
+            ("size", self.size.into()),
+            ("blocks", self.blocks.into()),
+        ])
+    }
+}
+
+impl ToJson for CarveHit {
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object([
+            ("block", self.block.into()),
+            ("blocks", self.run_blocks.into()),
+            ("type", self.kind.into()),
+            ("extension", self.extension.into()),
+        ])
+    }
+}
+
+impl ToJson for StressReport {
+    fn to_json(&self) -> JsonValue {
+        let ops = StressOp::ALL
+            .iter()
+            .map(|op| (op.name(), self.count(*op).into()));
+        JsonValue::object([
+            ("total_ops", self.total_ops().into()),
+            ("elapsed_ms", (self.elapsed.as_millis() as u64).into()),
+            ("ops", JsonValue::object(ops)),
+            ("no_space", self.no_space.into()),
+            ("failures", self.failures.into()),
+            ("mismatches", self.mismatches.into()),
+            ("errors", self.errors.clone().into()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_json_rendering() {
+        let mut value = JsonValue::object([
+            ("name", "a \"b\"\n".into()),
+            ("size", 42u32.into()),
+            ("none", JsonValue::Null),
+            ("list", vec![1u32, 2].into()),
+            ("empty", JsonValue::List(Vec::new())),
+        ]);
+        value.push("ok", true);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"b\"\n","size":42,"none":null,"list":[1,2],"empty":[],"ok":true}"#
+        );
+        assert_eq!(
+            JsonValue::object([("a", vec![1u32].into())]).to_pretty(),
+            "{\n  \"a\": [\n    1\n  ]\n}"
+        );
+
+        let path = TempPath::new("json.img");
+        let mut fs = crate::LolelfFs::create(&path, 1024 * 1024).unwrap();
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, b"hello").unwrap();
+        let entries = fs.list_dir(LOLELFFS_ROOT_INO).unwrap();
+        let listed = entries.to_json().to_string();
+        assert!(listed.contains(r#""name":"f","inode":"#));
+        assert!(listed.contains(r#""type":"file","mode":"0644""#));
+        assert!(listed.contains(r#""size":5,"#));
+        assert!(fs
+            .statfs()
+            .to_json()
+            .to_string()
+            .contains(r#""block_size":4096"#));
+        assert_eq!(
+            fs.comp_stats(ino).unwrap().to_json().to_string(),
+            r#"{"size":5,"blocks":1,"logical":5,"stored":5,"algorithms":{"none":{"blocks":1,"logical":5,"stored":5}}}"#
+        );
+        assert_eq!(
+            DigestCheck::Missing.to_json().to_string(),
+            r#"{"status":"missing"}"#
+        );
+    }
+}
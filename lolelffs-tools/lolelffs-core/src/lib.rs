@@ -0,0 +1,86 @@
+//! lolelffs-core: the lolelffs on-disk format and everything built on it
+//!
+//! This library reads, writes, checks and converts lolelffs filesystem
+//! images without the kernel module or mounting, and is what the `lolelffs`
+//! CLI and the FUSE driver are built on. It has no command-line, terminal or
+//! FUSE dependencies. The layers, from the bottom up:
+//!
+//! - format: `types` (on-disk structures and constants), `locator` (finding
+//!   an image inside a file or ELF binary), `compat`
+//! - I/O: `fs` (`LolelfFs`, block access, allocation), `bitmap`, `dir`,
+//!   `file`, `handle`, `stream`, `xattr`, `cow`, `pool`, `cache`,
+//!   `notouch`
+//! - data transforms: `compress`, `encrypt`, `hash`
+//! - operations: `fsck`, `recovery`, `resize`, `defrag`, `balance`, `sync`, `clone`,
+//!   `diff`, `archive`, `ext2`, and the rest
+//!
+//! Everything public here, including the names re-exported at the crate
+//! root, follows semver: breaking changes to it come with a major version
+//! bump; internals are `pub(crate)`.
+
+#[macro_use]
+mod trace;
+
+pub mod archive;
+pub mod balance;
+pub mod bitmap;
+pub mod cache;
+pub mod clone;
+pub mod compat;
+pub mod compress;
+pub mod cow;
+pub mod decrypt;
+pub mod dedup;
+pub mod defrag;
+pub mod diff;
+pub mod dir;
+pub mod doctor;
+pub mod encrypt;
+pub mod error;
+pub mod ext2;
+pub mod extcheck;
+pub mod file;
+pub mod forensic;
+pub mod fs;
+pub mod fsck;
+pub mod handle;
+pub mod hash;
+pub mod hint;
+pub mod json;
+pub mod locator;
+pub mod metadump;
+pub mod notouch;
+pub mod opaque;
+pub mod passwd;
+pub mod pool;
+pub mod preview;
+pub mod probe;
+pub mod progress;
+pub mod recompress;
+pub mod recovery;
+pub mod resize;
+pub mod shred;
+pub mod stream;
+pub mod stress;
+pub mod sync;
+#[cfg(any(test, feature = "testutil"))]
+#[doc(hidden)]
+pub mod testutil;
+pub mod tune;
+pub mod types;
+pub mod verify;
+pub mod xattr;
+
+pub use cache::{BlockCache, CacheStats};
+pub use error::{FsError, NoSpaceKind};
+pub use fs::{
+    BlockKind, IoStats, LolelfFs, OpenMode, OpenOptions, ThresholdAlarm, Thresholds, VerifyPolicy,
+};
+pub use handle::{FileOptions, FsFile};
+pub use json::{JsonValue, ToJson};
+pub use locator::ImageLocator;
+pub use notouch::NoTouchFs;
+pub use pool::{FsPool, PoolStats};
+pub use stream::{FsFileReader, FsFileWriter};
+pub use tune::TuneParams;
+pub use types::*;
@@ -0,0 +1,211 @@
+//! Where an image lives
+//!
+//! Besides plain image files, commands accept `file.img@offset=4096` for a
+//! filesystem inside a larger file, `/dev/sdb1` for a block device,
+//! and `elf:./binary` for the `.lolfs.super` section of an ELF binary. Plain
+//! paths and devices are probed, so an ELF binary also works without the
+//! `elf:` prefix. URLs are rejected when parsed: there is no remote backend.
+
+use crate::fs::{LolelfFs, OpenMode};
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A parsed image location
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageLocator {
+    /// A raw image, or an ELF binary detected by its magic
+    File(PathBuf),
+    /// A raw image starting at a byte offset inside a larger file
+    Offset { path: PathBuf, offset: u64 },
+    /// A block device (anything under `/dev/`)
+    BlockDevice(PathBuf),
+    /// The `.lolfs.super` section of an ELF binary
+    Elf(PathBuf),
+}
+
+impl FromStr for ImageLocator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("Empty image path");
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            bail!(
+                "Remote images are not supported; download {} and open the local copy",
+                s
+            );
+        }
+        if let Some(path) = s.strip_prefix("elf:") {
+            if path.is_empty() {
+                bail!("Missing binary path after 'elf:'");
+            }
+            return Ok(ImageLocator::Elf(PathBuf::from(path)));
+        }
+        if let Some((path, offset)) = s.rsplit_once("@offset=") {
+            let parsed = match offset.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => offset.parse(),
+            };
+            let Ok(offset) = parsed else {
+                bail!("Invalid offset '{}' (decimal or 0x-prefixed hex)", offset);
+            };
+            return Ok(ImageLocator::Offset {
+                path: PathBuf::from(path),
+                offset,
+            });
+        }
+        if s.starts_with("/dev/") {
+            return Ok(ImageLocator::BlockDevice(PathBuf::from(s)));
+        }
+        Ok(ImageLocator::File(PathBuf::from(s)))
+    }
+}
+
+impl fmt::Display for ImageLocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageLocator::File(path) | ImageLocator::BlockDevice(path) => {
+                write!(f, "{}", path.display())
+            }
+            ImageLocator::Offset { path, offset } => {
+                write!(f, "{}@offset={}", path.display(), offset)
+            }
+            ImageLocator::Elf(path) => write!(f, "elf:{}", path.display()),
+        }
+    }
+}
+
+impl From<PathBuf> for ImageLocator {
+    fn from(path: PathBuf) -> Self {
+        ImageLocator::File(path)
+    }
+}
+
+impl ImageLocator {
+    /// Local file backing the image
+    pub fn path(&self) -> &Path {
+        match self {
+            ImageLocator::File(path)
+            | ImageLocator::Offset { path, .. }
+            | ImageLocator::BlockDevice(path)
+            | ImageLocator::Elf(path) => path,
+        }
+    }
+
+    /// Backing file and the byte offset of the filesystem within it
+    pub fn resolve(&self) -> Result<(PathBuf, u64)> {
+        match self {
+            ImageLocator::File(path) | ImageLocator::BlockDevice(path) => {
+                // Unreadable or unrecognized files fall through to offset 0,
+                // so opening them reports the usual error
+                let offset = match File::open(path) {
+                    Ok(mut file) => crate::probe::locate(&mut file)?.unwrap_or(0),
+                    Err(_) => 0,
+                };
+                Ok((path.clone(), offset))
+            }
+            ImageLocator::Offset { path, offset } => Ok((path.clone(), *offset)),
+            ImageLocator::Elf(path) => {
+                let mut file = File::open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                match crate::probe::find_elf_section(&mut file)? {
+                    Some(offset) => Ok((path.clone(), offset)),
+                    None => bail!(
+                        "{} has no {} section",
+                        path.display(),
+                        crate::types::LOLELFFS_ELF_SECTION
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl LolelfFs {
+    /// Open the filesystem a locator points at
+    pub fn open_locator(locator: &ImageLocator, mode: OpenMode) -> Result<Self> {
+        let (path, offset) = locator.resolve()?;
+        Self::open_at(path, offset, mode)
+    }
+
+    /// Open the filesystem a locator points at with writes going to `scratch`
+    pub fn open_locator_cow<P: AsRef<Path>>(locator: &ImageLocator, scratch: P) -> Result<Self> {
+        let (path, offset) = locator.resolve()?;
+        Self::open_cow_at(path, offset, scratch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use crate::types::*;
+
+    #[test]
+    fn test_image_locator() {
+        let parse = |s: &str| s.parse::<ImageLocator>().unwrap();
+        assert_eq!(parse("a.img"), ImageLocator::File("a.img".into()));
+        assert_eq!(
+            parse("a.img@offset=0x1000"),
+            ImageLocator::Offset {
+                path: "a.img".into(),
+                offset: 4096
+            }
+        );
+        assert_eq!(
+            parse("/dev/sdb1"),
+            ImageLocator::BlockDevice("/dev/sdb1".into())
+        );
+        assert_eq!(parse("elf:./bin"), ImageLocator::Elf("./bin".into()));
+        for s in ["a.img@offset=-1", "elf:", "", "https://h/x.img"] {
+            assert!(s.parse::<ImageLocator>().is_err(), "{}", s);
+        }
+        for s in ["a.img", "a.img@offset=4096", "elf:./bin"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+
+        let raw = TempPath::new("locator.img");
+        let padded = TempPath::new("locator.bin");
+        let elf = TempPath::new("locator.elf");
+        let mut fs = LolelfFs::create(&raw, 1024 * 1024).unwrap();
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, b"embedded").unwrap();
+        drop(fs);
+        let image = std::fs::read(&raw).unwrap();
+
+        // A filesystem 512 bytes into a file is read and written in place
+        let mut bytes = vec![0xAAu8; 512];
+        bytes.extend_from_slice(&image);
+        std::fs::write(&padded, &bytes).unwrap();
+        let at_offset = parse(&format!("{}@offset=512", padded.display()));
+        let mut fs = LolelfFs::open_locator(&at_offset, OpenMode::ReadWrite).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"embedded");
+        fs.write_file(ino, b"rewritten").unwrap();
+        assert!(fs.grow(2 * 1024 * 1024).is_err());
+        drop(fs);
+        assert!(std::fs::read(&padded).unwrap()[..512]
+            .iter()
+            .all(|&b| b == 0xAA));
+        let mut fs = LolelfFs::open_locator(&at_offset, OpenMode::ReadOnly).unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"rewritten");
+
+        // ELF payloads open with or without the prefix
+        std::fs::write(&elf, crate::probe::wrap_in_elf(&image)).unwrap();
+        for locator in [
+            parse(&format!("elf:{}", elf.display())),
+            parse(&elf.display().to_string()),
+        ] {
+            let mut fs = LolelfFs::open_locator(&locator, OpenMode::ReadOnly).unwrap();
+            assert_eq!(fs.read_file(ino).unwrap(), b"embedded");
+        }
+        assert!(LolelfFs::open_locator(
+            &parse(&format!("elf:{}", raw.display())),
+            OpenMode::ReadOnly
+        )
+        .is_err());
+    }
+}
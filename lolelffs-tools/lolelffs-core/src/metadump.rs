@@ -0,0 +1,889 @@
+//! Deterministic dumps of filesystem metadata
+//!
+//! A dump covers the superblock, every allocated inode with its extent map
+//! and xattr names, and every directory's entries in on-disk slot order. File
+//! contents, xattr values and key material are left out. The output only
+//! depends on what is on disk, so dumps of the same image made by different
+//! tool versions can be diffed to spot metadata regressions.
+//!
+//! Optionally each file's content hash is recorded and the contents exported
+//! to a store of blobs named by hash. `LolelfFs::restore_metadump` rebuilds
+//! an image from a JSON dump, taking contents from such a store where
+//! available and zero-filling the rest, so a metadata problem can be
+//! reproduced without the private data that triggered it.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Hash used to name exported contents
+const CONTENT_HASH: u8 = LOLELFFS_HASH_SHA256;
+
+/// Output format of `LolelfFs::metadump`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    /// Indented `key: value` lines
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(DumpFormat::Text),
+            "json" => Ok(DumpFormat::Json),
+            _ => bail!("Unknown dump format '{}' (expected text or json)", s),
+        }
+    }
+}
+
+/// What `LolelfFs::metadump` writes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DumpOptions {
+    pub format: DumpFormat,
+    /// Record each regular file's content hash (reads all file data, and
+    /// needs encrypted images unlocked)
+    pub content_hashes: bool,
+}
+
+/// Work done by `LolelfFs::restore_metadump`
+#[derive(Debug, Clone, Default)]
+pub struct RestoreStats {
+    pub dirs: u32,
+    pub files: u32,
+    pub symlinks: u32,
+    pub hardlinks: u32,
+    /// Files whose contents were not in the store and were zero-filled
+    pub missing_contents: u32,
+    /// Dumped inodes not reachable from the root, which are not restored
+    pub skipped: u32,
+}
+
+/// A dumped value; both formats are rendered from the same tree so they
+/// always agree
+enum Value {
+    Num(u64),
+    Str(String),
+    List(Vec<Value>),
+    Map(Vec<(&'static str, Value)>),
+}
+
+impl Value {
+    fn hex(n: u32) -> Value {
+        Value::Str(format!("{:#x}", n))
+    }
+
+    fn is_scalar(&self) -> bool {
+        matches!(self, Value::Num(_) | Value::Str(_))
+    }
+
+    fn scalar_text(&self) -> String {
+        match self {
+            Value::Num(n) => n.to_string(),
+            Value::Str(s) if s.is_empty() || s.trim() != s || s.contains(['\n', '"']) => {
+                format!("{:?}", s)
+            }
+            Value::Str(s) => s.clone(),
+            Value::List(_) => "[]".to_string(),
+            Value::Map(_) => "{}".to_string(),
+        }
+    }
+
+    fn write_text(&self, indent: usize, out: &mut String) {
+        let pad = " ".repeat(indent);
+        match self {
+            Value::Map(fields) => {
+                for (key, value) in fields {
+                    if value.is_scalar() || value.is_empty() {
+                        let _ = writeln!(out, "{}{}: {}", pad, key, value.scalar_text());
+                    } else {
+                        let _ = writeln!(out, "{}{}:", pad, key);
+                        value.write_text(indent + 2, out);
+                    }
+                }
+            }
+            Value::List(items) => {
+                for item in items {
+                    if item.is_scalar() {
+                        let _ = writeln!(out, "{}- {}", pad, item.scalar_text());
+                        continue;
+                    }
+                    // Render the item one level deeper, then hang it off a dash
+                    let mut nested = String::new();
+                    item.write_text(indent + 2, &mut nested);
+                    let _ = write!(out, "{}- {}", pad, &nested[indent + 2..]);
+                }
+            }
+            scalar => {
+                let _ = writeln!(out, "{}{}", pad, scalar.scalar_text());
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::List(items) => items.is_empty(),
+            Value::Map(fields) => fields.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn write_json(&self, indent: usize, out: &mut String) {
+        let pad = " ".repeat(indent + 2);
+        match self {
+            Value::Num(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            Value::Str(s) => crate::json::json_string(s, out),
+            Value::List(items) if items.is_empty() => out.push_str("[]"),
+            Value::Map(fields) if fields.is_empty() => out.push_str("{}"),
+            Value::List(items) => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&pad);
+                    item.write_json(indent + 2, out);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                let _ = write!(out, "{}]", " ".repeat(indent));
+            }
+            Value::Map(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    let _ = write!(out, "{}\"{}\": ", pad, key);
+                    value.write_json(indent + 2, out);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                let _ = write!(out, "{}}}", " ".repeat(indent));
+            }
+        }
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn inode_type(inode: &Inode) -> &'static str {
+    if inode.is_dir() {
+        "dir"
+    } else if inode.is_file() {
+        "file"
+    } else if inode.is_symlink() {
+        "symlink"
+    } else {
+        "unknown"
+    }
+}
+
+fn dump_extent(extent: &Extent) -> Value {
+    Value::Map(vec![
+        ("logical", Value::Num(extent.ee_block as u64)),
+        ("len", Value::Num(extent.ee_len as u64)),
+        ("start", Value::Num(extent.ee_start as u64)),
+        (
+            "comp",
+            Value::Str(crate::compress::get_algo_name(extent.ee_comp_algo as u8).to_string()),
+        ),
+        (
+            "enc",
+            Value::Str(crate::encrypt::get_algo_name(extent.ee_enc_algo).to_string()),
+        ),
+        ("flags", Value::hex(extent.ee_flags as u32)),
+        ("meta", Value::Num(extent.ee_meta as u64)),
+    ])
+}
+
+fn parse_hex(s: &str) -> Result<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid hex value '{}' in metadump", s))
+}
+
+/// Contents for a restored file: the stored blob if the dump names one the
+/// store has, otherwise zeros of the dumped size. The flag says which.
+fn restored_content(record: &Json, data_dir: Option<&Path>) -> Result<(Vec<u8>, bool)> {
+    let size = record.field("size")?.as_u32()? as usize;
+    let blob = match (record.get("content"), data_dir) {
+        (Some(Json::Str(digest)), Some(dir)) => match crate::hash::parse_digest(digest) {
+            Ok((algo, expected)) => {
+                let (_, hex) = digest.split_once(':').unwrap_or_default();
+                std::fs::read(dir.join(hex))
+                    .ok()
+                    .filter(|data| crate::hash::hash(algo, data).ok() == Some(expected.clone()))
+            }
+            Err(_) => None,
+        },
+        _ => None,
+    };
+    Ok(match blob {
+        Some(data) if data.len() == size => (data, true),
+        _ => (vec![0; size], size == 0),
+    })
+}
+
+/// A parsed JSON value, just enough to read back what `metadump` writes
+#[derive(Debug)]
+enum Json {
+    Num(u64),
+    Str(String),
+    List(Vec<Json>),
+    Map(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json> {
+        let mut parser = JsonParser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != text.len() {
+            bail!("Trailing data at byte {}", parser.pos);
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Map(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn field(&self, key: &str) -> Result<&Json> {
+        self.get(key)
+            .with_context(|| format!("Missing '{}' in metadump", key))
+    }
+
+    fn as_u32(&self) -> Result<u32> {
+        match self {
+            Json::Num(n) => u32::try_from(*n).context("Number out of range in metadump"),
+            _ => bail!("Expected a number in metadump, found {:?}", self),
+        }
+    }
+
+    fn as_str(&self) -> Result<&str> {
+        match self {
+            Json::Str(s) => Ok(s),
+            _ => bail!("Expected a string in metadump, found {:?}", self),
+        }
+    }
+
+    fn as_list(&self) -> Result<&[Json]> {
+        match self {
+            Json::List(items) => Ok(items),
+            _ => bail!("Expected a list in metadump, found {:?}", self),
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<()> {
+        if !self.text[self.pos..].starts_with(literal) {
+            bail!("Expected '{}' at byte {}", literal, self.pos);
+        }
+        self.pos += literal.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_ws();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_ws();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Map(fields));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.skip_ws();
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                    self.skip_ws();
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect("}")?;
+                        return Ok(Json::Map(fields));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_ws();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::List(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_ws();
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect("]")?;
+                        return Ok(Json::List(items));
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                Ok(Json::Num(self.text[start..self.pos].parse()?))
+            }
+            _ => bail!("Unexpected input at byte {}", self.pos),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        loop {
+            let rest = &self.text[self.pos..];
+            let mut chars = rest.chars();
+            let Some(c) = chars.next() else {
+                bail!("Unterminated string");
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let Some(escape) = chars.next() else {
+                        bail!("Unterminated string");
+                    };
+                    self.pos += 1;
+                    match escape {
+                        '"' | '\\' | '/' => out.push(escape),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'u' => {
+                            let hex = rest.get(2..6).context("Truncated \\u escape")?;
+                            let code = u32::from_str_radix(hex, 16)?;
+                            out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                            self.pos += 4;
+                        }
+                        _ => bail!("Invalid escape '\\{}'", escape),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+impl LolelfFs {
+    fn dump_superblock(&self) -> Value {
+        let sb = &self.superblock;
+        Value::Map(vec![
+            ("magic", Value::hex(sb.magic)),
+            ("version", Value::Num(sb.version as u64)),
+            ("uuid", Value::Str(sb.uuid_string())),
+            ("label", Value::Str(sb.label())),
+            ("nr_blocks", Value::Num(sb.nr_blocks as u64)),
+            ("nr_inodes", Value::Num(sb.nr_inodes as u64)),
+            ("nr_istore_blocks", Value::Num(sb.nr_istore_blocks as u64)),
+            ("nr_ifree_blocks", Value::Num(sb.nr_ifree_blocks as u64)),
+            ("nr_bfree_blocks", Value::Num(sb.nr_bfree_blocks as u64)),
+            ("nr_free_inodes", Value::Num(sb.nr_free_inodes as u64)),
+            ("nr_free_blocks", Value::Num(sb.nr_free_blocks as u64)),
+            ("comp_default_algo", Value::Num(sb.comp_default_algo as u64)),
+            ("comp_enabled", Value::Num(sb.comp_enabled as u64)),
+            (
+                "comp_min_block_size",
+                Value::Num(sb.comp_min_block_size as u64),
+            ),
+            ("comp_features", Value::hex(sb.comp_features)),
+            ("max_extent_blocks", Value::Num(sb.max_extent_blocks as u64)),
+            (
+                "max_extent_blocks_large",
+                Value::Num(sb.max_extent_blocks_large as u64),
+            ),
+            ("enc_enabled", Value::Num(sb.enc_enabled as u64)),
+            ("enc_default_algo", Value::Num(sb.enc_default_algo as u64)),
+            ("enc_kdf_algo", Value::Num(sb.enc_kdf_algo as u64)),
+            (
+                "enc_kdf_iterations",
+                Value::Num(sb.enc_kdf_iterations as u64),
+            ),
+            ("enc_kdf_memory", Value::Num(sb.enc_kdf_memory as u64)),
+            (
+                "enc_kdf_parallelism",
+                Value::Num(sb.enc_kdf_parallelism as u64),
+            ),
+            ("enc_features", Value::hex(sb.enc_features)),
+            ("hash_algos", Value::hex(sb.hash_algos)),
+            ("last_orphan", Value::Num(sb.last_orphan as u64)),
+            ("state", Value::hex(sb.state)),
+        ])
+    }
+
+    /// Content hash of a regular file, as "algo:hex"
+    fn content_digest(&mut self, inode_num: u32) -> Result<(String, Vec<u8>)> {
+        let mut data = Vec::new();
+        self.read_file_to(inode_num, &mut data)?;
+        let digest = crate::hash::hash(CONTENT_HASH, &data)?;
+        Ok((crate::hash::format_digest(CONTENT_HASH, &digest), data))
+    }
+
+    fn dump_inode(&mut self, inode_num: u32, content_hashes: bool) -> Result<Value> {
+        let inode = self.read_inode(inode_num)?;
+        let mut fields = vec![
+            ("ino", Value::Num(inode_num as u64)),
+            ("type", Value::Str(inode_type(&inode).to_string())),
+            ("mode", Value::Str(format!("{:06o}", inode.i_mode))),
+            ("uid", Value::Num(inode.i_uid as u64)),
+            ("gid", Value::Num(inode.i_gid as u64)),
+            ("size", Value::Num(inode.i_size as u64)),
+            ("blocks", Value::Num(inode.i_blocks as u64)),
+            ("nlink", Value::Num(inode.i_nlink as u64)),
+            ("atime", Value::Num(inode.i_atime as u64)),
+            ("mtime", Value::Num(inode.i_mtime as u64)),
+            ("ctime", Value::Num(inode.i_ctime as u64)),
+            ("ei_block", Value::Num(inode.ei_block as u64)),
+            ("xattr_block", Value::Num(inode.xattr_block as u64)),
+            ("data", Value::Str(hex_bytes(&inode.i_data))),
+        ];
+
+        if inode.is_symlink() {
+            let target = crate::file::symlink_target(&inode);
+            fields.push((
+                "target",
+                Value::Str(String::from_utf8_lossy(&target).into_owned()),
+            ));
+        } else if inode.ei_block != 0 {
+            let extents = match self.read_extent_index(&inode) {
+                Ok(ei) => Value::List(
+                    ei.extents
+                        .iter()
+                        .take_while(|e| !e.is_empty())
+                        .map(dump_extent)
+                        .collect(),
+                ),
+                Err(e) => Value::Str(format!("unreadable: {:#}", e)),
+            };
+            fields.push(("extents", extents));
+        }
+
+        if content_hashes && inode.is_file() {
+            let content = match self.content_digest(inode_num) {
+                Ok((digest, _)) => Value::Str(digest),
+                Err(e) => Value::Str(format!("unreadable: {:#}", e)),
+            };
+            fields.push(("content", content));
+        }
+
+        if inode.xattr_block != 0 {
+            let xattrs = match self.list_xattrs(inode_num) {
+                Ok(names) => Value::List(names.into_iter().map(Value::Str).collect()),
+                Err(e) => Value::Str(format!("unreadable: {:#}", e)),
+            };
+            fields.push(("xattrs", xattrs));
+        }
+
+        if inode.is_dir() {
+            fields.push(("entries", self.dump_dir(inode_num)));
+        }
+
+        Ok(Value::Map(fields))
+    }
+
+    /// Directory entries in on-disk order, so slot reuse shows up in diffs
+    fn dump_dir(&mut self, dir_inode_num: u32) -> Value {
+        let blocks = match self.dir_blocks(dir_inode_num) {
+            Ok(blocks) => blocks,
+            Err(e) => return Value::Str(format!("unreadable: {:#}", e)),
+        };
+
+        let mut entries = Vec::new();
+        for block_num in blocks {
+            let block = match self.read_dir_block(block_num) {
+                Ok(block) => block,
+                Err(e) => {
+                    entries.push(Value::Map(vec![
+                        ("block", Value::Num(block_num as u64)),
+                        ("error", Value::Str(format!("{:#}", e))),
+                    ]));
+                    continue;
+                }
+            };
+            for (slot, raw) in block
+                .chunks_exact(FileEntry::SIZE)
+                .take(LOLELFFS_FILES_PER_BLOCK)
+                .enumerate()
+            {
+                if let Some(entry) = FileEntry::from_bytes(raw) {
+                    entries.push(Value::Map(vec![
+                        ("block", Value::Num(block_num as u64)),
+                        ("slot", Value::Num(slot as u64)),
+                        ("name", Value::Str(entry.filename)),
+                        ("ino", Value::Num(entry.inode as u64)),
+                    ]));
+                }
+            }
+        }
+        Value::List(entries)
+    }
+
+    /// Write a dump of all metadata to `out`
+    ///
+    /// The wrapped master key and KDF salt are not included, so dumps of
+    /// encrypted images can be shared.
+    pub fn metadump<W: Write>(&mut self, out: &mut W, options: &DumpOptions) -> Result<()> {
+        let mut inodes = Vec::new();
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            inodes.push(match self.dump_inode(inode_num, options.content_hashes) {
+                Ok(value) => value,
+                Err(e) => Value::Map(vec![
+                    ("ino", Value::Num(inode_num as u64)),
+                    ("error", Value::Str(format!("{:#}", e))),
+                ]),
+            });
+        }
+
+        let dump = Value::Map(vec![
+            ("superblock", self.dump_superblock()),
+            ("inodes", Value::List(inodes)),
+        ]);
+
+        let mut text = String::new();
+        match options.format {
+            DumpFormat::Text => dump.write_text(0, &mut text),
+            DumpFormat::Json => {
+                dump.write_json(0, &mut text);
+                text.push('\n');
+            }
+        }
+        out.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    /// Copy every regular file's contents into `dir`, named by content hash
+    ///
+    /// Contents already in the store are not written again. Returns the
+    /// number of blobs written.
+    pub fn export_contents(&mut self, dir: &Path) -> Result<u32> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut written = 0;
+        for inode_num in 0..self.superblock.nr_inodes {
+            if self.is_inode_free(inode_num)? || !self.read_inode(inode_num)?.is_file() {
+                continue;
+            }
+            let (digest, data) = self.content_digest(inode_num)?;
+            let (_, hex) = digest.split_once(':').unwrap_or_default();
+            let path = dir.join(hex);
+            if !path.exists() {
+                std::fs::write(&path, &data)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Build a new image at `path` from a JSON metadump
+    ///
+    /// The tree, names, modes, owners, times, hard links, symlinks and xattr
+    /// names are recreated, along with the image size, label and compression
+    /// and checksum settings. File contents come from `data_dir` when the
+    /// dump recorded their hash and the store has a matching blob; other
+    /// files are zero-filled to their dumped size. Inode numbers and block
+    /// placement are chosen afresh, xattr values are empty, and the image is
+    /// not encrypted.
+    pub fn restore_metadump(
+        path: &Path,
+        dump: &str,
+        data_dir: Option<&Path>,
+    ) -> Result<(LolelfFs, RestoreStats)> {
+        let dump = Json::parse(dump).context("Invalid metadump")?;
+        let sb = dump.field("superblock")?;
+        let nr_blocks = sb.field("nr_blocks")?.as_u32()?;
+
+        let mut fs = LolelfFs::create(path, nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64)?;
+        fs.set_label(sb.field("label")?.as_str()?)?;
+        let comp_enabled = sb.field("comp_enabled")?.as_u32()? != 0;
+        // Identical restored files share extents again, as they did
+        let reflink = match sb.get("comp_features") {
+            Some(features) => parse_hex(features.as_str()?)? & LOLELFFS_FEATURE_REFLINK != 0,
+            None => false,
+        };
+        fs.tune(
+            &crate::tune::TuneParams {
+                comp_algo: Some(sb.field("comp_default_algo")?.as_u32()? as u8),
+                comp_enabled: Some(comp_enabled),
+                comp_min_block_size: Some(sb.field("comp_min_block_size")?.as_u32()?),
+                max_extent_blocks_large: Some(sb.field("max_extent_blocks_large")?.as_u32()?),
+                kdf_iterations: None,
+                reflink: Some(reflink),
+            },
+            None,
+        )?;
+        let hash_algos = parse_hex(sb.field("hash_algos")?.as_str()?)?;
+        let mut dumped_sb = fs.superblock.clone();
+        dumped_sb.hash_algos = hash_algos;
+        fs.set_dir_checksums(dumped_sb.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM))?;
+        fs.superblock.hash_algos = hash_algos;
+        fs.write_superblock()?;
+
+        let mut records: HashMap<u32, &Json> = HashMap::new();
+        for record in dump.field("inodes")?.as_list()? {
+            records.insert(record.field("ino")?.as_u32()?, record);
+        }
+
+        let mut stats = RestoreStats::default();
+        let mut created = HashMap::from([(LOLELFFS_ROOT_INO, LOLELFFS_ROOT_INO)]);
+        let mut queue = std::collections::VecDeque::from([LOLELFFS_ROOT_INO]);
+        while let Some(old_dir) = queue.pop_front() {
+            let new_dir = created[&old_dir];
+            let Some(entries) = records[&old_dir].get("entries") else {
+                continue;
+            };
+            for entry in entries.as_list()? {
+                let name = entry.field("name")?.as_str()?;
+                let old_ino = entry.field("ino")?.as_u32()?;
+                let Some(record) = records.get(&old_ino) else {
+                    continue;
+                };
+                let kind = record.field("type")?.as_str()?;
+
+                if let Some(&new_ino) = created.get(&old_ino) {
+                    if kind != "dir" {
+                        fs.link(new_ino, new_dir, name)?;
+                        stats.hardlinks += 1;
+                    }
+                    continue;
+                }
+
+                let new_ino = match kind {
+                    "dir" => {
+                        stats.dirs += 1;
+                        queue.push_back(old_ino);
+                        fs.mkdir(new_dir, name)?
+                    }
+                    "symlink" => {
+                        stats.symlinks += 1;
+                        fs.symlink(new_dir, name, record.field("target")?.as_str()?)?
+                    }
+                    "file" => {
+                        stats.files += 1;
+                        let ino = fs.create_file(new_dir, name)?;
+                        let (data, found) = restored_content(record, data_dir)?;
+                        if !found {
+                            stats.missing_contents += 1;
+                        }
+                        fs.write_file(ino, &data)?;
+                        ino
+                    }
+                    _ => continue,
+                };
+                created.insert(old_ino, new_ino);
+            }
+        }
+        stats.skipped = records.len() as u32 - created.len() as u32;
+
+        // Attributes last, so filling directories does not disturb their times
+        let mut restored: Vec<(u32, u32)> = created.into_iter().collect();
+        restored.sort_unstable();
+        for (old_ino, new_ino) in restored {
+            let record = records[&old_ino];
+            if let Some(names) = record.get("xattrs") {
+                for name in names.as_list()? {
+                    fs.set_xattr(new_ino, name.as_str()?, b"")?;
+                }
+            }
+            let mut inode = fs.read_inode(new_ino)?;
+            let mode = u32::from_str_radix(record.field("mode")?.as_str()?, 8)
+                .context("Invalid mode in metadump")?;
+            inode.i_mode = (inode.i_mode & mode::S_IFMT) | (mode & 0o7777);
+            inode.i_uid = record.field("uid")?.as_u32()?;
+            inode.i_gid = record.field("gid")?.as_u32()?;
+            inode.i_atime = record.field("atime")?.as_u32()?;
+            inode.i_mtime = record.field("mtime")?.as_u32()?;
+            inode.i_ctime = record.field("ctime")?.as_u32()?;
+            fs.write_inode(new_ino, &inode)?;
+        }
+
+        Ok((fs, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_image, TempPath};
+
+    #[test]
+    fn test_metadump_deterministic() {
+        let (_path, mut fs) = temp_image("metadump.img");
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let f = fs.create_file(dir, "say \"hi\"").unwrap();
+        fs.write_file(f, &[3u8; 10000]).unwrap();
+        fs.symlink(LOLELFFS_ROOT_INO, "l", "d").unwrap();
+        fs.set_xattr(f, "user.tag", b"secret value").unwrap();
+
+        let mut text = Vec::new();
+        fs.metadump(
+            &mut text,
+            &DumpOptions {
+                format: DumpFormat::Text,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut again = Vec::new();
+        fs.metadump(
+            &mut again,
+            &DumpOptions {
+                format: DumpFormat::Text,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(text, again);
+
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("superblock:\n  magic: 0x101e1ff5\n"));
+        assert!(text.contains("  - ino: 0\n    type: dir\n"));
+        assert!(text.contains("name: \"say \\\"hi\\\"\""));
+        assert!(text.contains("target: d\n"));
+        assert!(text.contains("- user.tag\n"));
+        assert!(!text.contains("secret value"));
+
+        let mut json = Vec::new();
+        fs.metadump(
+            &mut json,
+            &DumpOptions {
+                format: DumpFormat::Json,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\n  \"superblock\": {\n    \"magic\": \"0x101e1ff5\","));
+        assert!(json.contains("\"name\": \"say \\\"hi\\\"\""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+
+        // A rename moves nothing but the entry, and the dump shows it
+        fs.rename(dir, "say \"hi\"", dir, "renamed").unwrap();
+        let mut renamed = Vec::new();
+        fs.metadump(
+            &mut renamed,
+            &DumpOptions {
+                format: DumpFormat::Text,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let renamed = String::from_utf8(renamed).unwrap();
+        assert!(renamed.contains("name: renamed\n"));
+        assert_eq!(renamed.lines().count(), text.lines().count());
+    }
+
+    #[test]
+    fn test_metarestore() {
+        let path = TempPath::new("metasrc.img");
+        let restored_path = TempPath::new("metadst.img");
+        let blobs = TempPath::new("metablobs");
+        let mut fs = LolelfFs::create(&path, 4 * 1024 * 1024).unwrap();
+        fs.set_label("src").unwrap();
+
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let shared = fs.create_file(dir, "shared").unwrap();
+        fs.write_file(shared, b"shared contents").unwrap();
+        let private = fs.create_file(dir, "private").unwrap();
+        fs.write_file(private, b"do not share").unwrap();
+        fs.link(shared, LOLELFFS_ROOT_INO, "hard").unwrap();
+        fs.symlink(dir, "s", "shared").unwrap();
+        fs.set_xattr(shared, "user.k", b"v").unwrap();
+        fs.set_mode(private, 0o600).unwrap();
+        fs.set_owner(private, Some(1000), Some(100)).unwrap();
+
+        let mut json = Vec::new();
+        fs.metadump(
+            &mut json,
+            &DumpOptions {
+                format: DumpFormat::Json,
+                content_hashes: true,
+            },
+        )
+        .unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        // Only the shareable file makes it into the store
+        assert_eq!(fs.export_contents(&blobs).unwrap(), 2);
+        let (_, hex) = fs
+            .content_digest(private)
+            .unwrap()
+            .0
+            .split_once(':')
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .unwrap();
+        std::fs::remove_file(blobs.join(hex)).unwrap();
+
+        let (mut restored, stats) =
+            LolelfFs::restore_metadump(&restored_path, &json, Some(&blobs)).unwrap();
+        assert_eq!(
+            (stats.dirs, stats.files, stats.symlinks, stats.hardlinks),
+            (1, 2, 1, 1)
+        );
+        assert_eq!((stats.missing_contents, stats.skipped), (1, 0));
+        assert_eq!(restored.superblock.label(), "src");
+        assert_eq!(restored.superblock.nr_blocks, fs.superblock.nr_blocks);
+
+        let shared2 = restored.resolve_path("/d/shared").unwrap();
+        assert_eq!(restored.resolve_path("/hard").unwrap(), shared2);
+        assert_eq!(restored.read_file(shared2).unwrap(), b"shared contents");
+        assert_eq!(restored.list_xattrs(shared2).unwrap(), vec!["user.k"]);
+        let private2 = restored.resolve_path("/d/private").unwrap();
+        assert_eq!(restored.read_file(private2).unwrap(), vec![0; 12]);
+        let inode = restored.read_inode(private2).unwrap();
+        let original = fs.read_inode(private).unwrap();
+        assert_eq!(
+            (inode.i_mode, inode.i_uid, inode.i_gid, inode.i_mtime),
+            (
+                original.i_mode,
+                original.i_uid,
+                original.i_gid,
+                original.i_mtime
+            )
+        );
+        let link = restored.resolve_path("/d/s").unwrap();
+        assert_eq!(restored.read_file(link).unwrap(), b"shared");
+        assert!(restored.fsck_full().unwrap().problems.is_empty());
+    }
+}
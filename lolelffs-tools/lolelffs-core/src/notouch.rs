@@ -0,0 +1,137 @@
+//! Compile-time no-touch access
+//!
+//! `OpenMode::ReadNoTouch` is enforced at run time: every write path goes
+//! through `ensure_writable` and fails. That catches mistakes inside the
+//! library, but every mutating method still compiles against such a handle.
+//! `NoTouchFs` owns a `LolelfFs` opened with `ReadNoTouch` and exposes only
+//! operations that read, so code given one cannot express a write at all,
+//! which is what an evidence workflow wants to be able to show. Unlocking
+//! is allowed; it only keeps the key in memory.
+
+use crate::dir::DirEntry;
+use crate::fs::{FsStats, LolelfFs, OpenMode};
+use crate::locator::ImageLocator;
+use crate::stream::FsFileReader;
+use crate::types::*;
+use crate::verify::DigestCheck;
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+
+/// A filesystem that can only be read, from `NoTouchFs::open`
+///
+/// Each method is the `LolelfFs` method of the same name.
+pub struct NoTouchFs {
+    fs: LolelfFs,
+}
+
+impl NoTouchFs {
+    /// Open the image at `path` without ever writing to it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(NoTouchFs {
+            fs: LolelfFs::open_with_mode(path, OpenMode::ReadNoTouch)?,
+        })
+    }
+
+    /// Open the image a locator points at without ever writing to it
+    pub fn open_locator(locator: &ImageLocator) -> Result<Self> {
+        Ok(NoTouchFs {
+            fs: LolelfFs::open_locator(locator, OpenMode::ReadNoTouch)?,
+        })
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.fs.superblock
+    }
+
+    pub fn statfs(&self) -> FsStats {
+        self.fs.statfs()
+    }
+
+    /// Decrypt the master key with `password`, in memory only
+    pub fn unlock(&mut self, password: &str) -> Result<()> {
+        self.fs.unlock(password)
+    }
+
+    pub fn read_inode(&mut self, inode_num: u32) -> Result<Inode> {
+        self.fs.read_inode(inode_num)
+    }
+
+    pub fn lookup(&mut self, dir_inode_num: u32, name: &str) -> Result<Option<u32>> {
+        self.fs.lookup(dir_inode_num, name)
+    }
+
+    pub fn list_dir(&mut self, dir_inode_num: u32) -> Result<Vec<DirEntry>> {
+        self.fs.list_dir(dir_inode_num)
+    }
+
+    pub fn resolve_path(&mut self, path: &str) -> Result<u32> {
+        self.fs.resolve_path(path)
+    }
+
+    pub fn resolve_path_follow(&mut self, path: &str, follow_last: bool) -> Result<u32> {
+        self.fs.resolve_path_follow(path, follow_last)
+    }
+
+    pub fn complete_path(&mut self, partial: &str) -> Result<Vec<String>> {
+        self.fs.complete_path(partial)
+    }
+
+    pub fn read_file(&mut self, inode_num: u32) -> Result<Vec<u8>> {
+        self.fs.read_file(inode_num)
+    }
+
+    pub fn read_file_range(&mut self, inode_num: u32, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.fs.read_file_range(inode_num, offset, len)
+    }
+
+    pub fn read_file_to<W: Write>(&mut self, inode_num: u32, writer: &mut W) -> Result<u64> {
+        self.fs.read_file_to(inode_num, writer)
+    }
+
+    pub fn open_reader(&mut self, inode_num: u32) -> Result<FsFileReader<'_>> {
+        self.fs.open_reader(inode_num)
+    }
+
+    pub fn get_xattr(&mut self, inode_num: u32, name: &str) -> Result<Vec<u8>> {
+        self.fs.get_xattr(inode_num, name)
+    }
+
+    pub fn list_xattrs(&mut self, inode_num: u32) -> Result<Vec<String>> {
+        self.fs.list_xattrs(inode_num)
+    }
+
+    pub fn file_sha256(&mut self, inode_num: u32) -> Result<String> {
+        self.fs.file_sha256(inode_num)
+    }
+
+    pub fn check_sha256(&mut self, inode_num: u32) -> Result<DigestCheck> {
+        self.fs.check_sha256(inode_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_no_touch_fs() {
+        let (path, mut fs) = temp_image("notouch-fs.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "evidence").unwrap();
+        fs.write_file(ino, b"exhibit B").unwrap();
+        fs.record_sha256(ino, b"exhibit B").unwrap();
+        drop(fs);
+        let before = std::fs::read(&path).unwrap();
+
+        let mut fs = NoTouchFs::open(&path).unwrap();
+        let ino = fs.resolve_path("/evidence").unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), b"exhibit B");
+        assert_eq!(fs.read_file_range(ino, 8, 10).unwrap(), b"B");
+        assert_eq!(fs.check_sha256(ino).unwrap(), DigestCheck::Match);
+        assert_eq!(fs.complete_path("/ev").unwrap(), ["/evidence"]);
+        drop(fs);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+}
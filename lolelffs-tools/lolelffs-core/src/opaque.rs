@@ -0,0 +1,154 @@
+//! Fully opaque images
+//!
+//! Per-file encryption hides what files contain, but the inode table,
+//! bitmaps, extent indexes, directories and xattrs stay readable, giving
+//! away names, sizes, timestamps and where everything lives. An opaque image
+//! encrypts every block after the superblock with AES-256-XTS, keyed from
+//! the master key and tweaked by the block number, underneath everything
+//! else. This layer works on raw blocks and never asks what a block holds.
+//! File data is therefore encrypted twice.
+//!
+//! Unused blocks hold encrypted zeros, so a locked image shows no more than
+//! block 0: its size, parameters and the compression exclusion list. Nothing
+//! can be read until the image is unlocked. The kernel module does not
+//! implement this layer and refuses to mount opaque images, which it would
+//! otherwise allocate from by reading encrypted bitmaps as plain ones; use
+//! the FUSE driver.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+impl LolelfFs {
+    /// Whether every block past the superblock is encrypted
+    pub fn is_opaque(&self) -> bool {
+        self.superblock.enc_features & LOLELFFS_ENC_FEAT_OPAQUE != 0
+    }
+
+    /// Key of the block layer, once the image is unlocked
+    fn image_key(&self) -> Result<[u8; 32]> {
+        if !self.enc_unlocked {
+            bail!("Image is opaque; unlock it with the password first");
+        }
+        Ok(crate::encrypt::derive_image_key(&self.enc_master_key))
+    }
+
+    /// Decrypt a block as stored in the image
+    pub(crate) fn open_image_block(&self, block_num: u32, data: Vec<u8>) -> Result<Vec<u8>> {
+        if block_num == 0 || !self.is_opaque() {
+            return Ok(data);
+        }
+        crate::encrypt::decrypt_aes_xts(&self.image_key()?, block_num as u64, &data)
+    }
+
+    /// Encrypt a block for storing in the image
+    pub(crate) fn seal_image_block<'a>(
+        &self,
+        block_num: u32,
+        data: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>> {
+        if block_num == 0 || !self.is_opaque() {
+            return Ok(Cow::Borrowed(data));
+        }
+        let sealed = crate::encrypt::encrypt_aes_xts(&self.image_key()?, block_num as u64, data)?;
+        Ok(Cow::Owned(sealed))
+    }
+
+    /// Whether the root inode decodes as a directory with its extent index
+    /// in the data area, which garbage from a wrong key almost never does
+    pub(crate) fn root_inode_plausible(&mut self) -> Result<bool> {
+        let root = self.read_inode(LOLELFFS_ROOT_INO)?;
+        Ok(root.is_dir()
+            && root.ei_block >= self.superblock.data_block_start()
+            && root.ei_block < self.superblock.nr_blocks)
+    }
+
+    /// Encrypt every block after the superblock, making the image opaque
+    ///
+    /// Meant for images that were just created (`mkfs --opaque`): blocks are
+    /// rewritten in place, and an interrupted run leaves an image that is
+    /// neither plain nor opaque. Needs an unlocked encrypted image.
+    pub fn make_opaque(&mut self) -> Result<()> {
+        self.ensure_writable()?;
+        if self.superblock.enc_enabled == 0 {
+            bail!("Only encrypted images can be made opaque");
+        }
+        if self.is_opaque() {
+            bail!("Image is already opaque");
+        }
+        if !self.enc_unlocked {
+            bail!("Image is locked; unlock it with the password first");
+        }
+        let key = crate::encrypt::derive_image_key(&self.enc_master_key);
+
+        let total = self.superblock.nr_blocks as u64 - 1;
+        for block_num in 1..self.superblock.nr_blocks {
+            let block = self.read_block(block_num)?;
+            let sealed = crate::encrypt::encrypt_aes_xts(&key, block_num as u64, &block)?;
+            self.write_meta_block(block_num, &sealed)?;
+            self.report_progress("opaque", block_num as u64, total, "");
+        }
+
+        self.superblock.enc_features |= LOLELFFS_ENC_FEAT_OPAQUE;
+        self.write_superblock()
+    }
+
+    /// Write encrypted zeros over `range`, as unused blocks of an opaque
+    /// image hold
+    pub(crate) fn zero_opaque_blocks(&mut self, range: std::ops::Range<u32>) -> Result<()> {
+        let zero = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        for block_num in range {
+            self.write_block(block_num, &zero)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_opaque_image() {
+        let path = TempPath::new("opaque.img");
+        let enc = Some(("secret".to_string(), LOLELFFS_ENC_AES256_XTS, 1000));
+        let mut fs = LolelfFs::create_with_encryption(&path, 4 * 1024 * 1024, enc).unwrap();
+        fs.make_opaque().unwrap();
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "distinctive-dir").unwrap();
+        let ino = fs.create_file(dir, "distinctive-name").unwrap();
+        fs.write_file(ino, b"contents").unwrap();
+        fs.set_xattr(ino, "user.tag", b"distinctive-xattr").unwrap();
+        drop(fs);
+
+        // Nothing past the superblock is plaintext
+        let raw = std::fs::read(&path).unwrap();
+        let body = &raw[LOLELFFS_BLOCK_SIZE as usize..];
+        for needle in [&b"distinctive"[..], &[0xFFu8; 64][..], &[0u8; 64][..]] {
+            assert!(!body.windows(needle.len()).any(|w| w == needle));
+        }
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert!(fs.read_inode(LOLELFFS_ROOT_INO).is_err());
+        assert!(fs.unlock("wrong").is_err());
+        assert!(!fs.enc_unlocked);
+        fs.unlock("secret").unwrap();
+        let found = fs
+            .resolve_path("/distinctive-dir/distinctive-name")
+            .unwrap();
+        assert_eq!(found, ino);
+        assert_eq!(fs.read_file(ino).unwrap(), b"contents");
+        assert_eq!(fs.get_xattr(ino, "user.tag").unwrap(), b"distinctive-xattr");
+
+        // Growing keeps the new blocks opaque
+        fs.grow(6 * 1024 * 1024).unwrap();
+        let big = vec![3u8; 1024 * 1024];
+        let other = fs.create_file(LOLELFFS_ROOT_INO, "big").unwrap();
+        fs.write_file(other, &big).unwrap();
+        assert_eq!(fs.read_file(other).unwrap(), big);
+        drop(fs);
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw[4 * 1024 * 1024..].windows(64).any(|w| w == [0u8; 64]));
+    }
+}
@@ -0,0 +1,191 @@
+//! Changing the encryption password
+//!
+//! File data is encrypted with the master key (or keys derived from it), and
+//! the password only protects the copy of the master key in the superblock.
+//! Changing it therefore re-wraps that one key under a fresh salt and leaves
+//! every data block alone.
+//!
+//! The wrapped key has no check value, so a wrong old password would
+//! silently wrap garbage and make the data unreadable for good. Before
+//! re-wrapping, the unwrapped key is tried on an encrypted block that can
+//! tell right from wrong: one sealed with an authenticated cipher, or one
+//! whose compression framing would not survive a wrong key.
+
+use crate::fs::LolelfFs;
+use crate::tune::LOLELFFS_MIN_KDF_ITERATIONS;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+/// How well `change_password` could confirm the old password
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    /// A block decoded correctly with the key it unwraps
+    Verified,
+    /// No block can tell (no data yet, or only uncompressed AES-XTS
+    /// blocks); the password was taken on trust
+    Unverified,
+}
+
+/// Wrap `master_key` under a key derived from `password` with a fresh salt
+pub(crate) fn wrap_master_key(
+    sb: &mut Superblock,
+    master_key: &[u8; 32],
+    password: &str,
+    iterations: u32,
+) -> Result<()> {
+    sb.enc_salt = crate::encrypt::generate_salt();
+    let key = crate::encrypt::derive_key_pbkdf2(password.as_bytes(), &sb.enc_salt, iterations);
+    sb.enc_master_key = crate::encrypt::encrypt_master_key(master_key, &key)?;
+    sb.enc_kdf_iterations = iterations;
+    Ok(())
+}
+
+impl LolelfFs {
+    /// Check that `master_key` decodes this image's data, on the first
+    /// encrypted block whose decoding can fail
+    pub(crate) fn check_master_key(&mut self, master_key: [u8; 32]) -> Result<PasswordCheck> {
+        let mut probe = None;
+        for walk in self.walk_tree(LOLELFFS_ROOT_INO)? {
+            let inode = walk.entry.inode;
+            if !inode.is_file() || inode.ei_block == 0 {
+                continue;
+            }
+            let ei = self.read_extent_index(&inode)?;
+            let checkable = ei.extents.iter().take_while(|e| !e.is_empty()).find(|e| {
+                e.ee_enc_algo != LOLELFFS_ENC_NONE
+                    && (crate::encrypt::tag_size(e.ee_enc_algo) > 0
+                        || e.ee_comp_algo as u8 != LOLELFFS_COMP_NONE)
+            });
+            if let Some(extent) = checkable {
+                probe = Some((walk.entry.inode_num, inode, ei.clone(), extent.ee_block));
+                break;
+            }
+        }
+        let Some((inode_num, inode, ei, logical_block)) = probe else {
+            return Ok(PasswordCheck::Unverified);
+        };
+
+        // Decode with the candidate key, then put the real state back
+        let saved = (self.enc_master_key, self.enc_unlocked);
+        self.enc_master_key = master_key;
+        self.enc_unlocked = true;
+        let key = self.file_key(inode_num, &inode);
+        let decoded = self.read_logical_block(&ei, &key, logical_block);
+        (self.enc_master_key, self.enc_unlocked) = saved;
+
+        match decoded {
+            Ok(_) => Ok(PasswordCheck::Verified),
+            Err(_) => bail!("Wrong password"),
+        }
+    }
+
+    /// Replace the encryption password, optionally with a new PBKDF2
+    /// iteration count (the current one otherwise)
+    ///
+    /// Fails without writing anything if `old` is provably wrong.
+    pub fn change_password(
+        &mut self,
+        old: &str,
+        new: &str,
+        iterations: Option<u32>,
+    ) -> Result<PasswordCheck> {
+        self.ensure_writable()?;
+        let mut sb = self.superblock.clone();
+        if sb.enc_enabled == 0 {
+            bail!("Filesystem is not encrypted");
+        }
+        if sb.enc_kdf_algo != LOLELFFS_KDF_PBKDF2 as u32 {
+            bail!("Only PBKDF2-protected images can change their password");
+        }
+        if new.is_empty() {
+            bail!("The new password is empty");
+        }
+        let iterations = iterations.unwrap_or(sb.enc_kdf_iterations);
+        if iterations < LOLELFFS_MIN_KDF_ITERATIONS {
+            bail!(
+                "KDF iterations must be at least {}",
+                LOLELFFS_MIN_KDF_ITERATIONS
+            );
+        }
+
+        // Unlocking an opaque image already proves the password
+        if self.is_opaque() && !self.enc_unlocked {
+            self.unlock(old)?;
+        }
+        let old_key =
+            crate::encrypt::derive_key_pbkdf2(old.as_bytes(), &sb.enc_salt, sb.enc_kdf_iterations);
+        let master_key = crate::encrypt::decrypt_master_key(&sb.enc_master_key, &old_key)?;
+        if self.enc_unlocked && master_key != self.enc_master_key {
+            bail!("Wrong password");
+        }
+        let check = if self.is_opaque() {
+            PasswordCheck::Verified
+        } else {
+            self.check_master_key(master_key)?
+        };
+
+        wrap_master_key(&mut sb, &master_key, new, iterations)?;
+        self.superblock = sb;
+        self.write_superblock()?;
+        Ok(check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_change_password() {
+        let path = TempPath::new("passwd.img");
+        let enc = Some((
+            "old".to_string(),
+            LOLELFFS_ENC_CHACHA20_POLY,
+            LOLELFFS_MIN_KDF_ITERATIONS,
+        ));
+        let mut fs = LolelfFs::create_with_encryption(&path, 4 * 1024 * 1024, enc).unwrap();
+        fs.unlock("old").unwrap();
+        assert_eq!(
+            fs.change_password("old", "first", None).unwrap(),
+            PasswordCheck::Unverified
+        );
+
+        let data = vec![5u8; 10_000];
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "secret").unwrap();
+        fs.write_file(ino, &data).unwrap();
+        drop(fs);
+
+        // The authenticated cipher catches a wrong password before anything
+        // is written
+        let mut fs = LolelfFs::open(&path).unwrap();
+        let before = fs.superblock.enc_master_key;
+        assert!(fs.change_password("wrong", "new", None).is_err());
+        assert_eq!(fs.superblock.enc_master_key, before);
+        let check = fs
+            .change_password("first", "new", Some(LOLELFFS_MIN_KDF_ITERATIONS + 1))
+            .unwrap();
+        assert_eq!(check, PasswordCheck::Verified);
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(
+            fs.superblock.enc_kdf_iterations,
+            LOLELFFS_MIN_KDF_ITERATIONS + 1
+        );
+        fs.unlock("new").unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), data);
+        drop(fs);
+
+        // Images from the C mkfs default to Argon2id, which is not implemented
+        let mut fs = LolelfFs::open(&path).unwrap();
+        fs.superblock.enc_kdf_algo = LOLELFFS_KDF_ARGON2ID as u32;
+        let err = fs.unlock("new").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::error::FsError>(),
+            Some(crate::error::FsError::Unsupported { .. })
+        ));
+        assert!(!fs.superblock.compat_issues().is_empty());
+        assert!(!fs.enc_unlocked);
+    }
+}
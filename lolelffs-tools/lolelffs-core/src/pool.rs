@@ -0,0 +1,199 @@
+//! A bounded cache of read-only image handles
+//!
+//! Services that inspect many images cannot keep every one open. `FsPool`
+//! holds at most `capacity` handles, evicting the least recently used, and
+//! keys them by canonical path so `./a.img` and `/srv/a.img` share a handle.
+
+use crate::dir::DirEntry;
+use crate::fs::LolelfFs;
+use crate::types::Inode;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// Counters describing how well the pool is caching
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Images actually opened from disk
+    pub opens: u64,
+    /// Requests served by an already open handle
+    pub hits: u64,
+    /// Handles closed to make room for another image
+    pub evictions: u64,
+}
+
+/// LRU cache of read-only `LolelfFs` handles keyed by image path
+pub struct FsPool {
+    capacity: usize,
+    handles: HashMap<PathBuf, LolelfFs>,
+    /// Least recently used at the front
+    order: VecDeque<PathBuf>,
+    stats: PoolStats,
+}
+
+impl FsPool {
+    /// Create a pool holding at most `capacity` open images (at least one)
+    pub fn new(capacity: usize) -> Self {
+        FsPool {
+            capacity: capacity.max(1),
+            handles: HashMap::new(),
+            order: VecDeque::new(),
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Most images kept open at once
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of images currently open
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Whether no image is open
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Cache counters since the pool was created
+    pub fn stats(&self) -> &PoolStats {
+        &self.stats
+    }
+
+    /// Whether `image` currently has an open handle
+    pub fn contains<P: AsRef<Path>>(&self, image: P) -> bool {
+        std::fs::canonicalize(image)
+            .map(|key| self.handles.contains_key(&key))
+            .unwrap_or(false)
+    }
+
+    /// Handle for `image`, opening it (and evicting the least recently used
+    /// handle if the pool is full) when it is not already open
+    pub fn get<P: AsRef<Path>>(&mut self, image: P) -> Result<&mut LolelfFs> {
+        let image = image.as_ref();
+        let key = std::fs::canonicalize(image)
+            .with_context(|| format!("Failed to open image '{}'", image.display()))?;
+
+        if self.handles.contains_key(&key) {
+            self.stats.hits += 1;
+            if let Some(pos) = self.order.iter().position(|p| *p == key) {
+                self.order.remove(pos);
+            }
+        } else {
+            // Open before evicting so a bad path leaves the pool untouched
+            let fs = LolelfFs::open_readonly(&key)?;
+            self.stats.opens += 1;
+            while self.handles.len() >= self.capacity {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.handles.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+            self.handles.insert(key.clone(), fs);
+        }
+
+        self.order.push_back(key.clone());
+        Ok(self
+            .handles
+            .get_mut(&key)
+            .expect("handle was just looked up or inserted"))
+    }
+
+    /// Close the handle for `image`, returning whether one was open
+    pub fn evict<P: AsRef<Path>>(&mut self, image: P) -> bool {
+        let Ok(key) = std::fs::canonicalize(image) else {
+            return false;
+        };
+        self.order.retain(|p| *p != key);
+        self.handles.remove(&key).is_some()
+    }
+
+    /// Close every handle
+    pub fn clear(&mut self) {
+        self.handles.clear();
+        self.order.clear();
+    }
+
+    /// Inode number of `path` in `image`, following symlinks
+    pub fn resolve<P: AsRef<Path>>(&mut self, image: P, path: &str) -> Result<u32> {
+        self.get(image)?.resolve_path_follow(path, true)
+    }
+
+    /// Inode number and inode of `path` in `image` (the last component is
+    /// not followed, like lstat)
+    pub fn stat<P: AsRef<Path>>(&mut self, image: P, path: &str) -> Result<(u32, Inode)> {
+        let fs = self.get(image)?;
+        let inode_num = fs.resolve_path_follow(path, false)?;
+        Ok((inode_num, fs.read_inode(inode_num)?))
+    }
+
+    /// Entries of the directory at `path` in `image`
+    pub fn list_dir<P: AsRef<Path>>(&mut self, image: P, path: &str) -> Result<Vec<DirEntry>> {
+        let fs = self.get(image)?;
+        let inode_num = fs.resolve_path_follow(path, true)?;
+        fs.list_dir(inode_num)
+    }
+
+    /// Contents of the file at `path` in `image`
+    pub fn read_file<P: AsRef<Path>>(&mut self, image: P, path: &str) -> Result<Vec<u8>> {
+        let fs = self.get(image)?;
+        let inode_num = fs.resolve_path_follow(path, true)?;
+        fs.read_file(inode_num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use crate::types::LOLELFFS_ROOT_INO;
+
+    #[test]
+    fn test_pool_lru_and_dedup() {
+        let paths: Vec<TempPath> = (0..3)
+            .map(|i| TempPath::new(&format!("pool-{}.img", i)))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            let mut fs = LolelfFs::create(path, 1024 * 1024).unwrap();
+            let ino = fs.create_file(LOLELFFS_ROOT_INO, "id").unwrap();
+            fs.write_file(ino, format!("image {}", i).as_bytes())
+                .unwrap();
+        }
+
+        let mut pool = FsPool::new(2);
+        assert_eq!(pool.read_file(&paths[0], "/id").unwrap(), b"image 0");
+        // A different spelling of the same path reuses the open handle
+        let alias = paths[0]
+            .with_file_name(".")
+            .join(paths[0].file_name().unwrap());
+        assert_eq!(pool.read_file(&alias, "/id").unwrap(), b"image 0");
+        assert_eq!(pool.stats().opens, 1);
+        assert_eq!(pool.stats().hits, 1);
+
+        // Touching image 0 again makes image 1 the one evicted for image 2
+        pool.read_file(&paths[1], "/id").unwrap();
+        pool.list_dir(&paths[0], "/").unwrap();
+        assert_eq!(pool.read_file(&paths[2], "/id").unwrap(), b"image 2");
+        assert_eq!(pool.len(), 2);
+        assert!(pool.contains(&paths[0]));
+        assert!(!pool.contains(&paths[1]));
+        assert_eq!(pool.stats().evictions, 1);
+
+        // Handles are read-only, and failures leave the pool as it was
+        assert!(pool
+            .get(&paths[0])
+            .unwrap()
+            .create_file(LOLELFFS_ROOT_INO, "x")
+            .is_err());
+        assert!(pool.stat(&paths[2], "/missing").is_err());
+        assert!(pool.get(TempPath::new("pool-missing.img")).is_err());
+        assert_eq!(pool.len(), 2);
+
+        assert!(pool.evict(&paths[2]));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.stat(&paths[1], "/id").unwrap().1.i_size, 7);
+    }
+}
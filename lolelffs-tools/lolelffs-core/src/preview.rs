@@ -0,0 +1,162 @@
+//! File previews for browsers
+//!
+//! Graphical front ends show a thumbnail or the first lines of a file while
+//! the user browses an image. A preview reads only a bounded prefix of the
+//! file through `read_prefix` and guesses its kind from magic numbers, so
+//! listing a directory of multi-gigabyte files stays fast.
+
+use crate::fs::LolelfFs;
+use anyhow::{bail, Result};
+
+/// Preview size used when the caller has no preference
+pub const DEFAULT_PREVIEW_BYTES: u64 = 64 * 1024;
+
+/// What a preview looks like it contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewKind {
+    /// UTF-8 text without NUL bytes
+    Text,
+    /// An image format browsers can decode, with its MIME type
+    Image(&'static str),
+    /// Anything else
+    Binary,
+}
+
+impl PreviewKind {
+    /// MIME type to hand to a viewer
+    pub fn mime(&self) -> &'static str {
+        match self {
+            PreviewKind::Text => "text/plain",
+            PreviewKind::Image(mime) => mime,
+            PreviewKind::Binary => "application/octet-stream",
+        }
+    }
+
+    /// Guess the kind of a file from its first bytes
+    ///
+    /// `truncated` says whether more of the file follows, in which case a
+    /// multi-byte character cut off at the end still counts as text.
+    pub fn sniff(data: &[u8], truncated: bool) -> Self {
+        const SIGNATURES: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n", "image/png"),
+            (b"\xff\xd8\xff", "image/jpeg"),
+            (b"GIF87a", "image/gif"),
+            (b"GIF89a", "image/gif"),
+            (b"BM", "image/bmp"),
+            (b"\x00\x00\x01\x00", "image/x-icon"),
+        ];
+        for (magic, mime) in SIGNATURES {
+            if data.starts_with(magic) {
+                return PreviewKind::Image(mime);
+            }
+        }
+        if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            return PreviewKind::Image("image/webp");
+        }
+
+        if data.contains(&0) {
+            return PreviewKind::Binary;
+        }
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            // At most 3 bytes of a 4-byte character can be cut off
+            Err(e) if truncated && e.error_len().is_none() && data.len() - e.valid_up_to() < 4 => {
+                std::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default()
+            }
+            Err(_) => return PreviewKind::Binary,
+        };
+        let start = text.trim_start();
+        if start.starts_with("<svg") || (start.starts_with("<?xml") && text.contains("<svg")) {
+            PreviewKind::Image("image/svg+xml")
+        } else {
+            PreviewKind::Text
+        }
+    }
+}
+
+/// The start of a file, ready to display
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// Full size of the file
+    pub size: u64,
+    /// Up to the requested number of bytes from the start of the file
+    pub data: Vec<u8>,
+    /// Whether the file continues past `data`
+    pub truncated: bool,
+    pub kind: PreviewKind,
+}
+
+impl Preview {
+    /// The preview as text, for `PreviewKind::Text`, without a character
+    /// cut off at the end
+    pub fn text(&self) -> Option<&str> {
+        if self.kind != PreviewKind::Text {
+            return None;
+        }
+        match std::str::from_utf8(&self.data) {
+            Ok(text) => Some(text),
+            Err(e) => std::str::from_utf8(&self.data[..e.valid_up_to()]).ok(),
+        }
+    }
+}
+
+impl LolelfFs {
+    /// Read up to `max_bytes` from the start of a regular file and guess
+    /// what it contains
+    pub fn preview(&mut self, inode_num: u32, max_bytes: u64) -> Result<Preview> {
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            bail!("Only regular files can be previewed");
+        }
+        let data = self.read_prefix(inode_num, max_bytes)?;
+        let size = inode.i_size as u64;
+        let truncated = (data.len() as u64) < size;
+        let kind = PreviewKind::sniff(&data, truncated);
+        Ok(Preview {
+            size,
+            data,
+            truncated,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+    use crate::types::*;
+
+    #[test]
+    fn test_preview_kinds() {
+        let (_path, mut fs) = temp_image("preview.img");
+
+        // A prefix ending inside a multi-byte character is still text
+        let text = "héllo wörld\n".repeat(1000);
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "notes.txt").unwrap();
+        fs.write_file(ino, text.as_bytes()).unwrap();
+        let preview = fs.preview(ino, 2).unwrap();
+        assert_eq!(preview.kind, PreviewKind::Text);
+        assert!(preview.truncated);
+        assert_eq!(preview.size, text.len() as u64);
+        assert_eq!(preview.text(), Some("h"));
+        let preview = fs.preview(ino, 1 << 20).unwrap();
+        assert!(!preview.truncated);
+        assert_eq!(preview.text(), Some(text.as_str()));
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&[0; 100_000]);
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "pic.png").unwrap();
+        fs.write_file(ino, &png).unwrap();
+        let preview = fs.preview(ino, 16).unwrap();
+        assert_eq!(preview.kind.mime(), "image/png");
+        assert_eq!(preview.data, png[..16]);
+        assert_eq!(preview.text(), None);
+
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "blob").unwrap();
+        fs.write_file(ino, &[1, 0, 2]).unwrap();
+        assert_eq!(fs.preview(ino, 16).unwrap().kind, PreviewKind::Binary);
+
+        assert!(fs.preview(LOLELFFS_ROOT_INO, 16).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Filesystem detection for lolelffs
+//!
+//! Cheaply answers "is this a lolelffs image, and at what offset?" without
+//! opening it as a filesystem. Images are recognized either as raw images
+//! (superblock at offset 0) or as ELF binaries carrying the filesystem in a
+//! `.lolfs.super` section, matching the kernel module's detection.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// ELF header magic
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+/// ELF class for 64-bit binaries
+const ELF_CLASS64: u8 = 2;
+
+/// Size of an ELF64 file header
+const ELF64_EHDR_SIZE: usize = 64;
+
+/// Size of an ELF64 section header
+const ELF64_SHDR_SIZE: u64 = 64;
+
+/// Result of a successful probe
+#[derive(Debug, Clone)]
+pub struct ProbeInfo {
+    /// Byte offset of the filesystem within the file
+    pub offset: u64,
+    /// Superblock found at that offset
+    pub superblock: Superblock,
+}
+
+/// Detect a lolelffs filesystem in a file
+///
+/// Returns `Ok(None)` if the file is readable but holds no lolelffs filesystem.
+pub fn probe<P: AsRef<Path>>(path: P) -> Result<Option<ProbeInfo>> {
+    let mut file = File::open(path.as_ref())?;
+    match locate(&mut file)? {
+        Some(offset) => read_probe_info(&mut file, offset),
+        None => Ok(None),
+    }
+}
+
+/// Detect a lolelffs filesystem at a known byte offset in a file
+pub fn probe_at<P: AsRef<Path>>(path: P, offset: u64) -> Result<Option<ProbeInfo>> {
+    let mut file = File::open(path.as_ref())?;
+    if !has_magic_at(&mut file, offset)? {
+        return Ok(None);
+    }
+    read_probe_info(&mut file, offset)
+}
+
+fn read_probe_info(file: &mut File, offset: u64) -> Result<Option<ProbeInfo>> {
+    let superblock = LolelfFs::read_superblock_at(file, offset)?;
+    Ok(Some(ProbeInfo { offset, superblock }))
+}
+
+/// Offset of the filesystem in a file: 0 for raw images, the section offset
+/// for ELF binaries, or `None` if neither carries the magic
+pub(crate) fn locate(file: &mut File) -> Result<Option<u64>> {
+    if has_magic_at(file, 0)? {
+        return Ok(Some(0));
+    }
+    match find_elf_section(file)? {
+        Some(offset) if has_magic_at(file, offset)? => Ok(Some(offset)),
+        _ => Ok(None),
+    }
+}
+
+/// Check for the lolelffs magic number at a byte offset
+fn has_magic_at(file: &mut File, offset: u64) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    file.seek(SeekFrom::Start(offset))?;
+    if file.read_exact(&mut magic).is_err() {
+        return Ok(false);
+    }
+    Ok(LittleEndian::read_u32(&magic) == LOLELFFS_MAGIC)
+}
+
+/// Find the offset of the `.lolfs.super` section in a 64-bit ELF file
+pub(crate) fn find_elf_section(file: &mut File) -> Result<Option<u64>> {
+    let mut ehdr = [0u8; ELF64_EHDR_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut ehdr).is_err() {
+        return Ok(None);
+    }
+
+    if ehdr[..4] != ELF_MAGIC || ehdr[4] != ELF_CLASS64 {
+        return Ok(None);
+    }
+
+    let shoff = LittleEndian::read_u64(&ehdr[0x28..]);
+    let shnum = LittleEndian::read_u16(&ehdr[0x3C..]) as u64;
+    let shstrndx = LittleEndian::read_u16(&ehdr[0x3E..]) as u64;
+
+    if shstrndx >= shnum {
+        return Ok(None);
+    }
+
+    // Load the section header string table
+    let (strtab_offset, strtab_size) = match read_section_header(file, shoff, shstrndx) {
+        Ok(header) => header,
+        Err(_) => return Ok(None),
+    };
+    let mut strtab = vec![0u8; strtab_size.min(1024 * 1024) as usize];
+    file.seek(SeekFrom::Start(strtab_offset))?;
+    if file.read_exact(&mut strtab).is_err() {
+        return Ok(None);
+    }
+
+    for idx in 0..shnum {
+        let mut shdr = [0u8; ELF64_SHDR_SIZE as usize];
+        file.seek(SeekFrom::Start(shoff + idx * ELF64_SHDR_SIZE))?;
+        if file.read_exact(&mut shdr).is_err() {
+            continue;
+        }
+
+        let name_off = LittleEndian::read_u32(&shdr[0..]) as usize;
+        if name_off >= strtab.len() {
+            continue;
+        }
+        let name_end = strtab[name_off..]
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(strtab.len(), |p| name_off + p);
+
+        if &strtab[name_off..name_end] == LOLELFFS_ELF_SECTION.as_bytes() {
+            return Ok(Some(LittleEndian::read_u64(&shdr[0x18..])));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read (sh_offset, sh_size) of a section header
+fn read_section_header(file: &mut File, shoff: u64, idx: u64) -> Result<(u64, u64)> {
+    let mut shdr = [0u8; ELF64_SHDR_SIZE as usize];
+    file.seek(SeekFrom::Start(shoff + idx * ELF64_SHDR_SIZE))?;
+    file.read_exact(&mut shdr)?;
+    Ok((
+        LittleEndian::read_u64(&shdr[0x18..]),
+        LittleEndian::read_u64(&shdr[0x20..]),
+    ))
+}
+
+/// Minimal ELF64 carrying `image` in a `.lolfs.super` section at 4096:
+/// header, string table, three section headers, then the image
+#[cfg(test)]
+pub(crate) fn wrap_in_elf(image: &[u8]) -> Vec<u8> {
+    let strtab = b"\0.shstrtab\0.lolfs.super\0";
+    let fs_offset = 4096u64;
+    let mut elf = vec![0u8; fs_offset as usize];
+    elf[..4].copy_from_slice(&ELF_MAGIC);
+    elf[4] = ELF_CLASS64;
+    LittleEndian::write_u64(&mut elf[0x28..], 128);
+    LittleEndian::write_u16(&mut elf[0x3C..], 3);
+    LittleEndian::write_u16(&mut elf[0x3E..], 1);
+    elf[64..64 + strtab.len()].copy_from_slice(strtab);
+    for (idx, (name, offset, size)) in [(1u32, 64u64, strtab.len() as u64), (11, fs_offset, 0)]
+        .iter()
+        .enumerate()
+    {
+        let shdr = 128 + (idx + 1) * ELF64_SHDR_SIZE as usize;
+        LittleEndian::write_u32(&mut elf[shdr..], *name);
+        LittleEndian::write_u64(&mut elf[shdr + 0x18..], *offset);
+        LittleEndian::write_u64(&mut elf[shdr + 0x20..], *size);
+    }
+    elf.extend_from_slice(image);
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_probe_raw_and_elf() {
+        let raw_path = TempPath::new("probe.img");
+        let elf_path = TempPath::new("probe.elf");
+
+        LolelfFs::create(&raw_path, 1024 * 1024).unwrap();
+        let info = probe(&raw_path).unwrap().unwrap();
+        assert_eq!(info.offset, 0);
+        assert_eq!(info.superblock.magic, LOLELFFS_MAGIC);
+
+        let fs_offset = 4096u64;
+        let mut elf = wrap_in_elf(&std::fs::read(&raw_path).unwrap());
+        std::fs::write(&elf_path, &elf).unwrap();
+
+        let info = probe(&elf_path).unwrap().unwrap();
+        assert_eq!(info.offset, fs_offset);
+        assert_eq!(info.superblock.magic, LOLELFFS_MAGIC);
+
+        // A plain ELF without the section is not a lolelffs image
+        elf.truncate(fs_offset as usize);
+        elf[64 + 11] = b'X';
+        std::fs::write(&elf_path, &elf).unwrap();
+        assert!(probe(&elf_path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Progress reporting for long operations
+//!
+//! Operations that walk a whole image or archive (fsck, tar import and
+//! export, image sync and everything built on it) report how far along they
+//! are through an optional callback, for progress bars and front ends that
+//! stream the events. An event is sent per item, so a callback that renders
+//! should throttle. When a phase's length is not known up front, as with a
+//! tar stream, `total` is 0 until the last event, which always has
+//! `current == total`.
+
+use crate::fs::LolelfFs;
+
+/// Where a long operation is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress<'a> {
+    /// Stage of the operation, e.g. "inodes" or "import"
+    pub phase: &'static str,
+    /// Items done so far in this phase
+    pub current: u64,
+    /// Items in this phase, 0 if not known yet
+    pub total: u64,
+    /// The item being worked on, usually a path; may be empty
+    pub message: &'a str,
+}
+
+/// Receives progress events; see `LolelfFs::set_progress`
+pub type ProgressCallback = Box<dyn FnMut(&Progress) + Send>;
+
+impl LolelfFs {
+    /// Send progress events of long operations to `callback`
+    pub fn set_progress(&mut self, callback: ProgressCallback) {
+        self.progress = Some(callback);
+    }
+
+    /// Stop reporting progress
+    pub fn clear_progress(&mut self) {
+        self.progress = None;
+    }
+
+    /// Pass an event to the callback, if one is set
+    pub(crate) fn report_progress(
+        &mut self,
+        phase: &'static str,
+        current: u64,
+        total: u64,
+        message: &str,
+    ) {
+        if let Some(callback) = self.progress.as_mut() {
+            callback(&Progress {
+                phase,
+                current,
+                total,
+                message,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+    use crate::types::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_progress_events() {
+        let (_path, mut fs) = temp_image("progress.img");
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "dir").unwrap();
+        for name in ["a", "b"] {
+            let ino = fs.create_file(dir, name).unwrap();
+            fs.write_file(ino, name.as_bytes()).unwrap();
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        fs.set_progress(Box::new(move |p: &Progress| {
+            sink.lock()
+                .unwrap()
+                .push((p.phase, p.current, p.total, p.message.to_string()));
+        }));
+
+        let mut tar = Vec::new();
+        fs.export_tar(&mut tar, LOLELFFS_ROOT_INO).unwrap();
+        assert_eq!(
+            events.lock().unwrap().clone(),
+            [
+                ("export", 1, 3, "dir".to_string()),
+                ("export", 2, 3, "dir/a".to_string()),
+                ("export", 3, 3, "dir/b".to_string()),
+            ]
+        );
+
+        // A stream of unknown length ends with current == total
+        events.lock().unwrap().clear();
+        let sub = fs.mkdir(LOLELFFS_ROOT_INO, "copy").unwrap();
+        fs.import_tar(&tar[..], sub).unwrap();
+        let imported = events.lock().unwrap().clone();
+        assert!(imported.iter().all(|e| e.0 == "import"));
+        assert!(imported[..imported.len() - 1].iter().all(|e| e.2 == 0));
+        let last = imported.last().unwrap();
+        assert_eq!(
+            (last.1, last.2),
+            (imported.len() as u64 - 1, imported.len() as u64 - 1)
+        );
+
+        // fsck walks inodes and blocks with known totals
+        events.lock().unwrap().clear();
+        fs.fsck_full().unwrap();
+        let checked = events.lock().unwrap().clone();
+        let last_inode = checked.iter().rfind(|e| e.0 == "inodes").unwrap();
+        assert_eq!(last_inode.1, fs.superblock.nr_inodes as u64);
+        assert_eq!(last_inode.1, last_inode.2);
+
+        fs.clear_progress();
+        events.lock().unwrap().clear();
+        fs.fsck_full().unwrap();
+        assert!(events.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Migrating files to another compression algorithm
+//!
+//! Each extent records the algorithm its blocks were written with, so an
+//! image can hold files compressed with several algorithms at once. Changing
+//! the superblock default with `tune` only affects later writes;
+//! `recompress` rewrites existing files with the chosen algorithm (or none),
+//! e.g. to shrink an old LZ4 image with zstd. Files keep their encryption
+//! algorithm and modification time. `set_compression` pins one file to an
+//! algorithm that every later write uses.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+/// Work done by `LolelfFs::recompress`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecompressStats {
+    /// Files rewritten with the new algorithm
+    pub files: u32,
+    /// Files left alone: already using it, empty, excluded from
+    /// compression, or set to another algorithm of their own
+    pub skipped: u32,
+    /// Data blocks the rewritten files used before
+    pub blocks_before: u64,
+    /// Data blocks the rewritten files use now
+    pub blocks_after: u64,
+}
+
+impl LolelfFs {
+    /// Give a regular file its own compression algorithm and rewrite it
+    /// with that algorithm now, or with `None` go back to the image's
+    /// default from the next write
+    ///
+    /// Every later write of the file, including through FUSE, uses the
+    /// algorithm. Returns whether the file was rewritten.
+    pub fn set_compression(&mut self, inode_num: u32, algo: Option<u8>) -> Result<bool> {
+        self.ensure_writable()?;
+        if let Some(algo) = algo {
+            if !crate::compat::comp_algo_supported(algo) {
+                bail!(
+                    "Compression algorithm {} is not supported by this build",
+                    algo
+                );
+            }
+        }
+        let mut inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            bail!("Per-file compression applies to regular files only");
+        }
+        inode.set_comp_override(algo);
+        inode.i_ctime = self.now();
+        self.write_inode(inode_num, &inode)?;
+
+        match algo {
+            Some(algo) => self.recompress_file(inode_num, algo),
+            None => Ok(false),
+        }
+    }
+
+    /// Rewrite one regular file with compression `algo`, returning whether
+    /// it was rewritten
+    ///
+    /// Files whose extents all use `algo` already, empty files, files
+    /// excluded from compression (unless `algo` is none), and files whose
+    /// own algorithm (see `set_compression`) is another one are skipped.
+    pub fn recompress_file(&mut self, inode_num: u32, algo: u8) -> Result<bool> {
+        self.ensure_writable()?;
+        if !crate::compat::comp_algo_supported(algo) {
+            bail!(
+                "Compression algorithm {} is not supported by this build",
+                algo
+            );
+        }
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            bail!("Inode {} is not a regular file", inode_num);
+        }
+        let Some(extents) = self.file_extents(inode_num)? else {
+            return Ok(false);
+        };
+        if extents.is_empty()
+            || extents.iter().all(|e| e.ee_comp_algo == algo as u16)
+            || inode.comp_override().is_some_and(|own| own != algo)
+            || (algo != LOLELFFS_COMP_NONE
+                && inode.comp_override().is_none()
+                && inode.flags() & LOLELFFS_INODE_NOCOMP != 0)
+        {
+            return Ok(false);
+        }
+
+        let data = self.read_file(inode_num)?;
+        let opts = crate::file::WriteOptions {
+            comp_algo: Some(algo),
+            enc_algo: Some(extents[0].ee_enc_algo),
+        };
+        self.write_file_with_options(inode_num, &data, &opts)?;
+
+        // The contents did not change
+        let mut rewritten = self.read_inode(inode_num)?;
+        rewritten.i_mtime = inode.i_mtime;
+        self.write_inode(inode_num, &rewritten)?;
+        Ok(true)
+    }
+
+    /// Rewrite `inode_num`, or every regular file below it if it is a
+    /// directory, with compression `algo`
+    pub fn recompress(&mut self, inode_num: u32, algo: u8) -> Result<RecompressStats> {
+        let mut files = Vec::new();
+        if self.read_inode(inode_num)?.is_dir() {
+            for walk in self.walk_tree(inode_num)? {
+                if walk.entry.inode.is_file() && !files.contains(&walk.entry.inode_num) {
+                    files.push(walk.entry.inode_num);
+                }
+            }
+        } else {
+            files.push(inode_num);
+        }
+
+        let mut stats = RecompressStats::default();
+        let total = files.len() as u64;
+        for (i, file) in files.into_iter().enumerate() {
+            let before = self.read_inode(file)?.i_blocks as u64;
+            if self.recompress_file(file, algo)? {
+                stats.files += 1;
+                stats.blocks_before += before;
+                stats.blocks_after += self.read_inode(file)?.i_blocks as u64;
+            } else {
+                stats.skipped += 1;
+            }
+            self.report_progress("recompress", i as u64 + 1, total, "");
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_recompress() {
+        let (_path, mut fs) = temp_image("recompress.img");
+        fs.superblock.comp_enabled = 0;
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "d").unwrap();
+        let data = b"recompress me ".repeat(2000);
+        let a = fs.create_file(dir, "a").unwrap();
+        fs.write_file(a, &data).unwrap();
+        let empty = fs.create_file(dir, "empty").unwrap();
+        let mtime = fs.read_inode(a).unwrap().i_mtime;
+        let free = fs.superblock.nr_free_blocks;
+
+        let stats = fs.recompress(LOLELFFS_ROOT_INO, LOLELFFS_COMP_LZ4).unwrap();
+        assert_eq!((stats.files, stats.skipped), (1, 1));
+        assert!(stats.blocks_after <= stats.blocks_before);
+        let extents = fs.file_extents(a).unwrap().unwrap();
+        assert!(extents
+            .iter()
+            .any(|e| e.ee_comp_algo == LOLELFFS_COMP_LZ4 as u16));
+        assert_eq!(fs.read_file(a).unwrap(), data);
+        assert_eq!(fs.read_inode(a).unwrap().i_mtime, mtime);
+        assert_eq!(fs.read_file(empty).unwrap(), b"");
+
+        // Back to uncompressed restores the original footprint
+        assert!(fs.recompress_file(a, LOLELFFS_COMP_NONE).unwrap());
+        assert!(!fs.recompress_file(a, LOLELFFS_COMP_NONE).unwrap());
+        assert_eq!(fs.superblock.nr_free_blocks, free);
+        assert_eq!(fs.read_file(a).unwrap(), data);
+    }
+
+    #[test]
+    fn test_set_compression() {
+        let (_path, mut fs) = temp_image("setcomp.img");
+        fs.set_comp_exclude(vec![glob::Pattern::new("*.raw").unwrap()])
+            .unwrap();
+        let data = b"per-file override ".repeat(1000);
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f.raw").unwrap();
+        fs.write_file(ino, &data).unwrap();
+        let algos = |fs: &mut LolelfFs| -> Vec<u16> {
+            let extents = fs.file_extents(ino).unwrap().unwrap();
+            extents.iter().map(|e| e.ee_comp_algo).collect()
+        };
+        assert_eq!(algos(&mut fs)[0], LOLELFFS_COMP_NONE as u16);
+
+        // The override beats the exclusion list, now and on later writes
+        assert!(fs.set_compression(ino, Some(LOLELFFS_COMP_ZLIB)).unwrap());
+        assert_eq!(algos(&mut fs)[0], LOLELFFS_COMP_ZLIB as u16);
+        fs.write_file(ino, &data).unwrap();
+        assert_eq!(algos(&mut fs)[0], LOLELFFS_COMP_ZLIB as u16);
+        assert_eq!(fs.read_file(ino).unwrap(), data);
+
+        // Image-wide migrations leave it alone
+        let stats = fs.recompress(LOLELFFS_ROOT_INO, LOLELFFS_COMP_LZ4).unwrap();
+        assert_eq!(stats.files, 0);
+
+        assert!(!fs.set_compression(ino, None).unwrap());
+        assert_eq!(fs.read_inode(ino).unwrap().comp_override(), None);
+        fs.write_file(ino, &data).unwrap();
+        assert_eq!(algos(&mut fs)[0], LOLELFFS_COMP_NONE as u16);
+    }
+}
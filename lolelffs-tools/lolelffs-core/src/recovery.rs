@@ -0,0 +1,174 @@
+//! Quick consistency pass after an unclean shutdown
+//!
+//! Every read-write handle sets `LOLELFFS_STATE_DIRTY` in the superblock
+//! when it opens the image and clears it when dropped, so a flag found set
+//! on open means the last writer crashed (or is still running). A full
+//! `fsck` walks the whole tree and can take far too long to run on every
+//! such open. `quick_check` does what fits in a time budget instead: it
+//! puts free counts that drifted from the bitmaps right, which is what an
+//! interrupted allocation usually leaves behind, and flags anything deeper
+//! for a real `fsck`. It never frees, allocates or moves anything.
+
+use crate::fs::{LolelfFs, OpenMode};
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+/// Outcome of `LolelfFs::quick_check`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuickCheckReport {
+    /// What was repaired
+    pub repaired: Vec<String>,
+    /// What needs a full `fsck`, including drift that could not be
+    /// repaired because the image is not writable
+    pub problems: Vec<String>,
+    /// Inodes whose block pointers were checked
+    pub inodes_checked: u32,
+    /// Whether every in-use inode was checked before the budget ran out
+    pub complete: bool,
+}
+
+impl QuickCheckReport {
+    /// Nothing left for `fsck` to look at, as far as the check got
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl LolelfFs {
+    /// Check the superblock and bitmaps, repair free count drift, then
+    /// check inodes' block pointers until `budget` is used up
+    pub fn quick_check(&mut self, budget: Duration) -> Result<QuickCheckReport> {
+        let deadline = Instant::now() + budget;
+        let mut report = QuickCheckReport::default();
+        let sb = self.superblock.clone();
+
+        // With a broken layout the bitmaps cannot be located
+        report.problems = sb.layout_issues();
+        if !report.problems.is_empty() {
+            return Ok(report);
+        }
+
+        let free_inodes = self.count_set_bits(sb.ifree_bitmap_start(), sb.nr_inodes)?;
+        let free_blocks = self.count_set_bits(sb.bfree_bitmap_start(), sb.nr_blocks)?;
+        let drift = [
+            ("inodes", sb.nr_free_inodes, free_inodes),
+            ("blocks", sb.nr_free_blocks, free_blocks),
+        ];
+        for (what, counted, actual) in drift {
+            if counted == actual {
+                continue;
+            }
+            let message = format!(
+                "superblock counted {} free {}, the bitmap has {}",
+                counted, what, actual
+            );
+            if self.mode == OpenMode::ReadWrite {
+                report.repaired.push(message);
+            } else {
+                report.problems.push(message);
+            }
+        }
+        if !report.repaired.is_empty() {
+            self.superblock.nr_free_inodes = free_inodes;
+            self.superblock.nr_free_blocks = free_blocks;
+            self.write_superblock()?;
+        }
+
+        let free_meta = self.count_set_bits(sb.bfree_bitmap_start(), sb.data_block_start())?;
+        if free_meta > 0 {
+            report
+                .problems
+                .push(format!("{} metadata blocks are marked free", free_meta));
+        }
+        if let Err(e) = self.orphans() {
+            report.problems.push(format!("{:#}", e));
+        }
+
+        let data_start = sb.data_block_start();
+        for inode_num in 0..sb.nr_inodes {
+            if Instant::now() >= deadline {
+                return Ok(report);
+            }
+            if self.is_inode_free(inode_num)? {
+                continue;
+            }
+            let inode = self.read_inode(inode_num)?;
+            // Symlinks keep their target inline
+            let ei_block = if inode.is_symlink() {
+                0
+            } else {
+                inode.ei_block
+            };
+            let pointers = [("extent index", ei_block), ("xattr", inode.xattr_block)];
+            for (what, block) in pointers {
+                if block == 0 {
+                    continue;
+                }
+                if block < data_start || block >= sb.nr_blocks {
+                    report.problems.push(format!(
+                        "inode {}: {} block {} is outside the data area",
+                        inode_num, what, block
+                    ));
+                } else if self.is_block_free(block)? {
+                    report.problems.push(format!(
+                        "inode {}: {} block {} is marked free",
+                        inode_num, what, block
+                    ));
+                }
+            }
+            report.inodes_checked += 1;
+        }
+        report.complete = true;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::OpenOptions;
+    use crate::testutil::temp_image;
+    use crate::types::*;
+
+    #[test]
+    fn test_auto_fsck_after_unclean_close() {
+        let (path, mut fs) = temp_image("recovery.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        fs.write_file(ino, b"before the crash").unwrap();
+        drop(fs);
+
+        let options = OpenOptions {
+            auto_fsck: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let mut fs = LolelfFs::open_with_options(&path, &options).unwrap();
+        assert!(!fs.opened_unclean());
+        assert!(fs.auto_fsck_report().is_none());
+
+        // Crash with the free count out of step with the bitmap
+        let free = fs.superblock.nr_free_blocks;
+        fs.superblock.nr_free_blocks -= 3;
+        fs.write_superblock().unwrap();
+        std::mem::forget(fs);
+
+        let mut fs = LolelfFs::open_with_options(&path, &options).unwrap();
+        assert!(fs.opened_unclean());
+        let report = fs.auto_fsck_report().unwrap().clone();
+        assert!(report.complete);
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.repaired.len(), 1);
+        assert_eq!(fs.superblock.nr_free_blocks, free);
+
+        // Deeper damage is only flagged
+        let mut inode = fs.read_inode(ino).unwrap();
+        inode.xattr_block = 1;
+        fs.write_inode(ino, &inode).unwrap();
+        let report = fs.quick_check(Duration::from_secs(60)).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.repaired.is_empty());
+        drop(fs);
+
+        let fs = LolelfFs::open_readonly(&path).unwrap();
+        assert!(!fs.opened_unclean());
+    }
+}
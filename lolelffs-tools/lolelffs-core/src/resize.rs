@@ -0,0 +1,219 @@
+//! Resizing lolelffs images
+//!
+//! The block bitmap sits directly before the data area, so when growing needs
+//! more bitmap blocks, the first data blocks are evacuated to make room; when
+//! shrinking, extents past the new end are moved below it and bitmap blocks no
+//! longer needed become data blocks.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+impl LolelfFs {
+    /// Grow the filesystem to `new_size` bytes, returning the blocks added
+    ///
+    /// The backing file is extended and the new blocks are marked free; the
+    /// inode count is unchanged.
+    pub fn grow(&mut self, new_size: u64) -> Result<u32> {
+        self.ensure_writable()?;
+
+        let old_blocks = self.superblock.nr_blocks;
+        let new_blocks = new_size / LOLELFFS_BLOCK_SIZE as u64;
+        if new_blocks > u32::MAX as u64 {
+            bail!(
+                "Size {} exceeds the maximum of {} blocks",
+                new_size,
+                u32::MAX
+            );
+        }
+        let new_blocks = new_blocks as u32;
+        if new_blocks <= old_blocks {
+            bail!(
+                "New size {} is not larger than the current {} bytes",
+                new_size,
+                old_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64
+            );
+        }
+
+        let old = Layout::with_inodes(old_blocks, self.superblock.nr_inodes);
+        let new = Layout::with_inodes(new_blocks, self.superblock.nr_inodes);
+        let bitmap_growth = new.nr_bfree_blocks - old.nr_bfree_blocks;
+
+        // Make room for the extra bitmap blocks before the image changes size,
+        // so running out of space leaves the filesystem as it was
+        let data_start = old.data_block_start();
+        if bitmap_growth > 0 {
+            self.evacuate(data_start..data_start + bitmap_growth)?;
+        }
+
+        self.set_image_len(new_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64)?;
+
+        // New bitmap blocks start out all-used, then the added blocks are freed
+        let zero = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        for block_num in data_start..data_start + bitmap_growth {
+            self.write_block(block_num, &zero)?;
+        }
+        self.superblock.nr_blocks = new_blocks;
+        self.superblock.nr_bfree_blocks = new.nr_bfree_blocks;
+        if self.is_opaque() {
+            self.zero_opaque_blocks(old_blocks..new_blocks)?;
+        }
+        self.mark_blocks(old_blocks, new_blocks - old_blocks, true)?;
+        self.superblock.nr_free_blocks += new_blocks - old_blocks;
+        self.write_superblock()?;
+
+        Ok(new_blocks - old_blocks)
+    }
+
+    /// Shrink the filesystem to `new_size` bytes, returning the blocks removed
+    ///
+    /// Everything stored past the new end is relocated first. Refuses when
+    /// the blocks in use would not fit; the inode count is unchanged.
+    pub fn shrink(&mut self, new_size: u64) -> Result<u32> {
+        self.ensure_writable()?;
+
+        let old_blocks = self.superblock.nr_blocks;
+        if new_size >= old_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64 {
+            bail!(
+                "New size {} is not smaller than the current {} bytes",
+                new_size,
+                old_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64
+            );
+        }
+        let new_blocks = (new_size / LOLELFFS_BLOCK_SIZE as u64) as u32;
+
+        let old = Layout::with_inodes(old_blocks, self.superblock.nr_inodes);
+        let new = Layout::with_inodes(new_blocks, self.superblock.nr_inodes);
+        if new_blocks <= old.data_block_start() {
+            bail!("Size {} leaves no room for data", new_size);
+        }
+
+        let used = old_blocks - old.data_block_start() - self.superblock.nr_free_blocks;
+        let capacity = new_blocks - new.data_block_start();
+        if used > capacity {
+            bail!(
+                "{} data blocks are in use, but only {} fit in {} bytes",
+                used,
+                capacity,
+                new_size
+            );
+        }
+
+        self.evacuate(new_blocks..old_blocks)?;
+
+        // Bits past the end read as used, as on a freshly created image
+        self.mark_blocks(
+            new_blocks,
+            new.nr_bfree_blocks * LOLELFFS_BITS_PER_BLOCK - new_blocks,
+            false,
+        )?;
+        self.superblock.nr_blocks = new_blocks;
+        self.superblock.nr_bfree_blocks = new.nr_bfree_blocks;
+
+        let released_bitmap = old.nr_bfree_blocks - new.nr_bfree_blocks;
+        self.mark_blocks(new.data_block_start(), released_bitmap, true)?;
+        self.superblock.nr_free_blocks += released_bitmap;
+        self.write_superblock()?;
+
+        self.set_image_len(new_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64)?;
+
+        Ok(old_blocks - new_blocks)
+    }
+
+    /// Smallest size in bytes `shrink` accepts: room for the data blocks in
+    /// use and the metadata of the current inode count
+    pub fn min_size(&self) -> u64 {
+        let nr_inodes = self.superblock.nr_inodes;
+        let layout = Layout::with_inodes(self.superblock.nr_blocks, nr_inodes);
+        let used =
+            self.superblock.nr_blocks - layout.data_block_start() - self.superblock.nr_free_blocks;
+
+        // Fewer blocks can only mean fewer bitmap blocks, so start from the
+        // bitmap size for the data alone and grow into the fit
+        let mut blocks =
+            used.max(1) + Layout::with_inodes(used.max(1), nr_inodes).data_block_start();
+        while blocks - Layout::with_inodes(blocks, nr_inodes).data_block_start() < used.max(1) {
+            blocks += 1;
+        }
+        blocks.max(LOLELFFS_MIN_BLOCKS) as u64 * LOLELFFS_BLOCK_SIZE as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_image, TempPath};
+
+    #[test]
+    fn test_grow_adds_bitmap_blocks() {
+        let (path, mut fs) = temp_image("resize.img");
+        fs.superblock.comp_enabled = 0;
+
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "dir").unwrap();
+        let file = fs.create_file(dir, "data").unwrap();
+        let content: Vec<u8> = (0..20000u32).map(|i| (i % 253) as u8).collect();
+        fs.write_file(file, &content).unwrap();
+        fs.set_xattr(file, "user.tag", b"kept").unwrap();
+
+        // 160M needs a second bitmap block, displacing the first data block
+        let old_start = fs.superblock.data_block_start();
+        let added = fs.grow(160 * 1024 * 1024).unwrap();
+        assert_eq!(added, 40960 - 1024);
+        assert_eq!(fs.superblock.nr_bfree_blocks, 2);
+        assert_eq!(fs.superblock.data_block_start(), old_start + 1);
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 160 * 1024 * 1024);
+        let file = fs.resolve_path("/dir/data").unwrap();
+        assert_eq!(fs.read_file(file).unwrap(), content);
+        assert_eq!(fs.get_xattr(file, "user.tag").unwrap(), b"kept");
+
+        let referenced = fs.referenced_blocks().unwrap();
+        assert!(referenced
+            .iter()
+            .all(|&b| b >= fs.superblock.data_block_start()));
+        let free = (fs.superblock.data_block_start()..fs.superblock.nr_blocks)
+            .filter(|&b| fs.is_block_free(b).unwrap())
+            .count() as u32;
+        assert_eq!(free, fs.superblock.nr_free_blocks);
+
+        // The added space is usable
+        let big = fs.create_file(LOLELFFS_ROOT_INO, "big").unwrap();
+        fs.write_file(big, &vec![7u8; 8 * 1024 * 1024]).unwrap();
+    }
+
+    #[test]
+    fn test_shrink_relocates_tail() {
+        let path = TempPath::new("shrink.img");
+        let mut fs = LolelfFs::create(&path, 8 * 1024 * 1024).unwrap();
+        fs.superblock.comp_enabled = 0;
+
+        // Push a file towards the end of the image, then free the space below it
+        let filler = fs.create_file(LOLELFFS_ROOT_INO, "filler").unwrap();
+        fs.write_file(filler, &vec![1u8; 5 * 1024 * 1024]).unwrap();
+        let keep = fs.create_file(LOLELFFS_ROOT_INO, "keep").unwrap();
+        let content: Vec<u8> = (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        fs.write_file(keep, &content).unwrap();
+        fs.unlink(LOLELFFS_ROOT_INO, "filler").unwrap();
+
+        assert!(fs.shrink(1024 * 1024).is_err());
+        let removed = fs.shrink(3 * 1024 * 1024).unwrap();
+        assert_eq!(removed, 1280);
+        assert_eq!(fs.read_file(keep).unwrap(), content);
+
+        // Growing and shrinking back across a bitmap block boundary
+        fs.grow(160 * 1024 * 1024).unwrap();
+        fs.shrink(4 * 1024 * 1024).unwrap();
+        assert_eq!(fs.superblock.nr_bfree_blocks, 1);
+        drop(fs);
+
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 1024 * 1024);
+        assert_eq!(fs.read_file(keep).unwrap(), content);
+        let free = (fs.superblock.data_block_start()..fs.superblock.nr_blocks)
+            .filter(|&b| fs.is_block_free(b).unwrap())
+            .count() as u32;
+        assert_eq!(free, fs.superblock.nr_free_blocks);
+    }
+}
@@ -0,0 +1,132 @@
+//! Secure deletion
+//!
+//! `unlink` only clears bitmap bits: the data, the extent index and the stale
+//! inode stay on disk until reused, which is exactly what `undelete` and
+//! `carve` look for. `secure_unlink` first overwrites the file's data and
+//! xattr blocks with random bytes, syncing after every pass, then unlinks it
+//! and zeroes the index blocks and inode slot so nothing points back at the
+//! old content.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use rand::RngCore;
+
+impl LolelfFs {
+    /// Overwrite a file's blocks `passes` times with random data, then unlink
+    /// it. Returns the number of data and xattr blocks overwritten.
+    ///
+    /// Files with other hard links are refused, since their data would be
+    /// destroyed along with this name.
+    pub fn secure_unlink(&mut self, parent_inode_num: u32, name: &str, passes: u32) -> Result<u32> {
+        self.ensure_writable()?;
+        if passes == 0 {
+            bail!("At least one overwrite pass is needed");
+        }
+
+        let inode_num = self
+            .lookup(parent_inode_num, name)?
+            .ok_or_else(|| anyhow::anyhow!("File '{}' not found", name))?;
+        let inode = self.read_inode(inode_num)?;
+        if inode.is_dir() {
+            bail!("Cannot shred directory '{}'", name);
+        }
+        if inode.i_nlink > 1 {
+            bail!(
+                "'{}' has {} other hard links; remove them first",
+                name,
+                inode.i_nlink - 1
+            );
+        }
+
+        let mut blocks = Vec::new();
+        if inode.ei_block != 0 {
+            let ei = self.read_extent_index(&inode)?;
+            for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+                // Blocks another file shares are that file's contents too
+                if extent.is_shared()
+                    && !self.extent_sharers(inode_num, extent.ee_start)?.is_empty()
+                {
+                    continue;
+                }
+                blocks.extend(extent.ee_start..extent.ee_start + extent.ee_len);
+            }
+        }
+        if inode.xattr_block != 0 {
+            let index = crate::xattr::read_xattr_index(self, inode.xattr_block)?;
+            for extent in index.extents.iter().take_while(|e| !e.is_empty()) {
+                blocks.extend(extent.ee_start..extent.ee_start + extent.ee_len);
+            }
+        }
+
+        let mut noise = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        let mut rng = rand::thread_rng();
+        for _ in 0..passes {
+            for &block_num in &blocks {
+                rng.fill_bytes(&mut noise);
+                self.write_block(block_num, &noise)?;
+            }
+            // Each pass must reach the device, not just replace the previous
+            // one in the page cache
+            self.flush()?;
+        }
+
+        self.unlink(parent_inode_num, name)?;
+
+        // Leave nothing for undelete to follow
+        let zero = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+        for index_block in [inode.ei_block, inode.xattr_block] {
+            if index_block != 0 {
+                self.write_block(index_block, &zero)?;
+            }
+        }
+        let mut stale = inode;
+        stale.i_size = 0;
+        stale.i_blocks = 0;
+        stale.ei_block = 0;
+        stale.xattr_block = 0;
+        stale.i_data = [0u8; 28];
+        self.write_inode(inode_num, &stale)?;
+        self.flush()?;
+
+        Ok(blocks.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_secure_unlink() {
+        let (path, mut fs) = temp_image("shred.img");
+        fs.superblock.comp_enabled = 0;
+
+        let secret = b"hunter2-correct-horse".repeat(1000);
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "secret").unwrap();
+        fs.write_file(ino, &secret).unwrap();
+        fs.set_xattr(ino, "user.note", b"xattr-secret-value")
+            .unwrap();
+        let linked = fs.create_file(LOLELFFS_ROOT_INO, "linked").unwrap();
+        fs.link(linked, LOLELFFS_ROOT_INO, "other").unwrap();
+        let free_before = fs.superblock.nr_free_blocks;
+
+        assert!(fs.secure_unlink(LOLELFFS_ROOT_INO, "linked", 1).is_err());
+        assert!(fs.secure_unlink(LOLELFFS_ROOT_INO, "secret", 0).is_err());
+        // Six data blocks and one xattr data block; both index blocks are
+        // freed as well
+        let shredded = fs.secure_unlink(LOLELFFS_ROOT_INO, "secret", 2).unwrap();
+        assert_eq!(shredded, 7);
+        assert!(fs.lookup(LOLELFFS_ROOT_INO, "secret").unwrap().is_none());
+        assert_eq!(fs.superblock.nr_free_blocks, free_before + 9);
+        let report = fs.fsck_full().unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        drop(fs);
+
+        let raw = std::fs::read(&path).unwrap();
+        for needle in [&b"hunter2-correct-horse"[..], b"xattr-secret-value"] {
+            assert!(!raw.windows(needle.len()).any(|w| w == needle));
+        }
+    }
+}
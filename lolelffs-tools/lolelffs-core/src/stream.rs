@@ -0,0 +1,324 @@
+//! Streaming access to file contents
+//!
+//! `read_file_to` pushes a whole file into a writer in one call. Code that
+//! wants to pull instead, such as the tar builder, a hasher or anything
+//! generic over `std::io::Read`, can open an `FsFileReader`. It decodes one
+//! block at a time as it is reached and keeps only that block, so memory
+//! stays constant however large the file is, and it seeks without decoding
+//! the blocks it skips.
+//!
+//! `FsFileWriter` is the other direction: writes collect in a buffer of
+//! `WRITER_BUFFER_SIZE` bytes that goes to the image through
+//! `write_file_at` whenever it fills, so `io::copy` from a multi-gigabyte
+//! source never holds more than that.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Bytes an `FsFileWriter` collects before writing them out
+pub const WRITER_BUFFER_SIZE: usize = 64 * LOLELFFS_BLOCK_SIZE as usize;
+
+/// `Read + Seek` over the contents of a regular file, from
+/// `LolelfFs::open_reader`
+///
+/// The size is taken when the reader is opened; writing to the file while
+/// it is open is prevented by the borrow of the filesystem.
+pub struct FsFileReader<'a> {
+    fs: &'a mut LolelfFs,
+    inode_num: u32,
+    ei: ExtentIndex,
+    key: [u8; 32],
+    size: u64,
+    pos: u64,
+    /// The block `pos` was last in, decoded and padded to a full block
+    block: Option<(u32, Vec<u8>)>,
+}
+
+impl LolelfFs {
+    /// Open regular file `inode_num` for reading through `std::io::Read`
+    pub fn open_reader(&mut self, inode_num: u32) -> Result<FsFileReader<'_>> {
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            bail!("Inode {} is not a regular file", inode_num);
+        }
+        let (ei, size) = if inode.ei_block == 0 {
+            (
+                ExtentIndex {
+                    nr_files: 0,
+                    extents: Vec::new(),
+                },
+                0,
+            )
+        } else {
+            (
+                self.readable_extent_index(inode_num, &inode)?,
+                inode.i_size as u64,
+            )
+        };
+        let key = self.file_key(inode_num, &inode);
+
+        Ok(FsFileReader {
+            fs: self,
+            inode_num,
+            ei,
+            key,
+            size,
+            pos: 0,
+            block: None,
+        })
+    }
+}
+
+/// `Write + Seek` into a regular file, from `LolelfFs::open_writer`
+///
+/// Writes are buffered; dropping the writer writes what is left but
+/// ignores errors, so call `flush` to see them.
+pub struct FsFileWriter<'a> {
+    fs: &'a mut LolelfFs,
+    inode_num: u32,
+    /// Where the next write goes
+    pos: u64,
+    /// Bytes not written to the image yet, which belong at `buf_start`
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl LolelfFs {
+    /// Open regular file `inode_num` for writing through `std::io::Write`,
+    /// starting at its first byte
+    ///
+    /// Existing contents are overwritten where written to and kept
+    /// elsewhere; `truncate` the file first to replace it.
+    pub fn open_writer(&mut self, inode_num: u32) -> Result<FsFileWriter<'_>> {
+        self.ensure_writable()?;
+        if !self.read_inode(inode_num)?.is_file() {
+            bail!("Inode {} is not a regular file", inode_num);
+        }
+        Ok(FsFileWriter {
+            fs: self,
+            inode_num,
+            pos: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+        })
+    }
+}
+
+impl FsFileWriter<'_> {
+    /// Inode being written
+    pub fn inode_num(&self) -> u32 {
+        self.inode_num
+    }
+
+    /// Write out the buffer, or with `whole_blocks` only up to the last
+    /// block boundary it covers, so the next write does not have to
+    /// decode a partial block again
+    fn write_buffer(&mut self, whole_blocks: bool) -> Result<()> {
+        let block_size = LOLELFFS_BLOCK_SIZE as u64;
+        let end = self.buf_start + self.buf.len() as u64;
+        let upto = if whole_blocks {
+            end / block_size * block_size
+        } else {
+            end
+        };
+        if upto <= self.buf_start {
+            return Ok(());
+        }
+        let n = (upto - self.buf_start) as usize;
+        self.fs
+            .write_file_at(self.inode_num, self.buf_start, &self.buf[..n])?;
+        self.buf.drain(..n);
+        self.buf_start = upto;
+        Ok(())
+    }
+}
+
+impl Write for FsFileWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            self.buf_start = self.pos;
+        } else if self.pos != self.buf_start + self.buf.len() as u64 {
+            self.write_buffer(false).map_err(io::Error::other)?;
+            self.buf_start = self.pos;
+        }
+        let n = data.len().min(WRITER_BUFFER_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        self.pos += n as u64;
+        if self.buf.len() >= WRITER_BUFFER_SIZE {
+            self.write_buffer(true).map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer(false).map_err(io::Error::other)
+    }
+}
+
+impl Seek for FsFileWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => {
+                let inode = self
+                    .fs
+                    .read_inode(self.inode_num)
+                    .map_err(io::Error::other)?;
+                (inode.i_size as u64, delta)
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+        };
+        self.pos = seek_position(base, delta)?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for FsFileWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.write_buffer(false);
+    }
+}
+
+/// `base` moved by `delta`, refusing to go below zero
+fn seek_position(base: u64, delta: i64) -> io::Result<u64> {
+    base.checked_add_signed(delta).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "seek to a negative or overflowing position",
+        )
+    })
+}
+
+impl FsFileReader<'_> {
+    /// Inode being read
+    pub fn inode_num(&self) -> u32 {
+        self.inode_num
+    }
+
+    /// File size in bytes when the reader was opened
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for FsFileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = LOLELFFS_BLOCK_SIZE as u64;
+        let logical_block = (self.pos / block_size) as u32;
+        if self.block.as_ref().map(|(num, _)| *num) != Some(logical_block) {
+            let mut block = self
+                .fs
+                .read_file_block(self.inode_num, &self.ei, &self.key, logical_block)
+                .map_err(io::Error::other)?;
+            block.resize(LOLELFFS_BLOCK_SIZE as usize, 0);
+            self.block = Some((logical_block, block));
+        }
+        let block = &self.block.as_ref().unwrap().1;
+
+        let block_start = logical_block as u64 * block_size;
+        let from = (self.pos - block_start) as usize;
+        let to = (self.size - block_start).min(block_size) as usize;
+        let n = (to - from).min(buf.len());
+        buf[..n].copy_from_slice(&block[from..from + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FsFileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.size, delta),
+            SeekFrom::Current(delta) => (self.pos, delta),
+        };
+        self.pos = seek_position(base, delta)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_open_reader() {
+        let (_path, mut fs) = temp_image("stream.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let data: Vec<u8> = (0..3 * LOLELFFS_BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs.write_file(ino, &data).unwrap();
+
+        // Small reads never cross a block, so this takes many calls
+        let mut reader = fs.open_reader(ino).unwrap();
+        let mut streamed = Vec::new();
+        let mut buf = [0u8; 1000];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            streamed.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(streamed, data);
+
+        let bs = LOLELFFS_BLOCK_SIZE as u64;
+        reader.seek(SeekFrom::Start(bs - 3)).unwrap();
+        let mut six = [0u8; 6];
+        reader.read_exact(&mut six).unwrap();
+        assert_eq!(six, data[bs as usize - 3..bs as usize + 3]);
+        assert_eq!(
+            reader.seek(SeekFrom::End(-10)).unwrap(),
+            data.len() as u64 - 10
+        );
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[data.len() - 10..]);
+        assert!(reader
+            .seek(SeekFrom::Current(-(data.len() as i64) - 1))
+            .is_err());
+        drop(reader);
+
+        assert!(fs.open_reader(LOLELFFS_ROOT_INO).is_err());
+    }
+
+    #[test]
+    fn test_open_writer() {
+        let (_path, mut fs) = temp_image("writer.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let data: Vec<u8> = b"streamed in odd-sized pieces ".repeat(20_000);
+
+        // More than the buffer, in writes that never line up with blocks
+        let mut writer = fs.open_writer(ino).unwrap();
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.seek(SeekFrom::Start(10)).unwrap();
+        writer.write_all(b"PATCHED").unwrap();
+        assert_eq!(writer.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        writer.write_all(b"!").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut expected = data.clone();
+        expected[10..17].copy_from_slice(b"PATCHED");
+        expected.push(b'!');
+        assert!(fs.read_file(ino).unwrap() == expected);
+        // Appends grew the extents rather than adding one per buffer
+        assert!(fs.file_extents(ino).unwrap().unwrap().len() < 8);
+    }
+}
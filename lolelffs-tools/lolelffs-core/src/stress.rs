@@ -0,0 +1,605 @@
+//! Concurrent mixed-workload stress testing
+//!
+//! `run_stress` starts several worker threads that create, write, read,
+//! delete, rename and list files in one shared directory until a deadline.
+//! Each worker only touches names it created (`w<worker>-<n>`), so it can
+//! check every read and listing against what it last wrote while still
+//! contending with the other workers for the directory and the allocator.
+//! Workers go either through a shared library handle, locked the same way the
+//! FUSE daemon locks it, or through the files of a mounted image.
+
+use crate::error::FsError;
+use crate::fs::LolelfFs;
+use anyhow::{anyhow, bail, Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most error messages kept in a report; the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// Operations a worker performs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StressOp {
+    Create,
+    Write,
+    Read,
+    Delete,
+    Rename,
+    List,
+}
+
+impl StressOp {
+    pub const ALL: [StressOp; 6] = [
+        StressOp::Create,
+        StressOp::Write,
+        StressOp::Read,
+        StressOp::Delete,
+        StressOp::Rename,
+        StressOp::List,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StressOp::Create => "create",
+            StressOp::Write => "write",
+            StressOp::Read => "read",
+            StressOp::Delete => "delete",
+            StressOp::Rename => "rename",
+            StressOp::List => "list",
+        }
+    }
+
+    fn index(&self) -> usize {
+        StressOp::ALL.iter().position(|op| op == self).unwrap_or(0)
+    }
+}
+
+/// Relative weights of the operations, parsed from `create=2,read=5,...`
+///
+/// Operations not named in the string get weight 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StressMix {
+    weights: [u32; 6],
+}
+
+impl Default for StressMix {
+    fn default() -> Self {
+        StressMix {
+            weights: [20, 30, 30, 10, 5, 5],
+        }
+    }
+}
+
+impl FromStr for StressMix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut weights = [0; 6];
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let Some((name, weight)) = part.split_once('=') else {
+                bail!("Invalid mix entry '{}' (expected op=weight)", part);
+            };
+            let Some(op) = StressOp::ALL.iter().find(|op| op.name() == name) else {
+                bail!(
+                    "Unknown operation '{}' (create, write, read, delete, rename, list)",
+                    name
+                );
+            };
+            weights[op.index()] = weight
+                .parse()
+                .map_err(|_| anyhow!("Invalid weight '{}' for {}", weight, name))?;
+        }
+        if weights[StressOp::Create.index()] == 0 {
+            bail!("The mix needs a create weight, or workers have nothing to work on");
+        }
+        Ok(StressMix { weights })
+    }
+}
+
+impl StressMix {
+    fn pick(&self, rng: &mut StdRng) -> StressOp {
+        let total: u32 = self.weights.iter().sum();
+        let mut roll = rng.gen_range(0..total);
+        for (op, &weight) in StressOp::ALL.iter().zip(&self.weights) {
+            if roll < weight {
+                return *op;
+            }
+            roll -= weight;
+        }
+        StressOp::Create
+    }
+}
+
+/// Parameters of a stress run
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    /// Worker threads
+    pub threads: usize,
+    /// How long workers keep issuing operations
+    pub duration: Duration,
+    /// Seed for the workers' random choices; worker `i` uses `seed + i`
+    pub seed: u64,
+    /// Largest file a worker writes, in bytes
+    pub max_file_size: usize,
+    /// Most files a worker keeps at once
+    pub max_files: usize,
+    pub mix: StressMix,
+}
+
+impl Default for StressOptions {
+    fn default() -> Self {
+        StressOptions {
+            threads: 4,
+            duration: Duration::from_secs(10),
+            seed: 0,
+            max_file_size: 64 * 1024,
+            max_files: 32,
+            mix: StressMix::default(),
+        }
+    }
+}
+
+/// Outcome of a stress run
+#[derive(Debug, Clone, Default)]
+pub struct StressReport {
+    /// Completed operations, indexed like `StressOp::ALL`
+    pub ops: [u64; 6],
+    /// Operations refused for lack of space, which are expected on a full image
+    pub no_space: u64,
+    /// Operations that failed for any other reason
+    pub failures: u64,
+    /// Reads or listings that disagreed with what the worker had written
+    pub mismatches: u64,
+    /// The first failures and mismatches, prefixed with the worker
+    pub errors: Vec<String>,
+    pub elapsed: Duration,
+}
+
+impl StressReport {
+    /// Completed operations of one kind
+    pub fn count(&self, op: StressOp) -> u64 {
+        self.ops[op.index()]
+    }
+
+    /// Completed operations of all kinds
+    pub fn total_ops(&self) -> u64 {
+        self.ops.iter().sum()
+    }
+
+    /// Completed operations per second
+    pub fn ops_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.total_ops() as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether the run found inconsistencies or unexpected failures
+    pub fn is_clean(&self) -> bool {
+        self.failures == 0 && self.mismatches == 0
+    }
+
+    fn merge(&mut self, other: StressReport) {
+        for (total, n) in self.ops.iter_mut().zip(other.ops) {
+            *total += n;
+        }
+        self.no_space += other.no_space;
+        self.failures += other.failures;
+        self.mismatches += other.mismatches;
+        for error in other.errors {
+            if self.errors.len() < MAX_REPORTED_ERRORS {
+                self.errors.push(error);
+            }
+        }
+    }
+}
+
+/// Where workers send their operations
+pub enum StressTarget {
+    /// A directory (by inode number) of a shared library handle
+    Image { fs: Arc<Mutex<LolelfFs>>, dir: u32 },
+    /// A directory inside a mounted image
+    Mount(PathBuf),
+}
+
+impl StressTarget {
+    fn lock(fs: &Mutex<LolelfFs>) -> std::sync::MutexGuard<'_, LolelfFs> {
+        // A worker that panicked mid-operation is itself worth reporting, so
+        // keep going with whatever state it left
+        fs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn mount_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(name)
+    }
+
+    fn create(&self, name: &str) -> Result<()> {
+        match self {
+            StressTarget::Image { fs, dir } => {
+                Self::lock(fs).create_file(*dir, name)?;
+            }
+            StressTarget::Mount(dir) => {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(Self::mount_path(dir, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        match self {
+            StressTarget::Image { fs, dir } => {
+                let mut fs = Self::lock(fs);
+                let inode_num = fs
+                    .lookup(*dir, name)?
+                    .ok_or_else(|| anyhow!("'{}' not found", name))?;
+                fs.write_file(inode_num, data)?;
+            }
+            StressTarget::Mount(dir) => std::fs::write(Self::mount_path(dir, name), data)?,
+        }
+        Ok(())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        match self {
+            StressTarget::Image { fs, dir } => {
+                let mut fs = Self::lock(fs);
+                let inode_num = fs
+                    .lookup(*dir, name)?
+                    .ok_or_else(|| anyhow!("'{}' not found", name))?;
+                fs.read_file(inode_num)
+            }
+            StressTarget::Mount(dir) => Ok(std::fs::read(Self::mount_path(dir, name))?),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        match self {
+            StressTarget::Image { fs, dir } => Self::lock(fs).unlink(*dir, name),
+            StressTarget::Mount(dir) => Ok(std::fs::remove_file(Self::mount_path(dir, name))?),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        match self {
+            StressTarget::Image { fs, dir } => Self::lock(fs).rename(*dir, from, *dir, to),
+            StressTarget::Mount(dir) => Ok(std::fs::rename(
+                Self::mount_path(dir, from),
+                Self::mount_path(dir, to),
+            )?),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        match self {
+            StressTarget::Image { fs, dir } => Ok(Self::lock(fs)
+                .list_dir(*dir)?
+                .into_iter()
+                .map(|entry| entry.filename)
+                .collect()),
+            StressTarget::Mount(dir) => {
+                let mut names = Vec::new();
+                for entry in std::fs::read_dir(dir)? {
+                    names.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+                Ok(names)
+            }
+        }
+    }
+}
+
+/// Whether an error means the filesystem is full rather than broken
+fn is_no_space(err: &anyhow::Error) -> bool {
+    if let Some(FsError::NoSpace { .. }) = err.downcast_ref::<FsError>() {
+        return true;
+    }
+    // ENOSPC through a mount
+    err.downcast_ref::<std::io::Error>()
+        .and_then(|e| e.raw_os_error())
+        .is_some_and(|code| code == 28)
+}
+
+/// One worker's view of the files it owns
+struct Worker<'a> {
+    id: usize,
+    target: &'a StressTarget,
+    options: &'a StressOptions,
+    rng: StdRng,
+    /// Expected contents by name
+    files: HashMap<String, Vec<u8>>,
+    next_name: u64,
+    report: StressReport,
+}
+
+impl Worker<'_> {
+    fn new_name(&mut self) -> String {
+        self.next_name += 1;
+        format!("w{}-{}", self.id, self.next_name)
+    }
+
+    fn some_file(&mut self) -> Option<String> {
+        if self.files.is_empty() {
+            return None;
+        }
+        let idx = self.rng.gen_range(0..self.files.len());
+        self.files.keys().nth(idx).cloned()
+    }
+
+    fn fail(&mut self, what: String, err: anyhow::Error) {
+        if is_no_space(&err) {
+            self.report.no_space += 1;
+            return;
+        }
+        self.report.failures += 1;
+        if self.report.errors.len() < MAX_REPORTED_ERRORS {
+            self.report
+                .errors
+                .push(format!("worker {}: {}: {:#}", self.id, what, err));
+        }
+    }
+
+    fn mismatch(&mut self, message: String) {
+        self.report.mismatches += 1;
+        if self.report.errors.len() < MAX_REPORTED_ERRORS {
+            self.report
+                .errors
+                .push(format!("worker {}: {}", self.id, message));
+        }
+    }
+
+    fn step(&mut self) {
+        let mut op = self.options.mix.pick(&mut self.rng);
+        if op == StressOp::Create && self.files.len() >= self.options.max_files {
+            op = StressOp::Delete;
+        }
+        // Everything but create and list needs a file to work on
+        let name = match op {
+            StressOp::Create | StressOp::List => None,
+            _ => match self.some_file() {
+                Some(name) => Some(name),
+                None => {
+                    op = StressOp::Create;
+                    None
+                }
+            },
+        };
+
+        let done = match (op, name) {
+            (StressOp::Create, _) => {
+                let name = self.new_name();
+                match self.target.create(&name) {
+                    Ok(()) => {
+                        self.files.insert(name, Vec::new());
+                        true
+                    }
+                    Err(e) => {
+                        self.fail(format!("create {}", name), e);
+                        false
+                    }
+                }
+            }
+            (StressOp::Write, Some(name)) => {
+                let len = self.rng.gen_range(0..=self.options.max_file_size);
+                let mut data = vec![0u8; len];
+                self.rng.fill_bytes(&mut data);
+                match self.target.write(&name, &data) {
+                    Ok(()) => {
+                        self.files.insert(name, data);
+                        true
+                    }
+                    Err(e) => {
+                        // A failed write leaves the content unknown
+                        self.files.remove(&name);
+                        let _ = self.target.delete(&name);
+                        self.fail(format!("write {}", name), e);
+                        false
+                    }
+                }
+            }
+            (StressOp::Read, Some(name)) => match self.target.read(&name) {
+                Ok(data) => {
+                    let expected = &self.files[&name];
+                    if data != *expected {
+                        let message = format!(
+                            "read {}: got {} bytes that differ from the {} bytes written",
+                            name,
+                            data.len(),
+                            expected.len()
+                        );
+                        self.mismatch(message);
+                    }
+                    true
+                }
+                Err(e) => {
+                    self.fail(format!("read {}", name), e);
+                    false
+                }
+            },
+            (StressOp::Delete, Some(name)) => match self.target.delete(&name) {
+                Ok(()) => {
+                    self.files.remove(&name);
+                    true
+                }
+                Err(e) => {
+                    self.fail(format!("delete {}", name), e);
+                    false
+                }
+            },
+            (StressOp::Rename, Some(name)) => {
+                let new_name = self.new_name();
+                match self.target.rename(&name, &new_name) {
+                    Ok(()) => {
+                        let data = self.files.remove(&name).unwrap_or_default();
+                        self.files.insert(new_name, data);
+                        true
+                    }
+                    Err(e) => {
+                        self.fail(format!("rename {} to {}", name, new_name), e);
+                        false
+                    }
+                }
+            }
+            (StressOp::List, _) => match self.target.list() {
+                Ok(names) => {
+                    let prefix = format!("w{}-", self.id);
+                    let mut missing: Vec<&String> = self
+                        .files
+                        .keys()
+                        .filter(|name| !names.contains(name))
+                        .collect();
+                    missing.sort();
+                    let stray = names
+                        .iter()
+                        .filter(|name| name.starts_with(&prefix) && !self.files.contains_key(*name))
+                        .count();
+                    if !missing.is_empty() || stray > 0 {
+                        let message = format!(
+                            "list: {} files missing (first {:?}), {} unexpected",
+                            missing.len(),
+                            missing.first(),
+                            stray
+                        );
+                        self.mismatch(message);
+                    }
+                    true
+                }
+                Err(e) => {
+                    self.fail("list".to_string(), e);
+                    false
+                }
+            },
+            (_, None) => false,
+        };
+        if done {
+            self.report.ops[op.index()] += 1;
+        }
+    }
+}
+
+/// Run the workload against `target` until `options.duration` has passed
+///
+/// Failures inside the workload are collected in the report rather than
+/// returned; only a setup problem is an error. The caller is expected to
+/// check the image afterwards, e.g. with `LolelfFs::fsck_full`.
+pub fn run_stress(target: &StressTarget, options: &StressOptions) -> Result<StressReport> {
+    if options.threads == 0 {
+        bail!("Stress run needs at least one thread");
+    }
+    if let StressTarget::Mount(dir) = target {
+        if !dir.is_dir() {
+            bail!("'{}' is not a directory", dir.display());
+        }
+    }
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let reports = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.threads)
+            .map(|id| {
+                scope.spawn(move || {
+                    let mut worker = Worker {
+                        id,
+                        target,
+                        options,
+                        rng: StdRng::seed_from_u64(options.seed.wrapping_add(id as u64)),
+                        files: HashMap::new(),
+                        next_name: 0,
+                        report: StressReport::default(),
+                    };
+                    while Instant::now() < deadline {
+                        worker.step();
+                    }
+                    worker.report
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .collect::<Vec<_>>()
+    });
+
+    let mut report = StressReport::default();
+    for (id, result) in reports.into_iter().enumerate() {
+        match result {
+            Ok(worker) => report.merge(worker),
+            Err(_) => {
+                report.failures += 1;
+                report.errors.push(format!("worker {}: panicked", id));
+            }
+        }
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+impl LolelfFs {
+    /// Run a stress workload in directory `dir` (created if missing) of this
+    /// image, returning the image afterwards so it can be checked
+    pub fn stress(self, dir: &str, options: &StressOptions) -> Result<(Self, StressReport)> {
+        let mut fs = self;
+        let root = crate::types::LOLELFFS_ROOT_INO;
+        let dir_inode = match fs.lookup(root, dir)? {
+            Some(inode_num) => inode_num,
+            None => fs
+                .mkdir(root, dir)
+                .with_context(|| format!("Failed to create '/{}'", dir))?,
+        };
+        let target = StressTarget::Image {
+            fs: Arc::new(Mutex::new(fs)),
+            dir: dir_inode,
+        };
+        let report = run_stress(&target, options)?;
+        let StressTarget::Image { fs, .. } = target else {
+            unreachable!("target was built as an image");
+        };
+        let fs = Arc::try_unwrap(fs)
+            .map_err(|_| anyhow!("Stress workers still hold the image"))?
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        Ok((fs, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_stress_library_workload() {
+        assert!("create=1,bogus=2".parse::<StressMix>().is_err());
+        assert!("read=1".parse::<StressMix>().is_err());
+        let mix: StressMix = "create=3,write=3,read=3,delete=1,rename=1,list=1"
+            .parse()
+            .unwrap();
+
+        let path = TempPath::new("stress.img");
+        let fs = LolelfFs::create(&path, 4 * 1024 * 1024).unwrap();
+        let options = StressOptions {
+            threads: 4,
+            duration: Duration::from_millis(300),
+            seed: 7,
+            max_file_size: 16 * 1024,
+            max_files: 8,
+            mix,
+        };
+        let (mut fs, report) = fs.stress("stress", &options).unwrap();
+        assert!(report.is_clean(), "{:?}", report.errors);
+        assert!(report.count(StressOp::Create) > 0);
+        assert!(report.count(StressOp::Read) > 0);
+        assert!(report.total_ops() > 0);
+
+        let check = fs.fsck_full().unwrap();
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+    }
+}
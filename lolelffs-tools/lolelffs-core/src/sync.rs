@@ -0,0 +1,538 @@
+//! Synchronization into lolelffs images
+//!
+//! Keeps a destination image in lockstep with a source image (e.g. for A/B
+//! update schemes) or a host directory. Entries whose path and type match are
+//! updated in place so their destination inode numbers stay stable across
+//! syncs.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Options controlling an image-to-image sync
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Remove destination entries that are not present in the source
+    pub delete: bool,
+}
+
+/// Summary of the work performed by a sync
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// Entries created in the destination (new inode numbers)
+    pub created: u32,
+    /// Existing destination entries whose data or metadata was rewritten
+    pub updated: u32,
+    /// Existing destination entries that already matched the source
+    pub unchanged: u32,
+    /// Destination entries removed (only with `SyncOptions::delete`)
+    pub removed: u32,
+}
+
+/// Per-sync state shared across the recursive walk
+struct SyncState<'a> {
+    opts: &'a SyncOptions,
+    stats: SyncStats,
+    /// Source inode -> destination inode, used to preserve hard links
+    linked: HashMap<u32, u32>,
+    /// Source entries visited, for progress reporting
+    visited: u64,
+}
+
+impl LolelfFs {
+    /// Make this image's tree identical to `src`, reusing destination inodes
+    /// wherever the same path already exists with the same file type
+    pub fn sync_from(&mut self, src: &mut LolelfFs, opts: &SyncOptions) -> Result<SyncStats> {
+        let mut state = SyncState {
+            opts,
+            stats: SyncStats::default(),
+            linked: HashMap::new(),
+            visited: 0,
+        };
+
+        let src_root = src.read_inode(LOLELFFS_ROOT_INO)?;
+        if self.sync_metadata(src, LOLELFFS_ROOT_INO, &src_root, LOLELFFS_ROOT_INO)? {
+            state.stats.updated += 1;
+        }
+        self.sync_dir(src, LOLELFFS_ROOT_INO, LOLELFFS_ROOT_INO, &mut state)?;
+        self.report_progress("sync", state.visited, state.visited, "");
+
+        Ok(state.stats)
+    }
+
+    /// Synchronize the contents of one directory, recursing into subdirectories
+    fn sync_dir(
+        &mut self,
+        src: &mut LolelfFs,
+        src_dir: u32,
+        dst_dir: u32,
+        state: &mut SyncState,
+    ) -> Result<()> {
+        let src_entries = src.list_dir(src_dir)?;
+        let mut dst_entries = self.dir_entry_map(dst_dir)?;
+
+        for entry in &src_entries {
+            state.visited += 1;
+            self.report_progress("sync", state.visited, 0, &entry.filename);
+            let existing = dst_entries
+                .remove(&entry.filename)
+                .map(|e| (e.inode_num, e.inode));
+
+            // Additional names for an already-synced inode become hard links
+            if !entry.inode.is_dir() {
+                if let Some(&dst_ino) = state.linked.get(&entry.inode_num) {
+                    match existing {
+                        Some((ino, _)) if ino == dst_ino => state.stats.unchanged += 1,
+                        Some(_) => {
+                            self.remove_tree(dst_dir, &entry.filename)?;
+                            self.link(dst_ino, dst_dir, &entry.filename)?;
+                            state.stats.updated += 1;
+                        }
+                        None => {
+                            self.link(dst_ino, dst_dir, &entry.filename)?;
+                            state.stats.created += 1;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let same_type = |inode: &Inode| {
+                (inode.i_mode & mode::S_IFMT) == (entry.inode.i_mode & mode::S_IFMT)
+            };
+
+            let dst_ino = match existing {
+                Some((ino, dst_inode)) if same_type(&dst_inode) => {
+                    if self.sync_existing(src, entry.inode_num, &entry.inode, ino, &dst_inode)? {
+                        state.stats.updated += 1;
+                    } else {
+                        state.stats.unchanged += 1;
+                    }
+                    ino
+                }
+                Some(_) => {
+                    // Type changed: the old inode cannot be reused
+                    self.remove_tree(dst_dir, &entry.filename)?;
+                    state.stats.removed += 1;
+                    state.stats.created += 1;
+                    self.sync_create(src, entry.inode_num, &entry.inode, dst_dir, &entry.filename)?
+                }
+                None => {
+                    state.stats.created += 1;
+                    self.sync_create(src, entry.inode_num, &entry.inode, dst_dir, &entry.filename)?
+                }
+            };
+
+            if entry.inode.is_dir() {
+                self.sync_dir(src, entry.inode_num, dst_ino, state)?;
+            } else if entry.inode.i_nlink > 1 {
+                state.linked.insert(entry.inode_num, dst_ino);
+            }
+        }
+
+        if state.opts.delete {
+            for name in dst_entries.keys() {
+                self.remove_tree(dst_dir, name)?;
+                state.stats.removed += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create a destination entry mirroring a source inode
+    fn sync_create(
+        &mut self,
+        src: &mut LolelfFs,
+        src_ino: u32,
+        src_inode: &Inode,
+        dst_dir: u32,
+        name: &str,
+    ) -> Result<u32> {
+        let dst_ino = if src_inode.is_dir() {
+            self.mkdir(dst_dir, name)?
+        } else if src_inode.is_symlink() {
+            let target = src.read_file(src_ino)?;
+            self.symlink(dst_dir, name, &String::from_utf8_lossy(&target))?
+        } else {
+            let ino = self.create_file(dst_dir, name)?;
+            let data = src.read_file(src_ino)?;
+            self.write_file(ino, &data)?;
+            ino
+        };
+
+        self.sync_metadata(src, src_ino, src_inode, dst_ino)?;
+        Ok(dst_ino)
+    }
+
+    /// Bring an existing destination entry of the same type up to date.
+    /// Returns true if anything was rewritten.
+    fn sync_existing(
+        &mut self,
+        src: &mut LolelfFs,
+        src_ino: u32,
+        src_inode: &Inode,
+        dst_ino: u32,
+        dst_inode: &Inode,
+    ) -> Result<bool> {
+        let mut changed = false;
+
+        if src_inode.is_symlink() {
+            if src_inode.i_data != dst_inode.i_data {
+                let mut inode = dst_inode.clone();
+                inode.i_data = src_inode.i_data;
+                inode.i_size = src_inode.i_size;
+                self.write_inode(dst_ino, &inode)?;
+                changed = true;
+            }
+        } else if src_inode.is_file()
+            && (src_inode.i_size != dst_inode.i_size || src_inode.i_mtime != dst_inode.i_mtime)
+        {
+            let data = src.read_file(src_ino)?;
+            self.write_file(dst_ino, &data)?;
+            changed = true;
+        }
+
+        // Metadata is synced last since data writes bump the timestamps
+        changed |= self.sync_metadata(src, src_ino, src_inode, dst_ino)?;
+        Ok(changed)
+    }
+
+    /// Copy mode, ownership, timestamps, and xattrs from a source inode.
+    /// Returns true if the destination inode was modified.
+    fn sync_metadata(
+        &mut self,
+        src: &mut LolelfFs,
+        src_ino: u32,
+        src_inode: &Inode,
+        dst_ino: u32,
+    ) -> Result<bool> {
+        let mut changed = false;
+
+        // Xattrs first, as updating them touches ctime on the destination
+        let src_xattrs = read_all_xattrs(src, src_ino)?;
+        let dst_xattrs = read_all_xattrs(self, dst_ino)?;
+        if src_xattrs != dst_xattrs {
+            for (name, _) in &dst_xattrs {
+                self.remove_xattr(dst_ino, name)?;
+            }
+            for (name, value) in &src_xattrs {
+                self.set_xattr(dst_ino, name, value)?;
+            }
+            changed = true;
+        }
+
+        let mut inode = self.read_inode(dst_ino)?;
+        if inode.i_mode != src_inode.i_mode
+            || inode.i_uid != src_inode.i_uid
+            || inode.i_gid != src_inode.i_gid
+            || inode.i_atime != src_inode.i_atime
+            || inode.i_mtime != src_inode.i_mtime
+        {
+            inode.i_mode = src_inode.i_mode;
+            inode.i_uid = src_inode.i_uid;
+            inode.i_gid = src_inode.i_gid;
+            inode.i_atime = src_inode.i_atime;
+            inode.i_mtime = src_inode.i_mtime;
+            self.write_inode(dst_ino, &inode)?;
+            changed = true;
+        }
+
+        Ok(changed)
+    }
+
+    /// Mirror a host directory into `dst_dir`, rsync-style
+    ///
+    /// Files are only rewritten when their size or mtime differs from the
+    /// host's, so repeated syncs of a mostly unchanged tree are cheap. Entries
+    /// of a different type are replaced, and host files that are neither
+    /// regular files, directories, nor symlinks are skipped.
+    pub fn sync_from_host(
+        &mut self,
+        source: &Path,
+        dst_dir: u32,
+        opts: &SyncOptions,
+    ) -> Result<SyncStats> {
+        let mut stats = SyncStats::default();
+        self.sync_host_dir(source, dst_dir, opts, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// Image size in bytes that is sure to hold a copy of host directory
+    /// `source`
+    ///
+    /// Counts a block per 4 KiB of file contents as if nothing compressed,
+    /// plus each file's extent index and each directory's index and entry
+    /// blocks, with slack for fragmentation. `mkfs --size auto` creates an
+    /// image this large and shrinks it to `min_size` once it is filled.
+    pub fn size_for_host_tree(source: &Path) -> Result<u64> {
+        let used = host_tree_blocks(source)?;
+        let needed = used + used / 8 + 64;
+        let mut blocks = needed + Layout::for_blocks(needed as u32).data_block_start() as u64;
+        while blocks - (Layout::for_blocks(blocks as u32).data_block_start() as u64) < needed {
+            blocks += 1;
+        }
+        if blocks > u32::MAX as u64 {
+            bail!("'{}' is too large for one image", source.display());
+        }
+        Ok(blocks.max(LOLELFFS_MIN_BLOCKS as u64) * LOLELFFS_BLOCK_SIZE as u64)
+    }
+
+    fn sync_host_dir(
+        &mut self,
+        source: &Path,
+        dst_dir: u32,
+        opts: &SyncOptions,
+        stats: &mut SyncStats,
+    ) -> Result<()> {
+        let mut dst_entries = self.dir_entry_map(dst_dir)?;
+
+        let mut children: Vec<_> = std::fs::read_dir(source)
+            .with_context(|| format!("Failed to read '{}'", source.display()))?
+            .collect::<std::io::Result<_>>()?;
+        children.sort_by_key(|entry| entry.file_name());
+
+        for child in children {
+            let name = child.file_name().to_string_lossy().into_owned();
+            let path = child.path();
+            let meta = std::fs::symlink_metadata(&path)
+                .with_context(|| format!("Failed to stat '{}'", path.display()))?;
+            let file_type = meta.file_type();
+            let kind = if file_type.is_dir() {
+                mode::S_IFDIR
+            } else if file_type.is_symlink() {
+                mode::S_IFLNK
+            } else if file_type.is_file() {
+                mode::S_IFREG
+            } else {
+                continue;
+            };
+
+            let existing = match dst_entries.remove(&name) {
+                Some(e) if e.inode.i_mode & mode::S_IFMT == kind => Some(e.inode_num),
+                Some(_) => {
+                    self.remove_tree(dst_dir, &name)?;
+                    stats.removed += 1;
+                    None
+                }
+                None => None,
+            };
+
+            let (inode_num, mut changed) = if kind == mode::S_IFDIR {
+                match existing {
+                    Some(ino) => (ino, false),
+                    None => (self.mkdir(dst_dir, &name)?, true),
+                }
+            } else if kind == mode::S_IFLNK {
+                let target = std::fs::read_link(&path)?.to_string_lossy().into_owned();
+                match existing {
+                    Some(ino) if self.read_file(ino)? == target.as_bytes() => (ino, false),
+                    Some(_) => {
+                        self.remove_tree(dst_dir, &name)?;
+                        (self.symlink(dst_dir, &name, &target)?, true)
+                    }
+                    None => (self.symlink(dst_dir, &name, &target)?, true),
+                }
+            } else {
+                let ino = match existing {
+                    Some(ino) => ino,
+                    None => self.create_file(dst_dir, &name)?,
+                };
+                let changed =
+                    existing.is_none() || self.file_changed(ino, meta.len(), host_mtime(&meta))?;
+                if changed {
+                    let data = std::fs::read(&path)
+                        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+                    self.write_file(ino, &data)?;
+                }
+                (ino, changed)
+            };
+
+            // Directory metadata goes last since adding children bumps its mtime
+            if kind == mode::S_IFDIR {
+                self.sync_host_dir(&path, inode_num, opts, stats)?;
+            }
+            changed |= self.sync_host_metadata(inode_num, &meta)?;
+
+            if existing.is_none() {
+                stats.created += 1;
+            } else if changed {
+                stats.updated += 1;
+            } else {
+                stats.unchanged += 1;
+            }
+        }
+
+        if opts.delete {
+            for name in dst_entries.keys() {
+                self.remove_tree(dst_dir, name)?;
+                stats.removed += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy permission bits (on unix) and mtime from host metadata.
+    /// Returns true if the inode was modified.
+    fn sync_host_metadata(&mut self, inode_num: u32, meta: &std::fs::Metadata) -> Result<bool> {
+        let mut inode = self.read_inode(inode_num)?;
+        let mut changed = false;
+
+        #[cfg(unix)]
+        if !meta.file_type().is_symlink() {
+            use std::os::unix::fs::PermissionsExt;
+            let perm = meta.permissions().mode() & 0o7777;
+            if inode.i_mode & 0o7777 != perm {
+                inode.i_mode = (inode.i_mode & mode::S_IFMT) | perm;
+                changed = true;
+            }
+        }
+
+        let mtime = host_mtime(meta);
+        if inode.i_mtime != mtime {
+            inode.i_mtime = mtime;
+            changed = true;
+        }
+
+        if changed {
+            self.write_inode(inode_num, &inode)?;
+        }
+        Ok(changed)
+    }
+}
+
+/// Modification time of a host file in seconds since the epoch
+fn host_mtime(meta: &std::fs::Metadata) -> u32 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// Blocks the entries below host directory `dir` take once copied, not
+/// counting `dir`'s own extent index
+fn host_tree_blocks(dir: &Path) -> Result<u64> {
+    let mut entries = 0u64;
+    let mut blocks = 0u64;
+    for child in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read '{}'", dir.display()))?
+    {
+        let path = child?.path();
+        let meta = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to stat '{}'", path.display()))?;
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            blocks += 1 + host_tree_blocks(&path)?;
+        } else if file_type.is_file() {
+            blocks += 1 + meta.len().div_ceil(LOLELFFS_BLOCK_SIZE as u64);
+        } else if !file_type.is_symlink() {
+            // Skipped by the sync; symlink targets live in the inode
+            continue;
+        }
+        entries += 1;
+    }
+    Ok(blocks + entries.div_ceil(LOLELFFS_FILES_PER_BLOCK as u64))
+}
+
+/// Read every xattr of an inode as sorted (name, value) pairs
+fn read_all_xattrs(fs: &mut LolelfFs, inode_num: u32) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    for name in fs.list_xattrs(inode_num)? {
+        let value = fs.get_xattr(inode_num, &name)?;
+        xattrs.push((name, value));
+    }
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temp_image, TempPath};
+
+    #[test]
+    fn test_sync_preserves_inode_numbers() {
+        let (_src_path, mut src) = temp_image("sync-src.img");
+        let (_dst_path, mut dst) = temp_image("sync-dst.img");
+        src.superblock.comp_enabled = 0;
+        dst.superblock.comp_enabled = 0;
+
+        let dir = src.mkdir(LOLELFFS_ROOT_INO, "etc").unwrap();
+        let file = src.create_file(dir, "config").unwrap();
+        src.write_file(file, b"v1").unwrap();
+
+        let opts = SyncOptions { delete: true };
+        let stats = dst.sync_from(&mut src, &opts).unwrap();
+        assert_eq!(stats.created, 2);
+
+        let dst_file = dst.resolve_path("/etc/config").unwrap();
+        assert_eq!(dst.read_file(dst_file).unwrap(), b"v1");
+
+        // Rewriting the source must update the same destination inode
+        src.write_file(file, b"version two").unwrap();
+        src.create_file(LOLELFFS_ROOT_INO, "new").unwrap();
+        dst.create_file(LOLELFFS_ROOT_INO, "stale").unwrap();
+
+        let stats = dst.sync_from(&mut src, &opts).unwrap();
+        assert_eq!(stats.created, 1);
+        assert_eq!(stats.removed, 1);
+        assert_eq!(dst.resolve_path("/etc/config").unwrap(), dst_file);
+        assert_eq!(dst.read_file(dst_file).unwrap(), b"version two");
+        assert!(dst.lookup(LOLELFFS_ROOT_INO, "stale").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sync_from_host() {
+        let host = TempPath::new("sync-hostdir");
+        std::fs::create_dir_all(host.join("etc")).unwrap();
+        std::fs::write(host.join("etc/config"), b"v1").unwrap();
+        std::fs::write(host.join("readme"), b"hello").unwrap();
+
+        let (_path, mut fs) = temp_image("sync-host.img");
+        let opts = SyncOptions { delete: true };
+        let stats = fs.sync_from_host(&host, LOLELFFS_ROOT_INO, &opts).unwrap();
+        assert_eq!(stats.created, 3);
+
+        let config = fs.resolve_path("/etc/config").unwrap();
+        assert_eq!(fs.read_file(config).unwrap(), b"v1");
+
+        // Only the changed file is rewritten, in place
+        let stats = fs.sync_from_host(&host, LOLELFFS_ROOT_INO, &opts).unwrap();
+        assert_eq!((stats.created, stats.updated, stats.unchanged), (0, 0, 3));
+
+        std::fs::write(host.join("etc/config"), b"version two").unwrap();
+        std::fs::remove_file(host.join("readme")).unwrap();
+        let stats = fs.sync_from_host(&host, LOLELFFS_ROOT_INO, &opts).unwrap();
+        assert_eq!(stats.removed, 1);
+        assert_eq!(fs.resolve_path("/etc/config").unwrap(), config);
+        assert_eq!(fs.read_file(config).unwrap(), b"version two");
+        assert!(fs.lookup(LOLELFFS_ROOT_INO, "readme").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_size_for_host_tree() {
+        let path = TempPath::new("sync-auto.img");
+        let host = TempPath::new("sync-autodir");
+        std::fs::create_dir_all(host.join("lib")).unwrap();
+        for i in 0..40 {
+            std::fs::write(host.join(format!("lib/f{}", i)), vec![i as u8; 9000]).unwrap();
+        }
+        let noise: Vec<u8> = (0..3 * 1024 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        std::fs::write(host.join("big"), &noise).unwrap();
+
+        let size = LolelfFs::size_for_host_tree(&host).unwrap();
+        let mut fs = LolelfFs::create(&path, size).unwrap();
+        fs.sync_from_host(&host, LOLELFFS_ROOT_INO, &SyncOptions::default())
+            .unwrap();
+        let min = fs.min_size();
+        assert!(min <= size);
+        fs.shrink(min).unwrap();
+        let big = fs.resolve_path("/big").unwrap();
+        assert_eq!(fs.read_file(big).unwrap(), noise);
+    }
+}
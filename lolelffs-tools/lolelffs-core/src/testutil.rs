@@ -0,0 +1,69 @@
+//! Scratch images for tests
+//!
+//! Tests run in parallel within one process, so a path made from the pid
+//! alone is only unique as long as no two tests pick the same name. A
+//! `TempPath` adds a per-process counter, and removes whatever ends up at
+//! the path when it is dropped, including when an assertion fails first.
+//!
+//! Built for this crate's tests and, with the `testutil` feature, for the
+//! CLI's; it is not part of the semver API.
+
+use crate::fs::LolelfFs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Size of the image `temp_image` creates
+pub const TEMP_IMAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+static NEXT: AtomicU32 = AtomicU32::new(0);
+
+/// A path under the system temp directory, removed on drop
+///
+/// Nothing is created; the test writes a file or a directory there.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A new path ending in `name`, e.g. `"dir.img"`, which only serves to
+    /// make leftovers easier to trace
+    pub fn new(name: &str) -> Self {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        TempPath(std::env::temp_dir().join(format!(
+            "lolelffs-{}-{}-{}",
+            std::process::id(),
+            n,
+            name
+        )))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        } else {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// A fresh `TEMP_IMAGE_SIZE` image with default options, and its path
+pub fn temp_image(name: &str) -> (TempPath, LolelfFs) {
+    let path = TempPath::new(name);
+    let fs = LolelfFs::create(&path, TEMP_IMAGE_SIZE).unwrap();
+    (path, fs)
+}
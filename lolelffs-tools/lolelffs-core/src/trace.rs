@@ -0,0 +1,14 @@
+//! Optional `tracing` instrumentation
+//!
+//! With the `tracing` feature, block I/O, allocation, compression and
+//! encryption run inside trace-level spans, so a subscriber such as
+//! tracing-flame can attribute time to them. Without the feature the `span!`
+//! macro expands to nothing.
+
+/// Enter a trace-level span for the rest of the enclosing block
+macro_rules! span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($name $(, $($fields)*)?).entered();
+    };
+}
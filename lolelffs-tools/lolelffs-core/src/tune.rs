@@ -0,0 +1,237 @@
+//! Adjusting superblock parameters on an existing image
+//!
+//! Only fields that are read afresh on every write can change after mkfs:
+//! the compression defaults, the extent length cap for new files, whether
+//! identical files share extents, and the cost of the password KDF.
+//! Existing data keeps whatever encoding it was written with.
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+/// Fewest PBKDF2 iterations `tune` will accept
+pub const LOLELFFS_MIN_KDF_ITERATIONS: u32 = 1000;
+
+/// Superblock fields to change; `None` leaves a field as it is
+#[derive(Debug, Clone, Default)]
+pub struct TuneParams {
+    /// Default compression algorithm for new writes
+    pub comp_algo: Option<u8>,
+    /// Whether new writes are compressed by default
+    pub comp_enabled: Option<bool>,
+    /// Minimum block size to compress
+    pub comp_min_block_size: Option<u32>,
+    /// Cap on extents without per-block metadata
+    pub max_extent_blocks_large: Option<u32>,
+    /// PBKDF2 iterations for the password key (needs the password)
+    pub kdf_iterations: Option<u32>,
+    /// Whether files with identical contents share extents
+    pub reflink: Option<bool>,
+}
+
+impl TuneParams {
+    /// Whether any field would change
+    pub fn is_empty(&self) -> bool {
+        self.comp_algo.is_none()
+            && self.comp_enabled.is_none()
+            && self.comp_min_block_size.is_none()
+            && self.max_extent_blocks_large.is_none()
+            && self.kdf_iterations.is_none()
+            && self.reflink.is_none()
+    }
+}
+
+impl LolelfFs {
+    /// Apply `params` to the superblock
+    ///
+    /// Every change is validated against the result of the others before
+    /// anything is written, so a rejected combination leaves the image as it
+    /// was. Changing the KDF iterations re-wraps the master key under a fresh
+    /// salt and needs the password; file data is untouched.
+    pub fn tune(&mut self, params: &TuneParams, password: Option<&str>) -> Result<()> {
+        self.ensure_writable()?;
+        let mut sb = self.superblock.clone();
+
+        if let Some(algo) = params.comp_algo {
+            if !crate::compat::comp_algo_supported(algo) {
+                bail!(
+                    "Compression algorithm {} is not supported by this build",
+                    algo
+                );
+            }
+            sb.comp_default_algo = algo as u32;
+        }
+        if let Some(enabled) = params.comp_enabled {
+            sb.comp_enabled = enabled as u32;
+        }
+        if sb.comp_enabled != 0 && sb.comp_default_algo == LOLELFFS_COMP_NONE as u32 {
+            bail!("Compression is enabled but the default algorithm is none");
+        }
+
+        if let Some(size) = params.comp_min_block_size {
+            if size > LOLELFFS_BLOCK_SIZE {
+                bail!(
+                    "Minimum compressible size {} exceeds the block size {}",
+                    size,
+                    LOLELFFS_BLOCK_SIZE
+                );
+            }
+            sb.comp_min_block_size = size;
+        }
+
+        if let Some(blocks) = params.max_extent_blocks_large {
+            if sb.comp_features & LOLELFFS_FEATURE_LARGE_EXTENTS == 0 {
+                bail!("Image does not support large extents");
+            }
+            if blocks < sb.max_extent_blocks || blocks > LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE {
+                bail!(
+                    "Large extent cap {} outside {}..={}",
+                    blocks,
+                    sb.max_extent_blocks,
+                    LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE
+                );
+            }
+            sb.max_extent_blocks_large = blocks;
+        }
+
+        if let Some(on) = params.reflink {
+            if on {
+                sb.comp_features |= LOLELFFS_FEATURE_REFLINK;
+            } else {
+                // Tools unaware of the feature would free shared blocks twice
+                let shared = self.dedup_stats()?.shared_extents;
+                if shared > 0 {
+                    bail!(
+                        "{} extents are still shared between files; rewrite them first",
+                        shared
+                    );
+                }
+                sb.comp_features &= !LOLELFFS_FEATURE_REFLINK;
+            }
+        }
+
+        if let Some(iterations) = params.kdf_iterations {
+            if sb.enc_enabled == 0 {
+                bail!("KDF iterations only apply to encrypted images");
+            }
+            if sb.enc_kdf_algo != LOLELFFS_KDF_PBKDF2 as u32 {
+                bail!("Only PBKDF2 iteration counts can be changed");
+            }
+            if iterations < LOLELFFS_MIN_KDF_ITERATIONS {
+                bail!(
+                    "KDF iterations must be at least {}",
+                    LOLELFFS_MIN_KDF_ITERATIONS
+                );
+            }
+            let Some(password) = password else {
+                bail!("Changing KDF iterations needs the password");
+            };
+            // The master key wrapping has no check value, so a wrong password
+            // cannot be detected here; it would leave the data unreadable
+            let old_key = crate::encrypt::derive_key_pbkdf2(
+                password.as_bytes(),
+                &sb.enc_salt,
+                sb.enc_kdf_iterations,
+            );
+            let master_key = crate::encrypt::decrypt_master_key(&sb.enc_master_key, &old_key)?;
+            if self.enc_unlocked && master_key != self.enc_master_key {
+                bail!("Password does not match the one the image was unlocked with");
+            }
+            crate::passwd::wrap_master_key(&mut sb, &master_key, password, iterations)?;
+        }
+
+        self.superblock = sb;
+        self.write_superblock()?;
+        if params.reflink == Some(false) {
+            self.drop_dedup_index()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn test_tune_params() {
+        let path = TempPath::new("tune.img");
+        let mut fs = LolelfFs::create_with_encryption(
+            &path,
+            4 * 1024 * 1024,
+            Some(("secret".to_string(), LOLELFFS_ENC_AES256_XTS, 2000)),
+        )
+        .unwrap();
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+        let content: Vec<u8> = (0..20000u32).map(|i| (i % 7) as u8).collect();
+        fs.write_file(ino, &content).unwrap();
+
+        // Rejected combinations leave the superblock alone
+        let before = fs.superblock.clone();
+        for params in [
+            TuneParams {
+                comp_algo: Some(LOLELFFS_COMP_NONE),
+                ..Default::default()
+            },
+            TuneParams {
+                comp_algo: Some(LOLELFFS_COMP_ZLIB),
+                comp_min_block_size: Some(LOLELFFS_BLOCK_SIZE + 1),
+                ..Default::default()
+            },
+            TuneParams {
+                max_extent_blocks_large: Some(LOLELFFS_MAX_BLOCKS_PER_EXTENT - 1),
+                ..Default::default()
+            },
+            TuneParams {
+                kdf_iterations: Some(LOLELFFS_MIN_KDF_ITERATIONS - 1),
+                ..Default::default()
+            },
+        ] {
+            assert!(fs.tune(&params, Some("secret")).is_err());
+        }
+        assert!(fs
+            .tune(
+                &TuneParams {
+                    kdf_iterations: Some(5000),
+                    ..Default::default()
+                },
+                None
+            )
+            .is_err());
+        assert_eq!(fs.superblock.comp_default_algo, before.comp_default_algo);
+        assert_eq!(fs.superblock.enc_kdf_iterations, 2000);
+
+        fs.tune(
+            &TuneParams {
+                comp_algo: Some(LOLELFFS_COMP_ZLIB),
+                comp_min_block_size: Some(512),
+                max_extent_blocks_large: Some(4096),
+                kdf_iterations: Some(5000),
+                ..Default::default()
+            },
+            Some("secret"),
+        )
+        .unwrap();
+        fs.tune(
+            &TuneParams {
+                comp_enabled: Some(false),
+                comp_algo: Some(LOLELFFS_COMP_NONE),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        drop(fs);
+
+        // The new values persist and the password still unlocks the data
+        let mut fs = LolelfFs::open(&path).unwrap();
+        assert_eq!(fs.superblock.comp_enabled, 0);
+        assert_eq!(fs.superblock.comp_min_block_size, 512);
+        assert_eq!(fs.superblock.max_extent_blocks_large, 4096);
+        assert_eq!(fs.superblock.enc_kdf_iterations, 5000);
+        assert_ne!(fs.superblock.enc_salt, before.enc_salt);
+        fs.unlock("secret").unwrap();
+        assert_eq!(fs.read_file(ino).unwrap(), content);
+    }
+}
@@ -5,6 +5,9 @@ use std::fmt;
 /// Magic number for lolelffs filesystems (0x101E1FF5 = "lolelffs" in hexspeak)
 pub const LOLELFFS_MAGIC: u32 = 0x101E1FF5;
 
+/// ELF section holding an embedded lolelffs filesystem
+pub const LOLELFFS_ELF_SECTION: &str = ".lolfs.super";
+
 /// Block size in bytes (4 KB)
 pub const LOLELFFS_BLOCK_SIZE: u32 = 4096;
 
@@ -21,12 +24,56 @@ pub const LOLELFFS_MAX_BLOCKS_PER_EXTENT_LARGE: u32 = 524288;
 /// Calculated as: (4096 - 4) / 24 = 170
 pub const LOLELFFS_MAX_EXTENTS: usize = 170;
 
+/// Size of the volume label field in the superblock
+pub const LOLELFFS_LABEL_MAX: usize = 64;
+
+/// Longest xattr name, excluding its namespace prefix
+pub const LOLELFFS_XATTR_NAME_MAX: usize = 255;
+
+/// Largest single xattr value
+pub const LOLELFFS_XATTR_VALUE_MAX: usize = 65535;
+
+/// Largest serialized xattr set per inode (8 blocks, as the kernel enforces)
+pub const LOLELFFS_XATTR_SET_MAX: usize = LOLELFFS_BLOCK_SIZE as usize * 8;
+
 /// Feature flags for comp_features field
 pub const LOLELFFS_FEATURE_LARGE_EXTENTS: u32 = 0x0001;
+pub const LOLELFFS_FEATURE_COMP_EXCLUDE: u32 = 0x0002; // Exclusion list in block 0
+pub const LOLELFFS_FEATURE_ORDERED_WRITES: u32 = 0x0004; // fsync between dependent updates
+pub const LOLELFFS_FEATURE_REFLINK: u32 = 0x0008; // Files may share extents
+pub const LOLELFFS_FEATURE_DIR_CSUM: u32 = 0x0010; // Directory blocks carry checksums
+
+/// Superblock state flags
+pub const LOLELFFS_STATE_DIRTY: u32 = 0x0001; // Open for writing, or not closed cleanly
+
+/// Byte offset in block 0 of the compression exclusion list
+///
+/// The list is a sequence of NUL-terminated name globs ended by an empty one.
+pub const LOLELFFS_COMP_EXCLUDE_OFFSET: usize = 1024;
+
+/// Inode flags (stored in `i_data` of non-symlinks)
+pub const LOLELFFS_INODE_NOCOMP: u32 = 0x0001; // Never compress this file's blocks
+pub const LOLELFFS_INODE_HOT: u32 = 0x0002; // Frequently read: uncompressed, near metadata
+pub const LOLELFFS_INODE_COLD: u32 = 0x0004; // Rarely read: zstd, at the back of the device
+pub const LOLELFFS_INODE_COMP_SET: u32 = 0x0008; // Written with the algorithm in LOLELFFS_INODE_COMP_MASK
+pub const LOLELFFS_INODE_COMP_MASK: u32 = 0xFF00; // Per-file compression algorithm (setcomp)
+pub const LOLELFFS_INODE_COMP_SHIFT: u32 = 8;
+
+/// Default limit on file sizes that `read_file` will load into memory
+pub const LOLELFFS_DEFAULT_MAX_READ_SIZE: usize = 256 * 1024 * 1024;
 
 /// Maximum filename length
 pub const LOLELFFS_MAX_FILENAME: usize = 255;
 
+/// Longest path the resolvers accept, in bytes
+pub const LOLELFFS_PATH_MAX: usize = 4096;
+
+/// Most components a path may have, including those symlinks expand to
+pub const LOLELFFS_MAX_PATH_DEPTH: usize = 256;
+
+/// Symlinks followed while resolving one path before giving up with ELOOP
+pub const LOLELFFS_MAX_SYMLINK_HOPS: u32 = 40;
+
 /// Filesystem version (always 1 - compression support is mandatory)
 pub const LOLELFFS_VERSION: u32 = 1;
 
@@ -46,6 +93,23 @@ pub const LOLELFFS_KDF_NONE: u8 = 0; // No KDF
 pub const LOLELFFS_KDF_ARGON2ID: u8 = 1; // Argon2id (recommended)
 pub const LOLELFFS_KDF_PBKDF2: u8 = 2; // PBKDF2-HMAC-SHA256
 
+/// Encryption feature flags (enc_features)
+pub const LOLELFFS_ENC_FEAT_PER_FILE_KEYS: u32 = 0x0001; // Blocks use HKDF-derived per-file keys
+pub const LOLELFFS_ENC_FEAT_OPAQUE: u32 = 0x0002; // Every block past the superblock is encrypted
+
+/// Hash algorithm IDs (shared by all integrity features)
+pub const LOLELFFS_HASH_NONE: u8 = 0; // No hashing
+pub const LOLELFFS_HASH_CRC32C: u8 = 1; // CRC32C (fast, 4-byte checksum)
+pub const LOLELFFS_HASH_XXH64: u8 = 2; // xxHash64 (fast, 8-byte non-cryptographic)
+pub const LOLELFFS_HASH_SHA256: u8 = 3; // SHA-256 (cryptographic)
+pub const LOLELFFS_HASH_BLAKE3: u8 = 4; // BLAKE3 (fast cryptographic)
+
+/// Integrity features that select a hash algorithm (byte index in hash_algos)
+pub const LOLELFFS_HASH_FEAT_CHECKSUM: u32 = 0; // Block/metadata checksums
+pub const LOLELFFS_HASH_FEAT_MANIFEST: u32 = 1; // File manifests
+pub const LOLELFFS_HASH_FEAT_DEDUP: u32 = 2; // Deduplication fingerprints
+pub const LOLELFFS_HASH_FEAT_MERKLE: u32 = 3; // Merkle trees
+
 /// Compression metadata magic
 pub const LOLELFFS_COMP_META_MAGIC: u32 = 0xC04FFEE5;
 
@@ -54,6 +118,7 @@ pub const LOLELFFS_EXT_COMPRESSED: u16 = 0x0001; // Extent contains compressed b
 pub const LOLELFFS_EXT_ENCRYPTED: u16 = 0x0002; // Extent contains encrypted blocks
 pub const LOLELFFS_EXT_HAS_META: u16 = 0x0004; // Has per-block metadata
 pub const LOLELFFS_EXT_MIXED: u16 = 0x0008; // Mixed compressed/uncompressed/encrypted
+pub const LOLELFFS_EXT_SHARED: u16 = 0x0010; // Blocks may belong to other files too
 
 /// Size of file entry structure
 pub const LOLELFFS_FILE_ENTRY_SIZE: usize = 259;
@@ -61,6 +126,12 @@ pub const LOLELFFS_FILE_ENTRY_SIZE: usize = 259;
 /// Number of file entries per block
 pub const LOLELFFS_FILES_PER_BLOCK: usize = 15;
 
+/// Offset of the checksum in a directory block, right after the entries
+///
+/// Present when a checksum algorithm is selected for
+/// `LOLELFFS_HASH_FEAT_CHECKSUM`; it covers the entries and the block number.
+pub const LOLELFFS_DIR_CSUM_OFFSET: usize = LOLELFFS_FILES_PER_BLOCK * LOLELFFS_FILE_ENTRY_SIZE;
+
 /// Bits per bitmap block
 pub const LOLELFFS_BITS_PER_BLOCK: u32 = LOLELFFS_BLOCK_SIZE * 8;
 
@@ -129,19 +200,62 @@ pub struct Superblock {
     pub enc_master_key: [u8; 32],
     /// Encryption feature flags
     pub enc_features: u32,
-    /// Reserved for future use
-    pub reserved: [u32; 3],
+    /// Hash algorithm per integrity feature (one byte each)
+    pub hash_algos: u32,
+    /// First inode on the orphan list of unnamed inodes (0 = empty)
+    pub last_orphan: u32,
+    /// Runtime state flags (`LOLELFFS_STATE_*`)
+    pub state: u32,
+    /// Volume UUID (all zeros on images made before UUIDs existed)
+    pub uuid: [u8; 16],
+    /// Volume label, NUL-padded UTF-8
+    pub label: [u8; LOLELFFS_LABEL_MAX],
 }
 
 impl Superblock {
-    /// Size of superblock on disk (172 bytes with encryption + large extents)
-    pub const SIZE: usize = 172;
+    /// Size of superblock on disk (252 bytes with encryption, large extents,
+    /// UUID, and label)
+    pub const SIZE: usize = 252;
+
+    /// Volume label, or an empty string if none is set
+    pub fn label(&self) -> String {
+        let len = self
+            .label
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.label.len());
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    /// Volume UUID in the usual 8-4-4-4-12 hex form
+    pub fn uuid_string(&self) -> String {
+        let hex: String = self.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
 
     /// Check if compression is enabled
     pub fn is_compression_enabled(&self) -> bool {
         self.comp_enabled != 0
     }
 
+    /// Get the hash algorithm recorded for an integrity feature
+    pub fn hash_algo(&self, feature: u32) -> u8 {
+        (self.hash_algos >> (feature * 8)) as u8
+    }
+
+    /// Record the hash algorithm used by an integrity feature
+    pub fn set_hash_algo(&mut self, feature: u32, algo: u8) {
+        let shift = feature * 8;
+        self.hash_algos = (self.hash_algos & !(0xFF << shift)) | ((algo as u32) << shift);
+    }
+
     /// Get the block number where inode store starts
     pub fn inode_store_start(&self) -> u32 {
         1 // Block 0 is superblock, block 1 starts inode store
@@ -161,6 +275,122 @@ impl Superblock {
     pub fn data_block_start(&self) -> u32 {
         self.bfree_bitmap_start() + self.nr_bfree_blocks
     }
+
+    /// Recompute the layout from the block and inode counts and describe
+    /// every field that disagrees with it
+    pub fn layout_issues(&self) -> Vec<String> {
+        let expected = Layout::with_inodes(self.nr_blocks, self.nr_inodes);
+        let mut issues = Vec::new();
+
+        if !self.nr_inodes.is_multiple_of(LOLELFFS_INODES_PER_BLOCK) {
+            issues.push(format!(
+                "nr_inodes {} is not a multiple of {} inodes per block",
+                self.nr_inodes, LOLELFFS_INODES_PER_BLOCK
+            ));
+        }
+        if self.nr_istore_blocks != expected.nr_istore_blocks {
+            issues.push(format!(
+                "nr_istore_blocks {} does not match {} inodes (expected {})",
+                self.nr_istore_blocks, self.nr_inodes, expected.nr_istore_blocks
+            ));
+        }
+        if self.nr_ifree_blocks != expected.nr_ifree_blocks {
+            issues.push(format!(
+                "nr_ifree_blocks {} does not match {} inodes (expected {})",
+                self.nr_ifree_blocks, self.nr_inodes, expected.nr_ifree_blocks
+            ));
+        }
+        if self.nr_bfree_blocks != expected.nr_bfree_blocks {
+            issues.push(format!(
+                "nr_bfree_blocks {} does not match {} blocks (expected {})",
+                self.nr_bfree_blocks, self.nr_blocks, expected.nr_bfree_blocks
+            ));
+        }
+        if self.data_block_start() >= self.nr_blocks {
+            issues.push(format!(
+                "data start block {} is beyond nr_blocks {}",
+                self.data_block_start(),
+                self.nr_blocks
+            ));
+        }
+        if self.nr_free_inodes >= self.nr_inodes {
+            issues.push(format!(
+                "nr_free_inodes {} leaves no room for the root inode ({} total)",
+                self.nr_free_inodes, self.nr_inodes
+            ));
+        }
+        let data_blocks = self.nr_blocks.saturating_sub(self.data_block_start());
+        if self.nr_free_blocks > data_blocks {
+            issues.push(format!(
+                "nr_free_blocks {} exceeds the {} data blocks",
+                self.nr_free_blocks, data_blocks
+            ));
+        }
+        if self.max_extent_blocks == 0 || self.max_extent_blocks > LOLELFFS_MAX_BLOCKS_PER_EXTENT {
+            issues.push(format!(
+                "max_extent_blocks {} outside 1..={}",
+                self.max_extent_blocks, LOLELFFS_MAX_BLOCKS_PER_EXTENT
+            ));
+        }
+
+        issues
+    }
+}
+
+/// Encode a volume label for the superblock (at most `LOLELFFS_LABEL_MAX` bytes)
+pub fn encode_label(label: &str) -> anyhow::Result<[u8; LOLELFFS_LABEL_MAX]> {
+    if label.len() > LOLELFFS_LABEL_MAX {
+        anyhow::bail!(
+            "Label is {} bytes, the limit is {}",
+            label.len(),
+            LOLELFFS_LABEL_MAX
+        );
+    }
+    if label.contains('\0') {
+        anyhow::bail!("Label must not contain NUL bytes");
+    }
+    let mut field = [0u8; LOLELFFS_LABEL_MAX];
+    field[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(field)
+}
+
+/// Filesystem layout derived from first principles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// Total number of blocks
+    pub nr_blocks: u32,
+    /// Total number of inodes
+    pub nr_inodes: u32,
+    /// Number of inode store blocks
+    pub nr_istore_blocks: u32,
+    /// Number of inode free bitmap blocks
+    pub nr_ifree_blocks: u32,
+    /// Number of block free bitmap blocks
+    pub nr_bfree_blocks: u32,
+}
+
+impl Layout {
+    /// Layout chosen when creating a filesystem of `nr_blocks` blocks
+    pub fn for_blocks(nr_blocks: u32) -> Self {
+        let nr_inodes = ((nr_blocks / LOLELFFS_INODES_PER_BLOCK) + 1) * LOLELFFS_INODES_PER_BLOCK;
+        Self::with_inodes(nr_blocks, nr_inodes)
+    }
+
+    /// Layout implied by a given block and inode count
+    pub fn with_inodes(nr_blocks: u32, nr_inodes: u32) -> Self {
+        Layout {
+            nr_blocks,
+            nr_inodes,
+            nr_istore_blocks: nr_inodes.div_ceil(LOLELFFS_INODES_PER_BLOCK),
+            nr_ifree_blocks: nr_inodes.div_ceil(LOLELFFS_BITS_PER_BLOCK),
+            nr_bfree_blocks: nr_blocks.div_ceil(LOLELFFS_BITS_PER_BLOCK),
+        }
+    }
+
+    /// First data block number
+    pub fn data_block_start(&self) -> u32 {
+        1 + self.nr_istore_blocks + self.nr_ifree_blocks + self.nr_bfree_blocks
+    }
 }
 
 /// Inode structure (on-disk format, 72 bytes)
@@ -188,7 +418,7 @@ pub struct Inode {
     pub ei_block: u32,
     /// Block number for xattr extent index (0 = no xattrs)
     pub xattr_block: u32,
-    /// Inline data (symlink target, max 27 chars + NUL)
+    /// Inline data (symlink target, max 27 chars + NUL; key generation for files)
     pub i_data: [u8; 28],
 }
 
@@ -211,6 +441,94 @@ impl Inode {
         (self.i_mode & mode::S_IFMT) == mode::S_IFLNK
     }
 
+    /// Key generation mixed into this file's derived encryption key
+    ///
+    /// Stored in the first four bytes of `i_data`, which only symlinks use.
+    pub fn generation(&self) -> u32 {
+        if self.is_symlink() {
+            return 0;
+        }
+        u32::from_le_bytes([
+            self.i_data[0],
+            self.i_data[1],
+            self.i_data[2],
+            self.i_data[3],
+        ])
+    }
+
+    /// Set the key generation (no-op for symlinks)
+    pub fn set_generation(&mut self, generation: u32) {
+        if !self.is_symlink() {
+            self.i_data[..4].copy_from_slice(&generation.to_le_bytes());
+        }
+    }
+
+    /// Inode flags (`LOLELFFS_INODE_*`)
+    ///
+    /// Stored in bytes 4..8 of `i_data`, after the key generation.
+    pub fn flags(&self) -> u32 {
+        if self.is_symlink() {
+            return 0;
+        }
+        u32::from_le_bytes([
+            self.i_data[4],
+            self.i_data[5],
+            self.i_data[6],
+            self.i_data[7],
+        ])
+    }
+
+    /// Set the inode flags (no-op for symlinks)
+    pub fn set_flags(&mut self, flags: u32) {
+        if !self.is_symlink() {
+            self.i_data[4..8].copy_from_slice(&flags.to_le_bytes());
+        }
+    }
+
+    /// Compression algorithm chosen for this file with `setcomp`, which
+    /// every write uses instead of the default, hints and exclusion list
+    pub fn comp_override(&self) -> Option<u8> {
+        let flags = self.flags();
+        if flags & LOLELFFS_INODE_COMP_SET == 0 {
+            return None;
+        }
+        Some(((flags & LOLELFFS_INODE_COMP_MASK) >> LOLELFFS_INODE_COMP_SHIFT) as u8)
+    }
+
+    /// Set or clear the per-file compression algorithm (no-op for symlinks)
+    pub fn set_comp_override(&mut self, algo: Option<u8>) {
+        let flags = self.flags() & !(LOLELFFS_INODE_COMP_SET | LOLELFFS_INODE_COMP_MASK);
+        let flags = match algo {
+            Some(algo) => {
+                flags | LOLELFFS_INODE_COMP_SET | (algo as u32) << LOLELFFS_INODE_COMP_SHIFT
+            }
+            None => flags,
+        };
+        self.set_flags(flags);
+    }
+
+    /// Next inode on the orphan list (0 = end)
+    ///
+    /// Stored in bytes 8..12 of `i_data`; only unnamed files are on the list.
+    pub fn next_orphan(&self) -> u32 {
+        if self.is_symlink() {
+            return 0;
+        }
+        u32::from_le_bytes([
+            self.i_data[8],
+            self.i_data[9],
+            self.i_data[10],
+            self.i_data[11],
+        ])
+    }
+
+    /// Set the next inode on the orphan list (no-op for symlinks)
+    pub fn set_next_orphan(&mut self, inode_num: u32) {
+        if !self.is_symlink() {
+            self.i_data[8..12].copy_from_slice(&inode_num.to_le_bytes());
+        }
+    }
+
     /// Get the file type character for display
     pub fn type_char(&self) -> char {
         if self.is_dir() {
@@ -321,6 +639,11 @@ impl Extent {
     pub fn is_mixed(&self) -> bool {
         self.ee_flags & LOLELFFS_EXT_MIXED != 0
     }
+
+    /// Check if extent may be shared with other files
+    pub fn is_shared(&self) -> bool {
+        self.ee_flags & LOLELFFS_EXT_SHARED != 0
+    }
 }
 
 /// Compression metadata for a single block (4 bytes)
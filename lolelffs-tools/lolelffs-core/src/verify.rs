@@ -0,0 +1,189 @@
+//! Per-file content digests
+//!
+//! A file's SHA-256 is kept as lowercase hex in the `user.lolelffs.sha256`
+//! xattr, recorded when the data comes in (`cp --checksum`, `import-tar
+//! --checksum`) or afterwards with `verify --update`. Checking recomputes the
+//! digest from what the image returns now, so it catches silent corruption
+//! that leaves the metadata intact and fsck happy. Being a plain xattr, the
+//! digest also travels through `export-tar` and back.
+
+use crate::fs::LolelfFs;
+use crate::hash::Hasher;
+use crate::types::*;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::io::Write;
+
+/// Xattr holding a file's SHA-256 as hex
+pub const SHA256_XATTR: &str = "user.lolelffs.sha256";
+
+/// Outcome of checking one file against its stored digest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestCheck {
+    /// Contents match the stored digest
+    Match,
+    /// Contents no longer match
+    Mismatch { stored: String, actual: String },
+    /// No digest has been stored for the file
+    Missing,
+}
+
+/// Files checked by `LolelfFs::verify_tree`, by path relative to the root
+/// of the walk
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Files whose contents matched
+    pub verified: u32,
+    /// Files given a digest because they had none (with `update`)
+    pub recorded: u32,
+    /// Files without a stored digest
+    pub missing: Vec<String>,
+    /// Files whose contents differ from their digest
+    pub mismatched: Vec<String>,
+    /// Files that could not be read or carry a malformed digest
+    pub errors: Vec<(String, String)>,
+}
+
+impl VerifyReport {
+    /// No mismatches and no errors
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.errors.is_empty()
+    }
+}
+
+/// Feeds everything written into a hasher
+struct HashWriter(Hasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl LolelfFs {
+    /// SHA-256 of a file's current contents, as hex
+    pub fn file_sha256(&mut self, inode_num: u32) -> Result<String> {
+        let mut writer = HashWriter(Hasher::new(LOLELFFS_HASH_SHA256)?);
+        self.read_file_to(inode_num, &mut writer)?;
+        Ok(to_hex(&writer.0.finalize()))
+    }
+
+    /// Store the digest of `content`, the data just written to a file
+    pub fn record_sha256(&mut self, inode_num: u32, content: &[u8]) -> Result<()> {
+        let digest = crate::hash::hash(LOLELFFS_HASH_SHA256, content)?;
+        self.set_xattr(inode_num, SHA256_XATTR, to_hex(&digest).as_bytes())
+    }
+
+    /// Store the digest of a file's current contents
+    pub fn update_sha256(&mut self, inode_num: u32) -> Result<()> {
+        let digest = self.file_sha256(inode_num)?;
+        self.set_xattr(inode_num, SHA256_XATTR, digest.as_bytes())
+    }
+
+    /// Compare a file's contents with its stored digest
+    pub fn check_sha256(&mut self, inode_num: u32) -> Result<DigestCheck> {
+        if !self
+            .list_xattrs(inode_num)?
+            .iter()
+            .any(|name| name == SHA256_XATTR)
+        {
+            return Ok(DigestCheck::Missing);
+        }
+        let stored = String::from_utf8_lossy(&self.get_xattr(inode_num, SHA256_XATTR)?)
+            .trim()
+            .to_ascii_lowercase();
+        if stored.len() != 64 || !stored.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Malformed {} value '{}'", SHA256_XATTR, stored);
+        }
+
+        let actual = self.file_sha256(inode_num)?;
+        Ok(if actual == stored {
+            DigestCheck::Match
+        } else {
+            DigestCheck::Mismatch { stored, actual }
+        })
+    }
+
+    /// Check every regular file below a directory, recording digests for
+    /// files without one if `update` is set
+    ///
+    /// A file with several links is checked once, under the first path found.
+    pub fn verify_tree(&mut self, dir_inode_num: u32, update: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut seen = HashSet::new();
+
+        for walked in self.walk_tree(dir_inode_num)? {
+            let inode_num = walked.entry.inode_num;
+            if !walked.entry.inode.is_file() || !seen.insert(inode_num) {
+                continue;
+            }
+
+            match self.check_sha256(inode_num) {
+                Ok(DigestCheck::Match) => report.verified += 1,
+                Ok(DigestCheck::Mismatch { .. }) => report.mismatched.push(walked.path),
+                Ok(DigestCheck::Missing) if update => match self.update_sha256(inode_num) {
+                    Ok(()) => report.recorded += 1,
+                    Err(e) => report.errors.push((walked.path, e.to_string())),
+                },
+                Ok(DigestCheck::Missing) => report.missing.push(walked.path),
+                Err(e) => report.errors.push((walked.path, e.to_string())),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_verify_detects_corruption() {
+        let (_path, mut fs) = temp_image("verify.img");
+        fs.superblock.comp_enabled = 0;
+
+        let content = b"important data\n".repeat(500);
+        let kept = fs.create_file(LOLELFFS_ROOT_INO, "kept").unwrap();
+        fs.write_file(kept, &content).unwrap();
+        fs.record_sha256(kept, &content).unwrap();
+        let rotted = fs.create_file(LOLELFFS_ROOT_INO, "rotted").unwrap();
+        fs.write_file(rotted, &content).unwrap();
+        fs.link(rotted, LOLELFFS_ROOT_INO, "alias").unwrap();
+        let sub = fs.mkdir(LOLELFFS_ROOT_INO, "sub").unwrap();
+        let later = fs.create_file(sub, "later").unwrap();
+        fs.write_file(later, b"added later").unwrap();
+
+        assert_eq!(fs.check_sha256(kept).unwrap(), DigestCheck::Match);
+        assert_eq!(fs.check_sha256(rotted).unwrap(), DigestCheck::Missing);
+        let report = fs.verify_tree(LOLELFFS_ROOT_INO, true).unwrap();
+        assert_eq!((report.verified, report.recorded), (1, 2));
+        assert!(report.is_clean());
+
+        // Flip a byte behind the filesystem's back
+        let inode = fs.read_inode(rotted).unwrap();
+        let block_num = fs.read_extent_index(&inode).unwrap().extents[0].ee_start;
+        let mut block = fs.read_block(block_num).unwrap();
+        block[100] ^= 0x01;
+        fs.write_block(block_num, &block).unwrap();
+
+        let report = fs.verify_tree(LOLELFFS_ROOT_INO, false).unwrap();
+        assert_eq!(report.verified, 2);
+        assert_eq!(report.mismatched.len(), 1);
+        assert!(report.missing.is_empty());
+        assert!(!report.is_clean());
+
+        fs.set_xattr(kept, SHA256_XATTR, b"not-hex").unwrap();
+        assert!(fs.check_sha256(kept).is_err());
+    }
+}
@@ -0,0 +1,726 @@
+//! Extended attribute operations for lolelffs
+
+use crate::fs::LolelfFs;
+use crate::types::*;
+use anyhow::{bail, Result};
+
+/// Directory xattrs named `user.lolelffs.default.NAME` give every file or
+/// directory created in it `user.NAME`; new subdirectories inherit the
+/// defaults themselves, so they apply to the whole subtree
+pub const DEFAULT_XATTR_PREFIX: &str = "user.lolelffs.default.";
+
+/// An xattr index still allocated by an inode that has been freed
+#[derive(Debug, Clone)]
+pub struct OrphanXattr {
+    /// Freed inode whose stale record points at the index
+    pub inode_num: u32,
+    /// Xattr index block
+    pub index_block: u32,
+    /// Leaked blocks, including the index block
+    pub blocks: Vec<u32>,
+}
+
+/// Xattr space used by one inode
+#[derive(Debug, Clone)]
+pub struct XattrUsage {
+    pub inode_num: u32,
+    /// Number of attributes
+    pub count: u32,
+    /// Serialized size of all attributes
+    pub bytes: u32,
+    /// Data blocks holding them, excluding the index block
+    pub blocks: u32,
+    /// Extents those blocks are split across
+    pub extents: u32,
+}
+
+impl XattrUsage {
+    /// Whether the set is larger than the kernel will accept
+    pub fn over_capacity(&self) -> bool {
+        self.bytes as usize > LOLELFFS_XATTR_SET_MAX
+    }
+}
+
+/// Parse xattr name to extract namespace and base name
+pub fn parse_xattr_name(name: &str) -> Result<(XattrNamespace, String)> {
+    if let Some(base) = name.strip_prefix("user.") {
+        Ok((XattrNamespace::User, base.to_string()))
+    } else if let Some(base) = name.strip_prefix("trusted.") {
+        Ok((XattrNamespace::Trusted, base.to_string()))
+    } else if let Some(base) = name.strip_prefix("system.") {
+        Ok((XattrNamespace::System, base.to_string()))
+    } else if let Some(base) = name.strip_prefix("security.") {
+        Ok((XattrNamespace::Security, base.to_string()))
+    } else {
+        bail!(
+            "Invalid xattr name '{}': must start with user., trusted., system., or security.",
+            name
+        );
+    }
+}
+
+/// Read xattr extent index block
+pub fn read_xattr_index(fs: &mut LolelfFs, block_num: u32) -> Result<XattrIndex> {
+    let block = fs.read_block(block_num)?;
+
+    let total_size = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+    let count = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+
+    let mut extents = Vec::new();
+    let mut offset = 8;
+
+    for _ in 0..LOLELFFS_MAX_EXTENTS {
+        if offset + 20 > block.len() {
+            break;
+        }
+
+        let ee_block = u32::from_le_bytes([
+            block[offset],
+            block[offset + 1],
+            block[offset + 2],
+            block[offset + 3],
+        ]);
+        let ee_len = u32::from_le_bytes([
+            block[offset + 4],
+            block[offset + 5],
+            block[offset + 6],
+            block[offset + 7],
+        ]);
+        let ee_start = u32::from_le_bytes([
+            block[offset + 8],
+            block[offset + 9],
+            block[offset + 10],
+            block[offset + 11],
+        ]);
+        let ee_comp_algo = u16::from_le_bytes([block[offset + 12], block[offset + 13]]);
+        let ee_enc_algo = block[offset + 14];
+        let ee_reserved = block[offset + 15];
+        let ee_flags = u16::from_le_bytes([block[offset + 16], block[offset + 17]]);
+        let ee_reserved2 = u16::from_le_bytes([block[offset + 18], block[offset + 19]]);
+        let ee_meta = u32::from_le_bytes([
+            block[offset + 20],
+            block[offset + 21],
+            block[offset + 22],
+            block[offset + 23],
+        ]);
+
+        extents.push(Extent {
+            ee_block,
+            ee_len,
+            ee_start,
+            ee_comp_algo,
+            ee_enc_algo,
+            ee_reserved,
+            ee_flags,
+            ee_reserved2,
+            ee_meta,
+        });
+
+        offset += 24;
+    }
+
+    Ok(XattrIndex {
+        total_size,
+        count,
+        extents,
+    })
+}
+
+/// Write xattr extent index block
+pub fn write_xattr_index(fs: &mut LolelfFs, block_num: u32, index: &XattrIndex) -> Result<()> {
+    let mut block = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+
+    // Write total_size and count
+    block[0..4].copy_from_slice(&index.total_size.to_le_bytes());
+    block[4..8].copy_from_slice(&index.count.to_le_bytes());
+
+    // Write extents
+    let mut offset = 8;
+    for extent in &index.extents {
+        if offset + 24 > block.len() {
+            break;
+        }
+
+        block[offset..offset + 4].copy_from_slice(&extent.ee_block.to_le_bytes());
+        block[offset + 4..offset + 8].copy_from_slice(&extent.ee_len.to_le_bytes());
+        block[offset + 8..offset + 12].copy_from_slice(&extent.ee_start.to_le_bytes());
+        block[offset + 12..offset + 14].copy_from_slice(&extent.ee_comp_algo.to_le_bytes());
+        block[offset + 14] = extent.ee_enc_algo;
+        block[offset + 15] = extent.ee_reserved;
+        block[offset + 16..offset + 18].copy_from_slice(&extent.ee_flags.to_le_bytes());
+        block[offset + 18..offset + 20].copy_from_slice(&extent.ee_reserved2.to_le_bytes());
+        block[offset + 20..offset + 24].copy_from_slice(&extent.ee_meta.to_le_bytes());
+
+        offset += 24;
+    }
+
+    fs.write_block(block_num, &block)
+}
+
+/// Read all xattr data from extents
+pub fn read_xattr_data(fs: &mut LolelfFs, index: &XattrIndex) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(index.total_size as usize);
+
+    for extent in &index.extents {
+        if extent.is_empty() {
+            break;
+        }
+
+        for i in 0..extent.ee_len {
+            let block_num = extent.ee_start + i;
+            let block = fs.read_block(block_num)?;
+            data.extend_from_slice(&block);
+        }
+    }
+
+    // Truncate to actual size
+    data.truncate(index.total_size as usize);
+    Ok(data)
+}
+
+/// Parse xattr entries from raw data
+pub fn parse_xattr_entries(data: &[u8]) -> Result<Vec<XattrEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + 12 <= data.len() {
+        let name_len = data[offset];
+        let name_index = data[offset + 1];
+        let value_len = u16::from_le_bytes([data[offset + 2], data[offset + 3]]);
+        let value_offset = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]);
+
+        // Check for end marker
+        if name_len == 0 && value_len == 0 {
+            break;
+        }
+
+        let header_offset = offset;
+        offset += 12;
+
+        // Read name (NUL-terminated)
+        if offset + name_len as usize > data.len() {
+            bail!("Corrupt xattr: name extends beyond data");
+        }
+
+        let name_bytes = &data[offset..offset + name_len as usize];
+        let name = String::from_utf8(name_bytes.to_vec())
+            .map_err(|e| anyhow::anyhow!("Invalid UTF-8 in xattr name: {}", e))?;
+
+        offset += name_len as usize;
+
+        // Skip NUL terminator
+        if offset >= data.len() || data[offset] != 0 {
+            bail!("Corrupt xattr: missing NUL terminator after name");
+        }
+        offset += 1;
+
+        // Read value
+        let value_abs_offset = header_offset + value_offset as usize;
+        if value_abs_offset + value_len as usize > data.len() {
+            bail!("Corrupt xattr: value extends beyond data");
+        }
+
+        let value = data[value_abs_offset..value_abs_offset + value_len as usize].to_vec();
+
+        let namespace = match name_index {
+            0 => XattrNamespace::User,
+            1 => XattrNamespace::Trusted,
+            2 => XattrNamespace::System,
+            3 => XattrNamespace::Security,
+            _ => bail!("Invalid xattr namespace index: {}", name_index),
+        };
+
+        entries.push(XattrEntry {
+            name_len: name.len() as u8,
+            name_index: namespace,
+            value_len: value.len() as u16,
+            value_offset: 0, // Will be calculated during serialization
+            name,
+            value,
+        });
+
+        // Advance offset past the value to get to the next entry
+        // Value is stored inline right after name+NUL
+        offset += value_len as usize;
+    }
+
+    Ok(entries)
+}
+
+/// Serialize xattr entries to bytes
+pub fn serialize_xattr_entries(entries: &[XattrEntry]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    for entry in entries {
+        let name_index_u8 = match entry.name_index {
+            XattrNamespace::User => 0u8,
+            XattrNamespace::Trusted => 1u8,
+            XattrNamespace::System => 2u8,
+            XattrNamespace::Security => 3u8,
+        };
+
+        let name_len = entry.name.len() as u8;
+        let value_len = entry.value.len() as u16;
+
+        // Calculate value offset: header(12) + name + NUL
+        let value_offset = 12 + name_len as u32 + 1;
+
+        // Write header
+        data.push(name_len);
+        data.push(name_index_u8);
+        data.extend_from_slice(&value_len.to_le_bytes());
+        data.extend_from_slice(&value_offset.to_le_bytes());
+        data.extend_from_slice(&[0u8; 4]); // reserved
+
+        // Write name + NUL
+        data.extend_from_slice(entry.name.as_bytes());
+        data.push(0); // NUL terminator
+
+        // Write value
+        data.extend_from_slice(&entry.value);
+    }
+
+    Ok(data)
+}
+
+/// Check an attribute against the per-name and per-value limits
+pub fn check_xattr_limits(base_name: &str, value: &[u8]) -> Result<()> {
+    if base_name.is_empty() {
+        bail!("Extended attribute name is empty");
+    }
+    if base_name.len() > LOLELFFS_XATTR_NAME_MAX {
+        bail!(
+            "Extended attribute name is {} bytes, the limit is {}",
+            base_name.len(),
+            LOLELFFS_XATTR_NAME_MAX
+        );
+    }
+    if value.len() > LOLELFFS_XATTR_VALUE_MAX {
+        bail!(
+            "Extended attribute value is {} bytes, the limit is {}",
+            value.len(),
+            LOLELFFS_XATTR_VALUE_MAX
+        );
+    }
+    Ok(())
+}
+
+/// Allocate `num_blocks` for xattr data, in one extent when a run is free
+fn alloc_xattr_extents(fs: &mut LolelfFs, num_blocks: u32) -> Result<Vec<Extent>> {
+    let mut extents: Vec<Extent> = Vec::new();
+    let mut allocated = 0u32;
+
+    while allocated < num_blocks {
+        let mut len = num_blocks - allocated;
+        let start = loop {
+            match fs.alloc_blocks(len) {
+                Ok(start) => break start,
+                Err(_) if len > 1 => len /= 2,
+                Err(e) => {
+                    for extent in &extents {
+                        fs.free_blocks(extent.ee_start, extent.ee_len)?;
+                    }
+                    return Err(e);
+                }
+            }
+        };
+        extents.push(Extent {
+            ee_block: allocated,
+            ee_len: len,
+            ee_start: start,
+            ..Extent::default()
+        });
+        allocated += len;
+    }
+
+    Ok(extents)
+}
+
+/// Store an inode's complete xattr set, replacing the one it had
+///
+/// The new data and index are written before the inode is pointed at them,
+/// and the old blocks are freed only after that, so a failure leaves the
+/// previous set intact. An empty set releases the index block as well. The
+/// inode is written back with any other changes the caller made to it.
+pub fn store_xattr_entries(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    inode: &mut Inode,
+    entries: &[XattrEntry],
+) -> Result<()> {
+    let data = serialize_xattr_entries(entries)?;
+    if data.len() > LOLELFFS_XATTR_SET_MAX {
+        bail!(
+            "Extended attributes need {} bytes, more than the {} allowed per inode",
+            data.len(),
+            LOLELFFS_XATTR_SET_MAX
+        );
+    }
+
+    // Block runs to free once the inode no longer reaches them
+    let mut released: Vec<(u32, u32)> = if inode.xattr_block != 0 {
+        read_xattr_index(fs, inode.xattr_block)?
+            .extents
+            .iter()
+            .take_while(|e| !e.is_empty())
+            .map(|e| (e.ee_start, e.ee_len))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if entries.is_empty() {
+        if inode.xattr_block != 0 {
+            released.push((inode.xattr_block, 1));
+            inode.xattr_block = 0;
+        }
+    } else {
+        let num_blocks = (data.len() as u32).div_ceil(LOLELFFS_BLOCK_SIZE);
+        let mut extents = alloc_xattr_extents(fs, num_blocks)?;
+
+        let index_block = if inode.xattr_block != 0 {
+            inode.xattr_block
+        } else {
+            match fs.alloc_blocks(1) {
+                Ok(block) => block,
+                Err(e) => {
+                    for extent in &extents {
+                        fs.free_blocks(extent.ee_start, extent.ee_len)?;
+                    }
+                    return Err(e);
+                }
+            }
+        };
+
+        let mut chunks = data.chunks(LOLELFFS_BLOCK_SIZE as usize);
+        for extent in &extents {
+            for block_num in extent.ee_start..extent.ee_start + extent.ee_len {
+                let chunk = chunks.next().unwrap_or_default();
+                let mut block = vec![0u8; LOLELFFS_BLOCK_SIZE as usize];
+                block[..chunk.len()].copy_from_slice(chunk);
+                fs.write_block(block_num, &block)?;
+            }
+        }
+
+        fs.barrier()?;
+        extents.resize(LOLELFFS_MAX_EXTENTS, Extent::default());
+        let index = XattrIndex {
+            total_size: data.len() as u32,
+            count: entries.len() as u32,
+            extents,
+        };
+        write_xattr_index(fs, index_block, &index)?;
+        inode.xattr_block = index_block;
+    }
+
+    fs.barrier()?;
+    fs.write_inode(inode_num, inode)?;
+    fs.barrier()?;
+    for (start, len) in released {
+        fs.free_blocks(start, len)?;
+    }
+
+    Ok(())
+}
+
+/// Give a new inode the xattrs its parent directory's defaults call for
+///
+/// The child must not have xattrs of its own yet. Nothing is written when
+/// the parent has no defaults.
+pub(crate) fn inherit_default_xattrs(
+    fs: &mut LolelfFs,
+    parent_inode_num: u32,
+    inode_num: u32,
+) -> Result<()> {
+    let parent = fs.read_inode(parent_inode_num)?;
+    if !parent.is_dir() || parent.xattr_block == 0 {
+        return Ok(());
+    }
+    let index = read_xattr_index(fs, parent.xattr_block)?;
+    let data = read_xattr_data(fs, &index)?;
+    let default_prefix = &DEFAULT_XATTR_PREFIX["user.".len()..];
+    let defaults: Vec<XattrEntry> = parse_xattr_entries(&data)?
+        .into_iter()
+        .filter(|e| e.name_index == XattrNamespace::User && e.name.starts_with(default_prefix))
+        .collect();
+    if defaults.is_empty() {
+        return Ok(());
+    }
+
+    let mut inode = fs.read_inode(inode_num)?;
+    let mut entries = Vec::new();
+    for default in defaults {
+        let name = &default.name[default_prefix.len()..];
+        if name.is_empty() {
+            continue;
+        }
+        entries.push(XattrEntry {
+            name_len: name.len() as u8,
+            name_index: XattrNamespace::User,
+            value_len: default.value_len,
+            value_offset: 0,
+            name: name.to_string(),
+            value: default.value.clone(),
+        });
+        if inode.is_dir() {
+            entries.push(default);
+        }
+    }
+    store_xattr_entries(fs, inode_num, &mut inode, &entries)
+}
+
+/// Report the xattr space used by an inode, or `None` if it has no xattrs
+pub fn xattr_usage(fs: &mut LolelfFs, inode_num: u32) -> Result<Option<XattrUsage>> {
+    let inode = fs.read_inode(inode_num)?;
+    if inode.xattr_block == 0 {
+        return Ok(None);
+    }
+
+    let index = read_xattr_index(fs, inode.xattr_block)?;
+    let used = index.extents.iter().take_while(|e| !e.is_empty());
+    Ok(Some(XattrUsage {
+        inode_num,
+        count: index.count,
+        bytes: index.total_size,
+        blocks: used.clone().map(|e| e.ee_len).sum(),
+        extents: used.count() as u32,
+    }))
+}
+
+/// Rewrite an inode's xattrs into as few extents as free space allows
+///
+/// Returns the extent count before and after. Sets above the capacity limit
+/// cannot be rewritten and must be trimmed with `removexattr` first.
+pub fn rebuild_xattrs(fs: &mut LolelfFs, inode_num: u32) -> Result<(u32, u32)> {
+    let before = match xattr_usage(fs, inode_num)? {
+        Some(usage) => usage,
+        None => return Ok((0, 0)),
+    };
+
+    let mut inode = fs.read_inode(inode_num)?;
+    let index = read_xattr_index(fs, inode.xattr_block)?;
+    let data = read_xattr_data(fs, &index)?;
+    let entries = parse_xattr_entries(&data)?;
+
+    store_xattr_entries(fs, inode_num, &mut inode, &entries)?;
+
+    let after = xattr_usage(fs, inode_num)?.map_or(0, |u| u.extents);
+    Ok((before.extents, after))
+}
+
+/// Find xattr blocks that no live inode references
+///
+/// A freed inode keeps its old `xattr_block`, so an inode released without
+/// `free_inode_xattrs` can still be traced to the blocks it leaked. Blocks are
+/// only reported while they are allocated and unclaimed by any live inode.
+pub fn find_orphan_xattrs(fs: &mut LolelfFs) -> Result<Vec<OrphanXattr>> {
+    let nr_blocks = fs.superblock.nr_blocks;
+    let mut referenced = fs.referenced_blocks()?;
+
+    let mut stale = Vec::new();
+    for inode_num in 0..fs.superblock.nr_inodes {
+        if !fs.is_inode_free(inode_num)? {
+            continue;
+        }
+        let inode = fs.read_inode(inode_num)?;
+        if inode.xattr_block != 0 && inode.xattr_block < nr_blocks {
+            stale.push((inode_num, inode.xattr_block));
+        }
+    }
+
+    let mut orphans = Vec::new();
+    for (inode_num, index_block) in stale {
+        if referenced.contains(&index_block) || fs.is_block_free(index_block)? {
+            continue;
+        }
+        // Several stale records may name the same index; claim it only once
+        referenced.insert(index_block);
+
+        let mut blocks = vec![index_block];
+        let index = read_xattr_index(fs, index_block)?;
+        for extent in index.extents.iter().take_while(|e| !e.is_empty()) {
+            for block in extent.ee_start..extent.ee_start.saturating_add(extent.ee_len) {
+                if block < nr_blocks && !referenced.contains(&block) && !fs.is_block_free(block)? {
+                    referenced.insert(block);
+                    blocks.push(block);
+                }
+            }
+        }
+
+        orphans.push(OrphanXattr {
+            inode_num,
+            index_block,
+            blocks,
+        });
+    }
+
+    Ok(orphans)
+}
+
+/// Free every orphaned xattr block, returning what was reclaimed
+pub fn free_orphan_xattrs(fs: &mut LolelfFs) -> Result<Vec<OrphanXattr>> {
+    let orphans = find_orphan_xattrs(fs)?;
+
+    for orphan in &orphans {
+        for &block in &orphan.blocks {
+            fs.free_blocks(block, 1)?;
+        }
+
+        // Drop the stale pointer so the blocks are never considered again
+        let mut inode = fs.read_inode(orphan.inode_num)?;
+        inode.xattr_block = 0;
+        fs.write_inode(orphan.inode_num, &inode)?;
+    }
+
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_image;
+
+    #[test]
+    fn test_parse_xattr_name() {
+        let (ns, name) = parse_xattr_name("user.foo").unwrap();
+        assert!(matches!(ns, XattrNamespace::User));
+        assert_eq!(name, "foo");
+
+        let (ns, name) = parse_xattr_name("security.selinux").unwrap();
+        assert!(matches!(ns, XattrNamespace::Security));
+        assert_eq!(name, "selinux");
+
+        assert!(parse_xattr_name("invalid").is_err());
+    }
+
+    #[test]
+    fn test_serialize_parse_roundtrip() {
+        let entries = vec![
+            XattrEntry {
+                name_len: 4,
+                name_index: XattrNamespace::User,
+                value_len: 6,
+                value_offset: 0,
+                name: "test".to_string(),
+                value: b"value1".to_vec(),
+            },
+            XattrEntry {
+                name_len: 7,
+                name_index: XattrNamespace::Security,
+                value_len: 36,
+                value_offset: 0,
+                name: "selinux".to_string(),
+                value: b"unconfined_u:object_r:user_home_t:s0".to_vec(),
+            },
+        ];
+
+        let serialized = serialize_xattr_entries(&entries).unwrap();
+        let parsed = parse_xattr_entries(&serialized).unwrap();
+
+        assert_eq!(entries.len(), parsed.len());
+        for (orig, parsed) in entries.iter().zip(parsed.iter()) {
+            assert_eq!(orig.name, parsed.name);
+            assert_eq!(orig.value, parsed.value);
+        }
+    }
+
+    #[test]
+    fn test_default_xattrs_are_inherited() {
+        let (_path, mut fs) = temp_image("xattr-default.img");
+        let dir = fs.mkdir(LOLELFFS_ROOT_INO, "builds").unwrap();
+        fs.set_xattr(dir, "user.lolelffs.default.provenance", b"ci-42")
+            .unwrap();
+        fs.set_xattr(dir, "user.comment", b"not inherited").unwrap();
+
+        let file = fs.create_file(dir, "out.bin").unwrap();
+        assert_eq!(fs.list_xattrs(file).unwrap(), vec!["user.provenance"]);
+        assert_eq!(fs.get_xattr(file, "user.provenance").unwrap(), b"ci-42");
+
+        // Subdirectories pass the defaults on
+        let sub = fs.mkdir(dir, "nested").unwrap();
+        let deep = fs.create_file(sub, "deep").unwrap();
+        assert_eq!(fs.get_xattr(deep, "user.provenance").unwrap(), b"ci-42");
+
+        let plain = fs.create_file(LOLELFFS_ROOT_INO, "plain").unwrap();
+        assert_eq!(fs.read_inode(plain).unwrap().xattr_block, 0);
+    }
+
+    #[test]
+    fn test_orphan_xattrs_are_reclaimed() {
+        let (_path, mut fs) = temp_image("xattr.img");
+
+        let kept = fs.create_file(LOLELFFS_ROOT_INO, "kept").unwrap();
+        fs.set_xattr(kept, "user.keep", b"yes").unwrap();
+
+        let free_before = fs.superblock.nr_free_blocks;
+        let leaked = fs.create_file(LOLELFFS_ROOT_INO, "leaked").unwrap();
+        fs.set_xattr(leaked, "user.big", &[7u8; 5000]).unwrap();
+
+        // Simulate a buggy delete that forgets free_inode_xattrs
+        let inode = fs.read_inode(leaked).unwrap();
+        fs.remove_dir_entry(LOLELFFS_ROOT_INO, "leaked").unwrap();
+        fs.free_blocks(inode.ei_block, 1).unwrap();
+        fs.free_inode(leaked).unwrap();
+
+        let orphans = find_orphan_xattrs(&mut fs).unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].inode_num, leaked);
+        assert_eq!(orphans[0].index_block, inode.xattr_block);
+        assert!(orphans[0].blocks.len() >= 2);
+
+        free_orphan_xattrs(&mut fs).unwrap();
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+        assert!(find_orphan_xattrs(&mut fs).unwrap().is_empty());
+        assert_eq!(fs.get_xattr(kept, "user.keep").unwrap(), b"yes");
+
+        // A properly deleted file leaves nothing behind
+        fs.unlink(LOLELFFS_ROOT_INO, "kept").unwrap();
+        assert!(find_orphan_xattrs(&mut fs).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_xattr_capacity_and_rebuild() {
+        let (_path, mut fs) = temp_image("xattr-index.img");
+        let ino = fs.create_file(LOLELFFS_ROOT_INO, "f").unwrap();
+
+        // Oversized names, values and sets are rejected, leaving the set as it was
+        fs.set_xattr(ino, "user.keep", b"yes").unwrap();
+        assert!(fs
+            .set_xattr(ino, &format!("user.{}", "n".repeat(256)), b"x")
+            .is_err());
+        assert!(fs.set_xattr(ino, "user.huge", &vec![0u8; 65536]).is_err());
+        for i in 0..7 {
+            let result = fs.set_xattr(ino, &format!("user.v{}", i), &[i as u8; 5000]);
+            assert_eq!(result.is_ok(), i < 6);
+        }
+        assert_eq!(fs.list_xattrs(ino).unwrap().len(), 7);
+        for i in 0..6 {
+            fs.remove_xattr(ino, &format!("user.v{}", i)).unwrap();
+        }
+
+        // Leave only single-block holes so a 3-block value has to be split
+        let free = fs.superblock.nr_free_blocks;
+        let start = fs.alloc_blocks(free).unwrap();
+        for hole in [0, 2, 4, 6] {
+            fs.free_blocks(start + hole, 1).unwrap();
+        }
+        fs.set_xattr(ino, "user.big", &[9u8; 9000]).unwrap();
+        assert_eq!(xattr_usage(&mut fs, ino).unwrap().unwrap().extents, 3);
+
+        fs.free_blocks(start + 8, free - 8).unwrap();
+        let free_before = fs.superblock.nr_free_blocks;
+        assert_eq!(rebuild_xattrs(&mut fs, ino).unwrap(), (3, 1));
+        assert_eq!(fs.superblock.nr_free_blocks, free_before);
+        assert_eq!(fs.get_xattr(ino, "user.big").unwrap(), vec![9u8; 9000]);
+        assert_eq!(fs.get_xattr(ino, "user.keep").unwrap(), b"yes");
+
+        let usage = xattr_usage(&mut fs, ino).unwrap().unwrap();
+        assert_eq!((usage.count, usage.blocks), (2, 3));
+        assert!(!usage.over_capacity());
+    }
+}
@@ -1,12 +1,16 @@
 use anyhow::{Context, Result};
 use clap::Parser;
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
 };
 use libc::{c_int, EEXIST, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTSUP};
 use log::{debug, error, info, warn};
-use lolelffs_tools::{Inode, LolelfFs, LOLELFFS_BLOCK_SIZE, LOLELFFS_ROOT_INO};
+use lolelffs_core::{
+    cache::LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS, FileOptions, FsError, FsFile, ImageLocator, Inode,
+    LolelfFs, OpenMode, VerifyPolicy, LOLELFFS_BLOCK_SIZE, LOLELFFS_MAX_FILENAME,
+    LOLELFFS_MAX_PATH_DEPTH, LOLELFFS_ROOT_INO,
+};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::path::PathBuf;
@@ -33,12 +37,21 @@ fn lolelffs_to_fuse_ino(lolelffs_ino: u32) -> u64 {
     }
 }
 
+/// Enter a span named after the FUSE operation (only with the `tracing`
+/// feature), so library spans nest under the request that caused them
+macro_rules! op_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
 /// FUSE driver for lolelffs filesystems
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Filesystem image path (can be raw image or ELF binary)
-    image: PathBuf,
+    /// Filesystem image (raw image, ELF binary, device or path@offset=N)
+    image: ImageLocator,
 
     /// Mount point directory
     mountpoint: PathBuf,
@@ -54,6 +67,20 @@ struct Args {
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
+
+    /// Checksum verification on reads: never, metadata, or always
+    #[arg(long, default_value = "metadata")]
+    verify: VerifyPolicy,
+
+    /// Decompressed blocks kept in memory for repeated reads (0 disables)
+    #[arg(long, value_name = "BLOCKS", default_value_t = LOLELFFS_DEFAULT_BLOCK_CACHE_BLOCKS)]
+    cache_blocks: usize,
+
+    /// Record operation spans as folded stacks in FILE (render with inferno
+    /// or flamegraph.pl)
+    #[cfg(feature = "tracing")]
+    #[arg(long, value_name = "FILE")]
+    trace_flame: Option<PathBuf>,
 }
 
 /// Main FUSE filesystem structure
@@ -62,6 +89,9 @@ struct LolelfFuseFs {
     read_only: bool,
     /// Maps child inode number to parent inode number for directory traversal
     parent_map: Arc<Mutex<HashMap<u64, u64>>>,
+    /// Files opened through `open`, by the handle given to the kernel
+    handles: HashMap<u64, FsFile>,
+    next_fh: u64,
 }
 
 impl LolelfFuseFs {
@@ -74,7 +104,24 @@ impl LolelfFuseFs {
             fs: Arc::new(Mutex::new(fs)),
             read_only,
             parent_map: Arc::new(Mutex::new(parent_map)),
+            handles: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// Number of directories between `ino` and the root, as far as lookups
+    /// have seen, capped just past `LOLELFFS_MAX_PATH_DEPTH`
+    fn depth(&self, mut ino: u64) -> usize {
+        let parent_map = self.parent_map.lock().unwrap();
+        let mut depth = 0;
+        while ino != FUSE_ROOT_INO && depth <= LOLELFFS_MAX_PATH_DEPTH {
+            match parent_map.get(&ino) {
+                Some(&parent) => ino = parent,
+                None => break,
+            }
+            depth += 1;
         }
+        depth
     }
 }
 
@@ -116,6 +163,20 @@ fn map_error(e: &anyhow::Error) -> c_int {
         return io_err.raw_os_error().unwrap_or(libc::EIO);
     }
 
+    match e.downcast_ref::<FsError>() {
+        Some(FsError::NoSpace { .. }) => return ENOSPC,
+        Some(FsError::ChecksumMismatch { .. }) | Some(FsError::BlockOutOfRange { .. }) => {
+            return libc::EIO
+        }
+        Some(FsError::SymlinkLoop { .. }) => return libc::ELOOP,
+        Some(FsError::NotOpenFor { .. }) => return libc::EBADF,
+        Some(FsError::Unsupported { .. }) => return libc::EOPNOTSUPP,
+        Some(FsError::NameTooLong { .. }) | Some(FsError::PathTooDeep { .. }) => {
+            return libc::ENAMETOOLONG
+        }
+        _ => {}
+    }
+
     // Pattern match on error messages
     if msg.contains("not found") || msg.contains("No such") {
         ENOENT
@@ -160,7 +221,25 @@ impl Filesystem for LolelfFuseFs {
         Ok(())
     }
 
+    fn destroy(&mut self) {
+        let mut fs = self.fs.lock().unwrap();
+        // Unmounting must leave the image complete for whoever opens it next
+        if let Err(e) = fs.flush() {
+            error!("Failed to flush image on unmount: {}", e);
+        }
+        let cache = fs.stats().block_cache;
+        info!(
+            "Block cache: {} hits, {} misses ({:.1}% hit rate), {} evictions",
+            cache.hits,
+            cache.misses,
+            cache.hit_rate() * 100.0,
+            cache.evictions
+        );
+        info!("Unmounted lolelffs FUSE filesystem");
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        op_span!("lookup", parent);
         debug!("lookup(parent={}, name={:?})", parent, name);
 
         let name_str = match name.to_str() {
@@ -171,6 +250,17 @@ impl Filesystem for LolelfFuseFs {
             }
         };
 
+        // Apply the same limits as the path resolvers; the kernel follows
+        // symlinks itself and enforces ELOOP
+        if name_str.len() > LOLELFFS_MAX_FILENAME {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        if self.depth(parent) >= LOLELFFS_MAX_PATH_DEPTH {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+
         let parent_ino = fuse_to_lolelffs_ino(parent);
         let mut fs = self.fs.lock().unwrap();
         match fs.lookup(parent_ino, name_str) {
@@ -213,6 +303,7 @@ impl Filesystem for LolelfFuseFs {
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        op_span!("getattr", ino);
         debug!("getattr(ino={})", ino);
 
         let lolelffs_ino = fuse_to_lolelffs_ino(ino);
@@ -238,6 +329,7 @@ impl Filesystem for LolelfFuseFs {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        op_span!("readdir", ino, offset);
         debug!("readdir(ino={}, offset={})", ino, offset);
 
         let mut fs = self.fs.lock().unwrap();
@@ -303,30 +395,97 @@ impl Filesystem for LolelfFuseFs {
         }
     }
 
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        op_span!("open", ino);
+        debug!("open(ino={}, flags={:#o})", ino, flags);
+
+        let access = flags & libc::O_ACCMODE;
+        let write = access != libc::O_RDONLY;
+        let options = FileOptions {
+            read: access != libc::O_WRONLY,
+            write,
+            append: write && flags & libc::O_APPEND != 0,
+            ..Default::default()
+        };
+        if write && self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let mut fs = self.fs.lock().unwrap();
+        match fs.open_inode(fuse_to_lolelffs_ino(ino), &options) {
+            Ok(file) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.handles.insert(fh, file);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                error!("Failed to open inode {}: {}", ino, e);
+                reply.error(map_error(&e));
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        op_span!("release", ino);
+        debug!("release(ino={}, fh={})", ino, fh);
+
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        op_span!("fsync", ino);
+        debug!("fsync(ino={}, fh={})", ino, fh);
+
+        let mut fs = self.fs.lock().unwrap();
+        let Some(file) = self.handles.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        match file.sync(&mut fs) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("Failed to sync inode {}: {}", ino, e);
+                reply.error(map_error(&e));
+            }
+        }
+    }
+
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
+        op_span!("read", ino, offset, size);
         debug!("read(ino={}, offset={}, size={})", ino, offset, size);
 
         let mut fs = self.fs.lock().unwrap();
-        match fs.read_file(fuse_to_lolelffs_ino(ino)) {
-            Ok(data) => {
-                let offset = offset as usize;
-                let end = (offset + size as usize).min(data.len());
-
-                if offset >= data.len() {
-                    reply.data(&[]);
-                } else {
-                    reply.data(&data[offset..end]);
-                }
+        let Some(file) = self.handles.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        // Only the blocks covering the request are decoded
+        let mut data = vec![0u8; size as usize];
+        match file.read_at(&mut fs, offset.max(0) as u64, &mut data) {
+            Ok(n) => {
+                reply.data(&data[..n]);
 
                 // Update atime
                 if let Ok(mut inode) = fs.read_inode(fuse_to_lolelffs_ino(ino)) {
@@ -344,6 +503,7 @@ impl Filesystem for LolelfFuseFs {
     }
 
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        op_span!("readlink", ino);
         debug!("readlink(ino={})", ino);
 
         let mut fs = self.fs.lock().unwrap();
@@ -381,6 +541,7 @@ impl Filesystem for LolelfFuseFs {
         _rdev: u32,
         reply: ReplyEntry,
     ) {
+        op_span!("mknod", parent);
         debug!("mknod(parent={}, name={:?}, mode={:o})", parent, name, mode);
 
         if self.read_only {
@@ -447,6 +608,7 @@ impl Filesystem for LolelfFuseFs {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        op_span!("mkdir", parent);
         debug!("mkdir(parent={}, name={:?}, mode={:o})", parent, name, mode);
 
         if self.read_only {
@@ -499,6 +661,7 @@ impl Filesystem for LolelfFuseFs {
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        op_span!("unlink", parent);
         debug!("unlink(parent={}, name={:?})", parent, name);
 
         if self.read_only {
@@ -539,6 +702,7 @@ impl Filesystem for LolelfFuseFs {
     }
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        op_span!("rmdir", parent);
         debug!("rmdir(parent={}, name={:?})", parent, name);
 
         if self.read_only {
@@ -586,6 +750,7 @@ impl Filesystem for LolelfFuseFs {
         link: &std::path::Path,
         reply: ReplyEntry,
     ) {
+        op_span!("symlink", parent);
         debug!(
             "symlink(parent={}, name={:?}, link={:?})",
             parent, name, link
@@ -648,6 +813,7 @@ impl Filesystem for LolelfFuseFs {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
+        op_span!("link", ino);
         debug!(
             "link(ino={}, newparent={}, newname={:?})",
             ino, newparent, newname
@@ -694,7 +860,7 @@ impl Filesystem for LolelfFuseFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -702,6 +868,7 @@ impl Filesystem for LolelfFuseFs {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        op_span!("write", ino, offset, len = data.len());
         debug!("write(ino={}, offset={}, size={})", ino, offset, data.len());
 
         if self.read_only {
@@ -710,30 +877,14 @@ impl Filesystem for LolelfFuseFs {
         }
 
         let mut fs = self.fs.lock().unwrap();
-
-        // Read current file contents
-        let mut file_data = match fs.read_file(fuse_to_lolelffs_ino(ino)) {
-            Ok(d) => d,
-            Err(e) => {
-                // If file doesn't exist or is empty, start with empty vec
-                debug!("File read returned error (may be empty): {}", e);
-                Vec::new()
-            }
+        let Some(file) = self.handles.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
         };
 
-        // Extend file if necessary
-        let offset = offset as usize;
-        let end_pos = offset + data.len();
-        if end_pos > file_data.len() {
-            file_data.resize(end_pos, 0);
-        }
-
-        // Write data at offset
-        file_data[offset..end_pos].copy_from_slice(data);
-
-        // Write back to filesystem
-        match fs.write_file(fuse_to_lolelffs_ino(ino), &file_data) {
-            Ok(()) => {
+        // Only the blocks the write touches are rewritten
+        match file.write_at(&mut fs, offset.max(0) as u64, data) {
+            Ok(_) => {
                 // Update mtime and ctime
                 if let Ok(mut inode) = fs.read_inode(fuse_to_lolelffs_ino(ino)) {
                     update_times(&mut inode, false, true, true);
@@ -762,13 +913,14 @@ impl Filesystem for LolelfFuseFs {
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        op_span!("setattr", ino);
         debug!("setattr(ino={})", ino);
 
         if self.read_only {
@@ -797,7 +949,12 @@ impl Filesystem for LolelfFuseFs {
                 }
 
                 if let Some(s) = size {
-                    if let Err(e) = fs.truncate(fuse_to_lolelffs_ino(ino), s as u32) {
+                    // ftruncate goes through the handle it was called on
+                    let truncated = match fh.and_then(|fh| self.handles.get_mut(&fh)) {
+                        Some(file) => file.truncate(&mut fs, s),
+                        None => fs.truncate(fuse_to_lolelffs_ino(ino), s as u32),
+                    };
+                    if let Err(e) = truncated {
                         error!("Failed to truncate file: {}", e);
                         reply.error(map_error(&e));
                         return;
@@ -1034,6 +1191,21 @@ impl Filesystem for LolelfFuseFs {
     }
 }
 
+#[cfg(feature = "tracing")]
+fn init_flame(
+    path: &std::path::Path,
+) -> Result<tracing_flame::FlushGuard<std::io::BufWriter<std::fs::File>>> {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let (layer, guard) = tracing_flame::FlameLayer::with_file(path)
+        .with_context(|| format!("Failed to create trace file {:?}", path))?;
+    // Not SubscriberInitExt::init, which would also replace env_logger
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+        .context("Failed to install the tracing subscriber")?;
+    info!("Recording operation spans to {:?}", path);
+    Ok(guard)
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -1041,30 +1213,49 @@ fn main() -> Result<()> {
     let log_level = if args.debug { "debug" } else { "info" };
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
 
-    info!("Opening lolelffs image: {:?}", args.image);
+    // Flushed when the filesystem is unmounted and main returns
+    #[cfg(feature = "tracing")]
+    let _flame_guard = match &args.trace_flame {
+        Some(path) => Some(init_flame(path)?),
+        None => None,
+    };
+
+    info!("Opening lolelffs image: {}", args.image);
 
     // Try to open filesystem (read-write or read-only)
-    let fs = if args.ro {
+    let mut fs = if args.ro {
         info!("Mounting read-only");
-        LolelfFs::open_readonly(&args.image)
-            .with_context(|| format!("Failed to open filesystem image: {:?}", args.image))?
+        LolelfFs::open_locator(&args.image, OpenMode::ReadOnly)
+            .with_context(|| format!("Failed to open filesystem image: {}", args.image))?
     } else {
-        match LolelfFs::open(&args.image) {
+        match LolelfFs::open_locator(&args.image, OpenMode::ReadWrite) {
             Ok(fs) => {
                 info!("Mounting read-write");
                 fs
             }
             Err(e) => {
                 warn!("Failed to open read-write, trying read-only: {}", e);
-                LolelfFs::open_readonly(&args.image)
-                    .with_context(|| format!("Failed to open filesystem image: {:?}", args.image))?
+                LolelfFs::open_locator(&args.image, OpenMode::ReadOnly)
+                    .with_context(|| format!("Failed to open filesystem image: {}", args.image))?
             }
         }
     };
 
+    fs.verify = args.verify;
+    info!("Checksum verification: {:?}", args.verify);
+    fs.set_block_cache_size(args.cache_blocks);
+
+    // Report the volume label as the filesystem name when one is set
+    let label = fs.superblock.label();
+    let fs_name = if label.is_empty() {
+        "lolelffs".to_string()
+    } else {
+        label
+    };
+
     let fuse_fs = LolelfFuseFs::new(fs, args.ro);
 
-    let mut mount_options = vec![MountOption::FSName("lolelffs".to_string())];
+    let mut mount_options = vec![MountOption::FSName(fs_name)];
 
     if args.ro {
         mount_options.push(MountOption::RO);
@@ -1,16 +1,12 @@
 //! lolelffs-tools: Userspace tools for interacting with lolelffs filesystems
 //!
-//! This library provides functionality to read, write, and manipulate lolelffs
-//! filesystem images without requiring the kernel module or mounting.
+//! The filesystem itself lives in `lolelffs-core`, re-exported here in full
+//! so existing `lolelffs_tools::` paths keep working. This crate adds what
+//! only the command-line tools need: mounting through the kernel module or
+//! the FUSE driver, and symlink farms over a mounted image. New code that
+//! only handles images should depend on `lolelffs-core` directly.
 
-pub mod bitmap;
-pub mod compress;
-pub mod dir;
-pub mod encrypt;
-pub mod file;
-pub mod fs;
-pub mod types;
-pub mod xattr;
+pub use lolelffs_core::*;
 
-pub use fs::LolelfFs;
-pub use types::*;
+pub mod mount;
+pub mod reflect;
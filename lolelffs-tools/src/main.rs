@@ -5,13 +5,71 @@ use chrono::{TimeZone, Utc};
 use clap::{Parser, Subcommand};
 use lolelffs_tools::*;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Exit status when a command fails, or when `fsck` finds errors it did not fix
+const EXIT_FAILURE: u8 = 1;
+/// Exit status when `df --check-thresholds` raises an alarm
+const EXIT_ALARM: u8 = 2;
+/// Host files larger than this are streamed into the image by `cp`
+/// instead of being read into memory first
+const CP_STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Set by `--quiet`
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// Set by `--json`
+static JSON: AtomicBool = AtomicBool::new(false);
+/// Set by `--progress-json`
+static PROGRESS_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Whether reports go to stdout as JSON rather than text
+fn json_output() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print a status message to stderr, keeping stdout for command output.
+/// Suppressed by `--quiet`; errors and warnings are not.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Ends a command with a specific exit status once its report is printed,
+/// letting everything it opened be dropped first
+#[derive(Debug)]
+struct ExitStatus(u8);
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "exit status {}", self.0)
+    }
+}
+
+impl std::error::Error for ExitStatus {}
 
 #[derive(Parser)]
 #[command(name = "lolelffs")]
 #[command(about = "CLI tools for interacting with lolelffs filesystems")]
 #[command(version)]
 struct Cli {
+    /// Suppress status messages on stderr
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Print reports as JSON (all commands except cat, export-tar, metadump, completions, complete, debug and shell)
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Stream progress events to stderr as JSON lines (mkfs, fsck, clone, convert, sync-image, import-tar, export-tar)
+    #[arg(long, global = true)]
+    progress_json: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,30 +80,87 @@ enum Commands {
     Ls {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path in the filesystem
         #[arg(default_value = "/")]
         path: String,
 
-        /// Long listing format
+        #[command(flatten)]
+        opts: LsArgs,
+    },
+
+    /// Show the directory hierarchy as an indented tree
+    Tree {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Directory to start from
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Show file sizes
         #[arg(short, long)]
-        long: bool,
+        size: bool,
+
+        /// Show inode numbers
+        #[arg(long)]
+        inodes: bool,
+    },
 
-        /// Show all files including hidden
+    /// Search for files by name, type, size, or modification time
+    ///
+    /// Takes find(1)-style arguments: [PATH...] [-name GLOB] [-iname GLOB]
+    /// [-type f|d|l] [-size [+-]N[kMG]] [-mtime [+-]DAYS] [-mmin [+-]MINUTES]
+    /// [-mindepth N] [-maxdepth N]. A leading + means "more than", - means
+    /// "less than".
+    Find {
+        /// Filesystem image path
         #[arg(short, long)]
-        all: bool,
+        image: ImageLocator,
+
+        /// Start paths followed by predicates
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        expression: Vec<String>,
     },
 
-    /// Read file contents
+    /// Read file contents (symlinks in the image are followed)
     Cat {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
-        /// Path to file
+        /// Paths to files, written out one after another
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Start reading each file at this byte offset (e.g. 4096, 1M)
+        #[arg(long)]
+        offset: Option<String>,
+
+        /// Read at most this many bytes of each file (e.g. 512, 64K)
+        #[arg(long)]
+        length: Option<String>,
+    },
+
+    /// Print the start of a file and what it looks like (text, image type)
+    Preview {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Path to file (symlinks in the image are followed)
         path: String,
 
+        /// Bytes to read from the start of the file (e.g. 4K, 64K)
+        #[arg(long, default_value = "64K")]
+        size: String,
+
         /// Password for encrypted filesystem
         #[arg(short = 'P', long)]
         password: Option<String>,
@@ -55,7 +170,7 @@ enum Commands {
     Write {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file
         path: String,
@@ -68,6 +183,15 @@ enum Commands {
         #[arg(short, long)]
         create: bool,
 
+        /// Add the data to the end of the file instead of replacing it
+        #[arg(short, long, conflicts_with = "offset")]
+        append: bool,
+
+        /// Overwrite the file from this byte offset (e.g. 4096, 1M), keeping
+        /// the rest of it
+        #[arg(long)]
+        offset: Option<String>,
+
         /// Password for encrypted filesystem
         #[arg(short = 'P', long)]
         password: Option<String>,
@@ -77,7 +201,7 @@ enum Commands {
     Mkdir {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to directory
         path: String,
@@ -91,7 +215,7 @@ enum Commands {
     Rm {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file or directory
         path: String,
@@ -105,32 +229,123 @@ enum Commands {
         dir: bool,
     },
 
+    /// Overwrite a file's data with random bytes, then remove it
+    Shred {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Path to file
+        path: String,
+
+        /// Number of overwrite passes
+        #[arg(short = 'n', long, default_value = "3")]
+        passes: u32,
+    },
+
+    /// Rename or move a file or directory
+    Mv {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Source path
+        source: String,
+
+        /// Destination path (moves into it if it is a directory)
+        dest: String,
+    },
+
     /// Create an empty file
     Touch {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file
         path: String,
     },
 
+    /// Change permission bits of a file or directory
+    Chmod {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Octal (e.g. 755) or symbolic (e.g. u+x,go-w) mode
+        mode: String,
+
+        /// Path to file or directory
+        path: String,
+    },
+
+    /// Change owner and group of a file or directory
+    Chown {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Numeric owner and/or group (UID, UID:GID, or :GID)
+        owner: String,
+
+        /// Path to file or directory
+        path: String,
+
+        /// Change directories and their contents recursively
+        #[arg(short = 'R', long)]
+        recursive: bool,
+    },
+
     /// Show file or inode information
     Stat {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file or directory
         path: String,
     },
 
+    /// Interactive shell with a working directory (debugfs-style)
+    Shell {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Open the image read-only
+        #[arg(short, long)]
+        read_only: bool,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Apply commands read from stdin, one per line, against a single open image
+    Batch {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Report failing lines and go on instead of stopping at the first
+        #[arg(long)]
+        keep_going: bool,
+
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
     /// Create a new filesystem
     Mkfs {
         /// Filesystem image path
         image: PathBuf,
 
-        /// Size in bytes (e.g., 1M, 10M, 100M)
+        /// Size in bytes (e.g., 1M, 10M, 100M), or "auto" for the smallest
+        /// image holding --from-dir or --template
         #[arg(short, long)]
         size: Option<String>,
 
@@ -149,34 +364,233 @@ enum Commands {
         /// PBKDF2 iterations
         #[arg(long, default_value = "100000")]
         iterations: u32,
+
+        /// Encrypt metadata too, so a locked image reveals nothing beyond
+        /// its size and parameters
+        #[arg(long, requires = "encrypt")]
+        opaque: bool,
+
+        /// Populate the new image with the contents of this image
+        #[arg(long)]
+        template: Option<ImageLocator>,
+
+        /// Password for an encrypted template image
+        #[arg(long, requires = "template")]
+        template_password: Option<String>,
+
+        /// Populate the new image with the contents of this host directory
+        #[arg(long, conflicts_with = "template")]
+        from_dir: Option<PathBuf>,
+
+        /// Volume label (up to 64 bytes)
+        #[arg(short = 'L', long)]
+        label: Option<String>,
+
+        /// Reproducible image: nil UUID and timestamps from $SOURCE_DATE_EPOCH (or 0)
+        #[arg(long)]
+        deterministic: bool,
     },
 
     /// Check filesystem integrity
     Fsck {
         /// Filesystem image path
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Verbose output
         #[arg(short, long)]
         verbose: bool,
+
+        /// Free xattr blocks left behind by deleted inodes
+        #[arg(long)]
+        orphan_xattrs: bool,
+
+        /// Free unnamed inodes that were never linked (only when nothing else has the image open)
+        #[arg(long)]
+        orphans: bool,
+
+        /// Drop corrupt entries from directory blocks that fail their checksum
+        #[arg(long)]
+        repair_dirs: bool,
+
+        /// Re-link inodes no directory names into /lost+found
+        #[arg(long)]
+        lost_found: bool,
+
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
+    /// Summarize image health with recommendations (quick; no tree walk)
+    Doctor {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+    },
+
+    /// Dump all metadata (no file data) in a stable, diffable form
+    Metadump {
+        /// Filesystem image path
+        image: ImageLocator,
+
+        /// Output format (text or json)
+        #[arg(long, default_value = "text")]
+        format: crate::metadump::DumpFormat,
+
+        /// Record each file's SHA-256 content hash
+        #[arg(long)]
+        content_hashes: bool,
+
+        /// Also store file contents in this directory, named by hash (implies --content-hashes)
+        #[arg(long, value_name = "DIR")]
+        export_data: Option<PathBuf>,
+
+        /// Password for encrypted filesystem (needed to hash contents)
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Rebuild an image from a JSON metadump and a store of file contents
+    Metarestore {
+        /// JSON dump written by `metadump --format json`
+        dump: PathBuf,
+
+        /// Directory of file contents written by `metadump --export-data`
+        #[arg(long, value_name = "DIR")]
+        data_dir: Option<PathBuf>,
+
+        /// Image to create
+        image: PathBuf,
     },
 
     /// Show filesystem statistics
     Df {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Human-readable sizes
         #[arg(short = 'H', long)]
         human: bool,
+
+        /// Check usage alarms and exit with status 2 if any is exceeded
+        #[arg(long)]
+        check_thresholds: bool,
+
+        /// Block usage alarm threshold in percent
+        #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u32).range(0..=100), requires = "check_thresholds")]
+        max_block_use: u32,
+
+        /// Inode usage alarm threshold in percent
+        #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u32).range(0..=100), requires = "check_thresholds")]
+        max_inode_use: u32,
+
+        /// Extent count alarm threshold per file or directory
+        #[arg(long, default_value_t = 150, requires = "check_thresholds")]
+        max_extents: usize,
+    },
+
+    /// Check files against the SHA-256 digests stored in their xattrs
+    Verify {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Directory to check
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Record digests for files that have none yet
+        #[arg(long)]
+        update: bool,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Validate one file's extent map and decode every block it names
+    CheckExtents {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// File to check
+        path: String,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Compare two images, listing added (A), removed (D) and modified (M) paths
+    Diff {
+        /// First image
+        a: ImageLocator,
+
+        /// Second image
+        b: ImageLocator,
+
+        /// Directory to compare in both images
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Print per-path size changes and totals instead of what changed
+        #[arg(long)]
+        stat: bool,
+
+        /// Do not report modification time differences
+        #[arg(long)]
+        ignore_times: bool,
+
+        /// Password for encrypted images (used for both)
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Summarize block usage per directory
+    #[command(disable_help_flag = true)]
+    Du {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Directory to summarize
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Human-readable sizes
+        #[arg(short = 'h', short_alias = 'H', long)]
+        human: bool,
+
+        /// Only print totals for directories this deep or shallower
+        #[arg(short = 'd', long)]
+        max_depth: Option<usize>,
+
+        /// Print help
+        #[arg(long, action = clap::ArgAction::Help)]
+        help: Option<bool>,
+    },
+
+    /// Report logical size, on-disk blocks and compression per file
+    Compstat {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// File or directory to report on
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Password for encrypted filesystem (to measure encrypted blocks)
+        #[arg(short = 'P', long)]
+        password: Option<String>,
     },
 
     /// Create a link
     Ln {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Target path
         target: String,
@@ -193,90 +607,514 @@ enum Commands {
     Super {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
+
+        /// Show sizes in human units and check the layout for inconsistencies
+        #[arg(short, long)]
+        verbose: bool,
     },
 
-    /// Unlock encrypted filesystem
-    Unlock {
+    /// Show or change the volume label
+    Label {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
-        /// Password for decryption
-        #[arg(short, long)]
+        /// New label (an empty string clears it); omit to print the current one
+        label: Option<String>,
+    },
+
+    /// Rebuild an image into a new, compacted one with the same contents
+    Clone {
+        /// Image to copy
+        src: ImageLocator,
+
+        /// New image to create
+        dst: PathBuf,
+
+        /// Shrink the new image to the smallest size that fits the data
+        #[arg(long)]
+        minimize: bool,
+
+        /// Password of an encrypted source, also used for the new image
+        #[arg(short = 'P', long)]
         password: Option<String>,
     },
 
-    /// Copy file from host to filesystem
-    Cp {
-        /// Filesystem image path
-        #[arg(short, long)]
-        image: PathBuf,
+    /// Convert an ext2 image to lolelffs, or a lolelffs image to ext2
+    Convert {
+        /// Read SRC as this format and write a lolelffs image (ext2)
+        #[arg(long, value_name = "FORMAT", value_parser = parse_foreign_format, required_unless_present = "to", conflicts_with = "to")]
+        from: Option<String>,
 
-        /// Source file on host
-        source: PathBuf,
+        /// Write DST in this format from a lolelffs image (ext2)
+        #[arg(long, value_name = "FORMAT", value_parser = parse_foreign_format)]
+        to: Option<String>,
 
-        /// Destination path in filesystem
-        dest: String,
+        /// Image to convert
+        src: PathBuf,
 
-        /// Password for encrypted filesystem
+        /// New image to create
+        dst: PathBuf,
+
+        /// Size of the new image (e.g., 200M, 1G); defaults to the source's
+        /// size, grown as needed to fit the tree
+        #[arg(long)]
+        size: Option<String>,
+
+        /// Password of an encrypted lolelffs source
         #[arg(short = 'P', long)]
         password: Option<String>,
     },
 
-    /// Extract file from filesystem to host
-    Extract {
+    /// Grow or shrink a filesystem image to a new size
+    Resize {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
-
-        /// Source path in filesystem
-        source: String,
+        image: ImageLocator,
 
-        /// Destination file on host
-        dest: PathBuf,
+        /// New size in bytes (e.g., 200M, 1G)
+        #[arg(short, long)]
+        size: String,
     },
 
-    /// Get an extended attribute value
-    Getfattr {
+    /// Rewrite fragmented files into contiguous extents
+    Defrag {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
-        /// Path to file or directory
-        path: String,
+        /// Only defragment this file (default: every regular file)
+        path: Option<String>,
 
-        /// Attribute name (e.g., user.comment, security.selinux)
-        name: String,
+        /// Report fragmentation without changing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
 
-        /// Print value as hex dump
-        #[arg(short = 'x', long)]
-        hex: bool,
+        #[command(flatten)]
+        scratch: ScratchArgs,
     },
 
-    /// Set an extended attribute
-    Setfattr {
+    /// Rewrite existing files with another compression algorithm
+    Recompress {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
-        /// Path to file or directory
+        /// Compression algorithm to migrate to (e.g. zstd, lz4, none)
+        #[arg(short, long)]
+        algo: String,
+
+        /// File or directory to rewrite (default: the whole image)
+        #[arg(default_value = "/")]
         path: String,
 
-        /// Attribute name (e.g., user.comment, security.selinux)
-        #[arg(short, long)]
-        name: String,
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
 
-        /// Attribute value
-        #[arg(short, long)]
-        value: String,
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
+    /// Mount an image with the kernel module, or with FUSE when that is unavailable
+    Mount {
+        /// Filesystem image (raw image, ELF binary, device or path@offset=N)
+        image: ImageLocator,
+
+        /// Mount point directory
+        dir: PathBuf,
+
+        /// Driver to use: auto (kernel module if root, loadable and the image
+        /// uses nothing it lacks), kernel, or fuse
+        #[arg(short, long, default_value = "auto")]
+        backend: mount::MountBackend,
+
+        /// Mount read-only
+        #[arg(short, long)]
+        ro: bool,
+    },
+
+    /// Show an image as a symlink tree in a real directory while a command
+    /// (an interactive shell by default) runs there, then tear it down
+    ///
+    /// Without --via-fuse the whole image is first extracted into a private
+    /// temporary directory, which takes as much time and free space as the
+    /// image's contents.
+    Reflect {
+        /// Filesystem image (raw image, ELF binary, device or path@offset=N)
+        image: ImageLocator,
+
+        /// Directory to fill with the symlink tree (created if missing,
+        /// must be empty)
+        target: PathBuf,
+
+        /// Serve contents from a hidden read-only FUSE mount instead of
+        /// copying the whole image to a temporary directory, where changes
+        /// are discarded
+        #[arg(long)]
+        via_fuse: bool,
+
+        /// Password for an encrypted filesystem (extraction only)
+        #[arg(short = 'P', long, conflicts_with = "via_fuse")]
+        password: Option<String>,
+
+        /// Command to run in the target directory (default: $SHELL)
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+
+    /// Unmount a lolelffs mount, waiting for a FUSE driver to flush and exit
+    Umount {
+        /// Mount point directory
+        dir: PathBuf,
+    },
+
+    /// List mounted lolelffs filesystems with their images
+    Mounts,
+
+    /// List the compression codecs this build can read and write
+    Codecs,
+
+    /// List the encryption ciphers this build can read and write
+    Ciphers,
+
+    /// Move all data out of a block range, e.g. a suspected bad area
+    Balance {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Blocks to empty, as FIRST-LAST (inclusive)
+        #[arg(short, long, value_name = "FIRST-LAST", value_parser = parse_block_range)]
+        blocks: std::ops::Range<u32>,
+
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
+    /// Run a concurrent create/write/read/delete/rename workload, then fsck
+    Stress {
+        /// Filesystem image path (checked after the run)
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Worker threads
+        #[arg(short = 'j', long, default_value_t = 4)]
+        threads: usize,
+
+        /// Seconds to run for
+        #[arg(short, long, default_value_t = 10)]
+        duration: u64,
+
+        /// Seed for the workers' random choices
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Largest file written (e.g. 64K, 1M)
+        #[arg(long, default_value = "64K")]
+        max_size: String,
+
+        /// Most files each worker keeps at once
+        #[arg(long, default_value_t = 32)]
+        max_files: usize,
+
+        /// Operation weights, e.g. create=2,write=3,read=3,delete=1,rename=1,list=1
+        #[arg(long)]
+        mix: Option<stress::StressMix>,
+
+        /// Directory the workers share, created under the root if missing
+        #[arg(long, default_value = "stress")]
+        dir: String,
+
+        /// Go through this mount point of the image instead of the library
+        #[arg(long, value_name = "MOUNTPOINT")]
+        mount: Option<PathBuf>,
+    },
+
+    /// Print a shell completion script (bash and zsh also complete paths inside images)
+    Completions {
+        /// Shell to generate the script for
+        shell: clap_complete::Shell,
+    },
+
+    /// List in-image paths completing a partial path (used by completion scripts)
+    #[command(hide = true)]
+    Complete {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Partial path to complete
+        #[arg(default_value = "")]
+        partial: String,
+    },
+
+    /// Adjust tunable filesystem parameters
+    Tune {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Never compress files whose name matches this glob or extension (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        comp_exclude: Vec<String>,
+
+        /// Remove all compression exclusions (applied before --comp-exclude)
+        #[arg(long)]
+        clear_comp_exclude: bool,
+
+        /// Checksum directory blocks with this algorithm (crc32c, xxh64, sha256, blake3, none)
+        #[arg(long, value_name = "ALGO")]
+        dir_checksums: Option<String>,
+
+        /// Flush data before the metadata that references it (on, off)
+        #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+        ordered_writes: Option<bool>,
+
+        /// Let files with identical contents share their blocks (on, off)
+        #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+        reflink: Option<bool>,
+
+        /// Default compression algorithm for new writes (none, lz4, zlib, zstd)
+        #[arg(long, value_name = "ALGO")]
+        comp_algo: Option<String>,
+
+        /// Compress new writes by default (on, off)
+        #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+        compression: Option<bool>,
+
+        /// Minimum block size to compress
+        #[arg(long, value_name = "BYTES")]
+        comp_min_block_size: Option<u32>,
+
+        /// Longest extent for blocks without per-block metadata
+        #[arg(long, value_name = "BLOCKS")]
+        max_extent_blocks: Option<u32>,
+
+        /// PBKDF2 iterations for the password (re-wraps the master key)
+        #[arg(long)]
+        kdf_iterations: Option<u32>,
+
+        /// Password, needed with --kdf-iterations (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+
+    /// Print blkid-style identification of an image
+    Id {
+        /// Filesystem image path
+        image: ImageLocator,
+    },
+
+    /// Unlock encrypted filesystem
+    Unlock {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Password for decryption
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+
+    /// Change the encryption password (re-wraps the master key only)
+    Passwd {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Current password (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+
+        /// New password (will prompt twice if not provided)
+        #[arg(long)]
+        new_password: Option<String>,
+
+        /// PBKDF2 iterations for the new password (default: keep the current count)
+        #[arg(long)]
+        kdf_iterations: Option<u32>,
+    },
+
+    /// Decrypt every block in place and turn encryption off
+    Decrypt {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Password (will prompt if not provided)
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Copy file from host to filesystem
+    Cp {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Source file on host
+        source: PathBuf,
+
+        /// Destination path in filesystem
+        dest: String,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Copy a host directory tree
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Copy symlinks as symlinks instead of following them
+        #[arg(long)]
+        symlinks: bool,
+
+        /// Record each file's SHA-256 for `verify`
+        #[arg(long)]
+        checksum: bool,
+    },
+
+    /// Extract file from filesystem to host
+    Extract {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Source path in filesystem
+        source: String,
+
+        /// Destination file on host
+        dest: PathBuf,
+
+        /// Extract a directory tree
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Skip files stored with an algorithm this build cannot decode
+        /// instead of failing
+        #[arg(long)]
+        skip_unsupported: bool,
+
+        /// Read files in the order they are stored on the image rather than
+        /// by path, for sequential I/O on slow or networked media
+        #[arg(long, requires = "recursive")]
+        physical_order: bool,
+    },
+
+    /// Extract the whole filesystem into a host directory
+    ExtractAll {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Host directory to fill (created if missing, must be empty)
+        dest: PathBuf,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Copy extended attributes to the host as well
+        #[arg(long)]
+        xattrs: bool,
+
+        /// Skip files stored with an algorithm this build cannot decode
+        /// instead of failing
+        #[arg(long)]
+        skip_unsupported: bool,
+
+        /// Read files in the order they are stored on the image rather than
+        /// by path
+        #[arg(long)]
+        physical_order: bool,
+    },
+
+    /// Get an extended attribute value
+    Getfattr {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Path to file or directory
+        path: String,
+
+        /// Attribute name (e.g., user.comment, security.selinux)
+        name: String,
+
+        /// Print value as hex dump
+        #[arg(short = 'x', long)]
+        hex: bool,
+    },
+
+    /// Set an extended attribute
+    Setfattr {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Path to file or directory
+        path: String,
+
+        /// Attribute name (e.g., user.comment, security.selinux)
+        #[arg(short, long)]
+        name: String,
+
+        /// Attribute value
+        #[arg(short, long)]
+        value: String,
+
+        /// Set a user.* default that files and directories later created in
+        /// the directory inherit
+        #[arg(long)]
+        default: bool,
+    },
+
+    /// Set a file's access hint: +hot (uncompressed, near metadata), +cold
+    /// (zstd, end of device), or -hot/-cold to clear it
+    Chattr {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// +hot, +cold, -hot or -cold
+        #[arg(allow_hyphen_values = true)]
+        attr: String,
+
+        /// Path to file
+        path: String,
+    },
+
+    /// Give a file its own compression algorithm and rewrite it with it now;
+    /// later writes (FUSE included) keep using it
+    Setcomp {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Path to file
+        path: String,
+
+        /// Compression algorithm (e.g. zstd, lz4, none)
+        #[arg(short, long, required_unless_present = "clear")]
+        algo: Option<String>,
+
+        /// Go back to the image default from the next write
+        #[arg(long, conflicts_with = "algo")]
+        clear: bool,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
     },
 
     /// List all extended attributes
     Listxattr {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file or directory
         path: String,
@@ -286,7 +1124,7 @@ enum Commands {
     Removexattr {
         /// Filesystem image path
         #[arg(short, long)]
-        image: PathBuf,
+        image: ImageLocator,
 
         /// Path to file or directory
         path: String,
@@ -294,598 +1132,4014 @@ enum Commands {
         /// Attribute name
         name: String,
     },
-}
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+    /// Report or compact per-inode extended attribute storage
+    XattrIndex {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
 
-    match cli.command {
-        Commands::Ls {
-            image,
-            path,
-            long,
-            all,
-        } => cmd_ls(&image, &path, long, all),
-        Commands::Cat {
-            image,
-            path,
-            password,
-        } => cmd_cat(&image, &path, password),
-        Commands::Write {
-            image,
-            path,
-            data,
-            create,
-            password,
-        } => cmd_write(&image, &path, data, create, password),
-        Commands::Mkdir {
-            image,
-            path,
-            parents,
-        } => cmd_mkdir(&image, &path, parents),
-        Commands::Rm {
-            image,
-            path,
-            recursive,
-            dir,
-        } => cmd_rm(&image, &path, recursive, dir),
-        Commands::Touch { image, path } => cmd_touch(&image, &path),
-        Commands::Stat { image, path } => cmd_stat(&image, &path),
-        Commands::Mkfs {
-            image,
-            size,
-            encrypt,
-            password,
-            algo,
-            iterations,
-        } => cmd_mkfs(&image, size, encrypt, password, &algo, iterations),
-        Commands::Fsck { image, verbose } => cmd_fsck(&image, verbose),
-        Commands::Df { image, human } => cmd_df(&image, human),
-        Commands::Ln {
-            image,
-            target,
-            link,
-            symbolic,
-        } => cmd_ln(&image, &target, &link, symbolic),
-        Commands::Super { image } => cmd_super(&image),
-        Commands::Unlock { image, password } => cmd_unlock(&image, password),
-        Commands::Cp {
-            image,
-            source,
-            dest,
-            password,
-        } => cmd_cp(&image, &source, &dest, password),
-        Commands::Extract {
-            image,
-            source,
-            dest,
-        } => cmd_extract(&image, &source, &dest),
+        #[command(subcommand)]
+        action: XattrIndexAction,
+    },
 
-        Commands::Getfattr {
-            image,
-            path,
-            name,
-            hex,
-        } => cmd_getfattr(&image, &path, &name, hex),
+    /// Sync another lolelffs image into this one, reusing inodes where paths match
+    SyncImage {
+        /// Destination filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Source filesystem image path
+        source: ImageLocator,
+
+        /// Remove entries not present in the source image
+        #[arg(long)]
+        delete: bool,
+
+        /// Password for encrypted destination filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Password for encrypted source filesystem
+        #[arg(long)]
+        source_password: Option<String>,
+    },
+
+    /// Mirror a host directory into the image, rewriting only changed files
+    Sync {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Host directory to mirror
+        source: PathBuf,
+
+        /// Destination directory in the filesystem
+        #[arg(default_value = "/")]
+        dest: String,
+
+        /// Remove entries not present in the host directory
+        #[arg(long)]
+        delete: bool,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
+    /// Import a tar archive (optionally gzip-compressed) into the filesystem
+    ImportTar {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Tar archive to import ("-" for stdin)
+        archive: PathBuf,
+
+        /// Directory in the filesystem to extract into
+        #[arg(short, long, default_value = "/")]
+        dest: String,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Create entries in sorted order with timestamps from
+        /// $SOURCE_DATE_EPOCH (or 0), so the same tree always gives the same
+        /// image (buffers the whole archive in memory)
+        #[arg(long)]
+        deterministic: bool,
+
+        /// Record each file's SHA-256 for `verify`
+        #[arg(long)]
+        checksum: bool,
+
+        #[command(flatten)]
+        scratch: ScratchArgs,
+    },
+
+    /// Export a directory tree as a tar archive
+    ExportTar {
+        /// Filesystem image path
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Output archive ("-" for stdout; .gz/.tgz names are gzip-compressed)
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+
+        /// Directory in the filesystem to export
+        #[arg(default_value = "/")]
+        path: String,
+
+        /// Gzip-compress the archive
+        #[arg(short = 'z', long)]
+        gzip: bool,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+
+        /// Leave out files stored with an algorithm this build cannot decode
+        /// instead of failing
+        #[arg(long)]
+        skip_unsupported: bool,
+
+        /// Archive files in the order they are stored on the image rather
+        /// than by path, for sequential I/O on slow or networked media
+        #[arg(long)]
+        physical_order: bool,
+    },
+
+    /// Inspect deleted data without modifying the image
+    Forensic {
+        /// Filesystem image path (opened in forensic mode, never written)
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        #[command(subcommand)]
+        action: ForensicAction,
+    },
+
+    /// Dump raw on-disk structures for format debugging
+    Debug {
+        /// Filesystem image path (opened in forensic mode, never written)
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        #[command(subcommand)]
+        action: DebugAction,
+    },
+
+    /// Show, commit or discard the changes held in a scratch file
+    Scratch {
+        /// Filesystem image the scratch file was made for
+        #[arg(short, long)]
+        image: ImageLocator,
+
+        /// Scratch file written by a command run with --scratch
+        #[arg(short, long)]
+        scratch: PathBuf,
+
+        #[command(subcommand)]
+        action: ScratchAction,
+    },
+}
+
+/// `--scratch` and `--commit` for commands that modify an image
+#[derive(clap::Args)]
+struct ScratchArgs {
+    /// Send every write to this scratch file and leave the image untouched
+    #[arg(long, value_name = "FILE")]
+    scratch: Option<PathBuf>,
+
+    /// Fold the scratch file into the image once the command succeeds
+    #[arg(long, requires = "scratch")]
+    commit: bool,
+}
+
+/// What `ls` prints, shared with the shell's `ls`
+#[derive(clap::Args, Clone, Copy, Default)]
+struct LsArgs {
+    /// Long listing format; `+` after the permissions marks extended
+    /// attributes
+    #[arg(short, long)]
+    long: bool,
+
+    /// Show all files including hidden
+    #[arg(short, long)]
+    all: bool,
+
+    /// List subdirectories recursively, one section per directory
+    #[arg(short = 'R', long)]
+    recursive: bool,
+
+    /// Print each entry's inode number (-i in the shell; here -i is --image)
+    #[arg(long)]
+    inode: bool,
+
+    /// Print the number of blocks allocated to each entry
+    #[arg(short, long)]
+    size: bool,
+}
+
+/// One line of a `batch` script
+#[derive(Parser)]
+#[command(name = "batch", no_binary_name = true, disable_help_subcommand = true)]
+struct BatchLine {
+    #[command(subcommand)]
+    command: BatchCommand,
+}
+
+/// What a `batch` script can do; arguments are those of the commands of the
+/// same name, without `--image`
+#[derive(Subcommand)]
+enum BatchCommand {
+    /// Create a directory
+    Mkdir {
+        path: String,
+        #[arg(short, long)]
+        parents: bool,
+    },
+
+    /// Copy a host file or tree into the image
+    Cp {
+        source: PathBuf,
+        dest: String,
+        #[arg(short, long)]
+        recursive: bool,
+        #[arg(long)]
+        symlinks: bool,
+        #[arg(long)]
+        checksum: bool,
+    },
+
+    /// Replace a file's contents
+    Write {
+        path: String,
+        #[arg(short, long)]
+        data: String,
+        #[arg(short, long)]
+        create: bool,
+    },
+
+    /// Create a file or update its timestamps
+    Touch { path: String },
+
+    /// Change permissions
+    Chmod { mode: String, path: String },
+
+    /// Change owner and/or group
+    Chown {
+        owner: String,
+        path: String,
+        #[arg(short = 'R', long)]
+        recursive: bool,
+    },
+
+    /// Set an extended attribute
+    Setfattr {
+        path: String,
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        value: String,
+    },
+
+    /// Create a hard or symbolic link
+    Ln {
+        target: String,
+        link: String,
+        #[arg(short, long)]
+        symbolic: bool,
+    },
+
+    /// Move or rename
+    Mv { source: String, dest: String },
+
+    /// Remove a file or directory
+    Rm {
+        path: String,
+        #[arg(short, long)]
+        recursive: bool,
+        #[arg(short, long)]
+        dir: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ScratchAction {
+    /// Count the blocks the scratch file holds
+    Status,
+
+    /// Write the held blocks into the image and remove the scratch file
+    Commit,
+
+    /// Remove the scratch file, dropping its changes
+    Discard,
+}
+
+#[derive(Subcommand)]
+enum XattrIndexAction {
+    /// Show attribute count, size and extents for every inode with xattrs
+    Usage,
+
+    /// Rewrite xattr data into as few extents as possible
+    Rebuild {
+        /// Only rebuild this path (default: every inode with xattrs)
+        path: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DebugAction {
+    /// Hexdump a raw block (repeated lines are collapsed to "*")
+    DumpBlock {
+        /// Block number
+        block: u32,
+    },
+
+    /// Print every field of a raw inode, and its extent index
+    DumpInode {
+        /// Inode number
+        inode: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum ForensicAction {
+    /// List unallocated data blocks, optionally dumping them to a host file
+    Unallocated {
+        /// Host file to receive the raw unallocated blocks
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// List extent indexes of deleted files
+    Orphans {
+        /// Host directory to recover orphaned files into
+        #[arg(short, long)]
+        recover: Option<PathBuf>,
+
+        /// Password for encrypted filesystem
+        #[arg(short = 'P', long)]
+        password: Option<String>,
+    },
+
+    /// Find file signatures in unallocated blocks
+    Carve {
+        /// Host directory to write carved candidates into
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    QUIET.store(cli.quiet, Ordering::Relaxed);
+    JSON.store(cli.json, Ordering::Relaxed);
+    PROGRESS_JSON.store(cli.progress_json, Ordering::Relaxed);
+
+    match run(cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => match err.downcast_ref::<ExitStatus>() {
+            Some(status) => ExitCode::from(status.0),
+            None => {
+                eprintln!("Error: {:?}", err);
+                ExitCode::from(EXIT_FAILURE)
+            }
+        },
+    }
+}
+
+fn run(command: Commands) -> Result<()> {
+    // These write file data, an archive, a script or raw dumps to stdout,
+    // or are interactive; everything else prints its report as JSON or
+    // prints nothing to stdout at all
+    if json_output() {
+        match command {
+            Commands::Metadump { .. } => {
+                bail!("--json is not supported for metadump; use --format json")
+            }
+            Commands::Cat { .. }
+            | Commands::ExportTar { .. }
+            | Commands::Completions { .. }
+            | Commands::Complete { .. }
+            | Commands::Debug { .. }
+            | Commands::Shell { .. } => bail!("--json is not supported for this command"),
+            _ => {}
+        }
+    }
+
+    match command {
+        Commands::Ls { image, path, opts } => cmd_ls(&image, &path, &opts),
+        Commands::Tree {
+            image,
+            path,
+            size,
+            inodes,
+        } => cmd_tree(&image, &path, size, inodes),
+        Commands::Find { image, expression } => cmd_find(&image, &expression),
+        Commands::Preview {
+            image,
+            path,
+            size,
+            password,
+        } => cmd_preview(&image, &path, parse_size(&size)?, password),
+        Commands::Cat {
+            image,
+            paths,
+            password,
+            offset,
+            length,
+        } => {
+            let offset = offset.as_deref().map(parse_size).transpose()?;
+            let length = length.as_deref().map(parse_size).transpose()?;
+            cmd_cat(&image, &paths, password, offset, length)
+        }
+        Commands::Write {
+            image,
+            path,
+            data,
+            create,
+            append,
+            offset,
+            password,
+        } => {
+            let offset = offset.as_deref().map(parse_size).transpose()?;
+            cmd_write(&image, &path, data, create, append, offset, password)
+        }
+        Commands::Mkdir {
+            image,
+            path,
+            parents,
+        } => cmd_mkdir(&image, &path, parents),
+        Commands::Rm {
+            image,
+            path,
+            recursive,
+            dir,
+        } => cmd_rm(&image, &path, recursive, dir),
+        Commands::Shred {
+            image,
+            path,
+            passes,
+        } => cmd_shred(&image, &path, passes),
+        Commands::Mv {
+            image,
+            source,
+            dest,
+        } => cmd_mv(&image, &source, &dest),
+        Commands::Touch { image, path } => cmd_touch(&image, &path),
+        Commands::Chmod { image, mode, path } => cmd_chmod(&image, &mode, &path),
+        Commands::Chown {
+            image,
+            owner,
+            path,
+            recursive,
+        } => cmd_chown(&image, &owner, &path, recursive),
+        Commands::Stat { image, path } => cmd_stat(&image, &path),
+        Commands::Shell {
+            image,
+            read_only,
+            password,
+        } => cmd_shell(&image, read_only, password),
+        Commands::Batch {
+            image,
+            password,
+            keep_going,
+            scratch,
+        } => cmd_batch(&image, password, keep_going, &scratch),
+        Commands::Mkfs {
+            image,
+            size,
+            encrypt,
+            password,
+            algo,
+            iterations,
+            opaque,
+            template,
+            template_password,
+            from_dir,
+            label,
+            deterministic,
+        } => cmd_mkfs(
+            &image,
+            size,
+            encrypt.then_some((password, algo.as_str(), iterations, opaque)),
+            label.as_deref(),
+            template.map(|path| (path, template_password)),
+            from_dir.as_deref(),
+            deterministic,
+        ),
+        Commands::Fsck {
+            image,
+            verbose,
+            orphan_xattrs,
+            orphans,
+            repair_dirs,
+            lost_found,
+            scratch,
+        } => cmd_fsck(
+            &image,
+            verbose,
+            orphan_xattrs,
+            orphans,
+            repair_dirs,
+            lost_found,
+            &scratch,
+        ),
+        Commands::Metadump {
+            image,
+            format,
+            content_hashes,
+            export_data,
+            password,
+        } => cmd_metadump(&image, format, content_hashes, export_data, password),
+        Commands::Metarestore {
+            dump,
+            data_dir,
+            image,
+        } => cmd_metarestore(&dump, data_dir, &image),
+        Commands::Doctor { image } => cmd_doctor(&image),
+        Commands::Diff {
+            a,
+            b,
+            path,
+            stat,
+            ignore_times,
+            password,
+        } => cmd_diff(&a, &b, &path, stat, ignore_times, password),
+        Commands::Df {
+            image,
+            human,
+            check_thresholds,
+            max_block_use,
+            max_inode_use,
+            max_extents,
+        } => {
+            let thresholds = check_thresholds.then_some(Thresholds {
+                max_block_use,
+                max_inode_use,
+                max_extents,
+            });
+            cmd_df(&image, human, thresholds)
+        }
+        Commands::Verify {
+            image,
+            path,
+            update,
+            password,
+        } => cmd_verify(&image, &path, update, password),
+        Commands::CheckExtents {
+            image,
+            path,
+            password,
+        } => cmd_check_extents(&image, &path, password),
+        Commands::Du {
+            image,
+            path,
+            human,
+            max_depth,
+            ..
+        } => cmd_du(&image, &path, human, max_depth),
+        Commands::Compstat {
+            image,
+            path,
+            password,
+        } => cmd_compstat(&image, &path, password),
+        Commands::Ln {
+            image,
+            target,
+            link,
+            symbolic,
+        } => cmd_ln(&image, &target, &link, symbolic),
+        Commands::Super { image, verbose } => cmd_super(&image, verbose),
+        Commands::Label { image, label } => cmd_label(&image, label.as_deref()),
+        Commands::Clone {
+            src,
+            dst,
+            minimize,
+            password,
+        } => cmd_clone(&src, &dst, minimize, password),
+        Commands::Convert {
+            from,
+            to: _,
+            src,
+            dst,
+            size,
+            password,
+        } => cmd_convert(from.is_some(), &src, &dst, size.as_deref(), password),
+        Commands::Resize { image, size } => cmd_resize(&image, &size),
+        Commands::Defrag {
+            image,
+            path,
+            dry_run,
+            scratch,
+        } => cmd_defrag(&image, path, dry_run, &scratch),
+        Commands::Recompress {
+            image,
+            algo,
+            path,
+            password,
+            scratch,
+        } => cmd_recompress(&image, &algo, &path, password, &scratch),
+        Commands::Mount {
+            image,
+            dir,
+            backend,
+            ro,
+        } => cmd_mount(&image, &dir, backend, ro),
+        Commands::Reflect {
+            image,
+            target,
+            via_fuse,
+            password,
+            command,
+        } => cmd_reflect(&image, &target, via_fuse, password, &command),
+        Commands::Umount { dir } => cmd_umount(&dir),
+        Commands::Mounts => cmd_mounts(),
+        Commands::Codecs => cmd_codecs(),
+        Commands::Ciphers => cmd_ciphers(),
+        Commands::Balance {
+            image,
+            blocks,
+            scratch,
+        } => cmd_balance(&image, blocks, &scratch),
+        Commands::Stress {
+            image,
+            threads,
+            duration,
+            seed,
+            max_size,
+            max_files,
+            mix,
+            dir,
+            mount,
+        } => {
+            let options = stress::StressOptions {
+                threads,
+                duration: std::time::Duration::from_secs(duration),
+                seed,
+                max_file_size: parse_size(&max_size)? as usize,
+                max_files,
+                mix: mix.unwrap_or_default(),
+            };
+            cmd_stress(&image, &dir, mount, &options)
+        }
+        Commands::Completions { shell } => cmd_completions(shell),
+        Commands::Complete { image, partial } => cmd_complete(&image, &partial),
+        Commands::Tune {
+            image,
+            comp_exclude,
+            clear_comp_exclude,
+            dir_checksums,
+            ordered_writes,
+            reflink,
+            comp_algo,
+            compression,
+            comp_min_block_size,
+            max_extent_blocks,
+            kdf_iterations,
+            password,
+        } => {
+            let comp_algo = comp_algo
+                .map(|name| crate::compress::parse_algo_name(&name))
+                .transpose()?;
+            let params = TuneParams {
+                comp_algo,
+                comp_enabled: compression,
+                comp_min_block_size,
+                max_extent_blocks_large: max_extent_blocks,
+                kdf_iterations,
+                reflink,
+            };
+            cmd_tune(
+                &image,
+                &comp_exclude,
+                clear_comp_exclude,
+                dir_checksums,
+                ordered_writes,
+                &params,
+                password,
+            )
+        }
+        Commands::Id { image } => cmd_id(&image),
+        Commands::Unlock { image, password } => cmd_unlock(&image, password),
+        Commands::Passwd {
+            image,
+            password,
+            new_password,
+            kdf_iterations,
+        } => cmd_passwd(&image, password, new_password, kdf_iterations),
+        Commands::Decrypt { image, password } => cmd_decrypt(&image, password),
+        Commands::Cp {
+            image,
+            source,
+            dest,
+            password,
+            recursive,
+            symlinks,
+            checksum,
+        } => cmd_cp(
+            &image, &source, &dest, password, recursive, symlinks, checksum,
+        ),
+        Commands::Extract {
+            image,
+            source,
+            dest,
+            recursive,
+            skip_unsupported,
+            physical_order,
+        } => cmd_extract(
+            &image,
+            &source,
+            &dest,
+            recursive,
+            skip_unsupported,
+            physical_order,
+        ),
+        Commands::ExtractAll {
+            image,
+            dest,
+            password,
+            xattrs,
+            skip_unsupported,
+            physical_order,
+        } => cmd_extract_all(
+            &image,
+            &dest,
+            password,
+            xattrs,
+            skip_unsupported,
+            physical_order,
+        ),
+
+        Commands::Getfattr {
+            image,
+            path,
+            name,
+            hex,
+        } => cmd_getfattr(&image, &path, &name, hex),
+
+        Commands::Setfattr {
+            image,
+            path,
+            name,
+            value,
+            default,
+        } => cmd_setfattr(&image, &path, &name, &value, default),
+
+        Commands::Chattr { image, attr, path } => cmd_chattr(&image, &attr, &path),
+        Commands::Setcomp {
+            image,
+            path,
+            algo,
+            clear: _,
+            password,
+        } => cmd_setcomp(&image, &path, algo.as_deref(), password),
+        Commands::Listxattr { image, path } => cmd_listxattr(&image, &path),
+
+        Commands::Removexattr { image, path, name } => cmd_removexattr(&image, &path, &name),
+        Commands::XattrIndex { image, action } => cmd_xattr_index(&image, action),
+
+        Commands::SyncImage {
+            image,
+            source,
+            delete,
+            password,
+            source_password,
+        } => cmd_sync_image(&image, &source, delete, password, source_password),
+        Commands::Sync {
+            image,
+            source,
+            dest,
+            delete,
+            password,
+            scratch,
+        } => cmd_sync(&image, &source, &dest, delete, password, &scratch),
+        Commands::ImportTar {
+            image,
+            archive,
+            dest,
+            password,
+            deterministic,
+            checksum,
+            scratch,
+        } => cmd_import_tar(
+            &image,
+            &archive,
+            &dest,
+            password,
+            deterministic,
+            checksum,
+            &scratch,
+        ),
+        Commands::ExportTar {
+            image,
+            output,
+            path,
+            gzip,
+            password,
+            skip_unsupported,
+            physical_order,
+        } => {
+            let opts = archive::TarExportOptions {
+                skip_unsupported,
+                physical_order,
+            };
+            cmd_export_tar(&image, &output, &path, gzip, password, &opts)
+        }
+        Commands::Forensic { image, action } => cmd_forensic(&image, action),
+        Commands::Debug { image, action } => cmd_debug(&image, action),
+        Commands::Scratch {
+            image,
+            scratch,
+            action,
+        } => cmd_scratch(&image, &scratch, action),
+    }
+}
+
+fn cmd_ls(image: &ImageLocator, path: &str, opts: &LsArgs) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let inode_num = fs.resolve_path(path)?;
+
+    let inode = fs.read_inode(inode_num)?;
+
+    if inode.is_file() {
+        // Just show the file itself
+        let filename = path.rsplit('/').next().unwrap_or(path);
+        if json_output() {
+            let entry = dir::DirEntry {
+                inode_num,
+                filename: filename.to_string(),
+                inode,
+            };
+            println!("{}", entry.to_json().to_pretty());
+        } else {
+            print_ls_entry(&mut fs, filename, inode_num, &inode, opts)?;
+        }
+        return Ok(());
+    }
+
+    print_ls_dir(&mut fs, path, inode_num, opts)
+}
+
+/// List a directory, or with `recursive` the whole tree below it
+///
+/// Shared by `ls` and the shell. Like coreutils, a recursive listing has a
+/// "path:" header per directory, sections in pre-order, and hidden
+/// directories only entered with `all`.
+fn print_ls_dir(fs: &mut LolelfFs, path: &str, inode_num: u32, opts: &LsArgs) -> Result<()> {
+    let all = opts.all;
+    if json_output() {
+        let mut listed = Vec::new();
+        if opts.recursive {
+            for walked in fs.walk_tree(inode_num)? {
+                if !all && walked.path.split('/').any(|c| c.starts_with('.')) {
+                    continue;
+                }
+                let mut entry = walked.entry.to_json();
+                entry.push("path", walked.path);
+                listed.push(entry);
+            }
+        } else {
+            for entry in fs.list_dir(inode_num)? {
+                if all || !entry.filename.starts_with('.') {
+                    listed.push(entry.to_json());
+                }
+            }
+        }
+        println!("{}", JsonValue::List(listed).to_pretty());
+        return Ok(());
+    }
+
+    if !opts.recursive {
+        let entries = fs.list_dir(inode_num)?;
+        print_ls_entries(fs, &entries, opts)?;
+        return Ok(());
+    }
+
+    let mut sections = vec![(path.to_string(), inode_num)];
+    for walked in fs.walk_tree(inode_num)? {
+        let hidden = walked.path.split('/').any(|c| c.starts_with('.'));
+        if walked.entry.inode.is_dir() && (all || !hidden) {
+            let dir_path = format!("{}/{}", path.trim_end_matches('/'), walked.path);
+            sections.push((dir_path, walked.entry.inode_num));
+        }
+    }
+
+    for (idx, (dir_path, dir_inode)) in sections.iter().enumerate() {
+        if idx > 0 {
+            println!();
+        }
+        println!("{}:", dir_path);
+        let mut entries = fs.list_dir(*dir_inode)?;
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        print_ls_entries(fs, &entries, opts)?;
+    }
+
+    Ok(())
+}
+
+fn print_ls_entries(fs: &mut LolelfFs, entries: &[dir::DirEntry], opts: &LsArgs) -> Result<()> {
+    for entry in entries {
+        if !opts.all && entry.filename.starts_with('.') {
+            continue;
+        }
+        print_ls_entry(fs, &entry.filename, entry.inode_num, &entry.inode, opts)?;
+    }
+    Ok(())
+}
+
+fn cmd_tree(image: &ImageLocator, path: &str, size: bool, inodes: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let inode_num = fs.resolve_path(path)?;
+
+    if !fs.read_inode(inode_num)?.is_dir() {
+        bail!("'{}' is not a directory", path);
+    }
+
+    if json_output() {
+        return print_tree_json(&mut fs, path, inode_num);
+    }
+
+    println!("{}", path);
+
+    // Whether the ancestor at each depth was the last of its siblings
+    let mut last_at_depth: Vec<bool> = Vec::new();
+    let mut dirs = 0;
+    let mut files = 0;
+
+    for walked in fs.walk_tree(inode_num)? {
+        last_at_depth.truncate(walked.depth - 1);
+
+        let mut line = String::new();
+        for &last in &last_at_depth {
+            line.push_str(if last { "    " } else { "│   " });
+        }
+        line.push_str(if walked.is_last {
+            "└── "
+        } else {
+            "├── "
+        });
+
+        let inode = &walked.entry.inode;
+        let mut details = Vec::new();
+        if inodes {
+            details.push(walked.entry.inode_num.to_string());
+        }
+        if size {
+            details.push(format_size(inode.i_size as u64));
+        }
+        if !details.is_empty() {
+            line.push_str(&format!("[{}]  ", details.join(" ")));
+        }
+        line.push_str(&walked.entry.filename);
+
+        if inode.is_symlink() {
+            line.push_str(&format!(
+                " -> {}",
+                String::from_utf8_lossy(&fs.read_file(walked.entry.inode_num)?)
+            ));
+        }
+        if inode.is_dir() {
+            dirs += 1;
+        } else {
+            files += 1;
+        }
+
+        println!("{}", line);
+        last_at_depth.push(walked.is_last);
+    }
+
+    println!();
+    println!("{} directories, {} files", dirs, files);
+
+    Ok(())
+}
+
+/// `tree` as nested JSON: each directory's entries under `contents`, the
+/// way `tree -J` nests them
+fn print_tree_json(fs: &mut LolelfFs, path: &str, inode_num: u32) -> Result<()> {
+    // Directories still being filled, innermost last
+    let mut open: Vec<(JsonValue, Vec<JsonValue>)> = Vec::new();
+    let mut top = Vec::new();
+    let close = |open: &mut Vec<(JsonValue, Vec<JsonValue>)>, top: &mut Vec<JsonValue>| {
+        if let Some((mut dir, contents)) = open.pop() {
+            dir.push("contents", contents);
+            open.last_mut().map_or(&mut *top, |(_, c)| c).push(dir);
+        }
+    };
+    let (mut dirs, mut files) = (0u32, 0u32);
+
+    for walked in fs.walk_tree(inode_num)? {
+        while open.len() >= walked.depth {
+            close(&mut open, &mut top);
+        }
+        let mut entry = walked.entry.to_json();
+        if walked.entry.inode.is_symlink() {
+            let target = fs.read_file(walked.entry.inode_num)?;
+            entry.push("target", String::from_utf8_lossy(&target).into_owned());
+        }
+        if walked.entry.inode.is_dir() {
+            dirs += 1;
+            open.push((entry, Vec::new()));
+        } else {
+            files += 1;
+            open.last_mut().map_or(&mut top, |(_, c)| c).push(entry);
+        }
+    }
+    while !open.is_empty() {
+        close(&mut open, &mut top);
+    }
+
+    let out = JsonValue::object([
+        ("path", path.into()),
+        ("directories", dirs.into()),
+        ("files", files.into()),
+        ("contents", top.into()),
+    ]);
+    println!("{}", out.to_pretty());
+    Ok(())
+}
+
+/// A single `find` test applied to each visited entry
+enum FindPredicate {
+    Name(glob::Pattern, bool),
+    Type(char),
+    Size(std::cmp::Ordering, u64),
+    Age(std::cmp::Ordering, u64, u64),
+}
+
+impl FindPredicate {
+    fn matches(&self, name: &str, inode: &Inode, now: u64) -> bool {
+        match self {
+            FindPredicate::Name(pattern, case_sensitive) => pattern.matches_with(
+                name,
+                glob::MatchOptions {
+                    case_sensitive: *case_sensitive,
+                    ..Default::default()
+                },
+            ),
+            FindPredicate::Type(kind) => inode.type_char() == *kind,
+            FindPredicate::Size(ord, size) => (inode.i_size as u64).cmp(size) == *ord,
+            FindPredicate::Age(ord, units, unit_secs) => {
+                let age = now.saturating_sub(inode.i_mtime as u64) / unit_secs;
+                age.cmp(units) == *ord
+            }
+        }
+    }
+}
+
+/// Split a "+N"/"-N"/"N" argument into a comparison and the remaining text
+fn parse_find_comparison(arg: &str) -> (std::cmp::Ordering, &str) {
+    if let Some(rest) = arg.strip_prefix('+') {
+        (std::cmp::Ordering::Greater, rest)
+    } else if let Some(rest) = arg.strip_prefix('-') {
+        (std::cmp::Ordering::Less, rest)
+    } else {
+        (std::cmp::Ordering::Equal, arg)
+    }
+}
+
+fn cmd_find(image: &ImageLocator, expression: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut predicates = Vec::new();
+    let mut min_depth = 0usize;
+    let mut max_depth = usize::MAX;
+
+    let mut args = expression.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if !predicates.is_empty() {
+                bail!("Paths must come before predicates: '{}'", arg);
+            }
+            paths.push(arg.clone());
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing value for '{}'", arg))?;
+        match arg.as_str() {
+            "-name" | "-iname" => {
+                let pattern = glob::Pattern::new(value)
+                    .with_context(|| format!("Invalid pattern '{}'", value))?;
+                predicates.push(FindPredicate::Name(pattern, arg == "-name"));
+            }
+            "-type" => match value.as_str() {
+                "f" => predicates.push(FindPredicate::Type('-')),
+                "d" => predicates.push(FindPredicate::Type('d')),
+                "l" => predicates.push(FindPredicate::Type('l')),
+                _ => bail!("Unknown type '{}' (expected f, d, or l)", value),
+            },
+            "-size" => {
+                let (ord, size) = parse_find_comparison(value);
+                predicates.push(FindPredicate::Size(ord, parse_size(size)?));
+            }
+            "-mtime" | "-mmin" => {
+                let (ord, units) = parse_find_comparison(value);
+                let units = units
+                    .parse()
+                    .with_context(|| format!("Invalid value for {}: {}", arg, value))?;
+                let unit_secs = if arg == "-mtime" { 86400 } else { 60 };
+                predicates.push(FindPredicate::Age(ord, units, unit_secs));
+            }
+            "-mindepth" => {
+                min_depth = value
+                    .parse()
+                    .with_context(|| format!("Invalid depth: {}", value))?
+            }
+            "-maxdepth" => {
+                max_depth = value
+                    .parse()
+                    .with_context(|| format!("Invalid depth: {}", value))?
+            }
+            _ => bail!("Unknown predicate '{}'", arg),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push("/".to_string());
+    }
+
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let matches =
+        |name: &str, inode: &Inode| predicates.iter().all(|p| p.matches(name, inode, now));
+    // With --json, matches are listed like `ls -R` entries once all are found
+    let mut listed = Vec::new();
+    let mut found = |path: String, entry: dir::DirEntry| {
+        if json_output() {
+            let mut entry = entry.to_json();
+            entry.push("path", path);
+            listed.push(entry);
+        } else {
+            println!("{}", path);
+        }
+    };
+
+    for path in &paths {
+        let inode_num = fs.resolve_path(path)?;
+        let inode = fs.read_inode(inode_num)?;
+        let name = match path.trim_end_matches('/').rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => "/",
+        };
+
+        if min_depth == 0 && matches(name, &inode) {
+            let entry = dir::DirEntry {
+                inode_num,
+                filename: name.to_string(),
+                inode: inode.clone(),
+            };
+            found(path.clone(), entry);
+        }
+        if !inode.is_dir() || max_depth == 0 {
+            continue;
+        }
+
+        let base = path.trim_end_matches('/');
+        for walked in fs.walk_tree(inode_num)? {
+            if walked.depth < min_depth || walked.depth > max_depth {
+                continue;
+            }
+            if matches(&walked.entry.filename, &walked.entry.inode) {
+                found(format!("{}/{}", base, walked.path), walked.entry);
+            }
+        }
+    }
+
+    if json_output() {
+        println!("{}", JsonValue::List(listed).to_pretty());
+    }
+    Ok(())
+}
+
+/// One `ls -l` line; files this build cannot decode are flagged at the end
+/// One line of `ls`: inode number and block count columns if asked for,
+/// then the name or the long format
+fn print_ls_entry(
+    fs: &mut LolelfFs,
+    filename: &str,
+    inode_num: u32,
+    inode: &Inode,
+    opts: &LsArgs,
+) -> Result<()> {
+    let mut columns = String::new();
+    if opts.inode {
+        columns.push_str(&format!("{:7} ", inode_num));
+    }
+    if opts.size {
+        columns.push_str(&format!("{:5} ", inode.i_blocks));
+    }
+    if !opts.long {
+        println!("{}{}", columns, filename);
+        return Ok(());
+    }
+
+    let unsupported = if inode.is_file() {
+        fs.unsupported_algorithm(inode_num)?
+            .map(|what| format!("  [unsupported: {}]", what))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let mtime = Utc
+        .timestamp_opt(inode.i_mtime as i64, 0)
+        .single()
+        .map(|dt| dt.format("%b %d %H:%M").to_string())
+        .unwrap_or_else(|| "???".to_string());
+
+    // Like the ACL marker of coreutils
+    let xattrs = if inode.xattr_block != 0 { "+" } else { "" };
+
+    println!(
+        "{}{}{}{} {:3} {:5} {:5} {:8} {} {}{}",
+        columns,
+        inode.type_char(),
+        inode.perm_string(),
+        xattrs,
+        inode.i_nlink,
+        inode.i_uid,
+        inode.i_gid,
+        inode.i_size,
+        mtime,
+        filename,
+        unsupported
+    );
+    Ok(())
+}
+
+fn cmd_cat(
+    image: &ImageLocator,
+    paths: &[String],
+    password: Option<String>,
+    offset: Option<u64>,
+    length: Option<u64>,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+
+    // Unlock if encrypted and password provided
+    unlock_if_needed(&mut fs, password)?;
+
+    let offset = offset.unwrap_or(0);
+    let length = length.unwrap_or(u64::MAX);
+    let mut out = io::BufWriter::new(io::stdout().lock());
+
+    // Like cat(1), a path that cannot be read is reported and skipped
+    let mut failed = false;
+    for path in paths {
+        let result = fs
+            .resolve_path_follow(path, true)
+            .and_then(|inode_num| fs.read_file_range_to(inode_num, offset, length, &mut out));
+        if let Err(e) = result {
+            if paths.len() == 1 {
+                return Err(e);
+            }
+            out.flush()?;
+            eprintln!("Error: {}: {:#}", path, e);
+            failed = true;
+        }
+    }
+    out.flush()?;
+
+    if failed {
+        return Err(ExitStatus(EXIT_FAILURE).into());
+    }
+    Ok(())
+}
+
+fn cmd_preview(
+    image: &ImageLocator,
+    path: &str,
+    size: u64,
+    password: Option<String>,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
+
+    let inode_num = fs.resolve_path_follow(path, true)?;
+    let preview = fs.preview(inode_num, size)?;
+
+    if json_output() {
+        let out = JsonValue::object([
+            ("path", path.into()),
+            ("size", preview.size.into()),
+            ("mime", preview.kind.mime().into()),
+            ("bytes", (preview.data.len() as u64).into()),
+            ("truncated", preview.truncated.into()),
+            ("text", preview.text().into()),
+        ]);
+        println!("{}", out.to_pretty());
+    } else {
+        info!(
+            "{}: {}, {} of {} bytes",
+            path,
+            preview.kind.mime(),
+            preview.data.len(),
+            preview.size
+        );
+        io::stdout().lock().write_all(&preview.data)?;
+    }
+    Ok(())
+}
+
+fn cmd_write(
+    image: &ImageLocator,
+    path: &str,
+    data: Option<String>,
+    create: bool,
+    append: bool,
+    offset: Option<u64>,
+    password: Option<String>,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+
+    // Unlock if encrypted and password provided
+    unlock_if_needed(&mut fs, password)?;
+
+    // Get the data to write
+    let content = match data {
+        Some(d) => d.into_bytes(),
+        None => {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            buf
+        }
+    };
+
+    if append || offset.is_some() {
+        return patch_path(&mut fs, path, &content, create, append, offset.unwrap_or(0));
+    }
+    write_path(&mut fs, path, &content, create)
+}
+
+/// Write `content` into the file at `path` from `offset`, or at its end
+/// with `append`, keeping the rest of the file
+fn patch_path(
+    fs: &mut LolelfFs,
+    path: &str,
+    content: &[u8],
+    create: bool,
+    append: bool,
+    offset: u64,
+) -> Result<()> {
+    let options = FileOptions {
+        read: false,
+        write: true,
+        append,
+        create,
+        truncate: false,
+    };
+    let mut file = fs.open_file(path, &options)?;
+    file.seek(fs, std::io::SeekFrom::Start(offset))?;
+    file.write(fs, content)?;
+    Ok(())
+}
+
+/// Replace the contents of the file at `path`, creating it if `create`
+fn write_path(fs: &mut LolelfFs, path: &str, content: &[u8], create: bool) -> Result<()> {
+    match fs.resolve_path(path) {
+        Ok(inode_num) => {
+            fs.write_file(inode_num, content)?;
+        }
+        Err(_) if create => {
+            // Create the file
+            let (parent_path, filename) = split_path(path);
+            let parent_inode = fs.resolve_path(&parent_path)?;
+            let inode_num = fs.create_file(parent_inode, filename)?;
+            fs.write_file(inode_num, content)?;
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
+
+fn cmd_mkdir(image: &ImageLocator, path: &str, parents: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    mkdir_path(&mut fs, path, parents)
+}
+
+fn mkdir_path(fs: &mut LolelfFs, path: &str, parents: bool) -> Result<()> {
+    if parents {
+        // Create parent directories as needed
+        let mut current = String::new();
+        for component in path.trim_matches('/').split('/') {
+            if component.is_empty() {
+                continue;
+            }
+            current.push('/');
+            current.push_str(component);
+
+            if fs.resolve_path(&current).is_err() {
+                let (parent_path, dirname) = split_path(&current);
+                let parent_inode = fs.resolve_path(&parent_path)?;
+                fs.mkdir(parent_inode, dirname)?;
+            }
+        }
+    } else {
+        let (parent_path, dirname) = split_path(path);
+        let parent_inode = fs.resolve_path(&parent_path)?;
+        fs.mkdir(parent_inode, dirname)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_rm(image: &ImageLocator, path: &str, recursive: bool, dir: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    rm_path(&mut fs, path, recursive, dir)
+}
+
+fn rm_path(fs: &mut LolelfFs, path: &str, recursive: bool, dir: bool) -> Result<()> {
+    let (parent_path, name) = split_path(path);
+    let parent_inode = fs.resolve_path(&parent_path)?;
+
+    let inode_num = fs
+        .lookup(parent_inode, name)?
+        .ok_or_else(|| anyhow::anyhow!("'{}' not found", path))?;
+
+    let inode = fs.read_inode(inode_num)?;
+
+    if inode.is_dir() {
+        if !dir && !recursive {
+            bail!("'{}' is a directory, use -d or -r flag", path);
+        }
+
+        if recursive {
+            // Remove contents recursively
+            remove_recursive(fs, inode_num)?;
+        }
+
+        fs.rmdir(parent_inode, name)?;
+    } else {
+        fs.unlink(parent_inode, name)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_shred(image: &ImageLocator, path: &str, passes: u32) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    let (parent_path, name) = split_path(path);
+    let parent_inode = fs.resolve_path(&parent_path)?;
+
+    let blocks = fs.secure_unlink(parent_inode, name, passes)?;
+    info!(
+        "Shredded '{}': {} blocks overwritten {} times",
+        path, blocks, passes
+    );
+    Ok(())
+}
+
+fn remove_recursive(fs: &mut LolelfFs, dir_inode: u32) -> Result<()> {
+    let entries = fs.list_dir(dir_inode)?;
+
+    for entry in entries {
+        if entry.inode.is_dir() {
+            remove_recursive(fs, entry.inode_num)?;
+            // The directory entry will be removed when we remove the parent
+        }
+        // Files will be removed when the directory is removed
+    }
+
+    Ok(())
+}
+
+fn cmd_mv(image: &ImageLocator, source: &str, dest: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    mv_path(&mut fs, source, dest)
+}
+
+fn mv_path(fs: &mut LolelfFs, source: &str, dest: &str) -> Result<()> {
+    let (src_parent_path, src_name) = split_path(source);
+    let src_parent = fs.resolve_path(&src_parent_path)?;
+
+    // Moving onto an existing directory puts the source inside it
+    let (dest_parent, dest_name) = match fs.resolve_path(dest) {
+        Ok(inode_num) if fs.read_inode(inode_num)?.is_dir() => (inode_num, src_name),
+        _ => {
+            let (parent_path, name) = split_path(dest);
+            (fs.resolve_path(&parent_path)?, name)
+        }
+    };
+
+    fs.rename(src_parent, src_name, dest_parent, dest_name)?;
+
+    Ok(())
+}
+
+fn cmd_touch(image: &ImageLocator, path: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    touch_path(&mut fs, path)
+}
+
+fn touch_path(fs: &mut LolelfFs, path: &str) -> Result<()> {
+    match fs.resolve_path(path) {
+        Ok(inode_num) => {
+            // Update timestamps
+            let mut inode = fs.read_inode(inode_num)?;
+            let now = fs.now();
+            inode.i_atime = now;
+            inode.i_mtime = now;
+            fs.write_inode(inode_num, &inode)?;
+        }
+        Err(_) => {
+            // Create the file
+            let (parent_path, filename) = split_path(path);
+            let parent_inode = fs.resolve_path(&parent_path)?;
+            fs.create_file(parent_inode, filename)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_chmod(image: &ImageLocator, spec: &str, path: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    chmod_path(&mut fs, spec, path)
+}
+
+fn chmod_path(fs: &mut LolelfFs, spec: &str, path: &str) -> Result<()> {
+    let inode_num = fs.resolve_path(path)?;
+    let inode = fs.read_inode(inode_num)?;
+
+    let perm = parse_mode(spec, inode.i_mode & 0o7777, inode.is_dir())?;
+    fs.set_mode(inode_num, perm)?;
+
+    Ok(())
+}
+
+fn cmd_chown(image: &ImageLocator, owner: &str, path: &str, recursive: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    let inode_num = fs.resolve_path(path)?;
+    let (uid, gid) = parse_owner(owner)?;
+
+    chown_inode(&mut fs, inode_num, uid, gid, recursive)
+}
+
+fn chown_inode(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    recursive: bool,
+) -> Result<()> {
+    fs.set_owner(inode_num, uid, gid)?;
+
+    if recursive && fs.read_inode(inode_num)?.is_dir() {
+        for entry in fs.list_dir(inode_num)? {
+            chown_inode(fs, entry.inode_num, uid, gid, recursive)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_stat(image: &ImageLocator, path: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let inode_num = fs.resolve_path(path)?;
+    let inode = fs.read_inode(inode_num)?;
+    if json_output() {
+        let mut out = JsonValue::object([("path", path.into()), ("inode", inode_num.into())]);
+        if let JsonValue::Object(fields) = inode.to_json() {
+            for (key, value) in fields {
+                out.push(key, value);
+            }
+        }
+        if inode.is_symlink() {
+            out.push("target", symlink_text(&inode));
+        }
+        if let Some(what) = fs.unsupported_algorithm(inode_num)? {
+            out.push("unsupported", what);
+        }
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+    print_stat(&mut fs, path, inode_num, &inode)
+}
+
+fn print_stat(fs: &mut LolelfFs, path: &str, inode_num: u32, inode: &Inode) -> Result<()> {
+    let file_type = if inode.is_dir() {
+        "directory"
+    } else if inode.is_symlink() {
+        "symbolic link"
+    } else {
+        "regular file"
+    };
+
+    println!("  File: {}", path);
+    println!(
+        "  Size: {:<15} Blocks: {:<10} {}",
+        inode.i_size, inode.i_blocks, file_type
+    );
+    println!("Inode: {:<15} Links: {}", inode_num, inode.i_nlink);
+    println!(
+        " Mode: {:o}/{}{:<9} Uid: {:5} Gid: {:5}",
+        inode.i_mode,
+        inode.type_char(),
+        inode.perm_string(),
+        inode.i_uid,
+        inode.i_gid
+    );
+
+    let atime = format_timestamp(inode.i_atime);
+    let mtime = format_timestamp(inode.i_mtime);
+    let ctime = format_timestamp(inode.i_ctime);
+
+    println!("Access: {}", atime);
+    println!("Modify: {}", mtime);
+    println!("Change: {}", ctime);
+
+    if inode.is_symlink() {
+        println!("Target: {}", symlink_text(inode));
+    }
+
+    if inode.ei_block != 0 {
+        println!("Extent Block: {}", inode.ei_block);
+    }
+
+    let hint = hint::AccessHint::from_flags(inode.flags());
+    if hint != hint::AccessHint::Normal {
+        println!("Hint: {}", hint);
+    }
+    if let Some(algo) = inode.comp_override() {
+        println!("Compression: {}", crate::compress::get_algo_name(algo));
+    }
+
+    if let Some(what) = fs.unsupported_algorithm(inode_num)? {
+        println!(
+            "Unsupported: stored with {}, which this build cannot read",
+            what
+        );
+    }
+    Ok(())
+}
+
+/// Inline symlink target stored in `i_data`
+fn symlink_text(inode: &Inode) -> String {
+    inode
+        .i_data
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect()
+}
+
+const SHELL_HELP: &str = "\
+ls [-laRis] [PATH]    list a directory (-R recurses, -i inodes, -s blocks)
+cd [PATH]             change the working directory (default /)
+pwd                   print the working directory
+cat PATH              print file contents
+stat PATH             show inode information
+extents PATH          dump a file's extent map
+write PATH TEXT...    replace a file's contents with TEXT and a newline
+mkdir PATH            create a directory
+rm PATH               remove a file or an empty directory
+help                  show this help
+exit, quit            leave the shell";
+
+fn cmd_shell(image: &ImageLocator, read_only: bool, password: Option<String>) -> Result<()> {
+    use std::io::{BufRead, IsTerminal};
+
+    let mut fs = if read_only {
+        LolelfFs::open_locator(image, OpenMode::ReadOnly)?
+    } else {
+        LolelfFs::open_locator(image, OpenMode::ReadWrite)?
+    };
+    unlock_if_needed(&mut fs, password)?;
+
+    // Only prompt when a person is typing, so scripts piped in stay quiet
+    let interactive = io::stdin().is_terminal();
+    let mut cwd = "/".to_string();
+    let mut line = String::new();
+    loop {
+        if interactive {
+            eprint!("lolelffs:{}> ", cwd);
+            io::stderr().flush()?;
+        }
+        line.clear();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let words = match shell_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                continue;
+            }
+        };
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        if command == "exit" || command == "quit" {
+            break;
+        }
+        // A failing command is reported and the session goes on
+        if let Err(e) = shell_command(&mut fs, &mut cwd, command, args) {
+            eprintln!("{}: {:#}", command, e);
+        }
+        io::stdout().flush()?;
+    }
+
+    Ok(())
+}
+
+fn shell_command(
+    fs: &mut LolelfFs,
+    cwd: &mut String,
+    command: &str,
+    args: &[String],
+) -> Result<()> {
+    let (flags, operands): (Vec<&String>, Vec<&String>) =
+        args.iter().partition(|a| a.starts_with('-') && a.len() > 1);
+    let operand = |required: bool| -> Result<String> {
+        match operands.first() {
+            Some(arg) => Ok(shell_path(cwd, arg)),
+            None if required => bail!("missing path operand"),
+            None => Ok(cwd.clone()),
+        }
+    };
+
+    match command {
+        "help" => println!("{}", SHELL_HELP),
+        "pwd" => println!("{}", cwd),
+        "cd" => {
+            let path = match operands.first() {
+                Some(arg) => shell_path(cwd, arg),
+                None => "/".to_string(),
+            };
+            let inode_num = fs.resolve_path_follow(&path, true)?;
+            if !fs.read_inode(inode_num)?.is_dir() {
+                bail!("'{}' is not a directory", path);
+            }
+            *cwd = path;
+        }
+        "ls" => {
+            let mut opts = LsArgs::default();
+            for flag in &flags {
+                for c in flag[1..].chars() {
+                    match c {
+                        'l' => opts.long = true,
+                        'a' => opts.all = true,
+                        'R' => opts.recursive = true,
+                        'i' => opts.inode = true,
+                        's' => opts.size = true,
+                        _ => bail!("unknown option -{}", c),
+                    }
+                }
+            }
+            let path = operand(false)?;
+            let inode_num = fs.resolve_path_follow(&path, true)?;
+            let inode = fs.read_inode(inode_num)?;
+            if inode.is_dir() {
+                print_ls_dir(fs, &path, inode_num, &opts)?;
+            } else {
+                print_ls_entry(fs, split_path(&path).1, inode_num, &inode, &opts)?;
+            }
+        }
+        "cat" => {
+            let inode_num = fs.resolve_path_follow(&operand(true)?, true)?;
+            fs.read_file_to(inode_num, &mut io::stdout().lock())?;
+        }
+        "stat" => {
+            let path = operand(true)?;
+            let inode_num = fs.resolve_path_follow(&path, false)?;
+            let inode = fs.read_inode(inode_num)?;
+            print_stat(fs, &path, inode_num, &inode)?;
+        }
+        "extents" => {
+            let path = operand(true)?;
+            let inode_num = fs.resolve_path_follow(&path, true)?;
+            let inode = fs.read_inode(inode_num)?;
+            if inode.ei_block == 0 {
+                bail!("'{}' has no extent index", path);
+            }
+            print_extent_table(&fs.read_extent_index(&inode)?);
+        }
+        "write" => {
+            let path = operand(true)?;
+            let mut content = operands[1..]
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+                .into_bytes();
+            content.push(b'\n');
+            let inode_num = match fs.resolve_path_follow(&path, true) {
+                Ok(inode_num) => inode_num,
+                Err(_) => {
+                    let (parent_path, name) = split_path(&path);
+                    let parent_inode = fs.resolve_path_follow(&parent_path, true)?;
+                    fs.create_file(parent_inode, name)?
+                }
+            };
+            fs.write_file(inode_num, &content)?;
+        }
+        "mkdir" => {
+            let path = operand(true)?;
+            let (parent_path, name) = split_path(&path);
+            let parent_inode = fs.resolve_path_follow(&parent_path, true)?;
+            fs.mkdir(parent_inode, name)?;
+        }
+        "rm" => {
+            let path = operand(true)?;
+            let (parent_path, name) = split_path(&path);
+            let parent_inode = fs.resolve_path_follow(&parent_path, true)?;
+            let inode_num = fs
+                .lookup(parent_inode, name)?
+                .ok_or_else(|| anyhow::anyhow!("'{}' not found", path))?;
+            if fs.read_inode(inode_num)?.is_dir() {
+                fs.rmdir(parent_inode, name)?;
+            } else {
+                fs.unlink(parent_inode, name)?;
+            }
+        }
+        _ => bail!("unknown command, try 'help'"),
+    }
+
+    Ok(())
+}
+
+/// Print the used extents of an extent index, one per line
+fn print_extent_table(ei: &ExtentIndex) {
+    println!(
+        "{:>8} {:>6} {:>10} {:>6} {:>12} {:>6} {:>8}",
+        "LOGICAL", "LEN", "PHYSICAL", "COMP", "ENC", "FLAGS", "META"
+    );
+    for extent in ei.extents.iter().take_while(|e| !e.is_empty()) {
+        println!(
+            "{:>8} {:>6} {:>10} {:>6} {:>12} {:>#6x} {:>8}",
+            extent.ee_block,
+            extent.ee_len,
+            extent.ee_start,
+            compress::get_algo_name(extent.ee_comp_algo as u8),
+            encrypt::get_algo_name(extent.ee_enc_algo),
+            extent.ee_flags,
+            extent.ee_meta
+        );
+    }
+}
+
+/// Make `arg` absolute against `cwd`, folding `.` and `..` lexically
+fn shell_path(cwd: &str, arg: &str) -> String {
+    let joined = if arg.starts_with('/') {
+        arg.to_string()
+    } else {
+        format!("{}/{}", cwd, arg)
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for component in joined.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Split a shell line into words, honouring quotes and backslash escapes
+fn shell_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => word.extend(chars.next()),
+                        Some(other) => word.push(other),
+                        None => bail!("unterminated {} quote", c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.extend(chars.next());
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Run the commands on stdin against one open handle, so a scripted build
+/// opens and validates the image once instead of once per command
+///
+/// Blank lines and lines starting with `#` are skipped. Words are split as
+/// in `shell`. Commands before a failing line stay applied.
+fn cmd_batch(
+    image: &ImageLocator,
+    password: Option<String>,
+    keep_going: bool,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    use std::io::BufRead;
+
+    let mut fs = open_for_write(image, scratch)?;
+    unlock_if_needed(&mut fs, password)?;
+
+    let mut applied = 0;
+    let mut failed = 0;
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        let words = shell_words(&line);
+        let result = words.and_then(|words| {
+            if words.first().is_none_or(|w| w.starts_with('#')) {
+                return Ok(false);
+            }
+            let line = BatchLine::try_parse_from(&words)
+                .map_err(|e| anyhow::anyhow!("{}", e.to_string().trim_end()))?;
+            batch_command(&mut fs, line.command)?;
+            Ok(true)
+        });
+        match result {
+            Ok(true) => applied += 1,
+            Ok(false) => {}
+            Err(e) if keep_going => {
+                eprintln!("line {}: {:#}", i + 1, e);
+                failed += 1;
+            }
+            Err(e) => bail!("line {}: {:#}", i + 1, e),
+        }
+    }
+
+    finish_scratch(&mut fs, scratch)?;
+    if failed > 0 {
+        bail!("{} of {} commands failed", failed, applied + failed);
+    }
+    info!("Applied {} commands to {}", applied, image);
+    Ok(())
+}
+
+fn batch_command(fs: &mut LolelfFs, command: BatchCommand) -> Result<()> {
+    match command {
+        BatchCommand::Mkdir { path, parents } => mkdir_path(fs, &path, parents),
+        BatchCommand::Cp {
+            source,
+            dest,
+            recursive,
+            symlinks,
+            checksum,
+        } => cp_path(fs, &source, &dest, recursive, symlinks, checksum),
+        BatchCommand::Write { path, data, create } => {
+            write_path(fs, &path, data.as_bytes(), create)
+        }
+        BatchCommand::Touch { path } => touch_path(fs, &path),
+        BatchCommand::Chmod { mode, path } => chmod_path(fs, &mode, &path),
+        BatchCommand::Chown {
+            owner,
+            path,
+            recursive,
+        } => {
+            let inode_num = fs.resolve_path(&path)?;
+            let (uid, gid) = parse_owner(&owner)?;
+            chown_inode(fs, inode_num, uid, gid, recursive)
+        }
+        BatchCommand::Setfattr { path, name, value } => {
+            let inode_num = fs.resolve_path(&path)?;
+            fs.set_xattr(inode_num, &name, value.as_bytes())
+        }
+        BatchCommand::Ln {
+            target,
+            link,
+            symbolic,
+        } => ln_path(fs, &target, &link, symbolic),
+        BatchCommand::Mv { source, dest } => mv_path(fs, &source, &dest),
+        BatchCommand::Rm {
+            path,
+            recursive,
+            dir,
+        } => rm_path(fs, &path, recursive, dir),
+    }
+}
+
+fn cmd_mkfs(
+    image: &PathBuf,
+    size: Option<String>,
+    encrypt: Option<(Option<String>, &str, u32, bool)>,
+    label: Option<&str>,
+    template: Option<(ImageLocator, Option<String>)>,
+    from_dir: Option<&Path>,
+    deterministic: bool,
+) -> Result<()> {
+    // Validate the label up front so a bad one leaves no image behind
+    if let Some(label) = label {
+        encode_label(label)?;
+    }
+    if let Some(dir) = from_dir {
+        if !dir.is_dir() {
+            bail!("'{}' is not a directory", dir.display());
+        }
+    }
+
+    // Open the template first so a bad path or password leaves no image behind
+    let mut template_fs = match &template {
+        Some((path, template_password)) => {
+            if path.path() == image.as_path() {
+                bail!("Template and new image must be different files");
+            }
+            let mut src = LolelfFs::open_locator(path, OpenMode::ReadOnly)?;
+            unlock_if_needed(&mut src, template_password.clone())?;
+            Some(src)
+        }
+        None => None,
+    };
+
+    // An automatic size starts from an upper bound and shrinks once populated
+    let auto_size = size.as_deref() == Some("auto");
+    let size_bytes = match size {
+        Some(_) if auto_size => match (&template_fs, from_dir) {
+            (Some(src), _) => src.superblock.nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64,
+            (None, Some(dir)) => LolelfFs::size_for_host_tree(dir)?,
+            (None, None) => bail!("--size auto needs --from-dir or --template"),
+        },
+        Some(s) => parse_size(&s)?,
+        // A new image cloned from a template defaults to the template's size
+        None if template_fs.is_some() && !image.exists() => {
+            let src = template_fs.as_ref().unwrap();
+            src.superblock.nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64
+        }
+        None => {
+            // Check if file exists and use its size
+            let meta = std::fs::metadata(image).with_context(|| {
+                format!(
+                    "Cannot stat '{}', specify --size to create",
+                    image.display()
+                )
+            })?;
+            meta.len()
+        }
+    };
+
+    if size_bytes < LOLELFFS_MIN_BLOCKS as u64 * LOLELFFS_BLOCK_SIZE as u64 {
+        bail!(
+            "Filesystem too small: minimum {} bytes",
+            LOLELFFS_MIN_BLOCKS as u64 * LOLELFFS_BLOCK_SIZE as u64
+        );
+    }
+
+    // Handle encryption if requested
+    let opaque = matches!(encrypt, Some((_, _, _, true)));
+    let enc_config = if let Some((password, algo, iterations, _)) = encrypt {
+        // Get password
+        let pwd = match password {
+            Some(p) => p,
+            None => {
+                eprint!("Enter encryption password: ");
+                io::stderr().flush()?;
+                let mut pwd = String::new();
+                io::stdin().read_line(&mut pwd)?;
+                pwd.trim().to_string()
+            }
+        };
+
+        if pwd.is_empty() {
+            bail!("Password cannot be empty");
+        }
+
+        // Parse algorithm
+        let enc_algo = encrypt::parse_algo_name(algo)?;
+        if enc_algo == LOLELFFS_ENC_NONE {
+            bail!("Use mkfs without --encrypt for an unencrypted image");
+        }
+
+        Some((pwd, enc_algo, iterations))
+    } else {
+        None
+    };
+
+    let enc_algo = enc_config.as_ref().map(|&(_, algo, _)| algo);
+    let mut fs = LolelfFs::create_with_encryption(image, size_bytes, enc_config)?;
+    attach_progress(&mut fs);
+    if opaque {
+        fs.make_opaque()?;
+    }
+    if deterministic {
+        if enc_algo.is_some() {
+            eprintln!(
+                "Warning: encrypted images use a random salt and key and are never reproducible"
+            );
+        }
+        fs.make_reproducible(source_date_epoch()?)?;
+    }
+    if let Some(label) = label {
+        fs.set_label(label)?;
+    }
+
+    // Re-lay out the template's or host directory's tree into the new image
+    let copied = match (template_fs.as_mut(), from_dir) {
+        (Some(src), _) => Some(fs.sync_from(src, &sync::SyncOptions { delete: false })?),
+        (None, Some(dir)) => {
+            Some(fs.sync_from_host(dir, LOLELFFS_ROOT_INO, &sync::SyncOptions::default())?)
+        }
+        (None, None) => None,
+    };
+    if auto_size {
+        let min = fs.min_size();
+        if min < fs.superblock.nr_blocks as u64 * LOLELFFS_BLOCK_SIZE as u64 {
+            fs.shrink(min)?;
+        }
+    }
+
+    let stats = fs.statfs();
+
+    info!("Created lolelffs filesystem on {}", image.display());
+    info!("  Total size: {} bytes", stats.total_size());
+    info!("  Block size: {} bytes", stats.block_size);
+    info!("  Total blocks: {}", stats.total_blocks);
+    info!("  Total inodes: {}", stats.total_inodes);
+    info!("  Free blocks: {}", stats.free_blocks);
+    info!("  Free inodes: {}", stats.free_inodes);
+    info!("  UUID: {}", fs.superblock.uuid_string());
+    if let Some(label) = label {
+        info!("  Label: {}", label);
+    }
+    if let Some(enc_algo) = enc_algo {
+        info!(
+            "  Encryption: enabled ({} with PBKDF2{})",
+            crate::encrypt::get_algo_name(enc_algo),
+            if opaque { ", opaque" } else { "" }
+        );
+    }
+    if let (Some((path, _)), Some(copied)) = (&template, &copied) {
+        info!(
+            "  Template: {} ({} entries copied)",
+            path,
+            copied.created + copied.updated
+        );
+    }
+    if let (Some(dir), Some(copied)) = (from_dir, copied) {
+        info!(
+            "  Populated from: {} ({} entries copied)",
+            dir.display(),
+            copied.created
+        );
+    }
+
+    Ok(())
+}
+
+/// Findings of a `fsck` run, printed as they are found or collected for `--json`
+#[derive(Default)]
+struct FsckLog {
+    errors: u32,
+    warnings: u32,
+    findings: Vec<JsonValue>,
+}
+
+impl FsckLog {
+    fn error(&mut self, message: impl Into<String>) {
+        self.errors += 1;
+        self.report("error", "ERROR", message.into());
+    }
+
+    fn warning(&mut self, message: impl Into<String>) {
+        self.warnings += 1;
+        self.report("warning", "WARNING", message.into());
+    }
+
+    fn fixed(&mut self, message: impl Into<String>) {
+        self.report("fixed", "FIXED", message.into());
+    }
+
+    fn report(&mut self, severity: &str, prefix: &str, message: String) {
+        if json_output() {
+            self.findings.push(JsonValue::object([
+                ("severity", severity.into()),
+                ("message", message.into()),
+            ]));
+        } else {
+            println!("{}: {}", prefix, message);
+        }
+    }
+}
+
+fn cmd_fsck(
+    image: &ImageLocator,
+    verbose: bool,
+    orphan_xattrs: bool,
+    free_orphans: bool,
+    repair_dirs: bool,
+    lost_found: bool,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    let repairing = orphan_xattrs || free_orphans || repair_dirs || lost_found;
+    let mut fs = if repairing || scratch.scratch.is_some() {
+        open_for_write(image, scratch)?
+    } else {
+        LolelfFs::open_locator(image, OpenMode::ReadOnly)?
+    };
+    fs.verify = VerifyPolicy::Always;
+    attach_progress(&mut fs);
+    let mut log = FsckLog::default();
+    // Progress lines would break the JSON document
+    let verbose = verbose && !json_output();
+
+    if verbose {
+        println!("Checking filesystem: {}", image);
+    }
+
+    // Check magic number
+    if fs.superblock.magic != LOLELFFS_MAGIC {
+        log.error("Invalid magic number");
+    } else if verbose {
+        println!("Magic number: OK");
+    }
+
+    // Check superblock consistency
+    for issue in fs.superblock.layout_issues() {
+        log.warning(format!("Superblock layout: {}", issue));
+    }
+    for issue in fs.superblock.compat_issues() {
+        log.warning(format!("Not supported by this build: {}", issue));
+    }
+
+    // Check root inode
+    let root_inode = fs.read_inode(LOLELFFS_ROOT_INO)?;
+    if !root_inode.is_dir() {
+        log.error("Root inode is not a directory");
+    } else if verbose {
+        println!("Root inode: OK");
+    }
+
+    if root_inode.ei_block == 0 {
+        log.error("Root inode has no extent index block");
+    } else if verbose {
+        println!("Root extent index: OK");
+    }
+
+    // Check free counts are reasonable
+    if fs.superblock.nr_free_inodes > fs.superblock.nr_inodes {
+        log.error("Free inodes > total inodes");
+    }
+
+    if fs.superblock.nr_free_blocks > fs.superblock.nr_blocks {
+        log.error("Free blocks > total blocks");
+    }
+
+    // Check directory block checksums before anything lists directories
+    if fs.superblock.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM) != LOLELFFS_HASH_NONE {
+        let mut bad_blocks = 0;
+        for inode_num in 0..fs.superblock.nr_inodes {
+            if fs.is_inode_free(inode_num)? || !fs.read_inode(inode_num)?.is_dir() {
+                continue;
+            }
+            for block_num in fs.dir_blocks(inode_num)? {
+                if fs.verify_dir_block(block_num)? {
+                    continue;
+                }
+                bad_blocks += 1;
+                if repair_dirs {
+                    let dropped = fs.repair_dir_block(block_num)?;
+                    log.fixed(format!(
+                        "Directory block {} of inode {}: dropped {} corrupt entries",
+                        block_num, inode_num, dropped
+                    ));
+                } else {
+                    log.error(format!("Directory block {} of inode {} fails its checksum (run with --repair-dirs)", block_num, inode_num));
+                }
+            }
+        }
+        if bad_blocks == 0 && verbose {
+            println!("Directory checksums: OK");
+        }
+    }
+
+    // Give lost inodes a name again before the full check counts them
+    if lost_found {
+        for inode_num in fs.recover_lost_inodes()? {
+            log.fixed(format!(
+                "Linked lost inode {} as /{}/#{}",
+                inode_num,
+                crate::fsck::LOST_FOUND,
+                inode_num
+            ));
+        }
+    }
+
+    // Walk every directory and cross-check inodes and blocks with the bitmaps
+    let report = fs.fsck_full()?;
+    for problem in &report.problems {
+        match problem.severity {
+            crate::fsck::Severity::Error => {
+                log.error(problem.message.as_str());
+            }
+            crate::fsck::Severity::Warning => {
+                log.warning(problem.message.as_str());
+            }
+        }
+    }
+    if verbose {
+        println!(
+            "Walked {} directories: {} inodes and {} data blocks in use",
+            report.dirs, report.inodes, report.blocks
+        );
+    }
+
+    // Check directory sizes match their allocated blocks
+    fsck_dir_sizes(&mut fs, LOLELFFS_ROOT_INO, "/", verbose, &mut log)?;
+
+    // Check for unnamed inodes left on the orphan list
+    match fs.orphans() {
+        Ok(unnamed) if unnamed.is_empty() => {
+            if verbose {
+                println!("Orphan list: empty");
+            }
+        }
+        Ok(unnamed) if free_orphans => {
+            let freed = fs.free_orphans()?;
+            log.fixed(format!(
+                "Cleared {} orphan list entries, freed {} unnamed inodes",
+                unnamed.len(),
+                freed
+            ));
+        }
+        Ok(unnamed) => {
+            log.warning(format!(
+                "{} unnamed inodes on the orphan list (run with --orphans to free)",
+                unnamed.len()
+            ));
+        }
+        Err(e) => {
+            log.error(e.to_string());
+        }
+    }
+
+    // Check for xattr blocks leaked by deleted inodes
+    let orphans = if orphan_xattrs {
+        crate::xattr::free_orphan_xattrs(&mut fs)?
+    } else {
+        crate::xattr::find_orphan_xattrs(&mut fs)?
+    };
+    for orphan in &orphans {
+        if orphan_xattrs {
+            log.fixed(format!(
+                "Freed {} orphaned xattr blocks of deleted inode {}",
+                orphan.blocks.len(),
+                orphan.inode_num
+            ));
+        } else {
+            log.warning(format!(
+                "Deleted inode {} leaked {} xattr blocks (run with --orphan-xattrs to free)",
+                orphan.inode_num,
+                orphan.blocks.len()
+            ));
+        }
+    }
+    if orphans.is_empty() && verbose {
+        println!("Orphaned xattrs: none");
+    }
+
+    if json_output() {
+        let mut out = JsonValue::object([
+            ("image", image.to_string().into()),
+            ("errors", log.errors.into()),
+            ("warnings", log.warnings.into()),
+            ("problems", JsonValue::List(log.findings)),
+        ]);
+        out.push(
+            "walked",
+            JsonValue::object([
+                ("dirs", report.dirs.into()),
+                ("inodes", report.inodes.into()),
+                ("blocks", report.blocks.into()),
+            ]),
+        );
+        println!("{}", out.to_pretty());
+    }
+
+    let (errors, warnings) = (log.errors, log.warnings);
+    if errors > 0 {
+        info!(
+            "Filesystem check FAILED: {} errors, {} warnings",
+            errors, warnings
+        );
+        return Err(ExitStatus(EXIT_FAILURE).into());
+    } else if warnings > 0 {
+        info!("Filesystem check completed with {} warnings", warnings);
+    } else {
+        info!("Filesystem check passed");
+    }
+
+    finish_scratch(&mut fs, scratch)
+}
+
+fn cmd_metadump(
+    image: &ImageLocator,
+    format: crate::metadump::DumpFormat,
+    content_hashes: bool,
+    export_data: Option<PathBuf>,
+    password: Option<String>,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let options = crate::metadump::DumpOptions {
+        format,
+        content_hashes: content_hashes || export_data.is_some(),
+    };
+    if options.content_hashes {
+        unlock_if_needed(&mut fs, password)?;
+    }
+
+    fs.metadump(&mut io::stdout().lock(), &options)?;
+    if let Some(dir) = export_data {
+        let written = fs.export_contents(&dir)?;
+        info!("Stored {} new content blobs in {}", written, dir.display());
+    }
+    Ok(())
+}
+
+fn cmd_metarestore(
+    dump: &PathBuf,
+    data_dir: Option<PathBuf>,
+    image: &std::path::Path,
+) -> Result<()> {
+    if image.exists() {
+        bail!("{} already exists", image.display());
+    }
+    let text = std::fs::read_to_string(dump)
+        .with_context(|| format!("Failed to read {}", dump.display()))?;
+    let (_, stats) = LolelfFs::restore_metadump(image, &text, data_dir.as_deref())?;
+
+    info!(
+        "Restored {} directories, {} files, {} symlinks, {} hard links into {}",
+        stats.dirs,
+        stats.files,
+        stats.symlinks,
+        stats.hardlinks,
+        image.display()
+    );
+    if stats.missing_contents > 0 {
+        info!(
+            "{} files had no stored contents and were zero-filled",
+            stats.missing_contents
+        );
+    }
+    if stats.skipped > 0 {
+        info!("{} unreachable inodes were not restored", stats.skipped);
+    }
+    Ok(())
+}
+
+/// Verify that every directory's size equals its allocated bytes
+fn fsck_dir_sizes(
+    fs: &mut LolelfFs,
+    dir_inode_num: u32,
+    path: &str,
+    verbose: bool,
+    log: &mut FsckLog,
+) -> Result<()> {
+    let dir_inode = fs.read_inode(dir_inode_num)?;
+    let expected_blocks = fs.dir_allocated_blocks(&dir_inode)?;
+    let expected_size = expected_blocks * LOLELFFS_BLOCK_SIZE;
+
+    if dir_inode.i_blocks != expected_blocks || dir_inode.i_size != expected_size {
+        log.warning(format!(
+            "Directory '{}' size mismatch: {} bytes/{} blocks vs expected {} bytes/{} blocks",
+            path, dir_inode.i_size, dir_inode.i_blocks, expected_size, expected_blocks
+        ));
+    } else if verbose {
+        println!("Directory '{}' size: OK", path);
+    }
+
+    let entries = match fs.list_dir(dir_inode_num) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()), // Reported by the traversal checks
+    };
+    for entry in entries {
+        if entry.inode.is_dir() {
+            let child_path = if path == "/" {
+                format!("/{}", entry.filename)
+            } else {
+                format!("{}/{}", path, entry.filename)
+            };
+            fsck_dir_sizes(fs, entry.inode_num, &child_path, verbose, log)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_doctor(image: &ImageLocator) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let report = fs.doctor()?;
+    let failed = report.worst() == Some(lolelffs_tools::doctor::Level::Error);
+
+    if json_output() {
+        let mut out = JsonValue::object([("image", image.to_string().into())]);
+        if let JsonValue::Object(fields) = report.to_json() {
+            for (key, value) in fields {
+                out.push(key, value);
+            }
+        }
+        println!("{}", out.to_pretty());
+    } else {
+        let stats = &report.stats;
+        println!("Image: {}", image);
+        println!(
+            "Space: {} of {} used ({}%), {} of {} inodes used ({}%)",
+            format_size(stats.used_size()),
+            format_size(stats.total_size()),
+            stats.block_use_percent(),
+            stats.total_inodes - stats.free_inodes,
+            stats.total_inodes,
+            stats.inode_use_percent()
+        );
+        println!(
+            "Fragmentation: score {}, {} of {} files fragmented, largest free run {} blocks",
+            report.frag_score(),
+            report.frag.fragmented_files,
+            report.frag.files,
+            report.frag.largest_free_run
+        );
+        let sb = &fs.superblock;
+        if sb.enc_enabled != 0 {
+            println!(
+                "Encryption: {}, {} iterations{}",
+                crate::encrypt::get_algo_name(sb.enc_default_algo as u8),
+                sb.enc_kdf_iterations,
+                if fs.is_opaque() { ", opaque" } else { "" }
+            );
+        } else {
+            println!("Encryption: off");
+        }
+
+        println!();
+        if report.findings.is_empty() {
+            println!("No problems found");
+        }
+        for finding in &report.findings {
+            println!(
+                "{:<8} {}: {}",
+                finding.level.to_string(),
+                finding.area,
+                finding.message
+            );
+        }
+        let advice = report.recommendations();
+        if !advice.is_empty() {
+            println!();
+            println!("Recommendations:");
+            for text in advice {
+                println!("  - {}", text);
+            }
+        }
+    }
+
+    if failed {
+        return Err(ExitStatus(EXIT_FAILURE).into());
+    }
+    Ok(())
+}
+
+fn cmd_df(image: &ImageLocator, human: bool, thresholds: Option<Thresholds>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let stats = fs.statfs();
+
+    if json_output() {
+        let mut out = JsonValue::object([("image", image.to_string().into())]);
+        if let JsonValue::Object(fields) = stats.to_json() {
+            for (key, value) in fields {
+                out.push(key, value);
+            }
+        }
+        if fs.reflink_enabled() {
+            out.push("dedup", fs.dedup_stats()?.to_json());
+        }
+        let alarms = match thresholds {
+            Some(thresholds) => fs.check_thresholds(&thresholds)?,
+            None => Vec::new(),
+        };
+        out.push("alarms", alarms.to_json());
+        println!("{}", out.to_pretty());
+        if !alarms.is_empty() {
+            return Err(ExitStatus(EXIT_ALARM).into());
+        }
+        return Ok(());
+    }
+
+    let used = stats.total_blocks - stats.free_blocks;
+    let use_percent = stats.block_use_percent();
+
+    if human {
+        println!("Filesystem      Size  Used Avail Use%");
+        println!(
+            "{:<15} {:>5} {:>5} {:>5} {:>3}%",
+            image,
+            format_size(stats.total_size()),
+            format_size(stats.used_size()),
+            format_size(stats.free_size()),
+            use_percent
+        );
+    } else {
+        println!("Filesystem      Blocks   Used   Avail Use%");
+        println!(
+            "{:<15} {:>6} {:>6} {:>7} {:>3}%",
+            image, stats.total_blocks, used, stats.free_blocks, use_percent
+        );
+    }
+
+    println!();
+    println!(
+        "Inodes: {} total, {} free",
+        stats.total_inodes, stats.free_inodes
+    );
+    if fs.reflink_enabled() {
+        let dedup = fs.dedup_stats()?;
+        println!(
+            "Shared: {} extents, {} saved",
+            dedup.shared_extents,
+            format_size(dedup.saved_bytes())
+        );
+    }
+
+    if let Some(thresholds) = thresholds {
+        let alarms = fs.check_thresholds(&thresholds)?;
+        println!();
+        if alarms.is_empty() {
+            println!("Thresholds: OK");
+        } else {
+            for alarm in &alarms {
+                println!("ALARM: {}", alarm);
+            }
+            return Err(ExitStatus(EXIT_ALARM).into());
+        }
+    }
 
-        Commands::Setfattr {
-            image,
-            path,
-            name,
-            value,
-        } => cmd_setfattr(&image, &path, &name, &value),
+    Ok(())
+}
 
-        Commands::Listxattr { image, path } => cmd_listxattr(&image, &path),
+fn cmd_du(image: &ImageLocator, path: &str, human: bool, max_depth: Option<usize>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let inode_num = fs.resolve_path(path)?;
+    let mut seen = std::collections::HashSet::new();
+    let listed = std::cell::RefCell::new(Vec::new());
+
+    let print = |blocks: u64, path: &str| {
+        let bytes = blocks * LOLELFFS_BLOCK_SIZE as u64;
+        if json_output() {
+            listed.borrow_mut().push(JsonValue::object([
+                ("path", path.into()),
+                ("blocks", blocks.into()),
+                ("bytes", bytes.into()),
+            ]));
+        } else if human {
+            println!("{}\t{}", format_size(bytes), path);
+        } else {
+            println!("{}\t{}", bytes / 1024, path);
+        }
+    };
 
-        Commands::Removexattr { image, path, name } => cmd_removexattr(&image, &path, &name),
+    let total = du_inode(&mut fs, inode_num, path, 0, max_depth, &mut seen, &print)?;
+    if !fs.read_inode(inode_num)?.is_dir() {
+        print(total, path);
+    }
+
+    if json_output() {
+        println!("{}", JsonValue::List(listed.into_inner()).to_pretty());
     }
+    Ok(())
 }
 
-fn cmd_ls(image: &PathBuf, path: &str, long: bool, all: bool) -> Result<()> {
-    let mut fs = LolelfFs::open_readonly(image)?;
+fn cmd_compstat(image: &ImageLocator, path: &str, password: Option<String>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
     let inode_num = fs.resolve_path(path)?;
 
-    let inode = fs.read_inode(inode_num)?;
+    let mut files = vec![(path.to_string(), inode_num)];
+    if fs.read_inode(inode_num)?.is_dir() {
+        files.clear();
+        for walked in fs.walk_tree(inode_num)? {
+            if walked.entry.inode.is_file() {
+                let file_path = format!("{}/{}", path.trim_end_matches('/'), walked.path);
+                files.push((file_path, walked.entry.inode_num));
+            }
+        }
+    }
 
-    if inode.is_file() {
-        // Just show the file itself
-        let filename = path.rsplit('/').next().unwrap_or(path);
-        if long {
-            print_long_entry(filename, inode_num, &inode);
+    let ratio = |stats: &compress::AlgoStats| {
+        if stats.logical == 0 {
+            "-".to_string()
         } else {
-            println!("{}", filename);
+            format!("{:.1}%", stats.stored as f64 * 100.0 / stats.logical as f64)
+        }
+    };
+
+    let mut total = compress::CompStats {
+        measured: true,
+        ..Default::default()
+    };
+    let mut seen = std::collections::HashSet::new();
+    if json_output() {
+        let mut listed = Vec::new();
+        for (file_path, file_inode) in files {
+            if !seen.insert(file_inode) {
+                continue;
+            }
+            let stats = fs.comp_stats(file_inode)?;
+            let mut entry =
+                JsonValue::object([("path", file_path.into()), ("inode", file_inode.into())]);
+            if let JsonValue::Object(fields) = stats.to_json() {
+                for (key, value) in fields {
+                    entry.push(key, value);
+                }
+            }
+            listed.push(entry);
+            total.add(&stats);
+        }
+        let out = JsonValue::object([("files", listed.into()), ("total", total.to_json())]);
+        println!("{}", out.to_pretty());
+        if !total.measured {
+            eprintln!(
+                "Warning: encrypted blocks were not measured; pass --password to include them"
+            );
         }
         return Ok(());
     }
 
-    let entries = fs.list_dir(inode_num)?;
-
-    for entry in &entries {
-        if !all && entry.filename.starts_with('.') {
+    println!(
+        "{:>12} {:>8} {:>12} {:>7}  {:<18} PATH",
+        "SIZE", "BLOCKS", "STORED", "RATIO", "ALGORITHMS"
+    );
+    for (file_path, file_inode) in files {
+        // Hard links share blocks, so each inode counts once
+        if !seen.insert(file_inode) {
             continue;
         }
-
-        if long {
-            print_long_entry(&entry.filename, entry.inode_num, &entry.inode);
+        let stats = fs.comp_stats(file_inode)?;
+        let algos: Vec<String> = stats
+            .by_algo
+            .iter()
+            .map(|(algo, a)| format!("{}:{}", compress::get_algo_name(*algo), a.blocks))
+            .collect();
+        let overall = compress::AlgoStats {
+            blocks: stats.blocks,
+            logical: stats.logical(),
+            stored: stats.stored(),
+        };
+        let (stored, file_ratio) = if stats.measured {
+            (overall.stored.to_string(), ratio(&overall))
         } else {
-            println!("{}", entry.filename);
-        }
+            ("?".to_string(), "?".to_string())
+        };
+        println!(
+            "{:>12} {:>8} {:>12} {:>7}  {:<18} {}",
+            stats.size,
+            stats.blocks,
+            stored,
+            file_ratio,
+            algos.join(","),
+            file_path
+        );
+        total.add(&stats);
+    }
+
+    println!();
+    println!(
+        "{:<10} {:>8} {:>12} {:>12} {:>7}",
+        "ALGORITHM", "BLOCKS", "LOGICAL", "STORED", "RATIO"
+    );
+    for (algo, stats) in &total.by_algo {
+        println!(
+            "{:<10} {:>8} {:>12} {:>12} {:>7}",
+            compress::get_algo_name(*algo),
+            stats.blocks,
+            format_size(stats.logical),
+            format_size(stats.stored),
+            ratio(stats)
+        );
+    }
+    let overall = compress::AlgoStats {
+        blocks: total.blocks,
+        logical: total.logical(),
+        stored: total.stored(),
+    };
+    println!(
+        "{:<10} {:>8} {:>12} {:>12} {:>7}",
+        "total",
+        overall.blocks,
+        format_size(overall.logical),
+        format_size(overall.stored),
+        ratio(&overall)
+    );
+    info!(
+        "{} files, {} on disk in {} blocks (compressed blocks are padded to a full block)",
+        seen.len(),
+        format_size(total.blocks * LOLELFFS_BLOCK_SIZE as u64),
+        total.blocks
+    );
+    if !total.measured {
+        eprintln!("Warning: encrypted blocks were not measured; pass --password to include them");
     }
 
     Ok(())
 }
 
-fn print_long_entry(filename: &str, _inode_num: u32, inode: &Inode) {
-    let mtime = Utc
-        .timestamp_opt(inode.i_mtime as i64, 0)
-        .single()
-        .map(|dt| dt.format("%b %d %H:%M").to_string())
-        .unwrap_or_else(|| "???".to_string());
+/// Sum i_blocks beneath an inode, printing each directory after its contents
+fn du_inode(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    path: &str,
+    depth: usize,
+    max_depth: Option<usize>,
+    seen: &mut std::collections::HashSet<u32>,
+    print: &dyn Fn(u64, &str),
+) -> Result<u64> {
+    // Hard-linked inodes are only counted once
+    if !seen.insert(inode_num) {
+        return Ok(0);
+    }
 
-    println!(
-        "{}{} {:3} {:5} {:5} {:8} {} {}",
-        inode.type_char(),
-        inode.perm_string(),
-        inode.i_nlink,
-        inode.i_uid,
-        inode.i_gid,
-        inode.i_size,
-        mtime,
-        filename
-    );
+    let inode = fs.read_inode(inode_num)?;
+    let mut total = inode.i_blocks as u64;
+
+    if inode.is_dir() {
+        for entry in fs.list_dir(inode_num)? {
+            let child = format!("{}/{}", path.trim_end_matches('/'), entry.filename);
+            total += du_inode(
+                fs,
+                entry.inode_num,
+                &child,
+                depth + 1,
+                max_depth,
+                seen,
+                print,
+            )?;
+        }
+        if max_depth.is_none_or(|max| depth <= max) {
+            print(total, path);
+        }
+    }
+
+    Ok(total)
 }
 
-fn cmd_cat(image: &PathBuf, path: &str, password: Option<String>) -> Result<()> {
-    let mut fs = LolelfFs::open_readonly(image)?;
+fn cmd_ln(image: &ImageLocator, target: &str, link: &str, symbolic: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    ln_path(&mut fs, target, link, symbolic)
+}
 
-    // Unlock if encrypted and password provided
-    unlock_if_needed(&mut fs, password)?;
+fn ln_path(fs: &mut LolelfFs, target: &str, link: &str, symbolic: bool) -> Result<()> {
+    let (parent_path, link_name) = split_path(link);
+    let parent_inode = fs.resolve_path(&parent_path)?;
 
-    let inode_num = fs.resolve_path(path)?;
+    if symbolic {
+        fs.symlink(parent_inode, link_name, target)?;
+    } else {
+        let target_inode = fs.resolve_path(target)?;
+        fs.link(target_inode, parent_inode, link_name)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_label(image: &ImageLocator, label: Option<&str>) -> Result<()> {
+    match label {
+        Some(label) => {
+            let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+            fs.set_label(label)?;
+            info!("Set label of {} to '{}'", image, label);
+        }
+        None => {
+            let fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+            if json_output() {
+                let out = JsonValue::object([("label", fs.superblock.label().into())]);
+                println!("{}", out.to_pretty());
+            } else {
+                println!("{}", fs.superblock.label());
+            }
+        }
+    }
+
+    Ok(())
+}
 
-    let data = fs.read_file(inode_num)?;
-    io::stdout().write_all(&data)?;
+fn cmd_resize(image: &ImageLocator, size: &str) -> Result<()> {
+    let new_size = parse_size(size)?;
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+
+    let current = fs.statfs().total_size();
+    let change = if new_size >= current {
+        format!("{} blocks added", fs.grow(new_size)?)
+    } else {
+        format!("{} blocks removed", fs.shrink(new_size)?)
+    };
+    let stats = fs.statfs();
+    info!(
+        "Resized {} to {} bytes ({}, {} free)",
+        image,
+        stats.total_size(),
+        change,
+        stats.free_blocks
+    );
 
     Ok(())
 }
 
-fn cmd_write(
-    image: &PathBuf,
-    path: &str,
-    data: Option<String>,
-    create: bool,
+fn cmd_clone(
+    src: &ImageLocator,
+    dst: &Path,
+    minimize: bool,
     password: Option<String>,
 ) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+    if dst.exists() {
+        bail!("'{}' already exists", dst.display());
+    }
+    let mut fs = LolelfFs::open_locator(src, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password.clone())?;
+    let before = fs.frag_stats()?;
+    attach_progress(&mut fs);
+
+    let opts = lolelffs_tools::clone::CloneOptions { minimize, password };
+    let (mut clone, stats) = match fs.clone_to(dst, &opts) {
+        Ok(done) => done,
+        Err(e) => {
+            let _ = std::fs::remove_file(dst);
+            return Err(e);
+        }
+    };
+    let after = clone.frag_stats()?;
+
+    info!(
+        "Cloned {} to {} ({} entries, {} bytes)",
+        src,
+        dst.display(),
+        stats.created,
+        clone.statfs().total_size()
+    );
+    print_frag_stats("Before:", &before);
+    print_frag_stats("After:", &after);
+    Ok(())
+}
 
-    // Unlock if encrypted and password provided
-    unlock_if_needed(&mut fs, password)?;
+fn cmd_convert(
+    from_ext2: bool,
+    src: &Path,
+    dst: &Path,
+    size: Option<&str>,
+    password: Option<String>,
+) -> Result<()> {
+    if dst.exists() {
+        bail!("'{}' already exists", dst.display());
+    }
+    let size = size.map(parse_size).transpose()?;
 
-    // Get the data to write
-    let content = match data {
-        Some(d) => d.into_bytes(),
-        None => {
-            let mut buf = Vec::new();
-            io::stdin().read_to_end(&mut buf)?;
-            buf
+    let converted = if from_ext2 {
+        convert_from_ext2(src, dst, size)
+    } else {
+        convert_to_ext2(src, dst, size, password)
+    };
+    let stats = match converted {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = std::fs::remove_file(dst);
+            return Err(e);
         }
     };
 
-    // Try to resolve the path
-    match fs.resolve_path(path) {
-        Ok(inode_num) => {
-            fs.write_file(inode_num, &content)?;
+    for (path, reason) in &stats.skipped {
+        eprintln!("Warning: skipped {}: {}", path, reason);
+    }
+    info!(
+        "Converted {} to {}: {} files, {} directories, {} symlinks, {} hard links, {} skipped",
+        src.display(),
+        dst.display(),
+        stats.files,
+        stats.dirs,
+        stats.symlinks,
+        stats.hardlinks,
+        stats.skipped.len()
+    );
+    Ok(())
+}
+
+fn convert_from_ext2(src: &Path, dst: &Path, size: Option<u64>) -> Result<ext2::Ext2Stats> {
+    let mut image = ext2::Ext2Image::open(src)?;
+    let size = size
+        .unwrap_or(image.size)
+        .max(LOLELFFS_MIN_BLOCKS as u64 * LOLELFFS_BLOCK_SIZE as u64);
+    let mut fs = LolelfFs::create(dst, size)?;
+    if !image.label.is_empty() {
+        fs.set_label(&image.label)?;
+    }
+    attach_progress(&mut fs);
+    fs.import_ext2(&mut image, LOLELFFS_ROOT_INO)
+}
+
+fn convert_to_ext2(
+    src: &Path,
+    dst: &Path,
+    size: Option<u64>,
+    password: Option<String>,
+) -> Result<ext2::Ext2Stats> {
+    let mut fs =
+        LolelfFs::open_locator(&ImageLocator::from(src.to_path_buf()), OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
+    attach_progress(&mut fs);
+    fs.export_ext2(LOLELFFS_ROOT_INO, dst, size)
+}
+
+fn print_frag_stats(label: &str, stats: &defrag::FragStats) {
+    println!(
+        "{:<8} {:>6} files, {:>6} fragmented, {:>7} extents, {:>7} runs, {:>6} free runs (largest {})",
+        label,
+        stats.files,
+        stats.fragmented_files,
+        stats.extents,
+        stats.runs,
+        stats.free_runs,
+        stats.largest_free_run
+    );
+}
+
+fn cmd_mount(
+    image: &ImageLocator,
+    dir: &Path,
+    backend: mount::MountBackend,
+    read_only: bool,
+) -> Result<()> {
+    let options = mount::MountOptions {
+        backend,
+        read_only,
+        fuse_binary: fuse_binary(),
+    };
+    let (mounted, kernel_error) = mount::mount_image(image, dir, &options)?;
+    if let Some(err) = kernel_error {
+        eprintln!("Warning: kernel mount failed, using FUSE: {:#}", err);
+    }
+    match mounted {
+        mount::Mounted::Kernel => {
+            info!("Mounted {} on {} (kernel module)", image, dir.display())
         }
-        Err(_) if create => {
-            // Create the file
-            let (parent_path, filename) = split_path(path);
-            let parent_inode = fs.resolve_path(&parent_path)?;
-            let inode_num = fs.create_file(parent_inode, filename)?;
-            fs.write_file(inode_num, &content)?;
+        mount::Mounted::Fuse { pid } => {
+            info!("Mounted {} on {} (FUSE, pid {})", image, dir.display(), pid)
         }
-        Err(e) => return Err(e),
     }
-
     Ok(())
 }
 
-fn cmd_mkdir(image: &PathBuf, path: &str, parents: bool) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_reflect(
+    image: &ImageLocator,
+    target: &Path,
+    via_fuse: bool,
+    password: Option<String>,
+    command: &[String],
+) -> Result<()> {
+    let created_target = !target.exists();
+    if created_target {
+        std::fs::create_dir_all(target)
+            .with_context(|| format!("Failed to create '{}'", target.display()))?;
+    } else if std::fs::read_dir(target)
+        .with_context(|| format!("'{}' is not a directory", target.display()))?
+        .next()
+        .is_some()
+    {
+        bail!("'{}' is not empty", target.display());
+    }
 
-    if parents {
-        // Create parent directories as needed
-        let mut current = String::new();
-        for component in path.trim_matches('/').split('/') {
-            if component.is_empty() {
-                continue;
+    let hidden = match create_temp_dir("lolelffs-reflect") {
+        Ok(hidden) => hidden,
+        Err(e) => {
+            if created_target {
+                let _ = std::fs::remove_dir(target);
             }
-            current.push('/');
-            current.push_str(component);
+            return Err(e);
+        }
+    };
 
-            if fs.resolve_path(&current).is_err() {
-                let (parent_path, dirname) = split_path(&current);
-                let parent_inode = fs.resolve_path(&parent_path)?;
-                fs.mkdir(parent_inode, dirname)?;
+    let backing = if via_fuse {
+        mount::mount_fuse(image, &hidden, true, &fuse_binary()).map(|_| ())
+    } else {
+        LolelfFs::open_locator(image, OpenMode::ReadOnly).and_then(|mut fs| {
+            unlock_if_needed(&mut fs, password)?;
+            let mut visited = std::collections::HashSet::new();
+            extract_tree(&mut fs, LOLELFFS_ROOT_INO, &hidden, &mut visited, true)
+        })
+    };
+    let status = backing.and_then(|()| {
+        let created = reflect::build_farm(&hidden, target)?;
+        info!(
+            "Reflecting {} into {} ({} entries)",
+            image,
+            target.display(),
+            created.len()
+        );
+        let status = run_in_dir(target, command);
+        let kept = reflect::remove_farm(&created);
+        if kept > 0 {
+            eprintln!(
+                "Warning: kept {} directories in '{}' that are no longer empty",
+                kept,
+                target.display()
+            );
+        }
+        status
+    });
+
+    // Tear down whatever was set up, even if the command failed
+    if via_fuse {
+        if mount::is_mount_point(&hidden).unwrap_or(false) {
+            if let Err(e) = mount::unmount(&hidden) {
+                eprintln!("Warning: {:#}", e);
             }
         }
+        let _ = std::fs::remove_dir(&hidden);
     } else {
-        let (parent_path, dirname) = split_path(path);
-        let parent_inode = fs.resolve_path(&parent_path)?;
-        fs.mkdir(parent_inode, dirname)?;
+        let _ = std::fs::remove_dir_all(&hidden);
+    }
+    if created_target {
+        let _ = std::fs::remove_dir(target);
     }
 
+    let status = status?;
+    if !status.success() {
+        return Err(ExitStatus(status.code().map_or(EXIT_FAILURE, |code| code as u8)).into());
+    }
     Ok(())
 }
 
-fn cmd_rm(image: &PathBuf, path: &str, recursive: bool, dir: bool) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
-    let (parent_path, name) = split_path(path);
-    let parent_inode = fs.resolve_path(&parent_path)?;
+/// Create a directory only this user can enter under the system temp
+/// directory, named `prefix` plus a random suffix
+///
+/// Creation is exclusive, so a leftover from an earlier run or another
+/// user's directory is never reused; a taken name just means another try.
+fn create_temp_dir(prefix: &str) -> Result<PathBuf> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    let mut attempts = 0;
+    loop {
+        let path = std::env::temp_dir().join(format!("{}-{:016x}", prefix, rand::random::<u64>()));
+        match builder.create(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 16 => attempts += 1,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create '{}'", path.display()))
+            }
+        }
+    }
+}
 
-    let inode_num = fs
-        .lookup(parent_inode, name)?
-        .ok_or_else(|| anyhow::anyhow!("'{}' not found", path))?;
+/// Run `command`, or the user's shell if it is empty, in `dir` and wait for
+/// it; Ctrl-C goes to the command alone so the caller lives to clean up
+fn run_in_dir(dir: &Path, command: &[String]) -> Result<std::process::ExitStatus> {
+    let shell;
+    let (program, args) = match command.split_first() {
+        Some((program, args)) => (program.as_str(), args),
+        None => {
+            shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+            (shell.as_str(), &[][..])
+        }
+    };
+    let mut child = std::process::Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+
+    // SAFETY: only swaps signal dispositions, no handler runs our code
+    let saved = unsafe {
+        (
+            libc::signal(libc::SIGINT, libc::SIG_IGN),
+            libc::signal(libc::SIGQUIT, libc::SIG_IGN),
+        )
+    };
+    let status = child.wait();
+    unsafe {
+        libc::signal(libc::SIGINT, saved.0);
+        libc::signal(libc::SIGQUIT, saved.1);
+    }
+    Ok(status?)
+}
 
-    let inode = fs.read_inode(inode_num)?;
+fn cmd_umount(dir: &Path) -> Result<()> {
+    let mount = mount::unmount(dir)?;
+    info!("Unmounted {} from {}", mount.image, dir.display());
+    Ok(())
+}
 
-    if inode.is_dir() {
-        if !dir && !recursive {
-            bail!("'{}' is a directory, use -d or -r flag", path);
+fn cmd_codecs() -> Result<()> {
+    let codecs = compress::codecs();
+    let origin = |id: u8| {
+        if id < compress::FIRST_CUSTOM_CODEC_ID {
+            "built-in"
+        } else {
+            "registered"
         }
+    };
 
-        if recursive {
-            // Remove contents recursively
-            remove_recursive(&mut fs, inode_num)?;
+    if json_output() {
+        let list: Vec<JsonValue> = codecs
+            .iter()
+            .map(|codec| {
+                JsonValue::object([
+                    ("id", (codec.id() as u64).into()),
+                    ("name", codec.name().into()),
+                    ("origin", origin(codec.id()).into()),
+                ])
+            })
+            .collect();
+        println!("{}", JsonValue::List(list).to_pretty());
+        return Ok(());
+    }
+
+    println!("{:>3}  {:<8} ORIGIN", "ID", "NAME");
+    for codec in &codecs {
+        println!(
+            "{:>3}  {:<8} {}",
+            codec.id(),
+            codec.name(),
+            origin(codec.id())
+        );
+    }
+    Ok(())
+}
+
+fn cmd_ciphers() -> Result<()> {
+    let ciphers = encrypt::ciphers();
+    let origin = |id: u8| {
+        if id < encrypt::FIRST_CUSTOM_CIPHER_ID {
+            "built-in"
+        } else {
+            "registered"
         }
+    };
 
-        fs.rmdir(parent_inode, name)?;
-    } else {
-        fs.unlink(parent_inode, name)?;
+    if json_output() {
+        let list: Vec<JsonValue> = ciphers
+            .iter()
+            .map(|cipher| {
+                JsonValue::object([
+                    ("id", (cipher.id() as u64).into()),
+                    ("name", cipher.name().into()),
+                    ("tag_size", (cipher.tag_size() as u64).into()),
+                    ("features", (cipher.features() as u64).into()),
+                    ("origin", origin(cipher.id()).into()),
+                ])
+            })
+            .collect();
+        println!("{}", JsonValue::List(list).to_pretty());
+        return Ok(());
     }
 
+    println!(
+        "{:>3}  {:<18} {:>3}  {:<10} ORIGIN",
+        "ID", "NAME", "TAG", "FEATURES"
+    );
+    for cipher in &ciphers {
+        println!(
+            "{:>3}  {:<18} {:>3}  0x{:08X} {}",
+            cipher.id(),
+            cipher.name(),
+            cipher.tag_size(),
+            cipher.features(),
+            origin(cipher.id())
+        );
+    }
     Ok(())
 }
 
-fn remove_recursive(fs: &mut LolelfFs, dir_inode: u32) -> Result<()> {
-    let entries = fs.list_dir(dir_inode)?;
+fn cmd_mounts() -> Result<()> {
+    let mounts = mount::lolelffs_mounts()?;
+    let describe = |mount: &mount::LolelfMount| {
+        let backend = match mount.backend {
+            mount::Mounted::Kernel => "kernel".to_string(),
+            mount::Mounted::Fuse { pid } => format!("fuse:{}", pid),
+        };
+        let mode = if mount.read_only { "ro" } else { "rw" };
+        (backend, mode)
+    };
 
-    for entry in entries {
-        if entry.inode.is_dir() {
-            remove_recursive(fs, entry.inode_num)?;
-            // The directory entry will be removed when we remove the parent
-        }
-        // Files will be removed when the directory is removed
+    if json_output() {
+        let list: Vec<JsonValue> = mounts
+            .iter()
+            .map(|mount| {
+                let (backend, pid) = match mount.backend {
+                    mount::Mounted::Kernel => ("kernel", None),
+                    mount::Mounted::Fuse { pid } => ("fuse", Some(pid)),
+                };
+                JsonValue::object([
+                    ("target", mount.target.display().to_string().into()),
+                    ("image", mount.image.as_str().into()),
+                    ("backend", backend.into()),
+                    ("pid", pid.into()),
+                    ("read_only", mount.read_only.into()),
+                ])
+            })
+            .collect();
+        println!("{}", JsonValue::List(list).to_pretty());
+        return Ok(());
     }
 
+    if mounts.is_empty() {
+        info!("No lolelffs filesystems mounted");
+        return Ok(());
+    }
+    println!("{:<24} {:<12} {:<4} IMAGE", "MOUNTPOINT", "BACKEND", "MODE");
+    for mount in &mounts {
+        let (backend, mode) = describe(mount);
+        println!(
+            "{:<24} {:<12} {:<4} {}",
+            mount.target.display(),
+            backend,
+            mode,
+            mount.image
+        );
+    }
     Ok(())
 }
 
-fn cmd_touch(image: &PathBuf, path: &str) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
-
-    match fs.resolve_path(path) {
-        Ok(inode_num) => {
-            // Update timestamps
-            let mut inode = fs.read_inode(inode_num)?;
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
-            inode.i_atime = now;
-            inode.i_mtime = now;
-            fs.write_inode(inode_num, &inode)?;
-        }
-        Err(_) => {
-            // Create the file
-            let (parent_path, filename) = split_path(path);
-            let parent_inode = fs.resolve_path(&parent_path)?;
-            fs.create_file(parent_inode, filename)?;
-        }
-    }
-
-    Ok(())
+/// The `lolelffs-fuse` installed next to this binary, else the one on PATH
+fn fuse_binary() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("lolelffs-fuse"))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from("lolelffs-fuse"))
 }
 
-fn cmd_stat(image: &PathBuf, path: &str) -> Result<()> {
-    let mut fs = LolelfFs::open_readonly(image)?;
-    let inode_num = fs.resolve_path(path)?;
-    let inode = fs.read_inode(inode_num)?;
+fn cmd_balance(
+    image: &ImageLocator,
+    blocks: std::ops::Range<u32>,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    let mut fs = open_for_write(image, scratch)?;
+    let stats = fs.balance(blocks.clone())?;
+    info!(
+        "Moved {} extents ({} blocks) out of blocks {}-{}",
+        stats.extents_moved,
+        stats.blocks_moved,
+        blocks.start,
+        blocks.end - 1
+    );
+    finish_scratch(&mut fs, scratch)
+}
 
-    let file_type = if inode.is_dir() {
-        "directory"
-    } else if inode.is_symlink() {
-        "symbolic link"
-    } else {
-        "regular file"
+fn cmd_stress(
+    image: &ImageLocator,
+    dir: &str,
+    mount: Option<PathBuf>,
+    options: &stress::StressOptions,
+) -> Result<()> {
+    info!(
+        "Running {} workers for {}s...",
+        options.threads,
+        options.duration.as_secs()
+    );
+    let (mut fs, report) = match mount {
+        Some(mount) => {
+            let dir = mount.join(dir);
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            let report = stress::run_stress(&stress::StressTarget::Mount(dir), options)?;
+            // The daemon writes through to the image, so it can be checked
+            // while still mounted
+            let fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+            (fs, report)
+        }
+        None => LolelfFs::open_locator(image, OpenMode::ReadWrite)?.stress(dir, options)?,
     };
 
-    println!("  File: {}", path);
+    if json_output() {
+        let check = fs.fsck_full()?;
+        let errors = check
+            .problems
+            .iter()
+            .filter(|p| p.severity == crate::fsck::Severity::Error)
+            .count();
+        let out = JsonValue::object([("stress", report.to_json()), ("fsck", check.to_json())]);
+        println!("{}", out.to_pretty());
+        if !report.is_clean() || errors > 0 {
+            info!("Stress run FAILED");
+            return Err(ExitStatus(EXIT_FAILURE).into());
+        }
+        info!("Stress run passed");
+        return Ok(());
+    }
+
     println!(
-        "  Size: {:<15} Blocks: {:<10} {}",
-        inode.i_size, inode.i_blocks, file_type
+        "{} operations in {:.2}s ({:.1} ops/s)",
+        report.total_ops(),
+        report.elapsed.as_secs_f64(),
+        report.ops_per_sec()
     );
-    println!("Inode: {:<15} Links: {}", inode_num, inode.i_nlink);
+    for op in stress::StressOp::ALL {
+        println!("  {:<8} {}", op.name(), report.count(op));
+    }
+    println!("Out of space: {}", report.no_space);
+    println!("Failures: {}", report.failures);
+    println!("Mismatches: {}", report.mismatches);
+    for error in &report.errors {
+        println!("ERROR: {}", error);
+    }
+
+    let check = fs.fsck_full()?;
+    let mut errors = 0;
+    for problem in &check.problems {
+        match problem.severity {
+            crate::fsck::Severity::Error => {
+                println!("ERROR: {}", problem.message);
+                errors += 1;
+            }
+            crate::fsck::Severity::Warning => println!("WARNING: {}", problem.message),
+        }
+    }
     println!(
-        " Mode: {:o}/{}{:<9} Uid: {:5} Gid: {:5}",
-        inode.i_mode,
-        inode.type_char(),
-        inode.perm_string(),
-        inode.i_uid,
-        inode.i_gid
+        "fsck: {} directories, {} inodes, {} data blocks, {} problems",
+        check.dirs,
+        check.inodes,
+        check.blocks,
+        check.problems.len()
     );
 
-    let atime = format_timestamp(inode.i_atime);
-    let mtime = format_timestamp(inode.i_mtime);
-    let ctime = format_timestamp(inode.i_ctime);
-
-    println!("Access: {}", atime);
-    println!("Modify: {}", mtime);
-    println!("Change: {}", ctime);
+    if !report.is_clean() || errors > 0 {
+        info!("Stress run FAILED");
+        return Err(ExitStatus(EXIT_FAILURE).into());
+    }
+    info!("Stress run passed");
+    Ok(())
+}
 
-    if inode.is_symlink() {
-        let target: String = inode
-            .i_data
+fn cmd_diff(
+    a: &ImageLocator,
+    b: &ImageLocator,
+    path: &str,
+    stat: bool,
+    ignore_times: bool,
+    password: Option<String>,
+) -> Result<()> {
+    let mut fs_a = LolelfFs::open_locator(a, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs_a, password.clone())?;
+    let mut fs_b = LolelfFs::open_locator(b, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs_b, password)?;
+
+    let dir_a = fs_a.resolve_path(path)?;
+    let dir_b = fs_b.resolve_path(path)?;
+    let opts = lolelffs_tools::diff::DiffOptions { ignore_times };
+    let changes = fs_a.diff(dir_a, &mut fs_b, dir_b, &opts)?;
+    let totals = lolelffs_tools::diff::DiffStat::of(&changes);
+    let full = |file: &str| format!("{}/{}", path.trim_end_matches('/'), file);
+
+    if json_output() {
+        let list: Vec<JsonValue> = changes
             .iter()
-            .take_while(|&&b| b != 0)
-            .map(|&b| b as char)
+            .map(|change| {
+                JsonValue::object([
+                    ("path", full(&change.path).into()),
+                    ("change", change.kind.to_string().into()),
+                    ("fields", change.fields.clone().into()),
+                    ("old_size", change.old_size.into()),
+                    ("new_size", change.new_size.into()),
+                ])
+            })
             .collect();
-        println!("Target: {}", target);
+        let out = JsonValue::object([
+            ("changes", JsonValue::List(list)),
+            ("added", totals.added.into()),
+            ("removed", totals.removed.into()),
+            ("modified", totals.modified.into()),
+            ("bytes_added", totals.bytes_added.into()),
+            ("bytes_removed", totals.bytes_removed.into()),
+        ]);
+        println!("{}", out.to_pretty());
+    } else if stat {
+        for change in &changes {
+            println!(
+                "{} {} | {} -> {}",
+                change.kind.code(),
+                full(&change.path),
+                change.old_size,
+                change.new_size
+            );
+        }
+        println!(
+            "{} added, {} removed, {} modified; +{} -{} bytes",
+            totals.added, totals.removed, totals.modified, totals.bytes_added, totals.bytes_removed
+        );
+    } else {
+        for change in &changes {
+            if change.fields.is_empty() {
+                println!("{}\t{}", change.kind.code(), full(&change.path));
+            } else {
+                println!(
+                    "{}\t{}\t({})",
+                    change.kind.code(),
+                    full(&change.path),
+                    change.fields.join(", ")
+                );
+            }
+        }
     }
 
-    if inode.ei_block != 0 {
-        println!("Extent Block: {}", inode.ei_block);
+    // Like diff(1): 1 means the trees differ
+    if !changes.is_empty() {
+        return Err(ExitStatus(EXIT_FAILURE).into());
     }
-
     Ok(())
 }
 
-fn cmd_mkfs(
-    image: &PathBuf,
-    size: Option<String>,
-    encrypt: bool,
+fn cmd_verify(
+    image: &ImageLocator,
+    path: &str,
+    update: bool,
     password: Option<String>,
-    algo: &str,
-    iterations: u32,
 ) -> Result<()> {
-    let size_bytes = match size {
-        Some(s) => parse_size(&s)?,
-        None => {
-            // Check if file exists and use its size
-            let meta = std::fs::metadata(image).with_context(|| {
-                format!(
-                    "Cannot stat '{}', specify --size to create",
-                    image.display()
-                )
-            })?;
-            meta.len()
-        }
+    let mode = if update {
+        OpenMode::ReadWrite
+    } else {
+        OpenMode::ReadOnly
     };
+    let mut fs = LolelfFs::open_locator(image, mode)?;
+    unlock_if_needed(&mut fs, password)?;
 
-    if size_bytes < LOLELFFS_MIN_BLOCKS as u64 * LOLELFFS_BLOCK_SIZE as u64 {
-        bail!(
-            "Filesystem too small: minimum {} bytes",
-            LOLELFFS_MIN_BLOCKS as u64 * LOLELFFS_BLOCK_SIZE as u64
-        );
+    let inode_num = fs.resolve_path(path)?;
+    if !fs.read_inode(inode_num)?.is_dir() {
+        bail!("'{}' is not a directory", path);
     }
 
-    // Handle encryption if requested
-    let enc_config = if encrypt {
-        // Get password
-        let pwd = match password {
-            Some(p) => p,
-            None => {
-                eprint!("Enter encryption password: ");
-                io::stderr().flush()?;
-                let mut pwd = String::new();
-                io::stdin().read_line(&mut pwd)?;
-                pwd.trim().to_string()
-            }
-        };
-
-        if pwd.is_empty() {
-            bail!("Password cannot be empty");
-        }
-
-        // Parse algorithm
-        let enc_algo = match algo {
-            "aes-256-xts" => LOLELFFS_ENC_AES256_XTS,
-            "chacha20-poly1305" => LOLELFFS_ENC_CHACHA20_POLY,
-            _ => bail!("Unknown encryption algorithm: {}", algo),
+    let report = fs.verify_tree(inode_num, update)?;
+    let full = |file: &str| format!("{}/{}", path.trim_end_matches('/'), file);
+    if json_output() {
+        let shown = verify::VerifyReport {
+            missing: report.missing.iter().map(|f| full(f)).collect(),
+            mismatched: report.mismatched.iter().map(|f| full(f)).collect(),
+            errors: report
+                .errors
+                .iter()
+                .map(|(f, err)| (full(f), err.clone()))
+                .collect(),
+            ..report.clone()
         };
-
-        Some((pwd, enc_algo, iterations))
+        println!("{}", shown.to_json().to_pretty());
     } else {
-        None
-    };
-
-    let fs = LolelfFs::create_with_encryption(image, size_bytes, enc_config)?;
-    let stats = fs.statfs();
-
-    println!("Created lolelffs filesystem on {}", image.display());
-    println!("  Total size: {} bytes", stats.total_size());
-    println!("  Block size: {} bytes", stats.block_size);
-    println!("  Total blocks: {}", stats.total_blocks);
-    println!("  Total inodes: {}", stats.total_inodes);
-    println!("  Free blocks: {}", stats.free_blocks);
-    println!("  Free inodes: {}", stats.free_inodes);
-    if encrypt {
-        println!("  Encryption: enabled ({} with PBKDF2)", algo);
+        for file in &report.mismatched {
+            println!("{}: FAILED", full(file));
+        }
+        for (file, err) in &report.errors {
+            println!("{}: ERROR: {}", full(file), err);
+        }
+    }
+    if !report.missing.is_empty() {
+        info!(
+            "{} files have no stored digest (record them with --update)",
+            report.missing.len()
+        );
     }
+    if report.recorded > 0 {
+        info!("Recorded digests for {} files", report.recorded);
+    }
+    info!(
+        "Verified {} files: {} mismatched, {} unreadable",
+        report.verified,
+        report.mismatched.len(),
+        report.errors.len()
+    );
 
+    if !report.is_clean() {
+        return Err(ExitStatus(EXIT_FAILURE).into());
+    }
     Ok(())
 }
 
-fn cmd_fsck(image: &PathBuf, verbose: bool) -> Result<()> {
-    let mut fs = LolelfFs::open_readonly(image)?;
-    let mut errors = 0;
-    let mut warnings = 0;
-
-    if verbose {
-        println!("Checking filesystem: {}", image.display());
-    }
+fn cmd_check_extents(image: &ImageLocator, path: &str, password: Option<String>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
+    let inode_num = fs.resolve_path(path)?;
+    let report = fs.check_extents(inode_num)?;
 
-    // Check magic number
-    if fs.superblock.magic != LOLELFFS_MAGIC {
-        println!("ERROR: Invalid magic number");
-        errors += 1;
-    } else if verbose {
-        println!("Magic number: OK");
+    if json_output() {
+        let mut out = JsonValue::object([("path", path.into()), ("inode", inode_num.into())]);
+        if let JsonValue::Object(fields) = report.to_json() {
+            for (key, value) in fields {
+                out.push(key, value);
+            }
+        }
+        println!("{}", out.to_pretty());
+        if !report.is_clean() {
+            return Err(ExitStatus(EXIT_FAILURE).into());
+        }
+        return Ok(());
     }
 
-    // Check superblock consistency
-    let expected_istore = fs.superblock.nr_inodes / LOLELFFS_INODES_PER_BLOCK;
-    if fs.superblock.nr_istore_blocks != expected_istore {
+    println!(
+        "{:>3} {:>8} {:>6} {:>10} {:>6} {:>12} {:>9}",
+        "#", "LOGICAL", "LEN", "PHYSICAL", "COMP", "ENC", "DECODED"
+    );
+    for (idx, check) in report.extents.iter().enumerate() {
+        let extent = &check.extent;
         println!(
-            "WARNING: Inode store blocks mismatch: {} vs expected {}",
-            fs.superblock.nr_istore_blocks, expected_istore
+            "{:>3} {:>8} {:>6} {:>10} {:>6} {:>12} {:>9}",
+            idx,
+            extent.ee_block,
+            extent.ee_len,
+            extent.ee_start,
+            compress::get_algo_name(extent.ee_comp_algo as u8),
+            encrypt::get_algo_name(extent.ee_enc_algo),
+            format!("{}/{}", check.blocks_ok, extent.ee_len)
         );
-        warnings += 1;
+        for problem in &check.problems {
+            println!("    {}", problem);
+        }
     }
-
-    // Check root inode
-    let root_inode = fs.read_inode(LOLELFFS_ROOT_INO)?;
-    if !root_inode.is_dir() {
-        println!("ERROR: Root inode is not a directory");
-        errors += 1;
-    } else if verbose {
-        println!("Root inode: OK");
+    for problem in &report.problems {
+        println!("{}", problem);
     }
-
-    if root_inode.ei_block == 0 {
-        println!("ERROR: Root inode has no extent index block");
-        errors += 1;
-    } else if verbose {
-        println!("Root extent index: OK");
+    match &report.digest {
+        Some(verify::DigestCheck::Match) => println!("SHA-256: OK"),
+        Some(verify::DigestCheck::Mismatch { stored, actual }) => {
+            println!("SHA-256: FAILED (stored {}, actual {})", stored, actual)
+        }
+        Some(verify::DigestCheck::Missing) => println!("SHA-256: none recorded"),
+        None => println!("SHA-256: not checked, blocks unreadable"),
     }
 
-    // Check free counts are reasonable
-    if fs.superblock.nr_free_inodes > fs.superblock.nr_inodes {
-        println!("ERROR: Free inodes > total inodes");
-        errors += 1;
+    if !report.is_clean() {
+        return Err(ExitStatus(EXIT_FAILURE).into());
     }
+    info!("{}: {} extents OK", path, report.extents.len());
+    Ok(())
+}
 
-    if fs.superblock.nr_free_blocks > fs.superblock.nr_blocks {
-        println!("ERROR: Free blocks > total blocks");
-        errors += 1;
-    }
+fn cmd_defrag(
+    image: &ImageLocator,
+    path: Option<String>,
+    dry_run: bool,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    let mut fs = if dry_run && scratch.scratch.is_none() {
+        LolelfFs::open_locator(image, OpenMode::ReadOnly)?
+    } else {
+        open_for_write(image, scratch)?
+    };
 
-    // Verify we can traverse the root directory
-    match fs.list_dir(LOLELFFS_ROOT_INO) {
-        Ok(entries) => {
-            if verbose {
-                println!("Root directory: {} entries", entries.len());
-            }
+    let before = fs.frag_stats()?;
+    print_frag_stats("Before:", &before);
+    if dry_run {
+        return Ok(());
+    }
 
-            // Check each entry
-            for entry in &entries {
-                match fs.read_inode(entry.inode_num) {
-                    Ok(_) => {
-                        if verbose {
-                            println!("  {}: inode {} OK", entry.filename, entry.inode_num);
-                        }
-                    }
-                    Err(e) => {
-                        println!(
-                            "ERROR: Cannot read inode {} for '{}': {}",
-                            entry.inode_num, entry.filename, e
-                        );
-                        errors += 1;
-                    }
-                }
+    match path {
+        Some(path) => {
+            let inode_num = fs.resolve_path(&path)?;
+            if !fs.read_inode(inode_num)?.is_file() {
+                bail!("'{}' is not a regular file", path);
             }
+            let (old, new) = fs.defrag_file(inode_num)?;
+            info!("{}: {} -> {} extents", path, old, new);
         }
-        Err(e) => {
-            println!("ERROR: Cannot list root directory: {}", e);
-            errors += 1;
+        None => {
+            let stats = fs.defrag()?;
+            info!(
+                "Moved {} files, merged {} extents",
+                stats.files_moved, stats.extents_merged
+            );
+            if stats.files_skipped > 0 {
+                info!(
+                    "{} files stayed fragmented: no free run was large enough",
+                    stats.files_skipped
+                );
+            }
         }
     }
 
-    println!();
-    if errors > 0 {
-        println!(
-            "Filesystem check FAILED: {} errors, {} warnings",
-            errors, warnings
-        );
-        std::process::exit(1);
-    } else if warnings > 0 {
-        println!("Filesystem check completed with {} warnings", warnings);
-    } else {
-        println!("Filesystem check passed");
+    print_frag_stats("After:", &fs.frag_stats()?);
+
+    finish_scratch(&mut fs, scratch)
+}
+
+fn cmd_recompress(
+    image: &ImageLocator,
+    algo: &str,
+    path: &str,
+    password: Option<String>,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    let algo = crate::compress::parse_algo_name(algo)?;
+    let mut fs = open_for_write(image, scratch)?;
+    attach_progress(&mut fs);
+    unlock_if_needed(&mut fs, password)?;
+
+    let inode_num = fs.resolve_path(path)?;
+    let stats = fs.recompress(inode_num, algo)?;
+    info!(
+        "Rewrote {} files with {} ({} -> {} blocks), skipped {}",
+        stats.files,
+        crate::compress::get_algo_name(algo),
+        stats.blocks_before,
+        stats.blocks_after,
+        stats.skipped
+    );
+
+    finish_scratch(&mut fs, scratch)
+}
+
+/// Bash hook completing absolute paths from the image named by -i/--image
+const BASH_IMAGE_PATHS: &str = r#"
+_lolelffs_image_paths() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" image="" i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            -i|--image) image="${COMP_WORDS[i+1]}" ;;
+            --image=*) image="${COMP_WORDS[i]#--image=}" ;;
+        esac
+    done
+    if [[ -n "$image" && "$cur" == /* ]]; then
+        compopt -o nospace 2>/dev/null
+        local IFS=$'\n'
+        COMPREPLY=($(lolelffs complete -i "$image" -- "$cur" 2>/dev/null))
+        return 0
+    fi
+    _lolelffs "$@"
+}
+complete -F _lolelffs_image_paths -o bashdefault -o default lolelffs
+"#;
+
+/// Zsh equivalent of `BASH_IMAGE_PATHS`
+const ZSH_IMAGE_PATHS: &str = r#"
+_lolelffs_image_paths() {
+    local image="" i
+    for ((i = 2; i < CURRENT; i++)); do
+        case "${words[i]}" in
+            -i|--image) image="${words[i+1]}" ;;
+            --image=*) image="${words[i]#--image=}" ;;
+        esac
+    done
+    if [[ -n "$image" && "$PREFIX" == /* ]]; then
+        local -a paths
+        paths=("${(@f)$(lolelffs complete -i "$image" -- "$PREFIX" 2>/dev/null)}")
+        compadd -U -S '' -- "${paths[@]}"
+        return
+    fi
+    _lolelffs "$@"
+}
+compdef _lolelffs_image_paths lolelffs
+"#;
+
+fn cmd_completions(shell: clap_complete::Shell) -> Result<()> {
+    use clap::CommandFactory;
+
+    let mut out = io::stdout().lock();
+    clap_complete::generate(shell, &mut Cli::command(), "lolelffs", &mut out);
+    match shell {
+        clap_complete::Shell::Bash => out.write_all(BASH_IMAGE_PATHS.as_bytes())?,
+        clap_complete::Shell::Zsh => out.write_all(ZSH_IMAGE_PATHS.as_bytes())?,
+        _ => {}
     }
 
     Ok(())
 }
 
-fn cmd_df(image: &PathBuf, human: bool) -> Result<()> {
-    let fs = LolelfFs::open_readonly(image)?;
-    let stats = fs.statfs();
+fn cmd_complete(image: &ImageLocator, partial: &str) -> Result<()> {
+    // Completion must stay quiet: an unreadable image just offers nothing
+    let Ok(mut fs) = NoTouchFs::open_locator(image) else {
+        return Ok(());
+    };
+    for path in fs.complete_path(partial).unwrap_or_default() {
+        println!("{}", path);
+    }
 
-    let used = stats.total_blocks - stats.free_blocks;
-    let use_percent = if stats.total_blocks > 0 {
-        (used as f64 / stats.total_blocks as f64 * 100.0) as u32
+    Ok(())
+}
+
+fn cmd_tune(
+    image: &ImageLocator,
+    comp_exclude: &[String],
+    clear_comp_exclude: bool,
+    dir_checksums: Option<String>,
+    ordered_writes: Option<bool>,
+    params: &TuneParams,
+    password: Option<String>,
+) -> Result<()> {
+    let dir_checksums = dir_checksums
+        .map(|name| crate::hash::parse_algo_name(&name))
+        .transpose()?;
+    let changing = clear_comp_exclude
+        || !comp_exclude.is_empty()
+        || dir_checksums.is_some()
+        || ordered_writes.is_some()
+        || !params.is_empty();
+    let mut fs = if changing {
+        LolelfFs::open_locator(image, OpenMode::ReadWrite)?
     } else {
-        0
+        LolelfFs::open_locator(image, OpenMode::ReadOnly)?
     };
 
-    if human {
-        println!("Filesystem      Size  Used Avail Use%");
-        println!(
-            "{:<15} {:>5} {:>5} {:>5} {:>3}%",
-            image.display(),
-            format_size(stats.total_size()),
-            format_size(stats.used_size()),
-            format_size(stats.free_size()),
-            use_percent
-        );
-    } else {
-        println!("Filesystem      Blocks   Used   Avail Use%");
-        println!(
-            "{:<15} {:>6} {:>6} {:>7} {:>3}%",
-            image.display(),
-            stats.total_blocks,
-            used,
-            stats.free_blocks,
-            use_percent
-        );
+    if let Some(algo) = dir_checksums {
+        fs.set_dir_checksums(algo)?;
     }
 
-    println!();
+    if let Some(on) = ordered_writes {
+        fs.set_ordered_writes(on)?;
+    }
+
+    if !params.is_empty() {
+        // A mistyped password would re-wrap the master key unrecoverably, so
+        // a prompted one must be entered twice
+        let password = match password {
+            Some(p) => Some(p),
+            None if params.kdf_iterations.is_some() && fs.superblock.enc_enabled != 0 => {
+                let mut entries = Vec::new();
+                for prompt in ["Enter password: ", "Confirm password: "] {
+                    eprint!("{}", prompt);
+                    io::stderr().flush()?;
+                    let mut pwd = String::new();
+                    io::stdin().read_line(&mut pwd)?;
+                    entries.push(pwd.trim().to_string());
+                }
+                if entries[0] != entries[1] {
+                    bail!("Passwords do not match");
+                }
+                entries.pop()
+            }
+            None => None,
+        };
+        fs.tune(params, password.as_deref())?;
+    }
+
+    if clear_comp_exclude || !comp_exclude.is_empty() {
+        let mut patterns = if clear_comp_exclude {
+            Vec::new()
+        } else {
+            fs.comp_exclude().to_vec()
+        };
+        for spec in comp_exclude {
+            let pattern = crate::compress::parse_exclude_pattern(spec)?;
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+        fs.set_comp_exclude(patterns)?;
+    }
+
+    let sb = &fs.superblock;
+    if json_output() {
+        let patterns: Vec<&str> = fs.comp_exclude().iter().map(|p| p.as_str()).collect();
+        let out = JsonValue::object([
+            ("comp_exclude", patterns.into()),
+            (
+                "dir_checksums",
+                crate::hash::get_algo_name(sb.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM)).into(),
+            ),
+            ("ordered_writes", fs.ordered_writes().into()),
+            ("reflinks", fs.reflink_enabled().into()),
+            ("comp_enabled", (sb.comp_enabled != 0).into()),
+            (
+                "comp_default_algo",
+                crate::compress::get_algo_name(sb.comp_default_algo as u8).into(),
+            ),
+            ("comp_min_block_size", sb.comp_min_block_size.into()),
+            ("max_extent_blocks", sb.max_extent_blocks_large.into()),
+            (
+                "kdf_iterations",
+                (sb.enc_enabled != 0)
+                    .then_some(sb.enc_kdf_iterations)
+                    .into(),
+            ),
+        ]);
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+
+    print_comp_exclude(&fs);
+    println!(
+        "Directory checksums: {}",
+        crate::hash::get_algo_name(fs.superblock.hash_algo(LOLELFFS_HASH_FEAT_CHECKSUM))
+    );
     println!(
-        "Inodes: {} total, {} free",
-        stats.total_inodes, stats.free_inodes
+        "Ordered writes: {}",
+        if fs.ordered_writes() { "on" } else { "off" }
     );
-
+    println!(
+        "Reflinks: {}",
+        if fs.reflink_enabled() { "on" } else { "off" }
+    );
+    println!(
+        "Compression: {} (default {}, min block size {})",
+        if sb.comp_enabled != 0 { "on" } else { "off" },
+        crate::compress::get_algo_name(sb.comp_default_algo as u8),
+        sb.comp_min_block_size
+    );
+    println!("Max extent blocks: {}", sb.max_extent_blocks_large);
+    if sb.enc_enabled != 0 {
+        println!("KDF iterations: {}", sb.enc_kdf_iterations);
+    }
     Ok(())
 }
 
-fn cmd_ln(image: &PathBuf, target: &str, link: &str, symbolic: bool) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
-    let (parent_path, link_name) = split_path(link);
-    let parent_inode = fs.resolve_path(&parent_path)?;
+/// Parse an on/off switch argument
+/// Parse an inclusive "FIRST-LAST" block range
+fn parse_block_range(s: &str) -> std::result::Result<std::ops::Range<u32>, String> {
+    let (first, last) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected FIRST-LAST, got '{}'", s))?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid block number '{}'", n))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+    if last < first || last == u32::MAX {
+        return Err(format!("invalid block range '{}'", s));
+    }
+    Ok(first..last + 1)
+}
 
-    if symbolic {
-        fs.symlink(parent_inode, link_name, target)?;
-    } else {
-        let target_inode = fs.resolve_path(target)?;
-        fs.link(target_inode, parent_inode, link_name)?;
+fn parse_foreign_format(s: &str) -> std::result::Result<String, String> {
+    match s {
+        "ext2" => Ok(s.to_string()),
+        _ => Err(format!("unsupported format '{}' (expected ext2)", s)),
+    }
+}
+
+fn parse_on_off(s: &str) -> std::result::Result<bool, String> {
+    match s {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        _ => Err(format!("expected on or off, got '{}'", s)),
     }
+}
 
-    Ok(())
+fn print_comp_exclude(fs: &LolelfFs) {
+    let patterns: Vec<&str> = fs.comp_exclude().iter().map(|p| p.as_str()).collect();
+    if patterns.is_empty() {
+        println!("Compression exclusions: none");
+    } else {
+        println!("Compression exclusions: {}", patterns.join(" "));
+    }
 }
 
-fn cmd_super(image: &PathBuf) -> Result<()> {
-    let fs = LolelfFs::open_readonly(image)?;
+fn cmd_super(image: &ImageLocator, verbose: bool) -> Result<()> {
+    let fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
     let sb = &fs.superblock;
+    let expected = Layout::with_inodes(sb.nr_blocks, sb.nr_inodes);
+
+    if json_output() {
+        let mut out = sb.to_json();
+        out.push("layout_issues", sb.layout_issues());
+        out.push("compat_issues", sb.compat_issues());
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+
+    // In verbose mode, block counts also show their size in human units
+    let blocks = |n: u32| {
+        if verbose {
+            format!(
+                "{} ({})",
+                n,
+                format_size(n as u64 * LOLELFFS_BLOCK_SIZE as u64)
+            )
+        } else {
+            n.to_string()
+        }
+    };
+    // In verbose mode, flag values that disagree with the derived layout
+    let check = |actual: u32, want: u32| {
+        if verbose && actual != want {
+            format!("  <-- MISMATCH: expected {}", want)
+        } else {
+            String::new()
+        }
+    };
 
-    println!("Superblock information for {}", image.display());
+    println!("Superblock information for {}", image);
     println!("  Magic: 0x{:08X}", sb.magic);
-    println!("  Total blocks: {}", sb.nr_blocks);
+    println!("  UUID: {}", sb.uuid_string());
+    println!("  Label: {}", sb.label());
+    if sb.state & LOLELFFS_STATE_DIRTY != 0 {
+        println!("  State: dirty (open for writing, or not closed cleanly)");
+    } else {
+        println!("  State: clean");
+    }
+    println!("  Total blocks: {}", blocks(sb.nr_blocks));
     println!("  Total inodes: {}", sb.nr_inodes);
-    println!("  Inode store blocks: {}", sb.nr_istore_blocks);
-    println!("  Inode free bitmap blocks: {}", sb.nr_ifree_blocks);
-    println!("  Block free bitmap blocks: {}", sb.nr_bfree_blocks);
+    println!(
+        "  Inode store blocks: {}{}",
+        blocks(sb.nr_istore_blocks),
+        check(sb.nr_istore_blocks, expected.nr_istore_blocks)
+    );
+    println!(
+        "  Inode free bitmap blocks: {}{}",
+        blocks(sb.nr_ifree_blocks),
+        check(sb.nr_ifree_blocks, expected.nr_ifree_blocks)
+    );
+    println!(
+        "  Block free bitmap blocks: {}{}",
+        blocks(sb.nr_bfree_blocks),
+        check(sb.nr_bfree_blocks, expected.nr_bfree_blocks)
+    );
     println!("  Free inodes: {}", sb.nr_free_inodes);
-    println!("  Free blocks: {}", sb.nr_free_blocks);
+    println!("  Free blocks: {}", blocks(sb.nr_free_blocks));
     println!();
     println!("Extent limits:");
     println!(
@@ -902,45 +5156,165 @@ fn cmd_super(image: &PathBuf) -> Result<()> {
     if sb.comp_features & LOLELFFS_FEATURE_LARGE_EXTENTS != 0 {
         println!("    - Large extents support enabled");
     }
+    if sb.comp_features & LOLELFFS_FEATURE_COMP_EXCLUDE != 0 {
+        print!("    - ");
+        print_comp_exclude(&fs);
+    }
+    println!();
+    println!("Integrity hashing:");
+    for (feature, name) in [
+        (LOLELFFS_HASH_FEAT_CHECKSUM, "Checksums"),
+        (LOLELFFS_HASH_FEAT_MANIFEST, "Manifests"),
+        (LOLELFFS_HASH_FEAT_DEDUP, "Dedup"),
+        (LOLELFFS_HASH_FEAT_MERKLE, "Merkle trees"),
+    ] {
+        println!(
+            "  {}: {}",
+            name,
+            crate::hash::get_algo_name(sb.hash_algo(feature))
+        );
+    }
     println!();
     println!("Layout:");
     println!("  Block 0: Superblock");
     println!(
         "  Blocks {}-{}: Inode store",
         sb.inode_store_start(),
-        sb.ifree_bitmap_start() - 1
+        sb.ifree_bitmap_start().saturating_sub(1)
     );
     println!(
         "  Blocks {}-{}: Inode free bitmap",
         sb.ifree_bitmap_start(),
-        sb.bfree_bitmap_start() - 1
+        sb.bfree_bitmap_start().saturating_sub(1)
     );
     println!(
         "  Blocks {}-{}: Block free bitmap",
         sb.bfree_bitmap_start(),
-        sb.data_block_start() - 1
+        sb.data_block_start().saturating_sub(1)
     );
     println!(
-        "  Blocks {}-{}: Data blocks",
+        "  Blocks {}-{}: Data blocks{}",
         sb.data_block_start(),
-        sb.nr_blocks - 1
+        sb.nr_blocks.saturating_sub(1),
+        check(sb.data_block_start(), expected.data_block_start())
     );
 
+    let unsupported = sb.compat_issues();
+    if !unsupported.is_empty() {
+        println!();
+        println!("Not supported by this build (image is read-only):");
+        for issue in &unsupported {
+            println!("  {}", issue);
+        }
+    }
+
+    if verbose {
+        let issues = sb.layout_issues();
+        println!();
+        println!("Sanity checks:");
+        if issues.is_empty() {
+            println!("  Layout matches values derived from block and inode counts");
+        } else {
+            for issue in &issues {
+                println!("  WARNING: {}", issue);
+            }
+            bail!("{} layout inconsistencies found", issues.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_id(image: &ImageLocator) -> Result<()> {
+    let (path, offset) = image.resolve()?;
+    let info = match crate::probe::probe_at(&path, offset)
+        .with_context(|| format!("Failed to probe '{}'", image))?
+    {
+        Some(info) => info,
+        None => bail!("'{}' is not a lolelffs image", image),
+    };
+    let sb = &info.superblock;
+
+    let named = |flags: u32, table: &'static [(u32, &'static str)]| {
+        table
+            .iter()
+            .filter(move |(bit, _)| flags & bit != 0)
+            .map(|&(_, name)| name)
+    };
+    let mut features: Vec<&str> =
+        named(sb.comp_features, crate::compat::SUPPORTED_FEATURES).collect();
+    if sb.comp_enabled != 0 {
+        features.push("compression");
+    }
+    if sb.enc_enabled != 0 {
+        features.push("encryption");
+    }
+    features.extend(named(
+        sb.enc_features,
+        crate::compat::SUPPORTED_ENC_FEATURES,
+    ));
+    let compression = if sb.comp_enabled != 0 {
+        crate::compress::get_algo_name(sb.comp_default_algo as u8)
+    } else {
+        "none"
+    };
+    let encryption = if sb.enc_enabled != 0 {
+        crate::encrypt::get_algo_name(sb.enc_default_algo as u8)
+    } else {
+        "none"
+    };
+
+    if json_output() {
+        // The blkid keys, lowercased; a missing UUID or label is null
+        let out = JsonValue::object([
+            ("devname", image.to_string().into()),
+            ("type", "lolelffs".into()),
+            (
+                "uuid",
+                (sb.uuid != [0; 16]).then(|| sb.uuid_string()).into(),
+            ),
+            ("label", (!sb.label().is_empty()).then(|| sb.label()).into()),
+            ("version", sb.version.into()),
+            ("offset", info.offset.into()),
+            ("block_size", LOLELFFS_BLOCK_SIZE.into()),
+            ("features", features.into()),
+            ("compression", compression.into()),
+            ("encryption", encryption.into()),
+        ]);
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+
+    println!("DEVNAME={}", image);
+    println!("TYPE=lolelffs");
+    if sb.uuid != [0; 16] {
+        println!("UUID={}", sb.uuid_string());
+    }
+    if !sb.label().is_empty() {
+        println!("LABEL={}", sb.label());
+    }
+    println!("VERSION={}", sb.version);
+    println!("OFFSET={}", info.offset);
+    println!("BLOCK_SIZE={}", LOLELFFS_BLOCK_SIZE);
+    println!("FEATURES={}", features.join(","));
+    println!("COMPRESSION={}", compression);
+    println!("ENCRYPTION={}", encryption);
+
     Ok(())
 }
 
-fn cmd_unlock(image: &PathBuf, password: Option<String>) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_unlock(image: &ImageLocator, password: Option<String>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
 
     // Check if encryption is enabled
     if fs.superblock.enc_enabled == 0 {
-        println!("Filesystem is not encrypted");
+        info!("Filesystem is not encrypted");
         return Ok(());
     }
 
     // Check if already unlocked
     if fs.enc_unlocked {
-        println!("Filesystem is already unlocked");
+        info!("Filesystem is already unlocked");
         return Ok(());
     }
 
@@ -959,8 +5333,8 @@ fn cmd_unlock(image: &PathBuf, password: Option<String>) -> Result<()> {
     // Unlock the filesystem
     fs.unlock(&pwd)?;
 
-    println!("Filesystem unlocked successfully");
-    println!(
+    info!("Filesystem unlocked successfully");
+    info!(
         "  Encryption algorithm: {}",
         crate::encrypt::get_algo_name(fs.superblock.enc_default_algo as u8)
     );
@@ -968,60 +5342,588 @@ fn cmd_unlock(image: &PathBuf, password: Option<String>) -> Result<()> {
     Ok(())
 }
 
-fn cmd_cp(image: &PathBuf, source: &PathBuf, dest: &str, password: Option<String>) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_passwd(
+    image: &ImageLocator,
+    password: Option<String>,
+    new_password: Option<String>,
+    kdf_iterations: Option<u32>,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    if fs.superblock.enc_enabled == 0 {
+        bail!("Filesystem is not encrypted");
+    }
+
+    let old = match password {
+        Some(p) => p,
+        None => {
+            eprint!("Enter current password: ");
+            io::stderr().flush()?;
+            let mut pwd = String::new();
+            io::stdin().read_line(&mut pwd)?;
+            pwd.trim().to_string()
+        }
+    };
+    // A mistyped new password would lock the data away, so a prompted one
+    // must be entered twice
+    let new = match new_password {
+        Some(p) => p,
+        None => {
+            let mut entries = Vec::new();
+            for prompt in ["Enter new password: ", "Confirm new password: "] {
+                eprint!("{}", prompt);
+                io::stderr().flush()?;
+                let mut pwd = String::new();
+                io::stdin().read_line(&mut pwd)?;
+                entries.push(pwd.trim().to_string());
+            }
+            if entries[0] != entries[1] {
+                bail!("Passwords do not match");
+            }
+            entries.pop().unwrap()
+        }
+    };
+
+    match fs.change_password(&old, &new, kdf_iterations)? {
+        passwd::PasswordCheck::Verified => {}
+        passwd::PasswordCheck::Unverified => {
+            info!("Warning: no encrypted block could confirm the current password")
+        }
+    }
+    info!(
+        "Password changed ({} KDF iterations)",
+        fs.superblock.enc_kdf_iterations
+    );
+    Ok(())
+}
+
+fn cmd_decrypt(image: &ImageLocator, password: Option<String>) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    attach_progress(&mut fs);
+    if fs.superblock.enc_enabled == 0 {
+        bail!("Filesystem is not encrypted");
+    }
+
+    let pwd = match password {
+        Some(p) => p,
+        None => {
+            eprint!("Enter password: ");
+            io::stderr().flush()?;
+            let mut pwd = String::new();
+            io::stdin().read_line(&mut pwd)?;
+            pwd.trim().to_string()
+        }
+    };
+
+    let stats = fs.decrypt_image(&pwd)?;
+    if stats.check == passwd::PasswordCheck::Unverified && stats.blocks > 0 {
+        info!("Warning: no encrypted block could confirm the password");
+    }
+    info!(
+        "Decrypted {} blocks in {} files; encryption is off",
+        stats.blocks, stats.files
+    );
+    Ok(())
+}
+
+fn cmd_cp(
+    image: &ImageLocator,
+    source: &std::path::Path,
+    dest: &str,
+    password: Option<String>,
+    recursive: bool,
+    symlinks: bool,
+    checksum: bool,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
 
     // Unlock if encrypted and password provided
     unlock_if_needed(&mut fs, password)?;
 
-    // Read source file from host
-    let content =
-        std::fs::read(source).with_context(|| format!("Failed to read '{}'", source.display()))?;
+    cp_path(&mut fs, source, dest, recursive, symlinks, checksum)
+}
+
+/// Copy the host file or tree `source` to `dest` in the image
+fn cp_path(
+    fs: &mut LolelfFs,
+    source: &std::path::Path,
+    dest: &str,
+    recursive: bool,
+    symlinks: bool,
+    checksum: bool,
+) -> Result<()> {
+    if source.is_dir() {
+        if !recursive {
+            bail!("'{}' is a directory (use -r)", source.display());
+        }
+
+        // Like cp(1): copy into an existing directory, otherwise create dest
+        let (parent_inode, name) = match fs.resolve_path(dest) {
+            Ok(inode_num) if fs.read_inode(inode_num)?.is_dir() => {
+                let name = source
+                    .canonicalize()?
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid source directory name"))?;
+                (inode_num, name)
+            }
+            _ => {
+                let (parent_path, name) = split_path(dest);
+                (fs.resolve_path(&parent_path)?, name.to_string())
+            }
+        };
+
+        let mut visited = std::collections::HashSet::new();
+        return cp_host_tree(
+            fs,
+            source,
+            parent_inode,
+            &name,
+            symlinks,
+            checksum,
+            &mut visited,
+        );
+    }
+
+    // Open before touching the image so a bad source leaves no empty file
+    let file = std::fs::File::open(source)
+        .with_context(|| format!("Failed to read '{}'", source.display()))?;
+
+    // Determine destination path
+    let dest_path = if dest.ends_with('/') {
+        // Destination is a directory, use source filename
+        let filename = source
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid source filename"))?
+            .to_string_lossy();
+        format!("{}{}", dest, filename)
+    } else {
+        dest.to_string()
+    };
+
+    // Create or overwrite file
+    let inode_num = match fs.resolve_path(&dest_path) {
+        Ok(inode_num) => inode_num,
+        Err(_) => {
+            let (parent_path, filename) = split_path(&dest_path);
+            let parent_inode = fs.resolve_path(&parent_path)?;
+            fs.create_file(parent_inode, filename)?
+        }
+    };
+    cp_host_file(fs, file, source, inode_num, checksum)
+}
+
+/// Replace the contents of `inode_num` with `file`, opened from `source`
+///
+/// Small files go through `write_file` in one piece, which keeps the old
+/// contents until the new ones are written and shares identical data on
+/// reflink images. Large ones are streamed, holding at most a writer
+/// buffer in memory.
+fn cp_host_file(
+    fs: &mut LolelfFs,
+    mut file: std::fs::File,
+    source: &std::path::Path,
+    inode_num: u32,
+    checksum: bool,
+) -> Result<()> {
+    if file.metadata()?.len() <= CP_STREAM_THRESHOLD {
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)
+            .with_context(|| format!("Failed to read '{}'", source.display()))?;
+        fs.write_file(inode_num, &content)?;
+        if checksum {
+            fs.record_sha256(inode_num, &content)?;
+        }
+        return Ok(());
+    }
+
+    fs.truncate(inode_num, 0)?;
+    let mut writer = fs.open_writer(inode_num)?;
+    std::io::copy(&mut file, &mut writer)
+        .with_context(|| format!("Failed to copy '{}'", source.display()))?;
+    writer.flush()?;
+    drop(writer);
+    if checksum {
+        fs.update_sha256(inode_num)?;
+    }
+    Ok(())
+}
+
+/// Copy a host file, directory, or symlink to `name` under `parent_inode`
+fn cp_host_tree(
+    fs: &mut LolelfFs,
+    source: &std::path::Path,
+    parent_inode: u32,
+    name: &str,
+    symlinks: bool,
+    checksum: bool,
+    visited: &mut std::collections::HashSet<PathBuf>,
+) -> Result<()> {
+    let meta = std::fs::symlink_metadata(source)
+        .with_context(|| format!("Failed to stat '{}'", source.display()))?;
+
+    let inode_num = if symlinks && meta.file_type().is_symlink() {
+        let target = std::fs::read_link(source)?;
+        if fs.lookup(parent_inode, name)?.is_some() {
+            bail!("'{}' already exists in the image", name);
+        }
+        return fs
+            .symlink(parent_inode, name, &target.to_string_lossy())
+            .map(|_| ())
+            .with_context(|| format!("Failed to copy symlink '{}'", source.display()));
+    } else if source.is_dir() {
+        // Followed symlinks could otherwise loop forever
+        if !visited.insert(source.canonicalize()?) {
+            bail!("Directory cycle at '{}'", source.display());
+        }
+
+        let inode_num = match fs.lookup(parent_inode, name)? {
+            Some(existing) if fs.read_inode(existing)?.is_dir() => existing,
+            Some(_) => bail!("'{}' exists and is not a directory", name),
+            None => fs.mkdir(parent_inode, name)?,
+        };
+
+        let mut children: Vec<_> = std::fs::read_dir(source)
+            .with_context(|| format!("Failed to read '{}'", source.display()))?
+            .collect::<std::io::Result<_>>()?;
+        children.sort_by_key(|entry| entry.file_name());
+        for child in children {
+            let child_name = child.file_name().to_string_lossy().into_owned();
+            cp_host_tree(
+                fs,
+                &child.path(),
+                inode_num,
+                &child_name,
+                symlinks,
+                checksum,
+                visited,
+            )?;
+        }
+        inode_num
+    } else {
+        let file = std::fs::File::open(source)
+            .with_context(|| format!("Failed to read '{}'", source.display()))?;
+        let inode_num = match fs.lookup(parent_inode, name)? {
+            Some(existing) => existing,
+            None => fs.create_file(parent_inode, name)?,
+        };
+        cp_host_file(fs, file, source, inode_num, checksum)?;
+        inode_num
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let perm = std::fs::metadata(source)?.permissions().mode() & 0o7777;
+        fs.set_mode(inode_num, perm)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_extract(
+    image: &ImageLocator,
+    source: &str,
+    dest: &PathBuf,
+    recursive: bool,
+    skip_unsupported: bool,
+    physical_order: bool,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    let inode_num = fs.resolve_path(source)?;
+
+    if fs.read_inode(inode_num)?.is_dir() {
+        if !recursive {
+            bail!("'{}' is a directory (use -r)", source);
+        }
+
+        // Like cp(1): extract into an existing directory, otherwise create dest
+        let target = if dest.is_dir() {
+            match split_path(source).1 {
+                "" => dest.clone(),
+                name => dest.join(name),
+            }
+        } else {
+            dest.clone()
+        };
+
+        if physical_order {
+            return extract_tree_physical(&mut fs, inode_num, &target, skip_unsupported);
+        }
+        let mut visited = std::collections::HashSet::new();
+        return extract_tree(&mut fs, inode_num, &target, &mut visited, skip_unsupported);
+    }
+
+    if skip_unsupported && skip_if_unsupported(&mut fs, inode_num, source)? {
+        return Ok(());
+    }
+    let mut out = std::io::BufWriter::new(
+        std::fs::File::create(dest)
+            .with_context(|| format!("Failed to write '{}'", dest.display()))?,
+    );
+    fs.read_file_to(inode_num, &mut out)?;
+    out.flush()
+        .with_context(|| format!("Failed to write '{}'", dest.display()))?;
+
+    Ok(())
+}
+
+fn cmd_extract_all(
+    image: &ImageLocator,
+    dest: &std::path::Path,
+    password: Option<String>,
+    xattrs: bool,
+    skip_unsupported: bool,
+    physical_order: bool,
+) -> Result<()> {
+    #[cfg(not(unix))]
+    if xattrs {
+        bail!("Copying xattrs to the host is not supported on this platform");
+    }
+    if dest.exists() {
+        let mut entries = std::fs::read_dir(dest)
+            .with_context(|| format!("'{}' is not a directory", dest.display()))?;
+        if entries.next().is_some() {
+            bail!("'{}' is not empty", dest.display());
+        }
+    }
+
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
+
+    if physical_order {
+        extract_tree_physical(&mut fs, LOLELFFS_ROOT_INO, dest, skip_unsupported)?;
+    } else {
+        let mut visited = std::collections::HashSet::new();
+        extract_tree(
+            &mut fs,
+            LOLELFFS_ROOT_INO,
+            dest,
+            &mut visited,
+            skip_unsupported,
+        )?;
+    }
+
+    // Timestamps and xattrs once everything exists; deepest entries first,
+    // since creating children bumps a directory's mtime
+    let walked = fs.walk_tree(LOLELFFS_ROOT_INO)?;
+    let mut failed_xattrs = 0;
+    let root = fs.read_inode(LOLELFFS_ROOT_INO)?;
+    let entries = walked
+        .iter()
+        .rev()
+        .map(|w| (dest.join(&w.path), w.entry.inode_num, &w.entry.inode))
+        .chain(std::iter::once((
+            dest.to_path_buf(),
+            LOLELFFS_ROOT_INO,
+            &root,
+        )));
+    for (path, inode_num, inode) in entries {
+        if std::fs::symlink_metadata(&path).is_err() {
+            // Skipped as unsupported
+            continue;
+        }
+        #[cfg(unix)]
+        if xattrs {
+            for name in fs.list_xattrs(inode_num)? {
+                let value = fs.get_xattr(inode_num, &name)?;
+                if let Err(e) = ::xattr::set(&path, &name, &value) {
+                    eprintln!(
+                        "Warning: cannot set {} on '{}': {}",
+                        name,
+                        path.display(),
+                        e
+                    );
+                    failed_xattrs += 1;
+                }
+            }
+        }
+        if !inode.is_symlink() {
+            let mtime =
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(inode.i_mtime as u64);
+            std::fs::File::open(&path)
+                .and_then(|f| f.set_modified(mtime))
+                .with_context(|| format!("Failed to set mtime of '{}'", path.display()))?;
+        }
+    }
+
+    let (mut files, mut dirs, mut symlinks) = (0, 0, 0);
+    for walk in &walked {
+        match &walk.entry.inode {
+            inode if inode.is_dir() => dirs += 1,
+            inode if inode.is_symlink() => symlinks += 1,
+            _ => files += 1,
+        }
+    }
+    info!(
+        "Extracted {} files, {} directories, {} symlinks to '{}'",
+        files,
+        dirs,
+        symlinks,
+        dest.display()
+    );
+    if failed_xattrs > 0 {
+        eprintln!("Warning: {} xattrs could not be set", failed_xattrs);
+    }
+
+    Ok(())
+}
+
+/// Warn and return true if a file is stored with an algorithm this build
+/// cannot decode
+fn skip_if_unsupported(fs: &mut LolelfFs, inode_num: u32, path: &str) -> Result<bool> {
+    match fs.unsupported_algorithm(inode_num)? {
+        Some(what) => {
+            eprintln!("Warning: skipping '{}': stored with {}", path, what);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Recreate an inode and everything below it at a host path
+fn extract_tree(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    dest: &std::path::Path,
+    visited: &mut std::collections::HashSet<u32>,
+    skip_unsupported: bool,
+) -> Result<()> {
+    let inode = fs.read_inode(inode_num)?;
+
+    if inode.is_symlink() {
+        return extract_symlink(fs, inode_num, dest);
+    }
+
+    if inode.is_dir() {
+        if !visited.insert(inode_num) {
+            bail!("Directory cycle at '{}'", dest.display());
+        }
+        if !dest.is_dir() {
+            std::fs::create_dir(dest)
+                .with_context(|| format!("Failed to create '{}'", dest.display()))?;
+        }
+        for entry in fs.list_dir(inode_num)? {
+            let child = dest.join(&entry.filename);
+            extract_tree(fs, entry.inode_num, &child, visited, skip_unsupported)?;
+        }
+    } else if !extract_file(fs, inode_num, dest, skip_unsupported)? {
+        return Ok(());
+    }
+
+    set_host_mode(dest, inode.i_mode)
+}
 
-    // Determine destination path
-    let dest_path = if dest.ends_with('/') {
-        // Destination is a directory, use source filename
-        let filename = source
-            .file_name()
-            .ok_or_else(|| anyhow::anyhow!("Invalid source filename"))?
-            .to_string_lossy();
-        format!("{}{}", dest, filename)
-    } else {
-        dest.to_string()
-    };
+/// Extract a directory tree, creating every directory and symlink first and
+/// then writing files in the order their contents are stored on the image
+fn extract_tree_physical(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    dest: &std::path::Path,
+    skip_unsupported: bool,
+) -> Result<()> {
+    let walked = fs.walk_tree_physical(inode_num)?;
+    if !dest.is_dir() {
+        std::fs::create_dir(dest)
+            .with_context(|| format!("Failed to create '{}'", dest.display()))?;
+    }
 
-    // Create or overwrite file
-    match fs.resolve_path(&dest_path) {
-        Ok(inode_num) => {
-            fs.write_file(inode_num, &content)?;
-        }
-        Err(_) => {
-            let (parent_path, filename) = split_path(&dest_path);
-            let parent_inode = fs.resolve_path(&parent_path)?;
-            let inode_num = fs.create_file(parent_inode, filename)?;
-            fs.write_file(inode_num, &content)?;
+    for walk in &walked {
+        let path = dest.join(&walk.path);
+        let inode = &walk.entry.inode;
+        if inode.is_dir() {
+            if !path.is_dir() {
+                std::fs::create_dir(&path)
+                    .with_context(|| format!("Failed to create '{}'", path.display()))?;
+            }
+        } else if inode.is_symlink() {
+            extract_symlink(fs, walk.entry.inode_num, &path)?;
+        } else if extract_file(fs, walk.entry.inode_num, &path, skip_unsupported)? {
+            set_host_mode(&path, inode.i_mode)?;
         }
     }
 
-    Ok(())
+    // Directory modes go last, deepest first, so a read-only directory
+    // never blocks its own children
+    for walk in walked.iter().rev().filter(|w| w.entry.inode.is_dir()) {
+        set_host_mode(&dest.join(&walk.path), walk.entry.inode.i_mode)?;
+    }
+    set_host_mode(dest, fs.read_inode(inode_num)?.i_mode)
 }
 
-fn cmd_extract(image: &PathBuf, source: &str, dest: &PathBuf) -> Result<()> {
-    let mut fs = LolelfFs::open_readonly(image)?;
-    let inode_num = fs.resolve_path(source)?;
-    let data = fs.read_file(inode_num)?;
+fn extract_symlink(fs: &mut LolelfFs, inode_num: u32, dest: &std::path::Path) -> Result<()> {
+    let target = String::from_utf8_lossy(&fs.read_file(inode_num)?).into_owned();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, dest)
+        .with_context(|| format!("Failed to create symlink '{}'", dest.display()))?;
+    #[cfg(not(unix))]
+    bail!(
+        "Cannot create symlink '{}' -> '{}' on this platform",
+        dest.display(),
+        target
+    );
+    Ok(())
+}
 
-    std::fs::write(dest, &data).with_context(|| format!("Failed to write '{}'", dest.display()))?;
+/// Write a regular file's contents to a host path, returning false if it
+/// was skipped for using an unsupported algorithm
+fn extract_file(
+    fs: &mut LolelfFs,
+    inode_num: u32,
+    dest: &std::path::Path,
+    skip_unsupported: bool,
+) -> Result<bool> {
+    if skip_unsupported && skip_if_unsupported(fs, inode_num, &dest.to_string_lossy())? {
+        return Ok(false);
+    }
+    let mut out = std::io::BufWriter::new(
+        std::fs::File::create(dest)
+            .with_context(|| format!("Failed to write '{}'", dest.display()))?,
+    );
+    fs.read_file_to(inode_num, &mut out)?;
+    out.flush()
+        .with_context(|| format!("Failed to write '{}'", dest.display()))?;
+    Ok(true)
+}
 
+/// Apply an inode's permission bits to a host path (a no-op off unix)
+fn set_host_mode(dest: &std::path::Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let perm = std::fs::Permissions::from_mode(mode & 0o7777);
+        std::fs::set_permissions(dest, perm)
+            .with_context(|| format!("Failed to set mode of '{}'", dest.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (dest, mode);
     Ok(())
 }
 
-fn cmd_getfattr(image: &PathBuf, path: &str, name: &str, hex: bool) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_getfattr(image: &ImageLocator, path: &str, name: &str, hex: bool) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
     let inode_num = fs.resolve_path(path)?;
 
     let value = fs.get_xattr(inode_num, name)?;
 
+    if json_output() {
+        // Text unless asked for hex or the value is not printable UTF-8
+        let text = std::str::from_utf8(&value)
+            .ok()
+            .filter(|s| !hex && !s.bytes().any(|b| b < 32 && b != b'\n' && b != b'\t'));
+        let (encoding, shown) = match text {
+            Some(text) => ("text", text.to_string()),
+            None => ("hex", value.iter().map(|b| format!("{:02x}", b)).collect()),
+        };
+        let out = JsonValue::object([
+            ("path", path.into()),
+            ("name", name.into()),
+            ("encoding", encoding.into()),
+            ("value", shown.into()),
+        ]);
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+
     println!("# file: {}", path);
     if hex || value.iter().any(|&b| b < 32 && b != b'\n' && b != b'\t') {
         // Print as hex if requested or if binary data
@@ -1047,22 +5949,102 @@ fn cmd_getfattr(image: &PathBuf, path: &str, name: &str, hex: bool) -> Result<()
     Ok(())
 }
 
-fn cmd_setfattr(image: &PathBuf, path: &str, name: &str, value: &str) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_setfattr(
+    image: &ImageLocator,
+    path: &str,
+    name: &str,
+    value: &str,
+    default: bool,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
     let inode_num = fs.resolve_path(path)?;
 
-    fs.set_xattr(inode_num, name, value.as_bytes())?;
-    println!("Set {} on {}", name, path);
+    let name = if default {
+        if !fs.read_inode(inode_num)?.is_dir() {
+            bail!("'{}' is not a directory", path);
+        }
+        let Some(base) = name.strip_prefix("user.") else {
+            bail!("Default attributes must be in the user. namespace");
+        };
+        format!("{}{}", xattr::DEFAULT_XATTR_PREFIX, base)
+    } else {
+        name.to_string()
+    };
+    fs.set_xattr(inode_num, &name, value.as_bytes())?;
+    info!("Set {} on {}", name, path);
+
+    Ok(())
+}
+
+fn cmd_chattr(image: &ImageLocator, attr: &str, path: &str) -> Result<()> {
+    let (set, name) = match attr.split_at_checked(1) {
+        Some(("+", name)) => (true, name),
+        Some(("-", name)) => (false, name),
+        _ => bail!("Expected +hot, +cold, -hot or -cold, got '{}'", attr),
+    };
+    let hint: hint::AccessHint = name.parse().map_err(anyhow::Error::msg)?;
+    if hint == hint::AccessHint::Normal {
+        bail!("Expected hot or cold, got '{}'", name);
+    }
+
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    let inode_num = fs.resolve_path(path)?;
+    let current = fs.access_hint(inode_num)?;
+    let new = match (set, current == hint) {
+        (true, _) => hint,
+        (false, true) => hint::AccessHint::Normal,
+        (false, false) => current,
+    };
+    fs.set_access_hint(inode_num, new)?;
+    info!(
+        "{}: access hint {} (applies from the next write)",
+        path, new
+    );
+    Ok(())
+}
 
+fn cmd_setcomp(
+    image: &ImageLocator,
+    path: &str,
+    algo: Option<&str>,
+    password: Option<String>,
+) -> Result<()> {
+    let algo = algo.map(crate::compress::parse_algo_name).transpose()?;
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    unlock_if_needed(&mut fs, password)?;
+    let inode_num = fs.resolve_path(path)?;
+    let rewritten = fs.set_compression(inode_num, algo)?;
+    match algo {
+        Some(algo) if rewritten => info!(
+            "{}: compression {} (rewritten)",
+            path,
+            crate::compress::get_algo_name(algo)
+        ),
+        Some(algo) => info!(
+            "{}: compression {}",
+            path,
+            crate::compress::get_algo_name(algo)
+        ),
+        None => info!(
+            "{}: image default compression (applies from the next write)",
+            path
+        ),
+    }
     Ok(())
 }
 
-fn cmd_listxattr(image: &PathBuf, path: &str) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_listxattr(image: &ImageLocator, path: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
     let inode_num = fs.resolve_path(path)?;
 
     let xattrs = fs.list_xattrs(inode_num)?;
 
+    if json_output() {
+        let out = JsonValue::object([("path", path.into()), ("xattrs", xattrs.into())]);
+        println!("{}", out.to_pretty());
+        return Ok(());
+    }
+
     if xattrs.is_empty() {
         println!("# file: {}", path);
         println!("(no extended attributes)");
@@ -1076,18 +6058,624 @@ fn cmd_listxattr(image: &PathBuf, path: &str) -> Result<()> {
     Ok(())
 }
 
-fn cmd_removexattr(image: &PathBuf, path: &str, name: &str) -> Result<()> {
-    let mut fs = LolelfFs::open(image)?;
+fn cmd_removexattr(image: &ImageLocator, path: &str, name: &str) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
     let inode_num = fs.resolve_path(path)?;
 
     fs.remove_xattr(inode_num, name)?;
-    println!("Removed {} from {}", name, path);
+    info!("Removed {} from {}", name, path);
+
+    Ok(())
+}
+
+/// Paths of every inode carrying xattrs, in tree order, one per inode
+fn xattr_inode_paths(fs: &mut LolelfFs) -> Result<Vec<(String, u32)>> {
+    let mut seen = std::collections::HashSet::new();
+    let mut paths = Vec::new();
+
+    let root = std::iter::once(("/".to_string(), LOLELFFS_ROOT_INO));
+    let walked: Vec<(String, u32)> = fs
+        .walk_tree(LOLELFFS_ROOT_INO)?
+        .into_iter()
+        .map(|w| (format!("/{}", w.path), w.entry.inode_num))
+        .collect();
+    for (path, inode_num) in root.chain(walked) {
+        if seen.insert(inode_num) && fs.read_inode(inode_num)?.xattr_block != 0 {
+            paths.push((path, inode_num));
+        }
+    }
+
+    Ok(paths)
+}
+
+fn cmd_xattr_index(image: &ImageLocator, action: XattrIndexAction) -> Result<()> {
+    match action {
+        XattrIndexAction::Usage => {
+            let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+            if json_output() {
+                let mut listed = Vec::new();
+                for (path, inode_num) in xattr_inode_paths(&mut fs)? {
+                    if let Some(usage) = xattr::xattr_usage(&mut fs, inode_num)? {
+                        let mut entry = usage.to_json();
+                        entry.push("path", path);
+                        listed.push(entry);
+                    }
+                }
+                println!("{}", JsonValue::List(listed).to_pretty());
+                return Ok(());
+            }
+            println!(
+                "{:>8}  {:>5}  {:>8}  {:>6}  {:>7}  PATH",
+                "INODE", "ATTRS", "BYTES", "BLOCKS", "EXTENTS"
+            );
+            let mut over = 0;
+            for (path, inode_num) in xattr_inode_paths(&mut fs)? {
+                let Some(usage) = xattr::xattr_usage(&mut fs, inode_num)? else {
+                    continue;
+                };
+                let flag = if usage.over_capacity() {
+                    over += 1;
+                    "  (over capacity)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{:>8}  {:>5}  {:>8}  {:>6}  {:>7}  {}{}",
+                    usage.inode_num,
+                    usage.count,
+                    usage.bytes,
+                    usage.blocks,
+                    usage.extents,
+                    path,
+                    flag
+                );
+            }
+            if over > 0 {
+                info!(
+                    "{} inodes exceed the {} byte xattr limit",
+                    over, LOLELFFS_XATTR_SET_MAX
+                );
+            }
+        }
+        XattrIndexAction::Rebuild { path } => {
+            let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+            let targets = match path {
+                Some(path) => {
+                    let inode_num = fs.resolve_path(&path)?;
+                    vec![(path, inode_num)]
+                }
+                None => xattr_inode_paths(&mut fs)?,
+            };
+
+            let mut merged = 0;
+            let mut listed = Vec::new();
+            for (path, inode_num) in &targets {
+                let (before, after) = xattr::rebuild_xattrs(&mut fs, *inode_num)
+                    .with_context(|| format!("Failed to rebuild xattrs of {}", path))?;
+                if after < before {
+                    if json_output() {
+                        listed.push(JsonValue::object([
+                            ("path", path.as_str().into()),
+                            ("extents_before", before.into()),
+                            ("extents_after", after.into()),
+                        ]));
+                    } else {
+                        println!("{}: {} -> {} extents", path, before, after);
+                    }
+                    merged += 1;
+                }
+            }
+            if json_output() {
+                println!("{}", JsonValue::List(listed).to_pretty());
+            }
+            info!(
+                "Rebuilt xattrs of {} inodes, {} compacted",
+                targets.len(),
+                merged
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_sync_image(
+    image: &ImageLocator,
+    source: &ImageLocator,
+    delete: bool,
+    password: Option<String>,
+    source_password: Option<String>,
+) -> Result<()> {
+    let mut src = LolelfFs::open_locator(source, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut src, source_password)?;
+
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadWrite)?;
+    unlock_if_needed(&mut fs, password)?;
+    attach_progress(&mut fs);
+
+    let opts = sync::SyncOptions { delete };
+    let stats = fs.sync_from(&mut src, &opts)?;
+
+    info!(
+        "Synced '{}' -> '{}': {} created, {} updated, {} unchanged, {} removed",
+        source, image, stats.created, stats.updated, stats.unchanged, stats.removed
+    );
+
+    Ok(())
+}
+
+fn cmd_sync(
+    image: &ImageLocator,
+    source: &std::path::Path,
+    dest: &str,
+    delete: bool,
+    password: Option<String>,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    if !source.is_dir() {
+        bail!("'{}' is not a directory", source.display());
+    }
+
+    let mut fs = open_for_write(image, scratch)?;
+    unlock_if_needed(&mut fs, password)?;
+
+    let dest_inode = fs.resolve_path(dest)?;
+    if !fs.read_inode(dest_inode)?.is_dir() {
+        bail!("'{}' is not a directory", dest);
+    }
+
+    let opts = sync::SyncOptions { delete };
+    let stats = fs.sync_from_host(source, dest_inode, &opts)?;
+
+    info!(
+        "Synced '{}' -> '{}': {} created, {} updated, {} unchanged, {} removed",
+        source.display(),
+        dest,
+        stats.created,
+        stats.updated,
+        stats.unchanged,
+        stats.removed
+    );
+
+    finish_scratch(&mut fs, scratch)
+}
+
+fn cmd_import_tar(
+    image: &ImageLocator,
+    archive: &PathBuf,
+    dest: &str,
+    password: Option<String>,
+    deterministic: bool,
+    checksum: bool,
+    scratch: &ScratchArgs,
+) -> Result<()> {
+    let mut fs = open_for_write(image, scratch)?;
+    unlock_if_needed(&mut fs, password)?;
+    if deterministic {
+        fs.fixed_time = Some(source_date_epoch()?);
+    }
+    attach_progress(&mut fs);
+    let opts = archive::TarImportOptions {
+        sorted: deterministic,
+        checksums: checksum,
+    };
+
+    let dest_inode = fs.resolve_path(dest)?;
+    if !fs.read_inode(dest_inode)?.is_dir() {
+        bail!("'{}' is not a directory", dest);
+    }
+
+    let stats = if archive.as_os_str() == "-" {
+        fs.import_tar_with(std::io::stdin().lock(), dest_inode, &opts)?
+    } else {
+        let file = std::fs::File::open(archive)
+            .with_context(|| format!("Failed to open '{}'", archive.display()))?;
+        fs.import_tar_with(file, dest_inode, &opts)?
+    };
+
+    info!(
+        "Imported '{}': {} files, {} directories, {} symlinks, {} hard links",
+        archive.display(),
+        stats.files,
+        stats.dirs,
+        stats.symlinks,
+        stats.hardlinks
+    );
+    if stats.skipped > 0 {
+        info!(
+            "Skipped {} entries with no lolelffs equivalent (devices, FIFOs)",
+            stats.skipped
+        );
+    }
+
+    finish_scratch(&mut fs, scratch)
+}
+
+/// Open `image` for writing, through a scratch overlay if `--scratch` was given
+fn open_for_write(image: &ImageLocator, scratch: &ScratchArgs) -> Result<LolelfFs> {
+    match &scratch.scratch {
+        Some(path) => LolelfFs::open_locator_cow(image, path),
+        None => LolelfFs::open_locator(image, OpenMode::ReadWrite),
+    }
+}
+
+/// Timestamp for `--deterministic`: `$SOURCE_DATE_EPOCH`, or 0 when unset
+fn source_date_epoch() -> Result<u32> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH '{}'", value)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Commit the scratch file if `--commit` was given, or say where the
+/// changes are waiting
+fn finish_scratch(fs: &mut LolelfFs, scratch: &ScratchArgs) -> Result<()> {
+    let Some(path) = &scratch.scratch else {
+        return Ok(());
+    };
+    if scratch.commit {
+        let blocks = fs.commit()?;
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove '{}'", path.display()))?;
+        info!("Committed {} blocks from '{}'", blocks, path.display());
+    } else {
+        info!(
+            "Image unchanged; {} blocks held in '{}' (see 'lolelffs scratch')",
+            fs.scratch_blocks(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn cmd_scratch(image: &ImageLocator, scratch: &PathBuf, action: ScratchAction) -> Result<()> {
+    if !scratch.exists() {
+        bail!("Scratch file '{}' does not exist", scratch.display());
+    }
+    // Opening checks the scratch file belongs to the image
+    let mut fs = LolelfFs::open_locator_cow(image, scratch)?;
+
+    match action {
+        ScratchAction::Status if json_output() => {
+            let out = JsonValue::object([
+                ("scratch", scratch.display().to_string().into()),
+                ("blocks", fs.scratch_blocks().into()),
+            ]);
+            println!("{}", out.to_pretty());
+        }
+        ScratchAction::Status => {
+            println!(
+                "{} blocks held in '{}'",
+                fs.scratch_blocks(),
+                scratch.display()
+            );
+        }
+        ScratchAction::Commit => {
+            let blocks = fs.commit()?;
+            std::fs::remove_file(scratch)
+                .with_context(|| format!("Failed to remove '{}'", scratch.display()))?;
+            info!("Committed {} blocks into {}", blocks, image);
+        }
+        ScratchAction::Discard => {
+            let blocks = fs.discard()?;
+            std::fs::remove_file(scratch)
+                .with_context(|| format!("Failed to remove '{}'", scratch.display()))?;
+            info!("Discarded {} blocks", blocks);
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_export_tar(
+    image: &ImageLocator,
+    output: &PathBuf,
+    path: &str,
+    gzip: bool,
+    password: Option<String>,
+    opts: &archive::TarExportOptions,
+) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::ReadOnly)?;
+    unlock_if_needed(&mut fs, password)?;
+    attach_progress(&mut fs);
+
+    let inode_num = fs.resolve_path(path)?;
+    if !fs.read_inode(inode_num)?.is_dir() {
+        bail!("'{}' is not a directory", path);
+    }
+
+    let to_stdout = output.as_os_str() == "-";
+    let gzip = gzip
+        || matches!(
+            output.extension().and_then(|e| e.to_str()),
+            Some("gz" | "tgz")
+        );
+    let out: Box<dyn Write> = if to_stdout {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(
+            std::fs::File::create(output)
+                .with_context(|| format!("Failed to write '{}'", output.display()))?,
+        )
+    };
+    let mut out = io::BufWriter::new(out);
+
+    let stats = if gzip {
+        let mut gz = flate2::write::GzEncoder::new(&mut out, flate2::Compression::default());
+        let stats = fs.export_tar_with(&mut gz, inode_num, opts)?;
+        gz.finish()?;
+        stats
+    } else {
+        fs.export_tar_with(&mut out, inode_num, opts)?
+    };
+    out.flush()?;
+
+    info!(
+        "Exported '{}': {} files, {} directories, {} symlinks, {} hard links",
+        path, stats.files, stats.dirs, stats.symlinks, stats.hardlinks
+    );
+    if stats.unsupported > 0 {
+        eprintln!(
+            "Warning: left out {} files stored with unsupported algorithms",
+            stats.unsupported
+        );
+    }
 
     Ok(())
 }
 
 // Helper functions
 
+fn cmd_forensic(image: &ImageLocator, action: ForensicAction) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::Forensic)?;
+
+    match action {
+        ForensicAction::Unallocated { output } => {
+            let runs = forensic::unallocated_runs(&mut fs)?;
+            let total: u64 = runs.iter().map(|&(_, len)| len as u64).sum();
+
+            match output {
+                Some(path) => {
+                    let mut out = std::io::BufWriter::new(
+                        std::fs::File::create(&path)
+                            .with_context(|| format!("Failed to write '{}'", path.display()))?,
+                    );
+                    let count = forensic::dump_unallocated(&mut fs, &mut out)?;
+                    out.flush()?;
+                    info!("Dumped {} unallocated blocks to {}", count, path.display());
+                }
+                None if json_output() => {
+                    let listed: Vec<JsonValue> = runs
+                        .iter()
+                        .map(|&(start, len)| {
+                            JsonValue::object([("start", start.into()), ("blocks", len.into())])
+                        })
+                        .collect();
+                    let out = JsonValue::object([
+                        ("runs", listed.into()),
+                        ("total_blocks", total.into()),
+                    ]);
+                    println!("{}", out.to_pretty());
+                }
+                None => {
+                    println!("{:>10}  {:>10}", "START", "BLOCKS");
+                    for (start, len) in &runs {
+                        println!("{:>10}  {:>10}", start, len);
+                    }
+                    info!(
+                        "{} unallocated blocks in {} runs ({})",
+                        total,
+                        runs.len(),
+                        format_size(total * LOLELFFS_BLOCK_SIZE as u64)
+                    );
+                }
+            }
+        }
+        ForensicAction::Orphans { recover, password } => {
+            if let Some(pwd) = password {
+                fs.unlock(&pwd)?;
+            }
+
+            let orphans = forensic::find_orphan_files(&mut fs)?;
+            if json_output() {
+                println!("{}", orphans.to_json().to_pretty());
+            } else {
+                println!(
+                    "{:>8}  {:>10}  {:>10}  {:>8}",
+                    "INODE", "EI_BLOCK", "SIZE", "BLOCKS"
+                );
+                for orphan in &orphans {
+                    let inode = orphan
+                        .inode_num
+                        .map_or("-".to_string(), |ino| ino.to_string());
+                    println!(
+                        "{:>8}  {:>10}  {:>10}  {:>8}",
+                        inode, orphan.ei_block, orphan.size, orphan.blocks
+                    );
+                }
+            }
+            info!("{} orphaned files found", orphans.len());
+
+            if let Some(dir) = recover {
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+                for orphan in &orphans {
+                    let path = dir.join(format!("orphan-{}.bin", orphan.ei_block));
+                    let mut out = std::io::BufWriter::new(
+                        std::fs::File::create(&path)
+                            .with_context(|| format!("Failed to write '{}'", path.display()))?,
+                    );
+                    match forensic::recover_orphan(&mut fs, orphan, &mut out) {
+                        Ok(_) => out.flush()?,
+                        Err(e) => eprintln!(
+                            "Warning: failed to recover block {}: {}",
+                            orphan.ei_block, e
+                        ),
+                    }
+                }
+            }
+        }
+        ForensicAction::Carve { output } => {
+            let hits = forensic::carve(&mut fs)?;
+            if json_output() {
+                println!("{}", hits.to_json().to_pretty());
+            } else {
+                println!("{:>10}  {:>8}  TYPE", "BLOCK", "BLOCKS");
+                for hit in &hits {
+                    println!("{:>10}  {:>8}  {}", hit.block, hit.run_blocks, hit.kind);
+                }
+            }
+            info!("{} candidates found", hits.len());
+
+            if let Some(dir) = output {
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+                for hit in &hits {
+                    let path = dir.join(format!("{}.{}", hit.block, hit.extension));
+                    let mut data =
+                        Vec::with_capacity(hit.run_blocks as usize * LOLELFFS_BLOCK_SIZE as usize);
+                    for block in hit.block..hit.block + hit.run_blocks {
+                        data.extend_from_slice(&fs.read_block(block)?);
+                    }
+                    std::fs::write(&path, &data)
+                        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_debug(image: &ImageLocator, action: DebugAction) -> Result<()> {
+    let mut fs = LolelfFs::open_locator(image, OpenMode::Forensic)?;
+    let sb = fs.superblock.clone();
+
+    match action {
+        DebugAction::DumpBlock { block } => {
+            let data = fs.read_block(block)?;
+            let region = if block == 0 {
+                "superblock".to_string()
+            } else if block < sb.ifree_bitmap_start() {
+                let first = (block - sb.inode_store_start()) * LOLELFFS_INODES_PER_BLOCK;
+                format!(
+                    "inode table, inodes {}-{}",
+                    first,
+                    first + LOLELFFS_INODES_PER_BLOCK - 1
+                )
+            } else if block < sb.bfree_bitmap_start() {
+                "inode bitmap".to_string()
+            } else if block < sb.data_block_start() {
+                "block bitmap".to_string()
+            } else if fs.is_block_free(block)? {
+                "data, free".to_string()
+            } else {
+                "data, in use".to_string()
+            };
+            println!("Block {} ({})", block, region);
+            print_hexdump(&data);
+        }
+        DebugAction::DumpInode { inode: inode_num } => {
+            let inode = fs.read_inode(inode_num)?;
+            let state = if fs.is_inode_free(inode_num)? {
+                "free"
+            } else {
+                "in use"
+            };
+            println!("Inode {} ({})", inode_num, state);
+            println!(
+                "  i_mode:      {:#o} ({}{})",
+                inode.i_mode,
+                inode.type_char(),
+                inode.perm_string()
+            );
+            println!("  i_uid:       {}", inode.i_uid);
+            println!("  i_gid:       {}", inode.i_gid);
+            println!("  i_size:      {}", inode.i_size);
+            for (name, ts) in [
+                ("i_ctime", inode.i_ctime),
+                ("i_atime", inode.i_atime),
+                ("i_mtime", inode.i_mtime),
+            ] {
+                println!("  {}:     {} ({})", name, ts, format_timestamp(ts));
+            }
+            println!("  i_blocks:    {}", inode.i_blocks);
+            println!("  i_nlink:     {}", inode.i_nlink);
+            println!("  ei_block:    {}", inode.ei_block);
+            println!("  xattr_block: {}", inode.xattr_block);
+            let data: Vec<String> = inode.i_data.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("  i_data:      {}", data.join(" "));
+            if inode.is_symlink() {
+                println!("  (target)     {}", symlink_text(&inode));
+            } else {
+                println!("  (generation) {}", inode.generation());
+            }
+
+            if inode.ei_block != 0 {
+                println!();
+                // A corrupt pointer is reported rather than followed
+                if inode.ei_block < sb.data_block_start() || inode.ei_block >= sb.nr_blocks {
+                    println!(
+                        "Extent index block {} is outside the data area {}..{}",
+                        inode.ei_block,
+                        sb.data_block_start(),
+                        sb.nr_blocks
+                    );
+                } else {
+                    let ei = fs.read_extent_index(&inode)?;
+                    println!(
+                        "Extent index (block {}, nr_files {}):",
+                        inode.ei_block, ei.nr_files
+                    );
+                    print_extent_table(&ei);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print `data` like `hexdump -C`, collapsing repeated lines into "*"
+fn print_hexdump(data: &[u8]) {
+    let mut prev: Option<&[u8]> = None;
+    let mut squeezed = false;
+    for (idx, line) in data.chunks(16).enumerate() {
+        if prev == Some(line) {
+            if !squeezed {
+                println!("*");
+                squeezed = true;
+            }
+            continue;
+        }
+        prev = Some(line);
+        squeezed = false;
+
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let (left, right) = hex.split_at(hex.len().min(8));
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(
+            "{:08x}  {:<23}  {:<23}  |{}|",
+            idx * 16,
+            left.join(" "),
+            right.join(" "),
+            ascii
+        );
+    }
+    println!("{:08x}", data.len());
+}
+
 fn split_path(path: &str) -> (String, &str) {
     let path = path.trim_end_matches('/');
     match path.rfind('/') {
@@ -1098,6 +6686,30 @@ fn split_path(path: &str) -> (String, &str) {
 }
 
 /// Unlock filesystem if it's encrypted and password is provided
+/// With `--progress-json`, stream `fs`'s progress events to stderr, one JSON
+/// object per line: the first and last of each phase, and at most ten a
+/// second in between
+fn attach_progress(fs: &mut LolelfFs) {
+    if !PROGRESS_JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut last: Option<(&'static str, Instant)> = None;
+    fs.set_progress(Box::new(move |event: &progress::Progress| {
+        let due = match last {
+            Some((phase, at)) => {
+                phase != event.phase
+                    || event.current == event.total
+                    || at.elapsed() >= Duration::from_millis(100)
+            }
+            None => true,
+        };
+        if due {
+            last = Some((event.phase, Instant::now()));
+            eprintln!("{}", event.to_json());
+        }
+    }));
+}
+
 fn unlock_if_needed(fs: &mut LolelfFs, password: Option<String>) -> Result<()> {
     // Check if filesystem is encrypted
     if fs.superblock.enc_enabled == 0 {
@@ -1137,6 +6749,93 @@ fn parse_size(s: &str) -> Result<u64> {
     Ok(num * multiplier)
 }
 
+/// Parse a numeric "UID", "UID:GID", ":GID" or "UID:" owner spec
+fn parse_owner(spec: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (uid, gid) = match spec.split_once(':') {
+        Some((uid, gid)) => (uid, Some(gid)),
+        None => (spec, None),
+    };
+
+    let parse = |s: &str| -> Result<Option<u32>> {
+        if s.is_empty() {
+            return Ok(None);
+        }
+        s.parse()
+            .map(Some)
+            .with_context(|| format!("Invalid owner '{}': expected numeric UID:GID", spec))
+    };
+
+    let uid = parse(uid)?;
+    let gid = match gid {
+        Some(gid) => parse(gid)?,
+        None => None,
+    };
+    if uid.is_none() && gid.is_none() {
+        bail!("Invalid owner '{}': expected numeric UID:GID", spec);
+    }
+
+    Ok((uid, gid))
+}
+
+/// Parse an octal or chmod(1)-style symbolic mode against the current bits
+fn parse_mode(spec: &str, current: u32, is_dir: bool) -> Result<u32> {
+    if !spec.is_empty() && spec.chars().all(|c| c.is_digit(8)) {
+        let perm =
+            u32::from_str_radix(spec, 8).with_context(|| format!("Invalid mode: {}", spec))?;
+        if perm > 0o7777 {
+            bail!("Invalid mode: {}", spec);
+        }
+        return Ok(perm);
+    }
+
+    let mut perm = current;
+    for clause in spec.split(',') {
+        let op_pos = clause
+            .find(['+', '-', '='])
+            .ok_or_else(|| anyhow::anyhow!("Invalid mode: {}", spec))?;
+        let (who, rest) = clause.split_at(op_pos);
+
+        // Which permission classes the clause applies to ("a" if omitted)
+        let mut mask = 0u32;
+        for c in who.chars() {
+            mask |= match c {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                'a' => 0o7777,
+                _ => bail!("Invalid mode: {}", spec),
+            };
+        }
+        if mask == 0 {
+            mask = 0o7777;
+        }
+
+        let op = rest.as_bytes()[0];
+        let mut bits = 0u32;
+        for c in rest[1..].chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                'X' if is_dir || perm & 0o111 != 0 => 0o111,
+                'X' => 0,
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => bail!("Invalid mode: {}", spec),
+            };
+        }
+        let bits = bits & mask;
+
+        perm = match op {
+            b'+' => perm | bits,
+            b'-' => perm & !bits,
+            _ => (perm & !(mask & 0o777)) | bits,
+        };
+    }
+
+    Ok(perm)
+}
+
 fn format_size(bytes: u64) -> String {
     if bytes >= 1024 * 1024 * 1024 {
         format!("{:.1}G", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
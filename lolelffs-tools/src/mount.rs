@@ -0,0 +1,534 @@
+//! Mounting images with the kernel module or the FUSE driver
+//!
+//! The kernel module is faster and is what production systems use, but it
+//! needs root and a loaded `lolelffs` module. `mount_image` prefers it when
+//! both are available and otherwise starts the bundled `lolelffs-fuse`
+//! driver in the background, so one command works on either kind of host.
+//! Kernel mounts go through mount(8), which sets up the loop device.
+//! Images using anything the kernel module does not implement, such as
+//! per-file keys or shared extents, always go to FUSE.
+
+use crate::locator::ImageLocator;
+use anyhow::{bail, Context, Result};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Filesystem type the kernel module registers
+pub const KERNEL_FS_TYPE: &str = "lolelffs";
+
+/// How long to wait for the FUSE driver to finish mounting
+const FUSE_MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which driver serves a mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountBackend {
+    /// The kernel module when running as root, it can be loaded and it
+    /// implements everything the image uses, else FUSE
+    #[default]
+    Auto,
+    Kernel,
+    Fuse,
+}
+
+impl std::str::FromStr for MountBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MountBackend::Auto),
+            "kernel" => Ok(MountBackend::Kernel),
+            "fuse" => Ok(MountBackend::Fuse),
+            _ => Err(format!(
+                "unknown mount backend '{}' (expected auto, kernel, or fuse)",
+                s
+            )),
+        }
+    }
+}
+
+/// How to mount an image
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    pub backend: MountBackend,
+    pub read_only: bool,
+    /// The `lolelffs-fuse` executable to start for FUSE mounts
+    pub fuse_binary: PathBuf,
+}
+
+/// How an image ended up mounted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mounted {
+    Kernel,
+    /// Served by a background `lolelffs-fuse` process
+    Fuse {
+        pid: u32,
+    },
+}
+
+/// One line of `/proc/mounts`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: PathBuf,
+    pub fs_type: String,
+    pub options: String,
+}
+
+impl MountEntry {
+    /// Parse a `/proc/mounts` line, undoing its octal escapes
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let source = unescape_mount_field(fields.next()?);
+        let target = PathBuf::from(unescape_mount_field(fields.next()?));
+        let fs_type = fields.next()?.to_string();
+        let options = fields.next()?.to_string();
+        Some(MountEntry {
+            source,
+            target,
+            fs_type,
+            options,
+        })
+    }
+}
+
+/// Decode the `\040`-style escapes the kernel uses for spaces and the like
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).and_then(|digits| {
+            if bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)) {
+                u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok()
+            } else {
+                None
+            }
+        });
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Everything currently mounted on this host
+pub fn mount_entries() -> Result<Vec<MountEntry>> {
+    let table = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    Ok(table.lines().filter_map(MountEntry::parse).collect())
+}
+
+/// Whether something is mounted on `dir`
+pub fn is_mount_point(dir: &Path) -> Result<bool> {
+    let dir = std::fs::canonicalize(dir)?;
+    Ok(mount_entries()?.iter().any(|entry| entry.target == dir))
+}
+
+/// Whether the process runs with an effective uid of 0
+pub fn is_privileged() -> bool {
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|uids| uids.split_whitespace().nth(1))
+        .is_some_and(|euid| euid == "0")
+}
+
+/// Whether the running kernel knows the lolelffs filesystem type
+pub fn kernel_module_loaded() -> bool {
+    std::fs::read_to_string("/proc/filesystems").is_ok_and(|list| {
+        list.lines()
+            .any(|line| line.split_whitespace().last() == Some(KERNEL_FS_TYPE))
+    })
+}
+
+/// Load the kernel module with modprobe, returning whether it is loaded now
+pub fn load_kernel_module() -> bool {
+    if kernel_module_loaded() {
+        return true;
+    }
+    let loaded = Command::new("modprobe")
+        .arg(KERNEL_FS_TYPE)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    loaded && kernel_module_loaded()
+}
+
+/// Mount with the kernel module through mount(8)
+///
+/// Images in regular files get a loop device, at the filesystem's offset
+/// for embedded images.
+pub fn mount_kernel(image: &ImageLocator, dir: &Path, read_only: bool) -> Result<()> {
+    let (path, offset) = image.resolve()?;
+    let mut options = Vec::new();
+    if !matches!(image, ImageLocator::BlockDevice(_)) || offset != 0 {
+        options.push("loop".to_string());
+    }
+    if offset != 0 {
+        options.push(format!("offset={}", offset));
+    }
+    if read_only {
+        options.push("ro".to_string());
+    }
+
+    let mut command = Command::new("mount");
+    command.args(["-t", KERNEL_FS_TYPE]);
+    if !options.is_empty() {
+        command.arg("-o").arg(options.join(","));
+    }
+    let output = command
+        .arg(&path)
+        .arg(dir)
+        .output()
+        .context("Failed to run mount")?;
+    if !output.status.success() {
+        bail!(
+            "mount failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Start `lolelffs-fuse` in the background and wait until the mount is up
+pub fn mount_fuse(image: &ImageLocator, dir: &Path, read_only: bool, binary: &Path) -> Result<u32> {
+    let mut command = Command::new(binary);
+    command.arg(image.to_string()).arg(dir);
+    if read_only {
+        command.arg("--ro");
+    }
+    // Its own process group keeps it alive when the shell's job is signalled
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", binary.display()))?;
+
+    let deadline = Instant::now() + FUSE_MOUNT_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = child.stderr.take() {
+                let _ = std::io::Read::read_to_string(&mut pipe, &mut stderr);
+            }
+            let reason = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or("no output");
+            bail!("{} exited with {}: {}", binary.display(), status, reason);
+        }
+        if is_mount_point(dir)? {
+            return Ok(child.id());
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            bail!(
+                "{} did not mount {} within {}s",
+                binary.display(),
+                dir.display(),
+                FUSE_MOUNT_TIMEOUT.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// What in `image` the kernel module does not implement
+fn kernel_issues(image: &ImageLocator) -> Result<Vec<String>> {
+    let (path, offset) = image.resolve()?;
+    match crate::probe::probe_at(&path, offset)? {
+        Some(info) => Ok(info.superblock.kernel_issues()),
+        None => bail!("No lolelffs filesystem found in '{}'", path.display()),
+    }
+}
+
+/// Mount `image` on `dir` with the backend `options` asks for
+///
+/// With `MountBackend::Auto` a failed kernel mount falls back to FUSE; the
+/// kernel error is returned alongside so callers can mention it.
+pub fn mount_image(
+    image: &ImageLocator,
+    dir: &Path,
+    options: &MountOptions,
+) -> Result<(Mounted, Option<anyhow::Error>)> {
+    if !dir.is_dir() {
+        bail!("Mount point '{}' is not a directory", dir.display());
+    }
+    if is_mount_point(dir)? {
+        bail!("'{}' is already a mount point", dir.display());
+    }
+
+    let kernel_issues = match options.backend {
+        MountBackend::Fuse => Vec::new(),
+        _ => kernel_issues(image)?,
+    };
+    let kernel_error = match options.backend {
+        MountBackend::Fuse => None,
+        MountBackend::Kernel => {
+            if !kernel_issues.is_empty() {
+                bail!(
+                    "The kernel module cannot mount this image ({}); use the FUSE backend",
+                    kernel_issues.join(", ")
+                );
+            }
+            if !is_privileged() {
+                bail!("Mounting with the kernel module requires root");
+            }
+            if !load_kernel_module() {
+                bail!("The {} kernel module is not available", KERNEL_FS_TYPE);
+            }
+            mount_kernel(image, dir, options.read_only)?;
+            return Ok((Mounted::Kernel, None));
+        }
+        MountBackend::Auto
+            if kernel_issues.is_empty() && is_privileged() && load_kernel_module() =>
+        {
+            match mount_kernel(image, dir, options.read_only) {
+                Ok(()) => return Ok((Mounted::Kernel, None)),
+                Err(err) => Some(err),
+            }
+        }
+        MountBackend::Auto => None,
+    };
+
+    let pid = mount_fuse(image, dir, options.read_only, &options.fuse_binary)?;
+    Ok((Mounted::Fuse { pid }, kernel_error))
+}
+
+/// An active lolelffs mount
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LolelfMount {
+    pub target: PathBuf,
+    /// The image as the driver was given it, or the mounted device when the
+    /// image cannot be traced back
+    pub image: String,
+    pub backend: Mounted,
+    pub read_only: bool,
+}
+
+/// Image and mount point arguments of a `lolelffs-fuse` command line
+fn fuse_daemon_args(args: &[String]) -> Option<(String, String)> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--verify" || arg == "--trace-flame" {
+            args.next();
+        } else if !arg.starts_with('-') {
+            positional.push(arg.clone());
+        }
+    }
+    let mut positional = positional.into_iter();
+    Some((positional.next()?, positional.next()?))
+}
+
+/// Running `lolelffs-fuse` processes with their image and mount point
+fn fuse_daemons() -> Vec<(u32, String, PathBuf)> {
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut daemons = Vec::new();
+    for entry in procs.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        let argv: Vec<String> = cmdline
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        let is_daemon = argv
+            .first()
+            .and_then(|argv0| Path::new(argv0).file_name())
+            .is_some_and(|name| name == "lolelffs-fuse");
+        let Some((image, mountpoint)) = is_daemon.then(|| fuse_daemon_args(&argv[1..])).flatten()
+        else {
+            continue;
+        };
+        // Relative mount points are relative to the daemon's directory
+        let mut mountpoint = PathBuf::from(mountpoint);
+        if mountpoint.is_relative() {
+            if let Ok(cwd) = std::fs::read_link(entry.path().join("cwd")) {
+                mountpoint = cwd.join(mountpoint);
+            }
+        }
+        let mountpoint = std::fs::canonicalize(&mountpoint).unwrap_or(mountpoint);
+        daemons.push((pid, image, mountpoint));
+    }
+    daemons
+}
+
+/// Backing file of a loop device, with its offset in locator syntax
+fn loop_backing_file(device: &str) -> Option<String> {
+    let name = Path::new(device).file_name()?.to_str()?;
+    if !name.starts_with("loop") {
+        return None;
+    }
+    let sys = Path::new("/sys/block").join(name).join("loop");
+    let file = std::fs::read_to_string(sys.join("backing_file")).ok()?;
+    let offset: u64 = std::fs::read_to_string(sys.join("offset"))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0);
+    Some(match offset {
+        0 => file.trim().to_string(),
+        offset => format!("{}@offset={}", file.trim(), offset),
+    })
+}
+
+/// Every lolelffs filesystem mounted on this host
+///
+/// Kernel mounts are recognized by their filesystem type, FUSE mounts by
+/// the `lolelffs-fuse` process serving them.
+pub fn lolelffs_mounts() -> Result<Vec<LolelfMount>> {
+    let daemons = fuse_daemons();
+    let mut mounts = Vec::new();
+    for entry in mount_entries()? {
+        let read_only = entry.options.split(',').any(|option| option == "ro");
+        if entry.fs_type == KERNEL_FS_TYPE {
+            mounts.push(LolelfMount {
+                image: loop_backing_file(&entry.source).unwrap_or(entry.source),
+                target: entry.target,
+                backend: Mounted::Kernel,
+                read_only,
+            });
+        } else if entry.fs_type.starts_with("fuse") {
+            if let Some((pid, image, _)) = daemons.iter().find(|(_, _, dir)| *dir == entry.target) {
+                mounts.push(LolelfMount {
+                    image: image.clone(),
+                    target: entry.target,
+                    backend: Mounted::Fuse { pid: *pid },
+                    read_only,
+                });
+            }
+        }
+    }
+    Ok(mounts)
+}
+
+/// Run an unmount command, returning its error output on failure
+fn run_unmount(program: &str, args: &[&str], target: &Path) -> std::result::Result<(), String> {
+    match Command::new(program).args(args).arg(target).output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!(
+            "{}: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => Err(format!("{}: {}", program, e)),
+    }
+}
+
+/// Whether process `pid` has exited (zombies count as exited)
+fn process_exited(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit_once(") ")
+            .is_some_and(|(_, rest)| rest.starts_with('Z')),
+        Err(_) => true,
+    }
+}
+
+/// Unmount the lolelffs filesystem on `dir`
+///
+/// FUSE mounts are released with fusermount3, fusermount, or umount,
+/// whichever works, and the call waits for the daemon to flush the image and
+/// exit so the image can be opened again as soon as it returns.
+pub fn unmount(dir: &Path) -> Result<LolelfMount> {
+    let target = std::fs::canonicalize(dir)
+        .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+    let Some(mount) = lolelffs_mounts()?.into_iter().find(|m| m.target == target) else {
+        bail!("'{}' is not a lolelffs mount point", dir.display());
+    };
+
+    match mount.backend {
+        Mounted::Kernel => {
+            if let Err(e) = run_unmount("umount", &[], &target) {
+                bail!("{}", e);
+            }
+        }
+        Mounted::Fuse { pid } => {
+            let mut errors = Vec::new();
+            for (program, args) in [
+                ("fusermount3", &["-u"][..]),
+                ("fusermount", &["-u"][..]),
+                ("umount", &[][..]),
+            ] {
+                match run_unmount(program, args, &target) {
+                    Ok(()) => {
+                        errors.clear();
+                        break;
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+            if !errors.is_empty() {
+                bail!("Failed to unmount {}: {}", dir.display(), errors.join("; "));
+            }
+
+            let deadline = Instant::now() + FUSE_MOUNT_TIMEOUT;
+            while !process_exited(pid) {
+                if Instant::now() >= deadline {
+                    bail!(
+                        "Unmounted {}, but lolelffs-fuse (pid {}) is still running",
+                        dir.display(),
+                        pid
+                    );
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+    Ok(mount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_table_parsing() {
+        let entry =
+            MountEntry::parse("/dev/loop3 /mnt/my\\040disk lolelffs rw,relatime 0 0").unwrap();
+        assert_eq!(entry.source, "/dev/loop3");
+        assert_eq!(entry.target, PathBuf::from("/mnt/my disk"));
+        assert_eq!(entry.fs_type, "lolelffs");
+        assert_eq!(entry.options, "rw,relatime");
+        assert!(MountEntry::parse("short line").is_none());
+        assert_eq!(unescape_mount_field("a\\134b\\01"), "a\\b\\01");
+
+        let args: Vec<String> = ["--verify", "never", "-r", "a.img", "/mnt/x"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            fuse_daemon_args(&args),
+            Some(("a.img".to_string(), "/mnt/x".to_string()))
+        );
+        assert_eq!(fuse_daemon_args(&args[..4]), None);
+
+        assert_eq!("fuse".parse(), Ok(MountBackend::Fuse));
+        assert!("nfs".parse::<MountBackend>().is_err());
+    }
+}
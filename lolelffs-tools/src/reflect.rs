@@ -0,0 +1,102 @@
+//! Symlink farms over a mounted or extracted image
+//!
+//! Some tools refuse anything but real paths and cannot be pointed at a FUSE
+//! mount hidden somewhere else. `build_farm` recreates a tree's directories
+//! under a target directory and links every other entry back to the
+//! original, so the tree can be browsed in place without copying file
+//! contents. `remove_farm` undoes exactly what was created and leaves
+//! anything a tool added to the target alone.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Mirror the tree under `source` into `target` as real directories and
+/// symlinks to the files, returning what was created in creation order
+///
+/// `target` must exist. Symlinks in `source` are linked to as they are
+/// (not followed), so relative links inside the image still resolve
+/// within it.
+pub fn build_farm(source: &Path, target: &Path) -> Result<Vec<PathBuf>> {
+    let mut created = Vec::new();
+    if let Err(e) = link_dir(source, target, &mut created) {
+        remove_farm(&created);
+        return Err(e);
+    }
+    Ok(created)
+}
+
+fn link_dir(source: &Path, target: &Path, created: &mut Vec<PathBuf>) -> Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(source)
+        .with_context(|| format!("Failed to read '{}'", source.display()))?
+        .collect::<std::io::Result<_>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let from = child.path();
+        let to = target.join(child.file_name());
+        if child.file_type()?.is_dir() {
+            std::fs::create_dir(&to)
+                .with_context(|| format!("Failed to create '{}'", to.display()))?;
+            created.push(to.clone());
+            link_dir(&from, &to, created)?;
+        } else {
+            std::os::unix::fs::symlink(&from, &to)
+                .with_context(|| format!("Failed to create symlink '{}'", to.display()))?;
+            created.push(to);
+        }
+    }
+    Ok(())
+}
+
+/// Remove what `build_farm` created, newest first
+///
+/// Directories that tools have since added files to are kept. Returns the
+/// number of entries that could not be removed.
+pub fn remove_farm(created: &[PathBuf]) -> usize {
+    let mut kept = 0;
+    for path in created.iter().rev() {
+        let removed = match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() => std::fs::remove_dir(path),
+            Ok(_) => std::fs::remove_file(path),
+            Err(_) => Ok(()),
+        };
+        if removed.is_err() {
+            kept += 1;
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lolelffs_core::testutil::TempPath;
+
+    #[test]
+    fn test_build_and_remove_farm() {
+        let base = TempPath::new("reflect");
+        let (source, target) = (base.join("src"), base.join("farm"));
+        std::fs::create_dir_all(source.join("etc")).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::write(source.join("etc/hosts"), b"127.0.0.1").unwrap();
+        std::os::unix::fs::symlink("etc/hosts", source.join("hosts")).unwrap();
+
+        let created = build_farm(&source, &target).unwrap();
+        assert_eq!(created.len(), 3);
+        assert!(target.join("etc").symlink_metadata().unwrap().is_dir());
+        assert_eq!(
+            std::fs::read_link(target.join("etc/hosts")).unwrap(),
+            source.join("etc/hosts")
+        );
+        assert_eq!(
+            std::fs::read(target.join("etc/hosts")).unwrap(),
+            b"127.0.0.1"
+        );
+
+        // Files a tool left behind survive the teardown
+        std::fs::write(target.join("etc/report"), b"").unwrap();
+        assert_eq!(remove_farm(&created), 1);
+        assert!(target.join("etc/report").exists());
+        assert!(!target.join("hosts").exists());
+    }
+}
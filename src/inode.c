@@ -73,6 +73,9 @@ struct inode *lolelffs_iget(struct super_block *sb, unsigned long ino)
     set_nlink(inode, le32_to_cpu(cinode->i_nlink));
 
     ci->xattr_block = le32_to_cpu(cinode->xattr_block);
+    /* Symlink target, or the key generation and inode flags of other types,
+     * which write_inode must put back as they were */
+    memcpy(ci->i_data, cinode->i_data, sizeof(ci->i_data));
 
     if (S_ISDIR(inode->i_mode)) {
         ci->ei_block = le32_to_cpu(cinode->ei_block);
@@ -82,7 +85,6 @@ struct inode *lolelffs_iget(struct super_block *sb, unsigned long ino)
         inode->i_fop = &lolelffs_file_ops;
         inode->i_mapping->a_ops = &lolelffs_aops;
     } else if (S_ISLNK(inode->i_mode)) {
-        strncpy(ci->i_data, cinode->i_data, sizeof(ci->i_data));
         inode->i_link = ci->i_data;
         inode->i_op = &symlink_inode_ops;
     }
@@ -230,6 +232,7 @@ static struct inode *lolelffs_new_inode(struct inode *dir, mode_t mode)
         inode->i_op = &symlink_inode_ops;
         ci = LOLELFFS_INODE(inode);
         ci->xattr_block = 0;
+        memset(ci->i_data, 0, sizeof(ci->i_data));
         return inode;
     }
 
@@ -252,6 +255,7 @@ static struct inode *lolelffs_new_inode(struct inode *dir, mode_t mode)
 #endif
     inode->i_blocks = 1;
     ci->xattr_block = 0; /* No xattrs initially */
+    memset(ci->i_data, 0, sizeof(ci->i_data)); /* No flags */
 
     if (S_ISDIR(mode)) {
         ci->ei_block = bno;
@@ -14,11 +14,37 @@
 
 #define LOLELFFS_FILENAME_LEN 255
 
+/* Size of the volume label field in the superblock */
+#define LOLELFFS_LABEL_MAX 64
+
 /* Filesystem version (always 1 - compression support is mandatory) */
 #define LOLELFFS_VERSION 1
 
 /* Feature flags for comp_features field */
 #define LOLELFFS_FEATURE_LARGE_EXTENTS 0x0001
+#define LOLELFFS_FEATURE_COMP_EXCLUDE  0x0002 /* Exclusion list in block 0 */
+#define LOLELFFS_FEATURE_ORDERED_WRITES 0x0004 /* Userspace tools fsync between dependent updates */
+#define LOLELFFS_FEATURE_REFLINK       0x0008 /* Files may share extents */
+#define LOLELFFS_FEATURE_DIR_CSUM      0x0010 /* Directory blocks carry checksums */
+
+/* comp_features bits the kernel module implements; it refuses images with others */
+#define LOLELFFS_KERNEL_FEATURES \
+    (LOLELFFS_FEATURE_LARGE_EXTENTS | LOLELFFS_FEATURE_COMP_EXCLUDE | \
+     LOLELFFS_FEATURE_ORDERED_WRITES)
+
+/* Superblock state flags */
+#define LOLELFFS_STATE_DIRTY 0x0001 /* Open for writing, or not closed cleanly */
+
+/* Compression exclusion list: NUL-terminated name globs in block 0 */
+#define LOLELFFS_COMP_EXCLUDE_OFFSET 1024
+
+/* Inode flags, stored in i_data[4..8] of non-symlinks */
+#define LOLELFFS_INODE_NOCOMP   0x0001 /* Never compress this file's blocks */
+#define LOLELFFS_INODE_HOT      0x0002 /* Frequently read: uncompressed, near metadata */
+#define LOLELFFS_INODE_COLD     0x0004 /* Rarely read: zstd, at the back of the device */
+#define LOLELFFS_INODE_COMP_SET 0x0008 /* Written with the algorithm in COMP_MASK */
+#define LOLELFFS_INODE_COMP_MASK  0xFF00 /* Per-file compression algorithm (setcomp) */
+#define LOLELFFS_INODE_COMP_SHIFT 8
 
 /* Compression algorithm IDs */
 #define LOLELFFS_COMP_NONE      0  /* No compression */
@@ -36,6 +62,20 @@
 #define LOLELFFS_KDF_ARGON2ID       1  /* Argon2id (recommended) */
 #define LOLELFFS_KDF_PBKDF2         2  /* PBKDF2-HMAC-SHA256 */
 
+/* Encryption feature flags (enc_features) */
+#define LOLELFFS_ENC_FEAT_PER_FILE_KEYS 0x0001 /* HKDF-derived per-file keys */
+#define LOLELFFS_ENC_FEAT_OPAQUE        0x0002 /* Every block past the superblock is encrypted */
+
+/* enc_features bits the kernel module implements; it refuses images with others */
+#define LOLELFFS_KERNEL_ENC_FEATURES    0
+
+/* Hash algorithm IDs (shared by all integrity features) */
+#define LOLELFFS_HASH_NONE          0  /* No hashing */
+#define LOLELFFS_HASH_CRC32C        1  /* CRC32C (fast, 4-byte checksum) */
+#define LOLELFFS_HASH_XXH64         2  /* xxHash64 (fast, 8-byte non-cryptographic) */
+#define LOLELFFS_HASH_SHA256        3  /* SHA-256 (cryptographic) */
+#define LOLELFFS_HASH_BLAKE3        4  /* BLAKE3 (fast cryptographic) */
+
 /* Compression metadata magic */
 #define LOLELFFS_COMP_META_MAGIC 0xC04FFEE5
 
@@ -44,6 +84,7 @@
 #define LOLELFFS_EXT_ENCRYPTED    0x0002  /* Extent contains encrypted blocks */
 #define LOLELFFS_EXT_HAS_META     0x0004  /* Has per-block metadata */
 #define LOLELFFS_EXT_MIXED        0x0008  /* Mixed compressed/uncompressed/encrypted */
+#define LOLELFFS_EXT_SHARED       0x0010  /* Blocks may belong to other files too */
 
 /* Extent structure with compression and encryption support (24 bytes) */
 struct lolelffs_extent {
@@ -87,6 +128,10 @@ struct lolelffs_file {
 
 #define LOLELFFS_FILES_PER_BLOCK \
     (LOLELFFS_BLOCK_SIZE / sizeof(struct lolelffs_file))
+/* Checksum of a directory block's entries, in the slack after them (when the
+ * checksum hash feature is enabled) */
+#define LOLELFFS_DIR_CSUM_OFFSET \
+    (LOLELFFS_FILES_PER_BLOCK * sizeof(struct lolelffs_file))
 #define LOLELFFS_FILES_PER_EXT \
     (LOLELFFS_FILES_PER_BLOCK *LOLELFFS_MAX_BLOCKS_PER_EXTENT)
 
@@ -99,6 +144,12 @@ struct lolelffs_file {
 #define LOLELFFS_XATTR_INDEX_SYSTEM     2
 #define LOLELFFS_XATTR_INDEX_SECURITY   3
 
+/* Xattr capacity: per-name and per-value limits, and the serialized size of
+ * all of an inode's xattrs together */
+#define LOLELFFS_XATTR_NAME_MAX         255
+#define LOLELFFS_XATTR_VALUE_MAX        65535
+#define LOLELFFS_XATTR_SET_MAX          (LOLELFFS_BLOCK_SIZE * 8)
+
 /* Xattr entry header structure */
 struct lolelffs_xattr_entry {
     uint8_t name_len;      /* Length of name (not including NUL) */
@@ -143,7 +194,8 @@ struct lolelffs_inode {
     uint32_t i_nlink;  /* Hard links count */
     uint32_t ei_block;  /* Block with list of extents for this file */
     uint32_t xattr_block; /* Block with xattr extent index (0 = no xattrs) */
-    char i_data[28]; /* store symlink content (max 27 chars + NUL) */
+    char i_data[28]; /* symlink content (max 27 chars + NUL), or key generation
+                      * and inode flags */
 };
 
 #define LOLELFFS_INODES_PER_BLOCK \
@@ -181,7 +233,11 @@ struct lolelffs_sb_info {
     uint8_t  enc_salt[32];         /* Salt for key derivation (32 bytes) */
     uint8_t  enc_master_key[32];   /* Encrypted master key (32 bytes) */
     uint32_t enc_features;         /* Feature flags for future extensions */
-    uint32_t reserved[3];          /* Reserved for future use */
+    uint32_t hash_algos;           /* Hash algorithm per integrity feature (one byte each) */
+    uint32_t last_orphan;          /* First inode on the unnamed-inode orphan list */
+    uint32_t state;                /* Runtime state flags (LOLELFFS_STATE_*) */
+    uint8_t  uuid[16];             /* Volume UUID (zero on older images) */
+    char     label[LOLELFFS_LABEL_MAX]; /* Volume label, NUL-padded */
 
 #ifdef __KERNEL__
     unsigned long *ifree_bitmap; /* In-memory free inodes bitmap */
@@ -79,7 +79,7 @@ int lolelffs_write_inode(struct inode *inode,
     disk_inode->i_nlink = inode->i_nlink;
     disk_inode->ei_block = ci->ei_block;
     disk_inode->xattr_block = ci->xattr_block;
-    strncpy(disk_inode->i_data, ci->i_data, sizeof(ci->i_data));
+    memcpy(disk_inode->i_data, ci->i_data, sizeof(ci->i_data));
 
     mark_buffer_dirty(bh);
     sync_dirty_buffer(bh);
@@ -243,6 +243,33 @@ int lolelffs_fill_super(struct super_block *sb, void *data, int silent)
         goto release;
     }
 
+    /* With reflink, extents may be shared between files, and freeing one
+     * file's blocks on unlink or truncate would free another's */
+    if (csb->comp_features & ~LOLELFFS_KERNEL_FEATURES) {
+        pr_err("Unsupported features 0x%x\n",
+               csb->comp_features & ~LOLELFFS_KERNEL_FEATURES);
+        ret = -EINVAL;
+        goto release;
+    }
+
+    /* Bitmaps and the inode table of opaque images are ciphertext;
+     * allocating from them would destroy the image */
+    if (csb->enc_features & LOLELFFS_ENC_FEAT_OPAQUE) {
+        pr_err("Opaque images are not supported, use lolelffs-fuse\n");
+        ret = -EINVAL;
+        goto release;
+    }
+
+    /* Keys derived per file are only implemented by the userspace tools;
+     * decrypting with the master key would return garbage and writing
+     * with it would corrupt the file */
+    if (csb->enc_features & ~LOLELFFS_KERNEL_ENC_FEATURES) {
+        pr_err("Unsupported encryption features 0x%x\n",
+               csb->enc_features & ~LOLELFFS_KERNEL_ENC_FEATURES);
+        ret = -EINVAL;
+        goto release;
+    }
+
     /* Alloc sb_info */
     sbi = kzalloc(sizeof(struct lolelffs_sb_info), GFP_KERNEL);
     if (!sbi) {
@@ -192,10 +192,10 @@ static int lolelffs_xattr_set(struct inode *inode, int name_index,
     name_len = strlen(name);
 
     /* Validate inputs */
-    if (name_len == 0 || name_len > 255)
+    if (name_len == 0 || name_len > LOLELFFS_XATTR_NAME_MAX)
         return -EINVAL;
 
-    if (value_len > 65535)
+    if (value_len > LOLELFFS_XATTR_VALUE_MAX)
         return -ENOSPC;
 
     /* Handle deletion */
@@ -350,7 +350,7 @@ static int lolelffs_xattr_set(struct inode *inode, int name_index,
     new_data_size = data_size + new_entry_size;
 
     /* Check if we have space (simple check for now) */
-    if (new_data_size > LOLELFFS_BLOCK_SIZE * 8) {
+    if (new_data_size > LOLELFFS_XATTR_SET_MAX) {
         ret = -ENOSPC;
         goto out;
     }